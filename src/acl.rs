@@ -0,0 +1,298 @@
+//! Access control module
+//!
+//! This module decides which peers may connect to the TCP ports. The access list is
+//! an ordered list of rules like "allow 192.168.1.0/24" or "deny 10.0.0.5", and the
+//! first rule matching the peer's address decides. A peer no rule matches is only
+//! admitted if the list has no allow rules, so a list of deny rules blocks just those
+//! peers while a single allow rule admits nobody else.
+//!
+//! An empty list admits every peer, so devices upgraded from firmware without access
+//! control stay reachable.
+
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr};
+
+/// Most rules an access list holds
+pub const MAX_RULES: usize = 16;
+
+/// Bytes of one rule in the settings blob
+const ENCODED_RULE_LEN: usize = 6;
+
+/// What happens to a peer matching a rule
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AclAction {
+    /// Admit the peer
+    Allow,
+    /// Close the connection before anything is sent
+    Deny,
+}
+
+impl fmt::Display for AclAction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclAction::Allow => write!(f, "allow"),
+            AclAction::Deny => write!(f, "deny"),
+        }
+    }
+}
+
+/// One entry of an access list: an action for an IPv4 address or CIDR prefix
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AclRule {
+    /// What happens to matching peers
+    pub action: AclAction,
+    /// Network address, with the bits outside the prefix cleared
+    pub network: Ipv4Addr,
+    /// Length of the prefix in bits, 32 for a single address
+    pub prefix_len: u8,
+}
+
+impl AclRule {
+    /// Create a rule, clearing the host bits of `network`
+    ///
+    /// Returns None if `prefix_len` is larger than 32.
+    pub fn new(action: AclAction, network: Ipv4Addr, prefix_len: u8) -> Option<Self> {
+        if prefix_len > 32 {
+            return None;
+        }
+        let network = Ipv4Addr::from(u32::from(network) & Self::mask(prefix_len));
+        Some(Self { action, network, prefix_len })
+    }
+
+    /// Parse the action and the address of a rule, e.g. "ALLOW" and "192.168.1.0/24"
+    pub fn parse(action: &str, address: &str) -> Option<Self> {
+        let action = match action.trim().to_ascii_uppercase().as_str() {
+            "ALLOW" => AclAction::Allow,
+            "DENY" => AclAction::Deny,
+            _ => return None,
+        };
+        let (network, prefix_len) = match address.trim().split_once('/') {
+            Some((network, prefix_len)) => (network, prefix_len.parse().ok()?),
+            None => (address.trim(), 32),
+        };
+        Self::new(action, network.parse().ok()?, prefix_len)
+    }
+
+    /// Check whether `ip` lies within the rule's network
+    pub fn matches(&self, ip: Ipv4Addr) -> bool {
+        u32::from(ip) & Self::mask(self.prefix_len) == u32::from(self.network)
+    }
+
+    /// Netmask of a prefix length
+    fn mask(prefix_len: u8) -> u32 {
+        // 前缀长度为0时移位32位会溢出
+        u32::MAX.checked_shl(32 - u32::from(prefix_len)).unwrap_or(0)
+    }
+}
+
+impl fmt::Display for AclRule {
+    /// Formats as "allow 192.168.1.0/24", or "deny 10.0.0.5" for a single address
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {}", self.action, self.network)?;
+        if self.prefix_len < 32 {
+            write!(f, "/{}", self.prefix_len)?;
+        }
+        Ok(())
+    }
+}
+
+/// Ordered rules deciding which peers may connect
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AccessList {
+    rules: Vec<AclRule>,
+}
+
+impl AccessList {
+    /// Create a list with `rules`, keeping at most `MAX_RULES`
+    pub fn new(mut rules: Vec<AclRule>) -> Self {
+        rules.truncate(MAX_RULES);
+        Self { rules }
+    }
+
+    /// Get the rules in the order they are checked
+    pub fn rules(&self) -> &[AclRule] {
+        &self.rules
+    }
+
+    /// Parse rules in the format of `Display`, e.g. "allow 192.168.1.0/24, deny 10.0.0.5"
+    ///
+    /// An empty string is the empty list.
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let mut list = Self::default();
+        for rule in spec.split(',').map(str::trim).filter(|rule| !rule.is_empty()) {
+            let parsed = rule
+                .split_once(' ')
+                .and_then(|(action, address)| AclRule::parse(action, address))
+                .ok_or_else(|| format!("Invalid rule: {} (use e.g. allow 192.168.1.0/24)", rule))?;
+            list.add(parsed)?;
+        }
+        Ok(list)
+    }
+
+    /// Check whether the list has no rules, admitting every peer
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Check whether a peer with address `ip` may connect
+    ///
+    /// IPv6 peers only match IPv4 rules through IPv4-mapped addresses.
+    pub fn is_allowed(&self, ip: IpAddr) -> bool {
+        let ip = match ip {
+            IpAddr::V4(ip) => Some(ip),
+            IpAddr::V6(ip) => ip.to_ipv4_mapped(),
+        };
+        let matched = ip.and_then(|ip| self.rules.iter().find(|rule| rule.matches(ip)));
+        match matched {
+            Some(rule) => rule.action == AclAction::Allow,
+            None => !self.rules.iter().any(|rule| rule.action == AclAction::Allow),
+        }
+    }
+
+    /// Append a rule
+    pub fn add(&mut self, rule: AclRule) -> std::result::Result<(), String> {
+        if self.rules.contains(&rule) {
+            return Err(format!("Rule already present: {}", rule));
+        }
+        if self.rules.len() >= MAX_RULES {
+            return Err(format!("Access list is full ({} rules)", MAX_RULES));
+        }
+        self.rules.push(rule);
+        Ok(())
+    }
+
+    /// Remove the rule at 1-based `position`, as listed by AT+ACL?
+    pub fn remove(&mut self, position: usize) -> Option<AclRule> {
+        (1..=self.rules.len())
+            .contains(&position)
+            .then(|| self.rules.remove(position - 1))
+    }
+
+    /// Remove every rule
+    pub fn clear(&mut self) {
+        self.rules.clear();
+    }
+
+    /// Encode as 6 bytes per rule: action, network address and prefix length
+    pub fn encode(&self) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.rules.len() * ENCODED_RULE_LEN);
+        for rule in &self.rules {
+            bytes.push(match rule.action {
+                AclAction::Allow => 1,
+                AclAction::Deny => 0,
+            });
+            bytes.extend_from_slice(&rule.network.octets());
+            bytes.push(rule.prefix_len);
+        }
+        bytes
+    }
+
+    /// Decode bytes written by `encode`, None if they are malformed
+    pub fn decode(bytes: &[u8]) -> Option<Self> {
+        let chunks = bytes.chunks_exact(ENCODED_RULE_LEN);
+        if !chunks.remainder().is_empty() {
+            return None;
+        }
+        let rules = chunks
+            .map(|rule| {
+                let action = match rule[0] {
+                    1 => AclAction::Allow,
+                    0 => AclAction::Deny,
+                    _ => return None,
+                };
+                AclRule::new(action, Ipv4Addr::new(rule[1], rule[2], rule[3], rule[4]), rule[5])
+            })
+            .collect::<Option<Vec<_>>>()?;
+        Some(Self::new(rules))
+    }
+}
+
+impl fmt::Display for AccessList {
+    /// Formats as the rules separated by ", ", e.g. "allow 192.168.1.0/24, deny 10.0.0.5"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (index, rule) in self.rules.iter().enumerate() {
+            if index > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", rule)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+
+    fn ip(address: &str) -> IpAddr {
+        address.parse().unwrap()
+    }
+
+    #[test]
+    fn rules_are_parsed_and_formatted() {
+        let list = AccessList::parse(" allow 192.168.1.77/24,DENY 10.0.0.5 ,").unwrap();
+        assert_eq!(list.rules().len(), 2);
+        // 主机位被清零
+        assert_eq!(list.rules()[0].network, Ipv4Addr::new(192, 168, 1, 0));
+        assert_eq!(list.to_string(), "allow 192.168.1.0/24, deny 10.0.0.5");
+        assert_eq!(AccessList::parse("").unwrap(), AccessList::default());
+
+        for spec in ["permit 10.0.0.1", "allow", "allow 10.0.0.256", "allow 10.0.0.0/33"] {
+            assert!(AccessList::parse(spec).is_err(), "{} was accepted", spec);
+        }
+        assert!(AccessList::parse("deny 10.0.0.5, deny 10.0.0.5").is_err());
+    }
+
+    #[test]
+    fn first_matching_rule_decides() {
+        let list = AccessList::parse("deny 192.168.1.13, allow 192.168.1.0/24").unwrap();
+        assert!(list.is_allowed(ip("192.168.1.12")));
+        assert!(!list.is_allowed(ip("192.168.1.13")));
+        // 有允许规则时，不匹配的对端被拒绝
+        assert!(!list.is_allowed(ip("10.0.0.1")));
+        assert!(list.is_allowed(IpAddr::V6(Ipv4Addr::new(192, 168, 1, 2).to_ipv6_mapped())));
+        assert!(!list.is_allowed(IpAddr::V6(Ipv6Addr::LOCALHOST)));
+    }
+
+    #[test]
+    fn deny_rules_only_block_their_peers() {
+        let list = AccessList::parse("deny 10.0.0.0/8").unwrap();
+        assert!(!list.is_allowed(ip("10.1.2.3")));
+        assert!(list.is_allowed(ip("192.168.1.2")));
+        assert!(AccessList::default().is_allowed(ip("10.1.2.3")));
+        assert!(AccessList::parse("allow 0.0.0.0/0").unwrap().is_allowed(ip("8.8.8.8")));
+    }
+
+    #[test]
+    fn rules_are_added_and_removed_by_position() {
+        let mut list = AccessList::default();
+        for host in 0..MAX_RULES as u8 {
+            let rule = AclRule::new(AclAction::Deny, Ipv4Addr::new(10, 0, 0, host), 32).unwrap();
+            list.add(rule).unwrap();
+        }
+        let extra = AclRule::parse("allow", "10.0.1.0/24").unwrap();
+        assert!(list.add(extra).is_err());
+
+        assert_eq!(list.remove(0), None);
+        assert_eq!(list.remove(MAX_RULES + 1), None);
+        assert_eq!(list.remove(1).map(|rule| rule.to_string()), Some("deny 10.0.0.0".to_string()));
+        list.add(extra).unwrap();
+        assert_eq!(list.rules().last(), Some(&extra));
+        list.clear();
+        assert!(list.is_empty());
+    }
+
+    #[test]
+    fn encoded_list_decodes_to_the_same_rules() {
+        let list = AccessList::parse("allow 192.168.1.0/24, deny 10.0.0.5, allow 0.0.0.0/0").unwrap();
+        let bytes = list.encode();
+        assert_eq!(bytes.len(), 3 * ENCODED_RULE_LEN);
+        assert_eq!(AccessList::decode(&bytes), Some(list));
+        assert_eq!(AccessList::decode(&[]), Some(AccessList::default()));
+
+        assert_eq!(AccessList::decode(&bytes[..5]), None);
+        assert_eq!(AccessList::decode(&[2, 10, 0, 0, 5, 32]), None);
+        assert_eq!(AccessList::decode(&[1, 10, 0, 0, 5, 33]), None);
+    }
+}
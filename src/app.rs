@@ -0,0 +1,561 @@
+//! Application module
+//!
+//! This module wires storage, WiFi, the UART bridges and the optional services
+//! together, so the crate can be embedded in other firmware next to its own tasks.
+//! `App::new` sets everything up, `App::start` spawns the forwarding and service
+//! threads and `App::stop` shuts the services down again. The accessors give an
+//! integration the managers, e.g. to inject data with `UartManager::send_data`.
+
+use esp_idf_hal::peripherals::Peripherals;
+use esp_idf_hal::rmt::CHANNEL0;
+use esp_idf_hal::uart::{UART0, UART1};
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use crate::boot_info;
+use crate::config::{AppConfig, BridgeConfig, TcpServerConfig, UartConfig};
+use crate::device_name;
+use crate::diagnostics;
+use crate::error::{Error, Result};
+use crate::gpio_control::GpioControl;
+use crate::http_server::HttpServer;
+use crate::log_level::{self, LogLevels};
+use crate::mdns::MdnsAdvertiser;
+use crate::mqtt_bridge::MqttBridge;
+use crate::reset_button::ResetButton;
+use crate::status_led::{DeviceStatus, StatusLed};
+use crate::storage::StorageManager;
+use crate::tcp_client_manager::TcpClientManager;
+use crate::tcp_client_mode::TcpClientMode;
+use crate::tcp_server::{Bridge, TcpServer};
+use crate::uart::UartManager;
+use crate::udp_bridge::UdpBridge;
+use crate::watchdog;
+use crate::wifi::WiFiManager;
+
+/// Name of the bridge of `AppConfig::uart` in AT+STATUS and the log
+pub const MAIN_BRIDGE_NAME: &str = "main";
+
+/// Peripherals used by the application
+///
+/// The other peripherals stay free for the embedding firmware.
+pub struct AppPeripherals {
+    /// UART0, used by a bridge with `uart_num: 0`
+    pub uart0: UART0,
+    /// UART1, used by a bridge with `uart_num: 1`
+    pub uart1: UART1,
+    /// RMT channel driving a WS2812 status LED
+    pub rmt_channel0: CHANNEL0,
+}
+
+impl From<Peripherals> for AppPeripherals {
+    fn from(peripherals: Peripherals) -> Self {
+        Self {
+            uart0: peripherals.uart0,
+            uart1: peripherals.uart1,
+            rmt_channel0: peripherals.rmt.channel0,
+        }
+    }
+}
+
+/// UART peripherals not yet taken by a bridge
+struct UartPeripherals {
+    uart0: Option<UART0>,
+    uart1: Option<UART1>,
+}
+
+impl UartPeripherals {
+    /// Create the UART manager of a bridge on the UART selected by `config.uart_num`
+    fn create(
+        &mut self,
+        config: UartConfig,
+        storage: Option<Arc<Mutex<StorageManager>>>,
+    ) -> Result<UartManager> {
+        let taken = || Error::uart(format!("UART{} is used by more than one bridge", config.uart_num));
+        match config.uart_num {
+            0 => UartManager::new(self.uart0.take().ok_or_else(taken)?, config, storage),
+            1 => UartManager::new(self.uart1.take().ok_or_else(taken)?, config, storage),
+            n => Err(Error::uart(format!("Unsupported UART number: {}", n))),
+        }
+    }
+}
+
+/// Create the UART and client manager of an additional bridge
+///
+/// Its settings are not saved, so the saved settings of the main bridge are not
+/// overwritten. Only its data port is served.
+fn create_extra_bridge(
+    config: BridgeConfig,
+    uarts: &mut UartPeripherals,
+    server_config: &TcpServerConfig,
+) -> Result<(Bridge, TcpServerConfig)> {
+    let uart_manager = Arc::new(uarts.create(config.uart, None)?);
+    let client_manager = Arc::new(TcpClientManager::with_queue_limit(server_config.client_queue_limit));
+    info!("Bridge {} created", config.name);
+
+    let server_config = TcpServerConfig {
+        port: config.port,
+        control_port: None,
+        rfc2217_port: None,
+        websocket_port: None,
+        ..server_config.clone()
+    };
+    let bridge = Bridge {
+        name: config.name,
+        port: config.port,
+        uart_manager,
+        client_manager,
+    };
+    Ok((bridge, server_config))
+}
+
+/// Spawn a named thread running `body`, logging the error it returns
+fn spawn_service(
+    name: &str,
+    stack_size: usize,
+    body: impl FnOnce() -> Result<()> + Send + 'static,
+) -> Result<JoinHandle<()>> {
+    let thread_name = name.to_string();
+    thread::Builder::new()
+        .name(name.into())
+        .stack_size(stack_size)
+        .spawn(move || {
+            if let Err(e) = body() {
+                error!("{} error: {:?}", thread_name, e);
+            }
+        })
+        .map_err(|e| Error::General(format!("Failed to spawn {} thread: {}", name, e)))
+}
+
+/// The UART to TCP bridge application
+pub struct App {
+    config: AppConfig,
+    storage: Option<Arc<Mutex<StorageManager>>>,
+    status: Arc<DeviceStatus>,
+    wifi_manager: Arc<Mutex<WiFiManager>>,
+    mdns: Option<Arc<Mutex<MdnsAdvertiser>>>,
+    /// Every bridge, the main bridge first
+    bridges: Vec<Bridge>,
+    /// TCP server of each bridge, in the order of `bridges`
+    servers: Vec<Arc<TcpServer>>,
+    udp_bridge: Option<Arc<UdpBridge>>,
+    client_link: Option<Arc<TcpClientMode>>,
+    mqtt: Option<Arc<MqttBridge>>,
+    http_server: Option<HttpServer>,
+    /// Whether `start` has been called
+    started: bool,
+}
+
+impl App {
+    /// Set up storage, WiFi, the UART bridges and the optional services
+    ///
+    /// Blocks until WiFi is up. Nothing is forwarded before `start`.
+    pub fn new(peripherals: AppPeripherals, config: AppConfig) -> Result<Self> {
+        diagnostics::init(&config.memory);
+        // Initialize storage shared by all managers
+        let storage = match StorageManager::new() {
+            Ok(storage) => Some(Arc::new(Mutex::new(storage))),
+            Err(e) => {
+                warn!("Failed to initialize storage manager: {}, settings will not be persisted", e);
+                None
+            }
+        };
+
+        // 记录启动次数和复位原因，存储不可用时也继续启动
+        boot_info::record(storage.as_ref());
+
+        // 优先使用AT+NAME保存的设备名称
+        let name = storage
+            .as_ref()
+            .and_then(|storage| storage.lock().ok()?.read_device_name())
+            .unwrap_or_else(|| config.device_name.clone());
+        if let Err(e) = device_name::set(&name) {
+            warn!("{}, using the default name", e);
+        }
+        info!("Device name: {}", device_name::get());
+
+        // 恢复保存的日志级别，便于跨重启排查问题
+        let saved_log_levels = storage
+            .as_ref()
+            .and_then(|storage| storage.lock().ok()?.read_log_levels());
+        if let Some(spec) = saved_log_levels {
+            match LogLevels::parse(&spec).map_err(Error::General).and_then(|levels| log_level::apply(&levels)) {
+                Ok(_) => info!("Restored log levels {}", spec),
+                Err(e) => warn!("Failed to restore log levels {}: {}", spec, e),
+            }
+        }
+
+        // 转发线程和服务线程启动前配置看门狗
+        if let Err(e) = watchdog::init(&config.watchdog) {
+            warn!("Failed to configure the thread watchdog: {}", e);
+        }
+
+        // 尽早启动复位按键监视，WiFi配置错误时也能恢复出厂设置
+        if let Err(e) = ResetButton::start(&config.reset_button, storage.clone()) {
+            warn!("Failed to start factory reset button: {}", e);
+        }
+
+        // 状态灯在WiFi启动期间慢闪
+        let status = Arc::new(DeviceStatus::new());
+        if let Err(e) = StatusLed::start(&config.status_led, Arc::clone(&status), peripherals.rmt_channel0) {
+            warn!("Failed to start status LED: {}", e);
+        }
+
+        // 优先显示保存在flash中的端口
+        let tcp_port = storage
+            .as_ref()
+            .and_then(|storage| storage.lock().ok()?.read_tcp_port())
+            .unwrap_or(config.tcp_server.port);
+
+        // Initialize WiFi
+        let mut wifi_manager = WiFiManager::new(config.wifi.clone(), storage.as_ref())?;
+        info!("WiFi manager created");
+
+        // Configure and start WiFi
+        wifi_manager.configure()?;
+        wifi_manager.start()?;
+
+        // WiFi已经在start方法中等待初始化完成
+        info!("WiFi initialization complete");
+        wifi_manager.log_connection_info(tcp_port);
+        wifi_manager.set_status(Arc::clone(&status));
+
+        // 通过mDNS广播主机名和TCP服务，失败时不影响其他功能
+        let mdns = match MdnsAdvertiser::new(&device_name::get(), tcp_port) {
+            Ok(mdns) => Some(Arc::new(Mutex::new(mdns))),
+            Err(e) => {
+                warn!("Failed to start mDNS: {}, the device is only reachable by IP", e);
+                None
+            }
+        };
+
+        // 共享WiFi管理器，以便通过TCP命令修改配置
+        let wifi_manager = Arc::new(Mutex::new(wifi_manager));
+        // 后台线程在STA断开后自动重连
+        WiFiManager::start_reconnect(Arc::clone(&wifi_manager))?;
+
+        // Create shared TCP client manager
+        let client_manager = Arc::new(TcpClientManager::with_queue_limit(
+            config.tcp_server.client_queue_limit,
+        ));
+        client_manager.set_status(Arc::clone(&status));
+        info!("TCP client manager created");
+
+        // Initialize UART
+        let mut uarts = UartPeripherals {
+            uart0: Some(peripherals.uart0),
+            uart1: Some(peripherals.uart1),
+        };
+        let uart_manager = Arc::new(uarts.create(config.uart.clone(), storage.clone())?);
+        info!("UART manager created");
+
+        // 其他串口桥各自使用独立的串口、客户端管理器和端口，某个桥失败时不影响其余的桥
+        let mut bridges = vec![Bridge {
+            name: MAIN_BRIDGE_NAME,
+            port: tcp_port,
+            uart_manager: Arc::clone(&uart_manager),
+            client_manager: Arc::clone(&client_manager),
+        }];
+        let mut extra_server_configs = Vec::with_capacity(config.bridges.len());
+        for bridge_config in config.bridges.iter().cloned() {
+            let name = bridge_config.name;
+            match create_extra_bridge(bridge_config, &mut uarts, &config.tcp_server) {
+                Ok((bridge, server_config)) => {
+                    bridges.push(bridge);
+                    extra_server_configs.push(server_config);
+                }
+                Err(e) => warn!("Failed to create bridge {}: {}", name, e),
+            }
+        }
+
+        // 主动连接远端主机（可与服务器同时运行）
+        let client_link = config.tcp_client.enabled.then(|| {
+            Arc::new(TcpClientMode::new(
+                config.tcp_client.clone(),
+                Arc::clone(&client_manager),
+                Arc::clone(&uart_manager),
+                config.tcp_server.buffer_size,
+            ))
+        });
+
+        // 把串口数据发布到MQTT代理服务器（可与TCP客户端同时使用）
+        let mqtt = if config.mqtt.enabled {
+            Some(Arc::new(MqttBridge::new(
+                config.mqtt.clone(),
+                &device_name::get(),
+                Arc::clone(&client_manager),
+                Arc::clone(&uart_manager),
+            )))
+        } else {
+            None
+        };
+
+        // 主串口桥的TCP服务器提供所有服务
+        let mut tcp_server = TcpServer::new(
+            config.tcp_server.clone(),
+            Arc::clone(&client_manager),
+            Arc::clone(&uart_manager),
+            Some(Arc::clone(&wifi_manager)),
+            storage.clone(),
+        );
+        if let Some(link) = &client_link {
+            tcp_server.set_client_link(Arc::clone(link));
+        }
+        if let Some(mdns) = &mdns {
+            tcp_server.set_mdns(Arc::clone(mdns));
+        }
+        if let Some(mqtt) = &mqtt {
+            tcp_server.set_mqtt(Arc::clone(mqtt));
+        }
+        tcp_server.set_status(Arc::clone(&status));
+        // 目标板的复位/BOOT控制线，初始化失败时只是不能使用AT+GPIO
+        if !config.gpio.pins.is_empty() {
+            match GpioControl::new(&config.gpio) {
+                Ok(gpio) => tcp_server.set_gpio(Arc::new(gpio)),
+                Err(e) => warn!("Failed to set up GPIO control: {}", e),
+            }
+        }
+        // 只有一个串口桥时保持原来的状态报告格式
+        if bridges.len() > 1 {
+            tcp_server.set_bridges(bridges.clone());
+        }
+
+        // 可选的UDP桥接，仅在启用时绑定端口，与TCP服务器使用同一个访问控制列表
+        let udp_bridge = if config.udp.enabled {
+            Some(Arc::new(UdpBridge::bind(
+                config.udp.clone(),
+                Arc::clone(&uart_manager),
+                tcp_server.access_list(),
+            )?))
+        } else {
+            None
+        };
+
+        let mut servers = vec![Arc::new(tcp_server)];
+        for (bridge, server_config) in bridges.iter().skip(1).zip(extra_server_configs) {
+            let mut bridge_server = TcpServer::new(
+                server_config,
+                Arc::clone(&bridge.client_manager),
+                Arc::clone(&bridge.uart_manager),
+                Some(Arc::clone(&wifi_manager)),
+                None,
+            );
+            bridge_server.set_bridges(bridges.clone());
+            servers.push(Arc::new(bridge_server));
+        }
+
+        Ok(Self {
+            config,
+            storage,
+            status,
+            wifi_manager,
+            mdns,
+            bridges,
+            servers,
+            udp_bridge,
+            client_link,
+            mqtt,
+            http_server: None,
+            started: false,
+        })
+    }
+
+    /// Start UART forwarding and spawn the threads of the TCP servers and services
+    ///
+    /// Returns the handles of the spawned service threads. The UART forwarding
+    /// threads are not among them, they run for the lifetime of the device.
+    pub fn start(&mut self) -> Result<Vec<JoinHandle<()>>> {
+        if self.started {
+            return Err(Error::General("Application already started".to_string()));
+        }
+        self.started = true;
+
+        // Start UART forwarding service
+        for (index, bridge) in self.bridges.iter().enumerate() {
+            // UDP接收方只属于主串口桥
+            let udp_peers = self
+                .udp_bridge
+                .as_ref()
+                .filter(|_| index == 0)
+                .map(|udp| udp.peers());
+            UartManager::start_forwarding(
+                Arc::clone(&bridge.uart_manager),
+                Arc::clone(&bridge.client_manager),
+                udp_peers,
+            )?;
+        }
+        info!("UART forwarding service started");
+
+        let mut handles = Vec::new();
+        if let Some(udp) = &self.udp_bridge {
+            let udp = Arc::clone(udp);
+            handles.push(spawn_service("udp_bridge", 4096, move || udp.run())?);
+        }
+
+        // 使用命名线程和更大的栈空间，某个附加串口桥启动失败时不影响其余的桥
+        for (index, (bridge, server)) in self.bridges.iter().zip(&self.servers).enumerate() {
+            info!("Starting TCP server of bridge {} on port {}...", bridge.name, bridge.port);
+            let server = Arc::clone(server);
+            match spawn_service("tcp_server", 8192, move || server.run()) {
+                Ok(handle) => handles.push(handle),
+                Err(e) if index > 0 => {
+                    warn!("Failed to start TCP server of bridge {}: {}", bridge.name, e);
+                }
+                Err(e) => return Err(e),
+            }
+        }
+
+        // 给TCP服务器时间启动
+        thread::sleep(Duration::from_millis(100));
+        info!("TCP server started and ready for connections");
+
+        // 网页配置界面和状态接口，启动失败时不影响数据转发
+        if self.config.http.enabled {
+            match HttpServer::start(
+                &self.config.http,
+                Arc::clone(&self.servers[0]),
+                Arc::clone(self.uart_manager()),
+                Arc::clone(self.client_manager()),
+                Arc::clone(&self.wifi_manager),
+                self.storage.clone(),
+                self.tcp_port(),
+            ) {
+                Ok(server) => self.http_server = Some(server),
+                Err(e) => {
+                    warn!("Failed to start HTTP server: {}, the configuration page is not available", e);
+                }
+            }
+        }
+
+        if let Some(link) = &self.client_link {
+            let link = Arc::clone(link);
+            handles.push(spawn_service("tcp_client", 8192, move || link.run())?);
+        }
+
+        if let Some(mqtt) = &self.mqtt {
+            let mqtt = Arc::clone(mqtt);
+            handles.push(spawn_service("mqtt_bridge", 6144, move || mqtt.run())?);
+        }
+
+        self.log_summary();
+        Ok(handles)
+    }
+
+    /// Stop the TCP servers, the connect-out client, the MQTT bridge and the HTTP server
+    ///
+    /// Their threads return shortly after. UART forwarding and the UDP bridge keep
+    /// running; the application cannot be started again.
+    pub fn stop(&mut self) {
+        info!("Stopping application services");
+        for server in &self.servers {
+            server.stop();
+        }
+        if let Some(link) = &self.client_link {
+            link.stop();
+        }
+        if let Some(mqtt) = &self.mqtt {
+            mqtt.stop();
+        }
+        // 释放HTTP服务器即停止其任务
+        self.http_server = None;
+    }
+
+    /// Log where the services can be reached
+    fn log_summary(&self) {
+        info!("==================================================");
+        info!("{} is running with TCP server and UART forwarding service", device_name::get());
+        info!("TCP Server Port: {}", self.tcp_port());
+        for bridge in self.bridges.iter().skip(1) {
+            info!(
+                "Bridge {}: TCP port {}, UART baudrate {}",
+                bridge.name,
+                bridge.port,
+                bridge.uart_manager.get_baudrate()
+            );
+        }
+        if let Some(mdns) = &self.mdns {
+            if let Ok(mdns) = mdns.lock() {
+                info!("mDNS: {}.local", mdns.hostname());
+            }
+        }
+        if let Some(link) = &self.client_link {
+            info!("Connect-out client: {}", link.remote());
+        }
+        if let Some(mqtt) = &self.mqtt {
+            info!("MQTT broker: {}", mqtt.broker());
+        }
+        if self.config.udp.enabled {
+            info!("UDP Bridge Port: {}", self.config.udp.port);
+        }
+        if self.http_server.is_some() {
+            info!("Configuration page: http://<device IP>:{}/", self.config.http.port);
+        }
+        info!(
+            "UART Baudrate: {} (can be changed via TCP commands)",
+            self.uart_manager().get_baudrate()
+        );
+        info!("Use AT+HELP command to see available commands");
+        info!("==================================================");
+    }
+
+    /// Get the application configuration
+    pub fn config(&self) -> &AppConfig {
+        &self.config
+    }
+
+    /// Get the data port of the main bridge (the saved port if one was saved)
+    pub fn tcp_port(&self) -> u16 {
+        self.bridges[0].port
+    }
+
+    /// Get every bridge, the main bridge first
+    pub fn bridges(&self) -> &[Bridge] {
+        &self.bridges
+    }
+
+    /// Get the UART manager of the main bridge
+    pub fn uart_manager(&self) -> &Arc<UartManager> {
+        &self.bridges[0].uart_manager
+    }
+
+    /// Get the client manager of the main bridge
+    pub fn client_manager(&self) -> &Arc<TcpClientManager> {
+        &self.bridges[0].client_manager
+    }
+
+    /// Get the WiFi manager
+    pub fn wifi_manager(&self) -> &Arc<Mutex<WiFiManager>> {
+        &self.wifi_manager
+    }
+
+    /// Get the storage manager (None if flash storage is not available)
+    pub fn storage(&self) -> Option<&Arc<Mutex<StorageManager>>> {
+        self.storage.as_ref()
+    }
+
+    /// Get the device status shown by the status LED
+    pub fn status(&self) -> &Arc<DeviceStatus> {
+        &self.status
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc;
+
+    #[test]
+    fn services_run_on_named_threads_and_errors_end_only_the_thread() {
+        let (tx, rx) = mpsc::channel();
+        let handle = spawn_service("probe", 16 * 1024, move || {
+            tx.send(thread::current().name().map(str::to_string)).unwrap();
+            Err(Error::General("stopped".to_string()))
+        })
+        .unwrap();
+        // 服务返回的错误只记录日志，不会让线程panic
+        assert!(handle.join().is_ok());
+        assert_eq!(rx.recv().unwrap().as_deref(), Some("probe"));
+    }
+}
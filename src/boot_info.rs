@@ -0,0 +1,297 @@
+//! Boot information module
+//!
+//! This module records why and how often the device starts, for the boot log and
+//! AT+BOOTINFO: the reset reason reported by ESP-IDF, a boot counter kept in NVS and
+//! the message of the last panic, which a panic hook writes to NVS before the device
+//! restarts.
+//!
+//! Flash storage is optional; without it the counter and the panic message are
+//! simply not available.
+
+use log::{info, warn};
+use std::fmt;
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::error::{Error, Result};
+use crate::storage::{self, StorageManager};
+use crate::time;
+
+/// Boot information recorded by `record`
+static BOOT_INFO: OnceLock<BootInfo> = OnceLock::new();
+
+/// Storage used by `reset` and the panic hook
+static STORAGE: OnceLock<Arc<Mutex<StorageManager>>> = OnceLock::new();
+
+/// Reason of the last reset as reported by `esp_reset_reason`
+///
+/// The host build has no reset reason and always reports 0 (`ESP_RST_UNKNOWN`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ResetReason(pub u32);
+
+impl ResetReason {
+    /// Read the reason of the last reset
+    #[cfg(target_os = "espidf")]
+    pub fn current() -> Self {
+        Self(unsafe { esp_idf_sys::esp_reset_reason() })
+    }
+
+    /// Read the reason of the last reset
+    #[cfg(not(target_os = "espidf"))]
+    pub fn current() -> Self {
+        Self(0)
+    }
+
+    /// Short description like "power on" or "task watchdog"
+    #[cfg(target_os = "espidf")]
+    pub fn description(self) -> &'static str {
+        match self.0 {
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_POWERON => "power on",
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_SW => "software restart",
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC => "panic",
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT => "interrupt watchdog",
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT => "task watchdog",
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT => "watchdog",
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_BROWNOUT => "brownout",
+            _ => "other",
+        }
+    }
+
+    /// Short description like "power on" or "task watchdog"
+    #[cfg(not(target_os = "espidf"))]
+    pub fn description(self) -> &'static str {
+        "unknown"
+    }
+
+    /// Whether the device reset because of a crash or a watchdog
+    #[cfg(target_os = "espidf")]
+    pub fn is_fault(self) -> bool {
+        matches!(
+            self.0,
+            esp_idf_sys::esp_reset_reason_t_ESP_RST_PANIC
+                | esp_idf_sys::esp_reset_reason_t_ESP_RST_INT_WDT
+                | esp_idf_sys::esp_reset_reason_t_ESP_RST_TASK_WDT
+                | esp_idf_sys::esp_reset_reason_t_ESP_RST_WDT
+        )
+    }
+
+    /// Whether the device reset because of a crash or a watchdog
+    #[cfg(not(target_os = "espidf"))]
+    pub fn is_fault(self) -> bool {
+        false
+    }
+}
+
+impl fmt::Display for ResetReason {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (reason {})", self.description(), self.0)
+    }
+}
+
+/// What is known about the current boot
+#[derive(Debug, Clone)]
+pub struct BootInfo {
+    /// Reason of the reset that started this boot
+    pub reset_reason: ResetReason,
+    /// Number of boots including this one, None without flash storage
+    pub boot_count: Option<u32>,
+    /// Message of the last panic stored in flash
+    pub last_panic: Option<String>,
+}
+
+impl BootInfo {
+    /// Report of AT+BOOTINFO in the "Key: value" format of AT+STATUS
+    pub fn report(&self) -> String {
+        let uptime = time::uptime();
+        let mut report = format!(
+            "Boot count: {}\r\nLast reset: {}\r\nUptime: {} s ({})\r\n",
+            self.boot_count
+                .map_or_else(|| "unknown".to_string(), |count| count.to_string()),
+            self.reset_reason,
+            uptime.as_secs(),
+            time::format_duration(uptime)
+        );
+        if let Some(message) = &self.last_panic {
+            report.push_str(&format!("Last panic: {}\r\n", message));
+        }
+        report
+    }
+}
+
+/// Count this boot, log the reset reason and install the panic hook
+///
+/// Failing storage writes are logged and otherwise ignored, so startup never
+/// depends on NVS. Only the first call has an effect.
+pub fn record(storage: Option<&Arc<Mutex<StorageManager>>>) {
+    if BOOT_INFO.get().is_some() {
+        return;
+    }
+
+    let reset_reason = ResetReason::current();
+    let mut boot_count = None;
+    let mut last_panic = None;
+    if let Some(storage) = storage {
+        match storage.lock() {
+            Ok(mut storage) => {
+                let count = match storage.get_u32(storage::BOOT_COUNT_KEY) {
+                    Ok(count) => count.unwrap_or(0).wrapping_add(1),
+                    Err(e) => {
+                        warn!("Failed to read boot counter: {}", e);
+                        1
+                    }
+                };
+                match storage.set_u32(storage::BOOT_COUNT_KEY, count) {
+                    Ok(()) => boot_count = Some(count),
+                    Err(e) => warn!("Failed to save boot counter: {}", e),
+                }
+                last_panic = read_last_panic(&storage);
+            }
+            Err(_) => warn!("Failed to lock storage manager, boot not counted"),
+        }
+        let _ = STORAGE.set(Arc::clone(storage));
+        install_panic_hook();
+    }
+
+    if reset_reason.is_fault() {
+        warn!("Last reset: {}", reset_reason);
+    } else {
+        info!("Last reset: {}", reset_reason);
+    }
+    match boot_count {
+        Some(count) => info!("Boot number {}", count),
+        None => info!("Boot counter not available"),
+    }
+    if let Some(message) = &last_panic {
+        warn!("Last panic: {}", message);
+    }
+
+    let _ = BOOT_INFO.set(BootInfo {
+        reset_reason,
+        boot_count,
+        last_panic,
+    });
+}
+
+/// Get the information recorded for this boot
+///
+/// Returns None before `record` was called.
+pub fn current() -> Option<BootInfo> {
+    BOOT_INFO.get().cloned().map(|mut info| {
+        // 计数器可能已被AT+BOOTINFO=RESET清除
+        if let Some(storage) = STORAGE.get() {
+            if let Ok(storage) = storage.lock() {
+                if let Ok(count) = storage.get_u32(storage::BOOT_COUNT_KEY) {
+                    info.boot_count = Some(count.unwrap_or(0));
+                }
+                info.last_panic = read_last_panic(&storage);
+            }
+        }
+        info
+    })
+}
+
+/// Clear the boot counter and the stored panic message (AT+BOOTINFO=RESET)
+pub fn reset() -> Result<()> {
+    let storage = STORAGE
+        .get()
+        .ok_or_else(|| Error::StorageError("Flash storage not available".to_string()))?;
+    let mut storage = storage
+        .lock()
+        .map_err(|_| Error::StorageError("Failed to lock storage manager".to_string()))?;
+    storage.remove(storage::BOOT_COUNT_KEY)?;
+    storage.remove(storage::LAST_PANIC_KEY)?;
+    info!("Boot counter cleared");
+    Ok(())
+}
+
+/// Restart the device
+#[cfg(target_os = "espidf")]
+pub fn restart() -> ! {
+    unsafe { esp_idf_sys::esp_restart() }
+}
+
+/// End the process, the closest the host build has to a restart
+#[cfg(not(target_os = "espidf"))]
+pub fn restart() -> ! {
+    std::process::exit(0)
+}
+
+/// Read the stored panic message, None if there is none or it cannot be read
+fn read_last_panic(storage: &StorageManager) -> Option<String> {
+    storage
+        .get_str::<{ storage::MAX_PANIC_LEN }>(storage::LAST_PANIC_KEY)
+        .ok()
+        .flatten()
+        .map(|message| message.to_string())
+}
+
+/// Store the message of a panic in flash before the default hook runs
+fn install_panic_hook() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |panic_info| {
+        let message = truncate(panic_info.to_string(), storage::MAX_PANIC_LEN);
+        // 发生panic的线程可能正持有存储锁，不能阻塞等待
+        if let Some(storage) = STORAGE.get() {
+            if let Ok(mut storage) = storage.try_lock() {
+                let _ = storage.set_str(storage::LAST_PANIC_KEY, &message);
+            }
+        }
+        default_hook(panic_info);
+    }));
+}
+
+/// Cut `message` to at most `max` bytes without splitting a character
+fn truncate(mut message: String, max: usize) -> String {
+    if message.len() > max {
+        let mut end = max;
+        while !message.is_char_boundary(end) {
+            end -= 1;
+        }
+        message.truncate(end);
+    }
+    message
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn report_shows_the_counter_and_the_last_panic() {
+        let mut info = BootInfo {
+            reset_reason: ResetReason(0),
+            boot_count: Some(7),
+            last_panic: None,
+        };
+        let report = info.report();
+        assert!(report.starts_with("Boot count: 7\r\nLast reset: unknown (reason 0)\r\nUptime: "), "{}", report);
+        assert!(!report.contains("Last panic"), "{}", report);
+
+        // 没有闪存时计数器未知
+        info.boot_count = None;
+        info.last_panic = Some("panicked at src/uart.rs:10:5".to_string());
+        let report = info.report();
+        assert!(report.starts_with("Boot count: unknown\r\n"), "{}", report);
+        assert!(report.ends_with("s)\r\nLast panic: panicked at src/uart.rs:10:5\r\n"), "{}", report);
+    }
+
+    #[test]
+    fn long_panic_message_is_cut_at_a_character_boundary() {
+        assert_eq!(truncate("short".to_string(), 8), "short");
+        assert_eq!(truncate("abcdefghij".to_string(), 8), "abcdefgh");
+        // "é"占两个字节，不能从中间截断
+        assert_eq!(truncate("abcdefgé".to_string(), 8), "abcdefg");
+    }
+
+    #[test]
+    fn host_build_has_no_fault_reset() {
+        let reason = ResetReason::current();
+        assert!(!reason.is_fault());
+        assert_eq!(reason.to_string(), "unknown (reason 0)");
+    }
+
+    #[test]
+    fn reset_without_storage_fails() {
+        // 测试中从不调用record，所以没有存储
+        assert!(reset().unwrap_err().to_string().contains("Flash storage not available"));
+    }
+}
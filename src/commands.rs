@@ -0,0 +1,3630 @@
+//! AT command registry
+//!
+//! This module provides the table of AT commands consulted by the TCP server. Each
+//! command is a `CommandHandler` with a name, the lines it adds to AT+HELP and an
+//! `execute` method, so the help text is generated from the same table that
+//! dispatches commands and cannot go stale.
+//!
+//! Configuration forms such as AT+BAUD=<rate> are first turned into a `CommandPlan`
+//! by the handler's `plan` method, which has no side effects, so AT+VERIFY= can
+//! check them. The handler's `apply` method then makes the change.
+//!
+//! Command lines are brought into their canonical form by `normalize` first, so
+//! "at + baud = 9600" is handled as AT+BAUD=9600.
+//!
+//! # Reply format
+//!
+//! Every reply ends with a line that is exactly "OK", or "ERROR <code> <message>"
+//! with one of the `ErrorCode`s. Data lines before it have the form
+//! "+<NAME>: <value>", e.g. "+BAUD: Current baudrate: 115200". A client that sends
+//! AT+VERBOSE=1 gets the free-form text of earlier firmware instead. The AT+OTA=
+//! upload keeps its own progress lines.
+
+use log::{debug, info, warn};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+
+use crate::acl::{AccessList, AclRule};
+use crate::boot_info;
+use crate::config::{ApBandwidth, GpioDirection, SerialFormat, TcpToUartEol, UartToTcpEol, WiFiAuth};
+use crate::config_transfer;
+use crate::device_name;
+use crate::diagnostics::MemorySnapshot;
+use crate::error::{Error, Result};
+use crate::gpio_control::{self, GpioAction};
+use crate::log_level::{self, LogLevels};
+use crate::log_stream;
+use crate::ota::OtaStatus;
+use crate::self_test::{self, SelfTest};
+use crate::storage::{self, StorageManager};
+use crate::tcp_client_manager::{SharedStream, TcpClientManager};
+use crate::tcp_server::{CommandContext, CommandPlan, TcpServer};
+use crate::time;
+use crate::uart::{self, UartManager};
+use crate::version::VersionInfo;
+use crate::wifi::{self, StaConnectResult, WiFiManager};
+
+/// Largest payload of one AT+SEND or AT+SENDLN command in bytes
+const MAX_SEND_BYTES: usize = 128;
+
+/// Code of an "ERROR <code> <message>" reply
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum ErrorCode {
+    /// No command of that name
+    UnknownCommand = 1,
+    /// The arguments were rejected before anything was changed
+    InvalidArgument = 2,
+    /// The command was accepted but failed
+    Failed = 3,
+    /// The command line is not valid UTF-8
+    InvalidFormat = 4,
+}
+
+impl ErrorCode {
+    /// Every code, in the order AT+HELP lists them
+    const ALL: [ErrorCode; 4] = [
+        ErrorCode::UnknownCommand,
+        ErrorCode::InvalidArgument,
+        ErrorCode::Failed,
+        ErrorCode::InvalidFormat,
+    ];
+
+    /// Description listed by AT+HELP
+    fn description(self) -> &'static str {
+        match self {
+            ErrorCode::UnknownCommand => "unknown command",
+            ErrorCode::InvalidArgument => "invalid argument",
+            ErrorCode::Failed => "command failed",
+            ErrorCode::InvalidFormat => "invalid command format",
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", *self as u8)
+    }
+}
+
+/// What the server does after a command was executed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Response {
+    /// Send this reply to the client; lines starting with "OK: " or "ERROR: " report
+    /// the outcome, other lines are data
+    Reply(String),
+    /// Send an error reply to the client
+    Error(ErrorCode, String),
+    /// Send this reply to the client, then restart the device
+    Restart(String),
+    /// Send this reply to the client, then restart the access point
+    RestartAp(String),
+    /// Run this command line instead (AT+!)
+    Rerun(String),
+    /// The handler already replied on the connection, restarting the device if set
+    Sent { restart: bool },
+}
+
+/// A command sent by a client, as passed to a `CommandHandler`
+pub(crate) struct CommandRequest<'a> {
+    /// The whole command line, e.g. "AT+BAUD=115200"
+    pub(crate) command: &'a str,
+    /// Managers and state shared by every command
+    pub(crate) context: &'a CommandContext,
+    /// Client manager of the port the command was received on
+    pub(crate) client_manager: &'a Arc<TcpClientManager>,
+    /// Connection of the requesting client
+    pub(crate) stream_arc: &'a SharedStream,
+    /// Address of the requesting client
+    pub(crate) peer_addr: &'a SocketAddr,
+}
+
+impl CommandRequest<'_> {
+    /// Apply `args` of `handler` as a configuration change: plan it, then apply the plan
+    ///
+    /// Forms the handler does not plan get the unknown command reply.
+    pub(crate) fn apply<H: CommandHandler + ?Sized>(&self, handler: &H, args: &str) -> Response {
+        match handler.plan(args) {
+            Some(Ok(plan)) => {
+                info!("Processing configuration command from client {}", self.peer_addr);
+                Response::Reply(handler.apply(&plan, self))
+            }
+            Some(Err(msg)) => Response::Error(ErrorCode::InvalidArgument, msg),
+            None => unknown_command(self.command),
+        }
+    }
+}
+
+/// An AT command
+pub(crate) trait CommandHandler: Send + Sync {
+    /// Name of the command, e.g. "AT+BAUD"
+    fn name(&self) -> &'static str;
+
+    /// Lines listed by AT+HELP, e.g. "AT+BAUD?       - Query current UART baud rate"
+    fn help(&self) -> &'static [&'static str];
+
+    /// Whether the command with `args` goes on to read an upload from the connection
+    ///
+    /// Binary control requests have no byte stream to upload on, so such commands
+    /// are refused there.
+    fn reads_upload(&self, _args: &str) -> bool {
+        false
+    }
+
+    /// Whether the command with `args` carries a secret, e.g. a WiFi password
+    ///
+    /// Such command lines are never recorded in the command history.
+    fn is_secret(&self, _args: &str) -> bool {
+        false
+    }
+
+    /// Whether the command lines of this command go into the command history
+    ///
+    /// AT+HISTORY and AT+! are left out, so listing and replaying the history does
+    /// not change it.
+    fn is_recorded(&self) -> bool {
+        true
+    }
+
+    /// Answer a query form of the command, e.g. "?", or None for other forms
+    fn answer(&self, _args: &str, _request: &CommandRequest) -> Option<String> {
+        None
+    }
+
+    /// Validate a configuration form of the command, e.g. "=9600", and plan the change
+    ///
+    /// Returns None if `args` is not a configuration form, otherwise the planned change
+    /// or the error message the command would reply with. This has no side effects.
+    fn plan(&self, _args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        None
+    }
+
+    /// Make a change planned by `plan` and build the reply
+    fn apply(&self, plan: &CommandPlan, _request: &CommandRequest) -> String {
+        foreign_plan(self.name(), plan)
+    }
+
+    /// Execute the command; `args` is the rest of the line after the name, e.g. "?"
+    ///
+    /// The default answers query forms and applies configuration forms.
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        Ok(match self.answer(args, request) {
+            Some(answer) => Response::Reply(answer),
+            None => request.apply(self, args),
+        })
+    }
+}
+
+/// Bring a command line into its canonical form, e.g. "at + baud = 9600" to "AT+BAUD=9600"
+///
+/// The "AT+" prefix and the command name are upper-cased, and spaces around the
+/// prefix, the name and '=' and around the argument are removed. The argument keeps
+/// its case, so SSIDs and banners are not changed. Other lines are only trimmed.
+pub(crate) fn normalize(line: &str) -> String {
+    let line = line.trim();
+    let rest = line
+        .get(..2)
+        .filter(|at| at.eq_ignore_ascii_case("AT"))
+        .and_then(|_| line[2..].trim_start().strip_prefix('+'));
+    let Some(rest) = rest else {
+        return line.to_string();
+    };
+
+    let rest = rest.trim_start();
+    let name_len = rest
+        .find(|c: char| !(c.is_ascii_alphanumeric() || c == '!'))
+        .unwrap_or(rest.len());
+    let (name, args) = rest.split_at(name_len);
+    let args = args.trim_start();
+    let name = name.to_ascii_uppercase();
+    if let Some(value) = args.strip_prefix('=') {
+        format!("AT+{}={}", name, value.trim())
+    } else if args.is_empty() || args.starts_with('?') {
+        format!("AT+{}{}", name, args)
+    } else {
+        // 保留名称和参数之间的分隔，例如"AT+! 3"
+        format!("AT+{} {}", name, args)
+    }
+}
+
+/// Reply to a command no handler accepts
+pub(crate) fn unknown_command(cmd_str: &str) -> Response {
+    Response::Error(ErrorCode::UnknownCommand, format!("Unknown command: {}", cmd_str))
+}
+
+/// Format the reply of command `name` (e.g. "AT+BAUD") for a client
+///
+/// Verbose clients get `text` unchanged. Otherwise every line becomes a
+/// "+<NAME>: " data line, and the reply ends with "OK", or with "ERROR 3" and the
+/// first "ERROR: " line of `text`.
+pub(crate) fn format_reply(name: &str, text: &str, verbose: bool) -> String {
+    if verbose {
+        return text.to_string();
+    }
+    let tag = name.strip_prefix("AT+").unwrap_or(name);
+    let mut reply = String::new();
+    let mut error = None;
+    for line in text.lines().map(str::trim).filter(|line| !line.is_empty()) {
+        match line.strip_prefix("ERROR: ") {
+            Some(message) => {
+                error.get_or_insert(message);
+            }
+            None => {
+                let value = line.strip_prefix("OK: ").unwrap_or(line);
+                reply.push_str(&format!("+{}: {}\r\n", tag, value));
+            }
+        }
+    }
+    match error {
+        Some(message) => reply + &format_error(ErrorCode::Failed, message, false),
+        None => reply + "OK\r\n",
+    }
+}
+
+/// Format an error reply for a client
+pub(crate) fn format_error(code: ErrorCode, message: &str, verbose: bool) -> String {
+    match code {
+        ErrorCode::UnknownCommand if verbose => {
+            format!("ERROR: {}\r\nType AT+HELP for available commands\r\n", message)
+        }
+        _ if verbose => format!("ERROR: {}\r\n", message),
+        _ => format!("ERROR {} {}\r\n", code, message),
+    }
+}
+
+/// Table of the AT commands, in the order AT+HELP lists them
+pub(crate) struct CommandRegistry {
+    handlers: Vec<Box<dyn CommandHandler>>,
+}
+
+impl CommandRegistry {
+    /// Create a registry without commands
+    pub(crate) fn new() -> Self {
+        Self { handlers: Vec::new() }
+    }
+
+    /// Create a registry with every command of the TCP server
+    pub(crate) fn standard() -> Self {
+        let mut registry = Self::new();
+        registry.register(Box::new(Baud));
+        registry.register(Box::new(AutoBaud));
+        registry.register(Box::new(Uart));
+        registry.register(Box::new(Frame));
+        registry.register(Box::new(Delimiter));
+        registry.register(Box::new(Batch));
+        registry.register(Box::new(WiFiStation));
+        registry.register(Box::new(AccessPoint));
+        registry.register(Box::new(Country));
+        registry.register(Box::new(TxPower));
+        registry.register(Box::new(ApSecurity));
+        registry.register(Box::new(Reset));
+        registry.register(Box::new(FactoryReset));
+        registry.register(Box::new(ConfigExport));
+        registry.register(Box::new(ConfigImport));
+        registry.register(Box::new(Port));
+        registry.register(Box::new(Banner));
+        registry.register(Box::new(Acl));
+        registry.register(Box::new(Name));
+        registry.register(Box::new(Raw));
+        registry.register(Box::new(Echo));
+        registry.register(Box::new(Verbose));
+        registry.register(Box::new(MarkGaps));
+        registry.register(Box::new(Tap));
+        registry.register(Box::new(SocketOptions));
+        registry.register(Box::new(Log));
+        registry.register(Box::new(LogLevel));
+        registry.register(Query::always("AT+UPTIME", &["AT+UPTIME      - Show time since boot"], uptime));
+        registry.register(Query::always("AT+VERSION", &[
+            "AT+VERSION     - Show firmware, ESP-IDF and chip versions",
+        ], version));
+        registry.register(Box::new(Ota));
+        registry.register(Query::always("AT+RSSI", &[
+            "AT+RSSI        - Show the signal strength, channel and BSSID of the station's network",
+        ], sta_link));
+        registry.register(Query::always("AT+STATUS", &[
+            "AT+STATUS      - Show system, WiFi, UART and client state",
+        ], |request| TcpServer::status_report(request.context)));
+        registry.register(Box::new(Stats));
+        registry.register(Box::new(UartErrors));
+        registry.register(Box::new(BootInfo));
+        registry.register(Query::always("AT+MEM", &[
+            "AT+MEM         - Show free heap and thread stack headroom",
+        ], |_| MemorySnapshot::take().to_string()));
+        registry.register(Query::always("AT+CLIENTS", &[
+            "AT+CLIENTS     - List connected clients with their traffic",
+        ], clients));
+        registry.register(Box::new(Kick));
+        registry.register(Query::always("AT+STATIONS", &[
+            "AT+STATIONS    - List devices connected to the WiFi access point",
+        ], stations));
+        registry.register(Query::always("AT+SCAN", &[
+            "AT+SCAN        - List WiFi networks in range, strongest first",
+        ], scan));
+        registry.register(Box::new(Gpio));
+        registry.register(Box::new(TargetReset));
+        registry.register(Box::new(SendHex));
+        registry.register(Box::new(SendLine));
+        registry.register(Box::new(Test));
+        registry.register(Box::new(Lock));
+        registry.register(Box::new(Unlock));
+        registry.register(Box::new(Bridge));
+        registry.register(Box::new(LineEndings));
+        registry.register(Box::new(Replay));
+        registry.register(Box::new(History));
+        registry.register(Box::new(RunHistory));
+        registry.register(Box::new(Verify));
+        registry.register(Query::always("AT+HELP", &["AT+HELP        - Show this help message"], |request| {
+            request.context.commands.help_text()
+        }));
+        registry
+    }
+
+    /// Add a command; AT+HELP lists it after the commands registered before
+    pub(crate) fn register(&mut self, handler: Box<dyn CommandHandler>) {
+        self.handlers.push(handler);
+    }
+
+    /// Find the handler of a command line, returning it with the rest of the line
+    ///
+    /// The longest matching name wins, so "AT+LOGLEVEL?" is not taken for AT+LOG. A
+    /// name ending in a letter or digit must be followed by a non-alphanumeric
+    /// character or the end of the line.
+    pub(crate) fn find<'a>(&self, cmd_str: &'a str) -> Option<(&dyn CommandHandler, &'a str)> {
+        self.handlers
+            .iter()
+            .filter_map(|handler| {
+                let args = cmd_str.strip_prefix(handler.name())?;
+                let open_ended = !handler.name().ends_with(|c: char| c.is_ascii_alphanumeric());
+                let boundary = !args.starts_with(|c: char| c.is_ascii_alphanumeric());
+                (open_ended || boundary).then_some((handler.as_ref(), args))
+            })
+            .max_by_key(|(handler, _)| handler.name().len())
+    }
+
+    /// Validate a configuration command line and plan the change it would make
+    ///
+    /// Returns None if no handler plans the line, e.g. for queries. This has no side
+    /// effects.
+    pub(crate) fn plan(&self, cmd_str: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let (handler, args) = self.find(cmd_str)?;
+        handler.plan(args)
+    }
+
+    /// Whether a command line carries a secret and must not be recorded
+    ///
+    /// Also covers a secret command passed as the argument of another one, e.g.
+    /// AT+VERIFY=AT+WIFISTA=<ssid>,<password>.
+    pub(crate) fn is_secret(&self, cmd_str: &str) -> bool {
+        let Some((handler, args)) = self.find(cmd_str) else {
+            return false;
+        };
+        handler.is_secret(args)
+            || args
+                .strip_prefix('=')
+                .map(normalize)
+                .is_some_and(|inner| inner.starts_with("AT+") && self.is_secret(&inner))
+    }
+
+    /// Whether `cmd_str` goes into the command history of the client sending it
+    ///
+    /// Lines carrying a secret are never recorded (see `is_secret`), neither are
+    /// the commands working on the history. Unknown commands are recorded.
+    pub(crate) fn is_recorded(&self, cmd_str: &str) -> bool {
+        match self.find(cmd_str) {
+            Some((handler, _)) if !handler.is_recorded() => false,
+            _ => !self.is_secret(cmd_str),
+        }
+    }
+
+    /// Text sent for AT+HELP
+    pub(crate) fn help_text(&self) -> String {
+        let lines: String = self
+            .handlers
+            .iter()
+            .flat_map(|handler| handler.help())
+            .map(|line| format!("  {}\r\n", line))
+            .collect();
+        let codes: String = ErrorCode::ALL
+            .iter()
+            .map(|code| format!("  {} - {}\r\n", code, code.description()))
+            .collect();
+        format!(
+            "\r\nAvailable commands:\r\n{}\r\nSupported baud rates: {}\r\n\
+            \r\nReplies end with OK or ERROR <code> <message>, after +<NAME>: <value> lines.\r\n\
+            Error codes:\r\n{}",
+            lines,
+            uart::SUPPORTED_BAUDRATES
+                .iter()
+                .map(|baudrate| baudrate.to_string())
+                .collect::<Vec<_>>()
+                .join(", "),
+            codes
+        )
+    }
+}
+
+/// Command that only has query forms, answered by a function
+///
+/// Both AT+NAME and AT+NAME? are answered; any other arguments are refused.
+struct Query {
+    name: &'static str,
+    help: &'static [&'static str],
+    answer: fn(&CommandRequest) -> String,
+}
+
+impl Query {
+    fn always(
+        name: &'static str,
+        help: &'static [&'static str],
+        answer: fn(&CommandRequest) -> String,
+    ) -> Box<dyn CommandHandler> {
+        Box::new(Self { name, help, answer })
+    }
+}
+
+impl CommandHandler for Query {
+    fn name(&self) -> &'static str {
+        self.name
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        self.help
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        matches!(args, "" | "?").then(|| (self.answer)(request))
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        Ok(match self.answer(args, request) {
+            Some(answer) => Response::Reply(answer),
+            None => Response::Error(ErrorCode::InvalidArgument, format!("{} takes no arguments", self.name)),
+        })
+    }
+}
+
+/// AT+OTA=<size>,<crc32> uploads firmware on the connection, AT+OTA? shows the partitions
+struct Ota;
+
+impl CommandHandler for Ota {
+    fn name(&self) -> &'static str {
+        "AT+OTA"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+OTA=<size>,<crc32> - Upload firmware after \"OK: Ready\", then restart",
+            "AT+OTA?        - Show the running partition and app version",
+        ]
+    }
+
+    fn reads_upload(&self, args: &str) -> bool {
+        args.starts_with('=')
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        // 固件上传期间连接处于二进制模式
+        if let Some(args) = args.strip_prefix('=') {
+            let restart = TcpServer::receive_firmware(
+                args,
+                request.context,
+                request.client_manager,
+                request.stream_arc,
+                request.peer_addr,
+            )?;
+            return Ok(Response::Sent { restart });
+        }
+        if args.starts_with('?') {
+            return Ok(Response::Reply(match OtaStatus::current() {
+                Ok(status) => format!("{}\r\n", status),
+                Err(e) => format!("ERROR: {}\r\n", e),
+            }));
+        }
+        Ok(unknown_command(request.command))
+    }
+}
+
+/// AT+CFGEXPORT prints the persisted settings, AT+CFGEXPORT=FULL includes the passwords
+struct ConfigExport;
+
+impl CommandHandler for ConfigExport {
+    fn name(&self) -> &'static str {
+        "AT+CFGEXPORT"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+CFGEXPORT   - Print the saved settings as key=value lines",
+            "AT+CFGEXPORT=FULL - Print the saved settings including the WiFi passwords",
+        ]
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        let include_secrets = match args {
+            "" | "?" => false,
+            "=FULL" => true,
+            other => {
+                return Ok(Response::Error(
+                    ErrorCode::InvalidArgument,
+                    format!("Invalid value: {} (use FULL)", other.trim_start_matches('=')),
+                ));
+            }
+        };
+        let Some(storage) = &request.context.storage else {
+            return Ok(Response::Error(ErrorCode::Failed, "Flash storage not available".to_string()));
+        };
+        let config = match storage.lock() {
+            Ok(storage) => storage.load_app_config().unwrap_or_default(),
+            Err(_) => return Ok(Response::Error(ErrorCode::Failed, "Failed to lock storage manager".to_string())),
+        };
+        if include_secrets {
+            info!("Client {} exported the configuration including passwords", request.peer_addr);
+        }
+        Ok(Response::Reply(config_transfer::export(&config, include_secrets)))
+    }
+}
+
+/// AT+CFGIMPORT=<len> reads an exported document and saves it if every setting is valid
+struct ConfigImport;
+
+impl CommandHandler for ConfigImport {
+    fn name(&self) -> &'static str {
+        "AT+CFGIMPORT"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+CFGIMPORT=<len> - Send <len> bytes of AT+CFGEXPORT output after \"Ready\" to save them"]
+    }
+
+    fn reads_upload(&self, args: &str) -> bool {
+        args.starts_with('=')
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        let len = match args.strip_prefix('=').map(|len| len.trim().parse::<usize>()) {
+            Some(Ok(len)) if (1..=config_transfer::MAX_DOCUMENT_LEN).contains(&len) => len,
+            _ => {
+                return Ok(Response::Error(
+                    ErrorCode::InvalidArgument,
+                    format!("Expected AT+CFGIMPORT=<len> with 1-{} bytes", config_transfer::MAX_DOCUMENT_LEN),
+                ));
+            }
+        };
+        // 数据端口会收到UART广播，会与提示和回复混在一起
+        if Arc::ptr_eq(request.client_manager, &request.context.data_clients) {
+            return Ok(Response::Error(
+                ErrorCode::InvalidArgument,
+                "Configuration imports are only accepted on the control port".to_string(),
+            ));
+        }
+        let Some(storage) = &request.context.storage else {
+            return Ok(Response::Error(ErrorCode::Failed, "Flash storage not available".to_string()));
+        };
+
+        let prompt = if request.client_manager.is_verbose(request.peer_addr) {
+            format!("OK: Ready for {} bytes\r\n", len)
+        } else {
+            format!("+CFGIMPORT: Ready for {} bytes\r\n", len)
+        };
+        let document = match TcpServer::receive_upload(
+            len,
+            &prompt,
+            request.client_manager,
+            request.stream_arc,
+            request.peer_addr,
+        ) {
+            Ok(document) => document,
+            Err(e) => return Ok(Response::Error(ErrorCode::Failed, e.to_string())),
+        };
+        let Ok(document) = String::from_utf8(document) else {
+            return Ok(Response::Error(ErrorCode::InvalidFormat, "Configuration is not UTF-8".to_string()));
+        };
+
+        // 全部设置有效时才一次写入
+        let Ok(mut storage) = storage.lock() else {
+            return Ok(Response::Error(ErrorCode::Failed, "Failed to lock storage manager".to_string()));
+        };
+        let base = storage.load_app_config().unwrap_or_default();
+        let config = match config_transfer::import(&base, &document) {
+            Ok(config) => config,
+            Err(errors) => {
+                let mut reply: String = errors
+                    .iter()
+                    .map(|error| format!("Invalid {}\r\n", error))
+                    .collect();
+                reply.push_str(&format!(
+                    "ERROR: Configuration not imported ({} invalid settings)\r\n",
+                    errors.len()
+                ));
+                return Ok(Response::Reply(reply));
+            }
+        };
+        Ok(Response::Reply(match storage.save_app_config(&config) {
+            Ok(()) => {
+                info!("Client {} imported the configuration", request.peer_addr);
+                "OK: Configuration imported, restart to apply it (AT+RESET=YES)\r\n".to_string()
+            }
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }))
+    }
+}
+
+/// AT+SOCKOPT=NODELAY,<1|0> switches Nagle's algorithm for the requesting client's
+/// connection, AT+SOCKOPT? shows it
+struct SocketOptions;
+
+impl CommandHandler for SocketOptions {
+    fn name(&self) -> &'static str {
+        "AT+SOCKOPT"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+SOCKOPT=NODELAY,1|0 - Send small writes at once (1) or coalesce them (0, avoid with AT+BATCH)",
+            "AT+SOCKOPT?    - Show the socket options of your connection",
+        ]
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        // 只修改发出命令的客户端自己的连接
+        let Ok(stream) = request.stream_arc.lock() else {
+            return Ok(Response::Error(ErrorCode::Failed, "Failed to lock stream".to_string()));
+        };
+        if args.starts_with('?') || args.is_empty() {
+            return Ok(Response::Reply(match stream.nodelay() {
+                Ok(nodelay) => format!("NODELAY: {}\r\n", if nodelay { 1 } else { 0 }),
+                Err(e) => format!("ERROR: Failed to read TCP_NODELAY: {}\r\n", e),
+            }));
+        }
+        let nodelay = match args.strip_prefix('=').and_then(|value| value.split_once(',')) {
+            Some((option, value)) if option.trim().eq_ignore_ascii_case("NODELAY") => match value.trim() {
+                "1" | "ON" => true,
+                "0" | "OFF" => false,
+                other => {
+                    return Ok(Response::Error(
+                        ErrorCode::InvalidArgument,
+                        format!("Invalid value: {} (use 1 or 0)", other),
+                    ));
+                }
+            },
+            _ => {
+                return Ok(Response::Error(
+                    ErrorCode::InvalidArgument,
+                    "Expected AT+SOCKOPT=NODELAY,<1|0>".to_string(),
+                ));
+            }
+        };
+        Ok(Response::Reply(match stream.set_nodelay(nodelay) {
+            Ok(()) => {
+                info!("Client {} set TCP_NODELAY to {}", request.peer_addr, nodelay);
+                format!("OK: NODELAY {}\r\n", if nodelay { 1 } else { 0 })
+            }
+            Err(e) => format!("ERROR: Failed to set TCP_NODELAY: {}\r\n", e),
+        }))
+    }
+}
+
+/// AT+HISTORY? lists the client's recent commands
+struct History;
+
+impl CommandHandler for History {
+    fn name(&self) -> &'static str {
+        "AT+HISTORY"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+HISTORY?    - List your recent commands"]
+    }
+
+    fn is_recorded(&self) -> bool {
+        false
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| history(request))
+    }
+}
+
+/// AT+! <n> runs entry n of the client's command history again
+struct RunHistory;
+
+impl CommandHandler for RunHistory {
+    fn name(&self) -> &'static str {
+        "AT+!"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+! <n>       - Run command <n> from the history again"]
+    }
+
+    fn is_recorded(&self) -> bool {
+        false
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        let index_str = args.trim();
+        let entry = match index_str.parse::<usize>() {
+            Ok(index) if index > 0 => request
+                .client_manager
+                .command_history(request.peer_addr)
+                .ok()
+                .and_then(|history| history.get(index - 1).cloned()),
+            _ => None,
+        };
+        // 由服务器重新执行，不再记录到历史
+        Ok(match entry {
+            Some(line) => Response::Rerun(line),
+            None => Response::Error(ErrorCode::InvalidArgument, format!("No history entry: {}", index_str)),
+        })
+    }
+}
+
+/// AT+VERIFY=<command> checks a configuration command without applying it
+struct Verify;
+
+impl CommandHandler for Verify {
+    fn name(&self) -> &'static str {
+        "AT+VERIFY"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+VERIFY=<cmd> - Check a configuration command without applying it"]
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        let Some(inner) = args.strip_prefix('=') else {
+            return Ok(unknown_command(request.command));
+        };
+        let inner = normalize(inner);
+        Ok(match request.context.commands.plan(&inner) {
+            Some(Ok(plan)) => Response::Reply(format!("OK: {}\r\n", plan)),
+            Some(Err(msg)) => Response::Error(ErrorCode::InvalidArgument, msg),
+            None => Response::Error(ErrorCode::InvalidArgument, format!("Command cannot be verified: {}", inner)),
+        })
+    }
+}
+
+/// Reply for a plan that was not made by the handler asked to apply it
+fn foreign_plan(name: &str, plan: &CommandPlan) -> String {
+    format!("ERROR: {} cannot apply this change: {}\r\n", name, plan)
+}
+
+/// Run `change` on the WiFi manager, None if there is none
+fn with_wifi<T>(
+    request: &CommandRequest,
+    change: impl FnOnce(&mut WiFiManager) -> Result<T>,
+) -> Option<Result<T>> {
+    let wifi_manager = request.context.wifi_manager.as_ref()?;
+    Some(match wifi_manager.lock() {
+        Ok(mut wifi) => change(&mut wifi),
+        Err(_) => Err(Error::wifi("Failed to lock WiFi manager")),
+    })
+}
+
+/// Reply when a command needs the WiFi manager and there is none
+const NO_WIFI: &str = "ERROR: WiFi manager not available\r\n";
+
+/// Run `change` on the storage manager, None if there is none
+fn with_storage<T>(
+    request: &CommandRequest,
+    change: impl FnOnce(&mut StorageManager) -> Result<T>,
+) -> Option<Result<T>> {
+    let storage = request.context.storage.as_ref()?;
+    Some(match storage.lock() {
+        Ok(mut storage) => change(&mut storage),
+        Err(_) => Err(Error::StorageError("Failed to lock storage manager".to_string())),
+    })
+}
+
+/// AT+BAUD=<rate> changes the UART baud rate
+struct Baud;
+
+impl CommandHandler for Baud {
+    fn name(&self) -> &'static str {
+        "AT+BAUD"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+BAUD=<rate>  - Change UART baud rate",
+            "AT+BAUD?       - Query current UART baud rate",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| baudrate(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let baud_str = args.strip_prefix('=')?;
+        Some(match baud_str.parse::<u32>() {
+            Ok(baudrate) if UartManager::is_valid_baudrate(baudrate) => Ok(CommandPlan::SetBaudrate(baudrate)),
+            Ok(baudrate) => Err(format!(
+                "Unsupported baudrate: {} (use {}-{})",
+                baudrate,
+                uart::MIN_BAUDRATE,
+                uart::MAX_BAUDRATE
+            )),
+            Err(_) => Err(format!("Invalid baudrate value: {}", baud_str)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetBaudrate(baudrate) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match request.context.uart_manager.set_baudrate(*baudrate) {
+            Ok(achieved) => {
+                info!(
+                    "Successfully changed baudrate to {} (achieved {}) for client {}",
+                    baudrate, achieved, request.peer_addr
+                );
+                format!("OK: Baudrate requested {}, achieved {}\r\n", baudrate, achieved)
+            }
+            Err(e) => format!("ERROR: Failed to set baudrate: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+AUTOBAUD[=APPLY] detects the baud rate of the attached device
+struct AutoBaud;
+
+impl AutoBaud {
+    /// Report the score of every baudrate and the best candidate
+    fn detect(uart_manager: &UartManager, apply: bool, peer_addr: &SocketAddr) -> String {
+        info!("Detecting baudrate for client {}", peer_addr);
+        let report = match uart_manager.detect_baudrate() {
+            Ok(report) => report,
+            Err(e) => return format!("ERROR: Baudrate detection failed: {}\r\n", e),
+        };
+
+        let mut response: String = report
+            .scores
+            .iter()
+            .map(|(baudrate, score)| match score {
+                Some(score) => format!("  {:>7}: {}\r\n", baudrate, score),
+                None => format!("  {:>7}: no readable data\r\n", baudrate),
+            })
+            .collect();
+        let Some(best) = report.best else {
+            response.push_str("ERROR: No readable data received from UART at any baudrate\r\n");
+            return response;
+        };
+        if !apply {
+            response.push_str(&format!("OK: Best candidate {} (use AT+AUTOBAUD=APPLY to switch)\r\n", best));
+        } else if best == uart_manager.get_baudrate() {
+            response.push_str(&format!("OK: Best candidate {} is already in use\r\n", best));
+        } else {
+            match uart_manager.set_baudrate(best) {
+                Ok(_) => response.push_str(&format!("OK: Baudrate changed to {}\r\n", best)),
+                Err(e) => response.push_str(&format!("ERROR: Failed to set baudrate: {}\r\n", e)),
+            }
+        }
+        response
+    }
+}
+
+impl CommandHandler for AutoBaud {
+    fn name(&self) -> &'static str {
+        "AT+AUTOBAUD"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+AUTOBAUD[=APPLY] - Detect the UART baud rate (and switch to it)"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        match args {
+            "" => Some(Ok(CommandPlan::DetectBaudrate { apply: false })),
+            "=APPLY" => Some(Ok(CommandPlan::DetectBaudrate { apply: true })),
+            _ => None,
+        }
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::DetectBaudrate { apply } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        Self::detect(&request.context.uart_manager, *apply, request.peer_addr)
+    }
+}
+
+/// AT+UART=<baud>,<data>,<parity>,<stop> changes all serial settings
+struct Uart;
+
+impl Uart {
+    /// Parse the `<baud>,<data>,<parity>,<stop>` arguments
+    ///
+    /// The error names the first field that was rejected.
+    fn parse(args: &str) -> std::result::Result<CommandPlan, String> {
+        let fields: Vec<&str> = args.split(',').map(str::trim).collect();
+        if fields.len() != 4 {
+            return Err("Expected AT+UART=<baud>,<data>,<parity>,<stop>".to_string());
+        }
+
+        let baudrate = match fields[0].parse::<u32>() {
+            Ok(baudrate) if UartManager::is_valid_baudrate(baudrate) => baudrate,
+            _ => return Err(format!("Invalid baud field: {}", fields[0])),
+        };
+        let data_bits = SerialFormat::parse_data_bits(fields[1])
+            .ok_or_else(|| format!("Invalid data bits field: {} (use 5-8)", fields[1]))?;
+        let parity = SerialFormat::parse_parity(fields[2])
+            .ok_or_else(|| format!("Invalid parity field: {} (use N, E or O)", fields[2]))?;
+        let stop_bits = SerialFormat::parse_stop_bits(fields[3])
+            .ok_or_else(|| format!("Invalid stop bits field: {} (use 1, 1.5 or 2)", fields[3]))?;
+
+        Ok(CommandPlan::SetSerialParams {
+            baudrate,
+            format: SerialFormat {
+                data_bits,
+                parity,
+                stop_bits,
+            },
+        })
+    }
+}
+
+impl CommandHandler for Uart {
+    fn name(&self) -> &'static str {
+        "AT+UART"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+UART=<baud>,<data>,<parity>,<stop> - Change serial settings (e.g. 9600,8,E,1)",
+            "AT+UART?       - Query serial settings",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| serial_settings(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse(args.strip_prefix('=')?))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetSerialParams { baudrate, format } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match request.context.uart_manager.set_serial_params(*baudrate, *format) {
+            Ok(achieved) => {
+                info!(
+                    "Successfully changed UART settings to {},{} for client {}",
+                    baudrate, format, request.peer_addr
+                );
+                format!(
+                    "OK: UART settings changed to {},{} (achieved {})\r\n",
+                    baudrate, format, achieved
+                )
+            }
+            Err(e) => format!("ERROR: Failed to set UART settings: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+FRAME=<gap_ms>,<max> ends UART frames at a pause
+struct Frame;
+
+impl Frame {
+    /// Parse the `<gap_ms>,<max>` or `OFF` argument
+    fn parse(args: &str) -> std::result::Result<CommandPlan, String> {
+        if args == "OFF" {
+            return Ok(CommandPlan::SetFrameGap { gap_ms: 0, max_bytes: uart::MAX_FRAME_BYTES });
+        }
+        let Some((gap, max)) = args.split_once(',') else {
+            return Err("Expected AT+FRAME=<gap_ms>,<max> or AT+FRAME=OFF".to_string());
+        };
+        let gap_ms = match gap.trim().parse::<u64>() {
+            Ok(gap_ms) if gap_ms <= 60_000 => gap_ms,
+            _ => return Err(format!("Invalid gap: {} (use 0-60000 ms)", gap.trim())),
+        };
+        let max_bytes = match max.trim().parse::<usize>() {
+            Ok(max_bytes) if (1..=uart::MAX_FRAME_BYTES).contains(&max_bytes) => max_bytes,
+            _ => {
+                return Err(format!(
+                    "Invalid frame size: {} (use 1-{})",
+                    max.trim(),
+                    uart::MAX_FRAME_BYTES
+                ))
+            }
+        };
+        Ok(CommandPlan::SetFrameGap { gap_ms, max_bytes })
+    }
+}
+
+impl CommandHandler for Frame {
+    fn name(&self) -> &'static str {
+        "AT+FRAME"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+FRAME=<gap_ms>,<max> - Send UART data in frames ended by a <gap_ms> pause",
+            "AT+FRAME=OFF   - Send UART data as it arrives",
+            "AT+FRAME?      - Query UART framing",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| framing(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse(args.strip_prefix('=')?.trim()))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetFrameGap { gap_ms, max_bytes } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let uart_manager = &request.context.uart_manager;
+        match uart_manager.set_frame_gap(*gap_ms, *max_bytes) {
+            Ok(_) => {
+                let framing = uart_manager.framing();
+                info!("UART framing set to {} by client {}", framing, request.peer_addr);
+                format!("OK: UART framing {}\r\n", framing)
+            }
+            Err(e) => format!("ERROR: Failed to set UART framing: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+DELIM=<hex> ends UART frames at a byte sequence
+struct Delimiter;
+
+impl Delimiter {
+    /// Parse the `<hex bytes>` or `OFF` argument
+    ///
+    /// Accepts e.g. "0D0A", "0D 0A" or "0x7E".
+    fn parse(args: &str) -> std::result::Result<CommandPlan, String> {
+        if args == "OFF" {
+            return Ok(CommandPlan::SetFrameDelimiter(None));
+        }
+        let hex: String = args
+            .split_whitespace()
+            .map(|part| part.strip_prefix("0x").or_else(|| part.strip_prefix("0X")).unwrap_or(part))
+            .collect();
+        let delimiter: Option<Vec<u8>> = hex
+            .as_bytes()
+            .chunks(2)
+            .map(|pair| {
+                // 只接受成对的十六进制数字（from_str_radix还会接受'+'号）
+                (pair.len() == 2 && pair.iter().all(u8::is_ascii_hexdigit))
+                    .then(|| std::str::from_utf8(pair).ok())
+                    .flatten()
+                    .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+            })
+            .collect();
+        let delimiter = match delimiter {
+            Some(delimiter) if !delimiter.is_empty() => delimiter,
+            _ => return Err(format!("Invalid delimiter: {} (use hex bytes, e.g. 0D0A)", args)),
+        };
+        if delimiter.len() > uart::MAX_DELIMITER_LEN {
+            return Err(format!(
+                "Delimiter too long: {} bytes (max {})",
+                delimiter.len(),
+                uart::MAX_DELIMITER_LEN
+            ));
+        }
+        Ok(CommandPlan::SetFrameDelimiter(Some(delimiter)))
+    }
+}
+
+impl CommandHandler for Delimiter {
+    fn name(&self) -> &'static str {
+        "AT+DELIM"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+DELIM=<hex> - Send UART data in frames ended by bytes <hex>, e.g. 0D0A (saved)",
+            "AT+DELIM=OFF   - Disable the frame delimiter",
+            "AT+DELIM?      - Query the frame delimiter",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| delimiter(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse(args.strip_prefix('=')?.trim()))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetFrameDelimiter(delimiter) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let uart_manager = &request.context.uart_manager;
+        match uart_manager.set_frame_delimiter(delimiter.as_deref()) {
+            Ok(_) => {
+                let framing = uart_manager.framing();
+                info!("UART framing set to {} by client {}", framing, request.peer_addr);
+                format!("OK: UART framing {}\r\n", framing)
+            }
+            Err(e) => format!("ERROR: Failed to set frame delimiter: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+BATCH=<bytes>,<ms> collects UART data into batches
+struct Batch;
+
+impl Batch {
+    /// Parse the `<bytes>,<ms>` or `OFF` argument
+    fn parse(args: &str) -> std::result::Result<CommandPlan, String> {
+        if args == "OFF" {
+            return Ok(CommandPlan::SetBatching(None));
+        }
+        let Some((bytes, ms)) = args.split_once(',') else {
+            return Err("Expected AT+BATCH=<bytes>,<ms> or AT+BATCH=OFF".to_string());
+        };
+        let batch_bytes = match bytes.trim().parse::<usize>() {
+            Ok(batch_bytes) if (1..=uart::MAX_FRAME_BYTES).contains(&batch_bytes) => batch_bytes,
+            _ => {
+                return Err(format!(
+                    "Invalid batch size: {} (use 1-{})",
+                    bytes.trim(),
+                    uart::MAX_FRAME_BYTES
+                ))
+            }
+        };
+        let batch_ms = match ms.trim().parse::<u64>() {
+            Ok(batch_ms) if batch_ms <= uart::MAX_BATCH_LATENCY_MS => batch_ms,
+            _ => {
+                return Err(format!(
+                    "Invalid batch latency: {} (use 0-{} ms)",
+                    ms.trim(),
+                    uart::MAX_BATCH_LATENCY_MS
+                ))
+            }
+        };
+        Ok(CommandPlan::SetBatching(Some((batch_bytes, batch_ms))))
+    }
+}
+
+impl CommandHandler for Batch {
+    fn name(&self) -> &'static str {
+        "AT+BATCH"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+BATCH=<bytes>,<ms> - Send UART data in batches of up to <bytes>, held at most <ms>",
+            "AT+BATCH=OFF   - Send UART data as it arrives",
+            "AT+BATCH?      - Query UART batching",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| batching(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse(args.strip_prefix('=')?.trim()))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetBatching(batching) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let uart_manager = &request.context.uart_manager;
+        let peer_addr = request.peer_addr;
+        let (batch_bytes, batch_ms) = batching.unwrap_or((uart_manager.framing().batch_bytes, 0));
+        match uart_manager.set_batching(batch_bytes, batch_ms) {
+            Ok(_) if batch_ms == 0 => {
+                info!("UART batching disabled by client {}", peer_addr);
+                "OK: UART batching off\r\n".to_string()
+            }
+            Ok(_) => {
+                info!(
+                    "UART batching set to {} bytes within {} ms by client {}",
+                    batch_bytes, batch_ms, peer_addr
+                );
+                let framing = uart_manager.framing();
+                if framing.is_batching() {
+                    format!("OK: UART batching {} bytes within {} ms\r\n", batch_bytes, batch_ms)
+                } else {
+                    format!(
+                        "OK: UART batching {} bytes within {} ms (inactive while framing is {})\r\n",
+                        batch_bytes, batch_ms, framing
+                    )
+                }
+            }
+            Err(e) => format!("ERROR: Failed to set UART batching: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+WIFISTA=<ssid>,<password> connects the WiFi station to a network
+struct WiFiStation;
+
+impl CommandHandler for WiFiStation {
+    fn name(&self) -> &'static str {
+        "AT+WIFISTA"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+WIFISTA=<ssid>,<password> - Connect the WiFi station to a network",
+            "AT+WIFISTA?    - Query the WiFi station SSID",
+        ]
+    }
+
+    fn is_secret(&self, args: &str) -> bool {
+        args.starts_with('=')
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| sta_ssid(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let args = args.strip_prefix('=')?;
+        let (ssid, password) = args.split_once(',').unwrap_or((args, ""));
+        Some(if ssid.is_empty() || ssid.len() > 32 {
+            Err("Invalid SSID (must be 1-32 bytes)".to_string())
+        } else if password.len() > 64 {
+            Err("Invalid password (must be at most 64 bytes)".to_string())
+        } else {
+            Ok(CommandPlan::SetStaCredentials {
+                ssid: ssid.to_string(),
+                password: password.to_string(),
+            })
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetStaCredentials { ssid, password } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match with_wifi(request, |wifi| wifi.set_sta_credentials(ssid, password)) {
+            None => NO_WIFI.to_string(),
+            Some(Ok(StaConnectResult::Connected)) => format!("OK: Connected to {}\r\n", ssid),
+            Some(Ok(StaConnectResult::Failed(reason))) => {
+                format!("ERROR: Connection to {} failed: {}\r\n", ssid, reason)
+            }
+            Some(Ok(StaConnectResult::TimedOut)) => format!("ERROR: Connection to {} timed out\r\n", ssid),
+            Some(Err(e)) => format!("ERROR: Failed to set WiFi station: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+AP=<ssid>,<password>,<channel>,<hidden>[,<width>] changes and restarts the access point
+struct AccessPoint;
+
+impl AccessPoint {
+    /// Parse the `<ssid>,<password>,<channel|auto>,<hidden>[,<HT20|HT40>]` arguments
+    ///
+    /// The fields are taken from the right, so the password may contain commas.
+    fn parse(args: &str) -> std::result::Result<CommandPlan, String> {
+        let usage = || "Expected <ssid>,<password>,<channel|auto>,<0|1>[,<HT20|HT40>]".to_string();
+        let (rest, last) = args.rsplit_once(',').ok_or_else(usage)?;
+        let bandwidth = ApBandwidth::parse(last);
+        let (rest, hidden) = match bandwidth {
+            Some(_) => rest.rsplit_once(',').ok_or_else(usage)?,
+            None => (rest, last),
+        };
+        let (rest, channel) = rest.rsplit_once(',').ok_or_else(usage)?;
+        let (ssid, password) = rest.split_once(',').ok_or_else(usage)?;
+
+        if ssid.is_empty() || ssid.len() > 32 {
+            return Err("Invalid SSID (must be 1-32 bytes)".to_string());
+        }
+        if password.len() > 64 {
+            return Err("Invalid password (must be at most 64 bytes)".to_string());
+        }
+        // 0表示扫描选择信道
+        let channel = match channel.trim() {
+            auto if auto.eq_ignore_ascii_case("AUTO") => 0,
+            number => number
+                .parse()
+                .ok()
+                .filter(|channel| (1..=13).contains(channel))
+                .ok_or_else(|| format!("Invalid channel: {} (use 1-13 or AUTO)", number))?,
+        };
+        let hidden = match hidden.trim() {
+            "1" => true,
+            "0" => false,
+            other => return Err(format!("Invalid hidden flag: {} (use 1 or 0)", other)),
+        };
+        Ok(CommandPlan::SetAccessPoint {
+            ssid: ssid.to_string(),
+            password: password.to_string(),
+            channel,
+            hidden,
+            bandwidth,
+        })
+    }
+}
+
+impl CommandHandler for AccessPoint {
+    fn name(&self) -> &'static str {
+        "AT+AP"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+AP=<ssid>,<password>,<channel|AUTO>,<hidden 0|1>[,<HT20|HT40>] - Change and restart the access point",
+            "AT+AP?         - Show the access point settings",
+        ]
+    }
+
+    fn is_secret(&self, args: &str) -> bool {
+        args.starts_with('=')
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| access_point(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse(args.strip_prefix('=')?))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetAccessPoint { ssid, password, channel, hidden, bandwidth } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        // AP在回复发送后才重启
+        match with_wifi(request, |wifi| wifi.set_access_point(ssid, password, *channel, *hidden, *bandwidth)) {
+            None => NO_WIFI.to_string(),
+            Some(Ok(_)) => format!(
+                "OK: Access point set to {}, restarting it (connected stations will drop)\r\n",
+                ssid
+            ),
+            Some(Err(e)) => format!("ERROR: Failed to set access point: {}\r\n", e),
+        }
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        if let Some(answer) = self.answer(args, request) {
+            return Ok(Response::Reply(answer));
+        }
+        // 只有设置成功时才重启AP
+        Ok(match request.apply(self, args) {
+            Response::Reply(reply) if reply.starts_with("OK") => Response::RestartAp(reply),
+            response => response,
+        })
+    }
+}
+
+/// AT+COUNTRY=<cc> sets the WiFi regulatory country
+struct Country;
+
+impl CommandHandler for Country {
+    fn name(&self) -> &'static str {
+        "AT+COUNTRY"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+COUNTRY=<cc> - Set the WiFi country, e.g. DE, or 01 for worldwide",
+            "AT+COUNTRY?    - Show the WiFi country",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| country(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let value = args.strip_prefix('=')?;
+        let code = value.trim().to_ascii_uppercase();
+        Some(if wifi::is_valid_country_code(&code) {
+            Ok(CommandPlan::SetCountry(code))
+        } else {
+            Err(format!("Invalid country code: {} (use two letters like DE, or 01 for worldwide)", value.trim()))
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetCountry(code) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match with_wifi(request, |wifi| wifi.set_country_code(code)) {
+            None => NO_WIFI.to_string(),
+            Some(Ok(_)) => format!("OK: WiFi country set to {}\r\n", code),
+            Some(Err(e)) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+TXPOWER=<dbm> limits the WiFi transmit power
+struct TxPower;
+
+impl CommandHandler for TxPower {
+    fn name(&self) -> &'static str {
+        "AT+TXPOWER"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+TXPOWER=<dbm> - Set the maximum WiFi transmit power (2-20 dBm)",
+            "AT+TXPOWER?    - Show the maximum WiFi transmit power",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| tx_power(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let value = args.strip_prefix('=')?.trim();
+        Some(
+            value
+                .parse()
+                .ok()
+                .filter(|dbm| (wifi::MIN_TX_POWER_DBM..=wifi::MAX_TX_POWER_DBM).contains(dbm))
+                .map(CommandPlan::SetTxPower)
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid TX power: {} (use {}-{} dBm)",
+                        value,
+                        wifi::MIN_TX_POWER_DBM,
+                        wifi::MAX_TX_POWER_DBM
+                    )
+                }),
+        )
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetTxPower(dbm) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match with_wifi(request, |wifi| wifi.set_tx_power(*dbm)) {
+            None => NO_WIFI.to_string(),
+            Some(Ok(_)) => format!("OK: WiFi max TX power set to {} dBm\r\n", dbm),
+            Some(Err(e)) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+APSEC=<mode>[,<password>] changes the access point security after restart
+struct ApSecurity;
+
+impl CommandHandler for ApSecurity {
+    fn name(&self) -> &'static str {
+        "AT+APSEC"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+APSEC=<OPEN|WPA2|WPA2WPA3|WPA3>[,<password>] - Change the access point security (after restart)",
+            "AT+APSEC?      - Show the access point security",
+        ]
+    }
+
+    fn is_secret(&self, args: &str) -> bool {
+        args.starts_with('=')
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| ap_security(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let args = args.strip_prefix('=')?;
+        let (mode, password) = match args.split_once(',') {
+            Some((mode, password)) => (mode, Some(password)),
+            None => (args, None),
+        };
+        Some(match WiFiAuth::parse(mode) {
+            None => Err(format!("Invalid mode: {} (use OPEN, WPA2, WPA2WPA3 or WPA3)", mode.trim())),
+            Some(auth) => match password.map(|password| auth.check_password(password)) {
+                Some(Err(e)) => Err(e),
+                _ => Ok(CommandPlan::SetApSecurity {
+                    auth,
+                    password: password.map(str::to_string),
+                }),
+            },
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetApSecurity { auth, password } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match with_wifi(request, |wifi| wifi.set_ap_security(*auth, password.as_deref())) {
+            None => NO_WIFI.to_string(),
+            Some(Ok(_)) => format!("OK: Access point security set to {} (takes effect after restart)\r\n", auth),
+            Some(Err(e)) => format!("ERROR: Failed to set access point security: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+RESET=YES restarts the device
+struct Reset;
+
+impl CommandHandler for Reset {
+    fn name(&self) -> &'static str {
+        "AT+RESET"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+RESET=YES   - Restart the device"]
+    }
+
+    // 重启和恢复出厂设置需要确认后缀，避免误操作
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args {
+            "=YES" => Ok(CommandPlan::Restart),
+            _ => Err("Confirm with AT+RESET=YES".to_string()),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        if *plan != CommandPlan::Restart {
+            return foreign_plan(self.name(), plan);
+        }
+        info!("Restart requested by client {}", request.peer_addr);
+        "OK: restarting\r\n".to_string()
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        Ok(restart_on_success(request.apply(self, args)))
+    }
+}
+
+/// AT+FACTORY=YES erases all settings and restarts the device
+struct FactoryReset;
+
+impl CommandHandler for FactoryReset {
+    fn name(&self) -> &'static str {
+        "AT+FACTORY"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+FACTORY=YES - Erase all settings and restart"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args {
+            "=YES" => Ok(CommandPlan::FactoryReset),
+            _ => Err("Confirm with AT+FACTORY=YES (erases all settings)".to_string()),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        if *plan != CommandPlan::FactoryReset {
+            return foreign_plan(self.name(), plan);
+        }
+        match with_storage(request, |storage| storage.erase_all()) {
+            None => "ERROR: Storage not available\r\n".to_string(),
+            Some(Ok(_)) => {
+                info!("Factory reset requested by client {}", request.peer_addr);
+                "OK: settings erased, restarting\r\n".to_string()
+            }
+            Some(Err(e)) => format!("ERROR: Failed to erase settings: {}\r\n", e),
+        }
+    }
+
+    fn execute(&self, args: &str, request: &CommandRequest) -> Result<Response> {
+        Ok(restart_on_success(request.apply(self, args)))
+    }
+}
+
+/// Restart the device after a reply starting with "OK" was sent
+fn restart_on_success(response: Response) -> Response {
+    // 只有操作成功时才重启
+    match response {
+        Response::Reply(reply) if reply.starts_with("OK") => Response::Restart(reply),
+        response => response,
+    }
+}
+
+/// AT+PORT=<port> changes the data port after restart
+struct Port;
+
+impl CommandHandler for Port {
+    fn name(&self) -> &'static str {
+        "AT+PORT"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+PORT=<port> - Change the data port (after restart)",
+            "AT+PORT?       - Show the active and saved data port",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| port(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let value = args.strip_prefix('=')?.trim();
+        Some(match value.parse::<u16>() {
+            Ok(0) | Err(_) => Err(format!("Invalid port: {} (use 1-65535)", value)),
+            Ok(port) => Ok(CommandPlan::SetTcpPort(port)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetTcpPort(port) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match with_storage(request, |storage| storage.save_tcp_port(*port)) {
+            None => "ERROR: Storage not available\r\n".to_string(),
+            Some(Ok(_)) => {
+                info!("TCP port {} saved by client {}", port, request.peer_addr);
+                format!("OK: Port {} will take effect after restart\r\n", port)
+            }
+            Some(Err(e)) => format!("ERROR: Failed to save port: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+BANNER=<text> changes the welcome banner
+struct Banner;
+
+impl CommandHandler for Banner {
+    fn name(&self) -> &'static str {
+        "AT+BANNER"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+BANNER=<text> - Set the welcome banner ({client_addr}, {baudrate}, {port}, \\n)",
+            "AT+BANNER=OFF  - Send no welcome banner",
+            "AT+BANNER?     - Show the welcome banner template",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| banner(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "OFF" => Ok(CommandPlan::SetBanner(None)),
+            "" => Err("Empty banner (use AT+BANNER=OFF to disable it)".to_string()),
+            banner if banner.len() > storage::MAX_BANNER_LEN => Err(format!(
+                "Banner is longer than {} bytes",
+                storage::MAX_BANNER_LEN
+            )),
+            banner => Ok(CommandPlan::SetBanner(Some(banner.to_string()))),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetBanner(banner) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match request.context.banner.lock() {
+            Ok(mut current) => *current = banner.clone(),
+            Err(_) => return "ERROR: Failed to lock welcome banner\r\n".to_string(),
+        }
+        info!("Welcome banner changed by client {}", request.peer_addr);
+
+        match (with_storage(request, |storage| storage.save_banner(banner.as_deref())), banner) {
+            (None, _) => "OK: Welcome banner changed (not saved, storage not available)\r\n".to_string(),
+            (Some(Ok(_)), Some(_)) => "OK: Welcome banner changed\r\n".to_string(),
+            (Some(Ok(_)), None) => "OK: Welcome banner disabled\r\n".to_string(),
+            (Some(Err(e)), _) => format!("ERROR: Banner changed but not saved: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+ACL=ADD|DEL|CLEAR edits the access list
+struct Acl;
+
+impl Acl {
+    /// Change a copy of the access list, save it and only then put it in effect
+    fn update(
+        request: &CommandRequest,
+        change: impl FnOnce(&mut AccessList) -> std::result::Result<String, String>,
+    ) -> String {
+        let peer_addr = request.peer_addr;
+        let Ok(mut list) = request.context.access_list.lock() else {
+            return "ERROR: Failed to lock access list\r\n".to_string();
+        };
+        let mut updated = list.clone();
+        let message = match change(&mut updated) {
+            Ok(message) => message,
+            Err(e) => return format!("ERROR: {}\r\n", e),
+        };
+        if let Some(Err(e)) = with_storage(request, |storage| storage.save_access_list(&updated)) {
+            return format!("ERROR: Access list not changed, saving failed: {}\r\n", e);
+        }
+        *list = updated;
+        info!("Access list changed by client {}: {}", peer_addr, message);
+
+        if list.is_allowed(peer_addr.ip()) {
+            format!("OK: {}\r\n", message)
+        } else {
+            warn!("Access list now refuses client {}", peer_addr);
+            format!("OK: {} (new connections from {} will be refused)\r\n", message, peer_addr.ip())
+        }
+    }
+}
+
+impl CommandHandler for Acl {
+    fn name(&self) -> &'static str {
+        "AT+ACL"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+ACL=ADD,ALLOW|DENY,<ip>[/<prefix>] - Add an access list rule",
+            "AT+ACL=DEL,<n> - Remove rule <n>",
+            "AT+ACL=CLEAR   - Remove all rules, allowing every peer",
+            "AT+ACL?        - List the rules, the first matching one decides",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| access_list(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let mut parts = args.strip_prefix('=')?.splitn(3, ',').map(str::trim);
+        let operation = parts.next().unwrap_or("").to_ascii_uppercase();
+        Some(match (operation.as_str(), parts.next(), parts.next()) {
+            ("ADD", Some(action), Some(address)) => AclRule::parse(action, address)
+                .map(CommandPlan::AddAclRule)
+                .ok_or_else(|| {
+                    format!("Invalid rule: {},{} (use ALLOW or DENY and <ip>[/<prefix>])", action, address)
+                }),
+            ("DEL", Some(position), None) => position
+                .parse()
+                .ok()
+                .filter(|&position| position > 0)
+                .map(CommandPlan::RemoveAclRule)
+                .ok_or_else(|| format!("Invalid rule number: {}", position)),
+            ("CLEAR", None, None) => Ok(CommandPlan::ClearAcl),
+            _ => Err("Invalid arguments (use ADD,<ALLOW|DENY>,<ip>[/<prefix>], DEL,<n> or CLEAR)".to_string()),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        match plan {
+            CommandPlan::AddAclRule(rule) => Self::update(request, |list| {
+                list.add(*rule)?;
+                Ok(format!("Rule {} added: {}", list.rules().len(), rule))
+            }),
+            CommandPlan::RemoveAclRule(position) => Self::update(request, |list| {
+                list.remove(*position)
+                    .map(|rule| format!("Rule {} removed: {}", position, rule))
+                    .ok_or_else(|| format!("No rule {} (the list has {})", position, list.rules().len()))
+            }),
+            CommandPlan::ClearAcl => Self::update(request, |list| {
+                list.clear();
+                Ok("Access list cleared, all peers allowed".to_string())
+            }),
+            plan => foreign_plan(self.name(), plan),
+        }
+    }
+}
+
+/// AT+NAME=<name> changes the device name and mDNS host name
+struct Name;
+
+impl CommandHandler for Name {
+    fn name(&self) -> &'static str {
+        "AT+NAME"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+NAME=<name> - Change the device name, also the mDNS host name <name>.local",
+            "AT+NAME?       - Show the device name",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| name(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let name = args.strip_prefix('=')?.trim();
+        Some(if device_name::is_valid(name) {
+            Ok(CommandPlan::SetDeviceName(name.to_string()))
+        } else {
+            Err(format!(
+                "Invalid device name: {} (use 1-{} letters, digits or '-')",
+                name,
+                device_name::MAX_LEN
+            ))
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetDeviceName(name) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        // 没有存储时只修改运行中的名称
+        if let Some(Err(e)) = with_storage(request, |storage| storage.save_device_name(name)) {
+            return format!("ERROR: Failed to save device name: {}\r\n", e);
+        }
+        if let Err(e) = device_name::set(name) {
+            return format!("ERROR: {}\r\n", e);
+        }
+        info!("Device name changed to {} by client {}", name, request.peer_addr);
+
+        let advertised = match &request.context.mdns {
+            Some(mdns) => match mdns.lock() {
+                Ok(mut mdns) => mdns.set_hostname(name),
+                Err(_) => Err(Error::wifi("Failed to lock mDNS advertiser")),
+            },
+            None => return format!("OK: Device name changed to {}\r\n", name),
+        };
+        match advertised {
+            Ok(_) => format!("OK: Device name changed to {} ({}.local)\r\n", name, name),
+            Err(e) => format!("ERROR: Device name changed but not advertised: {}\r\n", e),
+        }
+    }
+}
+
+/// Whether `request` came from the control port rather than the data port
+///
+/// 启用控制端口时数据端口始终透明，客户端设置在控制端口上不会有任何效果
+fn on_control_port(request: &CommandRequest) -> bool {
+    !Arc::ptr_eq(request.client_manager, &request.context.data_clients)
+}
+
+/// AT+RAW=1 switches the requesting client into raw transparent mode
+struct Raw;
+
+impl CommandHandler for Raw {
+    fn name(&self) -> &'static str {
+        "AT+RAW"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+RAW=1       - Enter raw mode (pause, +++, pause to return)"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "1" | "ON" => Ok(CommandPlan::SetRawMode(true)),
+            "0" | "OFF" => Ok(CommandPlan::SetRawMode(false)),
+            other => Err(format!("Invalid value: {} (use 1 or 0)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetRawMode(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        if on_control_port(request) {
+            return "ERROR: Raw mode only applies to data port clients\r\n".to_string();
+        }
+        match request.client_manager.set_raw_mode(request.peer_addr, *enabled) {
+            Ok(_) if *enabled => "OK: Entering raw mode, send +++ surrounded by a pause to return\r\n".to_string(),
+            Ok(_) => "OK: Command mode\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+ECHO=1|0 echoes what the requesting client types
+struct Echo;
+
+impl CommandHandler for Echo {
+    fn name(&self) -> &'static str {
+        "AT+ECHO"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+ECHO=1|0    - Echo what you type back to you"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "1" | "ON" => Ok(CommandPlan::SetEcho(true)),
+            "0" | "OFF" => Ok(CommandPlan::SetEcho(false)),
+            other => Err(format!("Invalid value: {} (use 1 or 0)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetEcho(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        if on_control_port(request) {
+            return "ERROR: Echo only applies to data port clients\r\n".to_string();
+        }
+        match request.client_manager.set_echo(request.peer_addr, *enabled) {
+            Ok(_) if *enabled => "OK: Echo enabled\r\n".to_string(),
+            Ok(_) => "OK: Echo disabled\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+VERBOSE=1|0 switches the reply format of the requesting client
+struct Verbose;
+
+impl CommandHandler for Verbose {
+    fn name(&self) -> &'static str {
+        "AT+VERBOSE"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+VERBOSE=1|0 - Reply with free-form text instead of OK/ERROR lines",
+            "AT+VERBOSE?    - Query the reply format",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| verbose(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "1" | "ON" => Ok(CommandPlan::SetVerbose(true)),
+            "0" | "OFF" => Ok(CommandPlan::SetVerbose(false)),
+            other => Err(format!("Invalid value: {} (use 1 or 0)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetVerbose(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match request.client_manager.set_verbose(request.peer_addr, *enabled) {
+            Ok(_) if *enabled => "OK: Verbose replies enabled\r\n".to_string(),
+            Ok(_) => "OK: Verbose replies disabled\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+MARKGAPS=ON|OFF marks dropped data instead of disconnecting a slow client
+struct MarkGaps;
+
+impl CommandHandler for MarkGaps {
+    fn name(&self) -> &'static str {
+        "AT+MARKGAPS"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+MARKGAPS=ON|OFF - Drop and mark data instead of disconnecting when this client falls behind",
+            "AT+MARKGAPS?   - Query gap marker setting",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| gap_markers(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "ON" | "1" => Ok(CommandPlan::SetMarkGaps(true)),
+            "OFF" | "0" => Ok(CommandPlan::SetMarkGaps(false)),
+            other => Err(format!("Invalid value: {} (use ON or OFF)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetMarkGaps(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        if on_control_port(request) {
+            return "ERROR: Gap markers only applies to data port clients\r\n".to_string();
+        }
+        match request.client_manager.set_mark_gaps(request.peer_addr, *enabled) {
+            Ok(_) if *enabled => "OK: Gap markers enabled\r\n".to_string(),
+            Ok(_) => "OK: Gap markers disabled\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+TAP=HEX|RAW switches a data port client between hex dumps and raw data
+struct Tap;
+
+impl CommandHandler for Tap {
+    fn name(&self) -> &'static str {
+        "AT+TAP"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+TAP=HEX|RAW - Receive traffic as hex dumps or raw data",
+            "AT+TAP?        - Query tap mode",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| tap_mode(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "HEX" => Ok(CommandPlan::SetHexTap(true)),
+            "RAW" => Ok(CommandPlan::SetHexTap(false)),
+            other => Err(format!("Invalid value: {} (use HEX or RAW)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetHexTap(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        // 监听模式只对接收UART广播的数据端口客户端有意义
+        if on_control_port(request) {
+            return "ERROR: Only data port clients can tap traffic\r\n".to_string();
+        }
+        match request.client_manager.set_hex_tap(request.peer_addr, *enabled) {
+            Ok(_) if *enabled => "OK: Tap mode HEX\r\n".to_string(),
+            Ok(_) => "OK: Tap mode RAW\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+LOG=ON|OFF streams the device log to the requesting client
+struct Log;
+
+impl CommandHandler for Log {
+    fn name(&self) -> &'static str {
+        "AT+LOG"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+LOG=ON|OFF  - Receive the device log (lines start with \"LOG: \")",
+            "AT+LOG?        - Query log streaming",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| log_streaming(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "ON" | "1" => Ok(CommandPlan::SetLogStream(true)),
+            "OFF" | "0" => Ok(CommandPlan::SetLogStream(false)),
+            other => Err(format!("Invalid value: {} (use ON or OFF)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetLogStream(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let client_manager = request.client_manager;
+        let result = if *enabled {
+            log_stream::register(client_manager).and_then(|_| client_manager.set_log_stream(request.peer_addr, true))
+        } else {
+            client_manager.set_log_stream(request.peer_addr, false)
+        };
+        match result {
+            Ok(_) if *enabled => "OK: Log streaming enabled\r\n".to_string(),
+            Ok(_) => "OK: Log streaming disabled\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+LOGLEVEL=<level>[,<target>] changes log levels, AT+LOGLEVEL=SAVE keeps them
+struct LogLevel;
+
+impl CommandHandler for LogLevel {
+    fn name(&self) -> &'static str {
+        "AT+LOGLEVEL"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+LOGLEVEL=<level>[,<target>] - Set the log level (error|warn|info|debug|trace)",
+            "AT+LOGLEVEL=SAVE - Keep the current log levels after restart",
+            "AT+LOGLEVEL?   - Show the log levels",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| log_levels(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let value = args.strip_prefix('=')?;
+        if value == "SAVE" {
+            return Some(Ok(CommandPlan::SaveLogLevels));
+        }
+        let (level, target) = match value.split_once(',') {
+            Some((level, target)) => (level.trim(), Some(target.trim())),
+            None => (value.trim(), None),
+        };
+        let Some(level) = log_level::parse_level(level) else {
+            return Some(Err(format!(
+                "Invalid log level: {} (use error, warn, info, debug or trace)",
+                level
+            )));
+        };
+        if let Some(Err(msg)) = target.map(log_level::validate_target) {
+            return Some(Err(msg));
+        }
+        Some(Ok(CommandPlan::SetLogLevel {
+            level,
+            target: target.map(str::to_string),
+        }))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        match plan {
+            CommandPlan::SetLogLevel { level, target } => match log_level::set_level(target.as_deref(), *level) {
+                Ok(levels) => {
+                    info!("Log levels set to {} by client {}", levels, request.peer_addr);
+                    format!("OK: Log levels {}\r\n", levels)
+                }
+                Err(e) => format!("ERROR: {}\r\n", e),
+            },
+            CommandPlan::SaveLogLevels => {
+                let levels = log_level::current();
+                match with_storage(request, |storage| storage.save_log_levels(&levels.to_string())) {
+                    None => "ERROR: Storage not available\r\n".to_string(),
+                    Some(Ok(_)) => format!("OK: Log levels {} saved\r\n", levels),
+                    Some(Err(e)) => format!("ERROR: Failed to save log levels: {}\r\n", e),
+                }
+            }
+            plan => foreign_plan(self.name(), plan),
+        }
+    }
+}
+
+/// AT+STATS? shows the traffic counters, AT+STATS=RESET clears them
+struct Stats;
+
+impl CommandHandler for Stats {
+    fn name(&self) -> &'static str {
+        "AT+STATS"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+STATS?      - Show traffic counters",
+            "AT+STATS=RESET - Reset traffic counters",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        (!args.starts_with('=')).then(|| TcpServer::stats_report(request.context))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "RESET" => Ok(CommandPlan::ResetStats),
+            other => Err(format!("Invalid value: {} (use RESET)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        if *plan != CommandPlan::ResetStats {
+            return foreign_plan(self.name(), plan);
+        }
+        request.context.uart_manager.reset_stats();
+        request.context.data_clients.reset_stats();
+        "OK: Statistics reset\r\n".to_string()
+    }
+}
+
+/// AT+UARTERR? shows the UART receive error counters, AT+UARTERR=RESET clears them
+struct UartErrors;
+
+impl CommandHandler for UartErrors {
+    fn name(&self) -> &'static str {
+        "AT+UARTERR"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+UARTERR?    - Show UART framing, parity, overflow and break counters",
+            "AT+UARTERR=RESET - Reset the UART error counters",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        (!args.starts_with('=')).then(|| TcpServer::uart_error_report(request.context))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "RESET" => Ok(CommandPlan::ResetUartErrors),
+            other => Err(format!("Invalid value: {} (use RESET)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        if *plan != CommandPlan::ResetUartErrors {
+            return foreign_plan(self.name(), plan);
+        }
+        request.context.uart_manager.reset_error_counters();
+        "OK: UART error counters reset\r\n".to_string()
+    }
+}
+
+/// AT+BOOTINFO? shows the boot counter and last panic, AT+BOOTINFO=RESET clears them
+struct BootInfo;
+
+impl CommandHandler for BootInfo {
+    fn name(&self) -> &'static str {
+        "AT+BOOTINFO"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+BOOTINFO?   - Show boot count, last reset reason, uptime and last panic",
+            "AT+BOOTINFO=RESET - Clear the boot counter and the stored panic message",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        (!args.starts_with('=')).then(|| boot_info(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "RESET" => Ok(CommandPlan::ResetBootInfo),
+            other => Err(format!("Invalid value: {} (use RESET)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, _request: &CommandRequest) -> String {
+        if *plan != CommandPlan::ResetBootInfo {
+            return foreign_plan(self.name(), plan);
+        }
+        match boot_info::reset() {
+            Ok(()) => "OK: Boot counter cleared\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+KICK=<ip:port> disconnects a data port client
+struct Kick;
+
+impl CommandHandler for Kick {
+    fn name(&self) -> &'static str {
+        "AT+KICK"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+KICK=<ip:port> - Disconnect a client"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let value = args.strip_prefix('=')?.trim();
+        Some(match value.parse::<SocketAddr>() {
+            Ok(addr) => Ok(CommandPlan::Kick(addr)),
+            Err(_) => Err(format!("Invalid address: {} (use <ip>:<port>)", value)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::Kick(addr) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match request.context.data_clients.disconnect(addr) {
+            Ok(true) => {
+                info!("Client {} kicked by client {}", addr, request.peer_addr);
+                format!("OK: Client {} disconnected\r\n", addr)
+            }
+            Ok(false) => format!("ERROR: No such client: {}\r\n", addr),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+GPIO=<name>,<0|1|PULSE[,ms]> drives a target control line
+struct Gpio;
+
+impl Gpio {
+    /// Parse the `<name>,<0|1|PULSE[,ms]>` arguments
+    fn parse(args: &str) -> std::result::Result<CommandPlan, String> {
+        let mut fields = args.split(',').map(str::trim);
+        let name = fields.next().unwrap_or_default();
+        if name.is_empty() {
+            return Err("Expected AT+GPIO=<name>,<0|1|PULSE[,ms]>".to_string());
+        }
+        let action = match (fields.next(), fields.next(), fields.next()) {
+            (Some("1"), None, None) => GpioAction::Set(true),
+            (Some("0"), None, None) => GpioAction::Set(false),
+            (Some("PULSE"), None, None) => GpioAction::Pulse(None),
+            (Some("PULSE"), Some(ms), None) => match ms.parse::<u64>() {
+                Ok(ms @ 1..=gpio_control::MAX_PULSE_MS) => GpioAction::Pulse(Some(ms)),
+                _ => {
+                    return Err(format!(
+                        "Invalid pulse length: {} (use 1-{} ms)",
+                        ms,
+                        gpio_control::MAX_PULSE_MS
+                    ))
+                }
+            },
+            _ => return Err("Expected AT+GPIO=<name>,<0|1|PULSE[,ms]>".to_string()),
+        };
+        Ok(CommandPlan::SetGpio {
+            name: name.to_string(),
+            action,
+        })
+    }
+}
+
+impl CommandHandler for Gpio {
+    fn name(&self) -> &'static str {
+        "AT+GPIO"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+GPIO=<name>,<0|1|PULSE[,ms]> - Drive a target control line, e.g. RESET",
+            "AT+GPIO?       - List the target control lines",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| gpio_states(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse(args.strip_prefix('=')?))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetGpio { name, action } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let Some(gpio) = &request.context.gpio else {
+            return "ERROR: No GPIOs configured\r\n".to_string();
+        };
+        match gpio.apply(name, *action) {
+            Ok(Some(ms)) => {
+                info!("GPIO {} pulsed by client {}", name, request.peer_addr);
+                format!("OK: GPIO {} pulsed for {} ms\r\n", name, ms)
+            }
+            Ok(None) => {
+                info!("GPIO {} set by client {}", name, request.peer_addr);
+                format!("OK: GPIO {} set to {}\r\n", name, u8::from(*action == GpioAction::Set(true)))
+            }
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// AT+TARGETRESET=BOOTLOADER|RUN resets an ESP target with the esptool sequence
+struct TargetReset;
+
+impl CommandHandler for TargetReset {
+    fn name(&self) -> &'static str {
+        "AT+TARGETRESET"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+TARGETRESET=BOOTLOADER|RUN - Reset an ESP target, e.g. before flashing"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')?.trim() {
+            "BOOTLOADER" => Ok(CommandPlan::ResetTarget { bootloader: true }),
+            "RUN" => Ok(CommandPlan::ResetTarget { bootloader: false }),
+            other => Err(format!("Invalid mode: {} (use BOOTLOADER or RUN)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::ResetTarget { bootloader } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let Some(gpio) = &request.context.gpio else {
+            return "ERROR: Target reset is not configured\r\n".to_string();
+        };
+        match gpio.reset_target(*bootloader) {
+            Ok(()) => {
+                info!("Target reset by client {}", request.peer_addr);
+                if *bootloader {
+                    "OK: Target reset into bootloader\r\n".to_string()
+                } else {
+                    "OK: Target reset\r\n".to_string()
+                }
+            }
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+/// Write the payload of AT+SEND or AT+SENDLN to UART
+fn send_to_uart(request: &CommandRequest, data: &[u8]) -> String {
+    let context = request.context;
+    let peer_addr = request.peer_addr;
+    if let Some(holder) = context.data_clients.locked_by_other(peer_addr) {
+        return format!("ERROR: UART locked by {}\r\n", holder);
+    }
+    match context.send_to_uart(peer_addr, data) {
+        Ok(()) => {
+            debug!("Client {} sent {} bytes to UART by command", peer_addr, data.len());
+            format!("OK: {} bytes sent to UART\r\n", data.len())
+        }
+        Err(e) => format!("ERROR: Failed to send to UART: {}\r\n", e),
+    }
+}
+
+/// AT+SEND=<hex> writes bytes to UART
+struct SendHex;
+
+impl SendHex {
+    /// Decode the hex payload, e.g. "48656C6C6F0D0A" or "48 65 6C"
+    ///
+    /// Whitespace between bytes is ignored. Errors name the 1-based position of the
+    /// offending character within the payload.
+    fn parse_hex(hex: &str) -> std::result::Result<Vec<u8>, String> {
+        let incomplete = |position: usize| {
+            format!("Incomplete hex byte at position {} (use two digits per byte)", position)
+        };
+        let mut data = Vec::new();
+        // 高4位及其位置
+        let mut high: Option<(u8, usize)> = None;
+        for (index, c) in hex.chars().enumerate() {
+            let position = index + 1;
+            if c.is_ascii_whitespace() {
+                if let Some((_, position)) = high {
+                    return Err(incomplete(position));
+                }
+                continue;
+            }
+            let Some(digit) = c.to_digit(16) else {
+                return Err(format!("Invalid hex digit '{}' at position {}", c, position));
+            };
+            match high.take() {
+                None => high = Some((digit as u8, position)),
+                Some((high, _)) => {
+                    if data.len() == MAX_SEND_BYTES {
+                        return Err(format!("Payload too long (max {} bytes)", MAX_SEND_BYTES));
+                    }
+                    data.push((high << 4) | digit as u8);
+                }
+            }
+        }
+        if let Some((_, position)) = high {
+            return Err(incomplete(position));
+        }
+        if data.is_empty() {
+            return Err("Empty payload (use AT+SEND=<hex bytes>)".to_string());
+        }
+        Ok(data)
+    }
+}
+
+impl CommandHandler for SendHex {
+    fn name(&self) -> &'static str {
+        "AT+SEND"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+SEND=<hex>  - Write bytes to UART, e.g. AT+SEND=48690D0A"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(Self::parse_hex(args.strip_prefix('=')?).map(CommandPlan::SendBytes))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SendBytes(data) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        send_to_uart(request, data)
+    }
+}
+
+/// AT+SENDLN=<text> writes a line of text to UART
+struct SendLine;
+
+impl CommandHandler for SendLine {
+    fn name(&self) -> &'static str {
+        "AT+SENDLN"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+SENDLN=<text> - Write a line of text to UART"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let text = args.strip_prefix('=')?;
+        Some(if text.len() > MAX_SEND_BYTES {
+            Err(format!("Text too long: {} bytes (max {})", text.len(), MAX_SEND_BYTES))
+        } else {
+            Ok(CommandPlan::SendLine(text.to_string()))
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SendLine(text) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        let line = [text.as_bytes(), request.context.send_line_ending.as_bytes()].concat();
+        send_to_uart(request, &line)
+    }
+}
+
+/// AT+TEST=LOOPBACK|TCP|NVS runs a self test
+struct Test;
+
+impl Test {
+    /// Report pass or fail with the byte counts
+    fn run(request: &CommandRequest, test: SelfTest) -> String {
+        let context = request.context;
+        let peer_addr = request.peer_addr;
+        info!("Running {} self test for client {}", test, peer_addr);
+        let report = match test {
+            SelfTest::Loopback => {
+                // 回环测试会写UART，遵守其他客户端的独占锁
+                if let Some(holder) = context.data_clients.locked_by_other(peer_addr) {
+                    return format!("ERROR: UART locked by {}\r\n", holder);
+                }
+                context
+                    .uart_manager
+                    .loopback_test(&self_test::test_pattern(self_test::LOOPBACK_PATTERN_BYTES))
+            }
+            SelfTest::Tcp => {
+                let pattern = self_test::test_pattern(self_test::TCP_PATTERN_BYTES);
+                return format!(
+                    "{}OK: TCP test sent {} pattern bytes\r\n",
+                    self_test::pattern_lines(&pattern),
+                    pattern.len()
+                );
+            }
+            SelfTest::Nvs => {
+                match with_storage(request, |storage| {
+                    storage.self_test(&self_test::test_pattern(self_test::NVS_PATTERN_BYTES))
+                }) {
+                    Some(report) => report,
+                    None => return "ERROR: Storage not available\r\n".to_string(),
+                }
+            }
+        };
+        match report {
+            Ok(report) if report.passed() => format!("OK: {} test passed, {}\r\n", test, report),
+            Ok(report) => format!("ERROR: {} test failed, {}\r\n", test, report),
+            Err(e) => format!("ERROR: {} test failed: {}\r\n", test, e),
+        }
+    }
+}
+
+impl CommandHandler for Test {
+    fn name(&self) -> &'static str {
+        "AT+TEST"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+TEST=LOOPBACK - Check the UART with TX jumpered to RX",
+            "AT+TEST=TCP    - Send a test pattern to this client",
+            "AT+TEST=NVS    - Check writing and reading flash",
+        ]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let test = args.strip_prefix('=')?;
+        Some(
+            SelfTest::parse(test)
+                .map(CommandPlan::RunSelfTest)
+                .ok_or_else(|| format!("Invalid test: {} (use LOOPBACK, TCP or NVS)", test.trim())),
+        )
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::RunSelfTest(test) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        Self::run(request, *test)
+    }
+}
+
+/// AT+LOCK gives the requesting client, or a named data port client, exclusive UART TX rights
+struct Lock;
+
+impl Lock {
+    /// Give data port client `addr` exclusive UART TX rights on behalf of the requester
+    fn lock_for(request: &CommandRequest, addr: &SocketAddr) -> String {
+        let data_clients = &request.context.data_clients;
+        if !data_clients.is_client_connected(addr) {
+            return format!("ERROR: No such client: {}\r\n", addr);
+        }
+        if let Some(holder) = data_clients.acquire_exclusive(addr) {
+            return format!("ERROR: UART locked by {}\r\n", holder);
+        }
+        // 客户端可能在检查之后断开，此时断开处理已经释放过锁
+        if !data_clients.is_client_connected(addr) {
+            data_clients.release_exclusive(addr);
+            return format!("ERROR: No such client: {}\r\n", addr);
+        }
+        info!("UART locked to client {} by client {}", addr, request.peer_addr);
+        format!("OK: UART locked by {}\r\n", addr)
+    }
+}
+
+impl CommandHandler for Lock {
+    fn name(&self) -> &'static str {
+        "AT+LOCK"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+LOCK        - Reject data from other clients until AT+UNLOCK",
+            "AT+LOCK=<ip>:<port> - Lock the UART to a data port client (control port)",
+            "AT+LOCK?       - Show which client has locked the UART",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| lock_holder(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        if args.is_empty() {
+            return Some(Ok(CommandPlan::LockUart(None)));
+        }
+        let value = args.strip_prefix('=')?.trim();
+        Some(match value.parse::<SocketAddr>() {
+            Ok(addr) => Ok(CommandPlan::LockUart(Some(addr))),
+            Err(_) => Err(format!("Invalid address: {} (use <ip>:<port>)", value)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::LockUart(addr) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        // 独占锁只对数据端口的客户端有意义，控制端口代替指定的数据客户端加锁
+        match (addr, on_control_port(request)) {
+            (None, true) => "ERROR: Name the data port client to lock the UART to (AT+LOCK=<ip>:<port>)\r\n".to_string(),
+            (Some(_), false) => "ERROR: Data port clients can only lock the UART to themselves\r\n".to_string(),
+            (Some(addr), true) => Self::lock_for(request, addr),
+            (None, false) => match request.client_manager.acquire_exclusive(request.peer_addr) {
+                Some(holder) => format!("ERROR: UART locked by {}\r\n", holder),
+                None => "OK: UART locked\r\n".to_string(),
+            },
+        }
+    }
+}
+
+/// AT+UNLOCK releases exclusive UART TX rights
+struct Unlock;
+
+impl CommandHandler for Unlock {
+    fn name(&self) -> &'static str {
+        "AT+UNLOCK"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &["AT+UNLOCK      - Release the UART lock (on the control port, whoever holds it)"]
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        args.is_empty().then_some(Ok(CommandPlan::UnlockUart))
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        if *plan != CommandPlan::UnlockUart {
+            return foreign_plan(self.name(), plan);
+        }
+        let data_clients = &request.context.data_clients;
+        if !on_control_port(request) {
+            return match data_clients.release_exclusive(request.peer_addr) {
+                Some(holder) => format!("ERROR: UART locked by {}\r\n", holder),
+                None => "OK: UART unlocked\r\n".to_string(),
+            };
+        }
+        match data_clients.exclusive_holder() {
+            None => "OK: UART unlocked\r\n".to_string(),
+            Some(holder) => match data_clients.release_exclusive(&holder) {
+                Some(other) => format!("ERROR: UART locked by {}\r\n", other),
+                None => {
+                    info!("UART lock of client {} released by client {}", holder, request.peer_addr);
+                    format!("OK: UART unlocked (was locked by {})\r\n", holder)
+                }
+            },
+        }
+    }
+}
+
+/// AT+BRIDGE=ON|OFF resumes or pauses forwarding between UART and the network
+struct Bridge;
+
+impl CommandHandler for Bridge {
+    fn name(&self) -> &'static str {
+        "AT+BRIDGE"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+BRIDGE=ON|OFF - Resume or pause UART forwarding for maintenance",
+            "AT+BRIDGE?     - Show whether UART forwarding is paused",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| bridge_state(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        Some(match args.strip_prefix('=')? {
+            "ON" | "1" => Ok(CommandPlan::SetBridge(true)),
+            "OFF" | "0" => Ok(CommandPlan::SetBridge(false)),
+            other => Err(format!("Invalid value: {} (use ON or OFF)", other)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetBridge(enabled) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        // 纯透明部署中暂停转发会使设备失去作用
+        if !*enabled && !request.context.bridge_pausable {
+            return "ERROR: Bridge cannot be paused in transparent mode\r\n".to_string();
+        }
+        request.context.uart_manager.set_bridge_enabled(*enabled);
+        info!(
+            "Bridge {} by client {}",
+            if *enabled { "resumed" } else { "paused" },
+            request.peer_addr
+        );
+        if *enabled {
+            "OK: Bridge resumed\r\n".to_string()
+        } else {
+            "OK: Bridge paused\r\n".to_string()
+        }
+    }
+}
+
+/// AT+EOL=<tcp>,<uart> sets the line ending translation of command mode clients
+struct LineEndings;
+
+impl CommandHandler for LineEndings {
+    fn name(&self) -> &'static str {
+        "AT+EOL"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+EOL=<tcp>,<uart> - Translate line endings, e.g. CrToCrLf,LfToCrLf",
+            "AT+EOL?        - Show the line ending translation",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| line_endings(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let args = args.strip_prefix('=')?;
+        let (tcp_mode, uart_mode) = args.split_once(',').unwrap_or((args, "None"));
+        Some(match (TcpToUartEol::parse(tcp_mode), UartToTcpEol::parse(uart_mode)) {
+            (Some(tcp_to_uart), Some(uart_to_tcp)) => Ok(CommandPlan::SetLineEndings { tcp_to_uart, uart_to_tcp }),
+            (None, _) => Err(format!(
+                "Invalid TCP>UART mode: {} (use None, CrToCrLf, LfToCrLf or StripCr)",
+                tcp_mode.trim()
+            )),
+            (_, None) => Err(format!("Invalid UART>TCP mode: {} (use None or LfToCrLf)", uart_mode.trim())),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetLineEndings { tcp_to_uart, uart_to_tcp } = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        request.context.uart_manager.set_line_endings(*tcp_to_uart, *uart_to_tcp);
+        info!("Line endings changed by client {}", request.peer_addr);
+        format!("OK: Line endings TCP>UART {}, UART>TCP {}\r\n", tcp_to_uart, uart_to_tcp)
+    }
+}
+
+/// AT+REPLAY=<n>|OFF replays the last UART bytes to new clients
+struct Replay;
+
+impl CommandHandler for Replay {
+    fn name(&self) -> &'static str {
+        "AT+REPLAY"
+    }
+
+    fn help(&self) -> &'static [&'static str] {
+        &[
+            "AT+REPLAY=<n>|OFF - Replay the last n UART bytes to new clients",
+            "AT+REPLAY?     - Show the UART history replay size",
+        ]
+    }
+
+    fn answer(&self, args: &str, request: &CommandRequest) -> Option<String> {
+        args.starts_with('?').then(|| replay(request))
+    }
+
+    fn plan(&self, args: &str) -> Option<std::result::Result<CommandPlan, String>> {
+        let value = args.strip_prefix('=')?;
+        if value == "OFF" {
+            return Some(Ok(CommandPlan::SetReplay(0)));
+        }
+        Some(match value.parse::<usize>() {
+            Ok(bytes) if bytes <= uart::MAX_REPLAY_BYTES => Ok(CommandPlan::SetReplay(bytes)),
+            _ => Err(format!("Invalid replay size: {} (use 0-{} or OFF)", value, uart::MAX_REPLAY_BYTES)),
+        })
+    }
+
+    fn apply(&self, plan: &CommandPlan, request: &CommandRequest) -> String {
+        let CommandPlan::SetReplay(bytes) = plan else {
+            return foreign_plan(self.name(), plan);
+        };
+        match request.context.uart_manager.set_replay_bytes(*bytes) {
+            Ok(()) if *bytes == 0 => "OK: UART history replay disabled\r\n".to_string(),
+            Ok(()) => format!("OK: Replaying last {} bytes to new clients\r\n", bytes),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        }
+    }
+}
+
+fn baudrate(request: &CommandRequest) -> String {
+    format!("Current baudrate: {}\r\n", request.context.uart_manager.get_baudrate())
+}
+
+fn serial_settings(request: &CommandRequest) -> String {
+    let uart_manager = &request.context.uart_manager;
+    let format = uart_manager.get_format();
+    format!(
+        "UART: {},{},{},{}\r\n",
+        uart_manager.get_baudrate(),
+        format.data_bits,
+        format.parity,
+        format.stop_bits
+    )
+}
+
+fn framing(request: &CommandRequest) -> String {
+    format!("UART framing: {}\r\n", request.context.uart_manager.framing())
+}
+
+fn delimiter(request: &CommandRequest) -> String {
+    match request.context.uart_manager.framing().delimiter {
+        Some(delimiter) => format!("Frame delimiter: {}\r\n", uart::format_hex(&delimiter)),
+        None => "Frame delimiter: off\r\n".to_string(),
+    }
+}
+
+fn batching(request: &CommandRequest) -> String {
+    let framing = request.context.uart_manager.framing();
+    if framing.batch_ms == 0 {
+        "UART batching: off\r\n".to_string()
+    } else if framing.is_batching() {
+        format!("UART batching: {} bytes within {} ms\r\n", framing.batch_bytes, framing.batch_ms)
+    } else {
+        format!(
+            "UART batching: {} bytes within {} ms (inactive while framing is {})\r\n",
+            framing.batch_bytes, framing.batch_ms, framing
+        )
+    }
+}
+
+// 不显示WiFi station的密码
+fn sta_ssid(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => format!("WiFi station SSID: {}\r\n", wifi.sta_ssid()),
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+fn sta_link(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => match wifi.sta_link() {
+            Ok(Some(link)) => format!(
+                "RSSI: {} dBm ({})\r\nChannel: {}\r\nBSSID: {}\r\n",
+                link.rssi,
+                link.quality(),
+                link.channel,
+                link.bssid_string()
+            ),
+            // 未连接不是错误
+            Ok(None) => "RSSI: not connected\r\n".to_string(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        },
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+fn country(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => format!("WiFi country: {}\r\n", wifi.country_code()),
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+fn tx_power(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => format!("WiFi max TX power: {} dBm\r\n", wifi.tx_power_dbm()),
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+// 不显示WiFi access point的密码
+fn access_point(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => format!(
+            "Access point SSID: {}\r\nChannel: {}{}\r\nHidden: {}\r\nBandwidth: {}\r\nSecurity: {}\r\n",
+            wifi.ap_ssid(),
+            wifi.ap_channel(),
+            if wifi.is_ap_auto_channel() { " (auto)" } else { "" },
+            if wifi.is_ap_hidden() { "YES" } else { "NO" },
+            wifi.ap_bandwidth(),
+            wifi.ap_auth()
+        ),
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+// 不显示WiFi access point的密码
+fn ap_security(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => format!("Access point security: {}\r\n", wifi.ap_auth()),
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+fn port(request: &CommandRequest) -> String {
+    let active_port = request.context.active_port.load(Ordering::Relaxed);
+    match TcpServer::saved_port(request.context) {
+        Some(saved) if saved != active_port => {
+            format!("Port: {} (saved: {}, used after restart)\r\n", active_port, saved)
+        }
+        _ => format!("Port: {}\r\n", active_port),
+    }
+}
+
+fn banner(request: &CommandRequest) -> String {
+    match request.context.banner.lock().as_deref() {
+        // 换行显示为\n，与AT+BANNER=的写法一致
+        Ok(Some(banner)) => format!(
+            "Welcome banner: {}\r\n",
+            banner.trim_end().replace("\r\n", "\\n")
+        ),
+        Ok(None) => "Welcome banner: OFF\r\n".to_string(),
+        Err(_) => "ERROR: Failed to lock welcome banner\r\n".to_string(),
+    }
+}
+
+fn access_list(request: &CommandRequest) -> String {
+    let Ok(list) = request.context.access_list.lock() else {
+        return "ERROR: Failed to lock access list\r\n".to_string();
+    };
+    if list.is_empty() {
+        return "Access list: empty (all peers allowed)\r\n".to_string();
+    }
+    list.rules()
+        .iter()
+        .enumerate()
+        .map(|(index, rule)| format!("{}: {}\r\n", index + 1, rule))
+        .collect()
+}
+
+fn name(_request: &CommandRequest) -> String {
+    let name = device_name::get();
+    format!("Device name: {} ({}.local)\r\n", name, name)
+}
+
+fn gap_markers(request: &CommandRequest) -> String {
+    match request.client_manager.mark_gaps(request.peer_addr) {
+        Ok(enabled) => format!("Gap markers: {}\r\n", if enabled { "ON" } else { "OFF" }),
+        Err(e) => format!("ERROR: {}\r\n", e),
+    }
+}
+
+fn verbose(request: &CommandRequest) -> String {
+    let enabled = request.client_manager.is_verbose(request.peer_addr);
+    format!("Verbose replies: {}\r\n", if enabled { "ON" } else { "OFF" })
+}
+
+fn tap_mode(request: &CommandRequest) -> String {
+    match request.client_manager.is_hex_tap(request.peer_addr) {
+        Ok(enabled) => format!("Tap mode: {}\r\n", if enabled { "HEX" } else { "RAW" }),
+        Err(e) => format!("ERROR: {}\r\n", e),
+    }
+}
+
+fn log_streaming(request: &CommandRequest) -> String {
+    match request.client_manager.is_log_stream(request.peer_addr) {
+        Ok(enabled) => format!("Log streaming: {}\r\n", if enabled { "ON" } else { "OFF" }),
+        Err(e) => format!("ERROR: {}\r\n", e),
+    }
+}
+
+fn log_levels(request: &CommandRequest) -> String {
+    let levels = log_level::current();
+    let saved = request
+        .context
+        .storage
+        .as_ref()
+        .and_then(|storage| storage.lock().ok()?.read_log_levels())
+        .and_then(|spec| LogLevels::parse(&spec).ok());
+    match saved {
+        Some(saved) if saved != levels => {
+            format!("Log levels: {} (saved: {})\r\n", levels, saved)
+        }
+        _ => format!("Log levels: {}\r\n", levels),
+    }
+}
+
+fn uptime(_request: &CommandRequest) -> String {
+    let uptime = time::uptime();
+    format!(
+        "Uptime: {} s ({})\r\n",
+        uptime.as_secs(),
+        time::format_duration(uptime)
+    )
+}
+
+fn boot_info(_request: &CommandRequest) -> String {
+    match boot_info::current() {
+        Some(info) => info.report(),
+        None => "ERROR: Boot information not recorded\r\n".to_string(),
+    }
+}
+
+fn version(_request: &CommandRequest) -> String {
+    format!("{}\r\n", VersionInfo::current())
+}
+
+// 控制端口上也列出数据端口的客户端
+fn clients(request: &CommandRequest) -> String {
+    match request.context.data_clients.list_clients() {
+        Ok(clients) if clients.is_empty() => "No clients connected\r\n".to_string(),
+        Ok(clients) => clients
+            .iter()
+            .map(|client| format!("Client: {}\r\n", client))
+            .collect(),
+        Err(e) => format!("ERROR: {}\r\n", e),
+    }
+}
+
+fn stations(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(wifi)) => match wifi.connected_stations() {
+            Ok(stations) if stations.is_empty() => "No stations connected\r\n".to_string(),
+            Ok(stations) => stations
+                .iter()
+                .map(|station| format!("Station: {}\r\n", station))
+                .collect(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        },
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+// 扫描期间AP保持运行
+fn scan(request: &CommandRequest) -> String {
+    match request.context.wifi_manager.as_ref().map(|wifi| wifi.lock()) {
+        Some(Ok(mut wifi)) => match wifi.scan() {
+            Ok(networks) if networks.is_empty() => "No networks found\r\n".to_string(),
+            Ok(networks) => networks
+                .iter()
+                .map(|network| format!("Network: {}\r\n", network))
+                .collect(),
+            Err(e) => format!("ERROR: {}\r\n", e),
+        },
+        Some(Err(_)) => "ERROR: Failed to lock WiFi manager\r\n".to_string(),
+        None => "ERROR: WiFi manager not available\r\n".to_string(),
+    }
+}
+
+fn gpio_states(request: &CommandRequest) -> String {
+    let states = request.context.gpio.as_ref().map(|gpio| gpio.states()).unwrap_or_default();
+    if states.is_empty() {
+        return "No GPIOs configured\r\n".to_string();
+    }
+    states
+        .iter()
+        .map(|state| {
+            format!(
+                "{} (GPIO{}, {}, active {}): {}{}\r\n",
+                state.name,
+                state.pin,
+                match state.direction {
+                    GpioDirection::Output => "output",
+                    GpioDirection::Input => "input",
+                },
+                if state.active_low { "low" } else { "high" },
+                u8::from(state.active),
+                if state.pulsing { " (pulsing)" } else { "" }
+            )
+        })
+        .collect()
+}
+
+fn lock_holder(request: &CommandRequest) -> String {
+    match request.context.data_clients.exclusive_holder() {
+        Some(holder) => format!("UART locked by {}\r\n", holder),
+        None => "UART not locked\r\n".to_string(),
+    }
+}
+
+fn bridge_state(request: &CommandRequest) -> String {
+    let uart_manager = &request.context.uart_manager;
+    if uart_manager.is_bridge_enabled() {
+        "Bridge: ON\r\n".to_string()
+    } else {
+        format!("Bridge: OFF ({} bytes from UART discarded)\r\n", uart_manager.bridge_discarded())
+    }
+}
+
+fn line_endings(request: &CommandRequest) -> String {
+    let (tcp_to_uart, uart_to_tcp) = request.context.uart_manager.line_endings();
+    format!("EOL: TCP>UART {}, UART>TCP {}\r\n", tcp_to_uart, uart_to_tcp)
+}
+
+fn replay(request: &CommandRequest) -> String {
+    let uart_manager = &request.context.uart_manager;
+    match uart_manager.replay_bytes() {
+        0 => "Replay: OFF\r\n".to_string(),
+        bytes => format!("Replay: {} bytes ({} buffered)\r\n", bytes, uart_manager.replay_len()),
+    }
+}
+
+fn history(request: &CommandRequest) -> String {
+    match request.client_manager.command_history(request.peer_addr) {
+        Ok(history) if history.is_empty() => "Command history is empty\r\n".to_string(),
+        Ok(history) => history
+            .iter()
+            .enumerate()
+            .map(|(i, line)| format!("{:>3}  {}\r\n", i + 1, line))
+            .collect(),
+        Err(e) => format!("ERROR: {}\r\n", e),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{Parity, StopBits};
+    use log::LevelFilter;
+
+    #[test]
+    fn replies_end_with_ok_after_data_lines() {
+        assert_eq!(
+            format_reply("AT+BAUD", "OK: Baudrate set to 9600\r\n", false),
+            "+BAUD: Baudrate set to 9600\r\nOK\r\n"
+        );
+        assert_eq!(
+            format_reply("AT+UPTIME", "Uptime: 5 s\r\nClients: 2\r\n", false),
+            "+UPTIME: Uptime: 5 s\r\n+UPTIME: Clients: 2\r\nOK\r\n"
+        );
+        assert_eq!(format_reply("AT+PING", "", false), "OK\r\n");
+        assert_eq!(
+            format_reply("AT+GPIO", "Pin 4 set\r\nERROR: Pin 5 is reserved\r\nERROR: later\r\n", false),
+            "+GPIO: Pin 4 set\r\nERROR 3 Pin 5 is reserved\r\n"
+        );
+        assert_eq!(format_reply("AT+BAUD", "OK: Baudrate set to 9600\r\n", true), "OK: Baudrate set to 9600\r\n");
+    }
+
+    #[test]
+    fn errors_carry_their_code() {
+        assert_eq!(
+            format_error(ErrorCode::UnknownCommand, "Unknown command: AT+X", false),
+            "ERROR 1 Unknown command: AT+X\r\n"
+        );
+        assert_eq!(format_error(ErrorCode::InvalidArgument, "Bad", false), "ERROR 2 Bad\r\n");
+        assert_eq!(format_error(ErrorCode::Failed, "Bad", false), "ERROR 3 Bad\r\n");
+        assert_eq!(format_error(ErrorCode::InvalidFormat, "Bad", false), "ERROR 4 Bad\r\n");
+        assert_eq!(format_error(ErrorCode::InvalidArgument, "Bad", true), "ERROR: Bad\r\n");
+        assert_eq!(
+            format_error(ErrorCode::UnknownCommand, "Unknown command: AT+X", true),
+            "ERROR: Unknown command: AT+X\r\nType AT+HELP for available commands\r\n"
+        );
+    }
+
+    #[test]
+    fn help_documents_the_error_codes() {
+        let help = CommandRegistry::standard().help_text();
+        assert!(help.contains("Replies end with OK or ERROR <code> <message>"));
+        for code in ErrorCode::ALL {
+            assert!(help.contains(&format!("  {} - {}\r\n", code, code.description())), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn settings_with_passwords_are_secret() {
+        let registry = CommandRegistry::standard();
+        for line in ["AT+WIFISTA=Net,pass", "AT+AP=Net,pass,6,0", "AT+APSEC=WPA2,pass", "AT+VERIFY=at+ap=Net,pass,6,0"] {
+            assert!(registry.is_secret(line), "{}", line);
+        }
+        for line in ["AT+WIFISTA?", "AT+AP?", "AT+APSEC?", "AT+BAUD=9600", "AT+VERIFY=AT+BAUD=9600", "AT+APX=1"] {
+            assert!(!registry.is_secret(line), "{}", line);
+        }
+    }
+
+    /// Plan of a configuration form, panicking if the handler does not plan it
+    fn plan(handler: &dyn CommandHandler, args: &str) -> std::result::Result<CommandPlan, String> {
+        handler.plan(args).unwrap_or_else(|| panic!("{}{} is not planned", handler.name(), args))
+    }
+
+    #[test]
+    fn queries_are_not_planned() {
+        let registry = CommandRegistry::standard();
+        let queries = ["AT+BAUD?", "AT+BAUD", "AT+STATS", "AT+STATS?", "AT+LOCK?", "AT+AUTOBAUD?", "AT+UNLOCK=1"];
+        for line in queries {
+            assert_eq!(registry.plan(line), None, "{}", line);
+        }
+        assert_eq!(registry.plan("AT+BAUD=9600"), Some(Ok(CommandPlan::SetBaudrate(9600))));
+    }
+
+    #[test]
+    fn plans_describe_the_change() {
+        let cases = [
+            (CommandPlan::SetBaudrate(57600), "Baudrate would change to 57600"),
+            (CommandPlan::SetMarkGaps(false), "Gap markers would be disabled"),
+            (CommandPlan::SetTcpPort(9000), "TCP port would be set to 9000 after restart"),
+            (CommandPlan::SetEcho(true), "Echo would be enabled"),
+            (CommandPlan::SetHexTap(true), "Tap mode would change to HEX"),
+            (CommandPlan::SetBridge(false), "Bridge would be paused"),
+            (CommandPlan::SetReplay(0), "UART history replay would be disabled"),
+            (
+                CommandPlan::SetGpio { name: "RESET".to_string(), action: GpioAction::Pulse(None) },
+                "GPIO RESET would be pulsed",
+            ),
+            (CommandPlan::ResetTarget { bootloader: true }, "Target would be reset into its bootloader"),
+            (CommandPlan::RunSelfTest(SelfTest::Loopback), "LOOPBACK self test would run"),
+        ];
+        for (plan, description) in cases {
+            assert_eq!(plan.to_string(), description);
+        }
+        // 计划中不显示密码
+        let station = plan(&WiFiStation, "=Workshop,secret-pass").unwrap();
+        assert_eq!(station.to_string(), "WiFi station would connect to Workshop");
+    }
+
+    #[test]
+    fn baud_accepts_rates_in_range() {
+        assert_eq!(plan(&Baud, "=115200"), Ok(CommandPlan::SetBaudrate(115_200)));
+        assert_eq!(
+            plan(&Baud, "=100"),
+            Err(format!("Unsupported baudrate: 100 (use {}-{})", uart::MIN_BAUDRATE, uart::MAX_BAUDRATE))
+        );
+        assert_eq!(plan(&Baud, "=fast"), Err("Invalid baudrate value: fast".to_string()));
+    }
+
+    #[test]
+    fn autobaud_only_applies_on_request() {
+        assert_eq!(plan(&AutoBaud, ""), Ok(CommandPlan::DetectBaudrate { apply: false }));
+        assert_eq!(plan(&AutoBaud, "=APPLY"), Ok(CommandPlan::DetectBaudrate { apply: true }));
+        assert_eq!(AutoBaud.plan("=NOW"), None);
+    }
+
+    #[test]
+    fn uart_names_the_rejected_field() {
+        assert_eq!(
+            plan(&Uart, "=9600,8,E,1"),
+            Ok(CommandPlan::SetSerialParams {
+                baudrate: 9600,
+                format: SerialFormat { data_bits: 8, parity: Parity::Even, stop_bits: StopBits::One },
+            })
+        );
+        assert_eq!(
+            plan(&Uart, "=9600, 7 ,O,1.5"),
+            Ok(CommandPlan::SetSerialParams {
+                baudrate: 9600,
+                format: SerialFormat { data_bits: 7, parity: Parity::Odd, stop_bits: StopBits::OnePointFive },
+            })
+        );
+        assert_eq!(plan(&Uart, "=9600,8,E"), Err("Expected AT+UART=<baud>,<data>,<parity>,<stop>".to_string()));
+        assert_eq!(plan(&Uart, "=1,8,N,1"), Err("Invalid baud field: 1".to_string()));
+        assert_eq!(plan(&Uart, "=9600,9,N,1"), Err("Invalid data bits field: 9 (use 5-8)".to_string()));
+        assert_eq!(plan(&Uart, "=9600,8,X,1"), Err("Invalid parity field: X (use N, E or O)".to_string()));
+        assert_eq!(plan(&Uart, "=9600,8,N,3"), Err("Invalid stop bits field: 3 (use 1, 1.5 or 2)".to_string()));
+    }
+
+    #[test]
+    fn frame_takes_a_gap_and_a_size() {
+        assert_eq!(plan(&Frame, "=20,512"), Ok(CommandPlan::SetFrameGap { gap_ms: 20, max_bytes: 512 }));
+        assert_eq!(
+            plan(&Frame, "=OFF"),
+            Ok(CommandPlan::SetFrameGap { gap_ms: 0, max_bytes: uart::MAX_FRAME_BYTES })
+        );
+        assert_eq!(plan(&Frame, "=20"), Err("Expected AT+FRAME=<gap_ms>,<max> or AT+FRAME=OFF".to_string()));
+        assert_eq!(plan(&Frame, "=60001,512"), Err("Invalid gap: 60001 (use 0-60000 ms)".to_string()));
+        assert_eq!(
+            plan(&Frame, "=20,0"),
+            Err(format!("Invalid frame size: 0 (use 1-{})", uart::MAX_FRAME_BYTES))
+        );
+    }
+
+    #[test]
+    fn delimiter_is_parsed_from_hex_bytes() {
+        let crlf = Ok(CommandPlan::SetFrameDelimiter(Some(vec![0x0D, 0x0A])));
+        assert_eq!(plan(&Delimiter, "=0D0A"), crlf);
+        assert_eq!(plan(&Delimiter, "=0D 0A"), crlf);
+        assert_eq!(plan(&Delimiter, "=0x0D 0x0A"), crlf);
+        assert_eq!(plan(&Delimiter, "=OFF"), Ok(CommandPlan::SetFrameDelimiter(None)));
+        for bad in ["0D0", "+D", "GG", ""] {
+            assert_eq!(
+                plan(&Delimiter, &format!("={}", bad)),
+                Err(format!("Invalid delimiter: {} (use hex bytes, e.g. 0D0A)", bad)),
+                "{}",
+                bad
+            );
+        }
+        assert_eq!(
+            plan(&Delimiter, "=000102030405060708"),
+            Err(format!("Delimiter too long: 9 bytes (max {})", uart::MAX_DELIMITER_LEN))
+        );
+    }
+
+    #[test]
+    fn batch_takes_a_size_and_a_latency() {
+        assert_eq!(plan(&Batch, "=256,10"), Ok(CommandPlan::SetBatching(Some((256, 10)))));
+        assert_eq!(plan(&Batch, "=OFF"), Ok(CommandPlan::SetBatching(None)));
+        assert_eq!(plan(&Batch, "=256"), Err("Expected AT+BATCH=<bytes>,<ms> or AT+BATCH=OFF".to_string()));
+        assert_eq!(
+            plan(&Batch, "=0,10"),
+            Err(format!("Invalid batch size: 0 (use 1-{})", uart::MAX_FRAME_BYTES))
+        );
+        assert_eq!(
+            plan(&Batch, "=256,1001"),
+            Err(format!("Invalid batch latency: 1001 (use 0-{} ms)", uart::MAX_BATCH_LATENCY_MS))
+        );
+    }
+
+    #[test]
+    fn station_credentials_are_checked() {
+        assert_eq!(
+            plan(&WiFiStation, "=Home,se,cret"),
+            Ok(CommandPlan::SetStaCredentials { ssid: "Home".to_string(), password: "se,cret".to_string() })
+        );
+        assert_eq!(
+            plan(&WiFiStation, "=Open"),
+            Ok(CommandPlan::SetStaCredentials { ssid: "Open".to_string(), password: String::new() })
+        );
+        assert_eq!(plan(&WiFiStation, "=,pass"), Err("Invalid SSID (must be 1-32 bytes)".to_string()));
+        assert_eq!(
+            plan(&WiFiStation, &format!("=Home,{}", "p".repeat(65))),
+            Err("Invalid password (must be at most 64 bytes)".to_string())
+        );
+    }
+
+    #[test]
+    fn access_point_fields_are_taken_from_the_right() {
+        assert_eq!(
+            plan(&AccessPoint, "=Lab,pa,ss,6,1,HT40"),
+            Ok(CommandPlan::SetAccessPoint {
+                ssid: "Lab".to_string(),
+                password: "pa,ss".to_string(),
+                channel: 6,
+                hidden: true,
+                bandwidth: Some(ApBandwidth::Ht40),
+            })
+        );
+        assert_eq!(
+            plan(&AccessPoint, "=Lab,password,auto,0"),
+            Ok(CommandPlan::SetAccessPoint {
+                ssid: "Lab".to_string(),
+                password: "password".to_string(),
+                channel: 0,
+                hidden: false,
+                bandwidth: None,
+            })
+        );
+        let usage = "Expected <ssid>,<password>,<channel|auto>,<0|1>[,<HT20|HT40>]".to_string();
+        assert_eq!(plan(&AccessPoint, "=Lab,6,0"), Err(usage));
+        assert_eq!(plan(&AccessPoint, "=Lab,password,14,0"), Err("Invalid channel: 14 (use 1-13 or AUTO)".to_string()));
+        assert_eq!(plan(&AccessPoint, "=Lab,password,6,2"), Err("Invalid hidden flag: 2 (use 1 or 0)".to_string()));
+    }
+
+    #[test]
+    fn reset_and_factory_need_confirmation() {
+        assert_eq!(plan(&Reset, "=YES"), Ok(CommandPlan::Restart));
+        assert_eq!(plan(&Reset, ""), Err("Confirm with AT+RESET=YES".to_string()));
+        assert_eq!(plan(&FactoryReset, "=YES"), Ok(CommandPlan::FactoryReset));
+        assert_eq!(plan(&FactoryReset, "=yes"), Err("Confirm with AT+FACTORY=YES (erases all settings)".to_string()));
+    }
+
+    #[test]
+    fn port_and_kick_are_checked() {
+        assert_eq!(plan(&Port, "=8080"), Ok(CommandPlan::SetTcpPort(8080)));
+        assert_eq!(plan(&Port, "=0"), Err("Invalid port: 0 (use 1-65535)".to_string()));
+        assert_eq!(plan(&Port, "=65536"), Err("Invalid port: 65536 (use 1-65535)".to_string()));
+        let addr: SocketAddr = "192.168.4.2:50000".parse().unwrap();
+        assert_eq!(plan(&Kick, "=192.168.4.2:50000"), Ok(CommandPlan::Kick(addr)));
+        assert_eq!(plan(&Kick, "=192.168.4.2"), Err("Invalid address: 192.168.4.2 (use <ip>:<port>)".to_string()));
+    }
+
+    #[test]
+    fn lock_names_a_client_by_address() {
+        let addr: SocketAddr = "192.168.4.2:50000".parse().unwrap();
+        assert_eq!(plan(&Lock, ""), Ok(CommandPlan::LockUart(None)));
+        assert_eq!(plan(&Lock, "=192.168.4.2:50000"), Ok(CommandPlan::LockUart(Some(addr))));
+        assert_eq!(plan(&Lock, "=me"), Err("Invalid address: me (use <ip>:<port>)".to_string()));
+        assert_eq!(plan(&Unlock, ""), Ok(CommandPlan::UnlockUart));
+    }
+
+    #[test]
+    fn banner_and_name_are_checked() {
+        assert_eq!(
+            plan(&Banner, "=Hi {client_addr}"),
+            Ok(CommandPlan::SetBanner(Some("Hi {client_addr}".to_string())))
+        );
+        assert_eq!(plan(&Banner, "=OFF"), Ok(CommandPlan::SetBanner(None)));
+        assert_eq!(plan(&Banner, "="), Err("Empty banner (use AT+BANNER=OFF to disable it)".to_string()));
+        assert_eq!(
+            plan(&Banner, &format!("={}", "x".repeat(storage::MAX_BANNER_LEN + 1))),
+            Err(format!("Banner is longer than {} bytes", storage::MAX_BANNER_LEN))
+        );
+        assert_eq!(plan(&Name, "=bench-3"), Ok(CommandPlan::SetDeviceName("bench-3".to_string())));
+        assert_eq!(
+            plan(&Name, "=bench_3"),
+            Err(format!("Invalid device name: bench_3 (use 1-{} letters, digits or '-')", device_name::MAX_LEN))
+        );
+    }
+
+    #[test]
+    fn access_list_edits_are_parsed() {
+        assert_eq!(
+            plan(&Acl, "=add,deny,10.0.0.0/8"),
+            Ok(CommandPlan::AddAclRule(AclRule::parse("DENY", "10.0.0.0/8").unwrap()))
+        );
+        assert_eq!(plan(&Acl, "=DEL,2"), Ok(CommandPlan::RemoveAclRule(2)));
+        assert_eq!(plan(&Acl, "=CLEAR"), Ok(CommandPlan::ClearAcl));
+        assert_eq!(
+            plan(&Acl, "=ADD,MAYBE,10.0.0.1"),
+            Err("Invalid rule: MAYBE,10.0.0.1 (use ALLOW or DENY and <ip>[/<prefix>])".to_string())
+        );
+        assert_eq!(plan(&Acl, "=DEL,0"), Err("Invalid rule number: 0".to_string()));
+        assert_eq!(
+            plan(&Acl, "=CLEAR,1"),
+            Err("Invalid arguments (use ADD,<ALLOW|DENY>,<ip>[/<prefix>], DEL,<n> or CLEAR)".to_string())
+        );
+    }
+
+    #[test]
+    fn switches_accept_their_values() {
+        assert_eq!(plan(&Raw, "=1"), Ok(CommandPlan::SetRawMode(true)));
+        assert_eq!(plan(&Raw, "=2"), Err("Invalid value: 2 (use 1 or 0)".to_string()));
+        assert_eq!(plan(&Echo, "=OFF"), Ok(CommandPlan::SetEcho(false)));
+        assert_eq!(plan(&Verbose, "=ON"), Ok(CommandPlan::SetVerbose(true)));
+        assert_eq!(plan(&MarkGaps, "=1"), Ok(CommandPlan::SetMarkGaps(true)));
+        assert_eq!(plan(&MarkGaps, "=yes"), Err("Invalid value: yes (use ON or OFF)".to_string()));
+        assert_eq!(plan(&Tap, "=HEX"), Ok(CommandPlan::SetHexTap(true)));
+        assert_eq!(plan(&Tap, "=1"), Err("Invalid value: 1 (use HEX or RAW)".to_string()));
+        assert_eq!(plan(&Log, "=0"), Ok(CommandPlan::SetLogStream(false)));
+        assert_eq!(plan(&Bridge, "=OFF"), Ok(CommandPlan::SetBridge(false)));
+        assert_eq!(plan(&Stats, "=RESET"), Ok(CommandPlan::ResetStats));
+        assert_eq!(plan(&UartErrors, "=RESET"), Ok(CommandPlan::ResetUartErrors));
+        assert_eq!(plan(&BootInfo, "=RESET"), Ok(CommandPlan::ResetBootInfo));
+        assert_eq!(plan(&BootInfo, "=CLEAR"), Err("Invalid value: CLEAR (use RESET)".to_string()));
+        assert_eq!(plan(&TargetReset, "=BOOTLOADER"), Ok(CommandPlan::ResetTarget { bootloader: true }));
+        assert_eq!(plan(&TargetReset, "=FLASH"), Err("Invalid mode: FLASH (use BOOTLOADER or RUN)".to_string()));
+    }
+
+    #[test]
+    fn log_level_takes_an_optional_target() {
+        assert_eq!(
+            plan(&LogLevel, "=debug,uart"),
+            Ok(CommandPlan::SetLogLevel { level: LevelFilter::Debug, target: Some("uart".to_string()) })
+        );
+        assert_eq!(plan(&LogLevel, "=warn"), Ok(CommandPlan::SetLogLevel { level: LevelFilter::Warn, target: None }));
+        assert_eq!(plan(&LogLevel, "=SAVE"), Ok(CommandPlan::SaveLogLevels));
+        assert_eq!(
+            plan(&LogLevel, "=loud"),
+            Err("Invalid log level: loud (use error, warn, info, debug or trace)".to_string())
+        );
+    }
+
+    #[test]
+    fn gpio_takes_a_line_and_an_action() {
+        let set = |name: &str, action| Ok(CommandPlan::SetGpio { name: name.to_string(), action });
+        assert_eq!(plan(&Gpio, "=RESET,1"), set("RESET", GpioAction::Set(true)));
+        assert_eq!(plan(&Gpio, "=BOOT, 0"), set("BOOT", GpioAction::Set(false)));
+        assert_eq!(plan(&Gpio, "=RESET,PULSE"), set("RESET", GpioAction::Pulse(None)));
+        assert_eq!(plan(&Gpio, "=RESET,PULSE,50"), set("RESET", GpioAction::Pulse(Some(50))));
+        assert_eq!(
+            plan(&Gpio, "=RESET,PULSE,0"),
+            Err(format!("Invalid pulse length: 0 (use 1-{} ms)", gpio_control::MAX_PULSE_MS))
+        );
+        let usage = Err("Expected AT+GPIO=<name>,<0|1|PULSE[,ms]>".to_string());
+        assert_eq!(plan(&Gpio, "=,1"), usage);
+        assert_eq!(plan(&Gpio, "=RESET,2"), usage);
+    }
+
+    #[test]
+    fn send_decodes_hex_and_limits_the_payload() {
+        assert_eq!(plan(&SendHex, "=48690D0A"), Ok(CommandPlan::SendBytes(b"Hi\r\n".to_vec())));
+        assert_eq!(plan(&SendHex, "=48 69"), Ok(CommandPlan::SendBytes(b"Hi".to_vec())));
+        assert_eq!(
+            plan(&SendHex, "=4 869"),
+            Err("Incomplete hex byte at position 1 (use two digits per byte)".to_string())
+        );
+        assert_eq!(plan(&SendHex, "=48G9"), Err("Invalid hex digit 'G' at position 3".to_string()));
+        assert_eq!(
+            plan(&SendHex, "=486"),
+            Err("Incomplete hex byte at position 3 (use two digits per byte)".to_string())
+        );
+        assert_eq!(plan(&SendHex, "="), Err("Empty payload (use AT+SEND=<hex bytes>)".to_string()));
+        assert!(plan(&SendHex, &format!("={}", "00".repeat(MAX_SEND_BYTES))).is_ok());
+        assert_eq!(
+            plan(&SendHex, &format!("={}", "00".repeat(MAX_SEND_BYTES + 1))),
+            Err(format!("Payload too long (max {} bytes)", MAX_SEND_BYTES))
+        );
+        assert_eq!(plan(&SendLine, "=hello"), Ok(CommandPlan::SendLine("hello".to_string())));
+        assert_eq!(
+            plan(&SendLine, &format!("={}", "x".repeat(MAX_SEND_BYTES + 1))),
+            Err(format!("Text too long: {} bytes (max {})", MAX_SEND_BYTES + 1, MAX_SEND_BYTES))
+        );
+    }
+
+    #[test]
+    fn send_names_the_position_of_the_bad_character() {
+        let cases = [
+            ("zz", "Invalid hex digit 'z' at position 1"),
+            ("48 6G", "Invalid hex digit 'G' at position 5"),
+            ("0d0a-", "Invalid hex digit '-' at position 5"),
+            // 位置按字符计，不按字节计
+            ("48é9", "Invalid hex digit 'é' at position 3"),
+            ("4869\t0", "Incomplete hex byte at position 6 (use two digits per byte)"),
+            ("48 6 9", "Incomplete hex byte at position 4 (use two digits per byte)"),
+        ];
+        for (payload, error) in cases {
+            assert_eq!(SendHex::parse_hex(payload), Err(error.to_string()), "{:?}", payload);
+        }
+        assert_eq!(SendHex::parse_hex("0d0A\t48"), Ok(b"\r\nH".to_vec()));
+        assert_eq!(
+            SendHex::parse_hex(&format!("{}00", "00".repeat(MAX_SEND_BYTES))),
+            Err(format!("Payload too long (max {} bytes)", MAX_SEND_BYTES))
+        );
+    }
+
+    #[test]
+    fn test_eol_and_replay_are_checked() {
+        assert_eq!(plan(&Test, "=loopback"), Ok(CommandPlan::RunSelfTest(SelfTest::Loopback)));
+        assert_eq!(plan(&Test, "=DISK"), Err("Invalid test: DISK (use LOOPBACK, TCP or NVS)".to_string()));
+        assert_eq!(
+            plan(&LineEndings, "=CrToCrLf,LfToCrLf"),
+            Ok(CommandPlan::SetLineEndings { tcp_to_uart: TcpToUartEol::CrToCrLf, uart_to_tcp: UartToTcpEol::LfToCrLf })
+        );
+        assert_eq!(
+            plan(&LineEndings, "=StripCr"),
+            Ok(CommandPlan::SetLineEndings { tcp_to_uart: TcpToUartEol::StripCr, uart_to_tcp: UartToTcpEol::None })
+        );
+        assert_eq!(
+            plan(&LineEndings, "=None,StripCr"),
+            Err("Invalid UART>TCP mode: StripCr (use None or LfToCrLf)".to_string())
+        );
+        assert_eq!(plan(&Replay, "=1024"), Ok(CommandPlan::SetReplay(1024)));
+        assert_eq!(plan(&Replay, "=OFF"), Ok(CommandPlan::SetReplay(0)));
+        assert_eq!(
+            plan(&Replay, &format!("={}", uart::MAX_REPLAY_BYTES + 1)),
+            Err(format!(
+                "Invalid replay size: {} (use 0-{} or OFF)",
+                uart::MAX_REPLAY_BYTES + 1,
+                uart::MAX_REPLAY_BYTES
+            ))
+        );
+    }
+
+    #[test]
+    fn wifi_settings_are_checked() {
+        assert_eq!(plan(&Country, "=de"), Ok(CommandPlan::SetCountry("DE".to_string())));
+        assert_eq!(
+            plan(&Country, "=Germany"),
+            Err("Invalid country code: Germany (use two letters like DE, or 01 for worldwide)".to_string())
+        );
+        assert_eq!(plan(&TxPower, "=10"), Ok(CommandPlan::SetTxPower(10)));
+        assert_eq!(
+            plan(&TxPower, "=30"),
+            Err(format!("Invalid TX power: 30 (use {}-{} dBm)", wifi::MIN_TX_POWER_DBM, wifi::MAX_TX_POWER_DBM))
+        );
+        assert_eq!(
+            plan(&ApSecurity, "=OPEN"),
+            Ok(CommandPlan::SetApSecurity { auth: WiFiAuth::parse("OPEN").unwrap(), password: None })
+        );
+        assert_eq!(
+            plan(&ApSecurity, "=WEP,secret"),
+            Err("Invalid mode: WEP (use OPEN, WPA2, WPA2WPA3 or WPA3)".to_string())
+        );
+    }
+}
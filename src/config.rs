@@ -1,8 +1,146 @@
 use heapless::String;
 
+use crate::acl::AccessList;
+use crate::device_name;
+
+/// Which WiFi interfaces are used
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WiFiMode {
+    /// Only the access point; the station never scans for a network
+    ApOnly,
+    /// Only the station; clients reach the device through the joined network
+    StaOnly,
+    /// Access point and station at the same time
+    Mixed,
+}
+
+impl WiFiMode {
+    /// Whether the access point is running in this mode
+    pub fn has_ap(self) -> bool {
+        self != WiFiMode::StaOnly
+    }
+
+    /// Whether the station is used in this mode
+    pub fn has_sta(self) -> bool {
+        self != WiFiMode::ApOnly
+    }
+}
+
+/// Security of a WiFi network
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WiFiAuth {
+    /// No password
+    Open,
+    /// WPA2 with a pre-shared key
+    Wpa2Personal,
+    /// WPA3 for clients that support it, WPA2 for the others
+    Wpa2Wpa3Personal,
+    /// WPA3 (SAE) only
+    Wpa3Personal,
+}
+
+impl WiFiAuth {
+    /// Parse a mode name: OPEN, WPA2, WPA2WPA3 or WPA3 (case-insensitive)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "OPEN" => Some(WiFiAuth::Open),
+            "WPA2" => Some(WiFiAuth::Wpa2Personal),
+            "WPA2WPA3" | "WPA2/WPA3" => Some(WiFiAuth::Wpa2Wpa3Personal),
+            "WPA3" => Some(WiFiAuth::Wpa3Personal),
+            _ => None,
+        }
+    }
+
+    /// Whether WPA3 is used, which older chips and ESP-IDF versions reject
+    pub fn uses_wpa3(self) -> bool {
+        matches!(self, WiFiAuth::Wpa2Wpa3Personal | WiFiAuth::Wpa3Personal)
+    }
+
+    /// The mode to use where WPA3 is not supported
+    pub fn without_wpa3(self) -> Self {
+        if self.uses_wpa3() {
+            WiFiAuth::Wpa2Personal
+        } else {
+            self
+        }
+    }
+
+    /// Check that `password` suits this mode
+    ///
+    /// Open networks take no password; the WPA modes need 8 to 63 bytes.
+    pub fn check_password(self, password: &str) -> std::result::Result<(), std::string::String> {
+        match self {
+            WiFiAuth::Open if !password.is_empty() => {
+                Err("Open networks take no password".to_string())
+            }
+            WiFiAuth::Open => Ok(()),
+            _ if !(8..=63).contains(&password.len()) => {
+                Err(format!("{} requires a password of 8 to 63 bytes", self))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl std::fmt::Display for WiFiAuth {
+    /// Formats as the name accepted by `parse`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WiFiAuth::Open => write!(f, "OPEN"),
+            WiFiAuth::Wpa2Personal => write!(f, "WPA2"),
+            WiFiAuth::Wpa2Wpa3Personal => write!(f, "WPA2WPA3"),
+            WiFiAuth::Wpa3Personal => write!(f, "WPA3"),
+        }
+    }
+}
+
+/// Channel width of the access point
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApBandwidth {
+    /// 20 MHz, the most compatible
+    Ht20,
+    /// 40 MHz, using a secondary channel 4 channels above or below the primary
+    Ht40,
+}
+
+impl ApBandwidth {
+    /// Parse HT20 or HT40 (case-insensitive)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "HT20" => Some(ApBandwidth::Ht20),
+            "HT40" => Some(ApBandwidth::Ht40),
+            _ => None,
+        }
+    }
+
+    /// Secondary channel for primary `channel`, None for HT20
+    ///
+    /// The secondary channel lies above the primary where possible, below it on the
+    /// upper channels.
+    pub fn secondary_channel(self, channel: u8) -> Option<u8> {
+        match self {
+            ApBandwidth::Ht20 => None,
+            ApBandwidth::Ht40 if channel <= 7 => Some(channel + 4),
+            ApBandwidth::Ht40 => Some(channel - 4),
+        }
+    }
+}
+
+impl std::fmt::Display for ApBandwidth {
+    /// Formats as the name accepted by `parse`
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ApBandwidth::Ht20 => write!(f, "HT20"),
+            ApBandwidth::Ht40 => write!(f, "HT40"),
+        }
+    }
+}
+
 /// WiFi configuration
 #[derive(Debug, Clone)]
 pub struct WiFiConfig {
+    /// Which interfaces are used
+    pub mode: WiFiMode,
     /// SSID for client mode
     pub client_ssid: String<32>,
     /// Password for client mode
@@ -11,25 +149,96 @@ pub struct WiFiConfig {
     pub ap_ssid: String<32>,
     /// Password for access point mode
     pub ap_password: String<64>,
-    /// WiFi channel for access point mode
+    /// Security of the access point; an empty `ap_password` always runs it open
+    pub ap_auth: WiFiAuth,
+    /// Weakest security the station accepts from the network it joins
+    pub sta_auth: WiFiAuth,
+    /// WiFi channel for access point mode, 0 picks the least congested channel by a
+    /// scan when the access point starts
     pub ap_channel: u8,
+    /// Whether the access point hides its SSID from beacons
+    pub ap_hidden: bool,
+    /// Channel width of the access point
+    pub ap_bandwidth: ApBandwidth,
+    /// Regulatory country as two letters, e.g. "DE", or "01" for worldwide
+    pub country_code: String<2>,
+    /// Maximum transmit power in dBm
+    pub tx_power_dbm: u8,
     /// Maximum number of connections for access point mode
     pub ap_max_connections: u16,
+    /// Seconds to wait for the station to connect after its credentials change
+    pub sta_connect_timeout_secs: u32,
+    /// Milliseconds after which a network scan is aborted
+    pub scan_timeout_ms: u64,
+    /// Delay in milliseconds before the first station reconnect attempt
+    pub sta_reconnect_interval_ms: u64,
+    /// Upper limit in milliseconds for the doubling station reconnect delay
+    pub sta_max_backoff_ms: u64,
+    /// Station signal in dBm below which a warning is logged once it lasted a
+    /// minute, 0 disables the warning
+    pub sta_weak_rssi_dbm: i8,
 }
 
 impl Default for WiFiConfig {
     fn default() -> Self {
         Self {
+            mode: WiFiMode::Mixed,
             client_ssid: String::try_from("your_wifi_ssid").unwrap_or_default(),
             client_password: String::try_from("your_wifi_password").unwrap_or_default(),
-            ap_ssid: String::try_from("ESP32-UART-Bridge").unwrap_or_default(),
+            // 加上MAC后缀，多台设备同时开机时SSID不会重复
+            ap_ssid: String::try_from(format!("ESP32-UART-Bridge-{}", device_name::mac_suffix()).as_str())
+                .unwrap_or_default(),
             ap_password: String::try_from("12345678").unwrap_or_default(),
+            ap_auth: WiFiAuth::Wpa2Personal,
+            sta_auth: WiFiAuth::Wpa2Personal,
             ap_channel: 1,                // 使用通道 1，减少干扰
+            ap_hidden: false,
+            ap_bandwidth: ApBandwidth::Ht20, // 20MHz兼容性最好，受干扰也少
+            country_code: String::try_from("CN").unwrap_or_default(), // ESP-IDF默认值，允许信道1-13
+            tx_power_dbm: 20,             // 驱动允许的最大功率
             ap_max_connections: 4,        // 限制连接数量以提高稳定性
+            sta_connect_timeout_secs: 10, // 连接新网络的最长等待时间
+            scan_timeout_ms: 5000,        // 扫描全部信道通常不到2秒
+            sta_reconnect_interval_ms: 1000, // 断开1秒后首次重连
+            sta_max_backoff_ms: 60_000,   // 每次失败加倍，最多等待1分钟
+            sta_weak_rssi_dbm: -80,       // 低于-80dBm时连接通常不稳定
         }
     }
 }
 
+/// What to do when a client connects while the server is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionPolicy {
+    /// Refuse the new client
+    RejectNew,
+    /// Disconnect the longest-connected client to make room
+    EvictOldest,
+}
+
+/// How the TCP server handles its data port clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IoModel {
+    /// One handler thread per client, plus a writer thread
+    ThreadPerClient,
+    /// All clients multiplexed with poll() on the server thread
+    ///
+    /// Costs no stack per client, so `max_clients` can be raised to 8 or more
+    /// (within CONFIG_LWIP_MAX_SOCKETS). A slow command delays every client.
+    Poll,
+}
+
+/// Protocol spoken on the control port
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlProtocol {
+    /// AT command lines, for humans and scripts
+    Text,
+    /// Framed binary requests and responses (see `control_frame`)
+    ///
+    /// No welcome message is sent, and AT+LOG=ON must not be used on such a
+    /// connection since log lines are not framed.
+    Binary,
+}
+
 /// TCP server configuration
 #[derive(Debug, Clone)]
 pub struct TcpServerConfig {
@@ -37,8 +246,67 @@ pub struct TcpServerConfig {
     pub bind_address: &'static str,
     /// Port for the TCP server
     pub port: u16,
+    /// Port accepting only AT commands (None mixes commands into the data port)
+    ///
+    /// When set, the data port is purely transparent and never parses commands.
+    pub control_port: Option<u16>,
+    /// Protocol of the control port
+    pub control_protocol: ControlProtocol,
+    /// Port speaking Telnet with RFC 2217 com port control (None disables it)
+    ///
+    /// For pyserial `rfc2217://` URLs and esptool. Its clients receive UART data
+    /// like data port clients and count towards `max_clients`.
+    pub rfc2217_port: Option<u16>,
+    /// Port accepting WebSocket connections for browser-based terminals (None disables it)
+    ///
+    /// UART data is sent as binary messages. Its clients count towards `max_clients`.
+    pub websocket_port: Option<u16>,
     /// Buffer size for TCP operations
     pub buffer_size: usize,
+    /// Bytes that may be queued for one client before it is dropped as too slow
+    pub client_queue_limit: usize,
+    /// Seconds without activity after which a client is disconnected (0 disables)
+    pub idle_timeout_secs: u64,
+    /// Maximum number of concurrent TCP clients (0 means unlimited)
+    pub max_clients: usize,
+    /// What to do when a client connects while `max_clients` are connected
+    pub eviction_policy: EvictionPolicy,
+    /// How data port clients are served (the control port always uses threads)
+    pub io_model: IoModel,
+    /// Start clients in raw transparent mode: no welcome banner and no AT commands
+    pub transparent: bool,
+    /// Guard time in milliseconds around the "+++" escape from raw mode
+    pub escape_guard_ms: u64,
+    /// Milliseconds without data after which a partial command line is released
+    pub command_timeout_ms: u64,
+    /// Line ending appended to the text of AT+SENDLN
+    pub send_line_ending: &'static str,
+    /// Send small writes at once (TCP_NODELAY) instead of coalescing them with Nagle's
+    /// algorithm
+    ///
+    /// Disabling it helps bulk transfers such as firmware uploads through the bridge.
+    /// UART batching (AT+BATCH) already coalesces UART data before it is written;
+    /// with Nagle on as well, a batch can wait another round trip for the previous
+    /// one to be acknowledged, so use one or the other. AT+SOCKOPT changes it for a
+    /// single client.
+    pub tcp_nodelay: bool,
+    /// Socket send buffer size in bytes (SO_SNDBUF), 0 keeps the lwIP default
+    pub send_buffer_size: usize,
+    /// Socket receive buffer size in bytes (SO_RCVBUF), 0 keeps the lwIP default
+    pub recv_buffer_size: usize,
+    /// Seconds without traffic before TCP keepalive probes start (0 disables keepalive)
+    pub keepalive_idle_secs: u32,
+    /// Seconds between TCP keepalive probes
+    pub keepalive_interval_secs: u32,
+    /// Unanswered keepalive probes after which a client is considered dead
+    pub keepalive_count: u32,
+    /// Message sent to data port clients when they connect (None sends nothing)
+    ///
+    /// "{name}", "{client_addr}", "{baudrate}", "{port}", "{clients}" and "{max_clients}"
+    /// are replaced when the client connects, and a literal "\n" starts a new line.
+    pub welcome_message: Option<std::string::String>,
+    /// Rules deciding which peers may connect to the TCP ports (empty admits everyone)
+    pub access_list: AccessList,
 }
 
 impl Default for TcpServerConfig {
@@ -46,7 +314,439 @@ impl Default for TcpServerConfig {
         Self {
             bind_address: "0.0.0.0",      // 绑定到所有接口
             port: 8080,                 // 标准端口
+            control_port: Some(8081),   // 数据端口的下一个端口
+            control_protocol: ControlProtocol::Text,
+            rfc2217_port: None,         // 默认不启用RFC 2217（常用端口2217）
+            websocket_port: None,       // 默认不启用WebSocket
             buffer_size: 2048,          // 增大缓冲区以提高性能
+            client_queue_limit: 8192,   // 每个客户端最多排队8KB
+            idle_timeout_secs: 300,     // 5分钟无活动则断开
+            max_clients: 4,             // 每个客户端一个线程，限制数量以节省内存
+            eviction_policy: EvictionPolicy::RejectNew,
+            io_model: IoModel::ThreadPerClient,
+            transparent: false,         // 默认支持AT命令
+            escape_guard_ms: 1000,      // 与Hayes调制解调器相同的保护时间
+            command_timeout_ms: 2000,   // 留出逐字输入命令的时间
+            send_line_ending: "\r\n",
+            tcp_nodelay: true,          // 交互式终端需要低延迟
+            send_buffer_size: 0,        // 使用lwIP默认值
+            recv_buffer_size: 0,
+            keepalive_idle_secs: 60,    // 空闲1分钟后开始探测
+            keepalive_interval_secs: 10,
+            keepalive_count: 3,         // 约90秒内发现断线的客户端
+            welcome_message: Some(
+                "Welcome to {name} (ESP32 UART-TCP Bridge)! Your client ID: {client_addr}\r\n\
+                Type AT+HELP for available commands\r\n\
+                Current UART baudrate: {baudrate}\r\n\
+                Connected clients: {clients}/{max_clients}\r\n"
+                    .to_string(),
+            ),
+            access_list: AccessList::default(),
+        }
+    }
+}
+
+impl TcpServerConfig {
+    /// Seconds after which keepalive drops a silent dead peer (None if keepalive is disabled)
+    pub fn keepalive_timeout_secs(&self) -> Option<u32> {
+        if self.keepalive_idle_secs == 0 {
+            return None;
+        }
+        Some(self.keepalive_idle_secs + self.keepalive_interval_secs * self.keepalive_count)
+    }
+}
+
+/// Connect-out TCP client configuration
+///
+/// The device dials out to a remote host instead of (or in addition to) accepting
+/// connections, for deployments behind NAT.
+#[derive(Debug, Clone)]
+pub struct TcpClientModeConfig {
+    /// Whether to connect out to the remote host
+    pub enabled: bool,
+    /// Host name or IP address of the remote host
+    pub remote_host: &'static str,
+    /// Port of the remote host
+    pub remote_port: u16,
+    /// Delay in milliseconds before the first reconnect attempt
+    pub reconnect_interval_ms: u64,
+    /// Upper limit in milliseconds for the doubling reconnect delay
+    pub max_backoff_ms: u64,
+    /// Timeout in milliseconds for a single connection attempt
+    pub connect_timeout_ms: u64,
+}
+
+impl Default for TcpClientModeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,             // 默认只作为服务器
+            remote_host: "192.168.1.100",
+            remote_port: 8080,
+            reconnect_interval_ms: 1000, // 首次重连等待1秒
+            max_backoff_ms: 60_000,     // 每次失败加倍，最多等待1分钟
+            connect_timeout_ms: 5000,
+        }
+    }
+}
+
+/// UDP bridge configuration
+#[derive(Debug, Clone)]
+pub struct UdpBridgeConfig {
+    /// Whether to run the UDP bridge next to the TCP server
+    pub enabled: bool,
+    /// Bind address for the UDP socket
+    pub bind_address: &'static str,
+    /// Port for the UDP socket
+    pub port: u16,
+    /// Maximum number of peers receiving UART data
+    pub max_peers: usize,
+    /// Seconds after its last datagram that a peer stops receiving UART data
+    pub peer_timeout_secs: u64,
+    /// Size of the receive buffer, larger datagrams are truncated
+    pub buffer_size: usize,
+}
+
+impl Default for UdpBridgeConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,             // 默认只使用TCP
+            bind_address: "0.0.0.0",
+            port: 8082,                 // 控制端口的下一个端口
+            max_peers: 4,
+            peer_timeout_secs: 30,      // 接收方需定期发送数据报保持活动
+            buffer_size: 1472,          // 以太网MTU下不分片的最大UDP负载
+        }
+    }
+}
+
+/// MQTT bridge configuration
+///
+/// UART data is published to "<topic_prefix>/rx" and messages received on
+/// "<topic_prefix>/tx" are written to UART, for deployments where no one opens a
+/// TCP session.
+#[derive(Debug, Clone)]
+pub struct MqttConfig {
+    /// Whether to connect to the broker
+    pub enabled: bool,
+    /// Broker URL, e.g. "mqtt://192.168.1.100:1883" or "mqtts://broker.example.com"
+    pub broker_url: &'static str,
+    /// Client identifier (None uses the host name)
+    pub client_id: Option<&'static str>,
+    /// User name for the broker (None connects anonymously)
+    pub username: Option<&'static str>,
+    /// Password for the broker
+    pub password: Option<&'static str>,
+    /// Prefix of the "rx" and "tx" topics
+    pub topic_prefix: &'static str,
+    /// Publish and subscribe with QoS 1 instead of 0
+    pub qos1: bool,
+    /// Timeout in milliseconds for the broker to accept a connection
+    pub connect_timeout_ms: u64,
+    /// Delay in milliseconds before the first reconnect attempt
+    pub reconnect_interval_ms: u64,
+    /// Upper limit in milliseconds for the doubling reconnect delay
+    pub max_backoff_ms: u64,
+}
+
+impl Default for MqttConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,             // 默认不连接代理服务器
+            broker_url: "mqtt://192.168.1.100:1883",
+            client_id: None,
+            username: None,
+            password: None,
+            topic_prefix: "espc3",
+            qos1: false,                // 串口数据按QoS 0发布，延迟最低
+            connect_timeout_ms: 10_000,
+            reconnect_interval_ms: 1000, // 首次重连等待1秒
+            max_backoff_ms: 60_000,     // 每次失败加倍，最多等待1分钟
+        }
+    }
+}
+
+/// HTTP configuration page configuration
+#[derive(Debug, Clone)]
+pub struct HttpServerConfig {
+    /// Whether to run the HTTP server (configuration page and JSON API)
+    ///
+    /// Off by default: the API changes WiFi credentials without authentication, so
+    /// only enable it on trusted networks, ideally with an access list.
+    pub enabled: bool,
+    /// Port for the configuration page and the JSON API
+    pub port: u16,
+    /// Largest request body accepted by POST /api/config
+    pub max_body_bytes: usize,
+}
+
+impl Default for HttpServerConfig {
+    fn default() -> Self {
+        Self {
+            enabled: false,             // 接口没有认证，需要时再开启
+            port: 80,                   // 浏览器默认端口
+            max_body_bytes: 1024,       // 表单字段都很短
+        }
+    }
+}
+
+/// Heap and stack monitoring configuration
+#[derive(Debug, Clone)]
+pub struct MemoryConfig {
+    /// Seconds between heap and stack summaries in the log (0 disables)
+    pub log_interval_secs: u64,
+    /// Free heap in bytes below which new data connections are refused and the
+    /// client with the largest backlog is dropped (0 disables)
+    pub low_heap_bytes: u32,
+}
+
+impl Default for MemoryConfig {
+    fn default() -> Self {
+        Self {
+            log_interval_secs: 300,
+            low_heap_bytes: 24 * 1024,  // 留出WiFi和lwIP突发分配的余量
+        }
+    }
+}
+
+/// Task watchdog configuration
+#[derive(Debug, Clone)]
+pub struct WatchdogConfig {
+    /// Whether the UART forwarding and TCP server threads are supervised
+    pub enabled: bool,
+    /// Time in milliseconds a supervised thread may go without progress before
+    /// the device resets
+    pub timeout_ms: u32,
+}
+
+impl Default for WatchdogConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            timeout_ms: 30_000,         // 长于最慢的命令（连接新WiFi最多10秒）
+        }
+    }
+}
+
+/// UART parity setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Parity {
+    /// No parity bit
+    None,
+    /// Even parity
+    Even,
+    /// Odd parity
+    Odd,
+}
+
+/// UART stop bits setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopBits {
+    /// 1 stop bit
+    One,
+    /// 1.5 stop bits
+    OnePointFive,
+    /// 2 stop bits
+    Two,
+}
+
+/// UART character format (data bits, parity and stop bits)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SerialFormat {
+    /// Number of data bits (5-8)
+    pub data_bits: u8,
+    /// Parity setting
+    pub parity: Parity,
+    /// Stop bits setting
+    pub stop_bits: StopBits,
+}
+
+impl SerialFormat {
+    /// Parse the data bits field of an AT+UART command
+    pub fn parse_data_bits(value: &str) -> Option<u8> {
+        match value.trim().parse::<u8>() {
+            Ok(bits @ 5..=8) => Some(bits),
+            _ => None,
+        }
+    }
+
+    /// Parse the parity field of an AT+UART command (N/E/O)
+    pub fn parse_parity(value: &str) -> Option<Parity> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "N" | "NONE" => Some(Parity::None),
+            "E" | "EVEN" => Some(Parity::Even),
+            "O" | "ODD" => Some(Parity::Odd),
+            _ => None,
+        }
+    }
+
+    /// Parse the stop bits field of an AT+UART command (1, 1.5 or 2)
+    pub fn parse_stop_bits(value: &str) -> Option<StopBits> {
+        match value.trim() {
+            "1" => Some(StopBits::One),
+            "1.5" => Some(StopBits::OnePointFive),
+            "2" => Some(StopBits::Two),
+            _ => None,
+        }
+    }
+
+    /// Parse a compact format string like "8N1" or "7E1.5"
+    pub fn parse_compact(value: &str) -> Option<Self> {
+        let value = value.trim();
+        if value.len() < 3 || !value.is_ascii() {
+            return None;
+        }
+        Some(Self {
+            data_bits: Self::parse_data_bits(&value[0..1])?,
+            parity: Self::parse_parity(&value[1..2])?,
+            stop_bits: Self::parse_stop_bits(&value[2..])?,
+        })
+    }
+}
+
+impl Default for SerialFormat {
+    fn default() -> Self {
+        Self {
+            data_bits: 8,
+            parity: Parity::None,
+            stop_bits: StopBits::One,
+        }
+    }
+}
+
+impl std::fmt::Display for Parity {
+    /// Formats as the single-letter notation (N/E/O)
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Parity::None => write!(f, "N"),
+            Parity::Even => write!(f, "E"),
+            Parity::Odd => write!(f, "O"),
+        }
+    }
+}
+
+impl std::fmt::Display for StopBits {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            StopBits::One => write!(f, "1"),
+            StopBits::OnePointFive => write!(f, "1.5"),
+            StopBits::Two => write!(f, "2"),
+        }
+    }
+}
+
+impl std::fmt::Display for SerialFormat {
+    /// Formats as the compact notation, e.g. "8N1"
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}{}{}", self.data_bits, self.parity, self.stop_bits)
+    }
+}
+
+/// Line ending translation of command mode data from TCP clients to the UART
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TcpToUartEol {
+    /// Send data as received
+    None,
+    /// Turn CR (as sent by most terminals) into CRLF
+    CrToCrLf,
+    /// Turn a bare LF into CRLF
+    LfToCrLf,
+    /// Remove CR
+    StripCr,
+}
+
+impl TcpToUartEol {
+    /// Parse a mode name of AT+EOL, case-insensitively
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "NONE" => Some(Self::None),
+            "CRTOCRLF" => Some(Self::CrToCrLf),
+            "LFTOCRLF" => Some(Self::LfToCrLf),
+            "STRIPCR" => Some(Self::StripCr),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for TcpToUartEol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TcpToUartEol::None => write!(f, "None"),
+            TcpToUartEol::CrToCrLf => write!(f, "CrToCrLf"),
+            TcpToUartEol::LfToCrLf => write!(f, "LfToCrLf"),
+            TcpToUartEol::StripCr => write!(f, "StripCr"),
+        }
+    }
+}
+
+/// Line ending translation of UART data to command mode TCP clients
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartToTcpEol {
+    /// Send data as received
+    None,
+    /// Turn a bare LF into CRLF, for terminals like PuTTY
+    LfToCrLf,
+}
+
+impl UartToTcpEol {
+    /// Parse a mode name of AT+EOL, case-insensitively
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "NONE" => Some(Self::None),
+            "LFTOCRLF" => Some(Self::LfToCrLf),
+            _ => None,
+        }
+    }
+}
+
+impl std::fmt::Display for UartToTcpEol {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UartToTcpEol::None => write!(f, "None"),
+            UartToTcpEol::LfToCrLf => write!(f, "LfToCrLf"),
+        }
+    }
+}
+
+/// Flow control between the UART and the attached device
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlowControl {
+    /// No flow control, data the clients cannot take is dropped
+    #[default]
+    None,
+    /// RTS/CTS handshake lines on `UartConfig::rts_pin` and `UartConfig::cts_pin`
+    Hardware,
+    /// XON/XOFF characters sent by the driver, for devices without handshake lines
+    Software,
+}
+
+impl std::fmt::Display for FlowControl {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FlowControl::None => write!(f, "None"),
+            FlowControl::Hardware => write!(f, "RTS/CTS"),
+            FlowControl::Software => write!(f, "XON/XOFF"),
+        }
+    }
+}
+
+/// Packet framing of the data between the network and the UART
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PacketCodec {
+    /// Forward the byte stream as is
+    #[default]
+    None,
+    /// SLIP (RFC 1055): packets end with 0xC0, which is escaped inside them
+    Slip,
+    /// Consistent Overhead Byte Stuffing: packets end with 0x00, which never occurs inside them
+    Cobs,
+}
+
+impl std::fmt::Display for PacketCodec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PacketCodec::None => write!(f, "None"),
+            PacketCodec::Slip => write!(f, "SLIP"),
+            PacketCodec::Cobs => write!(f, "COBS"),
         }
     }
 }
@@ -54,41 +754,364 @@ impl Default for TcpServerConfig {
 /// UART configuration
 #[derive(Debug, Clone)]
 pub struct UartConfig {
+    /// UART peripheral number (0 or 1); must match the peripheral passed to `UartManager::new`
+    pub uart_num: u8,
+    /// GPIO number of the TX pin
+    pub tx_pin: i32,
+    /// GPIO number of the RX pin
+    pub rx_pin: i32,
+    /// GPIO driving the RS485 transceiver's DE/RE pins (None disables half-duplex mode)
+    pub rs485_de_pin: Option<u8>,
+    /// Extra delay in microseconds around each transmission for slow RS485 transceivers
+    pub rs485_turnaround_us: u32,
+    /// Flow control towards the attached device
+    ///
+    /// Besides protecting the receive buffer, it lets the UART stop being read while
+    /// the TCP clients fall behind (see `backpressure_high_watermark`), so the
+    /// device is paused instead of its data being dropped.
+    pub flow_control: FlowControl,
+    /// GPIO of the RTS output for hardware flow control (None leaves RTS unused)
+    ///
+    /// Cannot be combined with `rs485_de_pin`, which uses the RTS signal.
+    pub rts_pin: Option<u8>,
+    /// GPIO of the CTS input for hardware flow control (None leaves CTS unused)
+    pub cts_pin: Option<u8>,
     /// Baud rate for UART
     pub baudrate: u32,
-    /// Buffer size for UART operations
+    /// Only accept the standard rates in `uart::SUPPORTED_BAUDRATES`
+    ///
+    /// Otherwise any rate from `uart::MIN_BAUDRATE` to `uart::MAX_BAUDRATE` that the
+    /// clock divider can reach within 2% is accepted, e.g. 74880 or 250000.
+    pub strict_baudrates: bool,
+    /// Character format (data bits, parity, stop bits)
+    pub format: SerialFormat,
+    /// Bytes taken from the driver per read by the forwarding thread
     pub buffer_size: usize,
+    /// Size of the driver's receive ring buffer in bytes
+    ///
+    /// Holds the data received while the forwarding thread is busy, so it must cover
+    /// the longest burst at the highest baudrate used: 1.5 Mbaud fills 8 KB in about
+    /// 55 ms. Raised to 129 if smaller, as ESP-IDF needs more than the 128 byte FIFO.
+    pub driver_rx_buffer_size: usize,
+    /// Size of the driver's transmit ring buffer in bytes (0 lets writes wait for the FIFO)
+    pub driver_tx_buffer_size: usize,
     /// Sleep duration between UART polling in milliseconds
     pub poll_interval_ms: u64,
+    /// Maximum bytes queued from TCP while the UART is being reconfigured
+    pub reconfig_queue_size: usize,
+    /// Number of TCP data chunks the UART writer thread can have queued
+    pub tx_queue_capacity: usize,
+    /// Wait for UART driver receive events instead of polling every `poll_interval_ms`
+    pub event_driven_rx: bool,
+    /// Size of the UART driver event queue used by event-driven receive
+    pub event_queue_size: usize,
+    /// Milliseconds without received bytes that end a frame (0 forwards data as it is read)
+    ///
+    /// Collected bytes are broadcast as one frame, for protocols that expect a
+    /// whole frame per TCP segment. Adds up to this much latency.
+    pub frame_gap_ms: u64,
+    /// Largest frame in bytes; a frame is broadcast early when it reaches this size
+    pub frame_max_bytes: usize,
+    /// Byte sequence that ends a frame, e.g. b"\r\n" (None disables delimiter framing)
+    ///
+    /// The delimiter is sent as the end of its frame. A frame without delimiter is
+    /// broadcast after `frame_gap_ms`, or 100 ms if no gap is set.
+    pub frame_delimiter: Option<Vec<u8>>,
+    /// Largest batch in bytes when batching is enabled
+    pub batch_bytes: usize,
+    /// Milliseconds received bytes may be held back to batch them (0 forwards data as it is read)
+    ///
+    /// Coalesces small reads into fewer, larger broadcasts at high baudrates, which
+    /// eases the load on the WiFi stack. Ignored while gap or delimiter framing is on.
+    pub batch_max_latency_ms: u64,
+    /// Most recent UART bytes kept and replayed to each new data port client (0 disables)
+    ///
+    /// Lets a client that connects late see e.g. the boot banner of the attached
+    /// device. At most `uart::MAX_REPLAY_BYTES`, and at most half the client queue limit
+    /// is replayed so the replay cannot get a client dropped as too slow.
+    pub replay_bytes: usize,
+    /// Enclose the replay in "--- replay ---" and "--- end of replay ---" lines
+    pub replay_markers: bool,
+    /// Milliseconds AT+AUTOBAUD listens at each candidate baudrate
+    pub autobaud_window_ms: u64,
+    /// Line ending translation of command mode client data to the UART
+    ///
+    /// Like `uart_to_tcp_eol`, never applied to raw mode clients, and changed at
+    /// runtime with AT+EOL (not saved).
+    pub tcp_to_uart_eol: TcpToUartEol,
+    /// Line ending translation of UART data to command mode clients
+    pub uart_to_tcp_eol: UartToTcpEol,
+    /// Prefix that lets the attached device send AT commands (None disables them)
+    ///
+    /// With e.g. "+++", a UART line like "+++AT+BAUD=9600" runs AT+BAUD=9600 and the
+    /// reply is written back to the UART instead of reaching any client. Only lines
+    /// starting with the prefix and "AT" are taken; a command line longer than
+    /// `uart::MAX_UART_COMMAND_LEN` bytes or not ended within 500 ms is forwarded as data.
+    pub command_prefix: Option<&'static str>,
+    /// Text sent to the TCP clients when a break is detected on the UART (None sends nothing)
+    ///
+    /// E.g. "\r\n<BREAK>\r\n". Like the error counters, needs event-driven receive.
+    pub break_marker: Option<&'static str>,
+    /// Bytes queued for one TCP client above which the UART is no longer read, so
+    /// flow control holds the device back (only with `flow_control`)
+    ///
+    /// Should stay below the per-client queue limit, or a slow client is dropped
+    /// before the pause takes effect.
+    pub backpressure_high_watermark: usize,
+    /// Bytes queued for the client furthest behind below which reading the UART resumes
+    pub backpressure_low_watermark: usize,
+    /// Milliseconds reading may stay paused before the client with the most data
+    /// queued is evicted, so a stalled client cannot pause the bus forever
+    pub backpressure_stall_ms: u64,
+    /// Packet framing applied in both directions (see `packet_codec`)
+    ///
+    /// UART data is decoded and each complete packet is sent to the clients as one
+    /// write, and every read from a network client is encoded as one packet. Packets
+    /// longer than `frame_max_bytes` are dropped. Takes the place of gap and
+    /// delimiter framing, which are ignored while a codec is set.
+    pub packet_codec: PacketCodec,
 }
 
 impl Default for UartConfig {
     fn default() -> Self {
         Self {
+            uart_num: 1,                // UART0用于日志输出
+            tx_pin: 21,
+            rx_pin: 20,
+            rs485_de_pin: None,         // 默认全双工
+            rs485_turnaround_us: 0,
+            flow_control: FlowControl::None,
+            rts_pin: None,
+            cts_pin: None,
             baudrate: 115_200,          // 标准波特率
-            buffer_size: 1024,          // 更大的缓冲区以减少读取次数
+            strict_baudrates: false,
+            format: SerialFormat::default(), // 8N1
+            buffer_size: 2048,          // 每次读取尽量取空驱动缓冲区
+            driver_rx_buffer_size: 8192, // 高波特率突发数据时不溢出
+            driver_tx_buffer_size: 1024,
             poll_interval_ms: 1,        // 最小轮询间隔以降低延迟
+            reconfig_queue_size: 4096,  // 波特率切换期间最多排队4KB
+            tx_queue_capacity: 32,      // 每个TCP读取为一块，最多排队32块
+            event_driven_rx: true,      // 由UART中断事件唤醒，不再轮询
+            event_queue_size: 16,       // 驱动事件队列长度
+            frame_gap_ms: 0,            // 默认不分帧，收到即转发
+            frame_max_bytes: 1024,
+            frame_delimiter: None,      // 默认不按分隔符分帧
+            batch_bytes: 1460,          // 一个TCP报文段的大小
+            batch_max_latency_ms: 0,    // 默认不合并，保持低延迟
+            replay_bytes: 0,            // 默认不保留历史数据
+            replay_markers: true,
+            autobaud_window_ms: 300,    // 9个候选波特率共约3秒
+            tcp_to_uart_eol: TcpToUartEol::None,
+            uart_to_tcp_eol: UartToTcpEol::None,
+            command_prefix: None,       // 默认不接受来自串口的命令
+            break_marker: None,         // 默认只计数，不通知客户端
+            backpressure_high_watermark: 6144, // 低于单个客户端的8KB队列上限
+            backpressure_low_watermark: 2048,
+            backpressure_stall_ms: 5000,
+            packet_codec: PacketCodec::None, // 默认透明传输字节流
         }
     }
 }
 
+/// Factory reset button configuration
+#[derive(Debug, Clone)]
+pub struct ResetButtonConfig {
+    /// GPIO of the button, active low (None disables the button)
+    pub pin: Option<u8>,
+    /// How long the button must be held to erase all settings, in milliseconds
+    pub hold_ms: u64,
+    /// GPIO of a status LED blinked before the restart (None if there is no LED)
+    ///
+    /// Must not be the pin of an enabled `StatusLedConfig`, which owns that GPIO.
+    pub status_led_pin: Option<u8>,
+}
+
+impl Default for ResetButtonConfig {
+    fn default() -> Self {
+        Self {
+            pin: Some(9),               // ESP32-C3的BOOT按键
+            hold_ms: 5000,              // 按住5秒，避免误触
+            status_led_pin: None,
+        }
+    }
+}
+
+/// Kind of status LED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StatusLedKind {
+    /// Plain LED on a GPIO, active high
+    Gpio,
+    /// Addressable WS2812 LED driven by the RMT peripheral
+    Ws2812,
+}
+
+/// Status LED configuration
+#[derive(Debug, Clone)]
+pub struct StatusLedConfig {
+    /// Whether the status LED is driven at all
+    pub enabled: bool,
+    /// GPIO of the LED (or of the WS2812 data line)
+    pub pin: u8,
+    /// Kind of LED on the pin
+    pub kind: StatusLedKind,
+}
+
+impl Default for StatusLedConfig {
+    fn default() -> Self {
+        Self {
+            enabled: true,
+            pin: 8,                         // ESP32-C3-DevKitM-1板载RGB灯
+            kind: StatusLedKind::Ws2812,
+        }
+    }
+}
+
+/// Direction of a GPIO controlled with AT+GPIO
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioDirection {
+    /// Driven by the bridge, e.g. the target's RESET or BOOT line
+    Output,
+    /// Only read, e.g. a status line of the target
+    Input,
+}
+
+/// One named GPIO controlled with AT+GPIO
+#[derive(Debug, Clone)]
+pub struct GpioPinConfig {
+    /// Name used in AT+GPIO, e.g. "RESET"
+    pub name: &'static str,
+    /// GPIO number
+    pub pin: u8,
+    /// Whether the pin is driven or read
+    pub direction: GpioDirection,
+    /// The pin is asserted (AT+GPIO value 1) when low, as for most RESET and BOOT lines
+    pub active_low: bool,
+}
+
+/// esptool-style reset sequencing of an ESP8266/ESP32 target (AT+TARGETRESET)
+///
+/// Emulates the DTR/RTS auto-reset circuit of USB serial adapters with two output
+/// pins of `GpioConfig::pins`, which should be configured active low.
+#[derive(Debug, Clone)]
+pub struct TargetResetConfig {
+    /// Name of the pin wired to the target's EN (reset) pin
+    pub reset_pin: &'static str,
+    /// Name of the pin wired to the target's IO0 (boot mode) pin
+    pub boot_pin: &'static str,
+    /// How long EN is held asserted, in milliseconds (esptool: 100)
+    pub reset_ms: u64,
+    /// How long IO0 stays asserted after EN is released, in milliseconds (esptool: 50)
+    pub boot_hold_ms: u64,
+}
+
+impl Default for TargetResetConfig {
+    fn default() -> Self {
+        Self {
+            reset_pin: "RESET",
+            boot_pin: "BOOT",
+            reset_ms: 100,              // 与esptool的默认时序相同
+            boot_hold_ms: 50,
+        }
+    }
+}
+
+/// GPIO control configuration
+///
+/// For remote reflashing, wire the target's RESET and BOOT pins and list them, e.g.
+/// `GpioPinConfig { name: "RESET", pin: 4, direction: GpioDirection::Output, active_low: true }`.
+/// The pins must not be used by the UART, the status LED or the reset button.
+#[derive(Debug, Clone)]
+pub struct GpioConfig {
+    /// Controlled pins (empty disables AT+GPIO)
+    pub pins: Vec<GpioPinConfig>,
+    /// Length of AT+GPIO=<name>,PULSE without an explicit length, in milliseconds
+    pub pulse_ms: u64,
+    /// Reset sequencing with two of `pins` (None disables AT+TARGETRESET)
+    pub target_reset: Option<TargetResetConfig>,
+}
+
+impl Default for GpioConfig {
+    fn default() -> Self {
+        Self {
+            pins: Vec::new(),           // 默认没有连接目标板的控制线
+            pulse_ms: 100,              // 足以复位大多数MCU
+            target_reset: None,
+        }
+    }
+}
+
+/// An additional UART bridge with its own UART and data port
+///
+/// The ESP32-C3 has UART0 free once logging is moved to USB-Serial-JTAG, so a
+/// bridge named "telemetry" on port 8081 with `uart_num: 0` can run next to the one
+/// of `AppConfig::uart`, e.g. for a console and a telemetry link. Commands received on
+/// its port act on its UART. Its settings are not saved to flash, and the control,
+/// RFC 2217 and WebSocket ports, UDP, MQTT, connect-out and HTTP only serve the
+/// first bridge.
+#[derive(Debug, Clone)]
+pub struct BridgeConfig {
+    /// Name shown in AT+STATUS, AT+STATS and the log, e.g. "telemetry"
+    pub name: &'static str,
+    /// Data port of the bridge
+    pub port: u16,
+    /// UART of the bridge (its `uart_num` must not be used by another bridge)
+    pub uart: UartConfig,
+}
+
 /// Application configuration
 #[derive(Debug, Clone)]
 pub struct AppConfig {
+    /// Name of the device, also its mDNS host name (see `device_name`)
+    pub device_name: String<32>,
     /// WiFi configuration
     pub wifi: WiFiConfig,
     /// TCP server configuration
     pub tcp_server: TcpServerConfig,
     /// UART configuration
     pub uart: UartConfig,
+    /// Additional UART bridges (empty runs only the bridge of `uart` and `tcp_server`)
+    pub bridges: Vec<BridgeConfig>,
+    /// Connect-out TCP client configuration
+    pub tcp_client: TcpClientModeConfig,
+    /// UDP bridge configuration
+    pub udp: UdpBridgeConfig,
+    /// MQTT bridge configuration
+    pub mqtt: MqttConfig,
+    /// HTTP configuration page configuration
+    pub http: HttpServerConfig,
+    /// Factory reset button configuration
+    pub reset_button: ResetButtonConfig,
+    /// Status LED configuration
+    pub status_led: StatusLedConfig,
+    /// GPIO control configuration
+    pub gpio: GpioConfig,
+    /// Task watchdog configuration
+    pub watchdog: WatchdogConfig,
+    /// Heap and stack monitoring configuration
+    pub memory: MemoryConfig,
+    /// Seconds between traffic statistics summaries in the log (0 disables)
+    pub stats_log_interval_secs: u64,
 }
 
 impl Default for AppConfig {
     fn default() -> Self {
         Self {
+            device_name: device_name::default_name(),
             wifi: WiFiConfig::default(),
             tcp_server: TcpServerConfig::default(),
             uart: UartConfig::default(),
+            bridges: Vec::new(),        // 默认只有一个串口桥
+            tcp_client: TcpClientModeConfig::default(),
+            udp: UdpBridgeConfig::default(),
+            mqtt: MqttConfig::default(),
+            http: HttpServerConfig::default(),
+            reset_button: ResetButtonConfig::default(),
+            status_led: StatusLedConfig::default(),
+            gpio: GpioConfig::default(),
+            watchdog: WatchdogConfig::default(),
+            memory: MemoryConfig::default(),
+            stats_log_interval_secs: 60,
         }
     }
 }
@@ -97,3 +1120,57 @@ impl Default for AppConfig {
 pub fn create_config() -> AppConfig {
     AppConfig::default()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn compact_format_round_trips() {
+        for text in ["8N1", "7E1", "5O2", "8N1.5"] {
+            assert_eq!(SerialFormat::parse_compact(text).unwrap().to_string(), text);
+        }
+        assert_eq!(SerialFormat::default().to_string(), "8N1");
+    }
+
+    #[test]
+    fn format_fields_are_validated() {
+        assert_eq!(SerialFormat::parse_data_bits("4"), None);
+        assert_eq!(SerialFormat::parse_data_bits("9"), None);
+        assert_eq!(SerialFormat::parse_parity("even"), Some(Parity::Even));
+        assert_eq!(SerialFormat::parse_parity("M"), None);
+        assert_eq!(SerialFormat::parse_stop_bits("3"), None);
+        assert_eq!(SerialFormat::parse_compact("8X1"), None);
+        assert_eq!(SerialFormat::parse_compact("8N"), None);
+    }
+
+    #[test]
+    fn commands_get_their_own_port_by_default() {
+        let config = TcpServerConfig::default();
+        assert_eq!(config.control_port, Some(config.port + 1));
+    }
+
+    #[test]
+    fn dead_peers_are_found_within_two_minutes() {
+        let mut config = TcpServerConfig::default();
+        assert_eq!(config.keepalive_timeout_secs(), Some(90));
+
+        config.keepalive_idle_secs = 0;
+        assert_eq!(config.keepalive_timeout_secs(), None);
+    }
+
+    #[test]
+    fn uart_receive_is_event_driven_by_default() {
+        let config = UartConfig::default();
+        assert!(config.event_driven_rx);
+        assert!(config.event_queue_size > 0);
+    }
+
+    #[test]
+    fn wifi_modes_select_their_interfaces() {
+        assert_eq!(WiFiConfig::default().mode, WiFiMode::Mixed);
+        assert!(WiFiMode::Mixed.has_ap() && WiFiMode::Mixed.has_sta());
+        assert!(WiFiMode::ApOnly.has_ap() && !WiFiMode::ApOnly.has_sta());
+        assert!(!WiFiMode::StaOnly.has_ap() && WiFiMode::StaOnly.has_sta());
+    }
+}
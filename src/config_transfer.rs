@@ -0,0 +1,319 @@
+//! Configuration transfer module
+//!
+//! This module turns the persisted settings into a text document for AT+CFGEXPORT
+//! and validates such a document for AT+CFGIMPORT=, so a batch of units can be
+//! provisioned from one exported configuration.
+//!
+//! The document has one `key=value` line per setting, e.g. `baudrate=115200`.
+//! Backslash, CR and LF in values are written as `\\`, `\r` and `\n`. Lines starting
+//! with '#' and empty lines are ignored, and keys missing from an imported document
+//! keep their current value. Passwords are only written by a full export.
+
+use std::fmt;
+
+use crate::acl::AccessList;
+use crate::config::{ApBandwidth, AppConfig, SerialFormat, WiFiAuth};
+use crate::device_name;
+use crate::storage;
+use crate::uart::{self, UartManager};
+use crate::wifi;
+
+/// Longest document accepted by AT+CFGIMPORT=
+pub const MAX_DOCUMENT_LEN: usize = 2048;
+
+/// A setting of an imported document that was rejected
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FieldError {
+    /// Key of the rejected line, or the line number if it has no key
+    pub field: String,
+    /// Why the value was rejected
+    pub message: String,
+}
+
+impl fmt::Display for FieldError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.field, self.message)
+    }
+}
+
+/// Write the persisted settings of `config` as a document
+///
+/// The WiFi passwords are only included with `include_secrets`.
+pub fn export(config: &AppConfig, include_secrets: bool) -> String {
+    let mut lines = vec![
+        ("baudrate", config.uart.baudrate.to_string()),
+        ("format", config.uart.format.to_string()),
+        (
+            "delimiter",
+            match &config.uart.frame_delimiter {
+                Some(delimiter) => delimiter.iter().map(|b| format!("{:02X}", b)).collect(),
+                None => "OFF".to_string(),
+            },
+        ),
+        ("replay", config.uart.replay_bytes.to_string()),
+        ("tcp_port", config.tcp_server.port.to_string()),
+        (
+            "banner",
+            config.tcp_server.welcome_message.clone().unwrap_or_else(|| "OFF".to_string()),
+        ),
+        ("acl", config.tcp_server.access_list.to_string()),
+        ("sta_ssid", config.wifi.client_ssid.to_string()),
+        ("ap_ssid", config.wifi.ap_ssid.to_string()),
+        (
+            "ap_channel",
+            match config.wifi.ap_channel {
+                0 => "auto".to_string(),
+                channel => channel.to_string(),
+            },
+        ),
+        ("ap_hidden", u8::from(config.wifi.ap_hidden).to_string()),
+        ("ap_bandwidth", config.wifi.ap_bandwidth.to_string()),
+        ("country", config.wifi.country_code.to_string()),
+        ("tx_power", config.wifi.tx_power_dbm.to_string()),
+        ("ap_max_conn", config.wifi.ap_max_connections.to_string()),
+        ("ap_auth", config.wifi.ap_auth.to_string()),
+        ("sta_auth", config.wifi.sta_auth.to_string()),
+        ("name", config.device_name.to_string()),
+    ];
+    if include_secrets {
+        lines.push(("sta_password", config.wifi.client_password.to_string()));
+        lines.push(("ap_password", config.wifi.ap_password.to_string()));
+    }
+    lines
+        .iter()
+        .map(|(key, value)| format!("{}={}\r\n", key, escape(value)))
+        .collect()
+}
+
+/// Apply a document to a copy of `base`
+///
+/// Every line is checked; if any is rejected, all errors are returned and nothing
+/// is changed.
+pub fn import(base: &AppConfig, document: &str) -> std::result::Result<AppConfig, Vec<FieldError>> {
+    let mut config = base.clone();
+    let mut errors = Vec::new();
+    for (number, line) in document.lines().enumerate() {
+        // 值不去除空白，密码和欢迎信息可以以空格开头或结尾
+        if line.trim().is_empty() || line.trim_start().starts_with('#') {
+            continue;
+        }
+        let Some((key, value)) = line.split_once('=') else {
+            errors.push(FieldError {
+                field: format!("line {}", number + 1),
+                message: "Expected <key>=<value>".to_string(),
+            });
+            continue;
+        };
+        let key = key.trim();
+        let result = unescape(value).and_then(|value| apply_field(&mut config, key, &value));
+        if let Err(message) = result {
+            errors.push(FieldError {
+                field: key.to_string(),
+                message,
+            });
+        }
+    }
+    if errors.is_empty() {
+        Ok(config)
+    } else {
+        Err(errors)
+    }
+}
+
+/// Validate one setting and store it in `config`
+fn apply_field(config: &mut AppConfig, key: &str, value: &str) -> std::result::Result<(), String> {
+    match key {
+        "baudrate" => {
+            config.uart.baudrate = value
+                .parse()
+                .ok()
+                .filter(|&baudrate| UartManager::is_valid_baudrate(baudrate))
+                .ok_or_else(|| format!("Invalid baudrate: {}", value))?;
+        }
+        "format" => {
+            config.uart.format = SerialFormat::parse_compact(value)
+                .ok_or_else(|| format!("Invalid format: {} (use e.g. 8N1)", value))?;
+        }
+        "delimiter" => {
+            config.uart.frame_delimiter = match value {
+                "OFF" => None,
+                hex => Some(parse_delimiter(hex)?),
+            };
+        }
+        "replay" => {
+            config.uart.replay_bytes = value
+                .parse()
+                .ok()
+                .filter(|&bytes| bytes <= uart::MAX_REPLAY_BYTES)
+                .ok_or_else(|| format!("Invalid replay size: {} (use 0-{})", value, uart::MAX_REPLAY_BYTES))?;
+        }
+        "tcp_port" => {
+            config.tcp_server.port = value
+                .parse()
+                .ok()
+                .filter(|&port| port != 0)
+                .ok_or_else(|| format!("Invalid port: {} (use 1-65535)", value))?;
+        }
+        "banner" => {
+            config.tcp_server.welcome_message = match value {
+                "OFF" => None,
+                "" => return Err("Empty banner (use OFF to disable it)".to_string()),
+                banner if banner.len() > storage::MAX_BANNER_LEN => {
+                    return Err(format!("Banner is longer than {} bytes", storage::MAX_BANNER_LEN));
+                }
+                banner => Some(banner.to_string()),
+            };
+        }
+        "acl" => {
+            config.tcp_server.access_list = AccessList::parse(value)?;
+        }
+        "sta_ssid" => {
+            config.wifi.client_ssid = heapless::String::try_from(value)
+                .ok()
+                .filter(|ssid| !ssid.is_empty())
+                .ok_or_else(|| "Invalid SSID (must be 1-32 bytes)".to_string())?;
+        }
+        "sta_password" => {
+            config.wifi.client_password = heapless::String::try_from(value)
+                .map_err(|_| "Invalid password (must be at most 64 bytes)".to_string())?;
+        }
+        "ap_ssid" => {
+            config.wifi.ap_ssid = heapless::String::try_from(value)
+                .ok()
+                .filter(|ssid| !ssid.is_empty())
+                .ok_or_else(|| "Invalid SSID (must be 1-32 bytes)".to_string())?;
+        }
+        "ap_password" => {
+            // 开放网络使用空密码，其他模式要求8到63个字符
+            if !value.is_empty() && !(8..=63).contains(&value.len()) {
+                return Err("Invalid password (must be empty or 8-63 bytes)".to_string());
+            }
+            config.wifi.ap_password = heapless::String::try_from(value)
+                .map_err(|_| "Invalid password (must be empty or 8-63 bytes)".to_string())?;
+        }
+        "ap_auth" => {
+            config.wifi.ap_auth = WiFiAuth::parse(value)
+                .ok_or_else(|| format!("Invalid mode: {} (use OPEN, WPA2, WPA2WPA3 or WPA3)", value))?;
+        }
+        "sta_auth" => {
+            config.wifi.sta_auth = WiFiAuth::parse(value)
+                .ok_or_else(|| format!("Invalid mode: {} (use OPEN, WPA2, WPA2WPA3 or WPA3)", value))?;
+        }
+        "ap_channel" => {
+            // 0表示开机时扫描选择信道
+            config.wifi.ap_channel = if value.eq_ignore_ascii_case("auto") {
+                0
+            } else {
+                value
+                    .parse()
+                    .ok()
+                    .filter(|channel| (1..=13).contains(channel))
+                    .ok_or_else(|| format!("Invalid channel: {} (use 1-13 or auto)", value))?
+            };
+        }
+        "ap_hidden" => {
+            config.wifi.ap_hidden = match value {
+                "1" => true,
+                "0" => false,
+                other => return Err(format!("Invalid value: {} (use 1 or 0)", other)),
+            };
+        }
+        "ap_bandwidth" => {
+            config.wifi.ap_bandwidth = ApBandwidth::parse(value)
+                .ok_or_else(|| format!("Invalid bandwidth: {} (use HT20 or HT40)", value))?;
+        }
+        "country" => {
+            config.wifi.country_code = heapless::String::try_from(value)
+                .ok()
+                .filter(|code| wifi::is_valid_country_code(code))
+                .ok_or_else(|| format!("Invalid country code: {} (use two letters like DE, or 01)", value))?;
+        }
+        "tx_power" => {
+            config.wifi.tx_power_dbm = value
+                .parse()
+                .ok()
+                .filter(|dbm| (wifi::MIN_TX_POWER_DBM..=wifi::MAX_TX_POWER_DBM).contains(dbm))
+                .ok_or_else(|| {
+                    format!(
+                        "Invalid TX power: {} (use {}-{} dBm)",
+                        value,
+                        wifi::MIN_TX_POWER_DBM,
+                        wifi::MAX_TX_POWER_DBM
+                    )
+                })?;
+        }
+        "ap_max_conn" => {
+            config.wifi.ap_max_connections = value
+                .parse()
+                .ok()
+                .filter(|count| (1..=10).contains(count))
+                .ok_or_else(|| format!("Invalid connection limit: {} (use 1-10)", value))?;
+        }
+        "name" => {
+            config.device_name = heapless::String::try_from(value)
+                .ok()
+                .filter(|name| device_name::is_valid(name))
+                .ok_or_else(|| format!("Invalid device name: {} (use 1-32 letters, digits or '-')", value))?;
+        }
+        _ => return Err("Unknown setting".to_string()),
+    }
+    Ok(())
+}
+
+/// Parse a delimiter written as hex bytes, e.g. "0D0A"
+fn parse_delimiter(hex: &str) -> std::result::Result<Vec<u8>, String> {
+    let invalid = || format!("Invalid delimiter: {} (use hex bytes, e.g. 0D0A)", hex);
+    let delimiter: Option<Vec<u8>> = hex
+        .as_bytes()
+        .chunks(2)
+        .map(|pair| match *pair {
+            [high, low] => {
+                let digit = |b: u8| char::from(b).to_digit(16);
+                Some((digit(high)? * 16 + digit(low)?) as u8)
+            }
+            _ => None,
+        })
+        .collect();
+    let delimiter = delimiter.filter(|delimiter| !delimiter.is_empty()).ok_or_else(invalid)?;
+    if delimiter.len() > uart::MAX_DELIMITER_LEN {
+        return Err(format!(
+            "Delimiter too long: {} bytes (max {})",
+            delimiter.len(),
+            uart::MAX_DELIMITER_LEN
+        ));
+    }
+    Ok(delimiter)
+}
+
+/// Escape a value so it fits on one line
+fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '\r' => escaped.push_str("\\r"),
+            '\n' => escaped.push_str("\\n"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Undo `escape`
+fn unescape(value: &str) -> std::result::Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut chars = value.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => unescaped.push('\\'),
+            Some('r') => unescaped.push('\r'),
+            Some('n') => unescaped.push('\n'),
+            _ => return Err(format!("Invalid escape sequence in {}", value)),
+        }
+    }
+    Ok(unescaped)
+}
@@ -0,0 +1,303 @@
+//! Binary control protocol module
+//!
+//! This module defines the framed protocol spoken on the control port when
+//! `TcpServerConfig::control_protocol` is `ControlProtocol::Binary`, for
+//! machine-to-machine control where text AT commands mixed with binary data would
+//! be ambiguous. The text AT interface stays the default for humans.
+//!
+//! Every request and response is one frame:
+//!
+//! | Bytes | Content                                                       |
+//! |-------|---------------------------------------------------------------|
+//! | 2     | Magic 0xA5 0x5A                                               |
+//! | 1     | Opcode                                                        |
+//! | 2     | Payload length, big endian                                    |
+//! | n     | Payload                                                       |
+//! | 2     | CRC-16/CCITT-FALSE of opcode, length and payload, big endian  |
+//!
+//! A response carries the opcode of its request with `RESPONSE_FLAG` set. Its
+//! payload is a status byte, `STATUS_OK` or the code of the "ERROR <code>" reply
+//! the text interface would send, followed by the reply text as AT+VERBOSE=1
+//! clients get it. Requests are turned into AT command lines by `command_line`, so
+//! they run through the same command registry as text commands. Commands that
+//! read an upload after their line, AT+OTA= and AT+CFGIMPORT=, need the text
+//! interface and are answered with status 2 (invalid argument).
+//!
+//! Bytes that do not start a valid frame are skipped up to the next magic, so a
+//! corrupted frame costs only that frame.
+
+use std::fmt;
+
+/// Bytes starting every frame
+pub const MAGIC: [u8; 2] = [0xA5, 0x5A];
+
+/// Largest request payload accepted; responses may be up to 65535 bytes
+pub const MAX_REQUEST_PAYLOAD: usize = 1024;
+
+/// Run the AT command line in the payload, e.g. "AT+BAUD?"
+pub const OPCODE_COMMAND: u8 = 0x01;
+/// Change the UART baudrate to the big endian u32 in the payload
+pub const OPCODE_SET_BAUD: u8 = 0x02;
+/// Get the AT+STATUS report
+pub const OPCODE_GET_STATUS: u8 = 0x03;
+/// Disconnect the data port client whose "ip:port" is the payload
+pub const OPCODE_KICK_CLIENT: u8 = 0x04;
+/// Restart the device once the response is sent
+pub const OPCODE_RESET: u8 = 0x05;
+/// Response to bytes that were not a valid frame
+pub const OPCODE_FRAME_ERROR: u8 = 0x7F;
+
+/// Set in the opcode of every response
+pub const RESPONSE_FLAG: u8 = 0x80;
+
+/// Status byte of a successful response
+pub const STATUS_OK: u8 = 0;
+
+/// Bytes before the payload: magic, opcode and length
+const HEADER_LEN: usize = 5;
+
+/// Bytes of the CRC after the payload
+const CRC_LEN: usize = 2;
+
+/// A request or response
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Frame {
+    pub opcode: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Why received bytes were not accepted as a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameError {
+    /// The CRC did not match the frame
+    Checksum,
+    /// The length exceeded `MAX_REQUEST_PAYLOAD`
+    TooLarge(usize),
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::Checksum => write!(f, "CRC mismatch"),
+            FrameError::TooLarge(len) => {
+                write!(f, "Payload of {} bytes exceeds {} bytes", len, MAX_REQUEST_PAYLOAD)
+            }
+        }
+    }
+}
+
+/// CRC-16/CCITT-FALSE (polynomial 0x1021, initial value 0xFFFF)
+pub fn crc16(data: &[u8]) -> u16 {
+    let mut crc = 0xFFFFu16;
+    for &byte in data {
+        crc ^= u16::from(byte) << 8;
+        for _ in 0..8 {
+            crc = if crc & 0x8000 != 0 { (crc << 1) ^ 0x1021 } else { crc << 1 };
+        }
+    }
+    crc
+}
+
+/// Encode a frame; payloads longer than 65535 bytes are cut off
+pub fn encode(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let payload = &payload[..payload.len().min(u16::MAX as usize)];
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len() + CRC_LEN);
+    frame.extend_from_slice(&MAGIC);
+    frame.push(opcode);
+    frame.extend_from_slice(&(payload.len() as u16).to_be_bytes());
+    frame.extend_from_slice(payload);
+    let crc = crc16(&frame[MAGIC.len()..]);
+    frame.extend_from_slice(&crc.to_be_bytes());
+    frame
+}
+
+/// Encode the response to a request with `opcode`
+pub fn response(opcode: u8, status: u8, text: &[u8]) -> Vec<u8> {
+    let mut payload = Vec::with_capacity(1 + text.len());
+    payload.push(status);
+    payload.extend_from_slice(text);
+    encode(opcode | RESPONSE_FLAG, &payload)
+}
+
+/// Translate a request into the AT command line it stands for
+///
+/// Returns None for an unknown opcode, and an error if the payload does not fit
+/// the opcode.
+pub fn command_line(frame: &Frame) -> Option<std::result::Result<String, String>> {
+    let text = || {
+        std::str::from_utf8(&frame.payload)
+            .map(str::to_string)
+            .map_err(|_| "Payload is not valid UTF-8".to_string())
+    };
+    Some(match frame.opcode {
+        OPCODE_COMMAND => text(),
+        OPCODE_SET_BAUD => <[u8; 4]>::try_from(frame.payload.as_slice())
+            .map(|bytes| format!("AT+BAUD={}", u32::from_be_bytes(bytes)))
+            .map_err(|_| format!("Expected a 4 byte baudrate, got {} bytes", frame.payload.len())),
+        OPCODE_GET_STATUS => Ok("AT+STATUS".to_string()),
+        OPCODE_KICK_CLIENT => text().map(|addr| format!("AT+KICK={}", addr)),
+        OPCODE_RESET => Ok("AT+RESET=YES".to_string()),
+        _ => return None,
+    })
+}
+
+/// Decoder for the frames of a byte stream
+#[derive(Debug, Default)]
+pub struct FrameDecoder {
+    /// Received bytes not decoded yet, starting at a magic once one was seen
+    buffer: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Create a decoder with nothing received yet
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add received bytes, appending every complete frame or rejected frame to `decoded`
+    pub fn decode(&mut self, input: &[u8], decoded: &mut Vec<std::result::Result<Frame, FrameError>>) {
+        self.buffer.extend_from_slice(input);
+        loop {
+            // 丢弃魔数之前的字节，保留可能是魔数开头的最后一个字节
+            match self.buffer.windows(MAGIC.len()).position(|window| window == MAGIC) {
+                Some(start) => {
+                    self.buffer.drain(..start);
+                }
+                None => {
+                    let keep = usize::from(self.buffer.last() == Some(&MAGIC[0]));
+                    let skip = self.buffer.len() - keep;
+                    self.buffer.drain(..skip);
+                    return;
+                }
+            }
+            if self.buffer.len() < HEADER_LEN {
+                return;
+            }
+
+            let len = u16::from_be_bytes([self.buffer[3], self.buffer[4]]) as usize;
+            if len > MAX_REQUEST_PAYLOAD {
+                decoded.push(Err(FrameError::TooLarge(len)));
+                // 跳过这个魔数，从下一个魔数重新同步
+                self.buffer.drain(..MAGIC.len());
+                continue;
+            }
+            let total = HEADER_LEN + len + CRC_LEN;
+            if self.buffer.len() < total {
+                return;
+            }
+            let crc = u16::from_be_bytes([self.buffer[total - 2], self.buffer[total - 1]]);
+            if crc16(&self.buffer[MAGIC.len()..total - CRC_LEN]) != crc {
+                decoded.push(Err(FrameError::Checksum));
+                self.buffer.drain(..MAGIC.len());
+                continue;
+            }
+            decoded.push(Ok(Frame {
+                opcode: self.buffer[2],
+                payload: self.buffer[HEADER_LEN..total - CRC_LEN].to_vec(),
+            }));
+            self.buffer.drain(..total);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `chunks` one after the other with one decoder
+    fn decode(chunks: &[&[u8]]) -> Vec<std::result::Result<Frame, FrameError>> {
+        let mut decoder = FrameDecoder::new();
+        let mut decoded = Vec::new();
+        for chunk in chunks {
+            decoder.decode(chunk, &mut decoded);
+        }
+        decoded
+    }
+
+    fn frame(opcode: u8, payload: &[u8]) -> std::result::Result<Frame, FrameError> {
+        Ok(Frame { opcode, payload: payload.to_vec() })
+    }
+
+    #[test]
+    fn crc16_check_value() {
+        // CRC-16/CCITT-FALSE的标准校验值
+        assert_eq!(crc16(b"123456789"), 0x29B1);
+        assert_eq!(crc16(b""), 0xFFFF);
+    }
+
+    #[test]
+    fn encoded_layout() {
+        let encoded = encode(OPCODE_GET_STATUS, b"");
+        assert_eq!(&encoded[..5], &[0xA5, 0x5A, OPCODE_GET_STATUS, 0, 0]);
+        assert_eq!(encoded[5..], crc16(&[OPCODE_GET_STATUS, 0, 0]).to_be_bytes());
+
+        let reply = response(OPCODE_SET_BAUD, STATUS_OK, b"done");
+        assert_eq!(decode(&[&reply]), [frame(OPCODE_SET_BAUD | RESPONSE_FLAG, b"\0done")]);
+    }
+
+    #[test]
+    fn frames_round_trip_with_noise_around_them() {
+        let stream = [
+            b"noise\xA5".to_vec(),
+            encode(OPCODE_COMMAND, b"AT+BAUD?"),
+            vec![0x5A, 0xA5],
+            encode(OPCODE_GET_STATUS, b""),
+        ]
+        .concat();
+        assert_eq!(decode(&[&stream]), [frame(OPCODE_COMMAND, b"AT+BAUD?"), frame(OPCODE_GET_STATUS, b"")]);
+    }
+
+    #[test]
+    fn frame_split_across_reads() {
+        let encoded = encode(OPCODE_KICK_CLIENT, b"192.168.4.2:50001");
+        for split in 1..encoded.len() {
+            let (first, second) = encoded.split_at(split);
+            assert!(decode(&[first]).is_empty(), "split at {}", split);
+            assert_eq!(decode(&[first, second]), [frame(OPCODE_KICK_CLIENT, b"192.168.4.2:50001")], "split at {}", split);
+        }
+        // 逐字节到达
+        let bytes: Vec<&[u8]> = encoded.chunks(1).collect();
+        assert_eq!(decode(&bytes), [frame(OPCODE_KICK_CLIENT, b"192.168.4.2:50001")]);
+    }
+
+    #[test]
+    fn payload_length_limit() {
+        let largest = vec![b'x'; MAX_REQUEST_PAYLOAD];
+        assert_eq!(decode(&[&encode(OPCODE_COMMAND, &largest)]), [frame(OPCODE_COMMAND, &largest)]);
+
+        // 超长的帧被拒绝，之后的帧仍然被解码
+        let stream = [encode(OPCODE_COMMAND, &[b'x'; MAX_REQUEST_PAYLOAD + 1]), encode(OPCODE_RESET, b"")].concat();
+        assert_eq!(
+            decode(&[&stream]),
+            [Err(FrameError::TooLarge(MAX_REQUEST_PAYLOAD + 1)), frame(OPCODE_RESET, b"")]
+        );
+    }
+
+    #[test]
+    fn responses_are_cut_at_the_length_field() {
+        let encoded = encode(OPCODE_COMMAND, &vec![b'x'; 70_000]);
+        assert_eq!(&encoded[3..5], &[0xFF, 0xFF]);
+        assert_eq!(encoded.len(), 5 + 65535 + 2);
+    }
+
+    #[test]
+    fn crc_mismatch_is_reported() {
+        let mut corrupted = encode(OPCODE_COMMAND, b"AT+BAUD?");
+        corrupted[6] ^= 0x01;
+        let stream = [corrupted, encode(OPCODE_GET_STATUS, b"")].concat();
+        assert_eq!(decode(&[&stream]), [Err(FrameError::Checksum), frame(OPCODE_GET_STATUS, b"")]);
+    }
+
+    #[test]
+    fn requests_become_command_lines() {
+        let line = |opcode, payload: &[u8]| command_line(&Frame { opcode, payload: payload.to_vec() });
+        assert_eq!(line(OPCODE_COMMAND, b"AT+UART?"), Some(Ok("AT+UART?".to_string())));
+        assert_eq!(line(OPCODE_SET_BAUD, &9600u32.to_be_bytes()), Some(Ok("AT+BAUD=9600".to_string())));
+        assert!(matches!(line(OPCODE_SET_BAUD, &[0, 0, 0x25]), Some(Err(_))));
+        assert_eq!(line(OPCODE_GET_STATUS, b""), Some(Ok("AT+STATUS".to_string())));
+        assert_eq!(line(OPCODE_KICK_CLIENT, b"10.0.0.2:4000"), Some(Ok("AT+KICK=10.0.0.2:4000".to_string())));
+        assert!(matches!(line(OPCODE_KICK_CLIENT, &[0xFF]), Some(Err(_))));
+        assert_eq!(line(OPCODE_RESET, b""), Some(Ok("AT+RESET=YES".to_string())));
+        assert_eq!(line(0x42, b""), None);
+    }
+}
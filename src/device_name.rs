@@ -0,0 +1,88 @@
+//! Device name module
+//!
+//! This module holds the name the device is known by. It is advertised over mDNS as
+//! "<name>.local", fills the "{name}" placeholder of the welcome banner and shows
+//! up in AT+STATUS and the startup log.
+//!
+//! The name starts as the one saved with AT+NAME or `AppConfig::device_name`, and a
+//! rename takes effect for every reader at once.
+
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+use crate::mdns;
+
+/// Longest device name in bytes
+pub const MAX_LEN: usize = 32;
+
+/// Prefix of the default device name
+const DEFAULT_PREFIX: &str = "esp32-uart-bridge";
+
+/// Current device name, empty until `set` is called
+static NAME: Mutex<heapless::String<MAX_LEN>> = Mutex::new(heapless::String::new());
+
+/// Last two bytes of the factory MAC address as four hex digits, e.g. "A1B2"
+///
+/// Tells apart units that share a default name or SSID.
+pub fn mac_suffix() -> String {
+    let mac = factory_mac();
+    format!("{:02X}{:02X}", mac[4], mac[5])
+}
+
+/// Factory MAC address from efuse
+#[cfg(target_os = "espidf")]
+fn factory_mac() -> [u8; 6] {
+    let mut mac = [0u8; 6];
+    unsafe {
+        esp_idf_sys::esp_efuse_mac_get_default(mac.as_mut_ptr());
+    }
+    mac
+}
+
+/// Factory MAC address; all zeros in the host build
+#[cfg(not(target_os = "espidf"))]
+fn factory_mac() -> [u8; 6] {
+    [0; 6]
+}
+
+/// Default device name, e.g. "esp32-uart-bridge-A1B2"
+pub fn default_name() -> heapless::String<MAX_LEN> {
+    let mut name = heapless::String::new();
+    // 前缀加后缀共22字节，不会超出长度
+    let _ = name.push_str(DEFAULT_PREFIX);
+    let _ = name.push('-');
+    let _ = name.push_str(&mac_suffix());
+    name
+}
+
+/// Whether `name` can be used as device name, i.e. as mDNS host name
+pub fn is_valid(name: &str) -> bool {
+    name.len() <= MAX_LEN && mdns::is_valid_hostname(name)
+}
+
+/// Get the current device name
+pub fn get() -> String {
+    match NAME.lock() {
+        Ok(name) if !name.is_empty() => name.to_string(),
+        _ => default_name().to_string(),
+    }
+}
+
+/// Change the device name
+///
+/// Only the running name changes; saving it and updating the mDNS advertisement
+/// is up to the caller.
+pub fn set(name: &str) -> Result<()> {
+    if !is_valid(name) {
+        return Err(Error::General(format!(
+            "Invalid device name: {} (use 1-{} letters, digits or '-')",
+            name, MAX_LEN
+        )));
+    }
+    let mut current = NAME
+        .lock()
+        .map_err(|_| Error::General("Failed to lock device name".to_string()))?;
+    current.clear();
+    let _ = current.push_str(name);
+    Ok(())
+}
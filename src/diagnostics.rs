@@ -0,0 +1,257 @@
+//! Diagnostics module
+//!
+//! This module samples the free heap and the stack headroom of the long-lived
+//! threads, for the periodic log summary and AT+MEM, and implements the low-memory
+//! protection: while the free heap is below `MemoryConfig::low_heap_bytes`, new data
+//! connections are refused and the client with the largest backlog is dropped.
+//!
+//! Threads are listed once they call `track_thread`; the returned guard removes
+//! them again before they exit, so a task handle is never used after its task ended.
+//!
+//! The host build has no FreeRTOS heap or task stacks: it reports an unlimited
+//! heap, so the low-memory protection never acts, and no stack headroom.
+
+use log::{info, warn};
+use std::fmt;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::Mutex;
+
+use crate::config::MemoryConfig;
+use crate::tcp_client_manager::TcpClientManager;
+
+/// Free heap threshold in bytes set by `init` (0 disables the protection)
+static LOW_HEAP_BYTES: AtomicU32 = AtomicU32::new(0);
+
+/// Tracked threads: FreeRTOS task handle (as an address) and name
+static THREADS: Mutex<Vec<(usize, &'static str)>> = Mutex::new(Vec::new());
+
+/// Stack headroom of one tracked thread
+#[derive(Debug, Clone)]
+pub struct StackUsage {
+    /// Thread name
+    pub name: &'static str,
+    /// Least free stack in bytes since the thread started
+    pub min_free: u32,
+}
+
+/// Heap and stack state at one point in time
+#[derive(Debug, Clone)]
+pub struct MemorySnapshot {
+    /// Free heap in bytes
+    pub free_heap: u32,
+    /// Lowest free heap in bytes since boot
+    pub min_free_heap: u32,
+    /// Largest block that can be allocated at once
+    pub largest_block: usize,
+    /// Threshold below which the low-memory protection acts (0 if disabled)
+    pub low_heap_bytes: u32,
+    /// Stack headroom of the tracked threads
+    pub stacks: Vec<StackUsage>,
+}
+
+impl MemorySnapshot {
+    /// Sample the heap and the tracked threads now
+    pub fn take() -> Self {
+        let stacks = match THREADS.lock() {
+            Ok(threads) => threads
+                .iter()
+                .map(|&(handle, name)| StackUsage {
+                    name,
+                    min_free: stack_headroom(handle),
+                })
+                .collect(),
+            Err(_) => Vec::new(),
+        };
+        Self {
+            free_heap: free_heap(),
+            min_free_heap: min_free_heap(),
+            largest_block: largest_free_block(),
+            low_heap_bytes: LOW_HEAP_BYTES.load(Ordering::Relaxed),
+            stacks,
+        }
+    }
+}
+
+impl fmt::Display for MemorySnapshot {
+    /// One "Key: value" line each, as in the AT+STATUS report
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Free heap: {} bytes (minimum {}, largest block {})\r\n",
+            self.free_heap, self.min_free_heap, self.largest_block
+        )?;
+        if self.low_heap_bytes > 0 {
+            write!(f, "Low memory below: {} bytes\r\n", self.low_heap_bytes)?;
+        }
+        for stack in &self.stacks {
+            write!(f, "Stack {}: {} bytes free\r\n", stack.name, stack.min_free)?;
+        }
+        Ok(())
+    }
+}
+
+/// Removes a thread from the stack report when dropped
+pub struct ThreadTracker {
+    handle: usize,
+}
+
+impl Drop for ThreadTracker {
+    fn drop(&mut self) {
+        if let Ok(mut threads) = THREADS.lock() {
+            if let Some(pos) = threads.iter().position(|&(handle, _)| handle == self.handle) {
+                threads.swap_remove(pos);
+            }
+        }
+    }
+}
+
+/// Set the low-memory threshold
+pub fn init(config: &MemoryConfig) {
+    LOW_HEAP_BYTES.store(config.low_heap_bytes, Ordering::Relaxed);
+}
+
+/// List the current thread in the stack report until the guard is dropped
+pub fn track_thread(name: &'static str) -> ThreadTracker {
+    let handle = current_task();
+    if let Ok(mut threads) = THREADS.lock() {
+        threads.push((handle, name));
+    }
+    ThreadTracker { handle }
+}
+
+/// Check whether the free heap is below the low-memory threshold
+pub fn heap_is_low() -> bool {
+    let threshold = LOW_HEAP_BYTES.load(Ordering::Relaxed);
+    threshold > 0 && free_heap() < threshold
+}
+
+/// Log a heap and stack summary
+pub fn log_summary() {
+    let snapshot = MemorySnapshot::take();
+    info!(
+        "Memory: {} bytes free heap (minimum {}, largest block {})",
+        snapshot.free_heap, snapshot.min_free_heap, snapshot.largest_block
+    );
+    for stack in &snapshot.stacks {
+        info!("  Stack {}: {} bytes free", stack.name, stack.min_free);
+    }
+}
+
+/// Drop the data client with the largest backlog while the heap is low
+///
+/// Called periodically from the main loop. Each call frees at most one client, so
+/// the queues of the others can drain before the next check.
+pub fn protect(client_manager: &TcpClientManager) {
+    if !heap_is_low() {
+        return;
+    }
+    let free_heap = free_heap();
+    match client_manager.evict_most_backlogged() {
+        Ok(Some((addr, queued))) => warn!(
+            "Low memory ({} bytes free): dropped client {} with {} bytes queued",
+            free_heap, addr, queued
+        ),
+        Ok(None) => warn!("Low memory ({} bytes free), no client backlog to drop", free_heap),
+        Err(e) => warn!("Low memory ({} bytes free), failed to drop a client: {}", free_heap, e),
+    }
+}
+
+/// Free heap in bytes
+#[cfg(target_os = "espidf")]
+pub fn free_heap() -> u32 {
+    unsafe { esp_idf_sys::esp_get_free_heap_size() }
+}
+
+/// Free heap in bytes; unlimited in the host build
+#[cfg(not(target_os = "espidf"))]
+pub fn free_heap() -> u32 {
+    u32::MAX
+}
+
+/// Lowest free heap in bytes since boot
+#[cfg(target_os = "espidf")]
+pub fn min_free_heap() -> u32 {
+    unsafe { esp_idf_sys::esp_get_minimum_free_heap_size() }
+}
+
+#[cfg(not(target_os = "espidf"))]
+pub fn min_free_heap() -> u32 {
+    u32::MAX
+}
+
+/// Largest block that can be allocated at once
+#[cfg(target_os = "espidf")]
+fn largest_free_block() -> usize {
+    unsafe { esp_idf_sys::heap_caps_get_largest_free_block(esp_idf_sys::MALLOC_CAP_8BIT) }
+}
+
+#[cfg(not(target_os = "espidf"))]
+fn largest_free_block() -> usize {
+    usize::MAX
+}
+
+/// FreeRTOS task handle of the calling thread, as an address
+#[cfg(target_os = "espidf")]
+fn current_task() -> usize {
+    unsafe { esp_idf_sys::xTaskGetCurrentTaskHandle() as usize }
+}
+
+/// Address that tells the calling thread apart from the others in the host build
+#[cfg(not(target_os = "espidf"))]
+fn current_task() -> usize {
+    thread_local!(static TASK: u8 = const { 0 });
+    TASK.with(|task| task as *const u8 as usize)
+}
+
+/// Set the FreeRTOS priority (0-24, higher runs first) of the calling thread
+#[cfg(target_os = "espidf")]
+pub fn set_task_priority(priority: u32) {
+    unsafe { esp_idf_sys::vTaskPrioritySet(esp_idf_sys::xTaskGetCurrentTaskHandle(), priority) }
+}
+
+/// Set the FreeRTOS priority of the calling thread; threads keep theirs in the host build
+#[cfg(not(target_os = "espidf"))]
+pub fn set_task_priority(_priority: u32) {}
+
+/// Least free stack in bytes since the task `handle` started
+#[cfg(target_os = "espidf")]
+fn stack_headroom(handle: usize) -> u32 {
+    // 线程退出前会先从列表中移除，句柄在锁内总是有效的
+    unsafe { esp_idf_sys::uxTaskGetStackHighWaterMark(handle as _) as u32 }
+}
+
+#[cfg(not(target_os = "espidf"))]
+fn stack_headroom(_handle: usize) -> u32 {
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snapshot_is_one_line_per_value() {
+        let mut snapshot = MemorySnapshot {
+            free_heap: 81920,
+            min_free_heap: 40960,
+            largest_block: 32768,
+            low_heap_bytes: 0,
+            stacks: vec![StackUsage { name: "uart_rx", min_free: 1200 }, StackUsage { name: "tcp_tx", min_free: 900 }],
+        };
+        assert_eq!(
+            snapshot.to_string(),
+            "Free heap: 81920 bytes (minimum 40960, largest block 32768)\r\n\
+             Stack uart_rx: 1200 bytes free\r\n\
+             Stack tcp_tx: 900 bytes free\r\n"
+        );
+
+        // 只有启用保护时才显示阈值
+        snapshot.low_heap_bytes = 24576;
+        snapshot.stacks.clear();
+        assert_eq!(
+            snapshot.to_string(),
+            "Free heap: 81920 bytes (minimum 40960, largest block 32768)\r\n\
+             Low memory below: 24576 bytes\r\n"
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Line ending translation module
+//!
+//! This module translates line endings between command mode TCP clients and the
+//! UART, for terminals and devices that disagree on CR, LF and CRLF. Data of raw
+//! mode clients is never translated, so transparent deployments are unaffected.
+//!
+//! Whether the last byte of a chunk was a CR is remembered, so a CRLF split across
+//! two reads is recognised and not translated twice.
+
+use std::borrow::Cow;
+
+use crate::config::{TcpToUartEol, UartToTcpEol};
+
+const CR: u8 = b'\r';
+const LF: u8 = b'\n';
+
+/// Translation state of one direction of one stream
+#[derive(Debug, Clone, Copy, Default)]
+pub struct EolState {
+    /// Whether the previous chunk ended with a CR
+    after_cr: bool,
+}
+
+impl EolState {
+    /// Translate data from a TCP client on its way to the UART
+    pub fn tcp_to_uart<'a>(&mut self, mode: TcpToUartEol, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match mode {
+            TcpToUartEol::None => self.pass(data),
+            TcpToUartEol::CrToCrLf => self.cr_to_crlf(data),
+            TcpToUartEol::LfToCrLf => self.lf_to_crlf(data),
+            TcpToUartEol::StripCr => {
+                self.after_cr = data.last() == Some(&CR);
+                if data.contains(&CR) {
+                    Cow::Owned(data.iter().copied().filter(|&b| b != CR).collect())
+                } else {
+                    Cow::Borrowed(data)
+                }
+            }
+        }
+    }
+
+    /// Translate UART data on its way to command mode TCP clients
+    pub fn uart_to_tcp<'a>(&mut self, mode: UartToTcpEol, data: &'a [u8]) -> Cow<'a, [u8]> {
+        match mode {
+            UartToTcpEol::None => self.pass(data),
+            UartToTcpEol::LfToCrLf => self.lf_to_crlf(data),
+        }
+    }
+
+    fn pass<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        self.after_cr = data.last() == Some(&CR);
+        Cow::Borrowed(data)
+    }
+
+    /// CR becomes CRLF; an LF right after a CR already has its CR
+    fn cr_to_crlf<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        let split_crlf = self.after_cr && data.first() == Some(&LF);
+        if !split_crlf && !data.contains(&CR) {
+            return self.pass(data);
+        }
+        let mut out = Vec::with_capacity(data.len() + data.len() / 8 + 1);
+        for &b in data {
+            match b {
+                CR => out.extend_from_slice(b"\r\n"),
+                LF if self.after_cr => {}
+                _ => out.push(b),
+            }
+            self.after_cr = b == CR;
+        }
+        Cow::Owned(out)
+    }
+
+    /// LF without a CR before it becomes CRLF
+    fn lf_to_crlf<'a>(&mut self, data: &'a [u8]) -> Cow<'a, [u8]> {
+        if !data.contains(&LF) {
+            return self.pass(data);
+        }
+        let mut out = Vec::with_capacity(data.len() + data.len() / 8 + 1);
+        for &b in data {
+            if b == LF && !self.after_cr {
+                out.push(CR);
+            }
+            out.push(b);
+            self.after_cr = b == CR;
+        }
+        Cow::Owned(out)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Translate `chunks` in order as one stream
+    fn tcp_to_uart(mode: TcpToUartEol, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut state = EolState::default();
+        chunks.iter().flat_map(|chunk| state.tcp_to_uart(mode, chunk).into_owned()).collect()
+    }
+
+    fn uart_to_tcp(mode: UartToTcpEol, chunks: &[&[u8]]) -> Vec<u8> {
+        let mut state = EolState::default();
+        chunks.iter().flat_map(|chunk| state.uart_to_tcp(mode, chunk).into_owned()).collect()
+    }
+
+    #[test]
+    fn tcp_line_endings_are_translated() {
+        let input: &[u8] = b"a\rb\nc\r\nd";
+        assert_eq!(tcp_to_uart(TcpToUartEol::None, &[input]), input);
+        assert_eq!(tcp_to_uart(TcpToUartEol::CrToCrLf, &[input]), b"a\r\nb\nc\r\nd");
+        assert_eq!(tcp_to_uart(TcpToUartEol::LfToCrLf, &[input]), b"a\rb\r\nc\r\nd");
+        assert_eq!(tcp_to_uart(TcpToUartEol::StripCr, &[input]), b"ab\nc\nd");
+        assert_eq!(uart_to_tcp(UartToTcpEol::LfToCrLf, &[b"a\nb\r\n\n"]), b"a\r\nb\r\n\r\n");
+    }
+
+    #[test]
+    fn crlf_split_across_reads_is_translated_once() {
+        assert_eq!(tcp_to_uart(TcpToUartEol::CrToCrLf, &[b"AT\r", b"\nAT\r", b"\n"]), b"AT\r\nAT\r\n");
+        assert_eq!(tcp_to_uart(TcpToUartEol::LfToCrLf, &[b"AT\r", b"\nAT\r", b"\n"]), b"AT\r\nAT\r\n");
+        assert_eq!(uart_to_tcp(UartToTcpEol::LfToCrLf, &[b"OK\r", b"\n"]), b"OK\r\n");
+        // 上一块以CR结尾时，下一块开头之后的LF照常处理
+        assert_eq!(tcp_to_uart(TcpToUartEol::CrToCrLf, &[b"\r", b"x\n"]), b"\r\nx\n");
+        assert_eq!(uart_to_tcp(UartToTcpEol::LfToCrLf, &[b"\r", b"x\n"]), b"\rx\r\n");
+    }
+
+    #[test]
+    fn every_split_gives_the_unsplit_result() {
+        let input: &[u8] = b"\r\r\n\nab\r\n\rc\n\r";
+        let tcp_modes = [TcpToUartEol::None, TcpToUartEol::CrToCrLf, TcpToUartEol::LfToCrLf, TcpToUartEol::StripCr];
+        for split in 0..=input.len() {
+            let chunks = [&input[..split], &input[split..]];
+            for mode in tcp_modes {
+                assert_eq!(tcp_to_uart(mode, &chunks), tcp_to_uart(mode, &[input]), "{:?} split at {}", mode, split);
+            }
+            for mode in [UartToTcpEol::None, UartToTcpEol::LfToCrLf] {
+                assert_eq!(uart_to_tcp(mode, &chunks), uart_to_tcp(mode, &[input]), "{:?} split at {}", mode, split);
+            }
+        }
+    }
+
+    #[test]
+    fn untouched_data_is_not_copied() {
+        let mut state = EolState::default();
+        assert!(matches!(state.tcp_to_uart(TcpToUartEol::CrToCrLf, b"plain\n"), Cow::Borrowed(_)));
+        assert!(matches!(state.tcp_to_uart(TcpToUartEol::StripCr, b"plain\n"), Cow::Borrowed(_)));
+        assert!(matches!(state.uart_to_tcp(UartToTcpEol::LfToCrLf, b"plain"), Cow::Borrowed(_)));
+    }
+}
@@ -2,19 +2,59 @@ use std::fmt;
 use std::io;
 use std::error::Error as StdError;
 
+/// Underlying cause of an error, e.g. an `io::Error` or `esp_idf_sys::EspError`
+pub type Source = Box<dyn StdError + Send + Sync>;
+
 /// Custom error type for the application
+///
+/// The cause of an error is part of its `Display` output ("op: cause"), so
+/// `source` returns None; the cause stays reachable through the `source` fields.
 #[derive(Debug)]
 pub enum Error {
     /// I/O errors
     Io(io::Error),
-    /// ESP-IDF specific errors
-    EspError(String),
+    /// ESP-IDF call that returned an error code
+    Esp {
+        /// The `esp_err_t` returned by the call
+        code: i32,
+        /// What was being done when the call failed
+        context: &'static str,
+    },
     /// WiFi configuration errors
-    WiFiError(String),
+    WiFiError {
+        /// The operation that failed
+        op: String,
+        /// What caused the failure, if known
+        source: Option<Source>,
+    },
     /// TCP server errors
-    TcpError(String),
+    TcpError {
+        /// The operation that failed
+        op: String,
+        /// What caused the failure, if known
+        source: Option<Source>,
+    },
     /// UART errors
-    UartError(String),
+    UartError {
+        /// The operation that failed
+        op: String,
+        /// What caused the failure, if known
+        source: Option<Source>,
+    },
+    /// MQTT bridge errors
+    MqttError {
+        /// The operation that failed
+        op: String,
+        /// What caused the failure, if known
+        source: Option<Source>,
+    },
+    /// Firmware update errors
+    OtaError {
+        /// The operation that failed
+        op: String,
+        /// What caused the failure, if known
+        source: Option<Source>,
+    },
     /// Client manager errors
     ClientError(String),
     /// Storage errors
@@ -23,14 +63,119 @@ pub enum Error {
     General(String),
 }
 
+impl Error {
+    /// An ESP-IDF call failed with `code`
+    pub fn esp(code: i32, context: &'static str) -> Self {
+        Error::Esp { code, context }
+    }
+
+    /// A WiFi operation failed
+    pub fn wifi(op: impl Into<String>) -> Self {
+        Error::WiFiError { op: op.into(), source: None }
+    }
+
+    /// A WiFi operation failed because of `source`
+    pub fn wifi_caused(op: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::WiFiError { op: op.into(), source: Some(source.into()) }
+    }
+
+    /// A TCP operation failed
+    pub fn tcp(op: impl Into<String>) -> Self {
+        Error::TcpError { op: op.into(), source: None }
+    }
+
+    /// A TCP operation failed because of `source`
+    pub fn tcp_caused(op: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::TcpError { op: op.into(), source: Some(source.into()) }
+    }
+
+    /// A UART operation failed
+    pub fn uart(op: impl Into<String>) -> Self {
+        Error::UartError { op: op.into(), source: None }
+    }
+
+    /// A UART operation failed because of `source`
+    pub fn uart_caused(op: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::UartError { op: op.into(), source: Some(source.into()) }
+    }
+
+    /// An MQTT operation failed
+    pub fn mqtt(op: impl Into<String>) -> Self {
+        Error::MqttError { op: op.into(), source: None }
+    }
+
+    /// An MQTT operation failed because of `source`
+    pub fn mqtt_caused(op: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::MqttError { op: op.into(), source: Some(source.into()) }
+    }
+
+    /// A firmware update operation failed
+    pub fn ota(op: impl Into<String>) -> Self {
+        Error::OtaError { op: op.into(), source: None }
+    }
+
+    /// A firmware update operation failed because of `source`
+    pub fn ota_caused(op: impl Into<String>, source: impl Into<Source>) -> Self {
+        Error::OtaError { op: op.into(), source: Some(source.into()) }
+    }
+
+    /// Get the ESP-IDF error code behind this error, if there is one
+    pub fn esp_code(&self) -> Option<i32> {
+        match self {
+            Error::Esp { code, .. } => Some(*code),
+            #[cfg(target_os = "espidf")]
+            Error::WiFiError { source: Some(source), .. }
+            | Error::TcpError { source: Some(source), .. }
+            | Error::UartError { source: Some(source), .. }
+            | Error::MqttError { source: Some(source), .. }
+            | Error::OtaError { source: Some(source), .. } => source
+                .downcast_ref::<esp_idf_sys::EspError>()
+                .map(|e| e.code()),
+            _ => None,
+        }
+    }
+}
+
+/// Write "op" or "op: source"
+fn write_op(f: &mut fmt::Formatter<'_>, op: &str, source: &Option<Source>) -> fmt::Result {
+    match source {
+        Some(source) => write!(f, "{}: {}", op, source),
+        None => write!(f, "{}", op),
+    }
+}
+
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Error::Io(err) => write!(f, "I/O error: {}", err),
-            Error::EspError(msg) => write!(f, "ESP-IDF error: {}", msg),
-            Error::WiFiError(msg) => write!(f, "WiFi error: {}", msg),
-            Error::TcpError(msg) => write!(f, "TCP error: {}", msg),
-            Error::UartError(msg) => write!(f, "UART error: {}", msg),
+            #[cfg(target_os = "espidf")]
+            Error::Esp { code, context } => match esp_idf_sys::EspError::from(*code) {
+                Some(err) => write!(f, "ESP-IDF error: {} failed: {}", context, err),
+                None => write!(f, "ESP-IDF error: {} failed", context),
+            },
+            // 主机上没有ESP-IDF的错误名称表，只显示错误码
+            #[cfg(not(target_os = "espidf"))]
+            Error::Esp { code, context } => write!(f, "ESP-IDF error: {} failed: {}", context, code),
+            Error::WiFiError { op, source } => {
+                write!(f, "WiFi error: ")?;
+                write_op(f, op, source)
+            }
+            Error::TcpError { op, source } => {
+                write!(f, "TCP error: ")?;
+                write_op(f, op, source)
+            }
+            Error::UartError { op, source } => {
+                write!(f, "UART error: ")?;
+                write_op(f, op, source)
+            }
+            Error::MqttError { op, source } => {
+                write!(f, "MQTT error: ")?;
+                write_op(f, op, source)
+            }
+            Error::OtaError { op, source } => {
+                write!(f, "OTA error: ")?;
+                write_op(f, op, source)
+            }
             Error::ClientError(msg) => write!(f, "Client error: {}", msg),
             Error::StorageError(msg) => write!(f, "Storage error: {}", msg),
             Error::General(msg) => write!(f, "Error: {}", msg),
@@ -38,14 +183,8 @@ impl fmt::Display for Error {
     }
 }
 
-impl StdError for Error {
-    fn source(&self) -> Option<&(dyn StdError + 'static)> {
-        match self {
-            Error::Io(err) => Some(err),
-            _ => None,
-        }
-    }
-}
+// 原因已经包含在Display输出中，source保持默认的None，避免错误链中重复打印
+impl StdError for Error {}
 
 impl From<io::Error> for Error {
     fn from(err: io::Error) -> Self {
@@ -53,6 +192,13 @@ impl From<io::Error> for Error {
     }
 }
 
+#[cfg(target_os = "espidf")]
+impl From<esp_idf_sys::EspError> for Error {
+    fn from(err: esp_idf_sys::EspError) -> Self {
+        Error::esp(err.code(), "ESP-IDF call")
+    }
+}
+
 impl From<anyhow::Error> for Error {
     fn from(err: anyhow::Error) -> Self {
         Error::General(err.to_string())
@@ -61,3 +207,21 @@ impl From<anyhow::Error> for Error {
 
 /// Result type for the application
 pub type Result<T> = std::result::Result<T, Error>;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cause_is_displayed_once() {
+        let cause = io::Error::new(io::ErrorKind::BrokenPipe, "pipe closed");
+        let error = Error::tcp_caused("Failed to write to client", cause);
+        assert_eq!(error.to_string(), "TCP error: Failed to write to client: pipe closed");
+        assert!(error.source().is_none());
+        assert_eq!(Error::uart("tx queue full").to_string(), "UART error: tx queue full");
+
+        let error = Error::from(io::Error::new(io::ErrorKind::TimedOut, "timed out"));
+        assert_eq!(error.to_string(), "I/O error: timed out");
+        assert!(error.source().is_none());
+    }
+}
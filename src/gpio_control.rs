@@ -0,0 +1,476 @@
+//! GPIO control module
+//!
+//! This module drives and reads the named GPIOs of `GpioConfig`, typically wired to
+//! the RESET and BOOT pins of the target, so it can be reset or put into its
+//! bootloader remotely with AT+GPIO and then reflashed through the serial bridge.
+//!
+//! Values are logical: 1 asserts a pin, which drives an active-low pin low. Outputs
+//! are set inactive at boot. A pulse is ended by its own short-lived thread, so a
+//! long pulse does not hold up the command that started it.
+//!
+//! With `GpioConfig::target_reset`, two of the pins emulate the DTR/RTS auto-reset
+//! circuit esptool relies on, so an ESP8266/ESP32 target can be put into its
+//! bootloader and flashed over WiFi through the RFC 2217 port or a raw connection.
+//!
+//! The host build simulates the pins: outputs keep the level they were set to and
+//! inputs read low.
+
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::{GpioConfig, GpioDirection, GpioPinConfig, TargetResetConfig};
+use crate::error::{Error, Result};
+use crate::uart::{FLASH_GPIOS, MAX_GPIO};
+
+/// Longest pulse accepted, in milliseconds
+pub const MAX_PULSE_MS: u64 = 60_000;
+
+/// What AT+GPIO does to a pin
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GpioAction {
+    /// Assert (true) or release (false) the pin
+    Set(bool),
+    /// Assert the pin for this many milliseconds (None uses `GpioConfig::pulse_ms`)
+    Pulse(Option<u64>),
+}
+
+/// Driver of one controlled pin
+enum PinIo {
+    Output(Mutex<pin::Output>),
+    Input(Mutex<pin::Input>),
+}
+
+/// One controlled pin
+struct ControlledPin {
+    config: GpioPinConfig,
+    io: PinIo,
+    /// Set while a pulse or reset sequence is in progress; other changes are refused meanwhile
+    pulsing: AtomicBool,
+}
+
+impl ControlledPin {
+    /// Drive an output to its asserted or released level
+    fn drive(&self, active: bool) -> Result<()> {
+        let PinIo::Output(driver) = &self.io else {
+            return Err(Error::General(format!("GPIO {} is an input", self.config.name)));
+        };
+        let mut driver = driver
+            .lock()
+            .map_err(|_| Error::General(format!("Failed to lock GPIO {}", self.config.name)))?;
+        let result = if active != self.config.active_low {
+            driver.set_high()
+        } else {
+            driver.set_low()
+        };
+        result.map_err(|e| Error::General(format!("Failed to drive GPIO {}: {}", self.config.name, e)))
+    }
+
+    /// Whether the pin is asserted (for outputs, the level being driven)
+    fn is_active(&self) -> bool {
+        let high = match &self.io {
+            PinIo::Output(driver) => driver.lock().map(|driver| driver.is_set_high()).unwrap_or(false),
+            PinIo::Input(driver) => driver.lock().map(|driver| driver.is_high()).unwrap_or(false),
+        };
+        high != self.config.active_low
+    }
+}
+
+/// State of one controlled pin, as listed by AT+GPIO?
+#[derive(Debug, Clone)]
+pub struct GpioState {
+    /// Configured name
+    pub name: &'static str,
+    /// GPIO number
+    pub pin: u8,
+    /// Whether the pin is driven or read
+    pub direction: GpioDirection,
+    /// Whether the pin is active low
+    pub active_low: bool,
+    /// Whether the pin is asserted
+    pub active: bool,
+    /// Whether a pulse or reset sequence is in progress
+    pub pulsing: bool,
+}
+
+/// Named GPIOs controlled with AT+GPIO
+pub struct GpioControl {
+    pins: Vec<ControlledPin>,
+    /// Length of a pulse without explicit length, in milliseconds
+    pulse_ms: u64,
+    /// Reset sequencing with the indices of the EN and IO0 pins
+    target_reset: Option<(TargetResetConfig, usize, usize)>,
+}
+
+impl GpioControl {
+    /// Set up the configured pins, driving outputs to their inactive level
+    pub fn new(config: &GpioConfig) -> Result<Self> {
+        let mut pins: Vec<ControlledPin> = Vec::with_capacity(config.pins.len());
+        for pin_config in &config.pins {
+            let pin = pin_config.pin as i32;
+            if pin > MAX_GPIO || FLASH_GPIOS.contains(&pin) {
+                return Err(Error::General(format!("Invalid GPIO{} for {}", pin, pin_config.name)));
+            }
+            if pins.iter().any(|other| {
+                other.config.pin == pin_config.pin || other.config.name.eq_ignore_ascii_case(pin_config.name)
+            }) {
+                return Err(Error::General(format!("GPIO {} (GPIO{}) is listed twice", pin_config.name, pin)));
+            }
+
+            let io = match pin_config.direction {
+                GpioDirection::Output => {
+                    let driver = pin::output(pin)
+                        .map_err(|e| Error::General(format!("Failed to set up GPIO{}: {}", pin, e)))?;
+                    PinIo::Output(Mutex::new(driver))
+                }
+                GpioDirection::Input => {
+                    let driver = pin::input(pin)
+                        .map_err(|e| Error::General(format!("Failed to set up GPIO{}: {}", pin, e)))?;
+                    PinIo::Input(Mutex::new(driver))
+                }
+            };
+            let controlled = ControlledPin {
+                config: pin_config.clone(),
+                io,
+                pulsing: AtomicBool::new(false),
+            };
+            // 上电后立即释放目标板的控制线
+            if pin_config.direction == GpioDirection::Output {
+                controlled.drive(false)?;
+            }
+            info!("GPIO {} on GPIO{} ready", pin_config.name, pin);
+            pins.push(controlled);
+        }
+        let target_reset = match &config.target_reset {
+            Some(reset) => {
+                let find_output = |name: &str| {
+                    pins.iter()
+                        .position(|pin| {
+                            pin.config.name.eq_ignore_ascii_case(name) && pin.config.direction == GpioDirection::Output
+                        })
+                        .ok_or_else(|| Error::General(format!("Target reset pin {} is not a configured output", name)))
+                };
+                let en = find_output(reset.reset_pin)?;
+                let io0 = find_output(reset.boot_pin)?;
+                if en == io0 {
+                    return Err(Error::General("Target reset needs two different pins".to_string()));
+                }
+                Some((reset.clone(), en, io0))
+            }
+            None => None,
+        };
+        Ok(Self {
+            pins,
+            pulse_ms: config.pulse_ms.min(MAX_PULSE_MS),
+            target_reset,
+        })
+    }
+
+    /// Apply an AT+GPIO action to the pin called `name` (case-insensitive)
+    ///
+    /// A pulse returns once the pin is asserted; it is released from another thread.
+    /// Returns the pulse length for a pulse.
+    pub fn apply(self: &Arc<Self>, name: &str, action: GpioAction) -> Result<Option<u64>> {
+        let index = self
+            .pins
+            .iter()
+            .position(|pin| pin.config.name.eq_ignore_ascii_case(name))
+            .ok_or_else(|| Error::General(format!("Unknown GPIO {}", name)))?;
+        let pin = &self.pins[index];
+        if pin.pulsing.load(Ordering::Acquire) {
+            return Err(Error::General(format!("GPIO {} is busy", pin.config.name)));
+        }
+
+        match action {
+            GpioAction::Set(active) => {
+                pin.drive(active)?;
+                info!("GPIO {} {}", pin.config.name, if active { "asserted" } else { "released" });
+                Ok(None)
+            }
+            GpioAction::Pulse(ms) => {
+                let ms = ms.unwrap_or(self.pulse_ms);
+                if pin.pulsing.swap(true, Ordering::AcqRel) {
+                    return Err(Error::General(format!("GPIO {} is busy", pin.config.name)));
+                }
+                if let Err(e) = pin.drive(true) {
+                    pin.pulsing.store(false, Ordering::Release);
+                    return Err(e);
+                }
+                info!("GPIO {} pulsed for {} ms", pin.config.name, ms);
+
+                // 在独立线程中结束脉冲，不阻塞命令处理
+                let control = Arc::clone(self);
+                let spawned = thread::Builder::new()
+                    .name("gpio_pulse".into())
+                    .stack_size(2048)
+                    .spawn(move || {
+                        thread::sleep(Duration::from_millis(ms));
+                        let pin = &control.pins[index];
+                        if let Err(e) = pin.drive(false) {
+                            warn!("Failed to end pulse on GPIO {}: {}", pin.config.name, e);
+                        }
+                        pin.pulsing.store(false, Ordering::Release);
+                    });
+                if let Err(e) = spawned {
+                    // 无法计时就立即释放，不能让目标板停在复位状态
+                    let _ = pin.drive(false);
+                    pin.pulsing.store(false, Ordering::Release);
+                    return Err(Error::General(format!("Failed to spawn pulse thread: {}", e)));
+                }
+                Ok(Some(ms))
+            }
+        }
+    }
+
+    /// Reset the target, into its bootloader if `bootloader` is set
+    ///
+    /// Runs the esptool sequence: assert EN (and IO0 for the bootloader), wait
+    /// `reset_ms`, release EN, wait `boot_hold_ms`, release IO0. Returns once the
+    /// sequence is finished.
+    pub fn reset_target(&self, bootloader: bool) -> Result<()> {
+        let (reset, en, io0) = self
+            .target_reset
+            .as_ref()
+            .ok_or_else(|| Error::General("Target reset is not configured".to_string()))?;
+        let (en, io0) = (&self.pins[*en], &self.pins[*io0]);
+        if en.pulsing.swap(true, Ordering::AcqRel) {
+            return Err(Error::General(format!("GPIO {} is busy", en.config.name)));
+        }
+        if io0.pulsing.swap(true, Ordering::AcqRel) {
+            en.pulsing.store(false, Ordering::Release);
+            return Err(Error::General(format!("GPIO {} is busy", io0.config.name)));
+        }
+
+        let result = Self::run_reset_sequence(reset, en, io0, bootloader);
+        if result.is_err() {
+            // 出错时释放两条控制线，让目标板正常运行
+            let _ = en.drive(false);
+            let _ = io0.drive(false);
+        }
+        en.pulsing.store(false, Ordering::Release);
+        io0.pulsing.store(false, Ordering::Release);
+        if result.is_ok() {
+            info!("Target reset {}", if bootloader { "into bootloader" } else { "to run" });
+        }
+        result
+    }
+
+    fn run_reset_sequence(
+        reset: &TargetResetConfig,
+        en: &ControlledPin,
+        io0: &ControlledPin,
+        bootloader: bool,
+    ) -> Result<()> {
+        io0.drive(bootloader)?;
+        en.drive(true)?;
+        thread::sleep(Duration::from_millis(reset.reset_ms));
+        en.drive(false)?;
+        if bootloader {
+            // IO0在EN释放后保持一段时间，目标板采样到下载模式
+            thread::sleep(Duration::from_millis(reset.boot_hold_ms));
+            io0.drive(false)?;
+        }
+        Ok(())
+    }
+
+    /// Get the state of every controlled pin
+    pub fn states(&self) -> Vec<GpioState> {
+        self.pins
+            .iter()
+            .map(|pin| GpioState {
+                name: pin.config.name,
+                pin: pin.config.pin,
+                direction: pin.config.direction,
+                active_low: pin.config.active_low,
+                active: pin.is_active(),
+                pulsing: pin.pulsing.load(Ordering::Acquire),
+            })
+            .collect()
+    }
+}
+
+/// Pin drivers of the ESP-IDF HAL
+#[cfg(target_os = "espidf")]
+mod pin {
+    use esp_idf_hal::gpio::{self, PinDriver};
+    use esp_idf_sys::EspError;
+
+    pub type Output = PinDriver<'static, gpio::AnyOutputPin, gpio::Output>;
+    pub type Input = PinDriver<'static, gpio::AnyIOPin, gpio::Input>;
+
+    pub fn output(pin: i32) -> Result<Output, EspError> {
+        PinDriver::output(unsafe { gpio::AnyOutputPin::new(pin) })
+    }
+
+    pub fn input(pin: i32) -> Result<Input, EspError> {
+        PinDriver::input(unsafe { gpio::AnyIOPin::new(pin) })
+    }
+}
+
+/// Simulated pins for the host build
+#[cfg(not(target_os = "espidf"))]
+mod pin {
+    use std::convert::Infallible;
+
+    /// Output that remembers the level it drives
+    #[derive(Default)]
+    pub struct Output {
+        high: bool,
+    }
+
+    impl Output {
+        pub fn set_high(&mut self) -> Result<(), Infallible> {
+            self.high = true;
+            Ok(())
+        }
+
+        pub fn set_low(&mut self) -> Result<(), Infallible> {
+            self.high = false;
+            Ok(())
+        }
+
+        pub fn is_set_high(&self) -> bool {
+            self.high
+        }
+    }
+
+    /// Input that always reads low
+    pub struct Input;
+
+    impl Input {
+        pub fn is_high(&self) -> bool {
+            false
+        }
+    }
+
+    pub fn output(_pin: i32) -> Result<Output, Infallible> {
+        Ok(Output::default())
+    }
+
+    pub fn input(_pin: i32) -> Result<Input, Infallible> {
+        Ok(Input)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn pin(name: &'static str, pin: u8, direction: GpioDirection, active_low: bool) -> GpioPinConfig {
+        GpioPinConfig { name, pin, direction, active_low }
+    }
+
+    fn control(pins: Vec<GpioPinConfig>) -> Result<Arc<GpioControl>> {
+        GpioControl::new(&GpioConfig { pins, pulse_ms: 100, target_reset: None }).map(Arc::new)
+    }
+
+    /// Level the simulated output of the pin at `index` drives
+    fn level(control: &GpioControl, index: usize) -> bool {
+        match &control.pins[index].io {
+            PinIo::Output(driver) => driver.lock().unwrap().is_set_high(),
+            PinIo::Input(_) => panic!("not an output"),
+        }
+    }
+
+    #[test]
+    fn outputs_start_released_and_active_low_pins_are_driven_low() {
+        let control = control(vec![
+            pin("RESET", 4, GpioDirection::Output, true),
+            pin("BOOT", 5, GpioDirection::Output, false),
+            pin("READY", 6, GpioDirection::Input, false),
+        ])
+        .unwrap();
+        assert!(control.states().iter().all(|state| !state.active && !state.pulsing));
+        assert!(level(&control, 0));
+        assert!(!level(&control, 1));
+
+        // 名称不区分大小写
+        assert_eq!(control.apply("reset", GpioAction::Set(true)).unwrap(), None);
+        assert!(!level(&control, 0));
+        assert!(control.states()[0].active);
+        control.apply("BOOT", GpioAction::Set(true)).unwrap();
+        assert!(level(&control, 1));
+
+        assert!(control.apply("READY", GpioAction::Set(true)).is_err());
+        assert!(control.apply("POWER", GpioAction::Set(true)).is_err());
+    }
+
+    #[test]
+    fn pulse_releases_the_pin_by_itself_and_blocks_other_changes() {
+        let control = control(vec![pin("RESET", 4, GpioDirection::Output, true)]).unwrap();
+        assert_eq!(control.apply("RESET", GpioAction::Pulse(Some(50))).unwrap(), Some(50));
+        assert!(control.states()[0].active);
+        assert!(control.states()[0].pulsing);
+        assert!(control.apply("RESET", GpioAction::Set(false)).is_err());
+
+        let started = std::time::Instant::now();
+        while control.states()[0].pulsing {
+            assert!(started.elapsed() < Duration::from_secs(2), "pulse never ended");
+            thread::sleep(Duration::from_millis(5));
+        }
+        assert!(!control.states()[0].active);
+        assert_eq!(control.apply("RESET", GpioAction::Pulse(None)).unwrap(), Some(100));
+    }
+
+    #[test]
+    fn pins_on_flash_beyond_the_chip_or_listed_twice_are_refused() {
+        let flash = *FLASH_GPIOS.start() as u8;
+        assert!(control(vec![pin("RESET", flash, GpioDirection::Output, true)]).is_err());
+        assert!(control(vec![pin("RESET", MAX_GPIO as u8 + 1, GpioDirection::Output, true)]).is_err());
+        assert!(control(vec![
+            pin("RESET", 4, GpioDirection::Output, true),
+            pin("reset", 5, GpioDirection::Output, true),
+        ])
+        .is_err());
+        assert!(control(vec![
+            pin("RESET", 4, GpioDirection::Output, true),
+            pin("BOOT", 4, GpioDirection::Output, true),
+        ])
+        .is_err());
+    }
+
+    /// RESET and BOOT wired for the esptool reset sequence, with short timings
+    fn esp_target() -> Result<Arc<GpioControl>> {
+        let reset = TargetResetConfig { reset_ms: 1, boot_hold_ms: 1, ..TargetResetConfig::default() };
+        let pins = vec![
+            pin("RESET", 4, GpioDirection::Output, true),
+            pin("BOOT", 5, GpioDirection::Output, true),
+        ];
+        GpioControl::new(&GpioConfig { pins, pulse_ms: 100, target_reset: Some(reset) }).map(Arc::new)
+    }
+
+    #[test]
+    fn target_reset_releases_both_lines_when_done() {
+        let control = esp_target().unwrap();
+        for bootloader in [true, false] {
+            control.reset_target(bootloader).unwrap();
+            assert!(control.states().iter().all(|state| !state.active && !state.pulsing));
+            assert!(level(&control, 0) && level(&control, 1));
+        }
+
+        // 脉冲进行中时不能复位
+        control.apply("BOOT", GpioAction::Pulse(Some(1000))).unwrap();
+        assert!(control.reset_target(true).unwrap_err().to_string().contains("GPIO BOOT is busy"));
+        assert!(!control.states()[0].pulsing);
+    }
+
+    #[test]
+    fn target_reset_needs_two_configured_outputs() {
+        assert!(control(vec![pin("RESET", 4, GpioDirection::Output, true)])
+            .unwrap()
+            .reset_target(false)
+            .is_err());
+
+        let config = |boot_pin, direction| GpioConfig {
+            pins: vec![pin("RESET", 4, GpioDirection::Output, true), pin("BOOT", 5, direction, true)],
+            pulse_ms: 100,
+            target_reset: Some(TargetResetConfig { boot_pin, ..TargetResetConfig::default() }),
+        };
+        assert!(GpioControl::new(&config("BOOT", GpioDirection::Output)).is_ok());
+        assert!(GpioControl::new(&config("BOOT", GpioDirection::Input)).is_err());
+        assert!(GpioControl::new(&config("RESET", GpioDirection::Output)).is_err());
+        assert!(GpioControl::new(&config("IO0", GpioDirection::Output)).is_err());
+    }
+}
@@ -0,0 +1,747 @@
+//! HTTP server module
+//!
+//! This module serves a configuration page and a small JSON API on the HTTP port:
+//! - `GET /`: the configuration page, embedded in the firmware
+//! - `GET /api/status`: settings, connected clients and traffic counters as JSON
+//! - `POST /api/config`: change settings, form encoded, answered with JSON
+//!
+//! `POST /api/config` accepts the fields `sta_ssid`, `sta_password`, `ap_ssid`,
+//! `ap_password`, `baudrate` and `tcp_port`. Missing or empty fields are left
+//! unchanged, so `curl -d baudrate=9600 http://esp32-uart.local/api/config` only
+//! changes the baud rate. Each field is turned into the AT command changing the
+//! setting (AT+BAUD, AT+PORT, AT+AP and AT+WIFISTA) and runs through the command
+//! registry of the TCP server, so the API validates and applies settings exactly
+//! like a control client. The TCP port applies after a restart.
+//!
+//! The server is off by default (see `HttpServerConfig::enabled`): anyone who can
+//! reach it can change the WiFi credentials. Requests from peers refused by the
+//! access list of the TCP server (AT+ACL) are answered with 403.
+//!
+//! `GET /api/status` is meant for monitoring systems such as Telegraf: it reports
+//! `uptime_secs`, `heap` (free and minimum free bytes), `wifi` (mode, AP/STA
+//! addresses, station RSSI, channel and BSSID; null while a WiFi change is in
+//! progress), `uart`
+//! (settings and TX queue), `tcp`, the `clients` list with byte counters and the
+//! uptime each client connected at (`connected_since_secs`), and the
+//! forwarding counters in `stats`.
+//!
+//! The host build has no HTTP server; there `HttpServer::start` fails and only the
+//! form handling is available, for the tests.
+
+// 主机构建中请求处理函数不会被调用
+#![cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::server::{Configuration, EspHttpConnection, EspHttpServer, Request};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::http::{Headers, Method};
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::io::{EspIOError, Read, Write};
+use log::{info, warn};
+#[cfg(target_os = "espidf")]
+use std::mem::ManuallyDrop;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+#[cfg(target_os = "espidf")]
+use std::net::TcpStream;
+#[cfg(target_os = "espidf")]
+use std::os::fd::FromRawFd;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::acl::AccessList;
+use crate::commands::CommandRegistry;
+use crate::config::{ApBandwidth, HttpServerConfig};
+use crate::device_name;
+use crate::diagnostics;
+use crate::error::{Error, Result};
+use crate::json::JsonWriter;
+use crate::storage::StorageManager;
+use crate::tcp_client_manager::TcpClientManager;
+use crate::tcp_server::TcpServer;
+use crate::time;
+use crate::uart::UartManager;
+use crate::wifi::WiFiManager;
+
+/// The configuration page
+const CONFIG_PAGE: &str = include_str!("config_page.html");
+
+/// Stack size of the HTTP server task, which also applies setting changes
+const HTTP_STACK_SIZE: usize = 8192;
+
+/// Connections the HTTP server keeps open at once, enough for one browser
+const HTTP_MAX_OPEN_SOCKETS: usize = 3;
+
+/// Address reported for a request whose peer cannot be read from the socket
+const UNKNOWN_PEER: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Shared state the request handlers read and change
+#[derive(Clone)]
+struct HttpState {
+    /// TCP server whose command registry applies setting changes
+    server: Arc<TcpServer>,
+    /// Rules deciding which peers may use the server, shared with the TCP server
+    access_list: Arc<Mutex<AccessList>>,
+    uart_manager: Arc<UartManager>,
+    client_manager: Arc<TcpClientManager>,
+    wifi_manager: Arc<Mutex<WiFiManager>>,
+    storage: Option<Arc<Mutex<StorageManager>>>,
+    /// Port the TCP server is listening on
+    tcp_port: u16,
+    /// Largest request body accepted
+    max_body_bytes: usize,
+}
+
+/// One setting changed by POST /api/config, as the AT command applying it
+#[derive(Debug, PartialEq, Eq)]
+struct ConfigCommand {
+    /// Name of the setting, as in the results
+    setting: &'static str,
+    /// Command line run through the command registry
+    line: String,
+}
+
+/// Access point settings kept when POST /api/config changes the SSID or password
+struct CurrentAp {
+    ssid: String,
+    /// Channel as accepted by AT+AP, a number or AUTO
+    channel: String,
+    hidden: bool,
+    bandwidth: ApBandwidth,
+}
+
+/// Outcome of applying one setting
+struct SettingResult {
+    /// Name of the setting, as in the form
+    setting: &'static str,
+    /// Whether it was applied (or saved)
+    ok: bool,
+    /// What happened, for the page and the log
+    message: String,
+}
+
+/// HTTP server with the configuration page
+///
+/// The server runs in its own ESP-IDF task and stops when this value is dropped.
+pub struct HttpServer {
+    #[cfg(target_os = "espidf")]
+    _server: EspHttpServer<'static>,
+}
+
+impl HttpServer {
+    /// Start the HTTP server on the configured port
+    ///
+    /// `tcp_port` is the port the TCP server is listening on, reported by the API.
+    /// Setting changes are applied by the commands of `server`.
+    #[cfg(target_os = "espidf")]
+    pub fn start(
+        config: &HttpServerConfig,
+        server: Arc<TcpServer>,
+        uart_manager: Arc<UartManager>,
+        client_manager: Arc<TcpClientManager>,
+        wifi_manager: Arc<Mutex<WiFiManager>>,
+        storage: Option<Arc<Mutex<StorageManager>>>,
+        tcp_port: u16,
+    ) -> Result<Self> {
+        let state = HttpState {
+            access_list: server.access_list(),
+            server,
+            uart_manager,
+            client_manager,
+            wifi_manager,
+            storage,
+            tcp_port,
+            max_body_bytes: config.max_body_bytes,
+        };
+
+        let mut server = EspHttpServer::new(&Configuration {
+            http_port: config.port,
+            stack_size: HTTP_STACK_SIZE,
+            max_open_sockets: HTTP_MAX_OPEN_SOCKETS,
+            // 连接数用满时关闭最久未用的连接，浏览器的保持连接不会挡住新请求
+            lru_purge_enable: true,
+            ..Default::default()
+        })
+        .map_err(|e| Error::tcp_caused("Failed to start HTTP server", e))?;
+
+        let page_access_list = Arc::clone(&state.access_list);
+        server
+            .fn_handler("/", Method::Get, move |mut req| {
+                if permitted_peer(&mut req, &page_access_list).is_none() {
+                    return send_error(req, 403, "Not permitted by the access list");
+                }
+                req.into_response(200, None, &[("Content-Type", "text/html; charset=utf-8")])?
+                    .write_all(CONFIG_PAGE.as_bytes())
+            })
+            .map_err(|e| Error::tcp_caused("Failed to register HTTP handler", e))?;
+
+        let status_state = state.clone();
+        server
+            .fn_handler("/api/status", Method::Get, move |mut req| {
+                if permitted_peer(&mut req, &status_state.access_list).is_none() {
+                    return send_error(req, 403, "Not permitted by the access list");
+                }
+                send_json(req, 200, &status_json(&status_state))
+            })
+            .map_err(|e| Error::tcp_caused("Failed to register HTTP handler", e))?;
+
+        server
+            .fn_handler("/api/config", Method::Post, move |req| handle_config(req, &state))
+            .map_err(|e| Error::tcp_caused("Failed to register HTTP handler", e))?;
+
+        info!("HTTP server listening on port {}", config.port);
+        Ok(Self { _server: server })
+    }
+
+    /// Start the HTTP server on the configured port
+    #[cfg(not(target_os = "espidf"))]
+    pub fn start(
+        _config: &HttpServerConfig,
+        _server: Arc<TcpServer>,
+        _uart_manager: Arc<UartManager>,
+        _client_manager: Arc<TcpClientManager>,
+        _wifi_manager: Arc<Mutex<WiFiManager>>,
+        _storage: Option<Arc<Mutex<StorageManager>>>,
+        _tcp_port: u16,
+    ) -> Result<Self> {
+        Err(Error::tcp("No HTTP server in the host build"))
+    }
+}
+
+/// Address of the peer that sent `req`, None if the socket cannot tell
+#[cfg(target_os = "espidf")]
+fn peer_addr(req: &mut Request<&mut EspHttpConnection<'_>>) -> Option<SocketAddr> {
+    let raw = req.connection().raw_connection().ok()?;
+    let fd = unsafe { esp_idf_sys::httpd_req_to_sockfd(raw.handle()) };
+    if fd < 0 {
+        return None;
+    }
+    // 借用HTTP服务器的套接字，ManuallyDrop保证不会关闭它
+    let socket = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(fd) });
+    socket.peer_addr().ok()
+}
+
+/// Check the peer of `req` against the access list
+///
+/// Returns the peer address, `UNKNOWN_PEER` if the socket cannot tell, or None
+/// if the peer is refused.
+#[cfg(target_os = "espidf")]
+fn permitted_peer(
+    req: &mut Request<&mut EspHttpConnection<'_>>,
+    access_list: &Mutex<AccessList>,
+) -> Option<SocketAddr> {
+    let peer = peer_addr(req);
+    if is_permitted(access_list, peer) {
+        Some(peer.unwrap_or(UNKNOWN_PEER))
+    } else {
+        warn!("Rejecting HTTP request from {:?}: not permitted by the access list", peer);
+        None
+    }
+}
+
+/// Check whether `peer` may use the server
+///
+/// A peer whose address is unknown is refused as soon as the list has rules. A
+/// poisoned lock admits the peer, like the TCP ports.
+fn is_permitted(access_list: &Mutex<AccessList>, peer: Option<SocketAddr>) -> bool {
+    access_list.lock().map_or(true, |list| match peer {
+        Some(peer) => list.is_allowed(peer.ip()),
+        None => list.is_empty(),
+    })
+}
+
+/// Send a JSON document with the given status code
+#[cfg(target_os = "espidf")]
+fn send_json(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    body: &str,
+) -> std::result::Result<(), EspIOError> {
+    req.into_response(status, None, &[("Content-Type", "application/json")])?
+        .write_all(body.as_bytes())
+}
+
+/// Send `{"ok":false,"error":...}` with the given status code
+#[cfg(target_os = "espidf")]
+fn send_error(
+    req: Request<&mut EspHttpConnection<'_>>,
+    status: u16,
+    error: &str,
+) -> std::result::Result<(), EspIOError> {
+    let mut json = JsonWriter::new();
+    json.begin_object()
+        .key("ok")
+        .bool(false)
+        .key("error")
+        .string(error)
+        .end_object();
+    send_json(req, status, &json.finish())
+}
+
+/// Build the GET /api/status document
+///
+/// Only counters and snapshots are read, so a monitoring system polling this
+/// never holds the locks used by UART forwarding and broadcasting for long.
+fn status_json(state: &HttpState) -> String {
+    let uptime = time::uptime();
+    let (free_heap, min_free_heap) = (diagnostics::free_heap(), diagnostics::min_free_heap());
+    let mut json = JsonWriter::new();
+    json.begin_object()
+        .key("uptime_secs")
+        .number(uptime.as_secs())
+        .key("heap")
+        .begin_object()
+        .key("free")
+        .number(u64::from(free_heap))
+        .key("min_free")
+        .number(u64::from(min_free_heap))
+        .end_object();
+
+    // 修改STA配置时WiFi管理器会被长时间锁定，此时不等待
+    json.key("wifi");
+    match state.wifi_manager.try_lock() {
+        Ok(wifi) => {
+            let ap_ip = wifi.ap_ip_info().map(|info| info.ip.to_string());
+            let sta_ip = wifi.sta_ip_info().map(|info| info.ip.to_string());
+            let ap_stations = wifi.connected_stations().map(|stations| stations.len()).unwrap_or(0);
+            json.begin_object()
+                .key("mode")
+                .string(&format!("{:?}", wifi.mode()))
+                .key("hostname")
+                .string(&device_name::get())
+                .key("ap_ssid")
+                .string(wifi.ap_ssid())
+                .key("ap_ip")
+                .optional_string(ap_ip.as_deref())
+                .key("ap_stations")
+                .number(ap_stations as u64)
+                .key("sta_ssid")
+                .string(wifi.sta_ssid())
+                .key("sta_connected")
+                .bool(wifi.is_sta_connected())
+                .key("sta_ip")
+                .optional_string(sta_ip.as_deref())
+                .key("sta_rssi");
+            // 未连接或读取失败时为null
+            let link = wifi.sta_link().ok().flatten();
+            match &link {
+                Some(link) => json.signed(i64::from(link.rssi)),
+                None => json.null(),
+            };
+            json.key("sta_channel");
+            match &link {
+                Some(link) => json.number(u64::from(link.channel)),
+                None => json.null(),
+            };
+            json.key("sta_bssid")
+                .optional_string(link.as_ref().map(|link| link.bssid_string()).as_deref())
+                .end_object();
+        }
+        Err(_) => {
+            json.null();
+        }
+    }
+
+    let saved_port = state
+        .storage
+        .as_ref()
+        .and_then(|storage| storage.lock().ok()?.read_tcp_port());
+    write_bridge_status(&mut json, &state.uart_manager, &state.client_manager, state.tcp_port, saved_port, uptime);
+    json.end_object();
+    json.finish()
+}
+
+/// Write the `uart`, `tcp`, `clients` and `stats` members of the status document
+fn write_bridge_status(
+    json: &mut JsonWriter,
+    uart_manager: &UartManager,
+    client_manager: &TcpClientManager,
+    tcp_port: u16,
+    saved_port: Option<u16>,
+    uptime: Duration,
+) {
+    json.key("uart")
+        .begin_object()
+        .key("baudrate")
+        .number(u64::from(uart_manager.get_baudrate()))
+        .key("format")
+        .string(&uart_manager.get_format().to_string())
+        .key("framing")
+        .string(&uart_manager.framing().to_string())
+        .key("tx_queue")
+        .number(uart_manager.get_tx_queue_len() as u64);
+    let errors = uart_manager.get_error_counters();
+    json.key("errors")
+        .begin_object()
+        .key("framing")
+        .number(u64::from(errors.framing))
+        .key("parity")
+        .number(u64::from(errors.parity))
+        .key("overflow")
+        .number(u64::from(errors.overflow))
+        .key("break")
+        .number(u64::from(errors.breaks))
+        .end_object()
+        .end_object();
+
+    // 客户端列表是快照，不持有客户端锁
+    let clients = client_manager.list_clients().unwrap_or_default();
+    json.key("tcp")
+        .begin_object()
+        .key("port")
+        .number(u64::from(tcp_port))
+        .key("saved_port");
+    match saved_port {
+        Some(port) => json.number(u64::from(port)),
+        None => json.null(),
+    };
+    json.key("clients").number(clients.len() as u64).end_object();
+
+    json.key("clients").begin_array();
+    for client in &clients {
+        json.begin_object()
+            .key("addr")
+            .string(&client.addr.to_string())
+            .key("connected_secs")
+            .number(uptime.saturating_sub(client.connected_at).as_secs())
+            .key("connected_since_secs")
+            .number(client.connected_at.as_secs())
+            .key("idle_secs")
+            .number(uptime.saturating_sub(client.last_activity).as_secs())
+            .key("bytes_sent")
+            .number(client.bytes_sent)
+            .key("bytes_received")
+            .number(client.bytes_received)
+            .end_object();
+    }
+    json.end_array();
+
+    let uart_stats = uart_manager.stats();
+    let client_stats = client_manager.stats();
+    json.key("stats")
+        .begin_object()
+        .key("bytes_sent_to_uart")
+        .number(uart_stats.bytes_sent_to_uart)
+        .key("bytes_received_from_uart")
+        .number(uart_stats.bytes_received_from_uart)
+        .key("flow_pauses")
+        .number(uart_stats.flow_pauses)
+        .key("flow_resumes")
+        .number(uart_stats.flow_resumes)
+        .key("packet_errors")
+        .number(uart_stats.packet_errors)
+        .key("bytes_broadcast")
+        .number(client_stats.bytes_broadcast)
+        .key("broadcast_errors")
+        .number(client_stats.broadcast_errors)
+        .key("clients_total")
+        .number(client_stats.clients_total)
+        .key("clients_evicted")
+        .number(client_stats.clients_evicted)
+        .end_object();
+}
+
+/// Handle POST /api/config
+#[cfg(target_os = "espidf")]
+fn handle_config(
+    mut req: Request<&mut EspHttpConnection<'_>>,
+    state: &HttpState,
+) -> std::result::Result<(), EspIOError> {
+    let Some(peer) = permitted_peer(&mut req, &state.access_list) else {
+        return send_error(req, 403, "Not permitted by the access list");
+    };
+    if req.content_len().unwrap_or(0) > state.max_body_bytes as u64 {
+        return send_error(req, 413, "Request body too large");
+    }
+
+    // 读取整个请求体，超过上限则拒绝
+    let mut body = Vec::new();
+    let mut buffer = [0u8; 256];
+    loop {
+        let n = req.read(&mut buffer)?;
+        if n == 0 {
+            break;
+        }
+        body.extend_from_slice(&buffer[..n]);
+        if body.len() > state.max_body_bytes {
+            return send_error(req, 413, "Request body too large");
+        }
+    }
+
+    let current_ap = match state.wifi_manager.lock() {
+        Ok(wifi) => CurrentAp {
+            ssid: wifi.ap_ssid().to_string(),
+            channel: if wifi.is_ap_auto_channel() {
+                "AUTO".to_string()
+            } else {
+                wifi.ap_channel().to_string()
+            },
+            hidden: wifi.is_ap_hidden(),
+            bandwidth: wifi.ap_bandwidth(),
+        },
+        Err(_) => return send_error(req, 500, "Failed to lock WiFi manager"),
+    };
+    let commands = match parse_config(&body, &current_ap, state.server.commands()) {
+        Ok(commands) => commands,
+        Err(error) => return send_error(req, 400, &error),
+    };
+    let results = apply_config(&commands, state, &peer);
+
+    let mut json = JsonWriter::new();
+    json.begin_object()
+        .key("ok")
+        .bool(results.iter().all(|result| result.ok))
+        .key("results")
+        .begin_array();
+    for result in &results {
+        json.begin_object()
+            .key("setting")
+            .string(result.setting)
+            .key("ok")
+            .bool(result.ok)
+            .key("message")
+            .string(&result.message)
+            .end_object();
+    }
+    let restart_required = results.iter().any(|result| result.ok && result.setting == "tcp_port");
+    json.end_array()
+        .key("restart_required")
+        .bool(restart_required)
+        .end_object();
+    send_json(req, 200, &json.finish())
+}
+
+/// Parse a form encoded POST /api/config body into the commands applying it
+///
+/// Every command is planned by its handler in `registry` first, so an invalid
+/// field rejects the whole request before anything is changed. The commands are
+/// ordered like `apply_config` runs them: the station last, since it waits for the
+/// new network.
+fn parse_config(
+    body: &[u8],
+    current_ap: &CurrentAp,
+    registry: &CommandRegistry,
+) -> std::result::Result<Vec<ConfigCommand>, String> {
+    let body = std::str::from_utf8(body).map_err(|_| "Request body is not UTF-8".to_string())?;
+    let mut baudrate = None;
+    let mut tcp_port = None;
+    let mut sta_ssid = None;
+    let mut sta_password = None;
+    let mut ap_ssid = None;
+    let mut ap_password = None;
+
+    for pair in body.split('&').filter(|pair| !pair.is_empty()) {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        let name = url_decode(name)?;
+        let value = url_decode(value)?;
+        // 空字段表示保持不变
+        if value.is_empty() && name != "sta_password" {
+            continue;
+        }
+        match name.as_str() {
+            "baudrate" => baudrate = Some(value),
+            "tcp_port" => tcp_port = Some(value),
+            "sta_ssid" => sta_ssid = Some(value),
+            "sta_password" => sta_password = Some(value),
+            "ap_ssid" => ap_ssid = Some(value),
+            "ap_password" => ap_password = Some(value),
+            _ => return Err(format!("Unknown setting: {}", name)),
+        }
+    }
+
+    let mut commands = Vec::new();
+    if let Some(baudrate) = baudrate {
+        commands.push(ConfigCommand { setting: "baudrate", line: format!("AT+BAUD={}", baudrate.trim()) });
+    }
+    if let Some(port) = tcp_port {
+        commands.push(ConfigCommand { setting: "tcp_port", line: format!("AT+PORT={}", port.trim()) });
+    }
+    match (ap_ssid, ap_password) {
+        (ssid, Some(password)) => {
+            let ssid = ssid.as_deref().unwrap_or(&current_ap.ssid);
+            let line = format!(
+                "AT+AP={},{},{},{},{}",
+                ssid,
+                password,
+                current_ap.channel,
+                u8::from(current_ap.hidden),
+                current_ap.bandwidth
+            );
+            commands.push(ConfigCommand { setting: "ap", line });
+        }
+        (Some(_), None) => return Err("Changing the AP SSID requires ap_password".to_string()),
+        (None, None) => {}
+    }
+    // 开放网络的STA密码可以为空
+    match (sta_ssid, sta_password) {
+        (Some(ssid), password) => {
+            let line = format!("AT+WIFISTA={},{}", ssid, password.unwrap_or_default());
+            commands.push(ConfigCommand { setting: "sta", line });
+        }
+        (None, Some(password)) if !password.is_empty() => {
+            return Err("Changing the STA password requires sta_ssid".to_string());
+        }
+        (None, _) => {}
+    }
+
+    for command in &commands {
+        if let Some(Err(error)) = registry.plan(&command.line) {
+            return Err(error);
+        }
+    }
+    Ok(commands)
+}
+
+/// Decode one form encoded name or value ("+" is a space, "%XX" a byte)
+fn url_decode(value: &str) -> std::result::Result<String, String> {
+    let mut bytes = Vec::with_capacity(value.len());
+    let mut input = value.bytes();
+    while let Some(b) = input.next() {
+        match b {
+            b'+' => bytes.push(b' '),
+            b'%' => {
+                let digit = |b: Option<u8>| b.and_then(|b| char::from(b).to_digit(16));
+                match (digit(input.next()), digit(input.next())) {
+                    (Some(high), Some(low)) => bytes.push((high * 16 + low) as u8),
+                    _ => return Err(format!("Invalid escape in form data: {}", value)),
+                }
+            }
+            b => bytes.push(b),
+        }
+    }
+    String::from_utf8(bytes).map_err(|_| "Form data is not UTF-8".to_string())
+}
+
+/// Run the commands of a validated change for `peer`, one result per setting
+fn apply_config(commands: &[ConfigCommand], state: &HttpState, peer: &SocketAddr) -> Vec<SettingResult> {
+    let mut results = Vec::new();
+    for command in commands {
+        let (ok, text) = state.server.run_command(&command.line, peer);
+        let message = text
+            .lines()
+            .map(|line| line.trim())
+            .map(|line| line.strip_prefix("OK: ").or_else(|| line.strip_prefix("ERROR: ")).unwrap_or(line))
+            .filter(|line| !line.is_empty())
+            .collect::<Vec<_>>()
+            .join("; ");
+        if ok {
+            info!("HTTP config from {}: {}", peer, message);
+        } else {
+            warn!("HTTP config from {}: {}", peer, message);
+        }
+        results.push(SettingResult { setting: command.setting, ok, message });
+    }
+    results
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UartConfig;
+    use crate::tcp_client_manager::mock::{addr, MockStream};
+    use crate::uart::mock;
+
+    fn current_ap() -> CurrentAp {
+        CurrentAp {
+            ssid: "Bridge-AP".to_string(),
+            channel: "AUTO".to_string(),
+            hidden: false,
+            bandwidth: ApBandwidth::Ht20,
+        }
+    }
+
+    fn lines(body: &str) -> std::result::Result<Vec<String>, String> {
+        let commands = parse_config(body.as_bytes(), &current_ap(), &CommandRegistry::standard())?;
+        Ok(commands.into_iter().map(|command| command.line).collect())
+    }
+
+    #[test]
+    fn form_fields_become_commands() {
+        assert_eq!(
+            lines("sta_ssid=Work+shop&sta_password=p%40ss&baudrate=9600&tcp_port=9000&ap_password=new-pass").unwrap(),
+            [
+                "AT+BAUD=9600",
+                "AT+PORT=9000",
+                "AT+AP=Bridge-AP,new-pass,AUTO,0,HT20",
+                "AT+WIFISTA=Work shop,p@ss",
+            ]
+        );
+        assert_eq!(lines("ap_ssid=Lab&ap_password=x,y").unwrap(), ["AT+AP=Lab,x,y,AUTO,0,HT20"]);
+        assert_eq!(lines("sta_ssid=Open&sta_password=").unwrap(), ["AT+WIFISTA=Open,"]);
+        // 空字段保持不变
+        assert_eq!(lines("baudrate=&tcp_port=").unwrap(), Vec::<String>::new());
+    }
+
+    #[test]
+    fn invalid_fields_are_rejected_by_the_command_handlers() {
+        assert!(lines("baudrate=12").unwrap_err().starts_with("Unsupported baudrate: 12"));
+        assert_eq!(lines("baudrate=fast").unwrap_err(), "Invalid baudrate value: fast");
+        assert_eq!(lines("tcp_port=0").unwrap_err(), "Invalid port: 0 (use 1-65535)");
+        assert_eq!(lines("sta_ssid=&sta_password=secret").unwrap_err(), "Changing the STA password requires sta_ssid");
+        assert_eq!(lines("ap_ssid=Lab").unwrap_err(), "Changing the AP SSID requires ap_password");
+        assert_eq!(lines("mode=ap").unwrap_err(), "Unknown setting: mode");
+        assert_eq!(lines("ap_ssid=%zz").unwrap_err(), "Invalid escape in form data: %zz");
+        assert_eq!(lines("ap_ssid=%ff").unwrap_err(), "Form data is not UTF-8");
+        // 一个字段无效时不会执行任何命令
+        assert!(lines("baudrate=9600&tcp_port=70000").is_err());
+    }
+
+    #[test]
+    fn refused_peers_are_not_permitted() {
+        let access_list = Mutex::new(AccessList::parse("deny 192.168.4.0/24").unwrap());
+        assert!(!is_permitted(&access_list, Some("192.168.4.2:50000".parse().unwrap())));
+        assert!(is_permitted(&access_list, Some("10.0.0.7:50000".parse().unwrap())));
+        // 无法得知对端地址时，有规则就拒绝
+        assert!(!is_permitted(&access_list, None));
+        assert!(is_permitted(&Mutex::new(AccessList::default()), None));
+    }
+
+    /// Status members written by `write_bridge_status`, as a JSON object
+    fn bridge_status(uart: &UartManager, clients: &TcpClientManager, saved_port: Option<u16>) -> String {
+        let mut json = JsonWriter::new();
+        json.begin_object();
+        write_bridge_status(&mut json, uart, clients, 8880, saved_port, Duration::from_secs(90));
+        json.end_object();
+        json.finish()
+    }
+
+    #[test]
+    fn status_reports_settings_clients_and_counters() {
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let clients = TcpClientManager::new();
+        let (stream, _wire) = MockStream::new(addr(1));
+        clients.add_client(addr(1), stream).unwrap();
+        clients.set_raw_mode(&addr(1), true).unwrap();
+        clients.broadcast(b"hello", b"hello").unwrap();
+        clients.write_queued().unwrap();
+
+        let status = bridge_status(&uart, &clients, None);
+        assert!(status.starts_with(r#"{"uart":{"baudrate":115200,"format":"8N1","#), "{}", status);
+        assert!(status.contains(r#""tcp":{"port":8880,"saved_port":null,"clients":1}"#), "{}", status);
+        assert!(status.contains(&format!(r#""clients":[{{"addr":"{}","#, addr(1))), "{}", status);
+        assert!(status.contains(r#""bytes_sent":5,"bytes_received":0}]"#), "{}", status);
+        assert!(status.contains(r#""bytes_broadcast":5,"#), "{}", status);
+        assert_eq!(status.matches('{').count(), status.matches('}').count());
+        assert_eq!(status.matches('[').count(), status.matches(']').count());
+    }
+
+    #[test]
+    fn status_reports_the_saved_port_and_no_clients() {
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let status = bridge_status(&uart, &TcpClientManager::new(), Some(9000));
+        let expected = r#""tcp":{"port":8880,"saved_port":9000,"clients":0},"clients":[],"stats":{"#;
+        assert!(status.contains(expected), "{}", status);
+    }
+
+    #[test]
+    fn status_does_not_wait_for_a_busy_client_list() {
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let clients = TcpClientManager::new();
+        let (stream, _wire) = MockStream::new(addr(1));
+        clients.add_client(addr(1), Arc::clone(&stream)).unwrap();
+
+        // 客户端的流被写线程锁定时仍然可以生成状态
+        let _writing = stream.lock().unwrap();
+        let status = bridge_status(&uart, &clients, None);
+        assert!(status.contains(r#""clients":1}"#), "{}", status);
+    }
+}
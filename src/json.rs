@@ -0,0 +1,180 @@
+//! JSON module
+//!
+//! This module provides a minimal JSON writer for the HTTP API. Documents are
+//! written straight into a String, which keeps serde and serde_json out of the
+//! firmware image.
+
+use std::fmt::Write;
+
+/// Writes a JSON document into a String
+///
+/// Values written after `key` belong to that key; values written inside an array
+/// are separated automatically. The caller is responsible for balancing
+/// `begin_*` and `end_*` calls.
+#[derive(Debug, Default)]
+pub struct JsonWriter {
+    /// Document written so far
+    out: String,
+    /// Whether the next value or key must be preceded by a comma
+    needs_comma: bool,
+}
+
+impl JsonWriter {
+    /// Create an empty writer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Write an object key; the next value written belongs to it
+    pub fn key(&mut self, key: &str) -> &mut Self {
+        self.separate();
+        self.write_escaped(key);
+        self.out.push(':');
+        self.needs_comma = false;
+        self
+    }
+
+    /// Write a string value
+    pub fn string(&mut self, value: &str) -> &mut Self {
+        self.separate();
+        self.write_escaped(value);
+        self.needs_comma = true;
+        self
+    }
+
+    /// Write an unsigned number value
+    pub fn number(&mut self, value: u64) -> &mut Self {
+        self.separate();
+        let _ = write!(self.out, "{}", value);
+        self.needs_comma = true;
+        self
+    }
+
+    /// Write a signed number value
+    pub fn signed(&mut self, value: i64) -> &mut Self {
+        self.separate();
+        let _ = write!(self.out, "{}", value);
+        self.needs_comma = true;
+        self
+    }
+
+    /// Write a boolean value
+    pub fn bool(&mut self, value: bool) -> &mut Self {
+        self.separate();
+        self.out.push_str(if value { "true" } else { "false" });
+        self.needs_comma = true;
+        self
+    }
+
+    /// Write null
+    pub fn null(&mut self) -> &mut Self {
+        self.separate();
+        self.out.push_str("null");
+        self.needs_comma = true;
+        self
+    }
+
+    /// Write a string value, or null for None
+    pub fn optional_string(&mut self, value: Option<&str>) -> &mut Self {
+        match value {
+            Some(value) => self.string(value),
+            None => self.null(),
+        }
+    }
+
+    /// Start an object value
+    pub fn begin_object(&mut self) -> &mut Self {
+        self.separate();
+        self.out.push('{');
+        self.needs_comma = false;
+        self
+    }
+
+    /// End the current object
+    pub fn end_object(&mut self) -> &mut Self {
+        self.out.push('}');
+        self.needs_comma = true;
+        self
+    }
+
+    /// Start an array value
+    pub fn begin_array(&mut self) -> &mut Self {
+        self.separate();
+        self.out.push('[');
+        self.needs_comma = false;
+        self
+    }
+
+    /// End the current array
+    pub fn end_array(&mut self) -> &mut Self {
+        self.out.push(']');
+        self.needs_comma = true;
+        self
+    }
+
+    /// Return the written document
+    pub fn finish(self) -> String {
+        self.out
+    }
+
+    /// Write the comma before a value or key, if one is needed
+    fn separate(&mut self) {
+        if self.needs_comma {
+            self.out.push(',');
+        }
+    }
+
+    /// Write a quoted string with JSON escapes
+    fn write_escaped(&mut self, value: &str) {
+        self.out.push('"');
+        for c in value.chars() {
+            match c {
+                '"' => self.out.push_str("\\\""),
+                '\\' => self.out.push_str("\\\\"),
+                '\n' => self.out.push_str("\\n"),
+                '\r' => self.out.push_str("\\r"),
+                '\t' => self.out.push_str("\\t"),
+                // 其余控制字符使用\u转义
+                c if (c as u32) < 0x20 => {
+                    let _ = write!(self.out, "\\u{:04x}", c as u32);
+                }
+                c => self.out.push(c),
+            }
+        }
+        self.out.push('"');
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn members_and_elements_are_separated() {
+        let mut json = JsonWriter::new();
+        json.begin_object()
+            .key("a")
+            .number(1)
+            .key("b")
+            .begin_array()
+            .signed(-2)
+            .bool(true)
+            .null()
+            .begin_object()
+            .end_object()
+            .end_array()
+            .key("c")
+            .optional_string(None)
+            .key("d")
+            .optional_string(Some("x"))
+            .end_object();
+        assert_eq!(json.finish(), r#"{"a":1,"b":[-2,true,null,{}],"c":null,"d":"x"}"#);
+    }
+
+    #[test]
+    fn strings_are_escaped() {
+        let mut json = JsonWriter::new();
+        json.begin_object().key("k\"ey").string("a\\b\n\r\t\u{1}é").end_object();
+        assert_eq!(json.finish(), r#"{"k\"ey":"a\\b\n\r\t\u0001é"}"#);
+    }
+}
@@ -4,19 +4,57 @@
 //! with a TCP server that forwards data between TCP clients and UART.
 
 // Export modules
+pub mod acl;
+#[cfg(target_os = "espidf")]
+pub mod app;
+pub mod boot_info;
+pub mod commands;
 pub mod config;
+pub mod config_transfer;
+pub mod control_frame;
+pub mod device_name;
+pub mod diagnostics;
+pub mod eol;
 pub mod error;
+pub mod gpio_control;
+pub mod http_server;
+pub mod json;
+pub mod log_level;
+pub mod log_stream;
+pub mod mdns;
+pub mod mqtt_bridge;
+pub mod ota;
+pub mod packet_codec;
+#[cfg(target_os = "espidf")]
+pub mod reset_button;
+pub mod rfc2217;
+#[cfg(feature = "secret-storage")]
+pub mod secret;
+pub mod self_test;
+pub mod status_led;
 pub mod storage;
 pub mod tcp_client_manager;
+pub mod tcp_client_mode;
 pub mod tcp_server;
+pub mod time;
 pub mod uart;
+pub mod udp_bridge;
+pub mod version;
+pub mod watchdog;
+pub mod websocket;
 pub mod wifi;
 
 // Re-export public interfaces for easier access from crate root
+#[cfg(target_os = "espidf")]
+pub use app::App;
 pub use config::{AppConfig, create_config};
 pub use error::{Error, Result};
+pub use http_server::HttpServer;
+pub use mqtt_bridge::MqttBridge;
 pub use storage::StorageManager;
-pub use tcp_client_manager::TcpClientManager;
+pub use tcp_client_manager::{ClientEvent, TcpClientManager};
+pub use tcp_client_mode::TcpClientMode;
 pub use tcp_server::TcpServer;
 pub use uart::UartManager;
+pub use udp_bridge::UdpBridge;
 pub use wifi::WiFiManager;
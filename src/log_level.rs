@@ -0,0 +1,219 @@
+//! Log level module
+//!
+//! This module changes the log verbosity at runtime (AT+LOGLEVEL). The default level
+//! applies to the Rust `log` records and to every ESP-IDF tag; a level set for a
+//! target, i.e. a Rust module path such as "espc3::uart" or an ESP-IDF tag such as
+//! "wifi", overrides it for that target. Changes take effect immediately, including
+//! for the `log_enabled!` checks that guard the hex dump traces.
+//!
+//! The levels can be written as a spec like "info,wifi=warn,espc3::uart=trace",
+//! which is how they are persisted by `StorageManager`.
+
+use log::{info, LevelFilter};
+use std::fmt;
+use std::sync::Mutex;
+
+use crate::error::{Error, Result};
+
+/// Most targets that can have their own level
+pub const MAX_TARGET_LEVELS: usize = 8;
+
+/// Longest target name
+const MAX_TARGET_LEN: usize = 32;
+
+/// Default level and per-target overrides
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LogLevels {
+    /// Level of every target without an override
+    pub default: LevelFilter,
+    /// Targets with their own level, in the order they were set
+    pub targets: Vec<(String, LevelFilter)>,
+}
+
+impl Default for LogLevels {
+    fn default() -> Self {
+        Self {
+            default: LevelFilter::Info,
+            targets: Vec::new(),
+        }
+    }
+}
+
+impl LogLevels {
+    /// Parse a spec written by `Display`, e.g. "info,wifi=warn"
+    pub fn parse(spec: &str) -> std::result::Result<Self, String> {
+        let mut parts = spec.split(',');
+        let default = parts.next().unwrap_or("").trim();
+        let mut levels = Self {
+            default: parse_level(default).ok_or_else(|| format!("Invalid log level: {}", default))?,
+            targets: Vec::new(),
+        };
+        for part in parts {
+            let (target, level) = part
+                .split_once('=')
+                .ok_or_else(|| format!("Expected <target>=<level>: {}", part))?;
+            let level = parse_level(level.trim()).ok_or_else(|| format!("Invalid log level: {}", level.trim()))?;
+            levels.set(Some(target.trim()), level)?;
+        }
+        Ok(levels)
+    }
+
+    /// Set the default level (target None) or the level of one target
+    pub fn set(&mut self, target: Option<&str>, level: LevelFilter) -> std::result::Result<(), String> {
+        let Some(target) = target else {
+            self.default = level;
+            return Ok(());
+        };
+        validate_target(target)?;
+        if let Some(entry) = self.targets.iter_mut().find(|(name, _)| name == target) {
+            entry.1 = level;
+        } else if self.targets.len() < MAX_TARGET_LEVELS {
+            self.targets.push((target.to_string(), level));
+        } else {
+            return Err(format!("At most {} targets can have their own level", MAX_TARGET_LEVELS));
+        }
+        Ok(())
+    }
+
+    /// Most verbose level of all targets
+    pub fn max(&self) -> LevelFilter {
+        self.targets
+            .iter()
+            .map(|(_, level)| *level)
+            .fold(self.default, Ord::max)
+    }
+}
+
+impl fmt::Display for LogLevels {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", level_name(self.default))?;
+        for (target, level) in &self.targets {
+            write!(f, ",{}={}", target, level_name(*level))?;
+        }
+        Ok(())
+    }
+}
+
+/// Levels currently in effect (None until first changed)
+static LEVELS: Mutex<Option<LogLevels>> = Mutex::new(None);
+
+/// Parse a level name: error, warn, info, debug or trace (any case)
+pub fn parse_level(name: &str) -> Option<LevelFilter> {
+    match name.to_ascii_lowercase().as_str() {
+        "error" => Some(LevelFilter::Error),
+        "warn" => Some(LevelFilter::Warn),
+        "info" => Some(LevelFilter::Info),
+        "debug" => Some(LevelFilter::Debug),
+        "trace" => Some(LevelFilter::Trace),
+        _ => None,
+    }
+}
+
+/// Lower case name of a level, as accepted by `parse_level`
+pub fn level_name(level: LevelFilter) -> &'static str {
+    match level {
+        LevelFilter::Off => "off",
+        LevelFilter::Error => "error",
+        LevelFilter::Warn => "warn",
+        LevelFilter::Info => "info",
+        LevelFilter::Debug => "debug",
+        LevelFilter::Trace => "trace",
+    }
+}
+
+/// Check that a target name can be stored in a spec
+pub fn validate_target(target: &str) -> std::result::Result<(), String> {
+    if target.is_empty() || target.len() > MAX_TARGET_LEN {
+        return Err(format!("Target must be 1-{} characters", MAX_TARGET_LEN));
+    }
+    if target == "*" || target.contains([',', '=']) || target.contains(char::is_whitespace) {
+        return Err(format!("Invalid target: {}", target));
+    }
+    Ok(())
+}
+
+/// Get the levels currently in effect
+pub fn current() -> LogLevels {
+    LEVELS
+        .lock()
+        .ok()
+        .and_then(|levels| levels.clone())
+        .unwrap_or_default()
+}
+
+/// Change the default level (target None) or the level of one target
+///
+/// Returns the levels now in effect.
+pub fn set_level(target: Option<&str>, level: LevelFilter) -> Result<LogLevels> {
+    let mut levels = current();
+    levels.set(target, level).map_err(Error::General)?;
+    apply(&levels)?;
+    Ok(levels)
+}
+
+/// Put a complete set of levels into effect, e.g. the levels saved in flash
+pub fn apply(levels: &LogLevels) -> Result<()> {
+    let mut current = LEVELS
+        .lock()
+        .map_err(|_| Error::General("Failed to lock log levels".to_string()))?;
+
+    set_target_levels(levels)?;
+    // 全局上限取最详细的级别，各目标再由ESP-IDF按标签过滤
+    log::set_max_level(levels.max());
+
+    *current = Some(levels.clone());
+    info!("Log levels set to {}", levels);
+    Ok(())
+}
+
+/// Hand the default and per-target levels to the ESP-IDF logger
+#[cfg(target_os = "espidf")]
+fn set_target_levels(levels: &LogLevels) -> Result<()> {
+    esp_idf_svc::log::set_target_level("*", levels.default)
+        .map_err(|e| Error::esp(e.code(), "Setting the default log level"))?;
+    for (target, level) in &levels.targets {
+        esp_idf_svc::log::set_target_level(target, *level)
+            .map_err(|e| Error::esp(e.code(), "Setting a target log level"))?;
+    }
+    Ok(())
+}
+
+/// The host build has no ESP-IDF logger; only the global maximum applies
+#[cfg(not(target_os = "espidf"))]
+fn set_target_levels(_levels: &LogLevels) -> Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn spec_round_trips() {
+        let levels = LogLevels::parse("info, wifi=WARN ,espc3::uart=trace").unwrap();
+        assert_eq!(levels.default, LevelFilter::Info);
+        assert_eq!(levels.max(), LevelFilter::Trace);
+        assert_eq!(levels.to_string(), "info,wifi=warn,espc3::uart=trace");
+        assert_eq!(LogLevels::parse(&levels.to_string()), Ok(levels));
+
+        assert_eq!(LogLevels::parse("loud"), Err("Invalid log level: loud".to_string()));
+        assert_eq!(LogLevels::parse("info,wifi"), Err("Expected <target>=<level>: wifi".to_string()));
+        assert!(LogLevels::parse("info,a b=warn").is_err());
+    }
+
+    #[test]
+    fn targets_are_overridden_in_place_up_to_the_limit() {
+        let mut levels = LogLevels::default();
+        levels.set(Some("wifi"), LevelFilter::Debug).unwrap();
+        levels.set(Some("wifi"), LevelFilter::Error).unwrap();
+        levels.set(None, LevelFilter::Warn).unwrap();
+        assert_eq!(levels.to_string(), "warn,wifi=error");
+        assert_eq!(levels.max(), LevelFilter::Warn);
+
+        for i in 1..MAX_TARGET_LEVELS {
+            levels.set(Some(&format!("tag{}", i)), LevelFilter::Info).unwrap();
+        }
+        assert!(levels.set(Some("one_too_many"), LevelFilter::Info).is_err());
+        levels.set(Some("wifi"), LevelFilter::Trace).unwrap();
+    }
+}
@@ -0,0 +1,255 @@
+//! Log streaming module
+//!
+//! This module copies the ESP-IDF log output (which includes the Rust `log` records)
+//! to TCP clients that subscribed with AT+LOG=ON. A vprintf hook installed with
+//! `esp_log_set_vprintf` still prints every line to the console and also pushes it
+//! into a bounded ring; the `log_pump` thread moves new lines from the ring to the
+//! subscribed clients, prefixed with "LOG: ".
+//!
+//! The hook never waits: when the ring is busy or full, lines are dropped (oldest
+//! first) and the next batch starts with a note of how many were lost. Delivery goes
+//! through the clients' outbound queues, so the UART broadcast path never blocks on
+//! a log subscriber.
+//!
+//! The host build has no ESP-IDF log output to hook, so subscribers get no lines there.
+
+// 主机构建中没有钩子写入环形缓冲区
+#![cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+
+use log::{error, info};
+use std::collections::VecDeque;
+#[cfg(target_os = "espidf")]
+use std::ffi::{c_char, c_int};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(target_os = "espidf")]
+use std::sync::OnceLock;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::tcp_client_manager::TcpClientManager;
+
+/// Lines kept for the pump; older lines are dropped first
+pub const LOG_RING_LINES: usize = 64;
+
+/// Longest line copied to clients, longer lines are cut
+const MAX_LINE_LEN: usize = 192;
+
+/// Interval in milliseconds at which the pump forwards new lines
+const PUMP_INTERVAL_MS: u64 = 100;
+
+/// Prefix that sets log lines apart from UART data
+const LINE_PREFIX: &str = "LOG: ";
+
+/// Lines captured by the hook and not yet forwarded
+struct LogRing {
+    /// Complete lines, oldest first
+    lines: VecDeque<String>,
+    /// Start of a line whose end has not been printed yet
+    partial: Vec<u8>,
+    /// Lines dropped because the ring was full
+    dropped: usize,
+}
+
+/// Whether the hook copies lines into the ring (only while someone subscribed)
+static CAPTURE: AtomicBool = AtomicBool::new(false);
+
+/// Lines dropped because the ring was locked when the hook ran
+static DROPPED_BUSY: AtomicUsize = AtomicUsize::new(0);
+
+/// Lines shared by the hook and the pump
+static RING: Mutex<LogRing> = Mutex::new(LogRing {
+    lines: VecDeque::new(),
+    partial: Vec::new(),
+    dropped: 0,
+});
+
+/// Client managers whose subscribers the pump serves
+static MANAGERS: Mutex<Vec<Arc<TcpClientManager>>> = Mutex::new(Vec::new());
+
+/// Set once the hook is installed and the pump is running
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+/// vprintf function that was installed before the hook
+#[cfg(target_os = "espidf")]
+static ORIGINAL_VPRINTF: OnceLock<esp_idf_sys::vprintf_like_t> = OnceLock::new();
+
+/// Serve the log subscribers of a client manager
+///
+/// Installs the hook and starts the pump on first use. Call this before
+/// subscribing a client with `TcpClientManager::set_log_stream`.
+pub fn register(manager: &Arc<TcpClientManager>) -> Result<()> {
+    {
+        let mut managers = MANAGERS
+            .lock()
+            .map_err(|_| Error::General("Failed to lock log managers".to_string()))?;
+        if !managers.iter().any(|m| Arc::ptr_eq(m, manager)) {
+            managers.push(Arc::clone(manager));
+        }
+    }
+
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return Ok(());
+    }
+    if let Err(e) = thread::Builder::new()
+        .name("log_pump".into())
+        .stack_size(4096)
+        .spawn(pump)
+    {
+        INSTALLED.store(false, Ordering::SeqCst);
+        return Err(Error::General(format!("Failed to spawn log pump thread: {}", e)));
+    }
+
+    install_hook();
+    info!("Log streaming hook installed");
+    Ok(())
+}
+
+/// Route the ESP-IDF log output through `log_vprintf`
+#[cfg(target_os = "espidf")]
+fn install_hook() {
+    let original = unsafe { esp_idf_sys::esp_log_set_vprintf(Some(log_vprintf)) };
+    let _ = ORIGINAL_VPRINTF.set(original);
+}
+
+#[cfg(not(target_os = "espidf"))]
+fn install_hook() {}
+
+/// vprintf replacement: print as before and copy the line for the subscribers
+#[cfg(target_os = "espidf")]
+unsafe extern "C" fn log_vprintf(format: *const c_char, args: esp_idf_sys::va_list) -> c_int {
+    let written = match ORIGINAL_VPRINTF.get().copied().flatten() {
+        Some(original) => original(format, args),
+        None => esp_idf_sys::vprintf(format, args),
+    };
+
+    if CAPTURE.load(Ordering::Relaxed) {
+        let mut buffer = [0u8; MAX_LINE_LEN];
+        // RISC-V的va_list只是指向参数区的指针，可以再遍历一次
+        let len = esp_idf_sys::vsnprintf(buffer.as_mut_ptr() as *mut c_char, buffer.len(), format, args);
+        if len > 0 {
+            let len = (len as usize).min(buffer.len() - 1);
+            capture(&buffer[..len]);
+        }
+    }
+    written
+}
+
+/// Append printed text to the ring, splitting it into lines
+fn capture(text: &[u8]) {
+    // 钩子可能在任何任务中运行，不能等待锁
+    let Ok(mut ring) = RING.try_lock() else {
+        DROPPED_BUSY.fetch_add(1, Ordering::Relaxed);
+        return;
+    };
+    for byte in text.iter().copied() {
+        if byte == b'\n' {
+            let line = String::from_utf8_lossy(&ring.partial).into_owned();
+            ring.partial.clear();
+            if ring.lines.len() == LOG_RING_LINES {
+                ring.lines.pop_front();
+                ring.dropped += 1;
+            }
+            ring.lines.push_back(line);
+        } else if ring.partial.len() < MAX_LINE_LEN {
+            ring.partial.push(byte);
+        }
+    }
+}
+
+/// Take the captured lines and the number of lines dropped since the last call
+fn take_lines() -> (Vec<String>, usize) {
+    let mut ring = match RING.lock() {
+        Ok(ring) => ring,
+        Err(_) => return (Vec::new(), 0),
+    };
+    let dropped = std::mem::take(&mut ring.dropped) + DROPPED_BUSY.swap(0, Ordering::Relaxed);
+    (ring.lines.drain(..).collect(), dropped)
+}
+
+/// Forward new lines to the subscribers until the device restarts
+fn pump() {
+    loop {
+        thread::sleep(Duration::from_millis(PUMP_INTERVAL_MS));
+
+        let managers = match MANAGERS.lock() {
+            Ok(managers) => managers.clone(),
+            Err(_) => {
+                error!("Failed to lock log managers");
+                continue;
+            }
+        };
+        let subscribed = managers.iter().any(|manager| manager.has_log_clients());
+        // 没有订阅者时停止复制，丢弃剩余的行
+        CAPTURE.store(subscribed, Ordering::Relaxed);
+        if !subscribed {
+            let _ = take_lines();
+            continue;
+        }
+
+        let (lines, dropped) = take_lines();
+        let mut text = String::new();
+        if dropped > 0 {
+            text.push_str(&format!("{}[{} lines dropped]\r\n", LINE_PREFIX, dropped));
+        }
+        for line in lines {
+            text.push_str(LINE_PREFIX);
+            text.push_str(&strip_escapes(&line));
+            text.push_str("\r\n");
+        }
+        for manager in &managers {
+            manager.send_log(&text);
+        }
+    }
+}
+
+/// Remove ANSI color sequences and carriage returns from a log line
+fn strip_escapes(line: &str) -> String {
+    let mut out = String::with_capacity(line.len());
+    let mut chars = line.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '\x1b' => {
+                // 跳过"ESC [ ... m"
+                for c in chars.by_ref() {
+                    if c.is_ascii_alphabetic() {
+                        break;
+                    }
+                }
+            }
+            '\r' => {}
+            c => out.push(c),
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn printed_text_is_split_into_lines_and_the_oldest_dropped() {
+        capture(b"I (120) wifi: sta ");
+        capture(b"connected\nI (130) tcp: listening\n");
+        let (lines, dropped) = take_lines();
+        assert_eq!(lines, ["I (120) wifi: sta connected", "I (130) tcp: listening"]);
+        assert_eq!(dropped, 0);
+
+        for i in 0..LOG_RING_LINES + 3 {
+            capture(format!("line {}\n", i).as_bytes());
+        }
+        let (lines, dropped) = take_lines();
+        assert_eq!(lines.len(), LOG_RING_LINES);
+        assert_eq!(lines[0], "line 3");
+        assert_eq!(dropped, 3);
+        assert_eq!(take_lines(), (Vec::new(), 0));
+    }
+
+    #[test]
+    fn colors_and_carriage_returns_are_removed() {
+        assert_eq!(strip_escapes("\x1b[0;32mI (42) main: ready\x1b[0m\r"), "I (42) main: ready");
+        assert_eq!(strip_escapes("plain"), "plain");
+    }
+}
@@ -1,29 +1,42 @@
+#[cfg(target_os = "espidf")]
 use esp_idf_sys as _; // If using the `binstart` feature of `esp-idf-sys`, always keep this module imported
-use log::{info, error};
+#[cfg(target_os = "espidf")]
+use log::{info, error, warn};
+#[cfg(target_os = "espidf")]
 use std::thread;
-use std::sync::Arc;
+#[cfg(target_os = "espidf")]
 use std::time::Duration;
+#[cfg(target_os = "espidf")]
 use esp_idf_hal::peripherals::Peripherals;
 
 // Import our library modules
+#[cfg(target_os = "espidf")]
 use espc3::{
+    app::{App, AppPeripherals},
     config::{AppConfig, create_config},
+    diagnostics,
     error::Result,
-    tcp_client_manager::TcpClientManager,
-    tcp_server::TcpServer,
-    uart::UartManager,
-    wifi::WiFiManager,
+    time,
+    version::VersionInfo,
+    watchdog,
 };
 
 // 不再需要导入旧的兼容性函数
 
+#[cfg(target_os = "espidf")]
 fn main() -> anyhow::Result<()> {
     // Initialize the ESP-IDF system
     esp_idf_sys::link_patches();
 
+    // Record the boot instant used for uptime reporting
+    time::boot_instant();
+
     // Configure logging
     esp_idf_svc::log::EspLogger::initialize_default();
     info!("ESP32 starting up...");
+    for line in VersionInfo::current().to_string().split("\r\n") {
+        info!("{}", line);
+    }
 
     // Create application configuration
     let config = create_config();
@@ -47,87 +60,91 @@ fn main() -> anyhow::Result<()> {
 }
 
 /// Run the application using the new object-oriented API
+#[cfg(target_os = "espidf")]
 fn run_with_new_api(peripherals: Peripherals, config: AppConfig) -> Result<()> {
     // 保存配置值以便后续使用
-    let tcp_port = config.tcp_server.port;
-    let uart_baudrate = config.uart.baudrate;
-    // Initialize WiFi
-    let mut wifi_manager = WiFiManager::new(config.wifi)?;
-    info!("WiFi manager created");
-
-    // Configure and start WiFi
-    wifi_manager.configure_mixed_mode()?;
-    wifi_manager.start()?;
-
-    // WiFi已经在start方法中等待初始化完成
-    info!("WiFi initialization complete");
-
-    // Create shared TCP client manager
-    let client_manager = Arc::new(TcpClientManager::new());
-    info!("TCP client manager created");
-
-    // Initialize UART
-    let uart_manager = Arc::new(UartManager::new(
-        peripherals.uart1,
-        peripherals.pins.gpio21,
-        peripherals.pins.gpio20,
-        config.uart,
-    )?);
-    info!("UART manager created");
-
-    // Start UART forwarding service
-    UartManager::start_forwarding(Arc::clone(&uart_manager), Arc::clone(&client_manager))?;
-    info!("UART forwarding service started");
-
-    // 创建并运行TCP服务器
-    info!("Starting TCP server on port {}...", tcp_port);
-    let tcp_server = Arc::new(TcpServer::new(
-        config.tcp_server,
-        Arc::clone(&client_manager),
-        Arc::clone(&uart_manager),
-    ));
-
-    // 使用命名线程和更大的栈空间
-    let server_arc = Arc::clone(&tcp_server);
-    let _server_thread = thread::Builder::new()
-        .name("tcp_server".into())
-        .stack_size(8192) // 增加栈大小以防止栈溢出
-        .spawn(move || {
-            info!("TCP server thread started");
-            if let Err(e) = server_arc.run() {
-                error!("TCP server error: {:?}", e);
-            }
-        })
-        .expect("Failed to spawn TCP server thread");
+    let stats_log_interval = Duration::from_secs(config.stats_log_interval_secs);
+    let memory_log_interval = Duration::from_secs(config.memory.log_interval_secs);
 
-    // 给TCP服务器时间启动
-    thread::sleep(Duration::from_millis(100));
-    info!("TCP server started and ready for connections");
-
-    info!("==================================================");
-    info!("ESP32 is running with TCP server and UART forwarding service");
-    info!("TCP Server Port: {}", tcp_port);
-    info!("UART Baudrate: {} (can be changed via TCP commands)", uart_baudrate);
-    info!("Use AT+HELP command to see available commands");
-    info!("==================================================");
+    let mut app = App::new(AppPeripherals::from(peripherals), config)?;
+    app.start()?;
+    let bridges = app.bridges();
+    let client_manager = app.client_manager();
+    let wifi_manager = app.wifi_manager();
 
     // 保持程序运行并定期检查状态
     let mut last_client_count = 0;
+    let mut stats_stopwatch = time::Stopwatch::start();
+    let mut memory_stopwatch = time::Stopwatch::start();
+    let _tracker = diagnostics::track_thread("main");
+    diagnostics::log_summary();
     loop {
         thread::sleep(Duration::from_secs(5));
 
-        // 检查客户端连接状态
-        if let Ok(current_client_count) = client_manager.client_count() {
-            if current_client_count != last_client_count {
-                if current_client_count > 0 {
-                    info!("Currently {} TCP client(s) connected", current_client_count);
-                } else {
+        // 在看门狗复位之前记录卡住的线程
+        watchdog::check();
+
+        // 内存不足时断开积压最多的客户端，释放其发送队列
+        for bridge in bridges {
+            diagnostics::protect(&bridge.client_manager);
+        }
+        if !memory_log_interval.is_zero() && memory_stopwatch.has_elapsed(memory_log_interval) {
+            memory_stopwatch.restart();
+            diagnostics::log_summary();
+        }
+
+        // 检查客户端连接状态，数量变化时列出所有客户端
+        if let Ok(clients) = client_manager.list_clients() {
+            if clients.len() != last_client_count {
+                if clients.is_empty() {
                     info!("No TCP clients connected. Waiting for connections...");
+                } else {
+                    info!("Currently {} TCP client(s) connected", clients.len());
+                    for client in &clients {
+                        info!("  {}", client);
+                    }
+                }
+                last_client_count = clients.len();
+            }
+        }
+
+        // 定期输出流量统计
+        if !stats_log_interval.is_zero() && stats_stopwatch.has_elapsed(stats_log_interval) {
+            stats_stopwatch.restart();
+            for bridge in bridges {
+                let uart_stats = bridge.uart_manager.stats();
+                let client_stats = bridge.client_manager.stats();
+                // 多个串口桥时分别标出桥的名称
+                let label = if bridges.len() > 1 { format!(" ({})", bridge.name) } else { String::new() };
+                info!(
+                    "Traffic{}: TCP->UART {} bytes, UART->TCP {} bytes, broadcast {} bytes, \
+                    {} broadcast errors, {} clients total, {} evicted",
+                    label,
+                    uart_stats.bytes_sent_to_uart,
+                    uart_stats.bytes_received_from_uart,
+                    client_stats.bytes_broadcast,
+                    client_stats.broadcast_errors,
+                    client_stats.clients_total,
+                    client_stats.clients_evicted
+                );
+                let errors = bridge.uart_manager.get_error_counters();
+                if !errors.is_zero() {
+                    warn!("UART errors{}: {}", label, errors);
                 }
-                last_client_count = current_client_count;
+            }
+            match wifi_manager.lock().map(|wifi| wifi.connected_stations()) {
+                Ok(Ok(stations)) => info!("WiFi: {} station(s) connected to the AP", stations.len()),
+                Ok(Err(e)) => warn!("Failed to list WiFi stations: {}", e),
+                Err(_) => warn!("Failed to lock WiFi manager"),
             }
         }
     }
 }
 
+/// The firmware needs ESP-IDF; the host build only exists for the library tests
+#[cfg(not(target_os = "espidf"))]
+fn main() {
+    eprintln!("espc3 runs on the ESP32-C3 only, build it for riscv32imc-esp-espidf");
+}
+
 // 旧的兼容性函数已删除
@@ -0,0 +1,150 @@
+//! mDNS module
+//!
+//! This module advertises the TCP bridge on the local network, so clients can find
+//! the device as "<hostname>.local" and discover the `_uartbridge._tcp` service
+//! instead of looking up its IP address in the serial log.
+//!
+//! The host build has no mDNS responder, so no advertiser can be started there.
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::mdns::EspMdns;
+#[cfg(target_os = "espidf")]
+use log::warn;
+use log::info;
+
+use crate::error::{Error, Result};
+
+/// Service type advertised for the TCP data port
+#[cfg(target_os = "espidf")]
+const SERVICE_TYPE: &str = "_uartbridge";
+
+/// Protocol of the advertised service
+#[cfg(target_os = "espidf")]
+const SERVICE_PROTO: &str = "_tcp";
+
+/// Instance name shown by service browsers
+#[cfg(target_os = "espidf")]
+const INSTANCE_NAME: &str = "ESP32 UART Bridge";
+
+/// Check whether `name` is a valid host name label
+///
+/// 1 to 32 ASCII letters, digits or '-', not starting or ending with '-'.
+pub fn is_valid_hostname(name: &str) -> bool {
+    !name.is_empty()
+        && name.len() <= 32
+        && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+        && !name.starts_with('-')
+        && !name.ends_with('-')
+}
+
+/// mDNS advertiser
+///
+/// Publishes the host name and the `_uartbridge._tcp` service with the data port
+/// in its SRV record and TXT record.
+pub struct MdnsAdvertiser {
+    /// The ESP mDNS responder
+    #[cfg(target_os = "espidf")]
+    mdns: EspMdns,
+    /// Advertised host name, without ".local"
+    hostname: String,
+    /// Advertised data port
+    port: u16,
+}
+
+impl MdnsAdvertiser {
+    /// Start advertising `hostname` and the service on `port`
+    #[cfg(target_os = "espidf")]
+    pub fn new(hostname: &str, port: u16) -> Result<Self> {
+        let mut mdns = EspMdns::take().map_err(|e| Error::wifi_caused("Failed to take mDNS", e))?;
+        mdns.set_hostname(hostname)
+            .map_err(|e| Error::wifi_caused("Failed to set mDNS host name", e))?;
+        mdns.set_instance_name(INSTANCE_NAME)
+            .map_err(|e| Error::wifi_caused("Failed to set mDNS instance name", e))?;
+
+        let mut advertiser = Self {
+            mdns,
+            hostname: hostname.to_string(),
+            port,
+        };
+        advertiser.add_service()?;
+        info!("mDNS: advertising {}.local, {}.{} on port {}", hostname, SERVICE_TYPE, SERVICE_PROTO, port);
+        Ok(advertiser)
+    }
+
+    /// Start advertising `hostname` and the service on `port`
+    #[cfg(not(target_os = "espidf"))]
+    pub fn new(_hostname: &str, _port: u16) -> Result<Self> {
+        Err(Error::wifi("No mDNS responder in the host build"))
+    }
+
+    /// Get the advertised host name, without ".local"
+    pub fn hostname(&self) -> &str {
+        &self.hostname
+    }
+
+    /// Get the advertised data port
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Advertise a new host name
+    pub fn set_hostname(&mut self, hostname: &str) -> Result<()> {
+        #[cfg(target_os = "espidf")]
+        self.mdns
+            .set_hostname(hostname)
+            .map_err(|e| Error::wifi_caused("Failed to set mDNS host name", e))?;
+        self.hostname = hostname.to_string();
+        info!("mDNS: host name changed to {}.local", hostname);
+        Ok(())
+    }
+
+    /// Advertise the service on a new port
+    pub fn set_port(&mut self, port: u16) -> Result<()> {
+        if port == self.port {
+            return Ok(());
+        }
+        // 删除后重新添加，SRV和TXT记录一起更新
+        #[cfg(target_os = "espidf")]
+        if let Err(e) = self.mdns.remove_service(SERVICE_TYPE, SERVICE_PROTO) {
+            warn!("Failed to remove mDNS service: {}", e);
+        }
+        self.port = port;
+        self.add_service()?;
+        info!("mDNS: service port changed to {}", port);
+        Ok(())
+    }
+
+    #[cfg(target_os = "espidf")]
+    fn add_service(&mut self) -> Result<()> {
+        let port = self.port.to_string();
+        self.mdns
+            .add_service(
+                Some(INSTANCE_NAME),
+                SERVICE_TYPE,
+                SERVICE_PROTO,
+                self.port,
+                &[("port", port.as_str())],
+            )
+            .map_err(|e| Error::wifi_caused("Failed to add mDNS service", e))
+    }
+
+    #[cfg(not(target_os = "espidf"))]
+    fn add_service(&mut self) -> Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn host_names_are_single_dns_labels() {
+        for name in ["esp32-uart", "bridge2", "A", &"x".repeat(32)] {
+            assert!(is_valid_hostname(name), "{}", name);
+        }
+        for name in ["", "-bridge", "bridge-", "esp32.local", "esp32_uart", "brücke", &"x".repeat(33)] {
+            assert!(!is_valid_hostname(name), "{}", name);
+        }
+    }
+}
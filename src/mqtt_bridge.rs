@@ -0,0 +1,474 @@
+//! MQTT bridge module
+//!
+//! This module connects the device to an MQTT broker, for deployments where no one
+//! opens a TCP session. UART data is published to "<prefix>/rx", one message per
+//! broadcast (so per frame when UART framing is enabled), and messages received on
+//! "<prefix>/tx" are written to UART. It can run alongside the TCP server.
+//!
+//! While connected, the bridge is registered with the data port's
+//! `TcpClientManager` as a virtual client at `MQTT_CLIENT_ADDR`, so it is listed
+//! and counted in the statistics like a TCP client. A lost connection is
+//! re-established with a doubling delay between failed attempts.
+//!
+//! The host build has no MQTT client; there every connection attempt fails.
+
+use log::{debug, error, info, trace, warn};
+use std::fmt;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::MqttConfig;
+use crate::error::{Error, Result};
+use crate::tcp_client_manager::{TcpClientManager, VirtualClient};
+use crate::time::{self, Stopwatch};
+use crate::uart::UartManager;
+use client::{Client, Connection, Event};
+
+/// Address under which the bridge is listed as a client
+pub const MQTT_CLIENT_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 1883));
+
+/// Interval in milliseconds at which waits check for a stop request or a lost connection
+const POLL_MS: u64 = 100;
+
+/// State of the broker connection
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MqttState {
+    /// Waiting for the broker to accept the connection
+    Connecting,
+    /// Connected and subscribed
+    Connected,
+    /// The last attempt failed or the connection dropped; retrying after the delay
+    Waiting(Duration),
+    /// The bridge was stopped
+    Stopped,
+}
+
+impl fmt::Display for MqttState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MqttState::Connecting => write!(f, "connecting"),
+            MqttState::Connected => write!(f, "connected"),
+            MqttState::Waiting(delay) => {
+                write!(f, "disconnected, retrying in {}", time::format_duration(*delay))
+            }
+            MqttState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Publishing side of the bridge, registered as a virtual client
+struct Uplink {
+    /// Client of the current connection (None between connections)
+    client: Mutex<Option<Client>>,
+    /// Topic UART data is published to
+    topic: String,
+}
+
+impl VirtualClient for Uplink {
+    fn deliver(&self, data: &[u8]) -> bool {
+        // 连接建立或关闭期间不等待，直接丢弃
+        let Ok(mut client) = self.client.try_lock() else {
+            return false;
+        };
+        let Some(client) = client.as_mut() else {
+            return false;
+        };
+        // 只放入发送队列，由MQTT任务发送，不阻塞UART转发
+        match client.enqueue(&self.topic, data) {
+            Ok(_) => {
+                trace!("UART -> MQTT: {} bytes", data.len());
+                true
+            }
+            Err(e) => {
+                debug!("Failed to queue MQTT message: {}", e);
+                false
+            }
+        }
+    }
+}
+
+/// MQTT bridge
+///
+/// Keeps one connection to the configured broker open and forwards data between it
+/// and UART.
+pub struct MqttBridge {
+    /// MQTT bridge configuration
+    config: MqttConfig,
+    /// Client identifier sent to the broker
+    client_id: String,
+    /// Topic whose messages are written to UART
+    tx_topic: String,
+    /// Client manager of the data port, so UART broadcasts reach the broker
+    client_manager: Arc<TcpClientManager>,
+    /// UART manager receiving the messages from the broker
+    uart_manager: Arc<UartManager>,
+    /// Publishing side, shared with the client manager while connected
+    uplink: Arc<Uplink>,
+    /// Whether the broker connection is up, maintained by the event thread
+    connected: AtomicBool,
+    /// Current state of the connection, reported by AT+STATUS
+    state: Mutex<MqttState>,
+    /// Set by `stop` to close the connection and end `run`
+    shutdown: AtomicBool,
+}
+
+impl MqttBridge {
+    /// Create a new bridge with the given configuration and managers
+    ///
+    /// `hostname` is the client identifier if none is configured.
+    pub fn new(
+        config: MqttConfig,
+        hostname: &str,
+        client_manager: Arc<TcpClientManager>,
+        uart_manager: Arc<UartManager>,
+    ) -> Self {
+        let prefix = config.topic_prefix.trim_end_matches('/');
+        let uplink = Arc::new(Uplink {
+            client: Mutex::new(None),
+            topic: format!("{}/rx", prefix),
+        });
+        Self {
+            client_id: config.client_id.unwrap_or(hostname).to_string(),
+            tx_topic: format!("{}/tx", prefix),
+            config,
+            client_manager,
+            uart_manager,
+            uplink,
+            connected: AtomicBool::new(false),
+            state: Mutex::new(MqttState::Connecting),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Broker URL as configured
+    pub fn broker(&self) -> &str {
+        self.config.broker_url
+    }
+
+    /// Get the current state of the connection
+    pub fn state(&self) -> MqttState {
+        match self.state.lock() {
+            Ok(state) => *state,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    fn set_state(&self, new_state: MqttState) {
+        match self.state.lock() {
+            Ok(mut state) => *state = new_state,
+            Err(poisoned) => *poisoned.into_inner() = new_state,
+        }
+    }
+
+    /// Keep the connection to the broker up until `stop` is called
+    ///
+    /// After a failed attempt or a dropped connection the delay starts at the
+    /// reconnect interval and doubles up to the maximum backoff. A connection that
+    /// was up resets it.
+    pub fn run(self: &Arc<Self>) -> Result<()> {
+        let initial_delay = Duration::from_millis(self.config.reconnect_interval_ms);
+        let max_delay = Duration::from_millis(self.config.max_backoff_ms).max(initial_delay);
+        let mut delay = initial_delay;
+        info!("MQTT bridge started for {}", self.broker());
+
+        while !self.is_stopped() {
+            self.set_state(MqttState::Connecting);
+            match self.session() {
+                Ok(()) => delay = initial_delay,
+                Err(e) => warn!("MQTT connection to {} failed: {}", self.broker(), e),
+            }
+            if self.is_stopped() {
+                break;
+            }
+
+            self.set_state(MqttState::Waiting(delay));
+            info!("Reconnecting to {} in {}", self.broker(), time::format_duration(delay));
+            // 分段等待，以便及时响应停止请求
+            let waited = Stopwatch::start();
+            while !waited.has_elapsed(delay) && !self.is_stopped() {
+                thread::sleep(Duration::from_millis(POLL_MS));
+            }
+            delay = (delay * 2).min(max_delay);
+        }
+
+        self.set_state(MqttState::Stopped);
+        info!("MQTT bridge stopped");
+        Ok(())
+    }
+
+    /// Ask `run` to close the connection and return
+    pub fn stop(&self) {
+        info!("Stopping MQTT bridge");
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether `stop` has been called
+    pub fn is_stopped(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Connect, forward data until the connection drops or the bridge stops, then
+    /// tear the client down
+    ///
+    /// Returns Ok if the connection was up, so the backoff restarts.
+    fn session(self: &Arc<Self>) -> Result<()> {
+        let (client, connection) = Client::connect(&self.config, &self.client_id)?;
+        self.connected.store(false, Ordering::SeqCst);
+
+        // 事件必须在单独的线程中处理，否则客户端调用会阻塞
+        let bridge = Arc::clone(self);
+        let events = thread::Builder::new()
+            .name("mqtt_events".into())
+            .stack_size(4096)
+            .spawn(move || bridge.handle_events(connection))
+            .map_err(|e| Error::mqtt_caused("Failed to spawn MQTT event thread", e))?;
+        self.set_client(Some(client));
+
+        // serve()返回前已注销虚拟客户端，再销毁客户端；销毁后事件线程随之结束
+        let result = self.serve();
+        self.set_client(None);
+        self.connected.store(false, Ordering::SeqCst);
+        if events.join().is_err() {
+            error!("MQTT event thread panicked");
+        }
+        result
+    }
+
+    /// Wait for the connection, subscribe, and keep the bridge registered until the
+    /// connection drops or the bridge stops
+    fn serve(&self) -> Result<()> {
+        let timeout = Duration::from_millis(self.config.connect_timeout_ms);
+        let waited = Stopwatch::start();
+        while !self.connected.load(Ordering::SeqCst) {
+            if self.is_stopped() {
+                return Ok(());
+            }
+            if waited.has_elapsed(timeout) {
+                return Err(Error::mqtt(format!(
+                    "Broker did not accept the connection within {}",
+                    time::format_duration(timeout)
+                )));
+            }
+            thread::sleep(Duration::from_millis(POLL_MS));
+        }
+
+        let subscribed = match self.uplink.client.lock() {
+            Ok(mut client) => match client.as_mut() {
+                Some(client) => client
+                    .subscribe(&self.tx_topic)
+                    .map_err(|e| Error::mqtt_caused(format!("Failed to subscribe to {}", self.tx_topic), e)),
+                None => Err(Error::mqtt("MQTT client is gone")),
+            },
+            Err(_) => Err(Error::mqtt("Failed to lock MQTT client")),
+        };
+        subscribed?;
+
+        let conn_id = self
+            .client_manager
+            .add_virtual_client(MQTT_CLIENT_ADDR, Arc::clone(&self.uplink) as Arc<dyn VirtualClient>)?;
+        self.set_state(MqttState::Connected);
+        info!(
+            "MQTT bridge connected to {}, publishing to {}, subscribed to {}",
+            self.broker(),
+            self.uplink.topic,
+            self.tx_topic
+        );
+
+        let connected = Stopwatch::start();
+        while self.connected.load(Ordering::SeqCst) && !self.is_stopped() {
+            thread::sleep(Duration::from_millis(POLL_MS));
+        }
+        if let Err(e) = self.client_manager.remove_client(&MQTT_CLIENT_ADDR, conn_id) {
+            error!("Failed to remove MQTT client: {}", e);
+        }
+        info!(
+            "MQTT connection to {} closed after {}",
+            self.broker(),
+            time::format_duration(connected.elapsed())
+        );
+        Ok(())
+    }
+
+    /// Replace the client of the current connection
+    fn set_client(&self, new_client: Option<Client>) {
+        // 在锁外销毁旧客户端，销毁会等待MQTT任务结束
+        let _old_client = match self.uplink.client.lock() {
+            Ok(mut client) => std::mem::replace(&mut *client, new_client),
+            Err(poisoned) => std::mem::replace(&mut *poisoned.into_inner(), new_client),
+        };
+    }
+
+    /// Process the events of one connection until its client is destroyed
+    fn handle_events(&self, connection: Connection) {
+        connection.for_each(|event| match event {
+            Event::Connected => {
+                debug!("MQTT broker {} accepted the connection", self.broker());
+                self.connected.store(true, Ordering::SeqCst);
+            }
+            Event::Disconnected => {
+                let was_connected = self.connected.swap(false, Ordering::SeqCst);
+                if was_connected {
+                    warn!("MQTT broker {} disconnected", self.broker());
+                }
+            }
+            // 大消息分片到达，只有第一片带主题
+            Event::Received { topic: Some(topic), .. } if topic != self.tx_topic => {}
+            Event::Received { data, .. } => self.forward_to_uart(data),
+            Event::Error(e) => debug!("MQTT error: {}", e),
+        });
+        debug!("MQTT event thread for {} finished", self.broker());
+    }
+
+    /// Write a message received from the broker to UART
+    fn forward_to_uart(&self, data: &[u8]) {
+        if data.is_empty() {
+            return;
+        }
+        self.client_manager.record_received(&MQTT_CLIENT_ADDR, data.len());
+        trace!("MQTT -> UART: {} bytes", data.len());
+        // 其他客户端独占UART时丢弃代理服务器的数据
+        if let Some(holder) = self.client_manager.locked_by_other(&MQTT_CLIENT_ADDR) {
+            debug!("Dropping MQTT message, UART locked by {}", holder);
+        } else if !self.uart_manager.is_bridge_enabled() {
+            debug!("Dropping MQTT message, bridge paused");
+        } else if let Err(e) = self.uart_manager.send_packet(data) {
+            error!("Error sending data to UART: {}", e);
+        }
+    }
+}
+
+/// The ESP-IDF MQTT client, reduced to what the bridge uses
+#[cfg(target_os = "espidf")]
+mod client {
+    use esp_idf_svc::mqtt::client::{
+        EspMqttClient, EspMqttConnection, EventPayload, MqttClientConfiguration, QoS,
+    };
+
+    use crate::config::MqttConfig;
+    use crate::error::{Error, Result};
+
+    /// Event of a connection, as far as the bridge handles it
+    pub enum Event<'a> {
+        Connected,
+        Disconnected,
+        /// A message or a fragment of one; only the first fragment has the topic
+        Received { topic: Option<&'a str>, data: &'a [u8] },
+        Error(String),
+    }
+
+    /// Client of one connection; dropping it closes the connection
+    pub struct Client {
+        inner: EspMqttClient<'static>,
+        qos: QoS,
+    }
+
+    impl Client {
+        /// Start connecting to the configured broker
+        pub fn connect(config: &MqttConfig, client_id: &str) -> Result<(Client, Connection)> {
+            let configuration = MqttClientConfiguration {
+                client_id: Some(client_id),
+                username: config.username,
+                password: config.password,
+                ..Default::default()
+            };
+            let (inner, connection) = EspMqttClient::new(config.broker_url, &configuration)
+                .map_err(|e| Error::mqtt_caused("Failed to create MQTT client", e))?;
+            let qos = if config.qos1 { QoS::AtLeastOnce } else { QoS::AtMostOnce };
+            Ok((Client { inner, qos }, Connection(connection)))
+        }
+
+        /// Subscribe to `topic`
+        pub fn subscribe(&mut self, topic: &str) -> std::result::Result<(), impl std::error::Error> {
+            self.inner.subscribe(topic, self.qos).map(|_| ())
+        }
+
+        /// Queue a message for the MQTT task without waiting for it to be sent
+        pub fn enqueue(&mut self, topic: &str, data: &[u8]) -> std::result::Result<(), impl std::fmt::Display> {
+            self.inner.enqueue(topic, self.qos, false, data).map(|_| ())
+        }
+    }
+
+    /// Event side of one connection
+    pub struct Connection(EspMqttConnection);
+
+    impl Connection {
+        /// Pass every event to `handle` until the client is destroyed
+        pub fn for_each(mut self, mut handle: impl FnMut(Event<'_>)) {
+            while let Ok(event) = self.0.next() {
+                match event.payload() {
+                    EventPayload::Connected(_) => handle(Event::Connected),
+                    EventPayload::Disconnected => handle(Event::Disconnected),
+                    EventPayload::Received { topic, data, .. } => handle(Event::Received { topic, data }),
+                    EventPayload::Error(e) => handle(Event::Error(format!("{:?}", e))),
+                    _ => {}
+                }
+            }
+        }
+    }
+}
+
+/// Stand-in for the MQTT client in the host build, which has none
+#[cfg(not(target_os = "espidf"))]
+mod client {
+    use std::convert::Infallible;
+
+    use crate::config::MqttConfig;
+    use crate::error::{Error, Result};
+
+    /// Never received, since no `Connection` is ever constructed
+    #[allow(dead_code)]
+    pub enum Event<'a> {
+        Connected,
+        Disconnected,
+        Received { topic: Option<&'a str>, data: &'a [u8] },
+        Error(String),
+    }
+
+    /// Never constructed, `connect` always fails
+    pub enum Client {}
+
+    impl Client {
+        pub fn connect(_config: &MqttConfig, _client_id: &str) -> Result<(Client, Connection)> {
+            Err(Error::mqtt("No MQTT client in the host build"))
+        }
+
+        pub fn subscribe(&mut self, _topic: &str) -> std::result::Result<(), Infallible> {
+            match *self {}
+        }
+
+        pub fn enqueue(&mut self, _topic: &str, _data: &[u8]) -> std::result::Result<(), Infallible> {
+            match *self {}
+        }
+    }
+
+    pub enum Connection {}
+
+    impl Connection {
+        pub fn for_each(self, _handle: impl FnMut(Event<'_>)) {
+            match self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn uart_data_is_dropped_between_connections() {
+        let uplink = Uplink { client: Mutex::new(None), topic: "bridge/rx".to_string() };
+        assert!(!uplink.deliver(b"reading 42"));
+    }
+
+    #[test]
+    fn connection_state_is_reported_readably() {
+        assert_eq!(MqttState::Connected.to_string(), "connected");
+        assert_eq!(
+            MqttState::Waiting(Duration::from_secs(4)).to_string(),
+            "disconnected, retrying in 0d 00h 00m 04s"
+        );
+    }
+}
@@ -0,0 +1,398 @@
+//! Firmware update module
+//!
+//! This module writes a firmware image received over a TCP connection into the next
+//! OTA partition (AT+OTA=<size>,<crc32>). The image is streamed straight to flash,
+//! its CRC32 (IEEE, as printed by `crc32` or zlib) is checked against the announced
+//! value, and only then is the new partition selected for boot. Any failure aborts
+//! the update, so the running firmware stays the boot partition.
+//!
+//! The uploader sends the image after the "OK: Ready" reply and receives
+//! "+OTA: <received>/<size>" every `ACK_INTERVAL_BYTES`, so it can tell a stalled
+//! transfer from a slow one.
+//!
+//! The host build has no OTA partitions, so AT+OTA is refused there.
+
+#[cfg(target_os = "espidf")]
+use esp_idf_svc::ota::EspOta;
+use log::debug;
+#[cfg(target_os = "espidf")]
+use log::{info, warn};
+use std::fmt;
+use std::io::ErrorKind;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+use crate::tcp_client_manager::ClientStream;
+use crate::time::Stopwatch;
+#[cfg(target_os = "espidf")]
+use crate::version::FIRMWARE_VERSION;
+
+/// Bytes between two progress acknowledgements
+pub const ACK_INTERVAL_BYTES: usize = 4096;
+
+/// Time without data after which an upload is aborted
+pub const STALL_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Bytes read from the connection and written to flash at a time
+#[cfg(any(target_os = "espidf", test))]
+const CHUNK_SIZE: usize = 1024;
+
+/// Quiet time that ends the discarding of the rest of an aborted upload
+const DRAIN_QUIET: Duration = Duration::from_millis(300);
+
+/// Image announced with AT+OTA=<size>,<crc32>
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OtaRequest {
+    /// Image size in bytes
+    pub size: usize,
+    /// CRC32 (IEEE) of the whole image
+    pub crc32: u32,
+}
+
+impl OtaRequest {
+    /// Parse "<size>,<crc32>", with the CRC in hex and an optional 0x prefix
+    pub fn parse(args: &str) -> std::result::Result<Self, String> {
+        let (size, crc) = args
+            .split_once(',')
+            .ok_or_else(|| "Expected AT+OTA=<size>,<crc32>".to_string())?;
+        let size = match size.trim().parse::<usize>() {
+            Ok(size) if size > 0 => size,
+            _ => return Err(format!("Invalid image size: {}", size.trim())),
+        };
+        let crc = crc.trim();
+        let hex = crc
+            .strip_prefix("0x")
+            .or_else(|| crc.strip_prefix("0X"))
+            .unwrap_or(crc);
+        let crc32 = u32::from_str_radix(hex, 16).map_err(|_| format!("Invalid CRC32: {}", crc))?;
+        Ok(Self { size, crc32 })
+    }
+}
+
+/// Running and boot partitions, for AT+OTA?
+#[derive(Debug, Clone)]
+pub struct OtaStatus {
+    /// Label of the partition the firmware runs from
+    pub running: String,
+    /// Version of the running application
+    pub version: String,
+    /// Label of the partition used on the next boot
+    pub boot: String,
+}
+
+impl OtaStatus {
+    /// Read the partition state from the bootloader data
+    #[cfg(target_os = "espidf")]
+    pub fn current() -> Result<Self> {
+        let ota = EspOta::new().map_err(|e| Error::ota_caused("Failed to open OTA", e))?;
+        let running = ota
+            .get_running_slot()
+            .map_err(|e| Error::ota_caused("Failed to read running partition", e))?;
+        let boot = ota
+            .get_boot_slot()
+            .map_err(|e| Error::ota_caused("Failed to read boot partition", e))?;
+        let version = match running.firmware {
+            Some(firmware) => firmware.version.to_string(),
+            None => FIRMWARE_VERSION.to_string(),
+        };
+        Ok(Self {
+            running: running.label.to_string(),
+            version,
+            boot: boot.label.to_string(),
+        })
+    }
+
+    /// Read the partition state from the bootloader data
+    #[cfg(not(target_os = "espidf"))]
+    pub fn current() -> Result<Self> {
+        Err(Error::ota("No OTA partitions in the host build"))
+    }
+}
+
+impl fmt::Display for OtaStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Partition: {}, version {}", self.running, self.version)?;
+        if self.boot != self.running {
+            write!(f, " (next boot: {})", self.boot)?;
+        }
+        Ok(())
+    }
+}
+
+/// Size of the partition the next update is written to
+#[cfg(target_os = "espidf")]
+pub fn update_partition_size() -> Option<usize> {
+    let partition = unsafe { esp_idf_sys::esp_ota_get_next_update_partition(std::ptr::null()) };
+    if partition.is_null() {
+        return None;
+    }
+    Some(unsafe { (*partition).size } as usize)
+}
+
+/// Size of the partition the next update is written to
+#[cfg(not(target_os = "espidf"))]
+pub fn update_partition_size() -> Option<usize> {
+    None
+}
+
+/// Receive an image from `stream` and select it for the next boot
+///
+/// The stream must be in blocking mode with `STALL_TIMEOUT` as read timeout. On
+/// error the update is aborted and the rest of the upload is discarded, so the
+/// connection can go back to command mode.
+pub fn receive(stream: &mut dyn ClientStream, request: &OtaRequest) -> Result<()> {
+    let result = write_image(stream, request);
+    if result.is_err() {
+        drain(stream);
+    }
+    result
+}
+
+/// Stream the image into the update partition and verify it
+#[cfg(target_os = "espidf")]
+fn write_image(stream: &mut dyn ClientStream, request: &OtaRequest) -> Result<()> {
+    let mut ota = EspOta::new().map_err(|e| Error::ota_caused("Failed to open OTA", e))?;
+    let mut update = ota
+        .initiate_update()
+        .map_err(|e| Error::ota_caused("Failed to start update", e))?;
+    info!("OTA update started: {} bytes, CRC32 {:08x}", request.size, request.crc32);
+
+    let result = copy_image(stream, request, |data| {
+        update.write(data).map(|_| ()).map_err(|e| Error::ota_caused("Failed to write image", e))
+    });
+    match result {
+        // complete()会校验镜像并把它设为启动分区
+        Ok(()) => update
+            .complete()
+            .map_err(|e| Error::ota_caused("Failed to activate image", e)),
+        Err(e) => {
+            warn!("Aborting OTA update: {}", e);
+            if let Err(abort_err) = update.abort() {
+                warn!("Failed to abort OTA update: {}", abort_err);
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Stream the image into the update partition and verify it
+#[cfg(not(target_os = "espidf"))]
+fn write_image(_stream: &mut dyn ClientStream, _request: &OtaRequest) -> Result<()> {
+    Err(Error::ota("No OTA partitions in the host build"))
+}
+
+/// Read the announced image from `stream` and pass it to `write` chunk by chunk
+///
+/// Acknowledges the progress every `ACK_INTERVAL_BYTES` and once complete. Fails
+/// if the connection closes or stalls early, or if the CRC32 of the image differs
+/// from the announced one.
+#[cfg(any(target_os = "espidf", test))]
+fn copy_image(
+    stream: &mut dyn ClientStream,
+    request: &OtaRequest,
+    mut write: impl FnMut(&[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut buffer = vec![0; CHUNK_SIZE];
+    let mut received = 0;
+    let mut crc = 0;
+    let mut next_ack = ACK_INTERVAL_BYTES;
+    while received < request.size {
+        let want = CHUNK_SIZE.min(request.size - received);
+        let n = match stream.read(&mut buffer[..want]) {
+            Ok(0) => return Err(Error::ota(format!("Connection closed after {} bytes", received))),
+            Ok(n) => n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Err(Error::ota(format!("Upload stalled after {} bytes", received)));
+            }
+            Err(e) => return Err(Error::ota_caused("Failed to read image", e)),
+        };
+        write(&buffer[..n])?;
+        crc = crc32_le(crc, &buffer[..n]);
+        received += n;
+
+        if received >= next_ack || received == request.size {
+            next_ack = received - received % ACK_INTERVAL_BYTES + ACK_INTERVAL_BYTES;
+            let ack = format!("+OTA: {}/{}\r\n", received, request.size);
+            stream
+                .write_all(ack.as_bytes())
+                .map_err(|e| Error::ota_caused("Failed to send progress", e))?;
+        }
+    }
+
+    if crc != request.crc32 {
+        return Err(Error::ota(format!(
+            "CRC32 mismatch: expected {:08x}, got {:08x}",
+            request.crc32, crc
+        )));
+    }
+    Ok(())
+}
+
+/// Continue the CRC32 (IEEE) `crc` over `data`, with the ROM routine
+#[cfg(target_os = "espidf")]
+fn crc32_le(crc: u32, data: &[u8]) -> u32 {
+    // ROM实现的CRC32支持分段累加
+    unsafe { esp_idf_sys::esp_rom_crc32_le(crc, data.as_ptr(), data.len() as u32) }
+}
+
+/// Continue the CRC32 (IEEE) `crc` over `data`, bitwise like the ROM routine
+#[cfg(all(not(target_os = "espidf"), test))]
+fn crc32_le(crc: u32, data: &[u8]) -> u32 {
+    let mut crc = !crc;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Discard the rest of an aborted upload until the sender goes quiet
+fn drain(stream: &mut dyn ClientStream) {
+    let _ = stream.set_read_timeout(Some(DRAIN_QUIET));
+    let stopwatch = Stopwatch::start();
+    let mut buffer = [0; 256];
+    let mut discarded = 0;
+    while !stopwatch.has_elapsed(STALL_TIMEOUT) {
+        match stream.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => discarded += n,
+            Err(e) if e.kind() == ErrorKind::Interrupted => {}
+            Err(_) => break,
+        }
+    }
+    debug!("Discarded {} bytes of the aborted upload", discarded);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    use crate::tcp_client_manager::mock::{addr, MockStream, Wire};
+
+    /// Image of `len` bytes with a position dependent pattern
+    fn image(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 + i / 251) as u8).collect()
+    }
+
+    /// Upload connection whose client has sent `data`
+    fn upload(data: &[u8]) -> (Arc<Mutex<MockStream>>, Wire) {
+        let (stream, wire) = MockStream::new(addr(1));
+        wire.lock().unwrap().input.extend(data);
+        (stream, wire)
+    }
+
+    /// Copy the upload of `data` announced as `request`, returning the result and what was written
+    fn copy(data: &[u8], request: &OtaRequest) -> (Result<()>, Vec<u8>, String) {
+        let (stream, wire) = upload(data);
+        let mut written = Vec::new();
+        let result = copy_image(&mut *stream.lock().unwrap(), request, |chunk| {
+            written.extend_from_slice(chunk);
+            Ok(())
+        });
+        let output = String::from_utf8(std::mem::take(&mut wire.lock().unwrap().output)).unwrap();
+        (result, written, output)
+    }
+
+    #[test]
+    fn request_is_parsed() {
+        assert_eq!(OtaRequest::parse("1024,cbf43926"), Ok(OtaRequest { size: 1024, crc32: 0xcbf4_3926 }));
+        assert_eq!(OtaRequest::parse(" 1 , 0XFF "), Ok(OtaRequest { size: 1, crc32: 0xff }));
+        assert_eq!(OtaRequest::parse("1024"), Err("Expected AT+OTA=<size>,<crc32>".to_string()));
+        assert_eq!(OtaRequest::parse("0,00"), Err("Invalid image size: 0".to_string()));
+        assert_eq!(OtaRequest::parse("-1,00"), Err("Invalid image size: -1".to_string()));
+        assert_eq!(OtaRequest::parse("10,xyz"), Err("Invalid CRC32: xyz".to_string()));
+        assert_eq!(OtaRequest::parse("10,1ffffffff"), Err("Invalid CRC32: 1ffffffff".to_string()));
+    }
+
+    #[test]
+    fn crc_accumulates_across_chunks() {
+        assert_eq!(crc32_le(0, b"123456789"), 0xcbf4_3926);
+        let data = image(5000);
+        let chunked = data.chunks(777).fold(0, crc32_le);
+        assert_eq!(chunked, crc32_le(0, &data));
+    }
+
+    #[test]
+    fn image_is_written_and_progress_acknowledged() {
+        let data = image(10_000);
+        let request = OtaRequest { size: data.len(), crc32: crc32_le(0, &data) };
+        let (result, written, output) = copy(&data, &request);
+        result.unwrap();
+        assert_eq!(written, data);
+        assert_eq!(output, "+OTA: 4096/10000\r\n+OTA: 8192/10000\r\n+OTA: 10000/10000\r\n");
+    }
+
+    #[test]
+    fn data_after_the_image_is_not_read() {
+        let data = image(100);
+        let request = OtaRequest { size: data.len(), crc32: crc32_le(0, &data) };
+        let (stream, wire) = upload(&data);
+        wire.lock().unwrap().input.extend(b"AT+OTA?\r\n");
+        copy_image(&mut *stream.lock().unwrap(), &request, |_| Ok(())).unwrap();
+        assert_eq!(wire.lock().unwrap().input, b"AT+OTA?\r\n");
+    }
+
+    #[test]
+    fn crc_mismatch_fails_the_update() {
+        let data = image(5000);
+        let request = OtaRequest { size: data.len(), crc32: crc32_le(0, &data) ^ 1 };
+        let (result, written, _) = copy(&data, &request);
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("CRC32 mismatch"), "{}", e);
+        assert_eq!(written.len(), data.len());
+    }
+
+    #[test]
+    fn stalled_upload_fails_with_the_received_size() {
+        let data = image(3000);
+        let request = OtaRequest { size: 8000, crc32: 0 };
+        let (result, written, output) = copy(&data, &request);
+        let e = result.unwrap_err();
+        assert!(e.to_string().contains("Upload stalled after 3000 bytes"), "{}", e);
+        assert_eq!(written, data);
+        assert!(output.is_empty());
+    }
+
+    #[test]
+    fn write_error_stops_the_upload() {
+        let data = image(5000);
+        let request = OtaRequest { size: data.len(), crc32: crc32_le(0, &data) };
+        let (stream, wire) = upload(&data);
+        let mut chunks = 0;
+        let result = copy_image(&mut *stream.lock().unwrap(), &request, |_| {
+            chunks += 1;
+            if chunks == 2 {
+                return Err(Error::ota("Flash write failed"));
+            }
+            Ok(())
+        });
+        assert!(result.unwrap_err().to_string().contains("Flash write failed"));
+        assert_eq!(wire.lock().unwrap().input.len(), data.len() - 2 * CHUNK_SIZE);
+    }
+
+    #[test]
+    fn failed_update_discards_the_rest_of_the_upload() {
+        let data = image(3000);
+        let (stream, wire) = upload(&data);
+        let request = OtaRequest { size: data.len(), crc32: 0 };
+        assert!(receive(&mut *stream.lock().unwrap(), &request).is_err());
+        // 连接回到命令模式前丢弃剩余的镜像数据
+        assert!(wire.lock().unwrap().input.is_empty());
+    }
+
+    #[test]
+    fn next_boot_partition_is_shown_only_when_it_changes() {
+        let mut status = OtaStatus {
+            running: "ota_0".to_string(),
+            version: "0.1.0".to_string(),
+            boot: "ota_0".to_string(),
+        };
+        assert_eq!(status.to_string(), "Partition: ota_0, version 0.1.0");
+        status.boot = "ota_1".to_string();
+        assert_eq!(status.to_string(), "Partition: ota_0, version 0.1.0 (next boot: ota_1)");
+    }
+}
@@ -0,0 +1,385 @@
+//! Packet codec module
+//!
+//! This module implements SLIP (RFC 1055) and COBS, the two framings selected by
+//! `UartConfig::packet_codec` for tunnelling packets over the serial link. UART
+//! data is decoded into packets that are broadcast one by one, and every read
+//! from a network client is encoded as one packet on its way to the UART.
+//!
+//! Decoding keeps its state between calls, so a packet or an escape sequence split
+//! across two reads is put back together. Packets longer than the limit passed to
+//! `PacketDecoder::decode` and malformed packets are dropped up to the next
+//! delimiter and reported as errors.
+
+use std::fmt;
+
+use crate::config::PacketCodec;
+
+/// SLIP byte ending a packet
+const SLIP_END: u8 = 0xC0;
+/// SLIP byte starting an escape sequence
+const SLIP_ESC: u8 = 0xDB;
+/// Escaped `SLIP_END`
+const SLIP_ESC_END: u8 = 0xDC;
+/// Escaped `SLIP_ESC`
+const SLIP_ESC_ESC: u8 = 0xDD;
+
+/// COBS byte ending a packet
+const COBS_DELIMITER: u8 = 0x00;
+/// Longest run of non-zero bytes one COBS code byte covers
+const COBS_MAX_RUN: usize = 254;
+
+/// Why received bytes did not make a packet
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecodeError {
+    /// The packet grew past the size limit
+    TooLong,
+    /// A SLIP escape byte was followed by something other than ESC_END or ESC_ESC,
+    /// including the END of the packet
+    InvalidEscape,
+    /// A COBS code byte pointed past the end of the packet
+    Truncated,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DecodeError::TooLong => write!(f, "packet too long"),
+            DecodeError::InvalidEscape => write!(f, "invalid SLIP escape sequence"),
+            DecodeError::Truncated => write!(f, "truncated COBS packet"),
+        }
+    }
+}
+
+/// Encode one packet for the UART; `PacketCodec::None` returns it unchanged
+pub fn encode(codec: PacketCodec, packet: &[u8]) -> Vec<u8> {
+    match codec {
+        PacketCodec::None => packet.to_vec(),
+        PacketCodec::Slip => slip_encode(packet),
+        PacketCodec::Cobs => cobs_encode(packet),
+    }
+}
+
+/// Encode a packet as SLIP, with an END before it to flush line noise and one after it
+pub fn slip_encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(packet.len() + 2);
+    encoded.push(SLIP_END);
+    for &byte in packet {
+        match byte {
+            SLIP_END => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_END]),
+            SLIP_ESC => encoded.extend_from_slice(&[SLIP_ESC, SLIP_ESC_ESC]),
+            byte => encoded.push(byte),
+        }
+    }
+    encoded.push(SLIP_END);
+    encoded
+}
+
+/// Encode a packet as COBS, followed by the zero delimiter
+pub fn cobs_encode(packet: &[u8]) -> Vec<u8> {
+    let mut encoded = Vec::with_capacity(packet.len() + packet.len() / COBS_MAX_RUN + 2);
+    // 每段以代码字节开始，值为到下一个零字节的距离
+    let mut code_index = 0;
+    encoded.push(0);
+    let mut run = 0;
+    for &byte in packet {
+        if byte != 0 {
+            encoded.push(byte);
+            run += 1;
+        }
+        if byte == 0 || run == COBS_MAX_RUN {
+            encoded[code_index] = run as u8 + 1;
+            code_index = encoded.len();
+            encoded.push(0);
+            run = 0;
+        }
+    }
+    encoded[code_index] = run as u8 + 1;
+    encoded.push(COBS_DELIMITER);
+    encoded
+}
+
+/// Decode a COBS packet without its delimiter
+pub fn cobs_decode(encoded: &[u8]) -> std::result::Result<Vec<u8>, DecodeError> {
+    let mut packet = Vec::with_capacity(encoded.len());
+    let mut rest = encoded;
+    while let Some((&code, tail)) = rest.split_first() {
+        let run = usize::from(code).checked_sub(1).ok_or(DecodeError::Truncated)?;
+        if run > tail.len() {
+            return Err(DecodeError::Truncated);
+        }
+        packet.extend_from_slice(&tail[..run]);
+        rest = &tail[run..];
+        // 最长段之后和最后一段之后没有被编码掉的零字节
+        if run < COBS_MAX_RUN && !rest.is_empty() {
+            packet.push(0);
+        }
+    }
+    Ok(packet)
+}
+
+/// Decoder for the packets of a UART byte stream
+#[derive(Debug)]
+pub struct PacketDecoder {
+    codec: PacketCodec,
+    /// Bytes of the packet being received, still COBS encoded for COBS
+    packet: Vec<u8>,
+    /// Whether the previous SLIP byte was an escape
+    escaped: bool,
+    /// Set after an error until the next delimiter, whose bytes are dropped
+    discarding: bool,
+}
+
+impl PacketDecoder {
+    /// Create a decoder for `codec` with nothing received yet
+    pub fn new(codec: PacketCodec) -> Self {
+        Self {
+            codec,
+            packet: Vec::new(),
+            escaped: false,
+            discarding: false,
+        }
+    }
+
+    /// Add received bytes, calling `emit` for every packet completed by them and
+    /// every packet dropped as malformed
+    ///
+    /// Packets longer than `max_len` bytes are dropped. Empty packets, such as the
+    /// END bytes SLIP senders put before each packet, are skipped.
+    pub fn decode(
+        &mut self,
+        data: &[u8],
+        max_len: usize,
+        mut emit: impl FnMut(std::result::Result<&[u8], DecodeError>),
+    ) {
+        match self.codec {
+            PacketCodec::None => {
+                if !data.is_empty() {
+                    emit(Ok(data));
+                }
+            }
+            PacketCodec::Slip => {
+                for &byte in data {
+                    self.push_slip(byte, max_len, &mut emit);
+                }
+            }
+            PacketCodec::Cobs => {
+                // 编码后的长度上限：每254字节多一个代码字节
+                let max_encoded = max_len + max_len / COBS_MAX_RUN + 1;
+                for &byte in data {
+                    self.push_cobs(byte, max_encoded, max_len, &mut emit);
+                }
+            }
+        }
+    }
+
+    fn push_slip(
+        &mut self,
+        byte: u8,
+        max_len: usize,
+        emit: &mut impl FnMut(std::result::Result<&[u8], DecodeError>),
+    ) {
+        if byte == SLIP_END {
+            // 转义字节后紧跟END说明数据包不完整
+            if self.escaped && !self.discarding {
+                emit(Err(DecodeError::InvalidEscape));
+            } else if !self.discarding && !self.packet.is_empty() {
+                emit(Ok(&self.packet));
+            }
+            self.reset();
+            return;
+        }
+        if self.discarding {
+            return;
+        }
+        let decoded = if self.escaped {
+            self.escaped = false;
+            match byte {
+                SLIP_ESC_END => SLIP_END,
+                SLIP_ESC_ESC => SLIP_ESC,
+                _ => return self.fail(DecodeError::InvalidEscape, emit),
+            }
+        } else if byte == SLIP_ESC {
+            self.escaped = true;
+            return;
+        } else {
+            byte
+        };
+        if self.packet.len() >= max_len {
+            return self.fail(DecodeError::TooLong, emit);
+        }
+        self.packet.push(decoded);
+    }
+
+    fn push_cobs(
+        &mut self,
+        byte: u8,
+        max_encoded: usize,
+        max_len: usize,
+        emit: &mut impl FnMut(std::result::Result<&[u8], DecodeError>),
+    ) {
+        if byte == COBS_DELIMITER {
+            if !self.discarding && !self.packet.is_empty() {
+                match cobs_decode(&self.packet) {
+                    Ok(packet) if packet.is_empty() => {}
+                    Ok(packet) if packet.len() > max_len => emit(Err(DecodeError::TooLong)),
+                    Ok(packet) => emit(Ok(&packet)),
+                    Err(e) => emit(Err(e)),
+                }
+            }
+            self.reset();
+            return;
+        }
+        if self.discarding {
+            return;
+        }
+        if self.packet.len() >= max_encoded {
+            return self.fail(DecodeError::TooLong, emit);
+        }
+        self.packet.push(byte);
+    }
+
+    /// Report an error and drop the rest of the packet
+    fn fail(&mut self, error: DecodeError, emit: &mut impl FnMut(std::result::Result<&[u8], DecodeError>)) {
+        emit(Err(error));
+        self.packet.clear();
+        self.escaped = false;
+        self.discarding = true;
+    }
+
+    /// Start a new packet after a delimiter
+    fn reset(&mut self) {
+        self.packet.clear();
+        self.escaped = false;
+        self.discarding = false;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Decode `chunks` one after the other, collecting packets and errors
+    fn decode(codec: PacketCodec, max_len: usize, chunks: &[&[u8]]) -> Vec<std::result::Result<Vec<u8>, DecodeError>> {
+        let mut decoder = PacketDecoder::new(codec);
+        let mut decoded = Vec::new();
+        for chunk in chunks {
+            decoder.decode(chunk, max_len, |packet| decoded.push(packet.map(<[u8]>::to_vec)));
+        }
+        decoded
+    }
+
+    /// Packets covering the special bytes of both codecs and the COBS run boundaries
+    fn samples() -> Vec<Vec<u8>> {
+        let run = |len: usize| (0..len).map(|i| (i % 255 + 1) as u8).collect::<Vec<u8>>();
+        vec![
+            vec![1],
+            vec![0],
+            vec![0, 0],
+            vec![SLIP_END, SLIP_ESC, SLIP_ESC_END, SLIP_ESC_ESC],
+            b"hello\0world\0".to_vec(),
+            run(253),
+            run(254),
+            run(255),
+            [run(254), vec![0], run(10)].concat(),
+            run(600),
+        ]
+    }
+
+    #[test]
+    fn slip_round_trip() {
+        for packet in samples() {
+            let encoded = slip_encode(&packet);
+            assert_eq!(encoded.iter().filter(|&&b| b == SLIP_END).count(), 2);
+            assert_eq!(decode(PacketCodec::Slip, 1024, &[&encoded]), [Ok(packet)]);
+        }
+    }
+
+    #[test]
+    fn cobs_round_trip() {
+        for packet in samples() {
+            let encoded = cobs_encode(&packet);
+            let (delimiter, body) = encoded.split_last().unwrap();
+            assert_eq!(*delimiter, COBS_DELIMITER);
+            assert!(!body.contains(&0), "{:?}", packet);
+            assert_eq!(cobs_decode(body), Ok(packet.clone()));
+            assert_eq!(decode(PacketCodec::Cobs, 1024, &[&encoded]), [Ok(packet)]);
+        }
+    }
+
+    #[test]
+    fn cobs_run_boundary() {
+        // 254个非零字节占满一个代码字节，不需要额外的零
+        let packet = vec![0x11; 254];
+        let encoded = cobs_encode(&packet);
+        assert_eq!(encoded[0], 0xFF);
+        assert_eq!(encoded.len(), 1 + 254 + 1 + 1);
+        assert_eq!(encoded[255], 1);
+
+        let packet = vec![0x11; 253];
+        assert_eq!(cobs_encode(&packet)[0], 0xFE);
+        assert_eq!(cobs_encode(&packet).len(), 1 + 253 + 1);
+    }
+
+    #[test]
+    fn packets_split_across_reads_are_joined() {
+        for codec in [PacketCodec::Slip, PacketCodec::Cobs] {
+            let packet = samples().concat();
+            let encoded = [encode(codec, &packet), encode(codec, b"next")].concat();
+            let chunks: Vec<&[u8]> = encoded.chunks(7).collect();
+            assert_eq!(decode(codec, 4096, &chunks), [Ok(packet), Ok(b"next".to_vec())], "{}", codec);
+        }
+    }
+
+    #[test]
+    fn slip_escape_split_across_reads() {
+        let decoded = decode(PacketCodec::Slip, 16, &[&[SLIP_END, b'a', SLIP_ESC], &[SLIP_ESC_END, SLIP_END]]);
+        assert_eq!(decoded, [Ok(vec![b'a', SLIP_END])]);
+    }
+
+    #[test]
+    fn empty_packets_are_skipped() {
+        assert!(decode(PacketCodec::Slip, 16, &[&[SLIP_END, SLIP_END, SLIP_END]]).is_empty());
+        assert!(decode(PacketCodec::Cobs, 16, &[&[0, 1, 0, 0]]).is_empty());
+        assert!(decode(PacketCodec::None, 16, &[&[]]).is_empty());
+    }
+
+    #[test]
+    fn too_long_packets_are_dropped() {
+        for codec in [PacketCodec::Slip, PacketCodec::Cobs] {
+            let encoded = [encode(codec, &[7; 9]), encode(codec, &[8; 8])].concat();
+            assert_eq!(decode(codec, 8, &[&encoded]), [Err(DecodeError::TooLong), Ok(vec![8; 8])], "{}", codec);
+        }
+    }
+
+    #[test]
+    fn invalid_slip_escape_drops_the_packet() {
+        let decoded = decode(PacketCodec::Slip, 16, &[&[b'a', SLIP_ESC, b'b', b'c', SLIP_END, b'd', SLIP_END]]);
+        assert_eq!(decoded, [Err(DecodeError::InvalidEscape), Ok(vec![b'd'])]);
+    }
+
+    #[test]
+    fn slip_escape_before_end_is_invalid() {
+        let decoded = decode(PacketCodec::Slip, 16, &[&[b'a', SLIP_ESC, SLIP_END, b'b', SLIP_END]]);
+        assert_eq!(decoded, [Err(DecodeError::InvalidEscape), Ok(vec![b'b'])]);
+    }
+
+    #[test]
+    fn truncated_cobs_packet_is_reported() {
+        assert_eq!(cobs_decode(&[5, 1, 2]), Err(DecodeError::Truncated));
+        let decoded = decode(PacketCodec::Cobs, 16, &[&[5, 1, 2, 0, 2, 9, 0]]);
+        assert_eq!(decoded, [Err(DecodeError::Truncated), Ok(vec![9])]);
+    }
+
+    #[test]
+    fn cobs_zero_code_byte_is_truncated() {
+        assert_eq!(cobs_decode(&[0]), Err(DecodeError::Truncated));
+        assert_eq!(cobs_decode(&[2, 1, 0, 1]), Err(DecodeError::Truncated));
+        assert_eq!(cobs_decode(&[]), Ok(Vec::new()));
+    }
+
+    #[test]
+    fn no_codec_passes_data_through() {
+        assert_eq!(encode(PacketCodec::None, b"\0\xc0"), b"\0\xc0");
+        assert_eq!(decode(PacketCodec::None, 1, &[b"abc"]), [Ok(b"abc".to_vec())]);
+    }
+}
@@ -0,0 +1,205 @@
+//! Factory reset button module
+//!
+//! This module watches a push button (by default the BOOT button on GPIO9 of the
+//! ESP32-C3) and erases all stored settings when it is held, so a device with wrong
+//! WiFi credentials can be recovered without a TCP session.
+//!
+//! GPIO9 is a strapping pin: holding it while the device powers up enters the ROM
+//! download mode instead, so press the button after the device has booted.
+
+use esp_idf_hal::gpio::{self, Input, PinDriver, Pull};
+use log::{error, info, warn};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::ResetButtonConfig;
+use crate::error::{Error, Result};
+use crate::storage::StorageManager;
+use crate::time::Stopwatch;
+use crate::uart::{FLASH_GPIOS, MAX_GPIO};
+
+/// Interval in milliseconds at which the button is sampled
+const POLL_INTERVAL_MS: u64 = 50;
+
+/// FreeRTOS priority of the monitor thread, below the forwarding threads
+const MONITOR_PRIORITY: u32 = 1;
+
+/// Number of LED blinks before the restart
+const LED_BLINKS: u32 = 10;
+
+/// Half period of an LED blink in milliseconds
+const LED_BLINK_MS: u64 = 100;
+
+/// Tracks how long the button has been held down
+struct ButtonHold {
+    /// How long the button must be held
+    hold: Duration,
+    /// Running since the button was pressed
+    pressed: Option<Stopwatch>,
+}
+
+impl ButtonHold {
+    fn new(hold: Duration) -> Self {
+        Self { hold, pressed: None }
+    }
+
+    /// Feed one sample of the button, true once it has been held long enough
+    fn sample(&mut self, down: bool) -> bool {
+        if !down {
+            if self.pressed.take().is_some() {
+                info!("Factory reset button released");
+            }
+            return false;
+        }
+        match &self.pressed {
+            None => {
+                info!("Factory reset button pressed, hold to erase all settings");
+                self.pressed = Some(Stopwatch::start());
+                false
+            }
+            Some(held) => held.has_elapsed(self.hold),
+        }
+    }
+}
+
+/// Factory reset button monitor
+pub struct ResetButton {
+    /// Button input, pulled up and active low
+    button: PinDriver<'static, gpio::AnyIOPin, Input>,
+    /// Status LED blinked before the restart
+    led: Option<PinDriver<'static, gpio::AnyOutputPin, gpio::Output>>,
+    /// How long the button must be held
+    hold: Duration,
+    /// Storage erased when the button is held long enough
+    storage: Option<Arc<Mutex<StorageManager>>>,
+}
+
+impl ResetButton {
+    /// Start watching the configured button on a low-priority thread
+    ///
+    /// Does nothing if no button pin is configured.
+    pub fn start(config: &ResetButtonConfig, storage: Option<Arc<Mutex<StorageManager>>>) -> Result<()> {
+        let Some(pin) = config.pin else {
+            info!("Factory reset button disabled");
+            return Ok(());
+        };
+
+        let mut button = PinDriver::input(unsafe { gpio::AnyIOPin::new(Self::gpio_num(pin, "button")?) })
+            .map_err(|e| Error::esp(e.code(), "Configuring the reset button GPIO"))?;
+        button
+            .set_pull(Pull::Up)
+            .map_err(|e| Error::esp(e.code(), "Enabling the reset button pull-up"))?;
+        let led = match config.status_led_pin {
+            Some(led_pin) => {
+                let led = PinDriver::output(unsafe { gpio::AnyOutputPin::new(Self::gpio_num(led_pin, "status LED")?) })
+                    .map_err(|e| Error::esp(e.code(), "Configuring the status LED GPIO"))?;
+                Some(led)
+            }
+            None => None,
+        };
+
+        let monitor = Self {
+            button,
+            led,
+            hold: Duration::from_millis(config.hold_ms),
+            storage,
+        };
+        thread::Builder::new()
+            .name("reset_button".into())
+            .stack_size(3072)
+            .spawn(move || {
+                unsafe {
+                    esp_idf_sys::vTaskPrioritySet(
+                        esp_idf_sys::xTaskGetCurrentTaskHandle(),
+                        MONITOR_PRIORITY, // 低优先级，不影响数据转发
+                    );
+                }
+                monitor.run();
+            })
+            .map_err(|e| Error::General(format!("Failed to spawn reset button thread: {}", e)))?;
+
+        info!(
+            "Factory reset button on GPIO{}, hold for {} ms to erase all settings",
+            pin, config.hold_ms
+        );
+        Ok(())
+    }
+
+    /// Sample the button until it has been held long enough, then reset
+    fn run(mut self) -> ! {
+        let mut hold = ButtonHold::new(self.hold);
+        loop {
+            thread::sleep(Duration::from_millis(POLL_INTERVAL_MS));
+
+            if hold.sample(self.button.is_low()) {
+                self.factory_reset();
+            }
+        }
+    }
+
+    /// Erase all settings, blink the LED and restart
+    fn factory_reset(&mut self) -> ! {
+        warn!("Factory reset button held, erasing all settings");
+        match self.storage.as_ref().map(|storage| storage.lock()) {
+            Some(Ok(mut storage)) => {
+                if let Err(e) = storage.erase_all() {
+                    error!("Failed to erase settings: {}", e);
+                }
+            }
+            Some(Err(_)) => error!("Failed to lock storage manager, settings not erased"),
+            None => warn!("Storage not available, nothing to erase"),
+        }
+
+        if let Some(led) = &mut self.led {
+            for _ in 0..LED_BLINKS {
+                let _ = led.set_high();
+                thread::sleep(Duration::from_millis(LED_BLINK_MS));
+                let _ = led.set_low();
+                thread::sleep(Duration::from_millis(LED_BLINK_MS));
+            }
+        }
+
+        warn!("Restarting device after factory reset");
+        unsafe { esp_idf_sys::esp_restart() }
+    }
+
+    /// Check a configured GPIO number
+    fn gpio_num(pin: u8, name: &str) -> Result<i32> {
+        let pin = pin as i32;
+        if pin > MAX_GPIO || FLASH_GPIOS.contains(&pin) {
+            return Err(Error::General(format!("Invalid {} pin GPIO{}", name, pin)));
+        }
+        Ok(pin)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+
+    #[test]
+    fn reset_needs_the_button_held_without_release() {
+        let _clock = time::lock_clock();
+        let mut hold = ButtonHold::new(Duration::from_millis(5000));
+
+        assert!(!hold.sample(true));
+        time::advance(Duration::from_millis(4000));
+        assert!(!hold.sample(true));
+        // 松开后重新计时
+        assert!(!hold.sample(false));
+        assert!(!hold.sample(true));
+        time::advance(Duration::from_millis(4000));
+        assert!(!hold.sample(true));
+        time::advance(Duration::from_millis(1000));
+        assert!(hold.sample(true));
+    }
+
+    #[test]
+    fn button_pins_outside_the_chip_or_on_flash_are_refused() {
+        assert_eq!(ResetButton::gpio_num(9, "button").unwrap(), 9);
+        assert!(ResetButton::gpio_num(14, "button").is_err());
+        assert!(ResetButton::gpio_num(22, "status LED").is_err());
+    }
+}
@@ -0,0 +1,514 @@
+//! RFC 2217 module
+//!
+//! This module implements the server side of the Telnet COM-PORT-CONTROL option
+//! (RFC 2217), so pyserial's `rfc2217://` URLs, esptool and other remote serial port
+//! clients can use the bridge and change the serial settings in-band, without AT
+//! commands.
+//!
+//! `TelnetSession` strips Telnet commands from the bytes a client sends, answers
+//! option negotiation and maps the com port subnegotiations onto `UartManager`.
+//! 0xFF data bytes are doubled in both directions (see `escape`). The client starts
+//! the negotiation, as pyserial does.
+//!
+//! The bridge has no modem control or break lines: DTR and RTS changes are
+//! acknowledged and remembered but drive no pins, and break and flow control
+//! requests are answered with the current state (off / none).
+
+use log::{debug, info, warn};
+
+use crate::config::{Parity, SerialFormat, StopBits};
+use crate::uart::UartManager;
+use crate::version::FIRMWARE_VERSION;
+
+/// Telnet "interpret as command" byte, doubled when it occurs in data
+pub const IAC: u8 = 255;
+const DONT: u8 = 254;
+const DO: u8 = 253;
+const WONT: u8 = 252;
+const WILL: u8 = 251;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+/// Telnet options
+const OPT_BINARY: u8 = 0;
+const OPT_SGA: u8 = 3;
+const OPT_COM_PORT: u8 = 44;
+
+/// Options the bridge enables on its side when asked with DO
+const LOCAL_OPTIONS: [u8; 2] = [OPT_BINARY, OPT_SGA];
+
+/// Options the bridge lets the client enable with WILL
+const REMOTE_OPTIONS: [u8; 3] = [OPT_BINARY, OPT_SGA, OPT_COM_PORT];
+
+/// Com port commands sent by the client; the server answers with the command + 100
+const SIGNATURE: u8 = 0;
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+const FLOWCONTROL_SUSPEND: u8 = 8;
+const FLOWCONTROL_RESUME: u8 = 9;
+const SET_LINESTATE_MASK: u8 = 10;
+const SET_MODEMSTATE_MASK: u8 = 11;
+const PURGE_DATA: u8 = 12;
+const SERVER_OFFSET: u8 = 100;
+
+/// SET-CONTROL values
+const CONTROL_FLOW_REQUEST: u8 = 0;
+const CONTROL_FLOW_NONE: u8 = 1;
+const CONTROL_BREAK_REQUEST: u8 = 4;
+const CONTROL_BREAK_OFF: u8 = 6;
+const CONTROL_DTR_REQUEST: u8 = 7;
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+const CONTROL_RTS_REQUEST: u8 = 10;
+const CONTROL_RTS_ON: u8 = 11;
+const CONTROL_RTS_OFF: u8 = 12;
+const CONTROL_INBOUND_FLOW_REQUEST: u8 = 13;
+const CONTROL_INBOUND_FLOW_NONE: u8 = 14;
+
+/// Longest subnegotiation kept; longer ones are ignored
+const MAX_SUBNEGOTIATION: usize = 64;
+
+/// Double every 0xFF byte, as Telnet requires for data
+pub fn escape(data: &[u8]) -> Vec<u8> {
+    let mut escaped = Vec::with_capacity(data.len() + 8);
+    for &byte in data {
+        escaped.push(byte);
+        if byte == IAC {
+            escaped.push(IAC);
+        }
+    }
+    escaped
+}
+
+/// Position of the parser in the Telnet byte stream
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ParseState {
+    /// Plain data
+    Data,
+    /// After IAC
+    Command,
+    /// After IAC and a negotiation verb (WILL/WONT/DO/DONT)
+    Negotiation(u8),
+    /// Inside a subnegotiation
+    Subnegotiation,
+    /// After IAC inside a subnegotiation
+    SubnegotiationCommand,
+}
+
+/// Telnet and com port state of one RFC 2217 connection
+pub struct TelnetSession {
+    /// Parser position, kept across reads so commands may be split between them
+    state: ParseState,
+    /// Bytes of the current subnegotiation, after IAC SB
+    subnegotiation: Vec<u8>,
+    /// The current subnegotiation exceeded `MAX_SUBNEGOTIATION` and is ignored
+    oversized: bool,
+    /// Options enabled on the bridge's side
+    local: Vec<u8>,
+    /// Options enabled on the client's side
+    remote: Vec<u8>,
+    /// DTR state last set by the client (no pin is driven)
+    dtr: bool,
+    /// RTS state last set by the client (no pin is driven)
+    rts: bool,
+}
+
+impl TelnetSession {
+    /// Create the state of a new connection
+    pub fn new() -> Self {
+        Self {
+            state: ParseState::Data,
+            subnegotiation: Vec::new(),
+            oversized: false,
+            local: Vec::new(),
+            remote: Vec::new(),
+            dtr: true,
+            rts: true,
+        }
+    }
+
+    /// Process bytes received from the client
+    ///
+    /// Data for the UART is appended to `data` with Telnet escaping removed, and
+    /// Telnet replies to send back to the client are appended to `replies`.
+    pub fn receive(&mut self, input: &[u8], uart: &UartManager, data: &mut Vec<u8>, replies: &mut Vec<u8>) {
+        self.parse(input, data, replies, |session, replies| session.subnegotiate(uart, replies));
+    }
+
+    /// Split received bytes into data, negotiations and subnegotiations
+    ///
+    /// `subnegotiate` is called for each complete subnegotiation that fits
+    /// `MAX_SUBNEGOTIATION`.
+    fn parse(
+        &mut self,
+        input: &[u8],
+        data: &mut Vec<u8>,
+        replies: &mut Vec<u8>,
+        mut subnegotiate: impl FnMut(&mut Self, &mut Vec<u8>),
+    ) {
+        for &byte in input {
+            self.state = match (self.state, byte) {
+                (ParseState::Data, IAC) => ParseState::Command,
+                (ParseState::Data, _) => {
+                    data.push(byte);
+                    ParseState::Data
+                }
+                (ParseState::Command, IAC) => {
+                    data.push(IAC);
+                    ParseState::Data
+                }
+                (ParseState::Command, WILL | WONT | DO | DONT) => ParseState::Negotiation(byte),
+                (ParseState::Command, SB) => {
+                    self.subnegotiation.clear();
+                    self.oversized = false;
+                    ParseState::Subnegotiation
+                }
+                // NOP、AYT等其他命令忽略
+                (ParseState::Command, _) => ParseState::Data,
+                (ParseState::Negotiation(verb), option) => {
+                    self.negotiate(verb, option, replies);
+                    ParseState::Data
+                }
+                (ParseState::Subnegotiation, IAC) => ParseState::SubnegotiationCommand,
+                (ParseState::Subnegotiation, _) => {
+                    self.push_subnegotiation(byte);
+                    ParseState::Subnegotiation
+                }
+                (ParseState::SubnegotiationCommand, IAC) => {
+                    self.push_subnegotiation(IAC);
+                    ParseState::Subnegotiation
+                }
+                (ParseState::SubnegotiationCommand, SE) => {
+                    if self.oversized {
+                        warn!("Ignoring oversized Telnet subnegotiation");
+                    } else {
+                        subnegotiate(self, replies);
+                    }
+                    ParseState::Data
+                }
+                // 子协商中出现其他命令，视为格式错误并丢弃
+                (ParseState::SubnegotiationCommand, _) => {
+                    debug!("Dropping malformed Telnet subnegotiation");
+                    ParseState::Data
+                }
+            };
+        }
+    }
+
+    /// Add a byte to the current subnegotiation
+    fn push_subnegotiation(&mut self, byte: u8) {
+        if self.subnegotiation.len() < MAX_SUBNEGOTIATION {
+            self.subnegotiation.push(byte);
+        } else {
+            self.oversized = true;
+        }
+    }
+
+    /// Answer a WILL/WONT/DO/DONT request
+    ///
+    /// Only state changes are answered, so acknowledgements never start a loop.
+    fn negotiate(&mut self, verb: u8, option: u8, replies: &mut Vec<u8>) {
+        debug!("Telnet negotiation {} {}", verb, option);
+        match verb {
+            WILL if !REMOTE_OPTIONS.contains(&option) => replies.extend([IAC, DONT, option]),
+            WILL if !self.remote.contains(&option) => {
+                self.remote.push(option);
+                replies.extend([IAC, DO, option]);
+                if option == OPT_COM_PORT {
+                    info!("RFC 2217 com port control enabled");
+                }
+            }
+            WONT if self.remote.contains(&option) => {
+                self.remote.retain(|&enabled| enabled != option);
+                replies.extend([IAC, DONT, option]);
+            }
+            DO if !LOCAL_OPTIONS.contains(&option) => replies.extend([IAC, WONT, option]),
+            DO if !self.local.contains(&option) => {
+                self.local.push(option);
+                replies.extend([IAC, WILL, option]);
+            }
+            DONT if self.local.contains(&option) => {
+                self.local.retain(|&enabled| enabled != option);
+                replies.extend([IAC, WONT, option]);
+            }
+            _ => {}
+        }
+    }
+
+    /// Handle a complete subnegotiation
+    fn subnegotiate(&mut self, uart: &UartManager, replies: &mut Vec<u8>) {
+        let subnegotiation = std::mem::take(&mut self.subnegotiation);
+        let [OPT_COM_PORT, command, ref value @ ..] = subnegotiation[..] else {
+            debug!("Ignoring Telnet subnegotiation for an unsupported option");
+            return;
+        };
+
+        match (command, value) {
+            (SIGNATURE, []) => {
+                let signature = format!("ESP32-C3 UART-TCP Bridge {}", FIRMWARE_VERSION);
+                Self::reply(replies, SIGNATURE, signature.as_bytes());
+            }
+            (SIGNATURE, signature) => {
+                info!("RFC 2217 client signature: {}", String::from_utf8_lossy(signature));
+            }
+            (SET_BAUDRATE, &[a, b, c, d]) => {
+                let baudrate = u32::from_be_bytes([a, b, c, d]);
+                if baudrate != 0 && baudrate != uart.get_baudrate() {
+                    if let Err(e) = uart.apply_serial_params(baudrate, uart.get_format()) {
+                        warn!("RFC 2217 client set invalid baudrate {}: {}", baudrate, e);
+                    }
+                }
+                Self::reply(replies, SET_BAUDRATE, &uart.get_baudrate().to_be_bytes());
+            }
+            (SET_DATASIZE, &[data_bits]) => {
+                if (5..=8).contains(&data_bits) {
+                    Self::change_format(uart, |format| format.data_bits = data_bits);
+                }
+                Self::reply(replies, SET_DATASIZE, &[uart.get_format().data_bits]);
+            }
+            (SET_PARITY, &[parity]) => {
+                let parity = match parity {
+                    1 => Some(Parity::None),
+                    2 => Some(Parity::Odd),
+                    3 => Some(Parity::Even),
+                    // 0为查询；4、5（MARK/SPACE）不支持
+                    _ => None,
+                };
+                if let Some(parity) = parity {
+                    Self::change_format(uart, |format| format.parity = parity);
+                }
+                let current = match uart.get_format().parity {
+                    Parity::None => 1,
+                    Parity::Odd => 2,
+                    Parity::Even => 3,
+                };
+                Self::reply(replies, SET_PARITY, &[current]);
+            }
+            (SET_STOPSIZE, &[stop_bits]) => {
+                let stop_bits = match stop_bits {
+                    1 => Some(StopBits::One),
+                    2 => Some(StopBits::Two),
+                    3 => Some(StopBits::OnePointFive),
+                    _ => None,
+                };
+                if let Some(stop_bits) = stop_bits {
+                    Self::change_format(uart, |format| format.stop_bits = stop_bits);
+                }
+                let current = match uart.get_format().stop_bits {
+                    StopBits::One => 1,
+                    StopBits::Two => 2,
+                    StopBits::OnePointFive => 3,
+                };
+                Self::reply(replies, SET_STOPSIZE, &[current]);
+            }
+            (SET_CONTROL, &[control]) => {
+                let current = self.control(control);
+                Self::reply(replies, SET_CONTROL, &[current]);
+            }
+            (FLOWCONTROL_SUSPEND | FLOWCONTROL_RESUME, _) => {
+                debug!("Ignoring RFC 2217 flow control request {}", command);
+            }
+            // 从不发送线路和调制解调器状态通知，原样确认掩码
+            (SET_LINESTATE_MASK | SET_MODEMSTATE_MASK, &[mask]) => Self::reply(replies, command, &[mask]),
+            // 已交给UART驱动的数据无法撤回，只确认请求
+            (PURGE_DATA, &[buffers @ 1..=3]) => Self::reply(replies, PURGE_DATA, &[buffers]),
+            _ => debug!("Ignoring RFC 2217 command {} with {} value bytes", command, value.len()),
+        }
+    }
+
+    /// Apply a SET-CONTROL request and return the resulting state to report
+    fn control(&mut self, control: u8) -> u8 {
+        match control {
+            CONTROL_DTR_ON | CONTROL_DTR_OFF => {
+                self.dtr = control == CONTROL_DTR_ON;
+                debug!("RFC 2217 client set DTR {}", if self.dtr { "on" } else { "off" });
+                control
+            }
+            CONTROL_RTS_ON | CONTROL_RTS_OFF => {
+                self.rts = control == CONTROL_RTS_ON;
+                debug!("RFC 2217 client set RTS {}", if self.rts { "on" } else { "off" });
+                control
+            }
+            CONTROL_DTR_REQUEST if self.dtr => CONTROL_DTR_ON,
+            CONTROL_DTR_REQUEST => CONTROL_DTR_OFF,
+            CONTROL_RTS_REQUEST if self.rts => CONTROL_RTS_ON,
+            CONTROL_RTS_REQUEST => CONTROL_RTS_OFF,
+            // 没有流控和BREAK线路，报告当前状态
+            CONTROL_FLOW_REQUEST..=3 => CONTROL_FLOW_NONE,
+            CONTROL_BREAK_REQUEST..=CONTROL_BREAK_OFF => CONTROL_BREAK_OFF,
+            CONTROL_INBOUND_FLOW_REQUEST.. => CONTROL_INBOUND_FLOW_NONE,
+        }
+    }
+
+    /// Change the character format, keeping the baudrate
+    fn change_format(uart: &UartManager, change: impl FnOnce(&mut SerialFormat)) {
+        let mut format = uart.get_format();
+        change(&mut format);
+        if format == uart.get_format() {
+            return;
+        }
+        if let Err(e) = uart.apply_serial_params(uart.get_baudrate(), format) {
+            warn!("RFC 2217 client failed to set serial format {}: {}", format, e);
+        }
+    }
+
+    /// Append a com port reply to `replies`
+    fn reply(replies: &mut Vec<u8>, command: u8, value: &[u8]) {
+        replies.extend([IAC, SB, OPT_COM_PORT, command + SERVER_OFFSET]);
+        replies.extend(escape(value));
+        replies.extend([IAC, SE]);
+    }
+}
+
+impl Default for TelnetSession {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UartConfig;
+    use crate::uart::mock::{manager, Line};
+
+    fn session() -> (TelnetSession, UartManager, Line) {
+        let (uart, line) = manager(UartConfig::default());
+        (TelnetSession::new(), uart, line)
+    }
+
+    /// Feed `input` to `telnet`, returning the UART data and the replies
+    fn receive(telnet: &mut TelnetSession, uart: &UartManager, input: &[u8]) -> (Vec<u8>, Vec<u8>) {
+        let (mut data, mut replies) = (Vec::new(), Vec::new());
+        telnet.receive(input, uart, &mut data, &mut replies);
+        (data, replies)
+    }
+
+    /// Com port subnegotiation `command` with `value`, escaped like a client sends it
+    fn com_port(command: u8, value: &[u8]) -> Vec<u8> {
+        let mut request = vec![IAC, SB, OPT_COM_PORT, command];
+        request.extend(escape(value));
+        request.extend([IAC, SE]);
+        request
+    }
+
+    #[test]
+    fn escape_doubles_every_iac() {
+        assert_eq!(escape(b"plain"), b"plain");
+        assert_eq!(escape(&[IAC]), [IAC, IAC]);
+        assert_eq!(escape(&[1, IAC, IAC, 2]), [1, IAC, IAC, IAC, IAC, 2]);
+    }
+
+    #[test]
+    fn doubled_iac_is_received_as_one_data_byte() {
+        let (mut telnet, uart, _line) = session();
+        let data = [0x00, IAC, 0x7f, IAC, IAC, 0x01];
+        let (received, replies) = receive(&mut telnet, &uart, &escape(&data));
+        assert_eq!(received, data);
+        assert!(replies.is_empty());
+    }
+
+    #[test]
+    fn escaped_data_survives_every_split_point() {
+        let data = [b'a', IAC, IAC, b'b', IAC];
+        let escaped = escape(&data);
+        for split in 0..=escaped.len() {
+            let (mut telnet, uart, _line) = session();
+            let (mut received, _) = receive(&mut telnet, &uart, &escaped[..split]);
+            received.extend(receive(&mut telnet, &uart, &escaped[split..]).0);
+            assert_eq!(received, data, "split at {}", split);
+        }
+    }
+
+    #[test]
+    fn commands_inside_the_data_stream_are_removed() {
+        let (mut telnet, uart, _line) = session();
+        // NOP和WILL协商夹在数据中间
+        let (received, replies) = receive(&mut telnet, &uart, &[b'a', IAC, 241, b'b', IAC, WILL, OPT_BINARY, b'c']);
+        assert_eq!(received, b"abc");
+        assert_eq!(replies, [IAC, DO, OPT_BINARY]);
+    }
+
+    #[test]
+    fn negotiation_answers_only_state_changes() {
+        let (mut telnet, uart, _line) = session();
+        assert_eq!(receive(&mut telnet, &uart, &[IAC, WILL, OPT_COM_PORT]).1, [IAC, DO, OPT_COM_PORT]);
+        assert!(receive(&mut telnet, &uart, &[IAC, WILL, OPT_COM_PORT]).1.is_empty());
+        assert_eq!(receive(&mut telnet, &uart, &[IAC, DO, OPT_SGA]).1, [IAC, WILL, OPT_SGA]);
+
+        // 不支持的选项被拒绝
+        assert_eq!(receive(&mut telnet, &uart, &[IAC, WILL, 24]).1, [IAC, DONT, 24]);
+        assert_eq!(receive(&mut telnet, &uart, &[IAC, DO, OPT_COM_PORT]).1, [IAC, WONT, OPT_COM_PORT]);
+        assert_eq!(receive(&mut telnet, &uart, &[IAC, WONT, OPT_COM_PORT]).1, [IAC, DONT, OPT_COM_PORT]);
+    }
+
+    #[test]
+    fn baudrate_with_an_iac_byte_is_unescaped_and_escaped_back() {
+        let (mut telnet, uart, line) = session();
+        // 115199 = 0x0001C1FF，最后一个字节需要转义
+        let request = com_port(SET_BAUDRATE, &115_199u32.to_be_bytes());
+        assert!(request.windows(2).any(|pair| pair == [IAC, IAC]));
+
+        let (received, replies) = receive(&mut telnet, &uart, &request);
+        assert!(received.is_empty());
+        assert_eq!(uart.get_baudrate(), 115_199);
+        assert_eq!(line.lock().unwrap().baudrate, 115_199);
+        assert_eq!(replies, com_port(SET_BAUDRATE + SERVER_OFFSET, &115_199u32.to_be_bytes()));
+    }
+
+    #[test]
+    fn subnegotiation_may_be_split_between_reads() {
+        let (mut telnet, uart, _line) = session();
+        let request = com_port(SET_BAUDRATE, &9600u32.to_be_bytes());
+        let (_, first) = receive(&mut telnet, &uart, &request[..5]);
+        assert!(first.is_empty());
+        let (_, replies) = receive(&mut telnet, &uart, &request[5..]);
+        assert_eq!(replies, com_port(SET_BAUDRATE + SERVER_OFFSET, &9600u32.to_be_bytes()));
+        assert_eq!(uart.get_baudrate(), 9600);
+    }
+
+    #[test]
+    fn format_subnegotiations_change_the_uart_format() {
+        let (mut telnet, uart, line) = session();
+        let mut input = com_port(SET_DATASIZE, &[7]);
+        input.extend(com_port(SET_PARITY, &[3]));
+        input.extend(com_port(SET_STOPSIZE, &[2]));
+        let (_, replies) = receive(&mut telnet, &uart, &input);
+
+        assert_eq!(uart.get_format().to_string(), "7E2");
+        assert_eq!(line.lock().unwrap().format, uart.get_format());
+        let mut expected = com_port(SET_DATASIZE + SERVER_OFFSET, &[7]);
+        expected.extend(com_port(SET_PARITY + SERVER_OFFSET, &[3]));
+        expected.extend(com_port(SET_STOPSIZE + SERVER_OFFSET, &[2]));
+        assert_eq!(replies, expected);
+
+        // 查询和不支持的值只报告当前设置
+        let (_, replies) = receive(&mut telnet, &uart, &com_port(SET_PARITY, &[4]));
+        assert_eq!(replies, com_port(SET_PARITY + SERVER_OFFSET, &[3]));
+        assert_eq!(uart.get_format().to_string(), "7E2");
+    }
+
+    #[test]
+    fn control_requests_report_the_remembered_lines() {
+        let (mut telnet, uart, _line) = session();
+        let (_, replies) = receive(&mut telnet, &uart, &com_port(SET_CONTROL, &[CONTROL_DTR_OFF]));
+        assert_eq!(replies, com_port(SET_CONTROL + SERVER_OFFSET, &[CONTROL_DTR_OFF]));
+        let (_, replies) = receive(&mut telnet, &uart, &com_port(SET_CONTROL, &[CONTROL_DTR_REQUEST]));
+        assert_eq!(replies, com_port(SET_CONTROL + SERVER_OFFSET, &[CONTROL_DTR_OFF]));
+        let (_, replies) = receive(&mut telnet, &uart, &com_port(SET_CONTROL, &[CONTROL_BREAK_REQUEST]));
+        assert_eq!(replies, com_port(SET_CONTROL + SERVER_OFFSET, &[CONTROL_BREAK_OFF]));
+    }
+
+    #[test]
+    fn oversized_subnegotiation_is_ignored() {
+        let (mut telnet, uart, _line) = session();
+        let mut request = com_port(SIGNATURE, &[b'x'; MAX_SUBNEGOTIATION]);
+        request.extend(b"data");
+        let (received, replies) = receive(&mut telnet, &uart, &request);
+        assert_eq!(received, b"data");
+        assert!(replies.is_empty());
+    }
+}
@@ -0,0 +1,161 @@
+//! Secret obfuscation module
+//!
+//! This module encrypts values stored under secret NVS keys (such as WiFi
+//! passwords) with AES-256-CTR using the mbedtls library bundled with ESP-IDF.
+//! The key is derived from the factory MAC address in efuse and a salt fixed at
+//! compile time through the `ESPC3_SECRET_SALT` environment variable.
+//!
+//! This is obfuscation bound to the device, not strong protection: anyone who
+//! has both the firmware image and the chip can derive the same key. It only
+//! keeps credentials from showing up as plaintext in a raw flash dump.
+//!
+//! The host build has no mbedtls; there values are only masked, for the tests.
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys as sys;
+
+use crate::error::{Error, Result};
+
+/// Marker at the start of every encrypted blob (also acts as a format version)
+const MAGIC: &[u8; 4] = b"ENC1";
+
+/// Length of the random nonce stored after the marker
+const NONCE_LEN: usize = 16;
+
+/// Salt mixed into the key derivation, fixed at compile time
+#[cfg(target_os = "espidf")]
+const SALT: &str = match option_env!("ESPC3_SECRET_SALT") {
+    Some(salt) => salt,
+    None => "espc3-uart-bridge",
+};
+
+/// Check whether a stored blob was produced by [`encrypt`]
+pub fn is_encrypted(blob: &[u8]) -> bool {
+    blob.len() >= MAGIC.len() + NONCE_LEN && blob.starts_with(MAGIC)
+}
+
+/// Encrypt a secret value into a blob suitable for NVS
+pub fn encrypt(plaintext: &[u8]) -> Result<Vec<u8>> {
+    let nonce = random_nonce();
+    let mut blob = Vec::with_capacity(MAGIC.len() + NONCE_LEN + plaintext.len());
+    blob.extend_from_slice(MAGIC);
+    blob.extend_from_slice(&nonce);
+    blob.extend_from_slice(&aes_ctr(&nonce, plaintext)?);
+    Ok(blob)
+}
+
+/// Decrypt a blob produced by [`encrypt`]
+pub fn decrypt(blob: &[u8]) -> Result<Vec<u8>> {
+    if !is_encrypted(blob) {
+        return Err(Error::StorageError("Secret blob has an unknown format".to_string()));
+    }
+
+    let mut nonce = [0u8; NONCE_LEN];
+    nonce.copy_from_slice(&blob[MAGIC.len()..MAGIC.len() + NONCE_LEN]);
+    aes_ctr(&nonce, &blob[MAGIC.len() + NONCE_LEN..])
+}
+
+/// Draw a nonce from the hardware random number generator
+#[cfg(target_os = "espidf")]
+fn random_nonce() -> [u8; NONCE_LEN] {
+    let mut nonce = [0u8; NONCE_LEN];
+    unsafe {
+        sys::esp_fill_random(nonce.as_mut_ptr() as *mut core::ffi::c_void, NONCE_LEN);
+    }
+    nonce
+}
+
+/// The host build has no random number generator; a fixed nonce does for the tests
+#[cfg(not(target_os = "espidf"))]
+fn random_nonce() -> [u8; NONCE_LEN] {
+    [0; NONCE_LEN]
+}
+
+/// Derive the device-bound key from the efuse MAC and the compile-time salt
+#[cfg(target_os = "espidf")]
+fn derive_key() -> Result<[u8; 32]> {
+    let mut mac = [0u8; 6];
+    let err = unsafe { sys::esp_efuse_mac_get_default(mac.as_mut_ptr()) };
+    if err != sys::ESP_OK {
+        return Err(Error::esp(err, "Reading the efuse MAC"));
+    }
+
+    let mut input = Vec::with_capacity(mac.len() + SALT.len());
+    input.extend_from_slice(&mac);
+    input.extend_from_slice(SALT.as_bytes());
+
+    let mut key = [0u8; 32];
+    let err = unsafe { sys::mbedtls_sha256(input.as_ptr(), input.len(), key.as_mut_ptr(), 0) };
+    if err != 0 {
+        return Err(Error::StorageError(format!("Failed to derive secret key (error code: {})", err)));
+    }
+    Ok(key)
+}
+
+/// Run AES-256-CTR over `input`; encryption and decryption are the same operation
+#[cfg(target_os = "espidf")]
+fn aes_ctr(nonce: &[u8; NONCE_LEN], input: &[u8]) -> Result<Vec<u8>> {
+    let key = derive_key()?;
+    let mut output = vec![0u8; input.len()];
+    let mut counter = *nonce;
+    let mut stream_block = [0u8; 16];
+    let mut offset = 0usize;
+
+    let err = unsafe {
+        let mut ctx: sys::mbedtls_aes_context = core::mem::zeroed();
+        sys::mbedtls_aes_init(&mut ctx);
+        let mut err = sys::mbedtls_aes_setkey_enc(&mut ctx, key.as_ptr(), 256);
+        if err == 0 {
+            err = sys::mbedtls_aes_crypt_ctr(
+                &mut ctx,
+                input.len(),
+                &mut offset,
+                counter.as_mut_ptr(),
+                stream_block.as_mut_ptr(),
+                input.as_ptr(),
+                output.as_mut_ptr(),
+            );
+        }
+        sys::mbedtls_aes_free(&mut ctx);
+        err
+    };
+
+    if err != 0 {
+        return Err(Error::StorageError(format!("AES operation failed (error code: {})", err)));
+    }
+    Ok(output)
+}
+
+/// Stand-in for AES-256-CTR in the host build, which only runs the tests
+///
+/// Masks `input` with the nonce. This hides nothing, it only keeps the blobs in the
+/// device format so the storage code can be tested with `secret-storage` enabled.
+#[cfg(not(target_os = "espidf"))]
+fn aes_ctr(nonce: &[u8; NONCE_LEN], input: &[u8]) -> Result<Vec<u8>> {
+    Ok(input
+        .iter()
+        .zip(nonce.iter().cycle())
+        .map(|(byte, mask)| byte ^ mask ^ 0xa5)
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encrypted_secret_decrypts_to_the_original() {
+        let blob = encrypt(b"wifi-password").unwrap();
+        assert!(is_encrypted(&blob));
+        assert_eq!(blob.len(), MAGIC.len() + NONCE_LEN + "wifi-password".len());
+        assert!(!blob.windows(13).any(|window| window == b"wifi-password"));
+        assert_eq!(decrypt(&blob).unwrap(), b"wifi-password");
+    }
+
+    #[test]
+    fn plaintext_values_are_not_taken_for_blobs() {
+        assert!(!is_encrypted(b"wifi-password"));
+        assert!(!is_encrypted(b"ENC1short"));
+        assert!(decrypt(b"wifi-password").is_err());
+    }
+}
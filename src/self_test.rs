@@ -0,0 +1,174 @@
+//! Self test module
+//!
+//! This module provides the pattern and result types of the AT+TEST self tests, used
+//! to verify a freshly installed unit in the field without a second serial device:
+//!
+//! - LOOPBACK writes a pattern to the UART and reads it back, with TX jumpered to RX
+//! - TCP sends a pattern to the requesting client to check the network path
+//! - NVS writes a pattern to a scratch key, reads it back and removes the key
+//!
+//! The pattern is pseudo-random but fixed, so a client can check the TCP pattern
+//! against its own copy.
+
+use std::fmt;
+
+/// Seed of the test pattern
+const PATTERN_SEED: u32 = 0x2545_F491;
+
+/// Bytes written and read back by the loopback test
+pub const LOOPBACK_PATTERN_BYTES: usize = 256;
+
+/// Bytes sent to the client by the TCP test
+pub const TCP_PATTERN_BYTES: usize = 512;
+
+/// Bytes written to flash by the NVS test
+pub const NVS_PATTERN_BYTES: usize = 64;
+
+/// Pattern bytes per line of the TCP test
+const TCP_LINE_BYTES: usize = 32;
+
+/// A self test run by AT+TEST=
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SelfTest {
+    /// UART loopback with TX jumpered to RX
+    Loopback,
+    /// Pattern sent to the requesting client
+    Tcp,
+    /// Write and read back of a scratch NVS key
+    Nvs,
+}
+
+impl SelfTest {
+    /// Parse the argument of AT+TEST= (LOOPBACK, TCP or NVS)
+    pub fn parse(value: &str) -> Option<Self> {
+        match value.trim().to_ascii_uppercase().as_str() {
+            "LOOPBACK" => Some(SelfTest::Loopback),
+            "TCP" => Some(SelfTest::Tcp),
+            "NVS" => Some(SelfTest::Nvs),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SelfTest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SelfTest::Loopback => write!(f, "LOOPBACK"),
+            SelfTest::Tcp => write!(f, "TCP"),
+            SelfTest::Nvs => write!(f, "NVS"),
+        }
+    }
+}
+
+/// Generate `len` bytes of the test pattern (xorshift32)
+pub fn test_pattern(len: usize) -> Vec<u8> {
+    let mut state = PATTERN_SEED;
+    (0..len)
+        .map(|_| {
+            state ^= state << 13;
+            state ^= state >> 17;
+            state ^= state << 5;
+            (state >> 24) as u8
+        })
+        .collect()
+}
+
+/// Format a pattern as lines of hex for the TCP test, e.g. "  0000: 3A7F...\r\n"
+pub fn pattern_lines(pattern: &[u8]) -> String {
+    pattern
+        .chunks(TCP_LINE_BYTES)
+        .enumerate()
+        .map(|(index, chunk)| {
+            let hex: String = chunk.iter().map(|b| format!("{:02X}", b)).collect();
+            format!("  {:04X}: {}\r\n", index * TCP_LINE_BYTES, hex)
+        })
+        .collect()
+}
+
+/// Outcome of writing a pattern and reading it back
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TestReport {
+    /// Bytes written
+    pub sent: usize,
+    /// Bytes read back
+    pub received: usize,
+    /// Offset of the first byte read back wrong, None if all bytes read back match
+    pub first_mismatch: Option<usize>,
+}
+
+impl TestReport {
+    /// Compare the bytes read back with the bytes written
+    pub fn compare(sent: &[u8], received: &[u8]) -> Self {
+        Self {
+            sent: sent.len(),
+            received: received.len(),
+            first_mismatch: sent.iter().zip(received).position(|(a, b)| a != b),
+        }
+    }
+
+    /// Whether every byte was read back unchanged
+    pub fn passed(&self) -> bool {
+        self.sent == self.received && self.first_mismatch.is_none()
+    }
+}
+
+impl fmt::Display for TestReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sent {} bytes, received {} bytes", self.sent, self.received)?;
+        if let Some(offset) = self.first_mismatch {
+            write!(f, ", first mismatch at offset {}", offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pattern_is_fixed_and_longer_patterns_extend_shorter_ones() {
+        let pattern = test_pattern(TCP_PATTERN_BYTES);
+        assert_eq!(pattern, test_pattern(TCP_PATTERN_BYTES));
+        assert_eq!(test_pattern(LOOPBACK_PATTERN_BYTES), pattern[..LOOPBACK_PATTERN_BYTES]);
+        // 所有字节值都应出现，才能发现卡住的数据位
+        let mut seen = [false; 256];
+        pattern.iter().for_each(|&b| seen[b as usize] = true);
+        assert!(seen.iter().filter(|&&seen| seen).count() > 200);
+    }
+
+    #[test]
+    fn pattern_lines_show_offsets_and_hex() {
+        let lines = pattern_lines(&(0..40).collect::<Vec<u8>>());
+        let lines: Vec<&str> = lines.split_terminator("\r\n").collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[0].starts_with("  0000: 000102"));
+        assert_eq!(lines[0].len(), 8 + 2 * TCP_LINE_BYTES);
+        assert_eq!(lines[1], "  0020: 2021222324252627");
+    }
+
+    #[test]
+    fn report_names_the_first_wrong_byte() {
+        let sent = test_pattern(8);
+        assert!(TestReport::compare(&sent, &sent).passed());
+
+        let mut corrupted = sent.clone();
+        corrupted[5] ^= 0x80;
+        let report = TestReport::compare(&sent, &corrupted);
+        assert!(!report.passed());
+        assert_eq!(report.to_string(), "sent 8 bytes, received 8 bytes, first mismatch at offset 5");
+
+        // 读回不完整也算失败
+        let short = TestReport::compare(&sent, &sent[..6]);
+        assert!(!short.passed());
+        assert_eq!(short.to_string(), "sent 8 bytes, received 6 bytes");
+    }
+
+    #[test]
+    fn tests_are_named_case_insensitively() {
+        assert_eq!(SelfTest::parse(" loopback"), Some(SelfTest::Loopback));
+        assert_eq!(SelfTest::parse("Nvs"), Some(SelfTest::Nvs));
+        assert_eq!(SelfTest::parse("FLASH"), None);
+        assert_eq!(SelfTest::Tcp.to_string(), "TCP");
+    }
+}
@@ -0,0 +1,303 @@
+//! Status LED module
+//!
+//! This module shows the device state on a plain GPIO LED or on the WS2812 RGB LED
+//! found on most ESP32-C3 boards:
+//!
+//! - slow blink (blue): WiFi is starting
+//! - solid (green): WiFi is up, no TCP clients
+//! - fast blink (cyan): at least one TCP client is connected
+//! - double flash (red): an error, e.g. the TCP port could not be bound
+//!
+//! The WiFi manager, the client manager and the TCP server publish their state to a
+//! shared `DeviceStatus`, and the LED thread samples it, so the hooks cost no more
+//! than an atomic store.
+//!
+//! The host build has no LED to drive, so only `DeviceStatus` is available there.
+
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::gpio::{self, Output, PinDriver};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::peripheral::Peripheral;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::rmt::{FixedLengthSignal, PinState, Pulse, RmtChannel, TransmitConfig, TxRmtDriver};
+#[cfg(target_os = "espidf")]
+use log::{info, warn};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+#[cfg(target_os = "espidf")]
+use std::sync::Arc;
+#[cfg(target_os = "espidf")]
+use std::thread;
+use std::time::Duration;
+
+#[cfg(target_os = "espidf")]
+use crate::config::{StatusLedConfig, StatusLedKind};
+#[cfg(target_os = "espidf")]
+use crate::error::{Error, Result};
+#[cfg(target_os = "espidf")]
+use crate::time;
+#[cfg(target_os = "espidf")]
+use crate::uart::{FLASH_GPIOS, MAX_GPIO};
+
+/// Interval in milliseconds at which the LED is updated
+#[cfg(target_os = "espidf")]
+const TICK_MS: u64 = 50;
+
+/// FreeRTOS priority of the LED thread, below the forwarding threads
+#[cfg(target_os = "espidf")]
+const LED_PRIORITY: u32 = 1;
+
+/// WS2812 brightness, out of 255 (the bare LED is glaring at full power)
+const WS2812_BRIGHTNESS: u8 = 32;
+
+/// Device state shared by the components that publish it and the status LED
+#[derive(Debug, Default)]
+pub struct DeviceStatus {
+    /// WiFi is up (the AP is running or the station has an address)
+    wifi_up: AtomicBool,
+    /// Number of connected TCP clients
+    clients: AtomicUsize,
+    /// A fatal error was reported
+    error: AtomicBool,
+}
+
+impl DeviceStatus {
+    /// Create a new device status in the starting state
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Report whether WiFi is up
+    pub fn set_wifi_up(&self, up: bool) {
+        self.wifi_up.store(up, Ordering::Relaxed);
+    }
+
+    /// Report the number of connected TCP clients
+    pub fn set_clients(&self, count: usize) {
+        self.clients.store(count, Ordering::Relaxed);
+    }
+
+    /// Report a fatal error; it stays shown until the device restarts
+    pub fn set_error(&self) {
+        self.error.store(true, Ordering::Relaxed);
+    }
+
+    /// Get the pattern to show for the current state
+    pub fn pattern(&self) -> LedPattern {
+        // 错误优先，其次是WiFi状态，最后是客户端数量
+        if self.error.load(Ordering::Relaxed) {
+            LedPattern::Error
+        } else if !self.wifi_up.load(Ordering::Relaxed) {
+            LedPattern::Starting
+        } else if self.clients.load(Ordering::Relaxed) > 0 {
+            LedPattern::Clients
+        } else {
+            LedPattern::Idle
+        }
+    }
+}
+
+/// Blink pattern of the status LED
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LedPattern {
+    /// Slow blink: WiFi is starting
+    Starting,
+    /// Solid: WiFi is up, no clients
+    Idle,
+    /// Fast blink: at least one client is connected
+    Clients,
+    /// Double flash: an error occurred
+    Error,
+}
+
+impl LedPattern {
+    /// Whether the LED is lit `elapsed` into the pattern
+    pub fn is_on(self, elapsed: Duration) -> bool {
+        let ms = elapsed.as_millis() as u64;
+        match self {
+            LedPattern::Starting => ms % 1000 < 500,
+            LedPattern::Idle => true,
+            LedPattern::Clients => ms % 200 < 100,
+            // 亮100ms，灭100ms，亮100ms，灭700ms
+            LedPattern::Error => matches!(ms % 1000, 0..=99 | 200..=299),
+        }
+    }
+
+    /// Color of the pattern on an RGB LED, as (red, green, blue)
+    pub fn color(self) -> (u8, u8, u8) {
+        match self {
+            LedPattern::Starting => (0, 0, WS2812_BRIGHTNESS),
+            LedPattern::Idle => (0, WS2812_BRIGHTNESS, 0),
+            LedPattern::Clients => (0, WS2812_BRIGHTNESS, WS2812_BRIGHTNESS),
+            LedPattern::Error => (WS2812_BRIGHTNESS, 0, 0),
+        }
+    }
+}
+
+/// Hardware driving the LED
+#[cfg(target_os = "espidf")]
+enum LedDriver {
+    /// Plain LED, active high
+    Gpio(PinDriver<'static, gpio::AnyOutputPin, Output>),
+    /// WS2812 LED with the RMT pulses for a 0 bit and a 1 bit
+    Ws2812 {
+        tx: TxRmtDriver<'static>,
+        zero: (Pulse, Pulse),
+        one: (Pulse, Pulse),
+    },
+}
+
+#[cfg(target_os = "espidf")]
+impl LedDriver {
+    /// Light the LED in `color`, or turn it off
+    fn set(&mut self, on: bool, color: (u8, u8, u8)) -> Result<()> {
+        match self {
+            LedDriver::Gpio(pin) => {
+                let result = if on { pin.set_high() } else { pin.set_low() };
+                result.map_err(|e| Error::esp(e.code(), "Setting the status LED GPIO"))
+            }
+            LedDriver::Ws2812 { tx, zero, one } => {
+                let (r, g, b) = if on { color } else { (0, 0, 0) };
+                // WS2812按GRB顺序、高位在前接收24位颜色
+                let grb = (u32::from(g) << 16) | (u32::from(r) << 8) | u32::from(b);
+                let mut signal = FixedLengthSignal::<24>::new();
+                for i in 0..24 {
+                    let bit = grb & (1 << (23 - i)) != 0;
+                    signal
+                        .set(i, if bit { one } else { zero })
+                        .map_err(|e| Error::esp(e.code(), "Encoding the WS2812 signal"))?;
+                }
+                tx.start_blocking(&signal)
+                    .map_err(|e| Error::esp(e.code(), "Sending the WS2812 signal"))
+            }
+        }
+    }
+}
+
+/// Status LED driver
+#[cfg(target_os = "espidf")]
+pub struct StatusLed {
+    /// LED hardware
+    driver: LedDriver,
+    /// State shown on the LED
+    status: Arc<DeviceStatus>,
+}
+
+#[cfg(target_os = "espidf")]
+impl StatusLed {
+    /// Start showing `status` on the configured LED on a low-priority thread
+    ///
+    /// `channel` is the RMT channel used for a WS2812 LED. Does nothing if the status
+    /// LED is disabled.
+    pub fn start<C: RmtChannel>(
+        config: &StatusLedConfig,
+        status: Arc<DeviceStatus>,
+        channel: impl Peripheral<P = C> + 'static,
+    ) -> Result<()> {
+        if !config.enabled {
+            info!("Status LED disabled");
+            return Ok(());
+        }
+
+        let pin = config.pin as i32;
+        if pin > MAX_GPIO || FLASH_GPIOS.contains(&pin) {
+            return Err(Error::General(format!("Invalid status LED pin GPIO{}", pin)));
+        }
+        let pin = unsafe { gpio::AnyOutputPin::new(pin) };
+        let driver = match config.kind {
+            StatusLedKind::Gpio => LedDriver::Gpio(
+                PinDriver::output(pin).map_err(|e| Error::esp(e.code(), "Configuring the status LED GPIO"))?,
+            ),
+            StatusLedKind::Ws2812 => Self::ws2812(channel, pin)?,
+        };
+
+        let mut led = Self { driver, status };
+        thread::Builder::new()
+            .name("status_led".into())
+            .stack_size(3072)
+            .spawn(move || {
+                unsafe {
+                    esp_idf_sys::vTaskPrioritySet(
+                        esp_idf_sys::xTaskGetCurrentTaskHandle(),
+                        LED_PRIORITY, // 低优先级，不影响数据转发
+                    );
+                }
+                led.run();
+            })
+            .map_err(|e| Error::General(format!("Failed to spawn status LED thread: {}", e)))?;
+
+        info!("Status LED ({:?}) on GPIO{}", config.kind, config.pin);
+        Ok(())
+    }
+
+    /// Set up the RMT channel for a WS2812 LED
+    fn ws2812<C: RmtChannel>(
+        channel: impl Peripheral<P = C> + 'static,
+        pin: gpio::AnyOutputPin,
+    ) -> Result<LedDriver> {
+        // 80MHz时钟不分频，脉宽精度12.5ns
+        let config = TransmitConfig::new().clock_divider(1);
+        let tx = TxRmtDriver::new(channel, pin, &config)
+            .map_err(|e| Error::esp(e.code(), "Configuring the WS2812 RMT channel"))?;
+        let ticks_hz = tx
+            .counter_clock()
+            .map_err(|e| Error::esp(e.code(), "Reading the RMT clock"))?;
+        let pulse = |state, ns| {
+            Pulse::new_with_duration(ticks_hz, state, &Duration::from_nanos(ns))
+                .map_err(|e| Error::esp(e.code(), "Computing the WS2812 timing"))
+        };
+        let zero = (pulse(PinState::High, 350)?, pulse(PinState::Low, 800)?);
+        let one = (pulse(PinState::High, 700)?, pulse(PinState::Low, 600)?);
+        Ok(LedDriver::Ws2812 { tx, zero, one })
+    }
+
+    /// Update the LED from the device status forever
+    fn run(&mut self) {
+        // 只在输出变化时写LED，避免频繁发送RMT信号
+        let mut shown: Option<(LedPattern, bool)> = None;
+        loop {
+            let pattern = self.status.pattern();
+            let on = pattern.is_on(time::uptime());
+            if shown != Some((pattern, on)) {
+                if let Err(e) = self.driver.set(on, pattern.color()) {
+                    warn!("Failed to update status LED: {}", e);
+                }
+                shown = Some((pattern, on));
+            }
+            thread::sleep(Duration::from_millis(TICK_MS));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn errors_outrank_wifi_and_clients() {
+        let status = DeviceStatus::new();
+        assert_eq!(status.pattern(), LedPattern::Starting);
+
+        status.set_clients(2);
+        assert_eq!(status.pattern(), LedPattern::Starting);
+        status.set_wifi_up(true);
+        assert_eq!(status.pattern(), LedPattern::Clients);
+        status.set_clients(0);
+        assert_eq!(status.pattern(), LedPattern::Idle);
+
+        status.set_error();
+        assert_eq!(status.pattern(), LedPattern::Error);
+        status.set_clients(1);
+        assert_eq!(status.pattern(), LedPattern::Error);
+    }
+
+    #[test]
+    fn patterns_blink_at_their_rates() {
+        let lit = |pattern: LedPattern| -> Vec<bool> {
+            (0..10).map(|i| pattern.is_on(Duration::from_millis(i * 100 + 1000))).collect()
+        };
+        assert_eq!(lit(LedPattern::Starting), [true, true, true, true, true, false, false, false, false, false]);
+        assert_eq!(lit(LedPattern::Idle), [true; 10]);
+        assert_eq!(lit(LedPattern::Clients), [true, false, true, false, true, false, true, false, true, false]);
+        assert_eq!(lit(LedPattern::Error), [true, false, true, false, false, false, false, false, false, false]);
+    }
+}
@@ -2,23 +2,564 @@
 //!
 //! This module provides functionality for storing and retrieving configuration
 //! values in non-volatile storage (NVS).
+//!
+//! The settings are kept together in one versioned blob (see [`CONFIG_KEY`]) with a
+//! CRC, so a half-written or foreign blob is detected and ignored. Settings saved by
+//! older firmware under one key each are imported into the blob on first boot.
+//!
+//! All NVS access goes through the [`NvsStore`] trait, so the manager can run on a
+//! store other than the ESP-IDF namespace, e.g. a map in host tests. Settings that
+//! are not part of the blob use the typed accessors like [`StorageManager::get_u32`].
+//!
+//! Values stored under secret keys (see [`SECRET_KEYS`]) are obfuscated with a
+//! device-bound key when the `secret-storage` feature is enabled. This only keeps
+//! them out of plaintext flash dumps; it is not strong protection.
+//!
+//! The host build has no NVS partition, so there `StorageManager::new` fails and
+//! only stores passed to [`StorageManager::with_store`] can be used.
 
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::nvs::{EspNvs, NvsCustom, EspCustomNvsPartition};
+#[cfg(target_os = "espidf")]
+use esp_idf_sys::EspError;
 use log::{info, error, warn};
+use std::fmt;
 
+use crate::acl::AccessList;
+use crate::config::{ApBandwidth, AppConfig, SerialFormat, WiFiAuth, WiFiConfig};
 use crate::error::{Error, Result};
+use crate::self_test::TestReport;
+
+/// Key of the blob holding all settings except secrets
+pub const CONFIG_KEY: &str = "app_cfg";
+
+/// Layout version of the settings blob written by this firmware
+///
+/// Version 2 appended the UART frame delimiter, version 3 the log levels, version 4
+/// the UART replay size, version 5 moved the host name out of the WiFi settings as
+/// device name, version 6 added the WiFi security modes, version 7 the hidden SSID
+/// and bandwidth of the access point, version 8 the WiFi country and TX power and
+/// version 9 the access list; blobs of older versions are still read.
+const CONFIG_VERSION: u8 = 9;
+
+/// Largest settings blob that is read back
+const MAX_CONFIG_LEN: usize = 768;
+
+/// Key of the UART baudrate in the per-key layout of older firmware
+const LEGACY_BAUDRATE_KEY: &str = "uart_baud";
+
+/// Key of the UART character format (e.g. "8N1") in the per-key layout
+const LEGACY_FORMAT_KEY: &str = "uart_fmt";
+
+/// Key of the TCP server data port in the per-key layout
+const LEGACY_TCP_PORT_KEY: &str = "tcp_port";
+
+/// Key of the WiFi station SSID in the per-key layout
+const LEGACY_STA_SSID_KEY: &str = "sta_ssid";
+
+/// Key for storing the WiFi station password in NVS
+pub const STA_PASSWORD_KEY: &str = "sta_pass";
+
+/// Key of the WiFi access point SSID in the per-key layout
+const LEGACY_AP_SSID_KEY: &str = "ap_ssid";
+
+/// Key for storing the WiFi access point password in NVS
+pub const AP_PASSWORD_KEY: &str = "ap_pass";
+
+/// Key of the WiFi access point channel in the per-key layout
+const LEGACY_AP_CHANNEL_KEY: &str = "ap_chan";
+
+/// Key of the WiFi access point connection limit in the per-key layout
+const LEGACY_AP_MAX_CONN_KEY: &str = "ap_maxconn";
+
+/// Key of the mDNS host name in the per-key layout
+const LEGACY_HOSTNAME_KEY: &str = "hostname";
+
+/// Key of the welcome banner template in the per-key layout (empty when disabled)
+const LEGACY_BANNER_KEY: &str = "banner";
+
+/// Keys of the per-key layout, removed once imported into the blob
+const LEGACY_KEYS: [&str; 9] = [
+    LEGACY_BAUDRATE_KEY,
+    LEGACY_FORMAT_KEY,
+    LEGACY_TCP_PORT_KEY,
+    LEGACY_STA_SSID_KEY,
+    LEGACY_AP_SSID_KEY,
+    LEGACY_AP_CHANNEL_KEY,
+    LEGACY_AP_MAX_CONN_KEY,
+    LEGACY_HOSTNAME_KEY,
+    LEGACY_BANNER_KEY,
+];
+
+/// Scratch key written and removed again by the NVS self test
+const SELF_TEST_KEY: &str = "selftest";
+
+/// Boot counter, kept outside the settings blob so boots do not rewrite the settings
+pub const BOOT_COUNT_KEY: &str = "boot_count";
+
+/// Message of the last panic, written just before the device restarts
+pub const LAST_PANIC_KEY: &str = "last_panic";
+
+/// Longest panic message that is stored
+pub const MAX_PANIC_LEN: usize = 192;
+
+/// Longest welcome banner template that can be stored
+pub const MAX_BANNER_LEN: usize = 256;
+
+/// Keys whose values are treated as secrets
+pub const SECRET_KEYS: [&str; 2] = [STA_PASSWORD_KEY, AP_PASSWORD_KEY];
+
+/// Maximum length of a secret value in bytes
+const MAX_SECRET_LEN: usize = 64;
+
+/// WiFi settings kept in the settings blob (the passwords are stored as secrets)
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct StoredWiFi {
+    client_ssid: heapless::String<32>,
+    ap_ssid: heapless::String<32>,
+    ap_channel: u8,
+    ap_max_connections: u16,
+    ap_auth: WiFiAuth,
+    sta_auth: WiFiAuth,
+    ap_hidden: bool,
+    ap_bandwidth: ApBandwidth,
+    country_code: heapless::String<2>,
+    tx_power_dbm: u8,
+}
+
+impl StoredWiFi {
+    fn from_config(config: &WiFiConfig) -> Self {
+        Self {
+            client_ssid: config.client_ssid.clone(),
+            ap_ssid: config.ap_ssid.clone(),
+            ap_channel: config.ap_channel,
+            ap_max_connections: config.ap_max_connections,
+            ap_auth: config.ap_auth,
+            sta_auth: config.sta_auth,
+            ap_hidden: config.ap_hidden,
+            ap_bandwidth: config.ap_bandwidth,
+            country_code: config.country_code.clone(),
+            tx_power_dbm: config.tx_power_dbm,
+        }
+    }
+}
+
+/// Contents of the settings blob; None marks a setting that was never saved
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct StoredSettings {
+    baudrate: Option<u32>,
+    format: Option<SerialFormat>,
+    tcp_port: Option<u16>,
+    wifi: Option<StoredWiFi>,
+    /// Welcome banner template, Some(None) when disabled
+    banner: Option<Option<String>>,
+    /// UART frame delimiter, Some(None) when disabled
+    frame_delimiter: Option<Option<Vec<u8>>>,
+    /// Log levels as a `LogLevels` spec
+    log_levels: Option<String>,
+    /// Bytes of UART history replayed to new clients, 0 when disabled
+    replay_bytes: Option<u16>,
+    /// Device name set with AT+NAME
+    device_name: Option<heapless::String<32>>,
+    /// Access list set with AT+ACL
+    access_list: Option<AccessList>,
+}
+
+impl StoredSettings {
+    fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Encode as version, payload length, payload and CRC32
+    ///
+    /// Every setting is a presence byte followed by its value; strings are a length
+    /// followed by UTF-8 bytes.
+    fn encode(&self) -> Vec<u8> {
+        let mut payload = BlobWriter::default();
+        payload.put_opt(self.baudrate, |w, baudrate| w.put_u32(baudrate));
+        payload.put_opt(self.format, |w, format| w.put_str8(&format.to_string()));
+        payload.put_opt(self.tcp_port, |w, port| w.put_u16(port));
+        payload.put_opt(self.wifi.as_ref(), |w, wifi| {
+            w.put_str8(&wifi.client_ssid);
+            w.put_str8(&wifi.ap_ssid);
+            w.put_u8(wifi.ap_channel);
+            w.put_u16(wifi.ap_max_connections);
+            w.put_str8(&wifi.ap_auth.to_string());
+            w.put_str8(&wifi.sta_auth.to_string());
+            w.put_u8(wifi.ap_hidden as u8);
+            w.put_str8(&wifi.ap_bandwidth.to_string());
+            w.put_str8(&wifi.country_code);
+            w.put_u8(wifi.tx_power_dbm);
+        });
+        payload.put_opt(self.banner.as_ref(), |w, banner| {
+            w.put_opt(banner.as_deref(), |w, banner| w.put_str16(banner));
+        });
+        payload.put_opt(self.frame_delimiter.as_ref(), |w, delimiter| {
+            w.put_opt(delimiter.as_deref(), |w, delimiter| w.put_bytes8(delimiter));
+        });
+        payload.put_opt(self.log_levels.as_deref(), |w, levels| w.put_str16(levels));
+        payload.put_opt(self.replay_bytes, |w, bytes| w.put_u16(bytes));
+        payload.put_opt(self.device_name.as_deref(), |w, name| w.put_str8(name));
+        payload.put_opt(self.access_list.as_ref(), |w, list| w.put_bytes8(&list.encode()));
+
+        let mut blob = BlobWriter::default();
+        blob.put_u8(CONFIG_VERSION);
+        blob.put_u16(payload.0.len() as u16);
+        blob.0.extend_from_slice(&payload.0);
+        let crc = crc32(&blob.0);
+        blob.put_u32(crc);
+        blob.0
+    }
+
+    /// Decode a blob written by `encode`, describing why it was rejected
+    fn decode(blob: &[u8]) -> std::result::Result<Self, String> {
+        if blob.len() < 3 + 4 {
+            return Err(format!("blob of {} bytes is too short", blob.len()));
+        }
+        let version = blob[0];
+        let len = u16::from_le_bytes([blob[1], blob[2]]) as usize;
+        if blob.len() != 3 + len + 4 {
+            return Err(format!("length {} does not match blob of {} bytes", len, blob.len()));
+        }
+        let (data, crc) = blob.split_at(3 + len);
+        if crc32(data) != u32::from_le_bytes([crc[0], crc[1], crc[2], crc[3]]) {
+            return Err("CRC mismatch".to_string());
+        }
+        // 新版本固件写入的布局无法解析，使用默认值
+        if version == 0 || version > CONFIG_VERSION {
+            return Err(format!("unsupported version {}", version));
+        }
+
+        let mut r = BlobReader::new(&data[3..]);
+        // 版本5之前设备名称作为主机名保存在WiFi设置中
+        let mut wifi_hostname = None;
+        let settings = (|| {
+            Some(Self {
+                baudrate: r.get_opt(|r| r.get_u32())?,
+                format: r
+                    .get_opt(|r| r.get_str8().map(|format| SerialFormat::parse_compact(&format)))?
+                    .flatten(),
+                tcp_port: r.get_opt(|r| r.get_u16())?,
+                wifi: r.get_opt(|r| {
+                    let defaults = WiFiConfig::default();
+                    let mut wifi = StoredWiFi {
+                        client_ssid: heapless::String::try_from(r.get_str8()?.as_str()).ok()?,
+                        ap_ssid: heapless::String::try_from(r.get_str8()?.as_str()).ok()?,
+                        ap_channel: r.get_u8()?,
+                        ap_max_connections: r.get_u16()?,
+                        ap_auth: defaults.ap_auth,
+                        sta_auth: defaults.sta_auth,
+                        ap_hidden: defaults.ap_hidden,
+                        ap_bandwidth: defaults.ap_bandwidth,
+                        country_code: defaults.country_code.clone(),
+                        tx_power_dbm: defaults.tx_power_dbm,
+                    };
+                    if version < 5 {
+                        wifi_hostname = Some(heapless::String::try_from(r.get_str8()?.as_str()).ok()?);
+                    }
+                    // 版本6开始保存安全模式，无法识别的模式使用默认值
+                    if version >= 6 {
+                        wifi.ap_auth = WiFiAuth::parse(&r.get_str8()?).unwrap_or(defaults.ap_auth);
+                        wifi.sta_auth = WiFiAuth::parse(&r.get_str8()?).unwrap_or(defaults.sta_auth);
+                    }
+                    if version >= 7 {
+                        wifi.ap_hidden = r.get_u8()? != 0;
+                        wifi.ap_bandwidth = ApBandwidth::parse(&r.get_str8()?).unwrap_or(defaults.ap_bandwidth);
+                    }
+                    if version >= 8 {
+                        wifi.country_code = heapless::String::try_from(r.get_str8()?.as_str()).ok()?;
+                        wifi.tx_power_dbm = r.get_u8()?;
+                    }
+                    Some(wifi)
+                })?,
+                banner: r.get_opt(|r| r.get_opt(|r| r.get_str16()))?,
+                // 版本1没有分隔符字段
+                frame_delimiter: if version >= 2 {
+                    r.get_opt(|r| r.get_opt(|r| r.get_bytes8()))?
+                } else {
+                    None
+                },
+                // 版本3开始保存日志级别
+                log_levels: if version >= 3 {
+                    r.get_opt(|r| r.get_str16())?
+                } else {
+                    None
+                },
+                replay_bytes: if version >= 4 {
+                    r.get_opt(|r| r.get_u16())?
+                } else {
+                    None
+                },
+                device_name: if version >= 5 {
+                    r.get_opt(|r| heapless::String::try_from(r.get_str8()?.as_str()).ok())?
+                } else {
+                    wifi_hostname.take()
+                },
+                access_list: if version >= 9 {
+                    r.get_opt(|r| AccessList::decode(&r.get_bytes8()?))?
+                } else {
+                    None
+                },
+            })
+        })();
+        settings.ok_or_else(|| "malformed payload".to_string())
+    }
+}
+
+/// Appends little-endian values to a settings blob
+#[derive(Default)]
+struct BlobWriter(Vec<u8>);
 
-/// Key for storing the UART baudrate in NVS
-const BAUDRATE_KEY: &str = "uart_baud";
+impl BlobWriter {
+    fn put_u8(&mut self, value: u8) {
+        self.0.push(value);
+    }
+
+    fn put_u16(&mut self, value: u16) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    fn put_u32(&mut self, value: u32) {
+        self.0.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// String of up to 255 bytes
+    fn put_str8(&mut self, value: &str) {
+        let bytes = &value.as_bytes()[..value.len().min(u8::MAX as usize)];
+        self.put_u8(bytes.len() as u8);
+        self.0.extend_from_slice(bytes);
+    }
+
+    /// Bytes of up to 255
+    fn put_bytes8(&mut self, value: &[u8]) {
+        let bytes = &value[..value.len().min(u8::MAX as usize)];
+        self.put_u8(bytes.len() as u8);
+        self.0.extend_from_slice(bytes);
+    }
+
+    /// String of up to 65535 bytes
+    fn put_str16(&mut self, value: &str) {
+        let bytes = &value.as_bytes()[..value.len().min(u16::MAX as usize)];
+        self.put_u16(bytes.len() as u16);
+        self.0.extend_from_slice(bytes);
+    }
+
+    fn put_opt<T>(&mut self, value: Option<T>, put: impl FnOnce(&mut Self, T)) {
+        match value {
+            Some(value) => {
+                self.put_u8(1);
+                put(self, value);
+            }
+            None => self.put_u8(0),
+        }
+    }
+}
+
+/// Reads the values written by `BlobWriter`; every getter returns None past the end
+struct BlobReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> BlobReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        Self { data, pos: 0 }
+    }
+
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.data.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn get_u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?[0])
+    }
+
+    fn get_u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn get_u32(&mut self) -> Option<u32> {
+        Some(u32::from_le_bytes(self.take(4)?.try_into().ok()?))
+    }
+
+    fn get_str8(&mut self) -> Option<String> {
+        let len = self.get_u8()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    fn get_bytes8(&mut self) -> Option<Vec<u8>> {
+        let len = self.get_u8()? as usize;
+        Some(self.take(len)?.to_vec())
+    }
+
+    fn get_str16(&mut self) -> Option<String> {
+        let len = self.get_u16()? as usize;
+        String::from_utf8(self.take(len)?.to_vec()).ok()
+    }
+
+    /// Read a presence byte and, if set, the value (None if the data is malformed)
+    fn get_opt<T>(&mut self, get: impl FnOnce(&mut Self) -> Option<T>) -> Option<Option<T>> {
+        match self.get_u8()? {
+            0 => Some(None),
+            1 => get(self).map(Some),
+            _ => None,
+        }
+    }
+}
+
+/// CRC32 (IEEE) of a settings blob, computed by the ROM routine
+#[cfg(target_os = "espidf")]
+fn crc32(data: &[u8]) -> u32 {
+    unsafe { esp_idf_sys::esp_rom_crc32_le(0, data.as_ptr(), data.len() as u32) }
+}
+
+/// CRC32 (IEEE) of a settings blob, computed bitwise like the ROM routine
+#[cfg(not(target_os = "espidf"))]
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &byte in data {
+        crc ^= u32::from(byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 { (crc >> 1) ^ 0xEDB8_8320 } else { crc >> 1 };
+        }
+    }
+    !crc
+}
+
+/// Failed access to an `NvsStore`, with the ESP-IDF error code (`esp_err_t`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct NvsError(pub i32);
+
+impl NvsError {
+    /// ESP-IDF error code
+    pub fn code(&self) -> i32 {
+        self.0
+    }
+}
+
+impl fmt::Display for NvsError {
+    #[cfg(target_os = "espidf")]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match EspError::from(self.0) {
+            Some(err) => write!(f, "{}", err),
+            None => write!(f, "ESP_OK"),
+        }
+    }
+
+    // 主机上没有ESP-IDF的错误名称表
+    #[cfg(not(target_os = "espidf"))]
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ESP-IDF error {:#x}", self.0)
+    }
+}
+
+impl std::error::Error for NvsError {}
+
+#[cfg(target_os = "espidf")]
+impl From<EspError> for NvsError {
+    fn from(err: EspError) -> Self {
+        NvsError(err.code())
+    }
+}
+
+/// Key/value store behind `StorageManager`
+///
+/// Mirrors the accessors of `EspNvs`: reads return None for a missing key, and
+/// `get_str` and `get_blob` fail if the value does not fit into the buffer.
+pub trait NvsStore: Send {
+    /// Read an 8-bit number
+    fn get_u8(&self, key: &str) -> std::result::Result<Option<u8>, NvsError>;
+    /// Read a 16-bit number
+    fn get_u16(&self, key: &str) -> std::result::Result<Option<u16>, NvsError>;
+    /// Read a 32-bit number
+    fn get_u32(&self, key: &str) -> std::result::Result<Option<u32>, NvsError>;
+    /// Write a 32-bit number
+    fn set_u32(&mut self, key: &str, value: u32) -> std::result::Result<(), NvsError>;
+    /// Read a string into `buf`, which must have room for the terminating zero
+    fn get_str<'a>(&self, key: &str, buf: &'a mut [u8]) -> std::result::Result<Option<&'a str>, NvsError>;
+    /// Write a string
+    fn set_str(&mut self, key: &str, value: &str) -> std::result::Result<(), NvsError>;
+    /// Read a blob into `buf`
+    fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> std::result::Result<Option<&'a [u8]>, NvsError>;
+    /// Write a blob
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> std::result::Result<(), NvsError>;
+    /// Remove a key, returning whether it existed
+    fn remove(&mut self, key: &str) -> std::result::Result<bool, NvsError>;
+    /// Remove every key of the store
+    fn erase_all(&mut self) -> std::result::Result<(), NvsError>;
+}
+
+#[cfg(target_os = "espidf")]
+impl NvsStore for EspNvs<NvsCustom> {
+    fn get_u8(&self, key: &str) -> std::result::Result<Option<u8>, NvsError> {
+        EspNvs::get_u8(self, key).map_err(NvsError::from)
+    }
+
+    fn get_u16(&self, key: &str) -> std::result::Result<Option<u16>, NvsError> {
+        EspNvs::get_u16(self, key).map_err(NvsError::from)
+    }
+
+    fn get_u32(&self, key: &str) -> std::result::Result<Option<u32>, NvsError> {
+        EspNvs::get_u32(self, key).map_err(NvsError::from)
+    }
+
+    fn set_u32(&mut self, key: &str, value: u32) -> std::result::Result<(), NvsError> {
+        EspNvs::set_u32(self, key, value).map_err(NvsError::from)
+    }
+
+    fn get_str<'a>(&self, key: &str, buf: &'a mut [u8]) -> std::result::Result<Option<&'a str>, NvsError> {
+        EspNvs::get_str(self, key, buf).map_err(NvsError::from)
+    }
+
+    fn set_str(&mut self, key: &str, value: &str) -> std::result::Result<(), NvsError> {
+        EspNvs::set_str(self, key, value).map_err(NvsError::from)
+    }
+
+    fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> std::result::Result<Option<&'a [u8]>, NvsError> {
+        EspNvs::get_blob(self, key, buf).map_err(NvsError::from)
+    }
+
+    fn set_blob(&mut self, key: &str, value: &[u8]) -> std::result::Result<(), NvsError> {
+        EspNvs::set_blob(self, key, value).map_err(NvsError::from)
+    }
+
+    fn remove(&mut self, key: &str) -> std::result::Result<bool, NvsError> {
+        EspNvs::remove(self, key).map_err(NvsError::from)
+    }
+
+    fn erase_all(&mut self) -> std::result::Result<(), NvsError> {
+        esp_idf_sys::esp!(unsafe { esp_idf_sys::nvs_erase_all(self.handle()) })?;
+        Ok(esp_idf_sys::esp!(unsafe { esp_idf_sys::nvs_commit(self.handle()) })?)
+    }
+}
+
+/// Storage error for a failed NVS access, keeping the ESP-IDF error code
+fn nvs_error(action: &str, key: &str, err: NvsError) -> Error {
+    Error::StorageError(format!(
+        "Failed to {} {} in NVS: {} (error code: {})",
+        action,
+        key,
+        err,
+        err.code()
+    ))
+}
 
 /// Storage manager for persistent configuration
 pub struct StorageManager {
-    /// NVS handle
-    nvs: EspNvs<NvsCustom>,
+    /// Key/value store, the application namespace of the NVS partition on the device
+    nvs: Box<dyn NvsStore>,
+    /// Settings as last read from or written to the settings blob
+    settings: StoredSettings,
 }
 
 impl StorageManager {
     /// Create a new storage manager
+    ///
+    /// Loads the settings blob, importing the settings of the older per-key layout
+    /// if no blob exists yet.
+    #[cfg(target_os = "espidf")]
     pub fn new() -> Result<Self> {
         // Use a custom NVS partition instead of the default one
         let nvs_partition = EspCustomNvsPartition::take("nvs")
@@ -28,41 +569,1073 @@ impl StorageManager {
         let nvs = EspNvs::new(nvs_partition, "uart_cfg", true)
             .map_err(|e| Error::StorageError(format!("Failed to open NVS namespace: {}", e)))?;
 
-        Ok(Self { nvs })
+        Ok(Self::with_store(Box::new(nvs)))
     }
 
-    /// Save the UART baudrate to NVS
-    pub fn save_baudrate(&mut self, baudrate: u32) -> Result<()> {
-        match self.nvs.set_u32(BAUDRATE_KEY, baudrate) {
-            Ok(_) => {
-                info!("Baudrate {} saved to flash", baudrate);
-                Ok(())
-            },
-            Err(e) => {
-                error!("Failed to save baudrate to NVS: {}", e);
-                Err(Error::StorageError(format!("Failed to save baudrate to NVS: {}", e)))
+    /// Create a new storage manager
+    #[cfg(not(target_os = "espidf"))]
+    pub fn new() -> Result<Self> {
+        Err(Error::StorageError("No NVS partition in the host build".to_string()))
+    }
+
+    /// Create a storage manager on top of `store`
+    ///
+    /// Loads the settings like `new`.
+    pub fn with_store(store: Box<dyn NvsStore>) -> Self {
+        let mut storage = Self {
+            nvs: store,
+            settings: StoredSettings::default(),
+        };
+
+        // 将升级前以明文保存的秘密值重新加密
+        #[cfg(feature = "secret-storage")]
+        storage.migrate_secrets();
+
+        storage.load_settings();
+        storage
+    }
+
+    /// Erase every key stored in the application namespace
+    ///
+    /// Covers all settings written by this manager, including keys added later,
+    /// so the next boot falls back to the compiled-in defaults.
+    pub fn erase_all(&mut self) -> Result<()> {
+        if let Err(e) = self.nvs.erase_all() {
+            error!("Failed to erase NVS namespace (error code: {})", e.code());
+            return Err(Error::esp(e.code(), "Erasing the NVS namespace"));
+        }
+        self.settings = StoredSettings::default();
+        info!("All stored settings erased");
+        Ok(())
+    }
+
+    /// Save every persisted setting of `config`
+    ///
+    /// Covers the UART baudrate, format, frame delimiter and replay size, the data
+    /// port, the welcome banner, the access list, the device name and the WiFi
+    /// settings. The WiFi
+    /// passwords are stored as secrets.
+    ///
+    /// The secrets are written first and the settings blob last, so the new
+    /// configuration only takes effect once the blob is written. If a write fails
+    /// the previous secrets are written back and the cached settings are kept.
+    pub fn save_app_config(&mut self, config: &AppConfig) -> Result<()> {
+        let settings = StoredSettings {
+            baudrate: Some(config.uart.baudrate),
+            format: Some(config.uart.format),
+            tcp_port: Some(config.tcp_server.port),
+            wifi: Some(StoredWiFi::from_config(&config.wifi)),
+            banner: Some(config.tcp_server.welcome_message.clone()),
+            frame_delimiter: Some(config.uart.frame_delimiter.clone()),
+            // 日志级别不属于AppConfig，保留已保存的值
+            log_levels: self.settings.log_levels.clone(),
+            replay_bytes: Some(config.uart.replay_bytes.min(u16::MAX as usize) as u16),
+            device_name: Some(config.device_name.clone()),
+            access_list: Some(config.tcp_server.access_list.clone()),
+        };
+
+        let previous = SECRET_KEYS.map(|key| self.read_secret(key));
+        let result = self
+            .save_secret(STA_PASSWORD_KEY, &config.wifi.client_password)
+            .and_then(|_| self.save_secret(AP_PASSWORD_KEY, &config.wifi.ap_password))
+            .and_then(|_| self.set_blob(CONFIG_KEY, &settings.encode()));
+        if let Err(e) = result {
+            error!("Failed to save Configuration: {}", e);
+            // 恢复旧的秘密值，使其与仍然有效的设置块一致
+            for (key, value) in SECRET_KEYS.into_iter().zip(previous) {
+                let restored = match value {
+                    Some(value) => self.save_secret(key, &value),
+                    None => self.remove(key).map(|_| ()),
+                };
+                if let Err(e) = restored {
+                    warn!("Failed to restore secret {}: {}", key, e);
+                }
             }
+            return Err(e);
+        }
+        self.settings = settings;
+        Ok(())
+    }
+
+    /// Load the default configuration with the saved settings applied
+    ///
+    /// Returns None if no settings were saved.
+    pub fn load_app_config(&self) -> Option<AppConfig> {
+        if self.settings.is_empty() {
+            return None;
+        }
+        let mut config = AppConfig::default();
+        if let Some(baudrate) = self.settings.baudrate {
+            config.uart.baudrate = baudrate;
+        }
+        if let Some(format) = self.settings.format {
+            config.uart.format = format;
+        }
+        if let Some(delimiter) = &self.settings.frame_delimiter {
+            config.uart.frame_delimiter = delimiter.clone();
+        }
+        if let Some(bytes) = self.settings.replay_bytes {
+            config.uart.replay_bytes = bytes as usize;
+        }
+        if let Some(port) = self.read_tcp_port() {
+            config.tcp_server.port = port;
+        }
+        if let Some(banner) = &self.settings.banner {
+            config.tcp_server.welcome_message = banner.clone();
         }
+        if let Some(list) = &self.settings.access_list {
+            config.tcp_server.access_list = list.clone();
+        }
+        if let Some(wifi) = self.read_wifi_config() {
+            config.wifi = wifi;
+        }
+        if let Some(name) = &self.settings.device_name {
+            config.device_name = name.clone();
+        }
+        Some(config)
+    }
+
+    /// Save the UART baudrate to NVS
+    pub fn save_baudrate(&mut self, baudrate: u32) -> Result<()> {
+        self.settings.baudrate = Some(baudrate);
+        self.write_settings("Baudrate")?;
+        info!("Baudrate {} saved to flash", baudrate);
+        Ok(())
     }
 
     /// Read the UART baudrate from NVS
-    /// Returns None if the baudrate is not found or invalid
+    /// Returns None if the baudrate is not found
     pub fn read_baudrate(&self) -> Option<u32> {
-        match self.nvs.get_u32(BAUDRATE_KEY) {
-            Ok(Some(baudrate)) => {
-                info!("Read baudrate {} from flash", baudrate);
-                Some(baudrate)
-            },
-            Ok(None) => {
-                // Key doesn't exist yet
-                warn!("No baudrate found in NVS");
-                None
+        self.settings.baudrate
+    }
+
+    /// Save the TCP server data port to NVS
+    pub fn save_tcp_port(&mut self, port: u16) -> Result<()> {
+        self.settings.tcp_port = Some(port);
+        self.write_settings("TCP port")?;
+        info!("TCP port {} saved to flash", port);
+        Ok(())
+    }
+
+    /// Read the TCP server data port from NVS
+    /// Returns None if the port is not found or zero
+    pub fn read_tcp_port(&self) -> Option<u16> {
+        self.settings.tcp_port.filter(|&port| port != 0)
+    }
+
+    /// Save the welcome banner template to NVS (None disables the banner)
+    pub fn save_banner(&mut self, banner: Option<&str>) -> Result<()> {
+        self.settings.banner = Some(banner.map(str::to_string));
+        self.write_settings("Welcome banner")?;
+        info!("Welcome banner saved to flash");
+        Ok(())
+    }
+
+    /// Read the welcome banner template from NVS
+    ///
+    /// Returns None if no banner was saved, and Some(None) if it was disabled.
+    pub fn read_banner(&self) -> Option<Option<String>> {
+        self.settings.banner.clone()
+    }
+
+    /// Save the access list to NVS
+    pub fn save_access_list(&mut self, list: &AccessList) -> Result<()> {
+        self.settings.access_list = Some(list.clone());
+        self.write_settings("Access list")?;
+        info!("Access list with {} rules saved to flash", list.rules().len());
+        Ok(())
+    }
+
+    /// Read the access list from NVS
+    /// Returns None if no access list was saved
+    pub fn read_access_list(&self) -> Option<AccessList> {
+        self.settings.access_list.clone()
+    }
+
+    /// Save the UART character format to NVS
+    pub fn save_format(&mut self, format: &SerialFormat) -> Result<()> {
+        self.settings.format = Some(*format);
+        self.write_settings("Serial format")?;
+        info!("Serial format {} saved to flash", format);
+        Ok(())
+    }
+
+    /// Read the UART character format from NVS
+    /// Returns None if the format is not found
+    pub fn read_format(&self) -> Option<SerialFormat> {
+        self.settings.format
+    }
+
+    /// Save the UART frame delimiter to NVS (None disables delimiter framing)
+    pub fn save_frame_delimiter(&mut self, delimiter: Option<&[u8]>) -> Result<()> {
+        self.settings.frame_delimiter = Some(delimiter.map(<[u8]>::to_vec));
+        self.write_settings("Frame delimiter")?;
+        info!("Frame delimiter saved to flash");
+        Ok(())
+    }
+
+    /// Read the UART frame delimiter from NVS
+    ///
+    /// Returns None if no delimiter was saved, and Some(None) if it was disabled.
+    pub fn read_frame_delimiter(&self) -> Option<Option<Vec<u8>>> {
+        self.settings.frame_delimiter.clone()
+    }
+
+    /// Save the size of the UART history replayed to new clients (0 disables it)
+    pub fn save_replay_bytes(&mut self, bytes: u16) -> Result<()> {
+        self.settings.replay_bytes = Some(bytes);
+        self.write_settings("Replay size")?;
+        info!("Replay size {} saved to flash", bytes);
+        Ok(())
+    }
+
+    /// Read the size of the UART history replayed to new clients
+    /// Returns None if no size was saved
+    pub fn read_replay_bytes(&self) -> Option<u16> {
+        self.settings.replay_bytes
+    }
+
+    /// Save the device name to NVS
+    pub fn save_device_name(&mut self, name: &str) -> Result<()> {
+        let name = heapless::String::try_from(name)
+            .map_err(|_| Error::StorageError(format!("Device name {} is too long", name)))?;
+        self.settings.device_name = Some(name);
+        self.write_settings("Device name")?;
+        info!("Device name saved to flash");
+        Ok(())
+    }
+
+    /// Read the device name from NVS
+    /// Returns None if no name was saved
+    pub fn read_device_name(&self) -> Option<heapless::String<32>> {
+        self.settings.device_name.clone()
+    }
+
+    /// Save the log levels to NVS as a `LogLevels` spec
+    pub fn save_log_levels(&mut self, spec: &str) -> Result<()> {
+        self.settings.log_levels = Some(spec.to_string());
+        self.write_settings("Log levels")?;
+        info!("Log levels {} saved to flash", spec);
+        Ok(())
+    }
+
+    /// Read the log levels from NVS
+    /// Returns None if no levels were saved
+    pub fn read_log_levels(&self) -> Option<String> {
+        self.settings.log_levels.clone()
+    }
+
+    /// Save the WiFi access point and station settings to NVS
+    pub fn save_wifi_config(&mut self, config: &WiFiConfig) -> Result<()> {
+        self.settings.wifi = Some(StoredWiFi::from_config(config));
+        self.write_settings("WiFi config")?;
+        self.save_secret(STA_PASSWORD_KEY, &config.client_password)?;
+        self.save_secret(AP_PASSWORD_KEY, &config.ap_password)?;
+        info!("WiFi config saved to flash");
+        Ok(())
+    }
+
+    /// Read the WiFi access point and station settings from NVS
+    ///
+    /// Returns None if no WiFi settings were saved. Passwords that are missing or do
+    /// not fit their configured length keep their default values.
+    pub fn read_wifi_config(&self) -> Option<WiFiConfig> {
+        let Some(wifi) = &self.settings.wifi else {
+            warn!("No WiFi config found in NVS");
+            return None;
+        };
+
+        let mut config = WiFiConfig {
+            client_ssid: wifi.client_ssid.clone(),
+            ap_ssid: wifi.ap_ssid.clone(),
+            ap_channel: wifi.ap_channel,
+            ap_max_connections: wifi.ap_max_connections,
+            ap_auth: wifi.ap_auth,
+            sta_auth: wifi.sta_auth,
+            ap_hidden: wifi.ap_hidden,
+            ap_bandwidth: wifi.ap_bandwidth,
+            country_code: wifi.country_code.clone(),
+            tx_power_dbm: wifi.tx_power_dbm,
+            ..WiFiConfig::default()
+        };
+        if let Some(password) = self.read_secret(STA_PASSWORD_KEY) {
+            match heapless::String::try_from(password.as_str()) {
+                Ok(password) => config.client_password = password,
+                Err(_) => warn!("Stored station password is too long, using default"),
+            }
+        }
+        if let Some(password) = self.read_secret(AP_PASSWORD_KEY) {
+            match heapless::String::try_from(password.as_str()) {
+                Ok(password) => config.ap_password = password,
+                Err(_) => warn!("Stored access point password is too long, using default"),
+            }
+        }
+
+        info!("Read WiFi config from flash");
+        Some(config)
+    }
+
+    /// Write `pattern` to a scratch key, read it back and remove the key (AT+TEST=NVS)
+    ///
+    /// The settings are not touched. The key is removed even if the read fails.
+    pub fn self_test(&mut self, pattern: &[u8]) -> Result<TestReport> {
+        self.set_blob(SELF_TEST_KEY, pattern)?;
+        let mut buf = vec![0u8; pattern.len()];
+        let read = self
+            .get_blob(SELF_TEST_KEY, &mut buf)
+            .map(|blob| TestReport::compare(pattern, blob.unwrap_or_default()));
+        if let Err(e) = self.remove(SELF_TEST_KEY) {
+            warn!("Failed to remove test key: {}", e);
+        }
+        read
+    }
+
+    /// Read a number
+    /// Returns None if the key is not found
+    pub fn get_u32(&self, key: &str) -> Result<Option<u32>> {
+        self.nvs.get_u32(key).map_err(|e| nvs_error("read", key, e))
+    }
+
+    /// Write a number under its own key
+    pub fn set_u32(&mut self, key: &str, value: u32) -> Result<()> {
+        self.nvs.set_u32(key, value).map_err(|e| nvs_error("write", key, e))
+    }
+
+    /// Read a string of at most `N` bytes
+    /// Returns None if the key is not found; a longer value is an error, not truncated
+    pub fn get_str<const N: usize>(&self, key: &str) -> Result<Option<heapless::String<N>>> {
+        // 多留一个字节给NVS的结尾0
+        let mut buf = vec![0u8; N + 1];
+        match self.nvs.get_str(key, &mut buf) {
+            Ok(Some(value)) => heapless::String::try_from(value)
+                .map(Some)
+                .map_err(|_| Error::StorageError(format!("Value of {} in NVS is too long", key))),
+            Ok(None) => Ok(None),
+            Err(e) => Err(nvs_error("read", key, e)),
+        }
+    }
+
+    /// Write a string under its own key
+    pub fn set_str(&mut self, key: &str, value: &str) -> Result<()> {
+        self.nvs.set_str(key, value).map_err(|e| nvs_error("write", key, e))
+    }
+
+    /// Read a blob into `buf`
+    /// Returns None if the key is not found; a blob larger than `buf` is an error
+    pub fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> Result<Option<&'a [u8]>> {
+        self.nvs.get_blob(key, buf).map_err(|e| nvs_error("read", key, e))
+    }
+
+    /// Write a blob under its own key
+    pub fn set_blob(&mut self, key: &str, value: &[u8]) -> Result<()> {
+        self.nvs.set_blob(key, value).map_err(|e| nvs_error("write", key, e))
+    }
+
+    /// Remove a key, returning whether it existed
+    pub fn remove(&mut self, key: &str) -> Result<bool> {
+        self.nvs.remove(key).map_err(|e| nvs_error("remove", key, e))
+    }
+
+    /// Write the cached settings to the settings blob
+    fn write_settings(&mut self, what: &str) -> Result<()> {
+        let blob = self.settings.encode();
+        self.set_blob(CONFIG_KEY, &blob).map_err(|e| {
+            error!("Failed to save {}: {}", what, e);
+            e
+        })
+    }
+
+    /// Read the settings blob, or import the per-key settings of older firmware
+    ///
+    /// A corrupt blob or one written by newer firmware is ignored, so the defaults
+    /// are used until the settings are saved again.
+    fn load_settings(&mut self) {
+        let mut buf = vec![0u8; MAX_CONFIG_LEN];
+        match self.get_blob(CONFIG_KEY, &mut buf) {
+            Ok(Some(blob)) => match StoredSettings::decode(blob) {
+                Ok(settings) => {
+                    info!("Read settings from flash");
+                    self.settings = settings;
+                }
+                Err(reason) => warn!("Ignoring stored settings ({}), using defaults", reason),
             },
+            Ok(None) => self.import_legacy_settings(),
+            Err(e) => warn!("{}, using defaults", e),
+        }
+    }
+
+    /// Move the settings of the per-key layout into the settings blob
+    fn import_legacy_settings(&mut self) {
+        let settings = self.read_legacy_settings();
+        if settings.is_empty() {
+            return;
+        }
+
+        self.settings = settings;
+        if let Err(e) = self.write_settings("Imported settings") {
+            // 保留旧的键，下次启动时重新导入
+            warn!("Failed to import per-key settings: {}", e);
+            return;
+        }
+        for key in LEGACY_KEYS {
+            if let Err(e) = self.remove(key) {
+                warn!("Failed to remove imported key: {}", e);
+            }
+        }
+        info!("Imported per-key settings into the settings blob");
+    }
+
+    /// Read the settings stored one per key by older firmware
+    fn read_legacy_settings(&self) -> StoredSettings {
+        let mut settings = StoredSettings {
+            baudrate: self.get_u32(LEGACY_BAUDRATE_KEY).ok().flatten(),
+            format: self
+                .read_string::<8>(LEGACY_FORMAT_KEY)
+                .and_then(|format| SerialFormat::parse_compact(&format)),
+            tcp_port: self.nvs.get_u16(LEGACY_TCP_PORT_KEY).ok().flatten(),
+            wifi: None,
+            banner: self
+                .read_string::<MAX_BANNER_LEN>(LEGACY_BANNER_KEY)
+                .map(|banner| (!banner.is_empty()).then(|| banner.to_string())),
+            frame_delimiter: None,
+            log_levels: None,
+            replay_bytes: None,
+            device_name: self.read_string::<32>(LEGACY_HOSTNAME_KEY),
+            access_list: None,
+        };
+
+        let client_ssid = self.read_string::<32>(LEGACY_STA_SSID_KEY);
+        let ap_ssid = self.read_string::<32>(LEGACY_AP_SSID_KEY);
+        if client_ssid.is_some() || ap_ssid.is_some() {
+            let defaults = WiFiConfig::default();
+            settings.wifi = Some(StoredWiFi {
+                client_ssid: client_ssid.unwrap_or(defaults.client_ssid),
+                ap_ssid: ap_ssid.unwrap_or(defaults.ap_ssid),
+                ap_channel: self
+                    .nvs
+                    .get_u8(LEGACY_AP_CHANNEL_KEY)
+                    .ok()
+                    .flatten()
+                    .unwrap_or(defaults.ap_channel),
+                ap_max_connections: self
+                    .nvs
+                    .get_u16(LEGACY_AP_MAX_CONN_KEY)
+                    .ok()
+                    .flatten()
+                    .unwrap_or(defaults.ap_max_connections),
+                ap_auth: defaults.ap_auth,
+                sta_auth: defaults.sta_auth,
+                ap_hidden: defaults.ap_hidden,
+                ap_bandwidth: defaults.ap_bandwidth,
+                country_code: defaults.country_code,
+                tx_power_dbm: defaults.tx_power_dbm,
+            });
+        }
+        settings
+    }
+
+    /// Read a string that must fit into a `heapless::String<N>`, logging failures
+    fn read_string<const N: usize>(&self, key: &str) -> Option<heapless::String<N>> {
+        self.get_str::<N>(key).unwrap_or_else(|e| {
+            warn!("{}", e);
+            None
+        })
+    }
+
+    /// Save a secret value to NVS
+    ///
+    /// With the `secret-storage` feature the value is obfuscated before it is written,
+    /// otherwise it is stored as a plain string.
+    pub fn save_secret(&mut self, key: &str, value: &str) -> Result<()> {
+        if value.len() > MAX_SECRET_LEN {
+            return Err(Error::StorageError(format!("Secret for key {} is too long", key)));
+        }
+
+        #[cfg(feature = "secret-storage")]
+        let result = crate::secret::encrypt(value.as_bytes()).and_then(|blob| self.replace_with_blob(key, &blob));
+        #[cfg(not(feature = "secret-storage"))]
+        let result = self.set_str(key, value);
+
+        result.map_err(|e| {
+            error!("Failed to save secret: {}", e);
+            e
+        })?;
+        info!("Secret {} saved to flash", key);
+        Ok(())
+    }
+
+    /// Read a secret value from NVS
+    /// Returns None if the key is not found or cannot be decoded
+    pub fn read_secret(&self, key: &str) -> Option<String> {
+        #[cfg(feature = "secret-storage")]
+        {
+            let mut buf = [0u8; 4 + 16 + MAX_SECRET_LEN];
+            match self.get_blob(key, &mut buf) {
+                Ok(Some(blob)) => {
+                    return match crate::secret::decrypt(blob).map(String::from_utf8) {
+                        Ok(Ok(value)) => Some(value),
+                        _ => {
+                            warn!("Failed to decode secret {} from NVS", key);
+                            None
+                        }
+                    };
+                }
+                Ok(None) => {}
+                Err(e) => warn!("Error reading secret: {}", e),
+            }
+        }
+
+        // 未启用加密或尚未迁移时按明文读取
+        self.read_plain_secret(key)
+    }
+
+    /// Read a secret that is stored as a plaintext string
+    fn read_plain_secret(&self, key: &str) -> Option<String> {
+        match self.get_str::<MAX_SECRET_LEN>(key) {
+            Ok(value) => value.map(|value| value.to_string()),
             Err(e) => {
-                // This is an actual error
-                warn!("Error reading baudrate from NVS: {}", e);
+                warn!("Error reading secret: {}", e);
                 None
             }
         }
     }
+
+    /// Write `blob` under `key`, replacing a plaintext secret stored there
+    ///
+    /// NVS keeps a string and a blob of the same name apart, so a plaintext string
+    /// is removed first. It is written back if the blob cannot be written, so a
+    /// failed write never loses the secret.
+    #[cfg(feature = "secret-storage")]
+    fn replace_with_blob(&mut self, key: &str, blob: &[u8]) -> Result<()> {
+        let plaintext = self.read_plain_secret(key);
+        if plaintext.is_some() {
+            self.remove(key)?;
+        }
+        self.set_blob(key, blob).inspect_err(|_| {
+            if let Some(value) = &plaintext {
+                if let Err(e) = self.set_str(key, value) {
+                    error!("Failed to restore plaintext secret {}: {}", key, e);
+                }
+            }
+        })
+    }
+
+    /// Re-write secrets that were stored in plaintext by an older firmware
+    #[cfg(feature = "secret-storage")]
+    fn migrate_secrets(&mut self) {
+        for key in SECRET_KEYS {
+            let Some(value) = self.read_plain_secret(key) else {
+                continue;
+            };
+
+            let result = crate::secret::encrypt(value.as_bytes()).and_then(|blob| self.replace_with_blob(key, &blob));
+            match result {
+                Ok(_) => info!("Migrated plaintext secret {} to obfuscated storage", key),
+                Err(e) => warn!("Failed to migrate secret {}: {}", key, e),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use std::collections::HashMap;
+    use std::sync::{Arc, Mutex};
+
+    // ESP-IDF error codes returned like the real NVS
+    const ESP_ERR_INVALID_ARG: i32 = 0x102;
+    const ESP_ERR_INVALID_STATE: i32 = 0x103;
+    const ESP_ERR_NVS_NOT_ENOUGH_SPACE: i32 = 0x1105;
+
+    /// A value of the mock store, typed like the NVS entries
+    #[derive(Debug, Clone, PartialEq, Eq)]
+    pub(crate) enum Value {
+        U8(u8),
+        U16(u16),
+        U32(u32),
+        Str(String),
+        Blob(Vec<u8>),
+    }
+
+    /// Contents of a `MockNvs` as seen by the test
+    #[derive(Debug, Default)]
+    pub(crate) struct MockFlash {
+        /// Stored values by key
+        pub values: HashMap<String, Value>,
+        /// Key whose writes fail, as with a full partition
+        pub failing_key: Option<&'static str>,
+        /// Whether blob writes fail, as when no page has room for a blob
+        pub failing_blobs: bool,
+    }
+
+    /// Shared handle on the contents of a mock store
+    pub(crate) type Flash = Arc<Mutex<MockFlash>>;
+
+    /// Key/value store keeping its values in a `MockFlash`
+    pub(crate) struct MockNvs {
+        flash: Flash,
+    }
+
+    /// Create a storage manager on an empty mock store, returning it and the store
+    pub(crate) fn storage() -> (StorageManager, Flash) {
+        let flash = Flash::default();
+        (reopen(&flash), flash)
+    }
+
+    /// Create a storage manager on `flash`, like a restart of the device
+    pub(crate) fn reopen(flash: &Flash) -> StorageManager {
+        StorageManager::with_store(Box::new(MockNvs { flash: Arc::clone(flash) }))
+    }
+
+    impl MockNvs {
+        fn get(&self, key: &str) -> Option<Value> {
+            self.flash.lock().unwrap().values.get(key).cloned()
+        }
+
+        fn set(&mut self, key: &str, value: Value) -> std::result::Result<(), NvsError> {
+            let mut flash = self.flash.lock().unwrap();
+            if flash.failing_key == Some(key) {
+                return Err(NvsError(ESP_ERR_INVALID_STATE));
+            }
+            flash.values.insert(key.to_string(), value);
+            Ok(())
+        }
+
+        /// Copy `value` into `buf`, failing like NVS if it does not fit
+        fn copy<'a>(value: &[u8], buf: &'a mut [u8]) -> std::result::Result<&'a mut [u8], NvsError> {
+            let target = buf
+                .get_mut(..value.len())
+                .ok_or(NvsError(ESP_ERR_INVALID_ARG))?;
+            target.copy_from_slice(value);
+            Ok(target)
+        }
+    }
+
+    impl NvsStore for MockNvs {
+        fn get_u8(&self, key: &str) -> std::result::Result<Option<u8>, NvsError> {
+            Ok(match self.get(key) {
+                Some(Value::U8(value)) => Some(value),
+                _ => None,
+            })
+        }
+
+        fn get_u16(&self, key: &str) -> std::result::Result<Option<u16>, NvsError> {
+            Ok(match self.get(key) {
+                Some(Value::U16(value)) => Some(value),
+                _ => None,
+            })
+        }
+
+        fn get_u32(&self, key: &str) -> std::result::Result<Option<u32>, NvsError> {
+            Ok(match self.get(key) {
+                Some(Value::U32(value)) => Some(value),
+                _ => None,
+            })
+        }
+
+        fn set_u32(&mut self, key: &str, value: u32) -> std::result::Result<(), NvsError> {
+            self.set(key, Value::U32(value))
+        }
+
+        fn get_str<'a>(&self, key: &str, buf: &'a mut [u8]) -> std::result::Result<Option<&'a str>, NvsError> {
+            let Some(Value::Str(value)) = self.get(key) else {
+                return Ok(None);
+            };
+            // 结尾0也要放得下
+            if value.len() >= buf.len() {
+                return Err(NvsError(ESP_ERR_INVALID_ARG));
+            }
+            let value = Self::copy(value.as_bytes(), buf)?;
+            Ok(Some(std::str::from_utf8(value).unwrap()))
+        }
+
+        fn set_str(&mut self, key: &str, value: &str) -> std::result::Result<(), NvsError> {
+            self.set(key, Value::Str(value.to_string()))
+        }
+
+        fn get_blob<'a>(&self, key: &str, buf: &'a mut [u8]) -> std::result::Result<Option<&'a [u8]>, NvsError> {
+            let Some(Value::Blob(value)) = self.get(key) else {
+                return Ok(None);
+            };
+            Ok(Some(Self::copy(&value, buf)?))
+        }
+
+        fn set_blob(&mut self, key: &str, value: &[u8]) -> std::result::Result<(), NvsError> {
+            if self.flash.lock().unwrap().failing_blobs {
+                return Err(NvsError(ESP_ERR_NVS_NOT_ENOUGH_SPACE));
+            }
+            self.set(key, Value::Blob(value.to_vec()))
+        }
+
+        fn remove(&mut self, key: &str) -> std::result::Result<bool, NvsError> {
+            Ok(self.flash.lock().unwrap().values.remove(key).is_some())
+        }
+
+        fn erase_all(&mut self) -> std::result::Result<(), NvsError> {
+            self.flash.lock().unwrap().values.clear();
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{reopen, storage, Flash, Value};
+    use super::*;
+    use crate::config_transfer;
+
+    /// Configuration differing from the defaults in every exported setting
+    fn changed_config() -> AppConfig {
+        let document = "baudrate=57600\n\
+                        format=7E1\n\
+                        delimiter=0D0A\n\
+                        replay=512\n\
+                        tcp_port=9000\n\
+                        banner=Bridge {name}\n\
+                        sta_ssid=Workshop\n\
+                        sta_password=station-pass\n\
+                        ap_ssid=Bridge-AP\n\
+                        ap_password=access-pass\n\
+                        ap_channel=6\n\
+                        ap_max_conn=2\n\
+                        name=bridge-7\n";
+        config_transfer::import(&AppConfig::default(), document).unwrap()
+    }
+
+    #[test]
+    fn exported_configuration_imports_into_another_unit() {
+        let config = changed_config();
+        let (mut source, _) = storage();
+        source.save_app_config(&config).unwrap();
+        let exported = config_transfer::export(&source.load_app_config().unwrap(), true);
+        assert_eq!(exported, config_transfer::export(&config, true));
+
+        // 另一台设备导入导出的文档，重启后读到相同的设置
+        let (mut target, flash) = storage();
+        let base = target.load_app_config().unwrap_or_default();
+        let imported = config_transfer::import(&base, &exported).unwrap();
+        target.save_app_config(&imported).unwrap();
+        let restarted = reopen(&flash);
+        assert_eq!(config_transfer::export(&restarted.load_app_config().unwrap(), true), exported);
+        assert_eq!(restarted.read_secret(STA_PASSWORD_KEY).as_deref(), Some("station-pass"));
+        assert_eq!(restarted.read_secret(AP_PASSWORD_KEY).as_deref(), Some("access-pass"));
+    }
+
+    #[test]
+    fn failed_save_keeps_the_previous_configuration() {
+        let (mut storage, flash) = storage();
+        let mut config = AppConfig::default();
+        config.wifi.client_password = "old-password".try_into().unwrap();
+        storage.save_app_config(&config).unwrap();
+        let saved = config_transfer::export(&storage.load_app_config().unwrap(), true);
+
+        flash.lock().unwrap().failing_key = Some(CONFIG_KEY);
+        assert!(storage.save_app_config(&changed_config()).is_err());
+        assert_eq!(config_transfer::export(&storage.load_app_config().unwrap(), true), saved);
+        assert_eq!(storage.read_secret(STA_PASSWORD_KEY).as_deref(), Some("old-password"));
+
+        // 重启后同样是旧的配置
+        flash.lock().unwrap().failing_key = None;
+        assert_eq!(config_transfer::export(&reopen(&flash).load_app_config().unwrap(), true), saved);
+    }
+
+    #[test]
+    fn failed_secret_write_keeps_the_previous_secrets() {
+        let (mut storage, flash) = storage();
+        let mut config = AppConfig::default();
+        config.wifi.client_password = "old-station".try_into().unwrap();
+        config.wifi.ap_password = "old-access".try_into().unwrap();
+        storage.save_app_config(&config).unwrap();
+
+        flash.lock().unwrap().failing_key = Some(AP_PASSWORD_KEY);
+        assert!(storage.save_app_config(&changed_config()).is_err());
+
+        // 已写入的站点密码恢复为旧值，接入点密码保持不变
+        flash.lock().unwrap().failing_key = None;
+        let restarted = reopen(&flash);
+        assert_eq!(restarted.read_secret(STA_PASSWORD_KEY).as_deref(), Some("old-station"));
+        assert_eq!(restarted.read_secret(AP_PASSWORD_KEY).as_deref(), Some("old-access"));
+    }
+
+    #[cfg(feature = "secret-storage")]
+    #[test]
+    fn failed_migration_keeps_the_plaintext_secret() {
+        let flash = Flash::default();
+        {
+            let mut flash = flash.lock().unwrap();
+            flash.values.insert(STA_PASSWORD_KEY.to_string(), Value::Str("station-pass".to_string()));
+            flash.failing_blobs = true;
+        }
+
+        let storage = reopen(&flash);
+        assert_eq!(storage.read_secret(STA_PASSWORD_KEY).as_deref(), Some("station-pass"));
+        assert_eq!(
+            flash.lock().unwrap().values.get(STA_PASSWORD_KEY),
+            Some(&Value::Str("station-pass".to_string()))
+        );
+    }
+
+    #[cfg(feature = "secret-storage")]
+    #[test]
+    fn failed_secret_write_keeps_the_plaintext_secret() {
+        let flash = Flash::default();
+        {
+            let mut flash = flash.lock().unwrap();
+            flash.values.insert(AP_PASSWORD_KEY.to_string(), Value::Str("old-access".to_string()));
+            flash.failing_blobs = true;
+        }
+        let mut storage = reopen(&flash);
+
+        assert!(storage.save_secret(AP_PASSWORD_KEY, "new-access").is_err());
+        assert_eq!(storage.read_secret(AP_PASSWORD_KEY).as_deref(), Some("old-access"));
+    }
+
+    #[test]
+    fn per_key_settings_are_imported_into_the_blob() {
+        let flash = Flash::default();
+        {
+            let values = &mut flash.lock().unwrap().values;
+            values.insert(LEGACY_BAUDRATE_KEY.to_string(), Value::U32(9600));
+            values.insert(LEGACY_TCP_PORT_KEY.to_string(), Value::U16(2323));
+            values.insert(LEGACY_STA_SSID_KEY.to_string(), Value::Str("Workshop".to_string()));
+            values.insert(LEGACY_AP_CHANNEL_KEY.to_string(), Value::U8(11));
+            values.insert(LEGACY_AP_MAX_CONN_KEY.to_string(), Value::U16(3));
+        }
+
+        let config = reopen(&flash).load_app_config().unwrap();
+        assert_eq!(config.uart.baudrate, 9600);
+        assert_eq!(config.tcp_server.port, 2323);
+        assert_eq!(config.wifi.client_ssid.as_str(), "Workshop");
+        assert_eq!(config.wifi.ap_channel, 11);
+        assert_eq!(config.wifi.ap_max_connections, 3);
+
+        let values = &flash.lock().unwrap().values;
+        assert!(LEGACY_KEYS.iter().all(|key| !values.contains_key(*key)));
+        assert!(values.contains_key(CONFIG_KEY));
+    }
+
+    /// Settings blob of `version` around `payload`, with a valid length and CRC
+    fn settings_blob(version: u8, payload: &[u8]) -> Vec<u8> {
+        let mut blob = BlobWriter::default();
+        blob.put_u8(version);
+        blob.put_u16(payload.len() as u16);
+        blob.0.extend_from_slice(payload);
+        let crc = crc32(&blob.0);
+        blob.put_u32(crc);
+        blob.0
+    }
+
+    /// Mock store holding only `blob` as settings
+    fn flash_with_blob(blob: Vec<u8>) -> Flash {
+        let flash = Flash::default();
+        flash.lock().unwrap().values.insert(CONFIG_KEY.to_string(), Value::Blob(blob));
+        flash
+    }
+
+    #[test]
+    fn crc32_matches_the_ieee_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn settings_blob_round_trips() {
+        let (mut storage, flash) = storage();
+        storage.save_app_config(&changed_config()).unwrap();
+        storage.save_log_levels("info,uart=debug").unwrap();
+
+        let Some(Value::Blob(blob)) = flash.lock().unwrap().values.get(CONFIG_KEY).cloned() else {
+            panic!("no settings blob written");
+        };
+        assert_eq!(blob[0], CONFIG_VERSION);
+        assert_eq!(StoredSettings::decode(&blob), Ok(storage.settings.clone()));
+    }
+
+    #[test]
+    fn corrupt_blobs_fall_back_to_defaults() {
+        let (mut storage, flash) = storage();
+        storage.save_app_config(&changed_config()).unwrap();
+        let Some(Value::Blob(blob)) = flash.lock().unwrap().values.get(CONFIG_KEY).cloned() else {
+            panic!("no settings blob written");
+        };
+
+        let mut flipped = blob.clone();
+        flipped[5] ^= 0x01;
+        let mut bad_crc = blob.clone();
+        *bad_crc.last_mut().unwrap() ^= 0x80;
+        let mut long = blob.clone();
+        long.push(0);
+        let corrupt = [
+            (Vec::new(), "too short"),
+            (blob[..6].to_vec(), "too short"),
+            (blob[..blob.len() - 1].to_vec(), "does not match"),
+            (long, "does not match"),
+            (flipped, "CRC mismatch"),
+            (bad_crc, "CRC mismatch"),
+        ];
+        for (blob, reason) in corrupt {
+            let error = StoredSettings::decode(&blob).unwrap_err();
+            assert!(error.contains(reason), "{:?}: {}", blob, error);
+            assert!(reopen(&flash_with_blob(blob)).load_app_config().is_none());
+        }
+
+        // 超出读取缓冲区的块同样被忽略
+        let oversize = settings_blob(CONFIG_VERSION, &[0; MAX_CONFIG_LEN]);
+        assert!(reopen(&flash_with_blob(oversize)).load_app_config().is_none());
+    }
+
+    #[test]
+    fn malformed_payload_with_valid_crc_falls_back_to_defaults() {
+        // 存在标记后缺少波特率
+        let blob = settings_blob(CONFIG_VERSION, &[1, 0x80]);
+        assert_eq!(StoredSettings::decode(&blob), Err("malformed payload".to_string()));
+        assert!(reopen(&flash_with_blob(blob)).load_app_config().is_none());
+    }
+
+    #[test]
+    fn blobs_of_unknown_versions_fall_back_to_defaults() {
+        let (mut storage, flash) = storage();
+        storage.save_baudrate(57600).unwrap();
+        let Some(Value::Blob(blob)) = flash.lock().unwrap().values.get(CONFIG_KEY).cloned() else {
+            panic!("no settings blob written");
+        };
+        let payload = &blob[3..blob.len() - 4];
+
+        for version in [0, CONFIG_VERSION + 1, u8::MAX] {
+            let blob = settings_blob(version, payload);
+            assert_eq!(StoredSettings::decode(&blob), Err(format!("unsupported version {}", version)));
+            let flash = flash_with_blob(blob);
+            assert!(reopen(&flash).load_app_config().is_none());
+            // 新版本固件的设置不被覆盖，降级后再升级仍然可用
+            assert!(matches!(flash.lock().unwrap().values.get(CONFIG_KEY), Some(Value::Blob(_))));
+        }
+    }
+
+    #[test]
+    fn version_1_blob_is_still_read() {
+        // 波特率，格式，端口，无WiFi和横幅
+        let mut payload = BlobWriter::default();
+        payload.put_opt(Some(19200), |w, baudrate| w.put_u32(baudrate));
+        payload.put_opt(Some("7E1"), |w, format| w.put_str8(format));
+        payload.put_opt(Some(4000), |w, port| w.put_u16(port));
+        payload.put_opt(None::<u8>, |w, _| w.put_u8(0));
+        payload.put_opt(None::<u8>, |w, _| w.put_u8(0));
+
+        let config = reopen(&flash_with_blob(settings_blob(1, &payload.0))).load_app_config().unwrap();
+        assert_eq!(config.uart.baudrate, 19200);
+        assert_eq!(config.uart.format.to_string(), "7E1");
+        assert_eq!(config.tcp_server.port, 4000);
+        assert_eq!(config.uart.frame_delimiter, AppConfig::default().uart.frame_delimiter);
+    }
+
+    #[test]
+    fn version_4_host_name_becomes_the_device_name() {
+        // 波特率，格式，端口为空，WiFi设置带主机名，其余为空
+        let mut payload = BlobWriter::default();
+        payload.put_opt(None::<u8>, |w, _| w.put_u8(0));
+        payload.put_opt(None::<u8>, |w, _| w.put_u8(0));
+        payload.put_opt(None::<u8>, |w, _| w.put_u8(0));
+        payload.put_opt(Some(()), |w, _| {
+            w.put_str8("Workshop");
+            w.put_str8("Bridge-AP");
+            w.put_u8(6);
+            w.put_u16(2);
+            w.put_str8("bench-3");
+        });
+        for _ in 0..4 {
+            payload.put_opt(None::<u8>, |w, _| w.put_u8(0));
+        }
+
+        let flash = flash_with_blob(settings_blob(4, &payload.0));
+        let mut storage = reopen(&flash);
+        let config = storage.load_app_config().unwrap();
+        assert_eq!(config.device_name.as_str(), "bench-3");
+        assert_eq!(config.wifi.client_ssid.as_str(), "Workshop");
+
+        // 再次保存后名称保存在自己的字段中
+        storage.save_baudrate(57600).unwrap();
+        let config = reopen(&flash).load_app_config().unwrap();
+        assert_eq!(config.device_name.as_str(), "bench-3");
+        assert_eq!(config.uart.baudrate, 57600);
+    }
+
+    #[test]
+    fn wifi_security_survives_a_restart() {
+        let (mut storage, flash) = storage();
+        let wifi = WiFiConfig {
+            ap_auth: WiFiAuth::Wpa3Personal,
+            sta_auth: WiFiAuth::Open,
+            ..Default::default()
+        };
+        storage.save_wifi_config(&wifi).unwrap();
+
+        let config = reopen(&flash).load_app_config().unwrap();
+        assert_eq!(config.wifi.ap_auth, WiFiAuth::Wpa3Personal);
+        assert_eq!(config.wifi.sta_auth, WiFiAuth::Open);
+    }
+
+    /// Longest key NVS accepts (the 16 byte key field includes the terminating 0)
+    const NVS_KEY_MAX_LEN: usize = 15;
+
+    /// Every key the storage manager writes or removes
+    fn all_keys() -> Vec<&'static str> {
+        let mut keys = vec![CONFIG_KEY, STA_PASSWORD_KEY, AP_PASSWORD_KEY];
+        keys.extend(LEGACY_KEYS);
+        keys
+    }
+
+    #[test]
+    fn keys_fit_nvs_and_do_not_collide() {
+        let keys = all_keys();
+        for (i, key) in keys.iter().enumerate() {
+            assert!(key.len() <= NVS_KEY_MAX_LEN, "key {} is too long for NVS", key);
+            assert!(!keys[i + 1..].contains(key), "key {} is used twice", key);
+        }
+    }
+
+    #[test]
+    fn wifi_passwords_are_stored_as_secrets() {
+        assert!(SECRET_KEYS.contains(&STA_PASSWORD_KEY));
+        assert!(SECRET_KEYS.contains(&AP_PASSWORD_KEY));
+        assert!(!SECRET_KEYS.contains(&LEGACY_STA_SSID_KEY));
+    }
+
+    fn settings() -> StoredSettings {
+        StoredSettings {
+            baudrate: Some(57600),
+            format: Some(SerialFormat::parse_compact("7E1").unwrap()),
+            tcp_port: Some(2323),
+            wifi: Some(StoredWiFi {
+                client_ssid: heapless::String::try_from("office").unwrap(),
+                ap_ssid: heapless::String::try_from("bridge").unwrap(),
+                ap_channel: 6,
+                ap_max_connections: 4,
+                ap_auth: WiFiAuth::Wpa3Personal,
+                sta_auth: WiFiAuth::Wpa2Personal,
+                ap_hidden: true,
+                ap_bandwidth: ApBandwidth::Ht40,
+                country_code: heapless::String::try_from("JP").unwrap(),
+                tx_power_dbm: 8,
+            }),
+            banner: Some(Some("Welcome to {hostname}".to_string())),
+            frame_delimiter: Some(Some(b"\r\n".to_vec())),
+            log_levels: Some("info,wifi=warn".to_string()),
+            replay_bytes: Some(4096),
+            device_name: Some(heapless::String::try_from("bridge-1").unwrap()),
+            access_list: Some(AccessList::parse("deny 192.168.4.0/24").unwrap()),
+        }
+    }
+
+    #[test]
+    fn every_setting_round_trips() {
+        let blob = settings().encode();
+        assert_eq!(blob[0], CONFIG_VERSION);
+        assert_eq!(StoredSettings::decode(&blob), Ok(settings()));
+
+        let disabled = StoredSettings { banner: Some(None), ..StoredSettings::default() };
+        assert_eq!(StoredSettings::decode(&disabled.encode()), Ok(disabled));
+        let empty = StoredSettings::decode(&StoredSettings::default().encode()).unwrap();
+        assert!(empty.is_empty());
+    }
 }
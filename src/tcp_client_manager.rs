@@ -1,31 +1,600 @@
 //! TCP Client Manager module
 //!
 //! This module provides functionality for managing TCP client connections.
+//!
+//! Connections are kept as `ClientStream` trait objects. On the device they are
+//! TCP streams, but the queueing, broadcast and disconnect logic only relies on the
+//! trait, so it also works with e.g. an in-memory stream off the device.
 
-use log::{info, error, debug, trace};
-use std::collections::HashMap;
-use std::io::Write;
+use log::{info, error, debug, trace, warn};
+use std::collections::{HashMap, VecDeque};
+use std::fmt::Write as _;
+use std::io::{self, Read, Write};
+use std::mem::ManuallyDrop;
 use std::net::{TcpStream, SocketAddr};
-use std::sync::{Arc, Mutex};
+use std::net::Shutdown;
+use std::os::fd::{AsRawFd, FromRawFd};
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicU8, AtomicUsize, Ordering};
+use std::time::Duration;
+use std::sync::{Arc, Mutex, OnceLock};
 
+use crate::diagnostics;
 use crate::error::{Error, Result};
+use crate::rfc2217;
+use crate::websocket;
+use crate::status_led::DeviceStatus;
+use crate::time::{self, Stopwatch};
+
+/// Maximum number of command lines kept in a client's history
+pub const MAX_HISTORY_ENTRIES: usize = 16;
+
+/// Maximum length of a command line kept in a client's history
+const MAX_HISTORY_LINE_LEN: usize = 128;
+
+/// Default number of bytes that may be queued for one client
+pub const DEFAULT_QUEUE_LIMIT: usize = 8192;
+
+/// Bytes of one transfer shown in a hex tap dump, the rest is only counted
+const MAX_TAP_DUMP_BYTES: usize = 256;
+
+/// Bytes shown per hex tap dump line
+const TAP_BYTES_PER_LINE: usize = 16;
+
+/// Identifies one connection of a client
+///
+/// A peer that reconnects from the same address and port gets a new id, so the
+/// handler of the old connection cannot remove the new one.
+pub type ConnectionId = u32;
+
+/// Receiver of the data queued for a virtual client
+///
+/// Virtual clients are bridges without a TCP stream (e.g. MQTT). They are listed
+/// and counted like other clients, but data is handed to them as it is broadcast,
+/// one call per UART frame, instead of being queued for the writer thread.
+pub trait VirtualClient: Send + Sync {
+    /// Take one piece of data; returns false if it had to be dropped
+    ///
+    /// Called from the UART forwarding thread, so this must not block.
+    fn deliver(&self, data: &[u8]) -> bool;
+}
+
+/// Connection of a client, as used by the client manager
+///
+/// Writes must not block once `set_nonblocking(true)` was called: a full
+/// connection returns `WouldBlock`, and the rest stays queued for the next round.
+pub trait ClientStream: Read + Write + Send {
+    /// Get the address of the peer
+    fn peer_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Switch the connection into or out of non-blocking mode
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()>;
+
+    /// Set how long a blocking read may take (None waits forever)
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Set how long a blocking write may take (None waits forever)
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()>;
+
+    /// Shut down the connection, making the reads of the client's handler fail
+    fn shutdown(&self, how: Shutdown) -> io::Result<()>;
+
+    /// Send small writes at once (true) or coalesce them with Nagle's algorithm
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()>;
+
+    /// Check whether small writes are sent at once
+    fn nodelay(&self) -> io::Result<bool>;
+
+    /// Open a second handle reading the same connection, for `StreamReader`
+    ///
+    /// Reads through the handle must not need the stream lock, and must fail once
+    /// `shutdown` was called.
+    fn read_view(&self) -> io::Result<Box<dyn Read + Send>>;
+}
+
+/// Connection shared between a client's handler and the client manager
+pub type SharedStream = Arc<Mutex<dyn ClientStream>>;
+
+impl ClientStream for TcpStream {
+    fn peer_addr(&self) -> io::Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+
+    fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        TcpStream::set_nonblocking(self, nonblocking)
+    }
+
+    fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+
+    fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_write_timeout(self, timeout)
+    }
+
+    fn shutdown(&self, how: Shutdown) -> io::Result<()> {
+        TcpStream::shutdown(self, how)
+    }
+
+    fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+        TcpStream::set_nodelay(self, nodelay)
+    }
+
+    fn nodelay(&self) -> io::Result<bool> {
+        TcpStream::nodelay(self)
+    }
+
+    fn read_view(&self) -> io::Result<Box<dyn Read + Send>> {
+        // 与共享流使用同一个套接字，ManuallyDrop保证不会重复关闭
+        let view = ManuallyDrop::new(unsafe { TcpStream::from_raw_fd(self.as_raw_fd()) });
+        Ok(Box::new(SocketView(view)))
+    }
+}
+
+/// Second view of a socket, never closed through this handle
+///
+/// lwIP lets one thread read a socket while another writes it. The view is not a
+/// duplicated descriptor, so socket options such as non-blocking mode apply to both.
+struct SocketView(ManuallyDrop<TcpStream>);
+
+impl Read for SocketView {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        (&*self.0).read(buf)
+    }
+}
+
+/// Where the data queued for a client goes
+enum ClientLink {
+    /// Connection shared with the client's handler thread
+    Stream(SharedStream),
+    /// Bridge receiving the data directly
+    Virtual(Arc<dyn VirtualClient>),
+}
+
+/// Read side of a client stream, used without taking the stream lock
+///
+/// The stream lock only serializes writes, so a handler waiting for its client
+/// never delays the writer thread. Reads go through `ClientStream::read_view`.
+pub struct StreamReader {
+    /// Read handle of the connection
+    view: Box<dyn Read + Send>,
+    /// Keeps the connection open while the reader exists
+    _stream: SharedStream,
+}
+
+impl StreamReader {
+    /// Create a reader for a stream shared with the client manager
+    pub fn new<S: ClientStream + 'static>(stream_arc: &Arc<Mutex<S>>) -> Result<Self> {
+        let view = stream_arc
+            .lock()
+            .map_err(|_| Error::ClientError("Failed to lock stream".to_string()))?
+            .read_view()
+            .map_err(|e| Error::ClientError(format!("Failed to open stream for reading: {}", e)))?;
+        Ok(Self {
+            view,
+            _stream: Arc::clone(stream_arc) as SharedStream,
+        })
+    }
+}
+
+impl Read for StreamReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.view.read(buf)
+    }
+}
+
+/// Per-client state stored alongside the TCP stream
+struct ClientEntry {
+    /// Connection this entry belongs to
+    id: ConnectionId,
+    /// Stream or bridge the client's data is written to
+    link: ClientLink,
+    /// Whether dropped data should be reported to this client with an in-band marker
+    mark_gaps: AtomicBool,
+    /// Whether the client is in raw transparent mode
+    raw_mode: AtomicBool,
+    /// Whether data received from the client is echoed back to it (command mode only)
+    echo: AtomicBool,
+    /// Whether command replies use the verbose text instead of the OK/ERROR format
+    verbose: AtomicBool,
+    /// Whether the client receives a hex dump of the traffic instead of the raw bytes
+    hex_tap: AtomicBool,
+    /// Whether the client receives a copy of the device log (see `log_stream`)
+    log_stream: AtomicBool,
+    /// Wire protocol of the client, a `ClientProtocol` discriminant
+    protocol: AtomicU8,
+    /// Bytes destined to this client that were dropped since the last marker
+    dropped_bytes: AtomicUsize,
+    /// Data waiting to be written by the writer thread
+    outbound: Mutex<VecDeque<u8>>,
+    /// Set when the outbound queue overflowed; the writer thread drops the client
+    overflowed: AtomicBool,
+    /// Time since the client was added
+    connected: Stopwatch,
+    /// Bytes written to the client
+    bytes_sent: AtomicU64,
+    /// Bytes received from the client
+    bytes_received: AtomicU64,
+    /// Uptime in milliseconds of the client's last activity
+    last_activity_ms: AtomicU64,
+    /// Most recent command lines issued by this client, oldest first
+    history: Mutex<VecDeque<String>>,
+}
+
+impl ClientEntry {
+    /// Get the wire protocol of the client
+    fn protocol(&self) -> ClientProtocol {
+        match self.protocol.load(Ordering::Relaxed) {
+            p if p == ClientProtocol::Telnet as u8 => ClientProtocol::Telnet,
+            p if p == ClientProtocol::WebSocket as u8 => ClientProtocol::WebSocket,
+            _ => ClientProtocol::Raw,
+        }
+    }
+
+    /// Record activity on this client now
+    fn touch(&self) {
+        self.last_activity_ms.store(time::uptime().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    /// Get the time since the client's last activity
+    fn idle_time(&self) -> Duration {
+        let now_ms = time::uptime().as_millis() as u64;
+        Duration::from_millis(now_ms.saturating_sub(self.last_activity_ms.load(Ordering::Relaxed)))
+    }
+
+    /// Check whether this is a virtual client, which cannot be evicted
+    fn is_virtual(&self) -> bool {
+        matches!(self.link, ClientLink::Virtual(_))
+    }
+
+    fn new(id: ConnectionId, link: ClientLink) -> Self {
+        Self {
+            id,
+            link,
+            mark_gaps: AtomicBool::new(false),
+            raw_mode: AtomicBool::new(false),
+            echo: AtomicBool::new(false),
+            verbose: AtomicBool::new(false),
+            hex_tap: AtomicBool::new(false),
+            log_stream: AtomicBool::new(false),
+            protocol: AtomicU8::new(ClientProtocol::Raw as u8),
+            dropped_bytes: AtomicUsize::new(0),
+            outbound: Mutex::new(VecDeque::new()),
+            overflowed: AtomicBool::new(false),
+            connected: Stopwatch::start(),
+            bytes_sent: AtomicU64::new(0),
+            bytes_received: AtomicU64::new(0),
+            last_activity_ms: AtomicU64::new(time::uptime().as_millis() as u64),
+            history: Mutex::new(VecDeque::with_capacity(MAX_HISTORY_ENTRIES)),
+        }
+    }
+}
+
+/// Wire protocol of a client, applied to the data queued for it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientProtocol {
+    /// Plain bytes (data port)
+    Raw = 0,
+    /// Telnet with 0xFF data bytes doubled (RFC 2217 port)
+    Telnet = 1,
+    /// Binary WebSocket messages (WebSocket port)
+    WebSocket = 2,
+}
+
+/// Traffic counters of one connection, updated without the clients map lock
+///
+/// Obtained once by a client's handler with `TcpClientManager::traffic_counter`,
+/// so counting the data read from the client costs no more than two atomic updates.
+pub struct TrafficCounter {
+    entry: Arc<ClientEntry>,
+}
+
+impl TrafficCounter {
+    /// Record `bytes` of data received from the client, counting as activity
+    pub fn record_received(&self, bytes: usize) {
+        self.entry.touch();
+        self.entry.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+}
+
+/// Snapshot of one connected client, see `TcpClientManager::list_clients`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientInfo {
+    /// Client socket address
+    pub addr: SocketAddr,
+    /// Uptime when the client connected
+    pub connected_at: Duration,
+    /// Uptime of the client's last activity
+    pub last_activity: Duration,
+    /// Bytes written to the client
+    pub bytes_sent: u64,
+    /// Bytes received from the client
+    pub bytes_received: u64,
+}
+
+impl std::fmt::Display for ClientInfo {
+    /// Formats as one line, with times relative to now
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let now = time::uptime();
+        write!(
+            f,
+            "{} connected {}, idle {}, sent {} bytes, received {} bytes",
+            self.addr,
+            time::format_duration(now.saturating_sub(self.connected_at)),
+            time::format_duration(now.saturating_sub(self.last_activity)),
+            self.bytes_sent,
+            self.bytes_received
+        )
+    }
+}
+
+/// Snapshot of the client manager counters
+///
+/// Counters wrap around on overflow instead of panicking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ClientStats {
+    /// Bytes of UART data queued for broadcast (counted once per broadcast)
+    pub bytes_broadcast: u64,
+    /// Clients dropped because their queue overflowed or their socket failed
+    pub broadcast_errors: u64,
+    /// Clients accepted since boot (or the last reset)
+    pub clients_total: u64,
+    /// Clients closed for being idle, too slow, kicked, or to make room for a new client
+    pub clients_evicted: u64,
+}
+
+/// Why a client left the manager without being evicted
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    /// The connection ended and its handler removed the client
+    Closed,
+    /// Another client closed the connection (AT+KICK)
+    Kicked,
+    /// The same address connected again before the connection was closed
+    Replaced,
+    /// Writing to the client failed
+    WriteFailed,
+}
+
+impl std::fmt::Display for DisconnectReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DisconnectReason::Closed => write!(f, "connection closed"),
+            DisconnectReason::Kicked => write!(f, "closed by another client"),
+            DisconnectReason::Replaced => write!(f, "replaced by a new connection"),
+            DisconnectReason::WriteFailed => write!(f, "write failed"),
+        }
+    }
+}
+
+/// Why the manager closed a client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EvictionReason {
+    /// No traffic for the given time
+    Idle(Duration),
+    /// The client limit was reached and a new client needed the slot
+    MakeRoom,
+    /// The device ran low on memory and the client had the most data queued
+    LowMemory,
+    /// The outbound queue grew past `queue_limit`, or the client held up UART flow control
+    TooSlow,
+}
+
+impl std::fmt::Display for EvictionReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            EvictionReason::Idle(idle) => write!(f, "idle for {}", time::format_duration(*idle)),
+            EvictionReason::MakeRoom => write!(f, "making room for a new client"),
+            EvictionReason::LowMemory => write!(f, "device low on memory"),
+            EvictionReason::TooSlow => write!(f, "client too slow"),
+        }
+    }
+}
+
+/// A client joining or leaving the manager, passed to the listeners added with
+/// `register_listener`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClientEvent {
+    /// A client was added, including virtual clients
+    Connected {
+        addr: SocketAddr,
+        id: ConnectionId,
+    },
+    /// A client was removed
+    Disconnected {
+        addr: SocketAddr,
+        reason: DisconnectReason,
+        /// Time the client was connected
+        connected_for: Duration,
+    },
+    /// A client was closed by the manager
+    Evicted {
+        addr: SocketAddr,
+        reason: EvictionReason,
+    },
+}
+
+/// Callback receiving every `ClientEvent` of a manager
+pub type ClientListener = Box<dyn Fn(ClientEvent) + Send + Sync>;
+
+/// A registered `ClientListener`, shared so it can be called outside the list lock
+type SharedListener = Arc<dyn Fn(ClientEvent) + Send + Sync>;
+
+/// Log a client event, the listener every manager starts with
+fn log_event(event: ClientEvent) {
+    match event {
+        ClientEvent::Connected { addr, id } => {
+            info!("Adding client {} to manager (connection {})", addr, id);
+        }
+        ClientEvent::Disconnected { addr, reason: DisconnectReason::Replaced, connected_for } => {
+            warn!(
+                "Client {} reconnected before its connection was closed, replacing the one of {}",
+                addr,
+                time::format_duration(connected_for)
+            );
+        }
+        ClientEvent::Disconnected { addr, reason: DisconnectReason::WriteFailed, .. } => {
+            debug!("Removed disconnected client {}", addr);
+        }
+        ClientEvent::Disconnected { addr, reason, connected_for } => {
+            info!("Removed client {} after {} ({})", addr, time::format_duration(connected_for), reason);
+        }
+        ClientEvent::Evicted { addr, reason } => info!("Evicting client {}: {}", addr, reason),
+    }
+}
+
+/// Atomic counters behind `ClientStats`
+#[derive(Default)]
+struct ClientCounters {
+    bytes_broadcast: AtomicU64,
+    broadcast_errors: AtomicU64,
+    clients_total: AtomicU64,
+    clients_evicted: AtomicU64,
+}
+
+/// Format the marker injected into a client's stream where data was dropped
+pub fn gap_marker(dropped: usize) -> String {
+    format!("\r\n[---- {} bytes dropped ----]\r\n", dropped)
+}
+
+/// Format a transfer as timestamped hex+ASCII lines for hex tap clients
+///
+/// `direction` describes the transfer, e.g. "UART>TCP". Only the first
+/// `MAX_TAP_DUMP_BYTES` bytes are shown, so a large frame cannot exhaust the heap.
+pub fn hex_dump(direction: &str, data: &[u8]) -> String {
+    let uptime = time::uptime();
+    let shown = &data[..data.len().min(MAX_TAP_DUMP_BYTES)];
+    // 每行约80字节：偏移、16个十六进制字节和ASCII
+    let mut dump = String::with_capacity(64 + shown.len().div_ceil(TAP_BYTES_PER_LINE) * 80);
+    let _ = write!(
+        dump,
+        "[{:>6}.{:03}] {} {} bytes\r\n",
+        uptime.as_secs(),
+        uptime.subsec_millis(),
+        direction,
+        data.len()
+    );
+    for (line, chunk) in shown.chunks(TAP_BYTES_PER_LINE).enumerate() {
+        let _ = write!(dump, "  {:04x} ", line * TAP_BYTES_PER_LINE);
+        for i in 0..TAP_BYTES_PER_LINE {
+            match chunk.get(i) {
+                Some(byte) => {
+                    let _ = write!(dump, " {:02x}", byte);
+                }
+                None => dump += "   ",
+            }
+        }
+        dump += "  |";
+        dump.extend(chunk.iter().map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' }));
+        dump += "|\r\n";
+    }
+    if data.len() > shown.len() {
+        let _ = write!(dump, "  ... {} more bytes\r\n", data.len() - shown.len());
+    }
+    dump
+}
 
 /// TCP Client Manager
 ///
 /// Manages TCP client connections and provides methods for broadcasting data to all clients.
+///
+/// `broadcast` only appends to per-client outbound queues and never touches a
+/// socket, so a slow client cannot delay UART forwarding. A single `tcp_tx` writer
+/// thread (see `start_writer`) drains the queues without blocking on any socket.
+/// A client whose queue grows past `queue_limit` bytes is dropped.
+///
+/// # Lock ordering
+///
+/// The `clients` map lock is a leaf lock: no other lock is taken while it is held.
+/// The writer thread copies the client entries out of the map before locking
+/// individual streams, and `add_client` releases the stream lock before inserting
+/// into the map. A per-client outbound queue lock may be taken while holding that
+/// client's stream lock, and is otherwise a leaf lock.
+/// Handlers read their client through a `StreamReader`, so the stream lock is only
+/// held for writes. A client stream lock may be held while taking the UART lock
+/// (see `UartManager`), never the other way around. Per-client flags are atomics and need no lock, and
+/// the per-client history lock and the `exclusive` lock are leaf locks as well.
+/// `VirtualClient::deliver` and the event listeners are called without holding any
+/// of these locks, and the listener list lock is a leaf lock.
 pub struct TcpClientManager {
-    /// Map of client socket addresses to TCP streams
-    clients: Mutex<HashMap<SocketAddr, Arc<Mutex<TcpStream>>>>,
-    /// Number of active clients (cached to avoid locking for count)
+    /// Map of client socket addresses to per-client state
+    clients: Mutex<HashMap<SocketAddr, Arc<ClientEntry>>>,
+    /// Number of active clients, a copy of the map length updated under the map lock
     client_count: std::sync::atomic::AtomicUsize,
+    /// Id given to the next connection
+    next_id: AtomicU32,
+    /// Bytes that may be queued for one client before it is dropped
+    queue_limit: usize,
+    /// Whether the writer thread has been started
+    writer_started: AtomicBool,
+    /// Traffic and connection counters
+    counters: ClientCounters,
+    /// Client holding exclusive UART TX rights (AT+LOCK), if any
+    exclusive: Mutex<Option<SocketAddr>>,
+    /// Device status updated when the number of clients changes
+    status: OnceLock<Arc<DeviceStatus>>,
+    /// Number of hex tap clients, lets TCP -> UART traffic skip the tap when zero
+    tap_clients: AtomicUsize,
+    /// Number of clients receiving the device log
+    log_clients: AtomicUsize,
+    /// Callbacks receiving client events
+    listeners: Mutex<Vec<SharedListener>>,
 }
 
 impl TcpClientManager {
     /// Create a new TCP client manager
     pub fn new() -> Self {
+        Self::with_queue_limit(DEFAULT_QUEUE_LIMIT)
+    }
+
+    /// Create a new TCP client manager that drops clients with more than
+    /// `queue_limit` bytes queued
+    pub fn with_queue_limit(queue_limit: usize) -> Self {
         Self {
             clients: Mutex::new(HashMap::new()),
             client_count: std::sync::atomic::AtomicUsize::new(0),
+            next_id: AtomicU32::new(1),
+            queue_limit,
+            writer_started: AtomicBool::new(false),
+            counters: ClientCounters::default(),
+            exclusive: Mutex::new(None),
+            status: OnceLock::new(),
+            tap_clients: AtomicUsize::new(0),
+            log_clients: AtomicUsize::new(0),
+            listeners: Mutex::new(vec![Arc::new(log_event)]),
+        }
+    }
+
+    /// Call `listener` for every client that is added, removed or evicted from now on
+    ///
+    /// Listeners run on the thread that changed the client, after the change, and
+    /// must not block for long. They may use the manager, including registering
+    /// further listeners.
+    pub fn register_listener(&self, listener: ClientListener) {
+        match self.listeners.lock() {
+            Ok(mut listeners) => listeners.push(Arc::from(listener)),
+            Err(_) => error!("Failed to lock client listeners, listener not registered"),
+        }
+    }
+
+    /// Pass `event` to every listener
+    fn emit(&self, event: ClientEvent) {
+        // 复制列表后释放锁，监听器可以再注册监听器
+        let listeners = match self.listeners.lock() {
+            Ok(listeners) => listeners.clone(),
+            Err(_) => return,
+        };
+        for listener in listeners {
+            listener(event);
+        }
+    }
+
+    /// Publish the number of connected clients to `status`
+    ///
+    /// Only the first status set is used.
+    pub fn set_status(&self, status: Arc<DeviceStatus>) {
+        status.set_clients(self.client_count.load(Ordering::Relaxed));
+        if self.status.set(status).is_err() {
+            warn!("Client manager status already set");
         }
     }
 
@@ -47,12 +616,20 @@ impl TcpClientManager {
 
     /// Add a new client with its stream
     ///
-    /// The stream is wrapped in an Arc<Mutex<>> for thread-safe sharing.
-    pub fn add_client(&self, addr: SocketAddr, stream_arc: Arc<Mutex<TcpStream>>) -> Result<()> {
-        // Try to get the stream lock and set it to blocking mode
+    /// The stream is wrapped in an Arc<Mutex<>> for thread-safe sharing. It is
+    /// switched to non-blocking mode: the writer thread only writes what the socket
+    /// accepts and keeps the rest queued, so one slow client cannot stall the others.
+    ///
+    /// Returns the id to pass to `remove_client` when the connection ends.
+    pub fn add_client<S: ClientStream + 'static>(
+        &self,
+        addr: SocketAddr,
+        stream_arc: Arc<Mutex<S>>,
+    ) -> Result<ConnectionId> {
+        // Try to get the stream lock and set it to non-blocking mode
         if let Ok(stream) = stream_arc.lock() {
-            if let Err(e) = stream.set_nonblocking(false) {
-                error!("Failed to set blocking mode for client {}: {}", addr, e);
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!("Failed to set non-blocking mode for client {}: {}", addr, e);
                 // Continue adding the client even if setting the mode fails
             }
         } else {
@@ -60,141 +637,2233 @@ impl TcpClientManager {
             // Continue adding the client even if locking fails
         }
 
+        self.insert_entry(addr, ClientLink::Stream(stream_arc))
+    }
+
+    /// Add a virtual client, e.g. a bridge to a message broker
+    ///
+    /// The client receives broadcasts through `VirtualClient::deliver`, is counted in
+    /// the statistics and `max_clients` like other clients, and is never evicted or
+    /// kicked; its owner removes it with `remove_client`. Use an address no TCP peer
+    /// can have, such as a loopback address.
+    pub fn add_virtual_client(&self, addr: SocketAddr, client: Arc<dyn VirtualClient>) -> Result<ConnectionId> {
+        self.insert_entry(addr, ClientLink::Virtual(client))
+    }
+
+    /// Insert a client entry under a new connection id
+    ///
+    /// An entry left by an earlier connection from the same address is replaced and
+    /// closed; its handler's `remove_client` then no longer matches.
+    fn insert_entry(&self, addr: SocketAddr, link: ClientLink) -> Result<ConnectionId> {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let entry = ClientEntry::new(id, link);
+        // 虚拟客户端只接收原始数据
+        if entry.is_virtual() {
+            entry.raw_mode.store(true, Ordering::Relaxed);
+        }
+
         // 尽量减少锁的持有时间
-        let is_new_client = {
+        let replaced = {
             let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
-            info!("Adding client {} to manager", addr);
-            let is_new = !clients.contains_key(&addr);
-            clients.insert(addr, stream_arc);
-            is_new
+            let replaced = clients.insert(addr, Arc::new(entry));
+            self.publish_count(clients.len());
+            replaced
         };
+        self.counters.clients_total.fetch_add(1, Ordering::Relaxed);
 
-        // 如果是新客户端，增加计数器
-        if is_new_client {
-            let count = self.client_count.fetch_add(1, std::sync::atomic::Ordering::SeqCst) + 1;
-            debug!("Total clients: {}", count);
+        // 同一地址快速重连时，旧连接的处理线程可能还没发现断开
+        if let Some(old) = replaced {
+            self.close_removed_entry(&addr, &old, "");
+            self.emit(ClientEvent::Disconnected {
+                addr,
+                reason: DisconnectReason::Replaced,
+                connected_for: old.connected.elapsed(),
+            });
         }
-        Ok(())
+        self.emit(ClientEvent::Connected { addr, id });
+        Ok(id)
     }
 
     /// Remove a client
-    pub fn remove_client(&self, addr: &SocketAddr) -> Result<()> {
+    ///
+    /// Only removes the entry if it still belongs to connection `id`, so a handler
+    /// that noticed its disconnect late cannot remove a newer connection.
+    pub fn remove_client(&self, addr: &SocketAddr, id: ConnectionId) -> Result<()> {
         // 尽量减少锁的持有时间
         let removed = {
             let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
-            clients.remove(addr).is_some()
+            self.remove_connection(&mut clients, addr, id)
         };
 
-        // 只在实际移除客户端时更新计数
-        if removed {
-            info!("Removed client {}", addr);
-            let count = self.client_count.fetch_sub(1, std::sync::atomic::Ordering::SeqCst) - 1;
-            debug!("Total clients: {}", count);
+        if let Some(entry) = removed {
+            self.release_exclusive(addr);
+            self.forget_tap(&entry);
+            self.emit(ClientEvent::Disconnected {
+                addr: *addr,
+                reason: DisconnectReason::Closed,
+                connected_for: entry.connected.elapsed(),
+            });
         }
 
         Ok(())
     }
 
+    /// Remove the entry of `addr` from the locked map if it belongs to connection `id`
+    fn remove_connection(
+        &self,
+        clients: &mut HashMap<SocketAddr, Arc<ClientEntry>>,
+        addr: &SocketAddr,
+        id: ConnectionId,
+    ) -> Option<Arc<ClientEntry>> {
+        if clients.get(addr).map(|entry| entry.id) != Some(id) {
+            return None;
+        }
+        let removed = clients.remove(addr);
+        self.publish_count(clients.len());
+        removed
+    }
+
     /// Broadcast data to all connected clients
-    /// Optimized for low latency
-    pub fn broadcast(&self, data: &[u8]) -> Result<usize> {
+    ///
+    /// The data is queued for the writer thread, so this never blocks on a socket.
+    /// Returns the number of clients the data was queued for. Clients whose queue
+    /// would exceed the limit are flagged and dropped by the writer thread, unless
+    /// they enabled gap markers (see `enqueue`).
+    /// Hex tap clients get a hex dump of the data instead, formatted once, and
+    /// command mode clients get `text`, the data with translated line endings.
+    pub fn broadcast(&self, data: &[u8], text: &[u8]) -> Result<usize> {
         // Skip if no data to send
         if data.is_empty() {
             return Ok(0);
         }
 
         // 尽量减少锁的持有时间，先复制客户端列表
-        let client_streams: Vec<(SocketAddr, Arc<Mutex<TcpStream>>)>;
-        {
-            let clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+        let client_entries = self.entries()?;
+
+        // 使用trace级别记录详细日志，减少日志开销
+        if log::log_enabled!(log::Level::Trace) {
+            trace!("Broadcasting {} bytes to {} clients", data.len(), client_entries.len());
+        }
 
-            // Skip if no clients registered
-            if clients.is_empty() {
-                return Ok(0);
+        // 十六进制转储、Telnet转义和WebSocket帧只计算一次，所有同类客户端共用
+        let mut dump: Option<String> = None;
+        let mut escaped: Option<Vec<u8>> = None;
+        let mut ws_frame: Option<Vec<u8>> = None;
+        let mut queued_count = 0;
+        for (addr, entry) in client_entries {
+            let payload = match entry.protocol() {
+                ClientProtocol::Raw if entry.hex_tap.load(Ordering::Relaxed) => {
+                    dump.get_or_insert_with(|| hex_dump("UART>TCP", data)).as_bytes()
+                }
+                ClientProtocol::Telnet if data.contains(&rfc2217::IAC) => {
+                    escaped.get_or_insert_with(|| rfc2217::escape(data))
+                }
+                ClientProtocol::WebSocket => {
+                    ws_frame.get_or_insert_with(|| websocket::frame(websocket::OPCODE_BINARY, data))
+                }
+                ClientProtocol::Raw if !entry.raw_mode.load(Ordering::Relaxed) => text,
+                _ => data,
+            };
+            if self.enqueue(&addr, &entry, payload) {
+                queued_count += 1;
             }
+        }
 
-            // 复制客户端列表，这样可以快速释放锁
-            client_streams = clients.iter().map(|(addr, stream)| (*addr, Arc::clone(stream))).collect();
+        if queued_count > 0 {
+            self.counters.bytes_broadcast.fetch_add(data.len() as u64, Ordering::Relaxed);
         }
 
-        // 记录断开连接的客户端
-        let mut disconnected_clients = Vec::new();
-        let mut success_count = 0;
+        Ok(queued_count)
+    }
 
-        // 使用trace级别记录详细日志，减少日志开销
-        if log::log_enabled!(log::Level::Trace) {
-            trace!("Broadcasting {} bytes to {} clients", data.len(), client_streams.len());
+    /// Queue a hex dump of data a client sent to UART for the other hex tap clients
+    ///
+    /// Does nothing unless some client is in hex tap mode.
+    pub fn tap_uart_tx(&self, source: &SocketAddr, data: &[u8]) {
+        if data.is_empty() || self.tap_clients.load(Ordering::Relaxed) == 0 {
+            return;
         }
+        let Ok(client_entries) = self.entries() else {
+            return;
+        };
 
-        // 处理所有客户端
-        for (addr, stream_arc) in client_streams {
-            // 尝试获取流的锁
-            if let Ok(mut stream) = stream_arc.lock() {
-                // 尝试写入数据
-                match stream.write_all(data) {
-                    Ok(_) => {
-                        // 立即刷新以提高响应速度
-                        if let Err(e) = stream.flush() {
-                            // 检查是否是临时错误
-                            let error_string = format!("{:?}", e);
-                            if !error_string.contains("WouldBlock") && !error_string.contains("TimedOut") {
-                                // 真正的错误，断开连接
-                                disconnected_clients.push(addr);
-                                continue;
-                            }
+        let mut dump: Option<String> = None;
+        for (addr, entry) in client_entries {
+            if addr == *source || !entry.hex_tap.load(Ordering::Relaxed) {
+                continue;
+            }
+            let payload = dump.get_or_insert_with(|| hex_dump(&format!("{}>UART", source), data));
+            self.enqueue(&addr, &entry, payload.as_bytes());
+        }
+    }
+
+    /// Queue device log lines for the clients that subscribed to them
+    ///
+    /// Does nothing unless some client receives the log. WebSocket clients get the
+    /// lines as one text message.
+    pub fn send_log(&self, text: &str) {
+        if text.is_empty() || self.log_clients.load(Ordering::Relaxed) == 0 {
+            return;
+        }
+        let Ok(client_entries) = self.entries() else {
+            return;
+        };
+
+        let mut ws_frame: Option<Vec<u8>> = None;
+        for (addr, entry) in client_entries {
+            if !entry.log_stream.load(Ordering::Relaxed) {
+                continue;
+            }
+            let payload = match entry.protocol() {
+                ClientProtocol::WebSocket => ws_frame
+                    .get_or_insert_with(|| websocket::frame(websocket::OPCODE_TEXT, text.as_bytes())),
+                _ => text.as_bytes(),
+            };
+            self.enqueue(&addr, &entry, payload);
+        }
+    }
+
+    /// Queue data for one client as is, behind the data already queued for it
+    ///
+    /// Returns false if the client is being dropped or its queue would overflow.
+    pub fn queue_to(&self, addr: &SocketAddr, data: &[u8]) -> Result<bool> {
+        let entry = self.get_entry(addr)?;
+        Ok(self.enqueue(addr, &entry, data))
+    }
+
+    /// Append data to a client's outbound queue
+    ///
+    /// Returns false if the client is being dropped or its queue would overflow.
+    /// Virtual clients get the data directly instead. A client with gap markers
+    /// enabled is kept when its queue would overflow: the data is dropped and
+    /// reported with one marker once the data queued before the gap is written.
+    fn enqueue(&self, addr: &SocketAddr, entry: &ClientEntry, data: &[u8]) -> bool {
+        if let ClientLink::Virtual(client) = &entry.link {
+            if !client.deliver(data) {
+                entry.dropped_bytes.fetch_add(data.len(), Ordering::Relaxed);
+                return false;
+            }
+            entry.bytes_sent.fetch_add(data.len() as u64, Ordering::Relaxed);
+            entry.touch();
+            return true;
+        }
+        if entry.overflowed.load(Ordering::Relaxed) {
+            return false;
+        }
+        let Ok(mut outbound) = entry.outbound.lock() else {
+            return false;
+        };
+        let overflow = outbound.len() + data.len() > self.queue_limit;
+        // 标记写出之前继续丢弃，使标记正好位于缺口处
+        let gap_pending = entry.dropped_bytes.load(Ordering::Relaxed) > 0;
+        if entry.mark_gaps.load(Ordering::Relaxed) && (overflow || gap_pending) {
+            if !gap_pending {
+                debug!("Client {} is too slow ({} bytes queued), dropping data", addr, outbound.len());
+            }
+            entry.dropped_bytes.fetch_add(data.len(), Ordering::Relaxed);
+            return false;
+        }
+        if overflow {
+            // 客户端太慢，交给写线程断开，不阻塞UART转发
+            warn!(
+                "Client {} is too slow ({} bytes queued), dropping it",
+                addr,
+                outbound.len()
+            );
+            entry.overflowed.store(true, Ordering::Relaxed);
+            return false;
+        }
+        outbound.extend(data);
+        true
+    }
+
+    /// Start the `tcp_tx` thread that writes queued data to the clients
+    ///
+    /// Calling this more than once has no effect.
+    pub fn start_writer(self_arc: &Arc<Self>) -> Result<()> {
+        if self_arc.writer_started.swap(true, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let manager = Arc::clone(self_arc);
+
+        std::thread::Builder::new()
+            .name("tcp_tx".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let _tracker = diagnostics::track_thread("tcp_tx");
+                loop {
+                    let wrote = match manager.write_queued() {
+                        Ok(wrote) => wrote,
+                        Err(e) => {
+                            error!("Failed to write queued client data: {}", e);
+                            false
                         }
-                        success_count += 1;
+                    };
+                    // 没有数据可写时短暂休眠，降低CPU占用
+                    if !wrote {
+                        std::thread::sleep(Duration::from_millis(1));
+                    }
+                }
+            })
+            .map_err(|e| Error::ClientError(format!("Failed to spawn client writer thread: {}", e)))?;
+
+        Ok(())
+    }
+
+    /// Write as much queued data as each client accepts without blocking
+    ///
+    /// Called by the writer thread, or directly by a server that multiplexes its
+    /// clients and does not start one. Returns true if any data was written.
+    pub fn write_queued(&self) -> Result<bool> {
+        let mut wrote = false;
+        let mut disconnected_clients = Vec::new();
+
+        for (addr, entry) in self.entries()? {
+            if entry.overflowed.load(Ordering::Relaxed) {
+                disconnected_clients.push((addr, entry.id, "Connection closed: client too slow\r\n"));
+                continue;
+            }
+            let gap_pending = entry.dropped_bytes.load(Ordering::Relaxed) > 0;
+            if !gap_pending && entry.outbound.lock().map(|outbound| outbound.is_empty()).unwrap_or(true) {
+                continue;
+            }
+
+            // 虚拟客户端的队列始终为空，这里只剩TCP流
+            let ClientLink::Stream(stream) = &entry.link else {
+                continue;
+            };
+            // 尝试获取流的锁
+            let Ok(mut stream) = stream.lock() else {
+                // 无法获取流的锁
+                disconnected_clients.push((addr, entry.id, ""));
+                continue;
+            };
+
+            let result = {
+                let Ok(mut outbound) = entry.outbound.lock() else {
+                    continue;
+                };
+                let (front, _) = outbound.as_slices();
+                let result = Self::write_available(&mut *stream, front);
+                if let Ok(written) = result {
+                    outbound.drain(..written);
+                    entry.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+                }
+                result.map(|written| (written, outbound.is_empty()))
+            };
+
+            // 缺口之前排队的数据全部写出后，在缺口处插入标记
+            let result = match result {
+                Ok((_, true)) if !Self::write_pending_gap_marker(&mut *stream, &entry, &addr) => {
+                    Err(io::ErrorKind::ConnectionReset.into())
+                }
+                result => result.map(|(written, _)| written),
+            };
+
+            match result {
+                Ok(written) => {
+                    if written > 0 {
+                        wrote = true;
+                        entry.touch();
                     }
-                    Err(e) => {
-                        // 检查是否是临时错误
-                        let error_string = format!("{:?}", e);
-                        if !error_string.contains("WouldBlock") && !error_string.contains("TimedOut") {
-                            // 真正的错误，断开连接
-                            disconnected_clients.push(addr);
+
+                    // 立即刷新以提高响应速度
+                    if let Err(e) = stream.flush() {
+                        // 临时错误下次再试，其他错误断开连接
+                        if !Self::is_transient(&e) {
+                            disconnected_clients.push((addr, entry.id, ""));
                         }
                     }
                 }
-            } else {
-                // 无法获取流的锁
-                disconnected_clients.push(addr);
+                Err(_) => {
+                    // 真正的错误，断开连接
+                    disconnected_clients.push((addr, entry.id, ""));
+                }
             }
         }
 
         // 如果有断开连接的客户端，则移除它们
-        if !disconnected_clients.is_empty() {
-            let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
-            for addr in disconnected_clients {
-                clients.remove(&addr);
-                debug!("Removed disconnected client {}", addr);
+        for (addr, id, message) in disconnected_clients {
+            let removed = {
+                let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+                self.remove_connection(&mut clients, &addr, id)
+            };
+            if let Some(entry) = removed {
+                self.counters.broadcast_errors.fetch_add(1, Ordering::Relaxed);
+                let overflowed = entry.overflowed.load(Ordering::Relaxed);
+                if overflowed {
+                    self.counters.clients_evicted.fetch_add(1, Ordering::Relaxed);
+                }
+                self.close_removed_entry(&addr, &entry, message);
+                self.emit(if overflowed {
+                    ClientEvent::Evicted { addr, reason: EvictionReason::TooSlow }
+                } else {
+                    ClientEvent::Disconnected {
+                        addr,
+                        reason: DisconnectReason::WriteFailed,
+                        connected_for: entry.connected.elapsed(),
+                    }
+                });
             }
         }
 
-        Ok(success_count)
+        Ok(wrote)
     }
 
-    /// Get the number of connected clients
-    /// Uses atomic counter for better performance
-    pub fn client_count(&self) -> Result<usize> {
-        // 尝试使用原子计数器
-        let count = self.client_count.load(std::sync::atomic::Ordering::Relaxed);
+    /// Get a snapshot of the counters
+    pub fn stats(&self) -> ClientStats {
+        ClientStats {
+            bytes_broadcast: self.counters.bytes_broadcast.load(Ordering::Relaxed),
+            broadcast_errors: self.counters.broadcast_errors.load(Ordering::Relaxed),
+            clients_total: self.counters.clients_total.load(Ordering::Relaxed),
+            clients_evicted: self.counters.clients_evicted.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the counters to zero
+    pub fn reset_stats(&self) {
+        self.counters.bytes_broadcast.store(0, Ordering::Relaxed);
+        self.counters.broadcast_errors.store(0, Ordering::Relaxed);
+        self.counters.clients_total.store(0, Ordering::Relaxed);
+        self.counters.clients_evicted.store(0, Ordering::Relaxed);
+    }
 
-        // 如果需要精确值，可以锁定并计数
-        if count == 0 {
-            // 可能是计数器不准确，直接计算实际客户端数量
-            let clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
-            let actual_count = clients.len();
+    /// Get a snapshot of all connected clients, sorted by address
+    pub fn list_clients(&self) -> Result<Vec<ClientInfo>> {
+        let now = time::uptime();
+        let mut clients: Vec<ClientInfo> = self
+            .entries()?
+            .into_iter()
+            .map(|(addr, entry)| ClientInfo {
+                addr,
+                connected_at: now.saturating_sub(entry.connected.elapsed()),
+                last_activity: Duration::from_millis(entry.last_activity_ms.load(Ordering::Relaxed)),
+                bytes_sent: entry.bytes_sent.load(Ordering::Relaxed),
+                bytes_received: entry.bytes_received.load(Ordering::Relaxed),
+            })
+            .collect();
+        clients.sort_by_key(|client| client.addr);
+        Ok(clients)
+    }
 
-            // 更新计数器
-            self.client_count.store(actual_count, std::sync::atomic::Ordering::Relaxed);
+    /// Get the number of bytes queued for a client
+    pub fn queue_len(&self, addr: &SocketAddr) -> Result<usize> {
+        let entry = self.get_entry(addr)?;
+        let outbound = entry.outbound.lock().map_err(|_| Error::ClientError("Failed to lock client queue".to_string()))?;
+        Ok(outbound.len())
+    }
 
-            Ok(actual_count)
-        } else {
-            Ok(count)
+    /// Get the bytes queued for the client furthest behind
+    ///
+    /// Virtual clients have no queue and count as 0.
+    pub fn max_queued_bytes(&self) -> Result<usize> {
+        // 队列锁不能在映射锁内获取
+        Ok(self
+            .entries()?
+            .iter()
+            .map(|(_, entry)| entry.outbound.lock().map(|outbound| outbound.len()).unwrap_or(0))
+            .max()
+            .unwrap_or(0))
+    }
+
+    /// Copy the client entries out of the map so the map lock is released quickly
+    fn entries(&self) -> Result<Vec<(SocketAddr, Arc<ClientEntry>)>> {
+        let clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+        Ok(clients.iter().map(|(addr, entry)| (*addr, Arc::clone(entry))).collect())
+    }
+
+    /// Write as much of `data` as the socket accepts without blocking
+    ///
+    /// Returns the number of bytes written. Temporary errors stop the write early,
+    /// any other error means the connection is broken.
+    fn write_available(stream: &mut dyn ClientStream, data: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        while written < data.len() {
+            match stream.write(&data[written..]) {
+                Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "failed to write to client")),
+                Ok(n) => written += n,
+                // 被信号中断时立即重试
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                // 发送缓冲区已满，剩余数据留在队列中
+                Err(e) if Self::is_transient(&e) => break,
+                Err(e) => return Err(e),
+            }
         }
+        Ok(written)
     }
-}
 
-/// Create a new TCP client manager wrapped in an Arc for thread-safe sharing
-pub fn create_tcp_client_manager() -> Arc<TcpClientManager> {
-    Arc::new(TcpClientManager::new())
+    /// Check whether a socket error only means "try again later"
+    ///
+    /// Any other error (e.g. BrokenPipe or ConnectionReset) means the connection is broken.
+    pub fn is_transient(e: &io::Error) -> bool {
+        matches!(
+            e.kind(),
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut | io::ErrorKind::Interrupted
+        )
+    }
+
+    /// Inject a gap marker for a client that opted in and had data dropped
+    ///
+    /// Returns false if the connection is broken. If the marker cannot be written
+    /// completely the rest is put at the front of the client's queue.
+    /// Markers are never injected into the stream of a raw mode client.
+    fn write_pending_gap_marker(stream: &mut dyn ClientStream, entry: &ClientEntry, addr: &SocketAddr) -> bool {
+        let dropped = entry.dropped_bytes.load(Ordering::Relaxed);
+        if dropped == 0 {
+            return true;
+        }
+        // 标记在丢弃后被关闭时不再报告
+        if entry.raw_mode.load(Ordering::Relaxed) || !entry.mark_gaps.load(Ordering::Relaxed) {
+            entry.dropped_bytes.fetch_sub(dropped, Ordering::Relaxed);
+            return true;
+        }
+
+        let marker = gap_marker(dropped);
+        let result = Self::write_available(stream, marker.as_bytes());
+        if let Ok(written) = result {
+            entry.bytes_sent.fetch_add(written as u64, Ordering::Relaxed);
+        }
+        match result {
+            Ok(written) if written == marker.len() => {
+                entry.dropped_bytes.fetch_sub(dropped, Ordering::Relaxed);
+                debug!("Reported {} dropped bytes to client {}", dropped, addr);
+                true
+            }
+            Ok(written) => {
+                // 剩余的标记放到队列最前面，随后续数据一起写出
+                entry.dropped_bytes.fetch_sub(dropped, Ordering::Relaxed);
+                if let Ok(mut outbound) = entry.outbound.lock() {
+                    for &byte in marker.as_bytes()[written..].iter().rev() {
+                        outbound.push_front(byte);
+                    }
+                }
+                debug!("Gap marker for client {} partially written, rest queued", addr);
+                true
+            }
+            Err(_) => false,
+        }
+    }
+
+    /// Enable or disable gap markers for a client
+    ///
+    /// Drops that happened before enabling are not reported.
+    pub fn set_mark_gaps(&self, addr: &SocketAddr, enabled: bool) -> Result<()> {
+        let entry = self.get_entry(addr)?;
+        if enabled && !entry.mark_gaps.load(Ordering::Relaxed) {
+            entry.dropped_bytes.store(0, Ordering::Relaxed);
+        }
+        entry.mark_gaps.store(enabled, Ordering::Relaxed);
+        info!("Gap markers {} for client {}", if enabled { "enabled" } else { "disabled" }, addr);
+        Ok(())
+    }
+
+    /// Check whether gap markers are enabled for a client
+    pub fn mark_gaps(&self, addr: &SocketAddr) -> Result<bool> {
+        Ok(self.get_entry(addr)?.mark_gaps.load(Ordering::Relaxed))
+    }
+
+    /// Switch a client into or out of raw transparent mode
+    pub fn set_raw_mode(&self, addr: &SocketAddr, enabled: bool) -> Result<()> {
+        self.get_entry(addr)?.raw_mode.store(enabled, Ordering::Relaxed);
+        info!("Client {} {} raw mode", addr, if enabled { "entered" } else { "left" });
+        Ok(())
+    }
+
+    /// Check whether a client is in raw transparent mode
+    pub fn is_raw_mode(&self, addr: &SocketAddr) -> bool {
+        self.get_entry(addr)
+            .map(|entry| entry.raw_mode.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// Enable or disable local echo for a client
+    pub fn set_echo(&self, addr: &SocketAddr, enabled: bool) -> Result<()> {
+        self.get_entry(addr)?.echo.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Check whether local echo is enabled for a client
+    pub fn is_echo(&self, addr: &SocketAddr) -> bool {
+        self.get_entry(addr)
+            .map(|entry| entry.echo.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// Switch a client between verbose command replies and the OK/ERROR format
+    pub fn set_verbose(&self, addr: &SocketAddr, enabled: bool) -> Result<()> {
+        self.get_entry(addr)?.verbose.store(enabled, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Check whether a client receives verbose command replies
+    pub fn is_verbose(&self, addr: &SocketAddr) -> bool {
+        self.get_entry(addr)
+            .map(|entry| entry.verbose.load(Ordering::Relaxed))
+            .unwrap_or(false)
+    }
+
+    /// Switch a client between hex tap mode and raw data
+    ///
+    /// In hex tap mode the client receives UART data, and data other clients send
+    /// to UART, as hex dumps (see `hex_dump`).
+    pub fn set_hex_tap(&self, addr: &SocketAddr, enabled: bool) -> Result<()> {
+        let entry = self.get_entry(addr)?;
+        if entry.hex_tap.swap(enabled, Ordering::Relaxed) != enabled {
+            if enabled {
+                self.tap_clients.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.tap_clients.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        info!("Client {} tap mode {}", addr, if enabled { "HEX" } else { "RAW" });
+        Ok(())
+    }
+
+    /// Subscribe a client to the device log or cancel the subscription
+    pub fn set_log_stream(&self, addr: &SocketAddr, enabled: bool) -> Result<()> {
+        let entry = self.get_entry(addr)?;
+        if entry.log_stream.swap(enabled, Ordering::Relaxed) != enabled {
+            if enabled {
+                self.log_clients.fetch_add(1, Ordering::Relaxed);
+            } else {
+                self.log_clients.fetch_sub(1, Ordering::Relaxed);
+            }
+        }
+        info!("Client {} log stream {}", addr, if enabled { "ON" } else { "OFF" });
+        Ok(())
+    }
+
+    /// Check whether a client receives the device log
+    pub fn is_log_stream(&self, addr: &SocketAddr) -> Result<bool> {
+        Ok(self.get_entry(addr)?.log_stream.load(Ordering::Relaxed))
+    }
+
+    /// Check whether any client receives the device log
+    pub fn has_log_clients(&self) -> bool {
+        self.log_clients.load(Ordering::Relaxed) > 0
+    }
+
+    /// Set the wire protocol broadcast data is encoded in for a client
+    pub fn set_protocol(&self, addr: &SocketAddr, protocol: ClientProtocol) -> Result<()> {
+        self.get_entry(addr)?.protocol.store(protocol as u8, Ordering::Relaxed);
+        Ok(())
+    }
+
+    /// Check whether a client is in hex tap mode
+    pub fn is_hex_tap(&self, addr: &SocketAddr) -> Result<bool> {
+        Ok(self.get_entry(addr)?.hex_tap.load(Ordering::Relaxed))
+    }
+
+    /// Record a command line in a client's history
+    ///
+    /// Lines longer than the history limit are not recorded.
+    pub fn record_command(&self, addr: &SocketAddr, line: &str) -> Result<()> {
+        if line.len() > MAX_HISTORY_LINE_LEN {
+            return Ok(());
+        }
+
+        let entry = self.get_entry(addr)?;
+        let mut history = entry.history.lock().map_err(|_| Error::ClientError("Failed to lock client history".to_string()))?;
+        if history.len() >= MAX_HISTORY_ENTRIES {
+            history.pop_front();
+        }
+        history.push_back(line.to_string());
+        Ok(())
+    }
+
+    /// Get a client's command history, oldest first
+    pub fn command_history(&self, addr: &SocketAddr) -> Result<Vec<String>> {
+        let entry = self.get_entry(addr)?;
+        let history = entry.history.lock().map_err(|_| Error::ClientError("Failed to lock client history".to_string()))?;
+        Ok(history.iter().cloned().collect())
+    }
+
+    /// Record activity from a client (e.g. data received from it)
+    pub fn touch(&self, addr: &SocketAddr) {
+        if let Ok(entry) = self.get_entry(addr) {
+            entry.touch();
+        }
+    }
+
+    /// Get the traffic counters of a connected client, to update them without the map lock
+    pub fn traffic_counter(&self, addr: &SocketAddr) -> Result<TrafficCounter> {
+        Ok(TrafficCounter { entry: self.get_entry(addr)? })
+    }
+
+    /// Record `bytes` of data received from a client, counting as activity
+    ///
+    /// Looks the client up in the map; handlers reading in a loop use a
+    /// `TrafficCounter` instead.
+    pub fn record_received(&self, addr: &SocketAddr, bytes: usize) {
+        if let Ok(entry) = self.get_entry(addr) {
+            entry.touch();
+            entry.bytes_received.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+    }
+
+    /// Close and remove clients that have been idle for longer than `timeout`
+    ///
+    /// Evicted clients are told why before their socket is shut down. Their handler
+    /// threads notice the shutdown on the next read. Returns the number of evicted clients.
+    pub fn evict_idle(&self, timeout: Duration) -> Result<usize> {
+        // 在锁内只挑选并移除空闲客户端，通知和关闭在锁外进行
+        let idle: Vec<(SocketAddr, Arc<ClientEntry>)> = {
+            let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+            let idle_addrs: Vec<SocketAddr> = clients
+                .iter()
+                .filter(|(_, entry)| !entry.is_virtual() && entry.idle_time() > timeout)
+                .map(|(addr, _)| *addr)
+                .collect();
+            let idle: Vec<_> = idle_addrs
+                .into_iter()
+                .filter_map(|addr| clients.remove(&addr).map(|entry| (addr, entry)))
+                .collect();
+            self.publish_count(clients.len());
+            idle
+        };
+
+        for (addr, entry) in &idle {
+            let idle_time = entry.idle_time();
+            self.close_removed_entry(addr, entry, "Connection closed due to inactivity\r\n");
+            self.emit(ClientEvent::Evicted {
+                addr: *addr,
+                reason: EvictionReason::Idle(idle_time),
+            });
+        }
+        self.counters.clients_evicted.fetch_add(idle.len() as u64, Ordering::Relaxed);
+
+        Ok(idle.len())
+    }
+
+    /// Close and remove the client that has been connected the longest
+    ///
+    /// Virtual clients are skipped. Returns the address of the evicted client, or
+    /// None if there are no clients.
+    pub fn evict_oldest(&self) -> Result<Option<SocketAddr>> {
+        let oldest = {
+            let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+            let oldest_addr = clients
+                .iter()
+                .filter(|(_, entry)| !entry.is_virtual())
+                .max_by_key(|(_, entry)| entry.connected.elapsed())
+                .map(|(addr, _)| *addr);
+            let oldest = oldest_addr.and_then(|addr| clients.remove(&addr).map(|entry| (addr, entry)));
+            self.publish_count(clients.len());
+            oldest
+        };
+
+        let Some((addr, entry)) = oldest else {
+            return Ok(None);
+        };
+        self.counters.clients_evicted.fetch_add(1, Ordering::Relaxed);
+        self.close_removed_entry(&addr, &entry, "Connection closed to make room for a new client\r\n");
+        self.emit(ClientEvent::Evicted { addr, reason: EvictionReason::MakeRoom });
+        Ok(Some(addr))
+    }
+
+    /// Close and remove the client with the most data queued, to free heap
+    ///
+    /// Virtual clients are skipped. Returns the address of the evicted client and the
+    /// bytes that were queued for it, or None if no client has data queued.
+    pub fn evict_most_backlogged(&self) -> Result<Option<(SocketAddr, usize)>> {
+        self.evict_backlogged(EvictionReason::LowMemory, "Connection closed: device low on memory\r\n")
+    }
+
+    /// Close and remove the client with the most data queued, which is stalling the UART
+    ///
+    /// Used when UART flow control has been holding the sender back for too long, so
+    /// a client that stopped reading cannot pause the serial bus forever. Returns like
+    /// `evict_most_backlogged`.
+    pub fn evict_stalled(&self) -> Result<Option<(SocketAddr, usize)>> {
+        self.evict_backlogged(EvictionReason::TooSlow, "Connection closed: client too slow\r\n")
+    }
+
+    /// Evict the non-virtual client with the most data queued for `reason`
+    fn evict_backlogged(&self, reason: EvictionReason, message: &str) -> Result<Option<(SocketAddr, usize)>> {
+        // 队列锁不能在映射锁内获取，先在锁外找出积压最多的客户端
+        let backlogged = self
+            .entries()?
+            .into_iter()
+            .filter(|(_, entry)| !entry.is_virtual())
+            .map(|(addr, entry)| {
+                let queued = entry.outbound.lock().map(|outbound| outbound.len()).unwrap_or(0);
+                (addr, entry, queued)
+            })
+            .filter(|(_, _, queued)| *queued > 0)
+            .max_by_key(|(_, _, queued)| *queued);
+        let Some((addr, entry, queued)) = backlogged else {
+            return Ok(None);
+        };
+
+        let removed = {
+            let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+            // 客户端可能已被其他线程移除或重新连接
+            self.remove_connection(&mut clients, &addr, entry.id)
+        };
+        let Some(entry) = removed else {
+            return Ok(None);
+        };
+        debug!("Client {} has {} bytes queued", addr, queued);
+        self.counters.clients_evicted.fetch_add(1, Ordering::Relaxed);
+        self.close_removed_entry(&addr, &entry, message);
+        self.emit(ClientEvent::Evicted { addr, reason });
+        Ok(Some((addr, queued)))
+    }
+
+    /// Forcibly disconnect a client
+    ///
+    /// The client is removed and its socket shut down, so its handler thread sees the
+    /// read fail and exits. Returns false if no client has this address, and an error
+    /// for a virtual client, which only its owner can remove.
+    pub fn disconnect(&self, addr: &SocketAddr) -> Result<bool> {
+        let removed = {
+            let mut clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+            if clients.get(addr).is_some_and(|entry| entry.is_virtual()) {
+                return Err(Error::ClientError(format!("{} is a bridge and cannot be disconnected", addr)));
+            }
+            let removed = clients.remove(addr);
+            self.publish_count(clients.len());
+            removed
+        };
+
+        let Some(entry) = removed else {
+            return Ok(false);
+        };
+        self.counters.clients_evicted.fetch_add(1, Ordering::Relaxed);
+        self.close_removed_entry(addr, &entry, "Connection closed by another client\r\n");
+        self.emit(ClientEvent::Disconnected {
+            addr: *addr,
+            reason: DisconnectReason::Kicked,
+            connected_for: entry.connected.elapsed(),
+        });
+        Ok(true)
+    }
+
+    /// Give `addr` exclusive UART TX rights
+    ///
+    /// Returns the holder if another client already has them; taking them again
+    /// as the holder succeeds.
+    pub fn acquire_exclusive(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        let mut holder = self.lock_exclusive();
+        match *holder {
+            Some(other) if other != *addr => Some(other),
+            _ => {
+                *holder = Some(*addr);
+                info!("Client {} has exclusive UART access", addr);
+                None
+            }
+        }
+    }
+
+    /// Release exclusive UART TX rights held by `addr`
+    ///
+    /// Returns the holder if another client has them, they are left in place.
+    pub fn release_exclusive(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        let mut holder = self.lock_exclusive();
+        match *holder {
+            Some(other) if other != *addr => Some(other),
+            Some(_) => {
+                *holder = None;
+                info!("Client {} released exclusive UART access", addr);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Get the client holding exclusive UART TX rights, if any
+    pub fn exclusive_holder(&self) -> Option<SocketAddr> {
+        *self.lock_exclusive()
+    }
+
+    /// Get the holder of exclusive UART TX rights if it is a client other than `addr`
+    ///
+    /// Data from `addr` must not be sent to UART while this returns Some.
+    pub fn locked_by_other(&self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.exclusive_holder().filter(|holder| holder != addr)
+    }
+
+    fn lock_exclusive(&self) -> std::sync::MutexGuard<'_, Option<SocketAddr>> {
+        // 只保存一个地址，中毒后的值仍然有效
+        self.exclusive.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Notify and shut down a client that was already removed from the map
+    ///
+    /// Also releases exclusive UART access held by the client. The removal already
+    /// updated the client count.
+    fn close_removed_entry(&self, addr: &SocketAddr, entry: &ClientEntry, message: &str) {
+        self.release_exclusive(addr);
+        self.forget_tap(entry);
+        // 虚拟客户端没有连接可关闭
+        let stream = match &entry.link {
+            ClientLink::Stream(stream) => stream.lock().ok(),
+            ClientLink::Virtual(_) => None,
+        };
+        if let Some(mut stream) = stream {
+            // WebSocket客户端用关闭帧告知原因
+            let message = match entry.protocol() {
+                ClientProtocol::WebSocket => websocket::close_frame(websocket::CLOSE_GOING_AWAY, message.trim_end()),
+                _ => message.as_bytes().to_vec(),
+            };
+            let _ = Self::write_available(&mut *stream, &message);
+            let _ = stream.flush();
+            if let Err(e) = stream.shutdown(Shutdown::Both) {
+                debug!("Failed to shut down client {}: {}", addr, e);
+            }
+        }
+    }
+
+    /// Stop counting a removed client as a hex tap or log client
+    fn forget_tap(&self, entry: &ClientEntry) {
+        if entry.hex_tap.swap(false, Ordering::Relaxed) {
+            self.tap_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+        if entry.log_stream.swap(false, Ordering::Relaxed) {
+            self.log_clients.fetch_sub(1, Ordering::Relaxed);
+        }
+    }
+
+    /// Record and report a changed number of connected clients
+    ///
+    /// Called with the map lock held and the map length, so the count cannot drift.
+    fn publish_count(&self, count: usize) {
+        self.client_count.store(count, Ordering::Relaxed);
+        debug!("Total clients: {}", count);
+        if let Some(status) = self.status.get() {
+            status.set_clients(count);
+        }
+    }
+
+    /// Look up the state of a connected client
+    fn get_entry(&self, addr: &SocketAddr) -> Result<Arc<ClientEntry>> {
+        let clients = self.clients.lock().map_err(|_| Error::ClientError("Failed to lock clients map".to_string()))?;
+        clients
+            .get(addr)
+            .cloned()
+            .ok_or_else(|| Error::ClientError(format!("Client {} not found", addr)))
+    }
+
+    /// Get the backlog in bytes above which a client is dropped
+    pub fn queue_limit(&self) -> usize {
+        self.queue_limit
+    }
+
+    /// Get the number of connected clients
+    ///
+    /// Reads the copy of the map length kept by every insert and removal, so the
+    /// UART forwarding path does not take the map lock.
+    pub fn client_count(&self) -> Result<usize> {
+        Ok(self.client_count.load(Ordering::Relaxed))
+    }
+}
+
+impl Default for TcpClientManager {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Create a new TCP client manager wrapped in an Arc for thread-safe sharing
+pub fn create_tcp_client_manager() -> Arc<TcpClientManager> {
+    Arc::new(TcpClientManager::new())
+}
+
+/// In-memory client connection for host tests
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+
+    /// Both ends of a `MockStream` as seen by the test
+    #[derive(Debug, Default)]
+    pub(crate) struct MockWire {
+        /// Bytes the client sent, not read yet
+        pub input: VecDeque<u8>,
+        /// Bytes written to the client
+        pub output: Vec<u8>,
+        /// Bytes the connection takes before writes return `WouldBlock` (None takes all)
+        pub write_capacity: Option<usize>,
+        /// Most bytes a single write takes (None takes the whole buffer)
+        pub max_write: Option<usize>,
+        /// Whether writes fail as if the peer reset the connection
+        pub broken: bool,
+        /// Set by `shutdown`
+        pub shut_down: bool,
+        /// Set by `set_nodelay`
+        pub nodelay: bool,
+    }
+
+    /// Shared handle on the wire of a mock connection
+    pub(crate) type Wire = Arc<Mutex<MockWire>>;
+
+    /// Client connection writing to and reading from a `MockWire`
+    pub(crate) struct MockStream {
+        addr: SocketAddr,
+        wire: Wire,
+    }
+
+    impl MockStream {
+        /// Create a connection from `addr`, returning it and its wire
+        pub(crate) fn new(addr: SocketAddr) -> (Arc<Mutex<Self>>, Wire) {
+            let wire = Wire::default();
+            let stream = Self { addr, wire: Arc::clone(&wire) };
+            (Arc::new(Mutex::new(stream)), wire)
+        }
+    }
+
+    /// Address of the `n`th test client
+    pub(crate) fn addr(n: u16) -> SocketAddr {
+        SocketAddr::from(([192, 168, 4, 2], 50_000 + n))
+    }
+
+    fn read_wire(wire: &Wire, buf: &mut [u8]) -> io::Result<usize> {
+        let mut wire = wire.lock().unwrap();
+        if wire.shut_down {
+            return Err(io::ErrorKind::ConnectionAborted.into());
+        }
+        if wire.input.is_empty() {
+            return Err(io::ErrorKind::WouldBlock.into());
+        }
+        let len = buf.len().min(wire.input.len());
+        for (slot, byte) in buf.iter_mut().zip(wire.input.drain(..len)) {
+            *slot = byte;
+        }
+        Ok(len)
+    }
+
+    impl Read for MockStream {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            read_wire(&self.wire, buf)
+        }
+    }
+
+    impl Write for MockStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let mut wire = self.wire.lock().unwrap();
+            if wire.broken || wire.shut_down {
+                return Err(io::ErrorKind::ConnectionReset.into());
+            }
+            let len = wire.write_capacity.map_or(buf.len(), |capacity| capacity.min(buf.len()));
+            let len = wire.max_write.map_or(len, |max| max.min(len));
+            if len == 0 && !buf.is_empty() {
+                return Err(io::ErrorKind::WouldBlock.into());
+            }
+            if let Some(capacity) = &mut wire.write_capacity {
+                *capacity -= len;
+            }
+            wire.output.extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    /// Read view of a `MockStream`
+    struct MockReader(Wire);
+
+    impl Read for MockReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            read_wire(&self.0, buf)
+        }
+    }
+
+    impl ClientStream for MockStream {
+        fn peer_addr(&self) -> io::Result<SocketAddr> {
+            Ok(self.addr)
+        }
+
+        fn set_nonblocking(&self, _nonblocking: bool) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_read_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn set_write_timeout(&self, _timeout: Option<Duration>) -> io::Result<()> {
+            Ok(())
+        }
+
+        fn shutdown(&self, _how: Shutdown) -> io::Result<()> {
+            self.wire.lock().unwrap().shut_down = true;
+            Ok(())
+        }
+
+        fn set_nodelay(&self, nodelay: bool) -> io::Result<()> {
+            self.wire.lock().unwrap().nodelay = nodelay;
+            Ok(())
+        }
+
+        fn nodelay(&self) -> io::Result<bool> {
+            Ok(self.wire.lock().unwrap().nodelay)
+        }
+
+        fn read_view(&self) -> io::Result<Box<dyn Read + Send>> {
+            Ok(Box::new(MockReader(Arc::clone(&self.wire))))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{addr, MockStream, Wire};
+    use super::*;
+    use crate::status_led::LedPattern;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+    use std::thread;
+    use std::time::Duration;
+
+    /// Connect a loopback pair and register the server side with the manager
+    ///
+    /// All pairs go through one listener: connections to different listeners may
+    /// get the same local port, and the manager tells clients apart by address.
+    fn connect(manager: &TcpClientManager) -> (SocketAddr, TcpStream) {
+        static LISTENER: std::sync::OnceLock<Mutex<TcpListener>> = std::sync::OnceLock::new();
+        let listener = LISTENER
+            .get_or_init(|| Mutex::new(TcpListener::bind("127.0.0.1:0").unwrap()))
+            .lock()
+            .unwrap_or_else(|e| e.into_inner());
+        let peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        manager.add_client(addr, Arc::new(Mutex::new(stream))).unwrap();
+        peer.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        (addr, peer)
+    }
+
+    /// Connection id of a connected client
+    fn id_of(manager: &TcpClientManager, addr: &SocketAddr) -> ConnectionId {
+        manager.get_entry(addr).unwrap().id
+    }
+
+    /// TCP stream of a client that is not virtual
+    fn stream_of(entry: &ClientEntry) -> &SharedStream {
+        match &entry.link {
+            ClientLink::Stream(stream) => stream,
+            ClientLink::Virtual(_) => panic!("not a TCP client"),
+        }
+    }
+
+    fn read_exact(peer: &mut TcpStream, len: usize) -> String {
+        let mut buf = vec![0; len];
+        peer.read_exact(&mut buf).unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+
+    #[test]
+    fn gap_marker_reports_the_dropped_byte_count() {
+        assert_eq!(gap_marker(42), "\r\n[---- 42 bytes dropped ----]\r\n");
+    }
+
+    #[test]
+    fn gap_markers_are_refused_for_unknown_clients() {
+        let manager = TcpClientManager::new();
+        let addr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(manager.set_mark_gaps(&addr, true).is_err());
+        assert!(manager.mark_gaps(&addr).is_err());
+    }
+
+    #[test]
+    fn gap_is_marked_after_the_data_queued_before_it() {
+        let manager = TcpClientManager::with_queue_limit(8);
+        let (addr, mut peer) = connect(&manager);
+        assert!(!manager.mark_gaps(&addr).unwrap());
+        manager.set_mark_gaps(&addr, true).unwrap();
+        assert!(manager.mark_gaps(&addr).unwrap());
+
+        assert_eq!(manager.broadcast(b"12345678", b"12345678").unwrap(), 1);
+        // 队列已满，之后的数据在标记写出之前都被丢弃
+        assert_eq!(manager.broadcast(b"abc", b"abc").unwrap(), 0);
+        assert_eq!(manager.broadcast(b"de", b"de").unwrap(), 0);
+        assert!(manager.write_queued().unwrap());
+
+        let expected = format!("12345678{}", gap_marker(5));
+        assert_eq!(read_exact(&mut peer, expected.len()), expected);
+        assert!(manager.is_client_connected(&addr));
+
+        manager.broadcast(b"next", b"next").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut peer, 4), "next");
+    }
+
+    #[test]
+    fn slow_client_without_gap_markers_is_dropped() {
+        let manager = TcpClientManager::with_queue_limit(4);
+        let (addr, mut peer) = connect(&manager);
+
+        assert_eq!(manager.broadcast(b"data", b"data").unwrap(), 1);
+        assert_eq!(manager.queue_len(&addr).unwrap(), 4);
+        assert_eq!(manager.broadcast(b"x", b"x").unwrap(), 0);
+        manager.write_queued().unwrap();
+
+        assert!(!manager.is_client_connected(&addr));
+        let notice = "Connection closed: client too slow\r\n";
+        assert_eq!(read_exact(&mut peer, notice.len()), notice);
+    }
+
+    #[test]
+    fn writer_thread_drains_the_queues() {
+        let manager = Arc::new(TcpClientManager::new());
+        let (addr, mut peer) = connect(&manager);
+        TcpClientManager::start_writer(&manager).unwrap();
+        // 重复启动不会再创建写线程
+        TcpClientManager::start_writer(&manager).unwrap();
+
+        assert_eq!(manager.broadcast(b"hello", b"hello").unwrap(), 1);
+        assert_eq!(read_exact(&mut peer, 5), "hello");
+        assert_eq!(manager.queue_len(&addr).unwrap(), 0);
+    }
+
+    #[test]
+    fn client_map_stays_usable_while_the_writer_waits_for_a_stream() {
+        let manager = Arc::new(TcpClientManager::new());
+        let (addr, mut peer) = connect(&manager);
+        let entry = manager.get_entry(&addr).unwrap();
+        let stream = stream_of(&entry).lock().unwrap();
+
+        // 广播只是排队，不等待客户端流
+        assert_eq!(manager.broadcast(b"data", b"data").unwrap(), 1);
+        let writer = {
+            let manager = Arc::clone(&manager);
+            thread::spawn(move || manager.write_queued().unwrap())
+        };
+        thread::sleep(Duration::from_millis(50));
+
+        // 写线程在等待客户端流的锁时不能占用客户端表的锁
+        let (done_tx, done_rx) = mpsc::channel();
+        {
+            let manager = Arc::clone(&manager);
+            thread::spawn(move || {
+                let (other, _peer) = connect(&manager);
+                done_tx.send((manager.is_client_connected(&other), manager.client_count().unwrap())).unwrap();
+            });
+        }
+        assert_eq!(done_rx.recv_timeout(Duration::from_secs(2)).unwrap(), (true, 2));
+
+        drop(stream);
+        assert!(writer.join().unwrap());
+        assert_eq!(read_exact(&mut peer, 4), "data");
+    }
+
+    #[test]
+    fn history_keeps_the_most_recent_commands() {
+        let manager = TcpClientManager::new();
+        let (addr, _peer) = connect(&manager);
+        assert!(manager.command_history(&addr).unwrap().is_empty());
+
+        for i in 0..MAX_HISTORY_ENTRIES + 2 {
+            manager.record_command(&addr, &format!("AT+CMD{}", i)).unwrap();
+        }
+        let history = manager.command_history(&addr).unwrap();
+        assert_eq!(history.len(), MAX_HISTORY_ENTRIES);
+        assert_eq!(history[0], "AT+CMD2");
+        assert_eq!(history[MAX_HISTORY_ENTRIES - 1], format!("AT+CMD{}", MAX_HISTORY_ENTRIES + 1));
+    }
+
+    #[test]
+    fn overlong_commands_are_not_recorded() {
+        let manager = TcpClientManager::new();
+        let (addr, _peer) = connect(&manager);
+        manager.record_command(&addr, &"X".repeat(MAX_HISTORY_LINE_LEN + 1)).unwrap();
+        manager.record_command(&addr, &"X".repeat(MAX_HISTORY_LINE_LEN)).unwrap();
+        assert_eq!(manager.command_history(&addr).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn idle_clients_are_told_and_evicted() {
+        let _clock = time::lock_clock();
+        let manager = TcpClientManager::new();
+        let (idle, mut idle_peer) = connect(&manager);
+        let (active, _active_peer) = connect(&manager);
+
+        time::advance(Duration::from_secs(30));
+        manager.touch(&active);
+        assert_eq!(manager.evict_idle(Duration::from_secs(20)).unwrap(), 1);
+
+        assert!(!manager.is_client_connected(&idle));
+        assert!(manager.is_client_connected(&active));
+        assert_eq!(manager.client_count().unwrap(), 1);
+
+        let notice = "Connection closed due to inactivity\r\n";
+        assert_eq!(read_exact(&mut idle_peer, notice.len()), notice);
+        assert_eq!(idle_peer.read(&mut [0; 1]).unwrap(), 0);
+    }
+
+    #[test]
+    fn longest_connected_client_makes_room() {
+        let _clock = time::lock_clock();
+        let manager = TcpClientManager::new();
+        assert_eq!(manager.evict_oldest().unwrap(), None);
+
+        let (oldest, mut oldest_peer) = connect(&manager);
+        time::advance(Duration::from_secs(1));
+        let (newer, _newer_peer) = connect(&manager);
+
+        assert_eq!(manager.evict_oldest().unwrap(), Some(oldest));
+        assert!(manager.is_client_connected(&newer));
+        assert_eq!(manager.client_count().unwrap(), 1);
+
+        let notice = "Connection closed to make room for a new client\r\n";
+        assert_eq!(read_exact(&mut oldest_peer, notice.len()), notice);
+    }
+
+    #[test]
+    fn client_with_the_largest_backlog_is_evicted_first() {
+        let manager = TcpClientManager::new();
+        assert_eq!(manager.evict_most_backlogged().unwrap(), None);
+
+        let (small, _small_peer) = connect(&manager);
+        let (large, mut large_peer) = connect(&manager);
+        let (idle, _idle_peer) = connect(&manager);
+        manager.get_entry(&small).unwrap().outbound.lock().unwrap().extend(b"abc");
+        manager.get_entry(&large).unwrap().outbound.lock().unwrap().extend(b"abcdefgh");
+
+        assert_eq!(manager.evict_most_backlogged().unwrap(), Some((large, 8)));
+        assert!(!manager.is_client_connected(&large));
+        assert_eq!(manager.client_count().unwrap(), 2);
+        let notice = "Connection closed: device low on memory\r\n";
+        assert_eq!(read_exact(&mut large_peer, notice.len()), notice);
+
+        // 没有积压的客户端不会被断开
+        assert_eq!(manager.evict_most_backlogged().unwrap(), Some((small, 3)));
+        assert_eq!(manager.evict_most_backlogged().unwrap(), None);
+        assert!(manager.is_client_connected(&idle));
+    }
+
+    #[test]
+    fn only_command_mode_clients_get_translated_line_endings() {
+        let manager = TcpClientManager::new();
+        let (command, mut command_peer) = connect(&manager);
+        let (raw, mut raw_peer) = connect(&manager);
+        manager.set_raw_mode(&raw, true).unwrap();
+        assert!(!manager.is_raw_mode(&command));
+
+        manager.broadcast(b"ok\n", b"ok\r\n").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut command_peer, 4), "ok\r\n");
+        assert_eq!(read_exact(&mut raw_peer, 3), "ok\n");
+    }
+
+    #[test]
+    fn traffic_counter_counts_without_a_lookup_and_keeps_the_client_active() {
+        let _clock = time::lock_clock();
+        let manager = TcpClientManager::new();
+        let (addr, _peer) = connect(&manager);
+        let unknown: SocketAddr = "127.0.0.1:1".parse().unwrap();
+        assert!(manager.traffic_counter(&unknown).is_err());
+
+        let traffic = manager.traffic_counter(&addr).unwrap();
+        time::advance(Duration::from_secs(30));
+        traffic.record_received(7);
+        traffic.record_received(5);
+        assert_eq!(manager.evict_idle(Duration::from_secs(20)).unwrap(), 0);
+
+        let clients = manager.list_clients().unwrap();
+        assert_eq!(clients[0].bytes_received, 12);
+        assert!(time::uptime().saturating_sub(clients[0].connected_at) >= Duration::from_secs(30));
+    }
+
+    #[test]
+    fn raw_mode_clients_never_get_gap_markers() {
+        let manager = TcpClientManager::with_queue_limit(4);
+        let (addr, mut peer) = connect(&manager);
+        manager.set_mark_gaps(&addr, true).unwrap();
+        manager.set_raw_mode(&addr, true).unwrap();
+        assert!(manager.is_raw_mode(&addr));
+
+        manager.broadcast(b"data", b"data").unwrap();
+        manager.broadcast(b"lost", b"lost").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut peer, 4), "data");
+
+        // 缺口已被清除，之后的数据正常送达
+        manager.broadcast(b"more", b"more").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut peer, 4), "more");
+    }
+
+    #[test]
+    fn clients_are_listed_in_address_order() {
+        let manager = TcpClientManager::new();
+        assert!(manager.list_clients().unwrap().is_empty());
+
+        let listed = |manager: &TcpClientManager| -> Vec<SocketAddr> {
+            manager.list_clients().unwrap().iter().map(|client| client.addr).collect()
+        };
+        let mut addrs: Vec<SocketAddr> = (0..3).map(|_| connect(&manager).0).collect();
+        addrs.sort();
+        assert_eq!(listed(&manager), addrs);
+
+        manager.remove_client(&addrs[1], id_of(&manager, &addrs[1])).unwrap();
+        assert_eq!(listed(&manager), [addrs[0], addrs[2]]);
+    }
+
+    #[test]
+    fn traffic_is_counted_per_client() {
+        let _clock = time::lock_clock();
+        let manager = TcpClientManager::new();
+        let (addr, mut peer) = connect(&manager);
+
+        manager.broadcast(b"hello", b"hello").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut peer, 5), "hello");
+        time::advance(Duration::from_secs(3));
+        manager.record_received(&addr, 12);
+
+        let clients = manager.list_clients().unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].bytes_sent, 5);
+        assert_eq!(clients[0].bytes_received, 12);
+        assert!(clients[0]
+            .to_string()
+            .ends_with("connected 0d 00h 00m 03s, idle 0d 00h 00m 00s, sent 5 bytes, received 12 bytes"));
+    }
+
+    #[test]
+    fn stats_count_traffic_and_evictions() {
+        let manager = TcpClientManager::with_queue_limit(4);
+        let (fast, mut fast_peer) = connect(&manager);
+        let (slow, _slow_peer) = connect(&manager);
+
+        manager.broadcast(b"data", b"data").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut fast_peer, 4), "data");
+
+        // 慢客户端的队列溢出后被断开
+        manager.get_entry(&slow).unwrap().outbound.lock().unwrap().extend(b"full");
+        manager.broadcast(b"x", b"x").unwrap();
+        manager.write_queued().unwrap();
+        assert!(manager.is_client_connected(&fast));
+        assert!(!manager.is_client_connected(&slow));
+
+        assert_eq!(
+            manager.stats(),
+            ClientStats {
+                bytes_broadcast: 5,
+                broadcast_errors: 1,
+                clients_total: 2,
+                clients_evicted: 1,
+            }
+        );
+        manager.reset_stats();
+        assert_eq!(manager.stats(), ClientStats::default());
+    }
+
+    #[test]
+    fn kicked_client_is_told_and_disconnected() {
+        let manager = TcpClientManager::new();
+        let (addr, mut peer) = connect(&manager);
+        let (other, _other_peer) = connect(&manager);
+
+        assert!(manager.disconnect(&addr).unwrap());
+        assert_eq!(read_exact(&mut peer, 37), "Connection closed by another client\r\n");
+        assert_eq!(peer.read(&mut [0u8; 1]).unwrap(), 0);
+        assert!(!manager.disconnect(&addr).unwrap());
+
+        let clients = manager.list_clients().unwrap();
+        assert_eq!(clients.len(), 1);
+        assert_eq!(clients[0].addr, other);
+        assert_eq!(manager.stats().clients_evicted, 1);
+    }
+
+    #[test]
+    fn only_the_lock_holder_may_release_it() {
+        let manager = TcpClientManager::new();
+        let (first, _first_peer) = connect(&manager);
+        let (second, _second_peer) = connect(&manager);
+
+        assert_eq!(manager.acquire_exclusive(&first), None);
+        assert_eq!(manager.acquire_exclusive(&first), None);
+        assert_eq!(manager.acquire_exclusive(&second), Some(first));
+        assert_eq!(manager.locked_by_other(&second), Some(first));
+        assert_eq!(manager.locked_by_other(&first), None);
+
+        assert_eq!(manager.release_exclusive(&second), Some(first));
+        assert_eq!(manager.release_exclusive(&first), None);
+        assert_eq!(manager.exclusive_holder(), None);
+    }
+
+    #[test]
+    fn lock_is_released_when_the_holder_leaves() {
+        let manager = TcpClientManager::new();
+        let (holder, _holder_peer) = connect(&manager);
+        let (kicked, _kicked_peer) = connect(&manager);
+
+        manager.acquire_exclusive(&holder);
+        manager.remove_client(&holder, id_of(&manager, &holder)).unwrap();
+        assert_eq!(manager.exclusive_holder(), None);
+
+        manager.acquire_exclusive(&kicked);
+        manager.disconnect(&kicked).unwrap();
+        assert_eq!(manager.exclusive_holder(), None);
+    }
+
+    #[test]
+    fn client_count_is_published_to_the_status() {
+        let manager = TcpClientManager::new();
+        let status = Arc::new(DeviceStatus::new());
+        status.set_wifi_up(true);
+        let (first, _first_peer) = connect(&manager);
+        manager.set_status(status.clone());
+        assert_eq!(status.pattern(), LedPattern::Clients);
+
+        let (second, _second_peer) = connect(&manager);
+        manager.remove_client(&first, id_of(&manager, &first)).unwrap();
+        assert_eq!(status.pattern(), LedPattern::Clients);
+        manager.disconnect(&second).unwrap();
+        assert_eq!(status.pattern(), LedPattern::Idle);
+    }
+
+    #[test]
+    fn polling_server_drains_queues_without_a_writer_thread() {
+        let manager = TcpClientManager::new();
+        let mut clients: Vec<_> = (0..9).map(|_| connect(&manager)).collect();
+
+        manager.broadcast(b"reading 42\r\n", b"reading 42\r\n").unwrap();
+        // 有排队数据的套接字会被poll()等待可写
+        for (addr, _) in &clients {
+            assert_eq!(manager.queue_len(addr).unwrap(), 12);
+        }
+
+        assert!(manager.write_queued().unwrap());
+        for (addr, peer) in &mut clients {
+            assert_eq!(manager.queue_len(addr).unwrap(), 0);
+            assert_eq!(read_exact(peer, 12), "reading 42\r\n");
+        }
+        assert!(!manager.write_queued().unwrap());
+    }
+
+    #[test]
+    fn hex_dump_shows_offsets_bytes_and_ascii() {
+        let dump = hex_dump("UART>TCP", b"OK\r\n0123456789abcdefXYZ");
+        let lines: Vec<&str> = dump.split("\r\n").collect();
+        assert!(lines[0].ends_with("] UART>TCP 23 bytes"), "{}", lines[0]);
+        assert_eq!(lines[1], "  0000  4f 4b 0d 0a 30 31 32 33 34 35 36 37 38 39 61 62  |OK..0123456789ab|");
+        assert_eq!(lines[2], "  0010  63 64 65 66 58 59 5a                             |cdefXYZ|");
+        assert_eq!(lines[3], "");
+
+        let large = hex_dump("UART>TCP", &[0; MAX_TAP_DUMP_BYTES + 10]);
+        assert!(large.ends_with("  ... 10 more bytes\r\n"));
+    }
+
+    #[test]
+    fn tap_clients_get_dumps_of_both_directions() {
+        let manager = TcpClientManager::new();
+        let (plain, mut plain_peer) = connect(&manager);
+        let (tap, mut tap_peer) = connect(&manager);
+        manager.set_hex_tap(&tap, true).unwrap();
+
+        manager.broadcast(b"hi", b"hi").unwrap();
+        manager.tap_uart_tx(&plain, b"AT\r");
+        // 监听客户端自己发送的数据不回显
+        manager.tap_uart_tx(&tap, b"ignored");
+        manager.write_queued().unwrap();
+
+        assert_eq!(manager.queue_len(&plain).unwrap(), 0);
+        assert_eq!(read_exact(&mut plain_peer, 2), "hi");
+        let expected = hex_dump("UART>TCP", b"hi") + &hex_dump(&format!("{}>UART", plain), b"AT\r");
+        // 时间戳在两次格式化之间可能变化，只比较去掉时间戳后的内容
+        let received = read_exact(&mut tap_peer, expected.len());
+        let strip = |dump: &str| -> Vec<String> {
+            dump.lines().map(|line| line.split_once("] ").map_or(line, |(_, rest)| rest).to_string()).collect()
+        };
+        assert_eq!(strip(&received), strip(&expected));
+    }
+
+    /// Bridge that takes data until it is full
+    struct Bridge {
+        received: Mutex<Vec<u8>>,
+        capacity: usize,
+    }
+
+    impl VirtualClient for Bridge {
+        fn deliver(&self, data: &[u8]) -> bool {
+            let mut received = self.received.lock().unwrap();
+            if received.len() + data.len() > self.capacity {
+                return false;
+            }
+            received.extend_from_slice(data);
+            true
+        }
+    }
+
+    #[test]
+    fn virtual_clients_get_broadcasts_directly_and_stay_connected() {
+        let manager = TcpClientManager::new();
+        let bridge = Arc::new(Bridge { received: Mutex::new(Vec::new()), capacity: 8 });
+        let bridge_addr: SocketAddr = "127.0.0.1:1883".parse().unwrap();
+        manager.add_virtual_client(bridge_addr, bridge.clone()).unwrap();
+        let (tcp, _tcp_peer) = connect(&manager);
+        assert_eq!(manager.client_count().unwrap(), 2);
+
+        assert_eq!(manager.broadcast(b"hello", b"hello").unwrap(), 2);
+        assert_eq!(*bridge.received.lock().unwrap(), b"hello");
+        assert_eq!(manager.queue_len(&bridge_addr).unwrap(), 0);
+        // 网桥满时丢弃数据，但不会被断开
+        assert_eq!(manager.broadcast(b"world", b"world").unwrap(), 1);
+        assert_eq!(*bridge.received.lock().unwrap(), b"hello");
+
+        assert!(manager.disconnect(&bridge_addr).is_err());
+        assert_eq!(manager.evict_oldest().unwrap(), Some(tcp));
+        assert_eq!(manager.evict_oldest().unwrap(), None);
+        assert_eq!(manager.client_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn log_lines_reach_only_subscribers() {
+        let manager = TcpClientManager::new();
+        let (plain, _plain_peer) = connect(&manager);
+        let (subscriber, mut subscriber_peer) = connect(&manager);
+        manager.send_log("LOG: lost\r\n");
+        assert!(!manager.has_log_clients());
+
+        manager.set_log_stream(&subscriber, true).unwrap();
+        manager.send_log("LOG: I (42) main: ready\r\n");
+        assert_eq!(manager.queue_len(&plain).unwrap(), 0);
+        manager.write_queued().unwrap();
+        assert_eq!(read_exact(&mut subscriber_peer, 25), "LOG: I (42) main: ready\r\n");
+
+        manager.remove_client(&subscriber, id_of(&manager, &subscriber)).unwrap();
+        assert!(!manager.has_log_clients());
+    }
+
+    #[test]
+    fn echo_is_off_until_enabled() {
+        let manager = TcpClientManager::new();
+        let (addr, _peer) = connect(&manager);
+        assert!(!manager.is_echo(&addr));
+
+        manager.set_echo(&addr, true).unwrap();
+        assert!(manager.is_echo(&addr));
+
+        manager.remove_client(&addr, id_of(&manager, &addr)).unwrap();
+        assert!(!manager.is_echo(&addr));
+        assert!(manager.set_echo(&addr, true).is_err());
+    }
+
+    #[test]
+    fn clients_are_switched_to_non_blocking_mode() {
+        let manager = TcpClientManager::new();
+        let (addr, _peer) = connect(&manager);
+        let entry = manager.get_entry(&addr).unwrap();
+        let mut stream = stream_of(&entry).lock().unwrap();
+        let err = stream.read(&mut [0u8; 1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn marker_that_does_not_fit_is_queued_in_front() {
+        let manager = TcpClientManager::new();
+        let (addr, mut peer) = connect(&manager);
+        manager.set_mark_gaps(&addr, true).unwrap();
+        let entry = manager.get_entry(&addr).unwrap();
+
+        // 写满套接字缓冲区，标记无法写出
+        let mut filled = 0;
+        {
+            let mut stream = stream_of(&entry).lock().unwrap();
+            loop {
+                let written = TcpClientManager::write_available(&mut *stream, &[b'x'; 4096]).unwrap();
+                filled += written;
+                if written < 4096 {
+                    break;
+                }
+            }
+            entry.dropped_bytes.store(7, Ordering::Relaxed);
+            entry.outbound.lock().unwrap().extend(b"after");
+            assert!(TcpClientManager::write_pending_gap_marker(&mut *stream, &entry, &addr));
+        }
+        assert_eq!(entry.dropped_bytes.load(Ordering::Relaxed), 0);
+        let queued: Vec<u8> = entry.outbound.lock().unwrap().iter().copied().collect();
+        assert_eq!(queued, format!("{}after", gap_marker(7)).into_bytes());
+
+        // 对端读取后，标记和之后的数据按顺序写出
+        let mut junk = vec![0; filled];
+        peer.read_exact(&mut junk).unwrap();
+        while !entry.outbound.lock().unwrap().is_empty() {
+            manager.write_queued().unwrap();
+            thread::sleep(Duration::from_millis(1));
+        }
+        let expected = format!("{}after", gap_marker(7));
+        assert_eq!(read_exact(&mut peer, expected.len()), expected);
+    }
+
+    #[test]
+    fn only_retryable_socket_errors_are_transient() {
+        for kind in [io::ErrorKind::WouldBlock, io::ErrorKind::TimedOut, io::ErrorKind::Interrupted] {
+            assert!(TcpClientManager::is_transient(&kind.into()), "{:?}", kind);
+        }
+        for kind in [io::ErrorKind::BrokenPipe, io::ErrorKind::ConnectionReset, io::ErrorKind::WriteZero] {
+            assert!(!TcpClientManager::is_transient(&kind.into()), "{:?}", kind);
+        }
+    }
+
+
+    /// Collect the events of `manager` into a shared list
+    fn record_events(manager: &TcpClientManager) -> Arc<Mutex<Vec<ClientEvent>>> {
+        let events = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&events);
+        manager.register_listener(Box::new(move |event| sink.lock().unwrap().push(event)));
+        events
+    }
+
+    /// Add a raw mode mock client, returning its connection id and wire
+    fn add_raw_client(manager: &TcpClientManager, n: u16) -> (ConnectionId, Wire) {
+        let (stream, wire) = MockStream::new(addr(n));
+        let id = manager.add_client(addr(n), stream).unwrap();
+        manager.set_raw_mode(&addr(n), true).unwrap();
+        (id, wire)
+    }
+
+    #[test]
+    fn events_follow_a_client_from_connect_to_disconnect() {
+        let _clock = time::lock_clock();
+        let manager = TcpClientManager::new();
+        let events = record_events(&manager);
+
+        let (first, _) = add_raw_client(&manager, 1);
+        time::advance(Duration::from_secs(5));
+        // 同一地址重连时旧连接被替换
+        let (second, _) = add_raw_client(&manager, 1);
+        assert!(manager.disconnect(&addr(1)).unwrap());
+        let (third, _) = add_raw_client(&manager, 2);
+        manager.remove_client(&addr(2), third).unwrap();
+
+        let events = events.lock().unwrap();
+        assert_eq!(events.len(), 6, "{:?}", events);
+        assert_eq!(events[0], ClientEvent::Connected { addr: addr(1), id: first });
+        assert!(matches!(
+            events[1],
+            ClientEvent::Disconnected { reason: DisconnectReason::Replaced, connected_for, .. }
+                if connected_for >= Duration::from_secs(5)
+        ));
+        assert_eq!(events[2], ClientEvent::Connected { addr: addr(1), id: second });
+        assert!(matches!(events[3], ClientEvent::Disconnected { reason: DisconnectReason::Kicked, .. }));
+        assert_eq!(events[4], ClientEvent::Connected { addr: addr(2), id: third });
+        assert!(matches!(events[5], ClientEvent::Disconnected { reason: DisconnectReason::Closed, .. }));
+    }
+
+    #[test]
+    fn listener_may_register_another_listener() {
+        let manager = Arc::new(TcpClientManager::new());
+        let late = Arc::new(Mutex::new(Vec::new()));
+        {
+            let weak = Arc::downgrade(&manager);
+            let late = Arc::clone(&late);
+            let registered = AtomicBool::new(false);
+            manager.register_listener(Box::new(move |_| {
+                let Some(manager) = weak.upgrade() else { return };
+                if !registered.swap(true, Ordering::Relaxed) {
+                    let late = Arc::clone(&late);
+                    manager.register_listener(Box::new(move |event| late.lock().unwrap().push(event)));
+                }
+            }));
+        }
+
+        add_raw_client(&manager, 1);
+        let (id, _) = add_raw_client(&manager, 2);
+        // 新监听器从下一个事件开始接收
+        assert_eq!(*late.lock().unwrap(), [ClientEvent::Connected { addr: addr(2), id }]);
+    }
+
+    #[test]
+    fn event_reasons_are_shown_readably() {
+        assert_eq!(EvictionReason::Idle(Duration::from_secs(90)).to_string(), "idle for 0d 00h 01m 30s");
+        assert_eq!(EvictionReason::MakeRoom.to_string(), "making room for a new client");
+        assert_eq!(DisconnectReason::Kicked.to_string(), "closed by another client");
+    }
+
+    #[test]
+    fn broadcast_is_written_by_write_queued() {
+        let manager = TcpClientManager::new();
+        let (_, wire) = add_raw_client(&manager, 1);
+
+        assert_eq!(manager.broadcast(b"hello", b"hello").unwrap(), 1);
+        assert!(wire.lock().unwrap().output.is_empty());
+        assert!(manager.write_queued().unwrap());
+
+        assert_eq!(wire.lock().unwrap().output, b"hello");
+        let clients = manager.list_clients().unwrap();
+        assert_eq!(clients[0].bytes_sent, 5);
+        assert_eq!(manager.stats().bytes_broadcast, 5);
+    }
+
+    #[test]
+    fn command_mode_clients_get_translated_text() {
+        let manager = TcpClientManager::new();
+        let (stream, wire) = MockStream::new(addr(1));
+        manager.add_client(addr(1), stream).unwrap();
+        add_raw_client(&manager, 2);
+
+        manager.broadcast(b"a\n", b"a\r\n").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"a\r\n");
+    }
+
+    #[test]
+    fn telnet_clients_get_iac_doubled() {
+        let manager = TcpClientManager::new();
+        let (stream, wire) = MockStream::new(addr(1));
+        manager.add_client(addr(1), stream).unwrap();
+        manager.set_protocol(&addr(1), ClientProtocol::Telnet).unwrap();
+        let (_, raw_wire) = add_raw_client(&manager, 2);
+
+        manager.broadcast(&[1, 0xff, 2], &[1, 0xff, 2]).unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, [1, 0xff, 0xff, 2]);
+        assert_eq!(raw_wire.lock().unwrap().output, [1, 0xff, 2]);
+    }
+
+    #[test]
+    fn partial_writes_keep_the_rest_queued() {
+        let manager = TcpClientManager::new();
+        let (_, wire) = add_raw_client(&manager, 1);
+        wire.lock().unwrap().write_capacity = Some(3);
+
+        manager.broadcast(b"abcdef", b"abcdef").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"abc");
+        assert_eq!(manager.queue_len(&addr(1)).unwrap(), 3);
+
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"abcdef");
+        assert_eq!(manager.queue_len(&addr(1)).unwrap(), 0);
+    }
+
+    #[test]
+    fn short_writes_deliver_the_whole_broadcast() {
+        let manager = TcpClientManager::new();
+        let (_, wire) = add_raw_client(&manager, 1);
+        wire.lock().unwrap().max_write = Some(2);
+
+        manager.broadcast(b"hello world", b"hello world").unwrap();
+        assert!(manager.write_queued().unwrap());
+
+        assert_eq!(wire.lock().unwrap().output, b"hello world");
+        assert_eq!(manager.queue_len(&addr(1)).unwrap(), 0);
+        assert_eq!(manager.list_clients().unwrap()[0].bytes_sent, 11);
+    }
+
+    #[test]
+    fn pending_bytes_go_out_before_the_next_broadcast() {
+        let manager = TcpClientManager::new();
+        let (_, wire) = add_raw_client(&manager, 1);
+        {
+            let mut wire = wire.lock().unwrap();
+            wire.max_write = Some(1);
+            wire.write_capacity = Some(3);
+        }
+
+        manager.broadcast(b"abcdef", b"abcdef").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"abc");
+
+        manager.broadcast(b"gh", b"gh").unwrap();
+        assert_eq!(manager.queue_len(&addr(1)).unwrap(), 5);
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+
+        assert_eq!(wire.lock().unwrap().output, b"abcdefgh");
+        assert_eq!(manager.queue_len(&addr(1)).unwrap(), 0);
+        assert!(manager.is_client_connected(&addr(1)));
+    }
+
+    #[test]
+    fn client_over_the_pending_cap_is_disconnected() {
+        let manager = TcpClientManager::with_queue_limit(8);
+        let (_, wire) = add_raw_client(&manager, 1);
+        {
+            let mut wire = wire.lock().unwrap();
+            wire.max_write = Some(2);
+            wire.write_capacity = Some(4);
+        }
+
+        manager.broadcast(b"abcdef", b"abcdef").unwrap();
+        manager.write_queued().unwrap();
+        assert_eq!(manager.queue_len(&addr(1)).unwrap(), 2);
+
+        // 未写出的2字节加上新的8字节超过上限
+        assert_eq!(manager.broadcast(b"01234567", b"01234567").unwrap(), 0);
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+
+        assert!(!manager.is_client_connected(&addr(1)));
+        let wire = wire.lock().unwrap();
+        assert!(wire.shut_down);
+        assert!(wire.output.starts_with(b"abcd"));
+        assert!(!wire.output.windows(8).any(|window| window == b"01234567"));
+        assert_eq!(manager.stats().clients_evicted, 1);
+    }
+
+    #[test]
+    fn stream_reader_does_not_need_the_stream_lock() {
+        let (stream, wire) = MockStream::new(addr(1));
+        let mut reader = StreamReader::new(&stream).unwrap();
+        wire.lock().unwrap().input.extend(b"AT\r\n");
+
+        let _writer = stream.lock().unwrap();
+        let mut buf = [0u8; 16];
+        assert_eq!(reader.read(&mut buf).unwrap(), 4);
+        assert_eq!(&buf[..4], b"AT\r\n");
+        assert_eq!(reader.read(&mut buf).unwrap_err().kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn reads_and_writes_of_a_socket_do_not_delay_each_other() {
+        use std::time::Instant;
+
+        // 115200波特率约每毫秒12字节
+        const UART_CHUNK: usize = 12;
+        const UART_CHUNKS: usize = 200;
+        const TCP_BYTES: usize = 256 * 1024;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (socket, peer) = listener.accept().unwrap();
+        let stream = Arc::new(Mutex::new(socket));
+        let manager = Arc::new(TcpClientManager::new());
+        manager.add_client(peer, Arc::clone(&stream)).unwrap();
+        manager.set_raw_mode(&peer, true).unwrap();
+
+        // 处理线程持续读取客户端发来的大量数据
+        let mut reader = StreamReader::new(&stream).unwrap();
+        let handler = std::thread::spawn(move || {
+            let mut buf = [0u8; 4096];
+            let mut received = 0;
+            while received < TCP_BYTES {
+                match reader.read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(n) => received += n,
+                    Err(e) if TcpClientManager::is_transient(&e) => std::thread::sleep(Duration::from_millis(1)),
+                    Err(e) => panic!("read failed: {}", e),
+                }
+            }
+            received
+        });
+
+        let mut downlink = client.try_clone().unwrap();
+        let receiver = std::thread::spawn(move || {
+            let mut data = vec![0u8; UART_CHUNK * UART_CHUNKS];
+            downlink.read_exact(&mut data).unwrap();
+            data
+        });
+        let sender = std::thread::spawn(move || {
+            for chunk in vec![0x55u8; TCP_BYTES].chunks(1024) {
+                client.write_all(chunk).unwrap();
+            }
+            client
+        });
+
+        // 同时按UART速率广播，每轮写出都不能被读取拖慢
+        let mut slowest = Duration::ZERO;
+        for chunk in 0..UART_CHUNKS {
+            let data = [chunk as u8; UART_CHUNK];
+            let started = Instant::now();
+            manager.broadcast(&data, &data).unwrap();
+            manager.write_queued().unwrap();
+            slowest = slowest.max(started.elapsed());
+            std::thread::sleep(Duration::from_millis(1));
+        }
+        while manager.queue_len(&peer).unwrap() > 0 {
+            manager.write_queued().unwrap();
+        }
+
+        let _client = sender.join().unwrap();
+        assert_eq!(handler.join().unwrap(), TCP_BYTES);
+        let data = receiver.join().unwrap();
+        assert!(data.chunks(UART_CHUNK).enumerate().all(|(n, chunk)| chunk.iter().all(|&b| b == n as u8)));
+        assert!(slowest < Duration::from_millis(5), "write pass took {:?}", slowest);
+        assert_eq!(manager.client_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn slow_client_is_evicted_by_the_writer() {
+        let manager = TcpClientManager::with_queue_limit(16);
+        let events = record_events(&manager);
+        let (_, wire) = add_raw_client(&manager, 1);
+        wire.lock().unwrap().write_capacity = Some(0);
+
+        assert_eq!(manager.broadcast(&[1; 10], &[1; 10]).unwrap(), 1);
+        assert_eq!(manager.broadcast(&[2; 10], &[2; 10]).unwrap(), 0);
+        manager.write_queued().unwrap();
+
+        assert_eq!(manager.client_count().unwrap(), 0);
+        assert!(wire.lock().unwrap().shut_down);
+        assert_eq!(manager.stats().clients_evicted, 1);
+        assert!(events
+            .lock()
+            .unwrap()
+            .contains(&ClientEvent::Evicted { addr: addr(1), reason: EvictionReason::TooSlow }));
+    }
+
+    #[test]
+    fn broken_connection_is_removed() {
+        let manager = TcpClientManager::new();
+        let events = record_events(&manager);
+        let (_, wire) = add_raw_client(&manager, 1);
+        wire.lock().unwrap().broken = true;
+
+        manager.broadcast(b"x", b"x").unwrap();
+        manager.write_queued().unwrap();
+
+        assert!(!manager.is_client_connected(&addr(1)));
+        assert!(events.lock().unwrap().iter().any(|event| matches!(
+            event,
+            ClientEvent::Disconnected { reason: DisconnectReason::WriteFailed, .. }
+        )));
+    }
+
+    #[test]
+    fn gap_marker_client_is_kept_and_told_once() {
+        let manager = TcpClientManager::with_queue_limit(16);
+        let (stream, wire) = MockStream::new(addr(1));
+        manager.add_client(addr(1), stream).unwrap();
+        manager.set_mark_gaps(&addr(1), true).unwrap();
+        wire.lock().unwrap().write_capacity = Some(0);
+
+        assert_eq!(manager.broadcast(&[b'a'; 10], &[b'a'; 10]).unwrap(), 1);
+        // 超出队列上限的数据被丢弃，之后的数据也丢弃，直到标记写出
+        assert_eq!(manager.broadcast(&[b'b'; 10], &[b'b'; 10]).unwrap(), 0);
+        assert_eq!(manager.broadcast(&[b'c'; 3], &[b'c'; 3]).unwrap(), 0);
+        manager.write_queued().unwrap();
+        assert!(manager.is_client_connected(&addr(1)));
+
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+        manager.broadcast(b"d", b"d").unwrap();
+        manager.write_queued().unwrap();
+
+        let mut expected = vec![b'a'; 10];
+        expected.extend_from_slice(gap_marker(13).as_bytes());
+        expected.push(b'd');
+        assert_eq!(wire.lock().unwrap().output, expected);
+        assert_eq!(manager.stats().clients_evicted, 0);
+    }
+
+    /// Add a command mode client with gap markers whose connection takes nothing yet
+    fn add_stalled_marking_client(manager: &TcpClientManager) -> Wire {
+        let (stream, wire) = MockStream::new(addr(1));
+        manager.add_client(addr(1), stream).unwrap();
+        manager.set_mark_gaps(&addr(1), true).unwrap();
+        wire.lock().unwrap().write_capacity = Some(0);
+        wire
+    }
+
+    #[test]
+    fn gap_marker_counts_as_sent() {
+        let manager = TcpClientManager::with_queue_limit(8);
+        let wire = add_stalled_marking_client(&manager);
+        manager.broadcast(&[0; 8], &[0; 8]).unwrap();
+        manager.broadcast(&[0; 5], &[0; 5]).unwrap();
+
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+
+        let sent = wire.lock().unwrap().output.len() as u64;
+        assert_eq!(sent, 8 + gap_marker(5).len() as u64);
+        assert_eq!(manager.list_clients().unwrap()[0].bytes_sent, sent);
+    }
+
+    #[test]
+    fn partially_written_gap_marker_is_completed_before_new_data() {
+        let manager = TcpClientManager::with_queue_limit(4);
+        let wire = add_stalled_marking_client(&manager);
+        manager.broadcast(b"1234", b"1234").unwrap();
+        manager.broadcast(b"5", b"5").unwrap();
+
+        // 队列写完后只能再写出标记的前3个字节
+        wire.lock().unwrap().write_capacity = Some(4 + 3);
+        manager.write_queued().unwrap();
+        assert!(manager.queue_len(&addr(1)).unwrap() > 0);
+
+        // 剩余的标记在队列里，可能分成两段写出
+        wire.lock().unwrap().write_capacity = None;
+        while manager.queue_len(&addr(1)).unwrap() > 0 {
+            manager.write_queued().unwrap();
+        }
+        manager.broadcast(b"6", b"6").unwrap();
+        manager.write_queued().unwrap();
+
+        let mut expected = b"1234".to_vec();
+        expected.extend_from_slice(gap_marker(1).as_bytes());
+        expected.push(b'6');
+        assert_eq!(wire.lock().unwrap().output, expected);
+        assert_eq!(manager.list_clients().unwrap()[0].bytes_sent, expected.len() as u64);
+    }
+
+    #[test]
+    fn raw_mode_clients_get_no_gap_marker() {
+        let manager = TcpClientManager::with_queue_limit(4);
+        let wire = add_stalled_marking_client(&manager);
+        manager.set_raw_mode(&addr(1), true).unwrap();
+        manager.broadcast(b"1234", b"1234").unwrap();
+        manager.broadcast(b"5", b"5").unwrap();
+
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+        manager.broadcast(b"6", b"6").unwrap();
+        manager.write_queued().unwrap();
+
+        assert_eq!(wire.lock().unwrap().output, b"12346");
+    }
+
+    #[test]
+    fn drops_before_enabling_markers_are_not_reported() {
+        let manager = TcpClientManager::with_queue_limit(4);
+        let wire = add_stalled_marking_client(&manager);
+        manager.broadcast(b"1234", b"1234").unwrap();
+        manager.broadcast(b"5", b"5").unwrap();
+        manager.set_mark_gaps(&addr(1), false).unwrap();
+        manager.set_mark_gaps(&addr(1), true).unwrap();
+
+        wire.lock().unwrap().write_capacity = None;
+        manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"1234");
+    }
+
+    #[test]
+    fn client_churn_while_broadcasting() {
+        const CHURN_THREADS: u16 = 4;
+        const CONNECTIONS: u16 = 200;
+        const CHUNKS: u32 = 2000;
+
+        let manager = Arc::new(TcpClientManager::with_queue_limit(1 << 20));
+        let events = record_events(&manager);
+        let done = Arc::new(AtomicBool::new(false));
+        let (finished_tx, finished_rx) = std::sync::mpsc::channel();
+
+        // 每个线程用自己的地址反复连接和断开
+        let churners: Vec<_> = (0..CHURN_THREADS)
+            .map(|t| {
+                let manager = Arc::clone(&manager);
+                std::thread::spawn(move || {
+                    let mut connections = Vec::new();
+                    for i in 0..CONNECTIONS {
+                        let n = t * CONNECTIONS + i;
+                        let (id, wire) = add_raw_client(&manager, n);
+                        std::thread::yield_now();
+                        manager.remove_client(&addr(n), id).unwrap();
+                        connections.push((id, wire));
+                    }
+                    connections
+                })
+            })
+            .collect();
+
+        let broadcaster = {
+            let manager = Arc::clone(&manager);
+            std::thread::spawn(move || {
+                for chunk in 0..CHUNKS {
+                    let data = chunk.to_be_bytes();
+                    manager.broadcast(&data, &data).unwrap();
+                }
+            })
+        };
+
+        let writer = {
+            let manager = Arc::clone(&manager);
+            let done = Arc::clone(&done);
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    manager.write_queued().unwrap();
+                    manager.list_clients().unwrap();
+                    manager.client_count().unwrap();
+                    manager.stats();
+                }
+            })
+        };
+
+        let watchdog = std::thread::spawn(move || {
+            let connections: Vec<_> = churners.into_iter().flat_map(|t| t.join().unwrap()).collect();
+            broadcaster.join().unwrap();
+            finished_tx.send(connections).unwrap();
+        });
+        let connections = finished_rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("client churn deadlocked");
+        done.store(true, Ordering::Relaxed);
+        watchdog.join().unwrap();
+        writer.join().unwrap();
+
+        let total = usize::from(CHURN_THREADS * CONNECTIONS);
+        let ids: std::collections::HashSet<_> = connections.iter().map(|(id, _)| *id).collect();
+        assert_eq!(ids.len(), total);
+        assert_eq!(manager.client_count().unwrap(), 0);
+        assert!(manager.list_clients().unwrap().is_empty());
+
+        let stats = manager.stats();
+        assert_eq!(stats.clients_total, total as u64);
+        assert_eq!(stats.clients_evicted, 0);
+        assert_eq!(stats.broadcast_errors, 0);
+        assert_eq!(stats.bytes_broadcast, u64::from(CHUNKS) * 4);
+
+        let events = events.lock().unwrap();
+        let connected = events.iter().filter(|e| matches!(e, ClientEvent::Connected { .. })).count();
+        let closed = events
+            .iter()
+            .filter(|e| matches!(e, ClientEvent::Disconnected { reason: DisconnectReason::Closed, .. }))
+            .count();
+        assert_eq!((connected, closed), (total, total));
+
+        // 每个连接收到的是连续且完整的一段广播
+        for (_, wire) in &connections {
+            // 写出队列中间断开时最后一段可能不完整
+            let output = &wire.lock().unwrap().output;
+            let chunks: Vec<u32> = output
+                .chunks_exact(4)
+                .map(|c| u32::from_be_bytes(c.try_into().unwrap()))
+                .collect();
+            assert!(chunks.windows(2).all(|w| w[1] == w[0] + 1), "{:?}", chunks);
+        }
+    }
+
+    #[test]
+    fn stale_connection_cannot_remove_its_successor() {
+        let manager = TcpClientManager::new();
+        let (old_id, old_wire) = add_raw_client(&manager, 1);
+        let (new_id, _) = add_raw_client(&manager, 1);
+        assert_ne!(old_id, new_id);
+        assert!(old_wire.lock().unwrap().shut_down);
+
+        manager.remove_client(&addr(1), old_id).unwrap();
+        assert!(manager.is_client_connected(&addr(1)));
+        manager.remove_client(&addr(1), new_id).unwrap();
+        assert!(!manager.is_client_connected(&addr(1)));
+    }
+
+    #[test]
+    fn rapid_reconnects_from_one_peer_leave_no_clients() {
+        const CONNECTIONS: usize = 100;
+
+        let manager = Arc::new(TcpClientManager::new());
+        let mut ids = Vec::new();
+        let mut stale_handlers = Vec::new();
+        for _ in 0..CONNECTIONS {
+            let (id, _) = add_raw_client(&manager, 1);
+            // 旧连接的处理线程晚于重连才发现断开
+            if let Some(old_id) = ids.last().copied() {
+                let manager = Arc::clone(&manager);
+                stale_handlers.push(std::thread::spawn(move || manager.remove_client(&addr(1), old_id).unwrap()));
+            }
+            ids.push(id);
+            assert_eq!(manager.client_count().unwrap(), 1);
+        }
+        for handler in stale_handlers {
+            handler.join().unwrap();
+        }
+
+        let unique: std::collections::HashSet<_> = ids.iter().collect();
+        assert_eq!(unique.len(), CONNECTIONS);
+        assert_eq!(manager.client_count().unwrap(), 1);
+        assert!(manager.is_client_connected(&addr(1)));
+
+        manager.remove_client(&addr(1), *ids.last().unwrap()).unwrap();
+        assert_eq!(manager.client_count().unwrap(), 0);
+        assert!(manager.list_clients().unwrap().is_empty());
+        assert_eq!(manager.stats().clients_total, CONNECTIONS as u64);
+    }
 }
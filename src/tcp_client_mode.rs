@@ -0,0 +1,283 @@
+//! Connect-out TCP client module
+//!
+//! This module provides a TCP client that dials out to a remote host and forwards data
+//! between that host and UART, for deployments behind NAT where the device cannot accept
+//! connections. It can run alongside the TCP server.
+//!
+//! The outbound connection is registered with the data port's `TcpClientManager`, so
+//! UART data is broadcast to it like to any accepted client. The link is re-established
+//! automatically, with a doubling delay between failed attempts.
+
+use log::{debug, error, info, trace, warn};
+use std::fmt;
+use std::io::{ErrorKind, Read};
+use std::net::{Shutdown, SocketAddr, TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TcpClientModeConfig;
+use crate::error::{Error, Result};
+use crate::tcp_client_manager::{StreamReader, TcpClientManager};
+use crate::time::{self, Stopwatch};
+use crate::uart::UartManager;
+
+/// Interval in milliseconds at which the reconnect delay checks for a stop request
+const STOP_POLL_MS: u64 = 100;
+
+/// Sleep in milliseconds between reads while the remote host sends nothing
+const READ_POLL_MS: u64 = 2;
+
+/// State of the outbound link
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LinkState {
+    /// Resolving the host or waiting for the connection to be accepted
+    Connecting,
+    /// Connected to the given remote address
+    Connected(SocketAddr),
+    /// The last attempt failed or the link dropped; retrying after the delay
+    Waiting(Duration),
+    /// The client was stopped
+    Stopped,
+}
+
+impl fmt::Display for LinkState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LinkState::Connecting => write!(f, "connecting"),
+            LinkState::Connected(addr) => write!(f, "connected to {}", addr),
+            LinkState::Waiting(delay) => {
+                write!(f, "disconnected, retrying in {}", time::format_duration(*delay))
+            }
+            LinkState::Stopped => write!(f, "stopped"),
+        }
+    }
+}
+
+/// Connect-out TCP client
+///
+/// Keeps one connection to the configured remote host open and forwards data between
+/// it and UART.
+pub struct TcpClientMode {
+    /// Connect-out client configuration
+    config: TcpClientModeConfig,
+    /// Client manager of the data port, so UART broadcasts reach the remote host
+    client_manager: Arc<TcpClientManager>,
+    /// UART manager receiving the data sent by the remote host
+    uart_manager: Arc<UartManager>,
+    /// Buffer size for reads from the remote host
+    buffer_size: usize,
+    /// Current state of the link, reported by AT+STATUS
+    state: Mutex<LinkState>,
+    /// Set by `stop` to close the link and end `run`
+    shutdown: AtomicBool,
+}
+
+impl TcpClientMode {
+    /// Create a new connect-out client with the given configuration and managers
+    pub fn new(
+        config: TcpClientModeConfig,
+        client_manager: Arc<TcpClientManager>,
+        uart_manager: Arc<UartManager>,
+        buffer_size: usize,
+    ) -> Self {
+        Self {
+            config,
+            client_manager,
+            uart_manager,
+            buffer_size,
+            state: Mutex::new(LinkState::Connecting),
+            shutdown: AtomicBool::new(false),
+        }
+    }
+
+    /// Remote endpoint as configured, e.g. "example.com:8080"
+    pub fn remote(&self) -> String {
+        format!("{}:{}", self.config.remote_host, self.config.remote_port)
+    }
+
+    /// Get the current state of the link
+    pub fn state(&self) -> LinkState {
+        match self.state.lock() {
+            Ok(state) => *state,
+            Err(poisoned) => *poisoned.into_inner(),
+        }
+    }
+
+    fn set_state(&self, new_state: LinkState) {
+        match self.state.lock() {
+            Ok(mut state) => *state = new_state,
+            Err(poisoned) => *poisoned.into_inner() = new_state,
+        }
+    }
+
+    /// Keep the link to the remote host up until `stop` is called
+    ///
+    /// After a failed attempt or a dropped link the delay starts at the reconnect
+    /// interval and doubles up to the maximum backoff. A link that was up resets it.
+    pub fn run(&self) -> Result<()> {
+        let initial_delay = Duration::from_millis(self.config.reconnect_interval_ms);
+        let max_delay = Duration::from_millis(self.config.max_backoff_ms).max(initial_delay);
+        let mut delay = initial_delay;
+        info!("Connect-out client started for {}", self.remote());
+
+        while !self.is_stopped() {
+            self.set_state(LinkState::Connecting);
+            match self.connect() {
+                Ok(stream) => {
+                    if let Err(e) = self.serve(stream) {
+                        error!("Link to {} failed: {}", self.remote(), e);
+                    }
+                    delay = initial_delay;
+                }
+                Err(e) => warn!("Failed to connect to {}: {}", self.remote(), e),
+            }
+            if self.is_stopped() {
+                break;
+            }
+
+            self.set_state(LinkState::Waiting(delay));
+            info!("Reconnecting to {} in {}", self.remote(), time::format_duration(delay));
+            // 分段等待，以便及时响应停止请求
+            let waited = Stopwatch::start();
+            while !waited.has_elapsed(delay) && !self.is_stopped() {
+                thread::sleep(Duration::from_millis(STOP_POLL_MS));
+            }
+            delay = next_delay(delay, max_delay);
+        }
+
+        self.set_state(LinkState::Stopped);
+        info!("Connect-out client stopped");
+        Ok(())
+    }
+
+    /// Ask `run` to close the link and return
+    pub fn stop(&self) {
+        info!("Stopping connect-out client");
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether `stop` has been called
+    pub fn is_stopped(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Resolve the remote host and connect to the first address that accepts
+    fn connect(&self) -> Result<TcpStream> {
+        let timeout = Duration::from_millis(self.config.connect_timeout_ms);
+        let addrs = (self.config.remote_host, self.config.remote_port)
+            .to_socket_addrs()
+            .map_err(|e| Error::tcp_caused(format!("Failed to resolve {}", self.remote()), e))?;
+
+        let mut last_error = None;
+        for addr in addrs {
+            debug!("Connecting to {}", addr);
+            match TcpStream::connect_timeout(&addr, timeout) {
+                Ok(stream) => return Ok(stream),
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(match last_error {
+            Some(e) => Error::Io(e),
+            None => Error::tcp(format!("No address found for {}", self.remote())),
+        })
+    }
+
+    /// Forward data over a connected stream until the link drops or the client stops
+    fn serve(&self, stream: TcpStream) -> Result<()> {
+        let peer_addr = stream
+            .peer_addr()
+            .map_err(|e| Error::tcp_caused("Failed to get peer address", e))?;
+
+        if let Err(e) = stream.set_nodelay(true) {
+            error!("Failed to set TCP_NODELAY for {}: {}", peer_addr, e);
+        }
+
+        // 与客户端管理器的写入线程共享同一个非阻塞流
+        let stream_arc = Arc::new(Mutex::new(stream));
+        let mut reader = StreamReader::new(&stream_arc)?;
+        let conn_id = self.client_manager.add_client(peer_addr, Arc::clone(&stream_arc))?;
+        let traffic = self.client_manager.traffic_counter(&peer_addr)?;
+        // 远端主机只交换数据，不解析AT命令
+        self.client_manager.set_raw_mode(&peer_addr, true)?;
+        self.set_state(LinkState::Connected(peer_addr));
+        info!("Connected to {}", peer_addr);
+
+        let connected = Stopwatch::start();
+        let mut buffer = vec![0; self.buffer_size];
+        let result = loop {
+            if self.is_stopped() {
+                if let Ok(stream) = stream_arc.lock() {
+                    let _ = stream.shutdown(Shutdown::Both);
+                }
+                break Ok(());
+            }
+
+            // 读取时不持有流锁，写线程可以同时写出UART数据
+            match reader.read(&mut buffer) {
+                Ok(0) => {
+                    info!("Remote host {} closed the link", peer_addr);
+                    break Ok(());
+                }
+                Ok(n) => {
+                    traffic.record_received(n);
+                    trace!("TCP -> UART: {} bytes from {}", n, peer_addr);
+                    // 其他客户端独占UART时丢弃远端数据
+                    if let Some(holder) = self.client_manager.locked_by_other(&peer_addr) {
+                        debug!("Dropping data from {}, UART locked by {}", peer_addr, holder);
+                    } else if !self.uart_manager.is_bridge_enabled() {
+                        debug!("Dropping data from {}, bridge paused", peer_addr);
+                    } else if let Err(e) = self.uart_manager.send_packet(&buffer[..n]) {
+                        error!("Error sending data to UART: {}", e);
+                    }
+                }
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                    thread::sleep(Duration::from_millis(READ_POLL_MS));
+                }
+                Err(e) if e.kind() == ErrorKind::Interrupted => {}
+                Err(e) => break Err(Error::Io(e)),
+            }
+        };
+
+        // 链路可能已被写入线程或空闲清理移除，重复移除不会出错
+        self.client_manager.remove_client(&peer_addr, conn_id)?;
+        info!(
+            "Link to {} closed after {}",
+            peer_addr,
+            time::format_duration(connected.elapsed())
+        );
+        result
+    }
+}
+
+/// Double a reconnect delay, capped at `max_delay`
+fn next_delay(delay: Duration, max_delay: Duration) -> Duration {
+    (delay * 2).min(max_delay)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reconnect_delay_doubles_up_to_the_limit() {
+        let max_delay = Duration::from_secs(5);
+        let mut delay = Duration::from_secs(1);
+        let mut delays = Vec::new();
+        for _ in 0..5 {
+            delay = next_delay(delay, max_delay);
+            delays.push(delay.as_secs());
+        }
+        assert_eq!(delays, [2, 4, 5, 5, 5]);
+    }
+
+    #[test]
+    fn link_state_is_reported_readably() {
+        let addr: SocketAddr = "192.168.1.100:8080".parse().unwrap();
+        assert_eq!(LinkState::Connected(addr).to_string(), "connected to 192.168.1.100:8080");
+        assert_eq!(LinkState::Connecting.to_string(), "connecting");
+        assert_eq!(LinkState::Waiting(Duration::from_secs(2)).to_string(), "disconnected, retrying in 0d 00h 00m 02s");
+        assert_eq!(LinkState::Stopped.to_string(), "stopped");
+    }
+}
@@ -4,383 +4,428 @@
 //! TCP clients and UART.
 //!
 //! It also supports command processing for controlling UART settings, such as changing
-//! the baud rate via TCP client commands.
+//! the baud rate via TCP client commands. When a control port is configured, commands
+//! are only accepted on that port and the data port is purely transparent.
+//!
+//! Data port clients are served by one thread each, or all together by the server
+//! thread with poll() (see `IoModel`). An optional RFC 2217 port serves remote serial
+//! port clients such as pyserial and esptool (see `rfc2217`), and an optional
+//! WebSocket port serves browser-based terminals (see `websocket`).
+//!
+//! Firmware updates are uploaded on the control port with AT+OTA (see `ota`).
 
-use log::{debug, error, info, trace};
-use std::io::{Read, Write};
-use std::net::{TcpListener, TcpStream};
+use log::{debug, error, info, trace, warn};
+use std::io::{ErrorKind, Read, Write};
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::os::fd::{AsRawFd, RawFd};
+use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
 use std::sync::{Arc, Mutex};
-use std::thread;
+use std::thread::{self, JoinHandle};
 use std::time::Duration;
 
-use crate::config::TcpServerConfig;
+use crate::acl::AccessList;
+use crate::commands::CommandRegistry;
+use crate::config::{EvictionPolicy, IoModel, TcpServerConfig};
+use crate::device_name;
+use crate::diagnostics;
+use crate::eol::EolState;
+use crate::gpio_control::GpioControl;
 use crate::error::{Error, Result};
-use crate::tcp_client_manager::TcpClientManager;
+use crate::mdns::MdnsAdvertiser;
+use crate::mqtt_bridge::MqttBridge;
+use crate::status_led::DeviceStatus;
+use crate::storage::StorageManager;
+use crate::tcp_client_manager::{ConnectionId, SharedStream, StreamReader, TcpClientManager, TrafficCounter};
+use crate::tcp_client_mode::TcpClientMode;
+use crate::time::{self, Stopwatch};
 use crate::uart::UartManager;
+use crate::watchdog;
+use crate::wifi::WiFiManager;
 
-/// TCP Server
-///
-/// Manages a TCP server that accepts connections and forwards data between clients and UART.
-pub struct TcpServer {
-    /// TCP server configuration
-    config: TcpServerConfig,
-    /// Client manager for handling client connections
-    client_manager: Arc<TcpClientManager>,
-    /// UART manager for sending/receiving data from UART
-    uart_manager: Arc<UartManager>,
-}
+mod command_handler;
+mod control_session;
+mod rfc2217_session;
+mod websocket_session;
 
-impl TcpServer {
-    /// Create a new TCP server with the given configuration and managers
-    pub fn new(
-        config: TcpServerConfig,
-        client_manager: Arc<TcpClientManager>,
-        uart_manager: Arc<UartManager>,
-    ) -> Self {
-        Self {
-            config,
-            client_manager,
-            uart_manager,
-        }
-    }
+pub use command_handler::CommandPlan;
 
-    /// Check if the received data is a command
-    ///
-    /// Commands start with "AT+" prefix
-    fn is_command(data: &[u8]) -> bool {
-        // 检查数据长度是否足够
-        if data.len() < 3 {
-            return false;
-        }
+/// Time in milliseconds a response may take to be written before the client is given up on
+const RESPONSE_WRITE_TIMEOUT_MS: u64 = 2000;
 
-        // 检查是否以AT+开头
-        if data[0] == b'A' && data[1] == b'T' && data[2] == b'+' {
-            return true;
-        }
+/// Interval in milliseconds at which accept loops check for a stop request
+const ACCEPT_POLL_MS: u64 = 50;
 
-        false
-    }
+/// Milliseconds the polling server waits in poll() before checking timers and the
+/// client queues again; also the added UART to TCP latency in that I/O model
+const POLL_TIMEOUT_MS: i32 = 5;
 
-    /// Process a command from a client
-    ///
-    /// Currently supported commands:
-    /// - AT+BAUD=<rate>: Change UART baud rate
-    /// - AT+BAUD?: Query current UART baud rate
-    fn process_command(
-        data: &[u8],
-        uart_manager: &Arc<UartManager>,
-        stream_arc: &Arc<Mutex<TcpStream>>,
-        peer_addr: &std::net::SocketAddr,
-    ) -> Result<()> {
-        // 将命令转换为字符串
-        let cmd_str = match std::str::from_utf8(data) {
-            Ok(s) => s.trim(),
-            Err(_) => {
-                // 发送错误响应
-                let response = "ERROR: Invalid command format (not UTF-8)\r\n";
-                Self::send_response(stream_arc, response, peer_addr)?;
-                return Err(Error::TcpError(
-                    "Invalid command format (not UTF-8)".to_string(),
-                ));
-            }
-        };
+/// Reads per client and poll() round, so one busy client cannot starve the others
+const POLL_READS_PER_CLIENT: usize = 4;
 
-        info!("Received command from client {}: {}", peer_addr, cmd_str);
-
-        // 处理波特率设置命令
-        if cmd_str.starts_with("AT+BAUD=") {
-            // 等待一小段时间，确保客户端准备好接收数据
-            thread::sleep(Duration::from_millis(20));
-
-            info!("Processing AT+BAUD= command from client {}", peer_addr);
-
-            // 提取波特率值
-            let baud_str = &cmd_str[8..];
-            match baud_str.parse::<u32>() {
-                Ok(baudrate) => {
-                    // 尝试设置新的波特率
-                    match uart_manager.as_ref().set_baudrate(baudrate) {
-                        Ok(_) => {
-                            // 发送成功响应
-                            let response = format!("OK: Baudrate changed to {}\r\n", baudrate);
-                            if let Err(e) = Self::send_response(stream_arc, &response, peer_addr) {
-                                error!(
-                                    "Failed to send baudrate change response to client {}: {}",
-                                    peer_addr, e
-                                );
-                                return Err(e);
-                            }
-                            info!(
-                                "Successfully changed baudrate to {} for client {}",
-                                baudrate, peer_addr
-                            );
-                        }
-                        Err(e) => {
-                            // 发送错误响应
-                            let response = format!("ERROR: Failed to set baudrate: {}\r\n", e);
-                            if let Err(e) = Self::send_response(stream_arc, &response, peer_addr) {
-                                error!(
-                                    "Failed to send baudrate error response to client {}: {}",
-                                    peer_addr, e
-                                );
-                                return Err(e);
-                            }
-                        }
-                    }
-                }
-                Err(_) => {
-                    // 波特率解析失败
-                    let response = format!("ERROR: Invalid baudrate value: {}\r\n", baud_str);
-                    if let Err(e) = Self::send_response(stream_arc, &response, peer_addr) {
-                        error!(
-                            "Failed to send invalid baudrate response to client {}: {}",
-                            peer_addr, e
-                        );
-                        return Err(e);
-                    }
-                }
-            }
-        }
-        // 处理波特率查询命令
-        else if cmd_str.starts_with("AT+BAUD?") {
-            // 等待一小段时间，确保客户端准备好接收数据
-            thread::sleep(Duration::from_millis(20));
+/// Per-connection handler of the RFC 2217 and WebSocket ports
+type ClientHandler =
+    fn(TcpStream, Arc<TcpClientManager>, CommandContext, TcpServerConfig, Arc<AtomicBool>) -> Result<()>;
 
-            info!("Processing AT+BAUD? command from client {}", peer_addr);
+/// Escape sequence that returns a raw mode client to command mode
+const ESCAPE_SEQUENCE: &[u8] = b"+++";
 
-            // 获取当前波特率
-            let current_baudrate = uart_manager.as_ref().get_baudrate();
-            let response = format!("Current baudrate: {}\r\n", current_baudrate);
-            if let Err(e) = Self::send_response(stream_arc, &response, peer_addr) {
-                error!(
-                    "Failed to send baudrate query response to client {}: {}",
-                    peer_addr, e
-                );
-                return Err(e);
-            }
-            info!(
-                "Successfully sent current baudrate {} to client {}",
-                current_baudrate, peer_addr
-            );
-        }
-        // 处理帮助命令
-        else if cmd_str.starts_with("AT+HELP") {
-            // 等待一小段时间，确保客户端准备好接收数据
-            thread::sleep(Duration::from_millis(20));
+/// Prefix that starts a command line
+const COMMAND_PREFIX: &[u8] = b"AT+";
 
-            info!("Processing AT+HELP command from client {}", peer_addr);
+/// Longest command line that is buffered; longer lines are forwarded as data
+const MAX_COMMAND_LINE_LEN: usize = 256;
 
-            let help_text = String::from("\r\nAvailable commands:\r\n")
-                + "  AT+BAUD=<rate>  - Change UART baud rate\r\n"
-                + "  AT+BAUD?       - Query current UART baud rate\r\n"
-                + "  AT+HELP        - Show this help message\r\n"
-                + "\r\nSupported baud rates: 9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1500000\r\n";
+/// Line sent before the UART history replayed to a new client
+const REPLAY_START: &[u8] = b"--- replay ---\r\n";
 
-            // 发送响应
-            if let Err(e) = Self::send_response(stream_arc, &help_text, peer_addr) {
-                error!("Failed to send help text to client {}: {}", peer_addr, e);
-                return Err(e);
-            }
-            info!("Successfully sent help text to client {}", peer_addr);
+/// Line sent after the UART history replayed to a new client
+const REPLAY_END: &[u8] = b"\r\n--- end of replay ---\r\n";
+
+/// Result of checking raw mode data for the escape sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapeCheck {
+    /// The data may be part of the escape sequence and is held back
+    Hold,
+    /// The data must be forwarded, preceded by `held` bytes of the escape sequence
+    Forward { held: usize },
+}
+
+/// Result of polling the escape detector while no data arrives
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum EscapePoll {
+    /// Nothing to do
+    Idle,
+    /// The escape sequence was followed by the guard time: leave raw mode
+    Escaped,
+    /// An incomplete sequence timed out: forward the `usize` held bytes
+    Release(usize),
+}
+
+/// Detects the "+++" escape sequence in raw mode
+///
+/// Like a Hayes modem, the sequence only counts when it is preceded and followed
+/// by the guard time without any other data, so it cannot be triggered by "+++"
+/// inside a binary stream.
+struct EscapeDetector {
+    /// Required silence before and after the sequence
+    guard: Duration,
+    /// Time since the last data was received
+    last_data: Stopwatch,
+    /// Number of escape bytes held back so far
+    held: usize,
+}
+
+impl EscapeDetector {
+    fn new(guard: Duration) -> Self {
+        Self {
+            guard,
+            last_data: Stopwatch::start(),
+            held: 0,
         }
-        // 未知命令
-        else {
-            // 等待一小段时间，确保客户端准备好接收数据
-            thread::sleep(Duration::from_millis(20));
+    }
 
-            info!(
-                "Processing unknown command '{}' from client {}",
-                cmd_str, peer_addr
-            );
+    /// Check data received in raw mode
+    fn on_data(&mut self, data: &[u8]) -> EscapeCheck {
+        let is_escape_part = data.iter().all(|&b| b == ESCAPE_SEQUENCE[0])
+            && self.held + data.len() <= ESCAPE_SEQUENCE.len()
+            && (self.held > 0 || self.last_data.has_elapsed(self.guard));
+        self.last_data.restart();
 
-            let response = format!(
-                "ERROR: Unknown command: {}\r\nType AT+HELP for available commands\r\n",
-                cmd_str
-            );
-            if let Err(e) = Self::send_response(stream_arc, &response, peer_addr) {
-                error!(
-                    "Failed to send unknown command response to client {}: {}",
-                    peer_addr, e
-                );
-                return Err(e);
+        if is_escape_part {
+            self.held += data.len();
+            EscapeCheck::Hold
+        } else {
+            EscapeCheck::Forward {
+                held: std::mem::take(&mut self.held),
             }
-            info!(
-                "Successfully sent unknown command response to client {}",
-                peer_addr
-            );
         }
-
-        Ok(())
     }
 
-    /// Send a response to a client
-    fn send_response(
-        stream_arc: &Arc<Mutex<TcpStream>>,
-        response: &str,
-        peer_addr: &std::net::SocketAddr,
-    ) -> Result<()> {
-        // 尝试获取流锁
-        let mut stream = match stream_arc.lock() {
-            Ok(guard) => guard,
-            Err(_) => {
-                return Err(Error::TcpError(format!(
-                    "Failed to lock stream for client {}",
-                    peer_addr
-                )))
-            }
-        };
+    /// Check whether the guard time after held escape bytes has passed
+    fn poll(&mut self) -> EscapePoll {
+        if self.held == 0 || !self.last_data.has_elapsed(self.guard) {
+            EscapePoll::Idle
+        } else if self.held == ESCAPE_SEQUENCE.len() {
+            self.held = 0;
+            EscapePoll::Escaped
+        } else {
+            EscapePoll::Release(std::mem::take(&mut self.held))
+        }
+    }
+}
 
-        // 尝试将流设置为阻塞模式，以确保数据发送完成
-        let _ = stream.set_nonblocking(false);
+/// Piece of client data as split by the `CommandFramer`
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Framed {
+    /// Bytes to forward to UART unchanged
+    Data(Vec<u8>),
+    /// A complete command line, without its terminator
+    Command(Vec<u8>),
+}
 
-        // 写入响应数据
-        match stream.write_all(response.as_bytes()) {
-            Ok(_) => {
-                // 立即刷新数据，确保数据被发送
-                if let Err(e) = stream.flush() {
-                    error!("Failed to flush response to client {}: {}", peer_addr, e);
-                    return Err(Error::TcpError(format!(
-                        "Failed to flush response to client {}: {}",
-                        peer_addr, e
-                    )));
-                }
+/// Splits client data into AT command lines and data to forward
+///
+/// The data is split on CR and LF. A line starting with "AT+" is a command, in any
+/// case and with spaces allowed before the '+' (see `commands::normalize`);
+/// one or more commands and plain data may arrive in a single read, and are
+/// returned in order. A line that starts like "AT+", or a part of it like a lone
+/// "A", is held back until CR or LF completes it, so commands split across reads
+/// or typed character by character work. Bytes that turn out not to be a command
+/// are released unchanged, including the held prefix, and so is a line longer than
+/// `MAX_COMMAND_LINE_LEN`. When no data arrives within the timeout a held line is
+/// released as well: as a command if it starts with "AT+", so clients that send no
+/// line terminator keep working, and as data otherwise.
+///
+/// The start of every read also counts as the start of a line.
+struct CommandFramer {
+    /// Bytes held back so far
+    line: Vec<u8>,
+    /// Time without data after which a held line is released
+    timeout: Duration,
+    /// Time since the last data was received
+    last_data: Stopwatch,
+    /// Whether a LF following the CR that ended the last command is dropped
+    skip_lf: bool,
+}
 
-                // 恢复非阻塞模式
-                let _ = stream.set_nonblocking(true);
+impl CommandFramer {
+    fn new(timeout: Duration) -> Self {
+        Self {
+            line: Vec::new(),
+            timeout,
+            last_data: Stopwatch::start(),
+            skip_lf: false,
+        }
+    }
 
-                info!("Sent response to client {}: {}", peer_addr, response.trim());
-                Ok(())
-            }
-            Err(e) => {
-                error!("Failed to send response to client {}: {}", peer_addr, e);
-                Err(Error::TcpError(format!(
-                    "Failed to send response to client {}: {}",
-                    peer_addr, e
-                )))
-            }
+    /// Check whether data may be the start of a command line
+    fn may_be_command(data: &[u8]) -> bool {
+        // "AT"不区分大小写，后面可以有空格再跟'+'
+        let len = data.len().min(2);
+        if !data[..len].eq_ignore_ascii_case(&COMMAND_PREFIX[..len]) {
+            return false;
+        }
+        match data.get(2..).and_then(|rest| rest.iter().find(|&&b| b != b' ')) {
+            Some(&byte) => byte == COMMAND_PREFIX[2],
+            None => true,
         }
     }
 
-    /// Run the TCP server
-    ///
-    /// This method starts the TCP server and accepts connections.
-    pub fn run(&self) -> Result<()> {
-        // 创建一个绑定到指定地址和端口的TCP监听器
-        let bind_address = format!("{}:{}", self.config.bind_address, self.config.port);
+    /// Check whether a line starts with the whole command prefix
+    fn is_command(line: &[u8]) -> bool {
+        Self::may_be_command(line) && line.iter().skip(2).any(|&b| b == COMMAND_PREFIX[2])
+    }
 
-        // 尝试绑定到指定地址和端口
-        info!("Attempting to bind TCP server to {}", bind_address);
-        let listener = match TcpListener::bind(&bind_address) {
-            Ok(l) => {
-                info!("Successfully bound to {}", bind_address);
-                l
-            }
-            Err(e) => {
-                // 如果绑定失败，尝试备选地址
-                error!("Failed to bind to {}: {}", bind_address, e);
+    fn is_line_end(byte: u8) -> bool {
+        byte == b'\r' || byte == b'\n'
+    }
 
-                // 尝试备选地址
-                let alt_bind_address = format!("192.168.4.1:{}", self.config.port);
-                info!("Trying alternative bind address: {}", alt_bind_address);
+    /// Feed received data, returning the pieces that are complete
+    fn push(&mut self, data: &[u8]) -> Vec<Framed> {
+        let mut framed = Vec::new();
+        let mut data = data;
+        // CR LF结束的命令，LF可能在下一次读取中到达
+        if std::mem::take(&mut self.skip_lf) {
+            data = data.strip_prefix(b"\n").unwrap_or(data);
+        }
+        if data.is_empty() {
+            return framed;
+        }
+        self.last_data.restart();
 
-                match TcpListener::bind(&alt_bind_address) {
-                    Ok(l) => {
-                        info!(
-                            "Successfully bound to alternative address: {}",
-                            alt_bind_address
-                        );
-                        l
-                    }
-                    Err(e2) => {
-                        // 如果备选地址也失败，尝试使用不同端口
-                        error!(
-                            "Failed to bind to alternative address {}: {}",
-                            alt_bind_address, e2
-                        );
+        // 相邻的非命令数据合并为一块转发
+        let mut forward = Vec::new();
+        let mut line_start = true;
+        let mut i = 0;
+        while i < data.len() {
+            let byte = data[i];
+            i += 1;
 
-                        let fallback_port = self.config.port + 1;
-                        let fallback_address = format!("0.0.0.0:{}", fallback_port);
-                        info!(
-                            "Trying fallback address with different port: {}",
-                            fallback_address
-                        );
+            // 行中间的数据：直接转发到行尾（包括行尾）
+            if self.line.is_empty() && !line_start {
+                let end = data[i - 1..]
+                    .iter()
+                    .position(|&b| Self::is_line_end(b))
+                    .map_or(data.len(), |pos| i + pos);
+                forward.extend_from_slice(&data[i - 1..end]);
+                i = end;
+                // 行尾之后（如果还有数据）是新的一行
+                line_start = true;
+                continue;
+            }
 
-                        TcpListener::bind(&fallback_address).map_err(|e3| {
-                            Error::TcpError(format!(
-                                "Failed to bind to any address: {}, {}, {}",
-                                e, e2, e3
-                            ))
-                        })?
+            if Self::is_line_end(byte) && Self::is_command(&self.line) {
+                if !forward.is_empty() {
+                    framed.push(Framed::Data(std::mem::take(&mut forward)));
+                }
+                framed.push(Framed::Command(std::mem::take(&mut self.line)));
+                if byte == b'\r' {
+                    match data.get(i) {
+                        Some(b'\n') => i += 1,
+                        Some(_) => {}
+                        None => self.skip_lf = true,
                     }
                 }
+                line_start = true;
+                continue;
             }
-        };
-
-        info!("TCP server successfully bound and listening");
 
-        // 设置套接字选项以提高可靠性
-        if let Err(e) = listener.set_nonblocking(false) {
-            error!("Failed to set TCP listener to blocking mode: {}", e);
-            // 即使设置模式失败也继续
-        } else {
-            info!("TCP server set to blocking mode");
+            self.line.push(byte);
+            if !Self::may_be_command(&self.line) || self.line.len() > MAX_COMMAND_LINE_LEN {
+                // 不是命令，连同已缓存的前缀一起原样转发
+                forward.append(&mut self.line);
+                line_start = Self::is_line_end(byte);
+            }
         }
 
-        // Accept connections and process them
-        for stream in listener.incoming() {
-            match stream {
-                Ok(stream) => {
-                    // Clone the managers for this thread
-                    let client_manager = Arc::clone(&self.client_manager);
-                    let uart_manager = Arc::clone(&self.uart_manager);
-                    let buffer_size = self.config.buffer_size;
+        if !forward.is_empty() {
+            framed.push(Framed::Data(forward));
+        }
+        framed
+    }
 
-                    // Handle each client in a new thread
-                    thread::spawn(move || {
-                        unsafe {
-                            esp_idf_sys::vTaskPrioritySet(
-                                esp_idf_sys::xTaskGetCurrentTaskHandle(),
-                                23, // 优先级范围通常是 0-24，数字越大优先级越高
-                            );
-                        }
-                        if let Err(e) =
-                            Self::handle_client(stream, client_manager, uart_manager, buffer_size)
-                        {
-                            error!("Error handling client: {}", e);
-                        }
-                    });
-                }
-                Err(e) => {
-                    error!("Connection failed: {}", e);
-                }
-            }
+    /// Release a held line once no data arrived within the timeout
+    fn poll(&mut self) -> Option<Framed> {
+        if self.line.is_empty() || !self.last_data.has_elapsed(self.timeout) {
+            return None;
         }
+        let line = std::mem::take(&mut self.line);
+        Some(if Self::is_command(&line) {
+            Framed::Command(line)
+        } else {
+            Framed::Data(line)
+        })
+    }
+}
+
+/// A UART bridge of the device, listed in AT+STATUS and AT+STATS
+#[derive(Clone)]
+pub struct Bridge {
+    /// Name of the bridge, e.g. "telemetry"
+    pub name: &'static str,
+    /// Data port of the bridge
+    pub port: u16,
+    /// UART of the bridge
+    pub uart_manager: Arc<UartManager>,
+    /// Clients of the bridge's data port
+    pub client_manager: Arc<TcpClientManager>,
+}
+
+/// Managers and state shared by every client handler and command
+#[derive(Clone)]
+pub(crate) struct CommandContext {
+    /// UART manager for sending/receiving data from UART
+    pub(crate) uart_manager: Arc<UartManager>,
+    /// Client manager of the data port (control port clients never receive UART data)
+    pub(crate) data_clients: Arc<TcpClientManager>,
+    /// WiFi manager for runtime WiFi configuration (None if not available)
+    pub(crate) wifi_manager: Option<Arc<Mutex<WiFiManager>>>,
+    /// Storage manager for persisting server settings (None if not available)
+    pub(crate) storage: Option<Arc<Mutex<StorageManager>>>,
+    /// Port the data listener is bound to (0 until bound)
+    pub(crate) active_port: Arc<AtomicU16>,
+    /// Connect-out client running alongside the server (None if disabled)
+    pub(crate) client_link: Option<Arc<TcpClientMode>>,
+    /// mDNS advertisement of the data port (None if not running)
+    pub(crate) mdns: Option<Arc<Mutex<MdnsAdvertiser>>>,
+    /// MQTT bridge running alongside the server (None if disabled)
+    pub(crate) mqtt: Option<Arc<MqttBridge>>,
+    /// Welcome banner template for data port clients (None sends no banner)
+    pub(crate) banner: Arc<Mutex<Option<String>>>,
+    /// Rules deciding which peers may connect to any of the ports
+    pub(crate) access_list: Arc<Mutex<AccessList>>,
+    /// Whether AT+BRIDGE may pause forwarding (not in transparent deployments)
+    pub(crate) bridge_pausable: bool,
+    /// Line ending appended by AT+SENDLN
+    pub(crate) send_line_ending: &'static str,
+    /// Named GPIOs for AT+GPIO (None if none are configured)
+    pub(crate) gpio: Option<Arc<GpioControl>>,
+    /// Every bridge of the device, including this one (empty with a single bridge)
+    pub(crate) bridges: Vec<Bridge>,
+    /// AT commands accepted from clients
+    pub(crate) commands: Arc<CommandRegistry>,
+}
 
+impl CommandContext {
+    /// Send data from a client to UART and copy it to the hex tap clients
+    ///
+    /// With a packet codec, `data` is sent as one packet.
+    pub(crate) fn send_to_uart(&self, peer_addr: &std::net::SocketAddr, data: &[u8]) -> Result<()> {
+        self.uart_manager.send_packet(data)?;
+        self.data_clients.tap_uart_tx(peer_addr, data);
         Ok(())
     }
+}
 
-    /// Handle a client connection
-    ///
-    /// This method handles a client connection, reading data from the client and forwarding it to UART.
-    /// It also handles receiving data from UART and sending it to the client.
-    fn handle_client(
-        stream: TcpStream,
-        client_manager: Arc<TcpClientManager>,
-        uart_manager: Arc<UartManager>,
-        buffer_size: usize,
-    ) -> Result<()> {
-        // 创建一个结构体来存储客户端的数据交互时间
-        struct ClientData {
-            last_interaction: std::time::Instant,
+/// Removes a client from its manager when dropped
+///
+/// Held by the protocol handlers for the lifetime of the connection, so the slot
+/// is freed on every way out, including errors returned early with `?`.
+struct Registration<'a> {
+    client_manager: &'a TcpClientManager,
+    peer_addr: SocketAddr,
+    conn_id: ConnectionId,
+}
+
+impl Drop for Registration<'_> {
+    fn drop(&mut self) {
+        // 连接已被移除时（如被踢出）什么也不做
+        if let Err(e) = self.client_manager.remove_client(&self.peer_addr, self.conn_id) {
+            error!("Failed to remove client {}: {}", self.peer_addr, e);
         }
+    }
+}
 
-        // 创建客户端数据实例
-        let mut client_data = ClientData {
-            last_interaction: std::time::Instant::now(),
-        };
+/// Result of one read from a data port client
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ReadOutcome {
+    /// Data was read and handled
+    Data,
+    /// No data was available
+    Idle,
+    /// The client disconnected and was removed from the manager
+    Closed,
+}
+
+/// State of one data port connection
+///
+/// Used by the per-client handler threads and by the polling server alike, so both
+/// I/O models handle data and commands the same way.
+struct ClientSession {
+    /// Address of the client
+    peer_addr: SocketAddr,
+    /// Connection id given by the client manager
+    conn_id: ConnectionId,
+    /// Traffic counters of this connection
+    traffic: TrafficCounter,
+    /// Raw socket, for poll()
+    fd: RawFd,
+    /// Stream shared with the client manager, locked only for writes
+    stream_arc: SharedStream,
+    /// Read side of the stream
+    reader: StreamReader,
+    /// Escape sequence detector (None when commands are disabled on the data port)
+    escape: Option<EscapeDetector>,
+    /// Splits command mode data into command lines and data
+    framer: CommandFramer,
+    /// Line ending translation state of the data sent to UART in command mode
+    eol: EolState,
+    /// 记录客户端最后一次数据交互的时间
+    last_interaction: Stopwatch,
+}
+
+impl ClientSession {
+    /// Register a new client with the manager, set up its socket and greet it
+    fn open(
+        stream: TcpStream,
+        client_manager: &Arc<TcpClientManager>,
+        context: &CommandContext,
+        config: &TcpServerConfig,
+    ) -> Result<Self> {
         let peer_addr = stream
             .peer_addr()
-            .map_err(|e| Error::TcpError(format!("Failed to get peer address: {}", e)))?;
+            .map_err(|e| Error::tcp_caused("Failed to get peer address", e))?;
+        let fd = stream.as_raw_fd();
 
         info!("New client connected: {}", peer_addr);
 
@@ -399,14 +444,34 @@ impl TcpServer {
         // Wrap the stream in an Arc<Mutex<>> for thread-safe sharing
         let stream_arc = Arc::new(Mutex::new(stream));
 
-        // Add the client to the manager
-        client_manager.add_client(peer_addr, Arc::clone(&stream_arc))?;
+        let reader = StreamReader::new(&stream_arc)?;
+
+        // Add the client to the manager, queueing the recent UART output first (AT+REPLAY)
+        let uart_manager = &context.uart_manager;
+        let conn_id = uart_manager.attach_with_replay(|history| -> Result<ConnectionId> {
+            let conn_id = client_manager.add_client(peer_addr, Arc::clone(&stream_arc))?;
+            // 只回放队列上限一半以内的最新数据，避免新客户端刚连接就因积压被断开
+            let history = &history[history.len().saturating_sub(client_manager.queue_limit() / 2)..];
+            if !history.is_empty() {
+                let markers = uart_manager.replay_markers();
+                if markers {
+                    client_manager.queue_to(&peer_addr, REPLAY_START)?;
+                }
+                client_manager.queue_to(&peer_addr, history)?;
+                if markers {
+                    client_manager.queue_to(&peer_addr, REPLAY_END)?;
+                }
+                debug!("Replaying {} bytes of UART history to {}", history.len(), peer_addr);
+            }
+            Ok(conn_id)
+        })?;
         debug!("Added client stream to manager for {}", peer_addr);
+        let traffic = client_manager.traffic_counter(&peer_addr)?;
 
         // Get the stream lock for setting options
         let stream_guard = stream_arc
             .lock()
-            .map_err(|_| Error::TcpError("Failed to lock stream".to_string()))?;
+            .map_err(|_| Error::tcp("Failed to lock stream"))?;
 
         // Set non-blocking mode so we don't block if there's no data
         if let Err(e) = stream_guard.set_nonblocking(true) {
@@ -417,156 +482,2066 @@ impl TcpServer {
             // Continue even if setting the mode fails
         }
 
-        // 设置 TCP 的缓冲区大小，提高性能
-        if let Err(e) = stream_guard.set_nodelay(true) {
-            error!("Failed to set TCP_NODELAY for client {}: {}", peer_addr, e);
-            // Continue even if setting the option fails
-        }
+        TcpServer::tune_socket(&stream_guard, config, &peer_addr);
+        TcpServer::enable_keepalive(&stream_guard, config, &peer_addr);
         debug!("Client {} ready for reading", peer_addr);
 
         // Release the lock so other threads can use the stream
         drop(stream_guard);
+        let stream_arc: SharedStream = stream_arc;
 
-        // 初始化缓冲区
-        let mut buffer = vec![0; buffer_size];
-        debug!("Starting to read from client {}", peer_addr);
+        // 透明模式下不发送欢迎消息，也不解析AT命令
+        // 启用控制端口时，数据端口始终透明且不能通过转义序列切换到命令模式
+        let commands_enabled = config.control_port.is_none();
+        let transparent = config.transparent || !commands_enabled;
+        if transparent {
+            client_manager.set_raw_mode(&peer_addr, true)?;
+        }
+        let escape = commands_enabled
+            .then(|| EscapeDetector::new(Duration::from_millis(config.escape_guard_ms)));
+        let framer = CommandFramer::new(Duration::from_millis(config.command_timeout_ms));
 
-        // 等待一小段时间，确保客户端已准备好接收数据
-        thread::sleep(Duration::from_millis(10));
+        // 发送欢迎消息（可通过AT+BANNER修改或关闭）
+        let template = context.banner.lock().ok().and_then(|banner| banner.clone());
+        if transparent {
+            debug!("Transparent mode, no welcome message for client {}", peer_addr);
+        } else if let Some(template) = template {
+            let welcome_msg =
+                TcpServer::render_banner(&template, &peer_addr, context, client_manager, config.max_clients);
+            if TcpServer::send_response(&stream_arc, &welcome_msg, &peer_addr).is_ok() {
+                info!("Sent welcome message to client {}", peer_addr);
+            }
+        }
 
-        // 发送欢迎消息
-        let welcome_msg = format!(
-            "Welcome to ESP32 UART-TCP Bridge! Your client ID: {}\r\n\
-            Type AT+HELP for available commands\r\n\
-            Current UART baudrate: {}\r\n",
+        Ok(Self {
             peer_addr,
-            uart_manager.as_ref().get_baudrate()
-        );
-        if let Ok(mut stream) = stream_arc.lock() {
-            match stream.write_all(welcome_msg.as_bytes()) {
-                Ok(_) => {
-                    // 立即刷新数据，确保数据被发送
-                    if let Err(e) = stream.flush() {
-                        error!(
-                            "Failed to flush welcome message to client {}: {}",
-                            peer_addr, e
-                        );
-                    } else {
-                        info!("Sent welcome message to client {}", peer_addr);
+            conn_id,
+            traffic,
+            fd,
+            stream_arc,
+            reader,
+            escape,
+            framer,
+            eol: EolState::default(),
+            last_interaction: Stopwatch::start(),
+        })
+    }
+
+    /// Handle the escape guard time and command lines that timed out
+    ///
+    /// Called regularly whether or not data arrives.
+    fn poll_timers(&mut self, context: &CommandContext, client_manager: &Arc<TcpClientManager>) -> Result<()> {
+        // 检查原始模式下的转义序列
+        match self.escape.as_mut().map_or(EscapePoll::Idle, EscapeDetector::poll) {
+            EscapePoll::Idle => {}
+            EscapePoll::Escaped => {
+                client_manager.set_raw_mode(&self.peer_addr, false)?;
+                let _ = TcpServer::send_response(&self.stream_arc, "\r\nOK: Command mode\r\n", &self.peer_addr);
+            }
+            EscapePoll::Release(held) => {
+                if client_manager.locked_by_other(&self.peer_addr).is_none() {
+                    if let Err(e) = context.send_to_uart(&self.peer_addr, &ESCAPE_SEQUENCE[..held]) {
+                        error!("Error sending data to UART: {}", e);
                     }
                 }
-                Err(e) => {
-                    error!(
-                        "Failed to send welcome message to client {}: {}",
-                        peer_addr, e
-                    );
-                }
             }
         }
 
-        loop {
-            // 获取流锁进行读取
-            let mut stream = match stream_arc.lock() {
-                Ok(guard) => guard,
-                Err(e) => {
-                    error!("Failed to lock stream for client {}: {}", peer_addr, e);
-                    break;
-                }
-            };
+        // 超时仍未完成的命令行
+        if let Some(framed) = self.framer.poll() {
+            TcpServer::dispatch_framed(framed, &mut self.eol, context, client_manager, &self.stream_arc, &self.peer_addr);
+        }
+        Ok(())
+    }
 
-            // Read data from the client
-            match stream.read(&mut buffer) {
-                Ok(0) => {
-                    // Connection closed by client
-                    info!("Client {} disconnected", peer_addr);
-                    // Remove the client from the manager
-                    client_manager.remove_client(&peer_addr)?;
-                    debug!("Removed client {} from manager", peer_addr);
-                    break;
+    /// Read once from the client and forward the data or process its commands
+    fn read(
+        &mut self,
+        buffer: &mut [u8],
+        context: &CommandContext,
+        client_manager: &Arc<TcpClientManager>,
+    ) -> Result<ReadOutcome> {
+        let peer_addr = self.peer_addr;
+
+        // Read data from the client
+        // 读取时不持有流锁，写线程可以同时写出UART数据
+        match self.reader.read(buffer) {
+            Ok(0) => {
+                // Connection closed by client
+                info!(
+                    "Client {} disconnected (idle for {})",
+                    peer_addr,
+                    time::format_duration(self.last_interaction.elapsed())
+                );
+                // Remove the client from the manager
+                client_manager.remove_client(&peer_addr, self.conn_id)?;
+                debug!("Removed client {} from manager", peer_addr);
+                Ok(ReadOutcome::Closed)
+            }
+            Ok(n) => {
+                // 更新最后一次数据交互时间
+                self.last_interaction.restart();
+                self.traffic.record_received(n);
+
+                // 使用trace级别记录详细日志，减少日志开销
+                if log::log_enabled!(log::Level::Trace) {
+                    let hex_str: String =
+                        buffer[0..n].iter().map(|b| format!("{:02X} ", b)).collect();
+                    trace!(
+                        "TCP -> UART: {} bytes from {} (hex): {}",
+                        n,
+                        peer_addr,
+                        hex_str
+                    );
+                } else {
+                    debug!("TCP -> UART: {} bytes from {}", n, peer_addr);
                 }
-                Ok(n) => {
-                    // Send the received data to UART
-                    if n > 0 {
-                        // 更新最后一次数据交互时间
-                        client_data.last_interaction = std::time::Instant::now();
-
-                        // 使用trace级别记录详细日志，减少日志开销
-                        if log::log_enabled!(log::Level::Trace) {
-                            let hex_str: String =
-                                buffer[0..n].iter().map(|b| format!("{:02X} ", b)).collect();
-                            trace!(
-                                "TCP -> UART: {} bytes from {} (hex): {}",
-                                n,
-                                peer_addr,
-                                hex_str
-                            );
-                        } else {
-                            debug!("TCP -> UART: {} bytes from {}", n, peer_addr);
-                        }
 
-                        // 检查是否是命令
-                        if Self::is_command(&buffer[0..n]) {
-                            // 释放流锁，以便在命令处理过程中可以重新获取锁
-                            drop(stream);
-
-                            // 等待一小段时间，确保客户端准备好接收数据
-                            thread::sleep(Duration::from_millis(10));
-
-                            // 处理命令
-                            if let Err(e) = Self::process_command(
-                                &buffer[0..n],
-                                &uart_manager,
-                                &stream_arc,
-                                &peer_addr,
-                            ) {
-                                error!("Error processing command from client {}: {}", peer_addr, e);
+                // 本地回显只用于命令模式，原始模式和UART广播从不回显
+                let raw_mode = client_manager.is_raw_mode(&peer_addr);
+                if !raw_mode && client_manager.is_echo(&peer_addr) {
+                    match self.stream_arc.lock() {
+                        Ok(mut stream) => {
+                            if let Err(e) = stream.write_all(&buffer[0..n]) {
+                                debug!("Failed to echo data to client {}: {}", peer_addr, e);
                             }
-                        } else {
-                            // 直接发送数据到UART，不做中间处理
-                            if let Err(e) = uart_manager.send_data(&buffer[0..n]) {
+                        }
+                        Err(_) => error!("Failed to lock stream for client {}", peer_addr),
+                    }
+                }
+
+                // 原始模式：所有数据直接发送到UART，只检查转义序列
+                if raw_mode {
+                    let check = self
+                        .escape
+                        .as_mut()
+                        .map_or(EscapeCheck::Forward { held: 0 }, |escape| {
+                            escape.on_data(&buffer[0..n])
+                        });
+                    // 其他客户端独占UART时拒绝数据（仍然接收UART广播）
+                    let locked_by = client_manager.locked_by_other(&peer_addr);
+                    if let (EscapeCheck::Forward { .. }, Some(holder)) = (check, locked_by) {
+                        TcpServer::reject_locked(&self.stream_arc, &holder, &peer_addr);
+                    } else if matches!(check, EscapeCheck::Forward { .. }) && !context.uart_manager.is_bridge_enabled() {
+                        TcpServer::reject_paused(&self.stream_arc, &peer_addr);
+                    } else if let EscapeCheck::Forward { held } = check {
+                        if held > 0 {
+                            if let Err(e) = context.send_to_uart(&peer_addr, &ESCAPE_SEQUENCE[..held]) {
                                 error!("Error sending data to UART: {}", e);
                             }
                         }
+                        if let Err(e) = context.send_to_uart(&peer_addr, &buffer[0..n]) {
+                            error!("Error sending data to UART: {}", e);
+                        }
                     }
                 }
-                Err(e) => {
-                    // Check if it's a "would block" error (no data available)
-                    let error_string = format!("{:?}", e);
-                    if error_string.contains("WouldBlock") || error_string.contains("TimedOut") {
-                        // This is just no data available, not an error, don't disconnect
-                        // 使用更短的睡眠时间，减少延迟
-                        thread::sleep(Duration::from_millis(1));
-                        continue;
-                    } else {
-                        // Real error, disconnect
-                        error!("Error reading from client {}: {}", peer_addr, e);
-                        // Remove the client from the manager
-                        client_manager.remove_client(&peer_addr)?;
-                        debug!("Removed client {} from manager due to error", peer_addr);
-                        break;
+                // 命令模式：按行组装AT命令，其余数据原样转发
+                else {
+                    for item in self.framer.push(&buffer[0..n]) {
+                        TcpServer::dispatch_framed(item, &mut self.eol, context, client_manager, &self.stream_arc, &peer_addr);
                     }
                 }
+                Ok(ReadOutcome::Data)
             }
-            thread::sleep(Duration::from_millis(2));
+            Err(e) => match e.kind() {
+                // This is just no data available, not an error, don't disconnect
+                // 被信号中断也只是稍后重试
+                ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => Ok(ReadOutcome::Idle),
+                ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+                    info!("Client {} connection lost: {}", peer_addr, e);
+                    client_manager.remove_client(&peer_addr, self.conn_id)?;
+                    Ok(ReadOutcome::Closed)
+                }
+                _ => {
+                    // Real error, disconnect
+                    error!("Error reading from client {}: {}", peer_addr, e);
+                    // Remove the client from the manager
+                    client_manager.remove_client(&peer_addr, self.conn_id)?;
+                    debug!("Removed client {} from manager due to error", peer_addr);
+                    Ok(ReadOutcome::Closed)
+                }
+            },
         }
+    }
 
-        Ok(())
+    /// Notify the client that the server is stopping, then close and remove it
+    fn close_on_shutdown(&self, client_manager: &TcpClientManager) -> Result<()> {
+        TcpServer::close_on_shutdown(client_manager, &self.stream_arc, &self.peer_addr, self.conn_id)
     }
 }
 
-/// Run a TCP server with the given client manager and UART manager
+/// TCP Server
 ///
-/// This is a convenience function for backward compatibility
-pub fn run_tcp_server(
-    client_manager: Arc<TcpClientManager>,
+/// Manages a TCP server that accepts connections and forwards data between clients and UART.
+pub struct TcpServer {
+    /// TCP server configuration
+    config: TcpServerConfig,
+    /// Client manager for handling client connections
+    client_manager: Arc<TcpClientManager>,
+    /// Client manager for control port connections (never receives UART data)
+    control_manager: Arc<TcpClientManager>,
+    /// Managers and state shared with every client handler
+    context: CommandContext,
+    /// Set by `stop` to end the accept loops and all client handlers
+    shutdown: Arc<AtomicBool>,
+    /// Device status flagged when the server cannot listen
+    status: Option<Arc<DeviceStatus>>,
+}
+
+impl TcpServer {
+    /// Create a new TCP server with the given configuration and managers
+    pub fn new(
+        config: TcpServerConfig,
+        client_manager: Arc<TcpClientManager>,
+        uart_manager: Arc<UartManager>,
+        wifi_manager: Option<Arc<Mutex<WiFiManager>>>,
+        storage: Option<Arc<Mutex<StorageManager>>>,
+    ) -> Self {
+        let context = CommandContext {
+            uart_manager,
+            data_clients: Arc::clone(&client_manager),
+            wifi_manager,
+            storage,
+            active_port: Arc::new(AtomicU16::new(0)),
+            client_link: None,
+            mdns: None,
+            mqtt: None,
+            banner: Arc::new(Mutex::new(config.welcome_message.clone())),
+            access_list: Arc::new(Mutex::new(config.access_list.clone())),
+            bridge_pausable: !config.transparent,
+            send_line_ending: config.send_line_ending,
+            gpio: None,
+            bridges: Vec::new(),
+            commands: Arc::new(CommandRegistry::standard()),
+        };
+        Self {
+            config,
+            client_manager,
+            control_manager: Arc::new(TcpClientManager::new()),
+            context,
+            shutdown: Arc::new(AtomicBool::new(false)),
+            status: None,
+        }
+    }
+
+    /// Report the state of a connect-out client in AT+STATUS
+    ///
+    /// Must be called before `run`, clients connected earlier do not see it.
+    pub fn set_client_link(&mut self, client_link: Arc<TcpClientMode>) {
+        self.context.client_link = Some(client_link);
+    }
+
+    /// Report the state of an MQTT bridge in AT+STATUS
+    ///
+    /// Must be called before `run`.
+    pub fn set_mqtt(&mut self, mqtt: Arc<MqttBridge>) {
+        self.context.mqtt = Some(mqtt);
+    }
+
+    /// Keep an mDNS advertisement in sync with the data port and AT+NAME
+    ///
+    /// Must be called before `run`.
+    pub fn set_mdns(&mut self, mdns: Arc<Mutex<MdnsAdvertiser>>) {
+        self.context.mdns = Some(mdns);
+    }
+
+    /// Control the target's RESET/BOOT lines with AT+GPIO
+    ///
+    /// Must be called before `run`.
+    pub fn set_gpio(&mut self, gpio: Arc<GpioControl>) {
+        self.context.gpio = Some(gpio);
+    }
+
+    /// Report every bridge of the device, including this one, in AT+STATUS and AT+STATS
+    ///
+    /// Must be called before `run`.
+    pub fn set_bridges(&mut self, bridges: Vec<Bridge>) {
+        self.context.bridges = bridges;
+    }
+
+    /// Report on `status` when no port can be bound
+    ///
+    /// Must be called before `run`.
+    pub fn set_status(&mut self, status: Arc<DeviceStatus>) {
+        self.status = Some(status);
+    }
+
+    /// Get the access list of the server, to admit peers of other services by it
+    ///
+    /// Changes made with AT+ACL apply to the services sharing the list at once.
+    pub fn access_list(&self) -> Arc<Mutex<AccessList>> {
+        Arc::clone(&self.context.access_list)
+    }
+
+    /// Get the AT commands of the server, e.g. to validate a command line
+    #[cfg(target_os = "espidf")]
+    pub(crate) fn commands(&self) -> &CommandRegistry {
+        &self.context.commands
+    }
+
+    /// Read the welcome banner saved with AT+BANNER
+    fn saved_banner(&self) -> Option<Option<String>> {
+        let storage = self.context.storage.as_ref()?.lock().ok()?;
+        storage.read_banner()
+    }
+
+    /// Read the access list saved with AT+ACL
+    fn saved_access_list(&self) -> Option<AccessList> {
+        let storage = self.context.storage.as_ref()?.lock().ok()?;
+        storage.read_access_list()
+    }
+
+    /// Fill in the placeholders of a welcome banner template
+    ///
+    /// A literal "\n" becomes CR LF, and the banner always ends with CR LF.
+    fn render_banner(
+        template: &str,
+        peer_addr: &std::net::SocketAddr,
+        context: &CommandContext,
+        client_manager: &TcpClientManager,
+        max_clients: usize,
+    ) -> String {
+        let max_clients = if max_clients > 0 {
+            max_clients.to_string()
+        } else {
+            "unlimited".to_string()
+        };
+        let mut banner = template
+            .replace("{name}", &device_name::get())
+            .replace("{client_addr}", &peer_addr.to_string())
+            .replace("{baudrate}", &context.uart_manager.get_baudrate().to_string())
+            .replace("{port}", &context.active_port.load(Ordering::Relaxed).to_string())
+            .replace("{clients}", &client_manager.client_count().unwrap_or(0).to_string())
+            .replace("{max_clients}", &max_clients)
+            .replace("\\n", "\r\n");
+        if !banner.ends_with('\n') {
+            banner.push_str("\r\n");
+        }
+        banner
+    }
+
+    /// Send a response to a client
+    ///
+    /// The socket is switched to blocking mode with a write timeout for the
+    /// response, so a full send buffer delays the reply instead of cutting it off,
+    /// and a client that stops reading cannot hold the handler forever.
+    fn send_response(
+        stream_arc: &SharedStream,
+        response: &str,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Result<()> {
+        Self::write_reply(stream_arc, response.as_bytes(), peer_addr)?;
+        info!("Sent response to client {}: {}", peer_addr, response.trim());
+        Ok(())
+    }
+
+    /// Write a reply completely, waiting at most `RESPONSE_WRITE_TIMEOUT_MS`
+    fn write_reply(stream_arc: &SharedStream, reply: &[u8], peer_addr: &std::net::SocketAddr) -> Result<()> {
+        // 尝试获取流锁
+        let mut stream = match stream_arc.lock() {
+            Ok(guard) => guard,
+            Err(_) => {
+                return Err(Error::tcp(format!(
+                    "Failed to lock stream for client {}",
+                    peer_addr
+                )))
+            }
+        };
+
+        // 临时切换到带超时的阻塞模式，确保响应完整发送
+        let _ = stream.set_nonblocking(false);
+        let _ = stream.set_write_timeout(Some(Duration::from_millis(RESPONSE_WRITE_TIMEOUT_MS)));
+        let result = stream.write_all(reply).and_then(|_| stream.flush());
+        let _ = stream.set_write_timeout(None);
+        let _ = stream.set_nonblocking(true);
+
+        match result {
+            Ok(_) => Ok(()),
+            Err(e) => {
+                error!("Failed to send response to client {}: {}", peer_addr, e);
+                Err(Error::tcp_caused(
+                    format!("Failed to send response to client {}", peer_addr),
+                    e,
+                ))
+            }
+        }
+    }
+
+    /// Run the TCP server
+    ///
+    /// This method starts the TCP server and accepts connections until `stop` is called.
+    pub fn run(&self) -> Result<()> {
+        // 允许在stop()之后再次运行
+        self.shutdown.store(false, Ordering::SeqCst);
+
+        // 优先使用保存在flash中的端口
+        let port = Self::saved_port(&self.context).unwrap_or(self.config.port);
+        if let Some(banner) = self.saved_banner() {
+            if let Ok(mut current) = self.context.banner.lock() {
+                *current = banner;
+            }
+        }
+        if let Some(list) = self.saved_access_list() {
+            if let Ok(mut current) = self.context.access_list.lock() {
+                *current = list;
+            }
+        }
+        if self.context.uart_manager.accepts_commands() {
+            let context = self.context.clone();
+            self.context
+                .uart_manager
+                .set_command_handler(Box::new(move |line| Self::run_uart_command(&context, line)));
+        }
+        let listener = self.bind_listener(port)?;
+        let active_port = listener.local_addr().map(|addr| addr.port()).unwrap_or(port);
+        self.context.active_port.store(active_port, Ordering::Relaxed);
+
+        // mDNS广播实际监听的端口
+        if let Some(mdns) = &self.context.mdns {
+            match mdns.lock() {
+                Ok(mut mdns) => {
+                    if let Err(e) = mdns.set_port(active_port) {
+                        warn!("Failed to update mDNS service port: {}", e);
+                    }
+                }
+                Err(_) => warn!("Failed to lock mDNS advertiser"),
+            }
+        }
+
+        // 启动客户端写线程（控制端口的写线程在spawn_control_server中启动）
+        // 轮询模式在服务线程中写出排队数据
+        if self.config.io_model == IoModel::ThreadPerClient {
+            TcpClientManager::start_writer(&self.client_manager)?;
+        }
+
+        let mut workers = Vec::new();
+
+        // 启动控制端口
+        if let Some(control_port) = self.config.control_port {
+            workers.push(self.spawn_control_server(self.bind_listener(control_port)?)?);
+        }
+
+        // 启动RFC 2217端口
+        if let Some(rfc2217_port) = self.config.rfc2217_port {
+            let listener = self.bind_listener(rfc2217_port)?;
+            workers.push(self.spawn_bridge_server(listener, "RFC 2217", Self::handle_rfc2217_client)?);
+        }
+
+        // 启动WebSocket端口
+        if let Some(websocket_port) = self.config.websocket_port {
+            let listener = self.bind_listener(websocket_port)?;
+            workers.push(self.spawn_bridge_server(listener, "WebSocket", Self::handle_websocket_client)?);
+        }
+
+        // 启动空闲客户端清理线程
+        if self.config.idle_timeout_secs > 0 {
+            workers.push(self.spawn_idle_reaper()?);
+        }
+
+        // Accept connections and process them until stopped
+        {
+            // 服务线程卡住时由看门狗复位，停止后不再监视
+            let _watchdog = watchdog::supervise("tcp_server");
+            let _tracker = diagnostics::track_thread("tcp_server");
+            match self.config.io_model {
+                IoModel::ThreadPerClient => {
+                    Self::accept_until_stopped(&listener, &self.shutdown, &self.context.access_list, |stream| {
+                        self.accept_client(stream)
+                    })
+                }
+                IoModel::Poll => self.serve_polled(&listener),
+            }
+        }
+
+        // 等待辅助线程退出，确保端口在返回前已释放
+        for worker in workers {
+            if worker.join().is_err() {
+                error!("TCP server worker thread panicked");
+            }
+        }
+        info!("TCP server stopped");
+        Ok(())
+    }
+
+    /// Stop the server
+    ///
+    /// `run` returns shortly after, and every client handler closes its connection
+    /// and removes the client from the manager. The server can be run again afterwards.
+    pub fn stop(&self) {
+        info!("Stopping TCP server");
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Check whether the server has been asked to stop
+    pub fn is_stopped(&self) -> bool {
+        self.shutdown.load(Ordering::SeqCst)
+    }
+
+    /// Accept connections on a listener until `shutdown` is set
+    ///
+    /// The listener is switched to non-blocking mode so the flag is checked at
+    /// least every `ACCEPT_POLL_MS` milliseconds. Peers refused by `access_list`
+    /// are closed without being passed to `on_accept`.
+    fn accept_until_stopped(
+        listener: &TcpListener,
+        shutdown: &AtomicBool,
+        access_list: &Mutex<AccessList>,
+        mut on_accept: impl FnMut(TcpStream),
+    ) {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Failed to set TCP listener to non-blocking mode: {}", e);
+        }
+
+        while !shutdown.load(Ordering::SeqCst) {
+            watchdog::feed();
+            match listener.accept() {
+                Ok((stream, _)) => {
+                    if !Self::is_permitted(&stream, access_list) {
+                        continue;
+                    }
+                    // 客户端处理线程会自行设置阻塞模式
+                    if let Err(e) = stream.set_nonblocking(false) {
+                        error!("Failed to set blocking mode for accepted client: {}", e);
+                    }
+                    on_accept(stream);
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+                }
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+                }
+            }
+        }
+    }
+
+    /// Check a new connection against the access list, closing it if it is refused
+    ///
+    /// Nothing is sent to a refused peer, so it learns nothing about the device.
+    fn is_permitted(stream: &TcpStream, access_list: &Mutex<AccessList>) -> bool {
+        let Ok(peer_addr) = stream.peer_addr() else {
+            let _ = stream.shutdown(Shutdown::Both);
+            return false;
+        };
+        // 锁异常时放行，避免把所有客户端拒之门外
+        let permitted = access_list
+            .lock()
+            .map_or(true, |list| list.is_allowed(peer_addr.ip()));
+        if !permitted {
+            warn!("Rejecting client {}: not permitted by the access list", peer_addr);
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        permitted
+    }
+
+    /// Admit a data port connection and spawn its handler thread
+    fn accept_client(&self, stream: TcpStream) {
+        let Some(stream) = Self::admit_client(stream, &self.client_manager, &self.config) else {
+            return;
+        };
+
+        // Clone the managers for this thread
+        let client_manager = Arc::clone(&self.client_manager);
+        let context = self.context.clone();
+        let config = self.config.clone();
+        let shutdown = Arc::clone(&self.shutdown);
+
+        // Handle each client in a new thread
+        thread::spawn(move || {
+            diagnostics::set_task_priority(23);
+            if let Err(e) = Self::handle_client(stream, client_manager, context, config, shutdown) {
+                error!("Error handling client: {}", e);
+            }
+        });
+    }
+
+    /// Apply `max_clients` to a new data port or RFC 2217 connection
+    ///
+    /// Returns the stream if the client may stay, after evicting the oldest client
+    /// if the policy asks for it. A rejected client is told why and closed.
+    fn admit_client(
+        mut stream: TcpStream,
+        client_manager: &TcpClientManager,
+        config: &TcpServerConfig,
+    ) -> Option<TcpStream> {
+        // 内存不足时拒绝新连接，控制端口不受影响，仍可用AT+MEM查看
+        if diagnostics::heap_is_low() {
+            warn!(
+                "Rejecting client {:?}: low on memory ({} bytes free)",
+                stream.peer_addr(),
+                diagnostics::free_heap()
+            );
+            let _ = stream.write_all(b"ERROR: Device is low on memory, try again later\r\n");
+            let _ = stream.flush();
+            let _ = stream.shutdown(Shutdown::Both);
+            return None;
+        }
+
+        // 检查是否已达到最大客户端数量
+        let client_count = client_manager.client_count().unwrap_or(0);
+        if config.max_clients > 0 && client_count >= config.max_clients {
+            match config.eviction_policy {
+                EvictionPolicy::RejectNew => {
+                    warn!(
+                        "Rejecting client {:?}: too many clients ({}/{})",
+                        stream.peer_addr(),
+                        client_count,
+                        config.max_clients
+                    );
+                    let response = format!(
+                        "ERROR: too many clients ({}/{})\r\n",
+                        client_count, config.max_clients
+                    );
+                    let _ = stream.write_all(response.as_bytes());
+                    let _ = stream.flush();
+                    let _ = stream.shutdown(Shutdown::Both);
+                    return None;
+                }
+                EvictionPolicy::EvictOldest => {
+                    if let Err(e) = client_manager.evict_oldest() {
+                        error!("Failed to evict oldest client: {}", e);
+                    }
+                }
+            }
+        }
+
+        Some(stream)
+    }
+
+    /// Bind a TCP listener to the configured address and the given port
+    ///
+    /// Falls back to the AP address and then to the next port if binding fails.
+    fn bind_listener(&self, port: u16) -> Result<TcpListener> {
+        // 创建一个绑定到指定地址和端口的TCP监听器
+        let bind_address = format!("{}:{}", self.config.bind_address, port);
+
+        // 尝试绑定到指定地址和端口
+        info!("Attempting to bind TCP server to {}", bind_address);
+        let listener = match TcpListener::bind(&bind_address) {
+            Ok(l) => {
+                info!("Successfully bound to {}", bind_address);
+                l
+            }
+            Err(e) => {
+                // 如果绑定失败，尝试备选地址
+                error!("Failed to bind to {}: {}", bind_address, e);
+
+                // 尝试备选地址
+                let alt_bind_address = format!("192.168.4.1:{}", port);
+                info!("Trying alternative bind address: {}", alt_bind_address);
+
+                match TcpListener::bind(&alt_bind_address) {
+                    Ok(l) => {
+                        info!(
+                            "Successfully bound to alternative address: {}",
+                            alt_bind_address
+                        );
+                        l
+                    }
+                    Err(e2) => {
+                        // 如果备选地址也失败，尝试使用不同端口
+                        error!(
+                            "Failed to bind to alternative address {}: {}",
+                            alt_bind_address, e2
+                        );
+
+                        let fallback_port = port + 1;
+                        let fallback_address = format!("0.0.0.0:{}", fallback_port);
+                        info!(
+                            "Trying fallback address with different port: {}",
+                            fallback_address
+                        );
+
+                        TcpListener::bind(&fallback_address).map_err(|e3| {
+                            if let Some(status) = &self.status {
+                                status.set_error();
+                            }
+                            Error::tcp(format!(
+                                "Failed to bind to any address: {}, {}, {}",
+                                e, e2, e3
+                            ))
+                        })?
+                    }
+                }
+            }
+        };
+
+        info!("TCP server successfully bound and listening");
+
+        Ok(listener)
+    }
+
+    /// Spawn a thread that accepts control port connections
+    fn spawn_control_server(&self, listener: TcpListener) -> Result<JoinHandle<()>> {
+        // 控制端口客户端不接收UART数据，写线程只发送日志流
+        TcpClientManager::start_writer(&self.control_manager)?;
+        let control_manager = Arc::clone(&self.control_manager);
+        let context = self.context.clone();
+        let config = self.config.clone();
+        let shutdown = Arc::clone(&self.shutdown);
+
+        let handle = thread::Builder::new()
+            .name("control_server".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let access_list = Arc::clone(&context.access_list);
+                Self::accept_until_stopped(&listener, &shutdown, &access_list, |stream| {
+                    let control_manager = Arc::clone(&control_manager);
+                    let context = context.clone();
+                    let config = config.clone();
+                    let shutdown = Arc::clone(&shutdown);
+                    thread::spawn(move || {
+                        if let Err(e) = Self::handle_control_client(
+                            stream,
+                            control_manager,
+                            context,
+                            config,
+                            shutdown,
+                        ) {
+                            error!("Error handling control client: {}", e);
+                        }
+                    });
+                });
+            })
+            .map_err(|e| Error::tcp_caused("Failed to spawn control server thread", e))?;
+
+        info!("Control port listening, AT commands are only accepted there");
+        Ok(handle)
+    }
+
+    /// Spawn the thread accepting RFC 2217 or WebSocket connections
+    ///
+    /// Each connection gets its own `handler` thread and joins the data port client
+    /// manager, so it receives UART data and counts towards `max_clients`.
+    fn spawn_bridge_server(
+        &self,
+        listener: TcpListener,
+        name: &'static str,
+        handler: ClientHandler,
+    ) -> Result<JoinHandle<()>> {
+        let client_manager = Arc::clone(&self.client_manager);
+        let context = self.context.clone();
+        let config = self.config.clone();
+        let shutdown = Arc::clone(&self.shutdown);
+
+        let handle = thread::Builder::new()
+            .name(format!("{}_server", name.to_ascii_lowercase().replace(' ', "")))
+            .stack_size(4096)
+            .spawn(move || {
+                let access_list = Arc::clone(&context.access_list);
+                Self::accept_until_stopped(&listener, &shutdown, &access_list, |stream| {
+                    let Some(stream) = Self::admit_client(stream, &client_manager, &config) else {
+                        return;
+                    };
+                    let client_manager = Arc::clone(&client_manager);
+                    let context = context.clone();
+                    let config = config.clone();
+                    let shutdown = Arc::clone(&shutdown);
+                    thread::spawn(move || {
+                        // 与数据端口客户端相同的优先级
+                        diagnostics::set_task_priority(23);
+                        if let Err(e) = handler(stream, client_manager, context, config, shutdown) {
+                            error!("Error handling {} client: {}", name, e);
+                        }
+                    });
+                });
+            })
+            .map_err(|e| Error::tcp_caused(format!("Failed to spawn {} server thread", name), e))?;
+
+        info!("{} port listening", name);
+        Ok(handle)
+    }
+
+    /// Spawn a thread that periodically evicts idle clients
+    fn spawn_idle_reaper(&self) -> Result<JoinHandle<()>> {
+        let managers = [
+            Arc::clone(&self.client_manager),
+            Arc::clone(&self.control_manager),
+        ];
+        let timeout = Duration::from_secs(self.config.idle_timeout_secs);
+        let check_interval = timeout.min(Duration::from_secs(1));
+        let shutdown = Arc::clone(&self.shutdown);
+
+        let handle = thread::Builder::new()
+            .name("idle_reaper".into())
+            .stack_size(4096)
+            .spawn(move || while !shutdown.load(Ordering::SeqCst) {
+                thread::sleep(check_interval);
+                for client_manager in &managers {
+                    match client_manager.evict_idle(timeout) {
+                        Ok(0) => {}
+                        Ok(n) => info!("Evicted {} idle client(s)", n),
+                        Err(e) => error!("Failed to evict idle clients: {}", e),
+                    }
+                }
+            })
+            .map_err(|e| Error::tcp_caused("Failed to spawn idle reaper thread", e))?;
+
+        info!(
+            "Idle clients will be disconnected after {} seconds",
+            self.config.idle_timeout_secs
+        );
+        Ok(handle)
+    }
+
+    /// Apply TCP_NODELAY and the buffer sizes of `config` to a client socket
+    ///
+    /// Failures are only logged: lwIP ignores or lacks some of the options
+    /// depending on its configuration.
+    fn tune_socket(stream: &TcpStream, config: &TcpServerConfig, peer_addr: &std::net::SocketAddr) {
+        if let Err(e) = stream.set_nodelay(config.tcp_nodelay) {
+            error!("Failed to set TCP_NODELAY for client {}: {}", peer_addr, e);
+            // Continue even if setting the option fails
+        }
+        // std没有提供缓冲区大小的设置，直接对lwIP套接字调用setsockopt
+        let fd = stream.as_raw_fd();
+        let buffers = [
+            (SocketOption::SendBuffer, config.send_buffer_size, "SO_SNDBUF"),
+            (SocketOption::ReceiveBuffer, config.recv_buffer_size, "SO_RCVBUF"),
+        ];
+        for (option, size, name) in buffers {
+            if size == 0 {
+                continue;
+            }
+            let size = size.min(i32::MAX as usize) as i32;
+            if let Err(e) = lwip::set_option(fd, option, size) {
+                warn!("Failed to set {} for client {}: {}", name, peer_addr, e);
+            }
+        }
+    }
+
+    /// Enable TCP keepalive on a client socket
+    ///
+    /// A peer that vanished without closing the connection (a phone going to sleep
+    /// or out of range) then fails the next read or write after about
+    /// idle + interval * count seconds, and the client is removed. Failures are only
+    /// logged: some ESP-IDF versions lack some of the options.
+    fn enable_keepalive(stream: &TcpStream, config: &TcpServerConfig, peer_addr: &std::net::SocketAddr) {
+        let Some(timeout_secs) = config.keepalive_timeout_secs() else {
+            return;
+        };
+        // std没有提供keepalive参数，直接对lwIP套接字调用setsockopt
+        let fd = stream.as_raw_fd();
+        let options = [
+            (SocketOption::KeepAlive, 1, "SO_KEEPALIVE"),
+            (SocketOption::KeepIdle, config.keepalive_idle_secs, "TCP_KEEPIDLE"),
+            (SocketOption::KeepInterval, config.keepalive_interval_secs, "TCP_KEEPINTVL"),
+            (SocketOption::KeepCount, config.keepalive_count, "TCP_KEEPCNT"),
+        ];
+        for (option, value, name) in options {
+            if let Err(e) = lwip::set_option(fd, option, value as i32) {
+                warn!("Failed to set {} for client {}: {}", name, peer_addr, e);
+            }
+        }
+        debug!(
+            "TCP keepalive for client {}: idle {} s, interval {} s, {} probes, dead after {} s",
+            peer_addr, config.keepalive_idle_secs, config.keepalive_interval_secs, config.keepalive_count, timeout_secs
+        );
+    }
+
+    /// Handle a client connection
+    ///
+    /// This method handles a client connection, reading data from the client and forwarding it to UART.
+    /// It also handles receiving data from UART and sending it to the client.
+    fn handle_client(
+        stream: TcpStream,
+        client_manager: Arc<TcpClientManager>,
+        context: CommandContext,
+        config: TcpServerConfig,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let _tracker = diagnostics::track_thread("data_client");
+        let mut session = ClientSession::open(stream, &client_manager, &context, &config)?;
+
+        // 初始化缓冲区
+        let mut buffer = vec![0; config.buffer_size];
+        debug!("Starting to read from client {}", session.peer_addr);
+
+        loop {
+            // 服务器停止时关闭连接
+            if shutdown.load(Ordering::SeqCst) {
+                session.close_on_shutdown(&client_manager)?;
+                break;
+            }
+
+            session.poll_timers(&context, &client_manager)?;
+
+            match session.read(&mut buffer, &context, &client_manager)? {
+                ReadOutcome::Data => {}
+                ReadOutcome::Idle => {
+                    // 使用更短的睡眠时间，减少延迟
+                    thread::sleep(Duration::from_millis(1));
+                    continue;
+                }
+                ReadOutcome::Closed => break,
+            }
+            thread::sleep(Duration::from_millis(2));
+        }
+
+        Ok(())
+    }
+
+    /// Serve all data port clients from the calling thread
+    ///
+    /// Client sockets are non-blocking and multiplexed with poll(): readable sockets
+    /// feed the command and UART path, and queued UART data is written in the same
+    /// loop, so no handler or writer threads are spawned. A slow command (AT+SCAN,
+    /// a blocking response write) delays all clients while it runs.
+    fn serve_polled(&self, listener: &TcpListener) {
+        if let Err(e) = listener.set_nonblocking(true) {
+            error!("Failed to set TCP listener to non-blocking mode: {}", e);
+        }
+        info!("Serving data port clients from one thread with poll()");
+
+        let mut sessions: Vec<ClientSession> = Vec::new();
+        let mut fds: Vec<lwip::PollFd> = Vec::new();
+        let mut buffer = vec![0; self.config.buffer_size];
+        let readable = lwip::POLLIN | lwip::POLLERR | lwip::POLLHUP;
+
+        while !self.shutdown.load(Ordering::SeqCst) {
+            watchdog::feed();
+            // fds[0]是监听套接字，其余与sessions一一对应
+            fds.clear();
+            fds.push(lwip::PollFd {
+                fd: listener.as_raw_fd(),
+                events: lwip::POLLIN,
+                revents: 0,
+            });
+            for session in &sessions {
+                let mut events = lwip::POLLIN;
+                if self.client_manager.queue_len(&session.peer_addr).unwrap_or(0) > 0 {
+                    events |= lwip::POLLOUT;
+                }
+                fds.push(lwip::PollFd { fd: session.fd, events, revents: 0 });
+            }
+
+            if let Err(e) = lwip::poll(&mut fds, POLL_TIMEOUT_MS) {
+                if e.kind() != ErrorKind::Interrupted {
+                    error!("poll() failed: {}", e);
+                    thread::sleep(Duration::from_millis(ACCEPT_POLL_MS));
+                }
+                continue;
+            }
+
+            // 读取就绪的客户端，每轮每个客户端最多读取几次，避免饿死其他客户端
+            let mut index = 0;
+            sessions.retain_mut(|session| {
+                index += 1;
+                if let Err(e) = session.poll_timers(&self.context, &self.client_manager) {
+                    error!("Error handling client {}: {}", session.peer_addr, e);
+                }
+                if fds[index].revents & readable == 0 {
+                    return true;
+                }
+                for _ in 0..POLL_READS_PER_CLIENT {
+                    match session.read(&mut buffer, &self.context, &self.client_manager) {
+                        Ok(ReadOutcome::Data) => {}
+                        Ok(ReadOutcome::Idle) => return true,
+                        Ok(ReadOutcome::Closed) => return false,
+                        Err(e) => {
+                            error!("Error handling client {}: {}", session.peer_addr, e);
+                            return false;
+                        }
+                    }
+                }
+                true
+            });
+
+            // 写出排队的UART数据（轮询模式下没有单独的写线程）
+            if let Err(e) = self.client_manager.write_queued() {
+                error!("Failed to write queued client data: {}", e);
+            }
+
+            if fds[0].revents & lwip::POLLIN != 0 {
+                self.accept_polled(listener, &mut sessions);
+            }
+        }
+
+        for session in &sessions {
+            if let Err(e) = session.close_on_shutdown(&self.client_manager) {
+                error!("Error closing client {}: {}", session.peer_addr, e);
+            }
+        }
+    }
+
+    /// Accept all pending data port connections into `sessions`
+    fn accept_polled(&self, listener: &TcpListener, sessions: &mut Vec<ClientSession>) {
+        loop {
+            let stream = match listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    error!("Connection failed: {}", e);
+                    return;
+                }
+            };
+            if !Self::is_permitted(&stream, &self.context.access_list) {
+                continue;
+            }
+            let Some(stream) = Self::admit_client(stream, &self.client_manager, &self.config) else {
+                continue;
+            };
+            match ClientSession::open(stream, &self.client_manager, &self.context, &self.config) {
+                Ok(session) => sessions.push(session),
+                Err(e) => error!("Error handling client: {}", e),
+            }
+        }
+    }
+
+    /// Forward framed data to UART or process a framed command line
+    fn dispatch_framed(
+        framed: Framed,
+        eol: &mut EolState,
+        context: &CommandContext,
+        client_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) {
+        match framed {
+            Framed::Data(data) => {
+                // 其他客户端独占UART时拒绝数据（仍然接收UART广播）
+                if let Some(holder) = client_manager.locked_by_other(peer_addr) {
+                    Self::reject_locked(stream_arc, &holder, peer_addr);
+                } else if !context.uart_manager.is_bridge_enabled() {
+                    Self::reject_paused(stream_arc, peer_addr);
+                } else {
+                    let (mode, _) = context.uart_manager.line_endings();
+                    if let Err(e) = context.send_to_uart(peer_addr, &eol.tcp_to_uart(mode, &data)) {
+                        error!("Error sending data to UART: {}", e);
+                    }
+                }
+            }
+            Framed::Command(line) => {
+                if let Err(e) =
+                    Self::process_command(&line, context, client_manager, stream_arc, peer_addr)
+                {
+                    error!("Error processing command from client {}: {}", peer_addr, e);
+                }
+            }
+        }
+    }
+
+    /// Tell a client its data was dropped because another client locked the UART
+    fn reject_locked(
+        stream_arc: &SharedStream,
+        holder: &std::net::SocketAddr,
+        peer_addr: &std::net::SocketAddr,
+    ) {
+        debug!("Dropping data from client {}, UART locked by {}", peer_addr, holder);
+        let response = format!("ERROR: UART locked by {}\r\n", holder);
+        let Ok(mut stream) = stream_arc.lock() else {
+            return;
+        };
+        if let Err(e) = stream.write_all(response.as_bytes()) {
+            debug!("Failed to send lock error to client {}: {}", peer_addr, e);
+        }
+    }
+
+    /// Tell a client its data was dropped because the bridge is paused (AT+BRIDGE=OFF)
+    fn reject_paused(stream_arc: &SharedStream, peer_addr: &std::net::SocketAddr) {
+        debug!("Dropping data from client {}, bridge paused", peer_addr);
+        let Ok(mut stream) = stream_arc.lock() else {
+            return;
+        };
+        if let Err(e) = stream.write_all(b"ERROR: bridge paused\r\n") {
+            debug!("Failed to send pause error to client {}: {}", peer_addr, e);
+        }
+    }
+
+    /// Notify a client that the server is stopping, then close and remove it
+    fn close_on_shutdown(
+        client_manager: &TcpClientManager,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+        conn_id: ConnectionId,
+    ) -> Result<()> {
+        info!("Closing client {}: server is stopping", peer_addr);
+        let _ = Self::send_response(stream_arc, "Server is shutting down\r\n", peer_addr);
+        if let Ok(stream) = stream_arc.lock() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        client_manager.remove_client(peer_addr, conn_id)
+    }
+
+}
+
+/// Run a TCP server with the given client manager and UART manager
+///
+/// This is a convenience function for backward compatibility
+pub fn run_tcp_server(
+    client_manager: Arc<TcpClientManager>,
     uart_manager: Arc<UartManager>,
 ) -> anyhow::Result<()> {
     // Create a TCP server with default configuration
     let config = crate::config::TcpServerConfig::default();
-    let server = TcpServer::new(config, client_manager, uart_manager);
+    let server = TcpServer::new(config, client_manager, uart_manager, None, None);
 
     // Run the server
     server.run()?;
 
     Ok(())
 }
+
+/// Integer options set on client sockets
+#[derive(Debug, Clone, Copy)]
+enum SocketOption {
+    SendBuffer,
+    ReceiveBuffer,
+    KeepAlive,
+    KeepIdle,
+    KeepInterval,
+    KeepCount,
+}
+
+/// lwIP socket calls that std does not provide
+#[cfg(target_os = "espidf")]
+mod lwip {
+    use std::io;
+    use std::os::fd::RawFd;
+
+    use super::SocketOption;
+
+    pub use esp_idf_sys::pollfd as PollFd;
+
+    pub const POLLIN: i16 = esp_idf_sys::POLLIN as i16;
+    pub const POLLOUT: i16 = esp_idf_sys::POLLOUT as i16;
+    pub const POLLERR: i16 = esp_idf_sys::POLLERR as i16;
+    pub const POLLHUP: i16 = esp_idf_sys::POLLHUP as i16;
+
+    /// Set an integer socket option with lwIP's setsockopt
+    pub fn set_option(fd: RawFd, option: SocketOption, value: i32) -> io::Result<()> {
+        let (level, option) = match option {
+            SocketOption::SendBuffer => (esp_idf_sys::SOL_SOCKET, esp_idf_sys::SO_SNDBUF),
+            SocketOption::ReceiveBuffer => (esp_idf_sys::SOL_SOCKET, esp_idf_sys::SO_RCVBUF),
+            SocketOption::KeepAlive => (esp_idf_sys::SOL_SOCKET, esp_idf_sys::SO_KEEPALIVE),
+            SocketOption::KeepIdle => (esp_idf_sys::IPPROTO_TCP, esp_idf_sys::TCP_KEEPIDLE),
+            SocketOption::KeepInterval => (esp_idf_sys::IPPROTO_TCP, esp_idf_sys::TCP_KEEPINTVL),
+            SocketOption::KeepCount => (esp_idf_sys::IPPROTO_TCP, esp_idf_sys::TCP_KEEPCNT),
+        };
+        let result = unsafe {
+            esp_idf_sys::lwip_setsockopt(
+                fd,
+                level as i32,
+                option as i32,
+                &value as *const i32 as *const core::ffi::c_void,
+                std::mem::size_of::<i32>() as esp_idf_sys::socklen_t,
+            )
+        };
+        if result == 0 {
+            Ok(())
+        } else {
+            Err(io::Error::last_os_error())
+        }
+    }
+
+    /// Wait up to `timeout_ms` until one of `fds` is ready, setting their `revents`
+    pub fn poll(fds: &mut [PollFd], timeout_ms: i32) -> io::Result<()> {
+        let ready = unsafe { esp_idf_sys::poll(fds.as_mut_ptr(), fds.len() as esp_idf_sys::nfds_t, timeout_ms) };
+        if ready < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+}
+
+/// Stand-ins for the lwIP socket calls in the host build
+///
+/// Options are not set, and `poll` waits out the timeout and reports every socket
+/// ready; the sockets are non-blocking, so a socket without data reads as idle.
+#[cfg(not(target_os = "espidf"))]
+mod lwip {
+    use std::io;
+    use std::os::fd::RawFd;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::SocketOption;
+
+    pub struct PollFd {
+        // 主机上的poll不查看套接字
+        #[allow(dead_code)]
+        pub fd: RawFd,
+        pub events: i16,
+        pub revents: i16,
+    }
+
+    pub const POLLIN: i16 = 0x1;
+    pub const POLLOUT: i16 = 0x4;
+    pub const POLLERR: i16 = 0x8;
+    pub const POLLHUP: i16 = 0x10;
+
+    pub fn set_option(_fd: RawFd, _option: SocketOption, _value: i32) -> io::Result<()> {
+        Ok(())
+    }
+
+    pub fn poll(fds: &mut [PollFd], timeout_ms: i32) -> io::Result<()> {
+        thread::sleep(Duration::from_millis(timeout_ms.max(0) as u64));
+        for fd in fds {
+            fd.revents = fd.events;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::commands::{self, ErrorCode};
+    use crate::config::UartConfig;
+    use crate::control_frame::{self, Frame, FrameError, OPCODE_COMMAND, OPCODE_FRAME_ERROR, STATUS_OK};
+    use crate::tcp_client_manager::mock::{addr, MockStream, Wire};
+    use crate::uart::mock;
+
+    /// Server with the default settings on a mock UART, without WiFi and storage
+    fn server() -> TcpServer {
+        let (uart, _line) = mock::manager(UartConfig::default());
+        TcpServer::new(TcpServerConfig::default(), Arc::new(TcpClientManager::new()), Arc::new(uart), None, None)
+    }
+
+    /// Connect mock client `n` to the control port
+    fn control_client(server: &TcpServer, n: u16) -> (SharedStream, Wire) {
+        let (stream, wire) = MockStream::new(addr(n));
+        server.control_manager.add_client(addr(n), Arc::clone(&stream)).unwrap();
+        let stream: SharedStream = stream;
+        (stream, wire)
+    }
+
+    /// Connect mock client `n` to the data port
+    fn data_client(server: &TcpServer, n: u16) -> (SharedStream, Wire) {
+        let (stream, wire) = MockStream::new(addr(n));
+        server.context.data_clients.add_client(addr(n), Arc::clone(&stream)).unwrap();
+        let stream: SharedStream = stream;
+        (stream, wire)
+    }
+
+    /// Run a text command line for client `n` of `manager` and return the reply
+    fn command(
+        server: &TcpServer,
+        manager: &Arc<TcpClientManager>,
+        client: &(SharedStream, Wire),
+        n: u16,
+        line: &str,
+    ) -> String {
+        TcpServer::process_command(line.as_bytes(), &server.context, manager, &client.0, &addr(n)).unwrap();
+        String::from_utf8(std::mem::take(&mut client.1.lock().unwrap().output)).unwrap()
+    }
+
+    /// Decode the control frames written to `wire`
+    fn written_frames(wire: &Wire) -> Vec<Frame> {
+        let mut decoded = Vec::new();
+        control_frame::FrameDecoder::new().decode(&wire.lock().unwrap().output, &mut decoded);
+        decoded.into_iter().map(|frame| frame.unwrap()).collect()
+    }
+
+    /// Feed `data` to a framer and return the pieces as text, commands marked with '>'
+    fn frame(framer: &mut CommandFramer, data: &[u8]) -> Vec<String> {
+        framer
+            .push(data)
+            .into_iter()
+            .map(|framed| match framed {
+                Framed::Command(line) => format!(">{}", String::from_utf8_lossy(&line)),
+                Framed::Data(data) => String::from_utf8_lossy(&data).into_owned(),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn commands_sent_in_one_segment_are_split() {
+        let _clock = time::lock_clock();
+        let mut framer = CommandFramer::new(Duration::from_millis(100));
+        assert_eq!(frame(&mut framer, b"AT+BAUD?\r\nAT+HELP\r\n"), [">AT+BAUD?", ">AT+HELP"]);
+        assert_eq!(frame(&mut framer, b"at + echo=1\nAT+STATS\r"), [">at + echo=1", ">AT+STATS"]);
+        // 上一个命令以CR结束，下一次读取开头的LF属于它
+        assert_eq!(frame(&mut framer, b"\nAT+UPTIME\r\n"), [">AT+UPTIME"]);
+    }
+
+    #[test]
+    fn sloppy_command_lines_map_to_the_canonical_command() {
+        let _clock = time::lock_clock();
+        let cases = [
+            ("at+baud=9600", "AT+BAUD=9600"),
+            ("AT + BAUD ? ", "AT+BAUD?"),
+            ("At+Baud = 9600", "AT+BAUD=9600"),
+            ("aT +baud?", "AT+BAUD?"),
+            ("at+Help", "AT+HELP"),
+            ("at+uart = 8 , n , 1", "AT+UART=8 , n , 1"),
+            ("AT+WIFISTA= MyNet,Secret ", "AT+WIFISTA=MyNet,Secret"),
+            ("at+banner=Hello World", "AT+BANNER=Hello World"),
+            ("at+reset=confirm", "AT+RESET=confirm"),
+            ("at+echo =1", "AT+ECHO=1"),
+            ("at+verbose=  0", "AT+VERBOSE=0"),
+            ("at+stats", "AT+STATS"),
+            ("at+! 3", "AT+! 3"),
+        ];
+        let registry = CommandRegistry::standard();
+        for terminator in ["\r", "\n", "\r\n"] {
+            for (input, canonical) in cases {
+                let mut framer = CommandFramer::new(Duration::from_millis(100));
+                let line = format!("{}{}", input, terminator);
+                let framed = frame(&mut framer, line.as_bytes());
+                assert_eq!(framed, [format!(">{}", input)], "{:?}", line);
+                let cmd_str = commands::normalize(input);
+                assert_eq!(cmd_str, canonical, "{:?}", input);
+                assert!(registry.find(&cmd_str).is_some(), "{:?}", input);
+            }
+        }
+    }
+
+    #[test]
+    fn data_around_commands_is_forwarded_in_order() {
+        let _clock = time::lock_clock();
+        let mut framer = CommandFramer::new(Duration::from_millis(100));
+        assert_eq!(
+            frame(&mut framer, b"AT+BAUD?\r\n\x01\x02AT+X\nhello\r\nAT+HELP\r\ntail"),
+            [">AT+BAUD?", "\x01\x02AT+X\nhello\r\n", ">AT+HELP", "tail"]
+        );
+        assert_eq!(frame(&mut framer, b"ATZ\r\nAT+ECHO=1\r\n"), ["ATZ\r\n", ">AT+ECHO=1"]);
+    }
+
+    #[test]
+    fn command_split_across_segments_is_held_back() {
+        let _clock = time::lock_clock();
+        let mut framer = CommandFramer::new(Duration::from_millis(100));
+        assert_eq!(frame(&mut framer, b"data\nAT+BA"), ["data\n"]);
+        assert_eq!(frame(&mut framer, b"UD?\r\n\xFF"), [">AT+BAUD?", "\u{FFFD}"]);
+    }
+
+    #[test]
+    fn held_line_is_released_after_the_timeout() {
+        let _clock = time::lock_clock();
+        let mut framer = CommandFramer::new(Duration::from_millis(100));
+        assert!(frame(&mut framer, b"AT").is_empty());
+        assert_eq!(framer.poll(), None);
+        time::advance(Duration::from_millis(100));
+        assert_eq!(framer.poll(), Some(Framed::Data(b"AT".to_vec())));
+
+        // 没有行尾的完整命令超时后仍然执行
+        assert!(frame(&mut framer, b"AT+BAUD?").is_empty());
+        time::advance(Duration::from_millis(100));
+        assert_eq!(framer.poll(), Some(Framed::Command(b"AT+BAUD?".to_vec())));
+    }
+
+    #[test]
+    fn mixed_segment_runs_the_commands_and_forwards_the_rest() {
+        let _clock = time::lock_clock();
+        let config = TcpServerConfig { control_port: None, ..TcpServerConfig::default() };
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let server = TcpServer::new(config, Arc::new(TcpClientManager::new()), Arc::new(uart), None, None);
+        let clients = Arc::clone(&server.context.data_clients);
+        let (stream, wire) = data_client(&server, 1);
+
+        let mut framer = CommandFramer::new(Duration::from_millis(100));
+        let mut eol = EolState::default();
+        for framed in framer.push(b"AT+BAUD?\r\nAT+ECHO=1\r\n\x00\xFF\r\n") {
+            TcpServer::dispatch_framed(framed, &mut eol, &server.context, &clients, &stream, &addr(1));
+        }
+
+        let replies = String::from_utf8(std::mem::take(&mut wire.lock().unwrap().output)).unwrap();
+        assert_eq!(replies, "+BAUD: Current baudrate: 115200\r\nOK\r\n+ECHO: Echo enabled\r\nOK\r\n");
+        assert_eq!(mock::queued_tx(&server.context.uart_manager), b"\x00\xFF\r\n");
+    }
+
+    #[test]
+    fn replies_are_sent_without_delay() {
+        let server = server();
+        let control = control_client(&server, 1);
+        let started = std::time::Instant::now();
+        for _ in 0..5 {
+            let reply = command(&server, &server.control_manager, &control, 1, "AT+BAUD?");
+            assert_eq!(reply, "+BAUD: Current baudrate: 115200\r\nOK\r\n");
+        }
+        // 以前每个回复之前都等待20 ms
+        assert!(started.elapsed() < Duration::from_millis(50), "{:?}", started.elapsed());
+    }
+
+    #[test]
+    fn every_reply_ends_with_ok_or_an_error_code() {
+        let server = server();
+        let control = control_client(&server, 1);
+        for line in ["AT+BAUD?", "AT+UART?", "AT+HELP", "AT+STATS", "AT+ECHO=1", "AT+NOPE", "AT+BAUD=abc"] {
+            let reply = command(&server, &server.control_manager, &control, 1, line);
+            let lines: Vec<_> = reply.lines().collect();
+            let (status, data) = lines.split_last().unwrap();
+            assert!(*status == "OK" || status.starts_with("ERROR "), "{}: {:?}", line, reply);
+            assert!(data.iter().all(|data| data.starts_with('+')), "{}: {:?}", line, reply);
+        }
+
+        assert_eq!(
+            command(&server, &server.control_manager, &control, 1, "AT+NOPE"),
+            "ERROR 1 Unknown command: AT+NOPE\r\n"
+        );
+        assert!(command(&server, &server.control_manager, &control, 1, "AT+BAUD=abc").starts_with("ERROR 2 "));
+        // 非UTF-8命令先回复错误，再作为错误返回
+        TcpServer::process_command(b"AT+BAUD=\xFF", &server.context, &server.control_manager, &control.0, &addr(1))
+            .unwrap_err();
+        assert_eq!(control.1.lock().unwrap().output, b"ERROR 4 Invalid command format (not UTF-8)\r\n");
+    }
+
+    #[test]
+    fn send_reports_where_the_payload_is_invalid() {
+        let server = server();
+        let control = control_client(&server, 1);
+        let manager = &server.control_manager;
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+SEND=48 6G"),
+            "ERROR 2 Invalid hex digit 'G' at position 5\r\n"
+        );
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+SEND=486"),
+            "ERROR 2 Incomplete hex byte at position 3 (use two digits per byte)\r\n"
+        );
+        assert!(mock::queued_tx(&server.context.uart_manager).is_empty());
+
+        assert!(command(&server, manager, &control, 1, "AT+SEND=48 69 00").ends_with("OK\r\n"));
+        assert_eq!(mock::queued_tx(&server.context.uart_manager), b"Hi\x00");
+    }
+
+    #[test]
+    fn verbose_clients_get_the_legacy_text() {
+        let server = server();
+        let control = control_client(&server, 1);
+        let manager = &server.control_manager;
+        assert_eq!(command(&server, manager, &control, 1, "AT+VERBOSE=1"), "OK: Verbose replies enabled\r\n");
+        assert_eq!(command(&server, manager, &control, 1, "AT+BAUD?"), "Current baudrate: 115200\r\n");
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+NOPE"),
+            "ERROR: Unknown command: AT+NOPE\r\nType AT+HELP for available commands\r\n"
+        );
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+VERBOSE=0"),
+            "+VERBOSE: Verbose replies disabled\r\nOK\r\n"
+        );
+        assert_eq!(command(&server, manager, &control, 1, "AT+BAUD?"), "+BAUD: Current baudrate: 115200\r\nOK\r\n");
+    }
+
+    #[test]
+    fn welcome_message_is_sent_without_delay() {
+        let config = TcpServerConfig {
+            control_port: None,
+            welcome_message: Some("Hello\\n".to_string()),
+            ..TcpServerConfig::default()
+        };
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let server = TcpServer::new(config, Arc::new(TcpClientManager::new()), Arc::new(uart), None, None);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let started = std::time::Instant::now();
+        let _session = ClientSession::open(stream, &server.client_manager, &server.context, &server.config).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(10), "{:?}", started.elapsed());
+
+        let mut welcome = [0u8; 7];
+        client.set_read_timeout(Some(Duration::from_secs(1))).unwrap();
+        client.read_exact(&mut welcome).unwrap();
+        assert_eq!(&welcome, b"Hello\r\n");
+    }
+
+    #[test]
+    fn binary_requests_cannot_upload() {
+        let server = server();
+        let (stream, wire) = control_client(&server, 1);
+        for line in ["AT+CFGIMPORT=10", "AT+OTA=1024,1A2B3C4D"] {
+            let frame = Frame { opcode: OPCODE_COMMAND, payload: line.as_bytes().to_vec() };
+            TcpServer::dispatch_binary(Ok(frame), &server.context, &server.control_manager, &stream, &addr(1));
+        }
+
+        let replies = written_frames(&wire);
+        assert_eq!(replies.len(), 2);
+        for reply in replies {
+            assert_eq!(reply.opcode, OPCODE_COMMAND | control_frame::RESPONSE_FLAG);
+            assert_eq!(reply.payload[0], ErrorCode::InvalidArgument as u8);
+            assert!(String::from_utf8_lossy(&reply.payload[1..]).contains("only accepted in text mode"));
+        }
+    }
+
+    #[test]
+    fn invalid_frames_get_a_frame_error() {
+        let server = server();
+        let (stream, wire) = control_client(&server, 1);
+        TcpServer::dispatch_binary(Err(FrameError::Checksum), &server.context, &server.control_manager, &stream, &addr(1));
+
+        let replies = written_frames(&wire);
+        assert_eq!(replies[0].opcode, OPCODE_FRAME_ERROR | control_frame::RESPONSE_FLAG);
+        assert_eq!(replies[0].payload[0], ErrorCode::InvalidFormat as u8);
+    }
+
+    #[test]
+    fn binary_query_replies_with_status_ok() {
+        let server = server();
+        let (stream, wire) = control_client(&server, 1);
+        let frame = Frame { opcode: OPCODE_COMMAND, payload: b"AT+BAUD?".to_vec() };
+        TcpServer::dispatch_binary(Ok(frame), &server.context, &server.control_manager, &stream, &addr(1));
+
+        let replies = written_frames(&wire);
+        assert_eq!(replies[0].payload[0], STATUS_OK);
+        assert!(String::from_utf8_lossy(&replies[0].payload[1..]).contains("115200"));
+    }
+
+    #[test]
+    fn client_settings_are_rejected_on_the_control_port() {
+        // 默认配置启用控制端口
+        let server = server();
+        let control = control_client(&server, 1);
+        for (line, setting) in [("AT+ECHO=1", "Echo"), ("AT+RAW=1", "Raw mode"), ("AT+MARKGAPS=ON", "Gap markers")] {
+            let reply = command(&server, &server.control_manager, &control, 1, line);
+            assert!(reply.starts_with("ERROR"), "{}: {}", line, reply);
+            assert!(reply.contains(&format!("{} only applies to data port clients", setting)), "{}", reply);
+        }
+        assert!(!server.control_manager.is_echo(&addr(1)));
+        assert!(!server.control_manager.is_raw_mode(&addr(1)));
+        assert!(!server.control_manager.mark_gaps(&addr(1)).unwrap());
+    }
+
+    #[test]
+    fn data_clients_change_their_own_settings() {
+        let config = TcpServerConfig { control_port: None, ..TcpServerConfig::default() };
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let server = TcpServer::new(config, Arc::new(TcpClientManager::new()), Arc::new(uart), None, None);
+        let clients = Arc::clone(&server.context.data_clients);
+        let data = data_client(&server, 1);
+        assert_eq!(command(&server, &clients, &data, 1, "AT+ECHO=1"), "+ECHO: Echo enabled\r\nOK\r\n");
+        assert_eq!(command(&server, &clients, &data, 1, "AT+MARKGAPS=ON"), "+MARKGAPS: Gap markers enabled\r\nOK\r\n");
+        assert!(clients.is_echo(&addr(1)));
+        assert!(clients.mark_gaps(&addr(1)).unwrap());
+    }
+
+    #[test]
+    fn control_port_locks_the_uart_for_a_data_client() {
+        // 默认配置下数据端口透明，只能从控制端口加锁
+        let server = server();
+        let control = control_client(&server, 1);
+        let _data = data_client(&server, 2);
+        let data_clients = &server.context.data_clients;
+
+        let reply = command(&server, &server.control_manager, &control, 1, "AT+LOCK");
+        assert!(reply.contains("Name the data port client"), "{}", reply);
+        let reply = command(&server, &server.control_manager, &control, 1, &format!("AT+LOCK={}", addr(3)));
+        assert!(reply.contains(&format!("No such client: {}", addr(3))), "{}", reply);
+        assert_eq!(data_clients.exclusive_holder(), None);
+
+        let reply = command(&server, &server.control_manager, &control, 1, &format!("AT+LOCK={}", addr(2)));
+        assert!(reply.ends_with("OK\r\n"), "{}", reply);
+        assert_eq!(data_clients.exclusive_holder(), Some(addr(2)));
+        assert_eq!(data_clients.locked_by_other(&addr(4)), Some(addr(2)));
+        let reply = command(&server, &server.control_manager, &control, 1, "AT+LOCK?");
+        assert!(reply.contains(&format!("UART locked by {}", addr(2))), "{}", reply);
+
+        let reply = command(&server, &server.control_manager, &control, 1, "AT+UNLOCK");
+        assert!(reply.contains(&format!("was locked by {}", addr(2))), "{}", reply);
+        assert_eq!(data_clients.exclusive_holder(), None);
+    }
+
+    #[test]
+    fn lock_taken_for_a_client_ends_when_it_disconnects() {
+        let server = server();
+        let control = control_client(&server, 1);
+        let _data = data_client(&server, 2);
+        command(&server, &server.control_manager, &control, 1, &format!("AT+LOCK={}", addr(2)));
+
+        assert!(server.context.data_clients.disconnect(&addr(2)).unwrap());
+        assert_eq!(server.context.data_clients.exclusive_holder(), None);
+    }
+
+    #[test]
+    fn data_clients_cannot_lock_for_others() {
+        let config = TcpServerConfig { control_port: None, ..TcpServerConfig::default() };
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let server = TcpServer::new(config, Arc::new(TcpClientManager::new()), Arc::new(uart), None, None);
+        let clients = Arc::clone(&server.context.data_clients);
+        let data = data_client(&server, 1);
+        let _other = data_client(&server, 2);
+
+        let reply = command(&server, &clients, &data, 1, &format!("AT+LOCK={}", addr(2)));
+        assert!(reply.contains("only lock the UART to themselves"), "{}", reply);
+        assert!(command(&server, &clients, &data, 1, "AT+LOCK").ends_with("OK\r\n"));
+        assert_eq!(clients.exclusive_holder(), Some(addr(1)));
+    }
+
+    #[test]
+    fn secret_commands_are_left_out_of_the_history() {
+        let server = server();
+        let control = control_client(&server, 1);
+        for line in [
+            "AT+BAUD?",
+            "AT+WIFISTA=Workshop,station-pass",
+            "at+ap = Bridge,access-pass,6,0",
+            "AT+APSEC=WPA2,access-pass",
+            "AT+VERIFY=AT+WIFISTA=Workshop,station-pass",
+            "AT+WIFISTA?",
+        ] {
+            command(&server, &server.control_manager, &control, 1, line);
+        }
+
+        let history = server.control_manager.command_history(&addr(1)).unwrap();
+        assert_eq!(history, ["AT+BAUD?", "AT+WIFISTA?"]);
+        let reply = command(&server, &server.control_manager, &control, 1, "AT+HISTORY?");
+        assert_eq!(reply, "+HISTORY: 1  AT+BAUD?\r\n+HISTORY: 2  AT+WIFISTA?\r\nOK\r\n");
+    }
+
+    #[test]
+    fn replayed_commands_are_not_recorded_again() {
+        let server = server();
+        let control = control_client(&server, 1);
+        let manager = &server.control_manager;
+        command(&server, manager, &control, 1, "AT+UART?");
+        command(&server, manager, &control, 1, "AT+BAUD?");
+
+        assert_eq!(command(&server, manager, &control, 1, "AT+! 2"), "+BAUD: Current baudrate: 115200\r\nOK\r\n");
+        assert!(command(&server, manager, &control, 1, "AT+! 1").ends_with("OK\r\n"));
+        // 重放不改变历史，编号保持不变
+        assert_eq!(manager.command_history(&addr(1)).unwrap(), ["AT+UART?", "AT+BAUD?"]);
+        assert_eq!(command(&server, manager, &control, 1, "AT+! 2"), "+BAUD: Current baudrate: 115200\r\nOK\r\n");
+        assert!(command(&server, manager, &control, 1, "AT+! 3").starts_with("ERROR "));
+    }
+
+    #[test]
+    fn runtime_changes_race_a_live_uart_stream() {
+        const COMMAND_THREADS: u16 = 4;
+        const CHURN_THREADS: u16 = 2;
+        const ROUNDS: usize = 50;
+        const CHUNKS: u32 = 2000;
+
+        let _clock = time::lock_clock();
+        let (uart, line) = mock::manager(UartConfig::default());
+        line.lock().unwrap().loopback = true;
+        let uart = Arc::new(uart);
+        let server = Arc::new(TcpServer::new(
+            TcpServerConfig::default(),
+            Arc::new(TcpClientManager::new()),
+            Arc::clone(&uart),
+            None,
+            None,
+        ));
+        // 全程在线的数据客户端，收到的数据应与发出的完全一致
+        let observer = data_client(&server, 0);
+        let (finished_tx, finished_rx) = std::sync::mpsc::channel();
+
+        // 经过UART回环的数据流：入队、写出、读回并广播
+        let stream = {
+            let (server, uart) = (Arc::clone(&server), Arc::clone(&uart));
+            std::thread::spawn(move || {
+                for chunk in 0..CHUNKS {
+                    uart.send_data(format!("{:05}\n", chunk).as_bytes()).unwrap();
+                    mock::pump(&uart, &server.context.data_clients).unwrap();
+                    server.context.data_clients.write_queued().unwrap();
+                    std::thread::yield_now();
+                }
+            })
+        };
+
+        // 控制客户端反复修改和查询波特率、欢迎横幅和统计
+        let commanders: Vec<_> = (1..=COMMAND_THREADS)
+            .map(|n| {
+                let server = Arc::clone(&server);
+                std::thread::spawn(move || {
+                    let control = control_client(&server, n);
+                    for round in 0..ROUNDS {
+                        let baudrate = if (round + usize::from(n)) % 2 == 0 { 9600 } else { 115_200 };
+                        for line in [
+                            format!("AT+BAUD={}", baudrate),
+                            "AT+BAUD?".to_string(),
+                            "AT+UART?".to_string(),
+                            format!("AT+BANNER=Client {} round {}", n, round),
+                            "AT+BANNER?".to_string(),
+                            "AT+STATS?".to_string(),
+                        ] {
+                            let reply = command(&server, &server.control_manager, &control, n, &line);
+                            assert!(reply.ends_with("OK\r\n"), "{}: {:?}", line, reply);
+                        }
+                        assert!([9600, 115_200].contains(&server.context.uart_manager.get_baudrate()));
+                        server.context.uart_manager.get_format();
+                        server.context.uart_manager.stats();
+                    }
+                })
+            })
+            .collect();
+
+        // 数据客户端不断连接和断开
+        let churners: Vec<_> = (1..=CHURN_THREADS)
+            .map(|t| {
+                let server = Arc::clone(&server);
+                std::thread::spawn(move || {
+                    for i in 0..ROUNDS as u16 * 4 {
+                        let n = 100 + t * 1000 + i;
+                        let (stream, _wire) = MockStream::new(addr(n));
+                        let id = server.context.data_clients.add_client(addr(n), stream).unwrap();
+                        std::thread::yield_now();
+                        server.context.data_clients.remove_client(&addr(n), id).unwrap();
+                    }
+                })
+            })
+            .collect();
+
+        let watchdog = std::thread::spawn(move || {
+            for thread in commanders.into_iter().chain(churners) {
+                thread.join().unwrap();
+            }
+            stream.join().unwrap();
+            finished_tx.send(()).unwrap();
+        });
+        finished_rx
+            .recv_timeout(Duration::from_secs(60))
+            .expect("runtime changes deadlocked against the UART stream");
+        watchdog.join().unwrap();
+
+        // 读回重新配置期间暂停读取时留下的数据
+        while !line.lock().unwrap().rx.is_empty() {
+            mock::pump(&uart, &server.context.data_clients).unwrap();
+        }
+        server.context.data_clients.write_queued().unwrap();
+
+        let expected: String = (0..CHUNKS).map(|chunk| format!("{:05}\n", chunk)).collect();
+        assert_eq!(String::from_utf8(line.lock().unwrap().tx.clone()).unwrap(), expected);
+        assert_eq!(String::from_utf8(observer.1.lock().unwrap().output.clone()).unwrap(), expected);
+        assert_eq!(uart.get_baudrate(), line.lock().unwrap().baudrate);
+        assert_eq!(server.context.data_clients.client_count().unwrap(), 1);
+    }
+
+    #[test]
+    fn sockopt_switches_nodelay_of_the_own_connection() {
+        let server = server();
+        let manager = &server.control_manager;
+        let control = control_client(&server, 1);
+        let other = control_client(&server, 2);
+
+        assert_eq!(command(&server, manager, &control, 1, "AT+SOCKOPT=NODELAY,1"), "+SOCKOPT: NODELAY 1\r\nOK\r\n");
+        assert!(control.1.lock().unwrap().nodelay);
+        assert!(!other.1.lock().unwrap().nodelay);
+        assert_eq!(command(&server, manager, &control, 1, "AT+SOCKOPT?"), "+SOCKOPT: NODELAY: 1\r\nOK\r\n");
+        assert_eq!(command(&server, manager, &other, 2, "AT+SOCKOPT?"), "+SOCKOPT: NODELAY: 0\r\nOK\r\n");
+
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+SOCKOPT=NODELAY,2"),
+            "ERROR 2 Invalid value: 2 (use 1 or 0)\r\n"
+        );
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+SOCKOPT=NAGLE,0"),
+            "ERROR 2 Expected AT+SOCKOPT=NODELAY,<1|0>\r\n"
+        );
+        assert!(control.1.lock().unwrap().nodelay);
+    }
+
+    #[test]
+    fn uart_command_replies_go_back_to_the_uart() {
+        let server = server();
+        let data = data_client(&server, 1);
+
+        TcpServer::run_uart_command(&server.context, "AT+BAUD?");
+        assert_eq!(mock::queued_tx(&server.context.uart_manager), b"+BAUD: Current baudrate: 115200\r\nOK\r\n");
+        assert!(data.1.lock().unwrap().output.is_empty());
+
+        TcpServer::run_uart_command(&server.context, "AT+NOPE");
+        assert!(mock::queued_tx(&server.context.uart_manager).starts_with(b"ERROR"));
+    }
+
+    #[test]
+    fn uart_error_counters_are_shown_and_reset() {
+        let server = server();
+        let manager = &server.control_manager;
+        let control = control_client(&server, 1);
+
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+UARTERR?"),
+            "+UARTERR: Framing errors: 0\r\n+UARTERR: Parity errors: 0\r\n\
+             +UARTERR: Overflows: 0\r\n+UARTERR: Breaks: 0\r\nOK\r\n"
+        );
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+UARTERR=RESET"),
+            "+UARTERR: UART error counters reset\r\nOK\r\n"
+        );
+        assert!(command(&server, manager, &control, 1, "AT+UARTERR=ALL").starts_with("ERROR"));
+    }
+
+    #[test]
+    fn verified_changes_are_not_applied() {
+        let (uart, line) = mock::manager(UartConfig::default());
+        let (storage, flash) = crate::storage::mock::storage();
+        let storage = Arc::new(Mutex::new(storage));
+        let server = TcpServer::new(
+            TcpServerConfig::default(),
+            Arc::new(TcpClientManager::new()),
+            Arc::new(uart),
+            None,
+            Some(Arc::clone(&storage)),
+        );
+        let control = control_client(&server, 1);
+        let manager = &server.control_manager;
+        let baudrate = line.lock().unwrap().baudrate;
+
+        let changes = ["AT+BAUD=9600", "AT+PORT=9000", "AT+WIFISTA=Workshop,station-pass", "AT+AP=Lab,access-pass,6,0"];
+        for change in changes {
+            let reply = command(&server, manager, &control, 1, &format!("AT+VERIFY={}", change));
+            assert!(reply.starts_with("+VERIFY: ") && reply.ends_with("\r\nOK\r\n"), "{}: {:?}", change, reply);
+        }
+        assert_eq!(server.context.uart_manager.get_baudrate(), 115_200);
+        assert_eq!(line.lock().unwrap().baudrate, baudrate);
+        assert_eq!(storage.lock().unwrap().read_tcp_port(), None);
+        assert!(flash.lock().unwrap().values.is_empty());
+
+        // 与真正执行命令时的错误相同
+        for change in ["AT+BAUD=12", "AT+PORT=0", "AT+WIFISTA=,pass", "AT+AP=Lab,access-pass,14,0"] {
+            let verified = command(&server, manager, &control, 1, &format!("AT+VERIFY={}", change));
+            assert!(verified.starts_with("ERROR 2 "), "{}: {:?}", change, verified);
+            assert_eq!(verified, command(&server, manager, &control, 1, change));
+        }
+        assert!(flash.lock().unwrap().values.is_empty());
+    }
+
+    #[test]
+    fn queries_refuse_arguments() {
+        let server = server();
+        let control = control_client(&server, 1);
+        let manager = &server.control_manager;
+        for line in ["AT+UPTIME", "AT+UPTIME?", "AT+HELP", "AT+CLIENTS?"] {
+            assert!(command(&server, manager, &control, 1, line).ends_with("OK\r\n"), "{}", line);
+        }
+        assert_eq!(
+            command(&server, manager, &control, 1, "AT+UPTIME=junk"),
+            "ERROR 2 AT+UPTIME takes no arguments\r\n"
+        );
+        assert_eq!(command(&server, manager, &control, 1, "AT+SCAN=x"), "ERROR 2 AT+SCAN takes no arguments\r\n");
+    }
+
+    #[test]
+    fn registration_removes_the_client_on_every_path() {
+        let server = server();
+        let clients = &server.context.data_clients;
+        let (stream, _wire) = MockStream::new(addr(1));
+        let conn_id = clients.add_client(addr(1), stream).unwrap();
+
+        let handler = || -> Result<()> {
+            let _registration = Registration { client_manager: clients, peer_addr: addr(1), conn_id };
+            Err(Error::tcp("Handler failed"))
+        };
+        handler().unwrap_err();
+        assert_eq!(clients.client_count().unwrap(), 0);
+
+        // 同一地址的新连接不会被旧连接的登记移除
+        let (stream, _wire) = MockStream::new(addr(1));
+        let successor = clients.add_client(addr(1), stream).unwrap();
+        drop(Registration { client_manager: clients, peer_addr: addr(1), conn_id });
+        assert!(clients.is_client_connected(&addr(1)));
+        drop(Registration { client_manager: clients, peer_addr: addr(1), conn_id: successor });
+        assert!(!clients.is_client_connected(&addr(1)));
+    }
+
+    /// Wait up to two seconds for `condition`, which the server thread makes true
+    fn wait_until(mut condition: impl FnMut() -> bool) -> bool {
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while !condition() {
+            if std::time::Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn polled_server_serves_more_than_eight_clients() {
+        const CLIENTS: usize = 9;
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let config = TcpServerConfig {
+            io_model: IoModel::Poll,
+            max_clients: CLIENTS,
+            transparent: true,
+            ..TcpServerConfig::default()
+        };
+        let server = TcpServer::new(config, Arc::new(TcpClientManager::new()), Arc::new(uart), None, None);
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let local_addr = listener.local_addr().unwrap();
+
+        thread::scope(|scope| {
+            let serving = scope.spawn(|| server.serve_polled(&listener));
+            let mut clients: Vec<TcpStream> =
+                (0..CLIENTS).map(|_| TcpStream::connect(local_addr).unwrap()).collect();
+            assert!(wait_until(|| server.client_manager.client_count().unwrap() == CLIENTS));
+
+            // 每个客户端的数据都由同一个线程转发到UART
+            for (n, client) in clients.iter_mut().enumerate() {
+                write!(client, "<{}>", n).unwrap();
+            }
+            let mut forwarded = Vec::new();
+            assert!(wait_until(|| {
+                forwarded.extend(mock::queued_tx(&server.context.uart_manager));
+                forwarded.len() == CLIENTS * 3
+            }));
+            let forwarded = String::from_utf8(forwarded).unwrap();
+            assert!((0..CLIENTS).all(|n| forwarded.contains(&format!("<{}>", n))), "{}", forwarded);
+
+            // 广播的UART数据由同一个循环写给所有客户端
+            assert_eq!(server.client_manager.broadcast(b"uart", b"uart").unwrap(), CLIENTS);
+            for client in &mut clients {
+                client.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+                let mut received = [0u8; 4];
+                client.read_exact(&mut received).unwrap();
+                assert_eq!(&received, b"uart");
+            }
+
+            server.stop();
+            serving.join().unwrap();
+        });
+        assert_eq!(server.client_manager.client_count().unwrap(), 0);
+    }
+
+    #[test]
+    fn escape_needs_a_pause_on_both_sides() {
+        let _clock = time::lock_clock();
+        let guard = Duration::from_secs(1);
+        let mut detector = EscapeDetector::new(guard);
+
+        time::advance(guard);
+        assert_eq!(detector.on_data(b"++"), EscapeCheck::Hold);
+        assert_eq!(detector.on_data(b"+"), EscapeCheck::Hold);
+        assert_eq!(detector.poll(), EscapePoll::Idle);
+        time::advance(guard);
+        assert_eq!(detector.poll(), EscapePoll::Escaped);
+        assert_eq!(detector.poll(), EscapePoll::Idle);
+    }
+
+    #[test]
+    fn escape_inside_a_data_stream_is_forwarded() {
+        let _clock = time::lock_clock();
+        let guard = Duration::from_secs(1);
+        let mut detector = EscapeDetector::new(guard);
+
+        // 没有前置静默期的+++只是普通数据
+        assert_eq!(detector.on_data(b"+++"), EscapeCheck::Forward { held: 0 });
+
+        time::advance(guard);
+        assert_eq!(detector.on_data(b"++"), EscapeCheck::Hold);
+        assert_eq!(detector.on_data(b"x"), EscapeCheck::Forward { held: 2 });
+
+        // 不完整的序列在保护时间后原样释放
+        time::advance(guard);
+        assert_eq!(detector.on_data(b"+"), EscapeCheck::Hold);
+        time::advance(guard);
+        assert_eq!(detector.poll(), EscapePoll::Release(1));
+    }
+
+    #[test]
+    fn accept_loop_returns_once_stopped() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let shutdown = Arc::new(AtomicBool::new(false));
+
+        let acceptor = {
+            let shutdown = Arc::clone(&shutdown);
+            thread::spawn(move || {
+                let mut accepted = Vec::new();
+                let access_list = Mutex::new(AccessList::new(Vec::new()));
+                TcpServer::accept_until_stopped(&listener, &shutdown, &access_list, |stream| accepted.push(stream));
+                accepted
+            })
+        };
+
+        let _first = TcpStream::connect(addr).unwrap();
+        let _second = TcpStream::connect(addr).unwrap();
+        thread::sleep(Duration::from_millis(3 * ACCEPT_POLL_MS));
+        shutdown.store(true, Ordering::SeqCst);
+
+        let accepted = acceptor.join().unwrap();
+        assert_eq!(accepted.len(), 2);
+    }
+
+    #[test]
+    fn command_split_across_reads_is_assembled() {
+        let mut framer = CommandFramer::new(Duration::from_secs(2));
+        assert_eq!(framer.push(b"A"), []);
+        assert_eq!(framer.push(b"T+BAU"), []);
+        assert_eq!(framer.push(b"D?\r"), [Framed::Command(b"AT+BAUD?".to_vec())]);
+        // CR之后单独到达的LF被丢弃
+        assert_eq!(framer.push(b"\nhello"), [Framed::Data(b"hello".to_vec())]);
+    }
+
+    #[test]
+    fn data_after_a_command_is_forwarded() {
+        let mut framer = CommandFramer::new(Duration::from_secs(2));
+        assert_eq!(
+            framer.push(b"AT+UPTIME\r\nrest"),
+            [Framed::Command(b"AT+UPTIME".to_vec()), Framed::Data(b"rest".to_vec())]
+        );
+    }
+
+    #[test]
+    fn held_prefix_is_released_when_it_is_not_a_command() {
+        let mut framer = CommandFramer::new(Duration::from_secs(2));
+        assert_eq!(framer.push(b"AT"), []);
+        assert_eq!(framer.push(b"Z\r\n"), [Framed::Data(b"ATZ\r\n".to_vec())]);
+        assert_eq!(framer.push(b"binary"), [Framed::Data(b"binary".to_vec())]);
+
+        let mut long_line = b"AT+".to_vec();
+        long_line.resize(MAX_COMMAND_LINE_LEN + 1, b'x');
+        assert_eq!(framer.push(&long_line), [Framed::Data(long_line.clone())]);
+    }
+
+    #[test]
+    fn unterminated_line_is_released_after_the_timeout() {
+        let _clock = time::lock_clock();
+        let mut framer = CommandFramer::new(Duration::from_secs(2));
+        assert_eq!(framer.push(b"AT+HELP"), []);
+        assert_eq!(framer.poll(), None);
+        time::advance(Duration::from_secs(2));
+        assert_eq!(framer.poll(), Some(Framed::Command(b"AT+HELP".to_vec())));
+
+        assert_eq!(framer.push(b"A"), []);
+        time::advance(Duration::from_secs(2));
+        assert_eq!(framer.poll(), Some(Framed::Data(b"A".to_vec())));
+    }
+
+    #[test]
+    fn every_command_line_in_a_read_is_processed() {
+        let mut framer = CommandFramer::new(Duration::from_secs(2));
+        assert_eq!(
+            framer.push(b"AT+BAUD?\r\nAT+UPTIME\nping\r\nAT+ECHO=1\rAT+HE"),
+            [
+                Framed::Command(b"AT+BAUD?".to_vec()),
+                Framed::Command(b"AT+UPTIME".to_vec()),
+                Framed::Data(b"ping\r\n".to_vec()),
+                Framed::Command(b"AT+ECHO=1".to_vec()),
+            ]
+        );
+        assert_eq!(framer.push(b"LP\r\n"), [Framed::Command(b"AT+HELP".to_vec())]);
+    }
+
+    #[test]
+    fn command_text_inside_a_data_line_is_not_a_command() {
+        let mut framer = CommandFramer::new(Duration::from_secs(2));
+        assert_eq!(framer.push(b"say AT+RESET\r\n"), [Framed::Data(b"say AT+RESET\r\n".to_vec())]);
+    }
+
+    #[test]
+    fn long_response_is_written_completely() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, addr) = listener.accept().unwrap();
+        stream.set_nonblocking(true).unwrap();
+        let stream_arc: SharedStream = Arc::new(Mutex::new(stream));
+
+        // 响应大于套接字发送缓冲区，非阻塞写入会被截断
+        let response = "0123456789abcdef".repeat(64 * 1024);
+        let reader = thread::spawn(move || {
+            let mut received = Vec::new();
+            peer.read_to_end(&mut received).unwrap();
+            received.len()
+        });
+        TcpServer::send_response(&stream_arc, &response, &addr).unwrap();
+
+        // 发送后恢复非阻塞模式
+        let mut stream = stream_arc.lock().unwrap();
+        assert!(matches!(
+            stream.read(&mut [0u8; 1]),
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock
+        ));
+        stream.shutdown(Shutdown::Both).unwrap();
+        assert_eq!(reader.join().unwrap(), response.len());
+    }
+
+    #[test]
+    fn bridge_sections_name_the_port_the_report_was_asked_on() {
+        assert_eq!(TcpServer::bridge_header("main", 8080, false), "Bridge: main (port 8080)\r\n");
+        assert_eq!(TcpServer::bridge_header("telemetry", 8081, true), "Bridge: telemetry (port 8081, this port)\r\n");
+    }
+}
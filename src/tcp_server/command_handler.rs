@@ -0,0 +1,817 @@
+//! Command handling of the TCP server
+//!
+//! Runs parsed commands for control, data, RFC 2217 and WebSocket clients and writes
+//! their replies, including the AT+OTA and AT+CFGIMPORT upload transfers.
+
+use log::{debug, error, info, warn};
+use std::fmt;
+use std::io::{Read, Write};
+use std::net::{Ipv4Addr, Shutdown, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::acl::AclRule;
+use crate::boot_info;
+use crate::commands::{self, CommandRequest, ErrorCode, Response};
+use crate::config::{ApBandwidth, SerialFormat, TcpToUartEol, UartToTcpEol, WiFiAuth};
+use crate::control_frame::STATUS_OK;
+use crate::device_name;
+use crate::diagnostics;
+use crate::gpio_control::GpioAction;
+use crate::error::{Error, Result};
+use crate::log_level;
+use crate::ota::{self, OtaRequest};
+use crate::self_test::SelfTest;
+use crate::tcp_client_manager::{ClientStream, SharedStream, TcpClientManager};
+use crate::time;
+use crate::uart::{self, UartManager};
+use crate::wifi::WiFiManager;
+
+use super::{CommandContext, TcpServer, RESPONSE_WRITE_TIMEOUT_MS};
+
+/// Time in milliseconds a client gets to receive the reply before the device restarts
+const RESTART_GRACE_MS: u64 = 500;
+
+/// Time in milliseconds an AT+CFGIMPORT= upload may pause before it is given up
+const UPLOAD_STALL_TIMEOUT_MS: u64 = 10_000;
+
+/// Address commands received from the UART are logged under; no TCP peer has it
+const UART_COMMAND_ADDR: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 0));
+
+/// Change requested by a configuration command
+///
+/// Plans are produced by the command handlers without touching hardware or storage,
+/// so a command can be validated (AT+VERIFY=) before it is executed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CommandPlan {
+    /// Change the UART baud rate
+    SetBaudrate(u32),
+    /// Change all serial parameters of the UART
+    SetSerialParams {
+        /// New baud rate
+        baudrate: u32,
+        /// New data bits, parity and stop bits
+        format: SerialFormat,
+    },
+    /// Change the gap and size limit of UART receive framing (gap 0 disables it)
+    SetFrameGap {
+        /// Quiet time in milliseconds that ends a frame
+        gap_ms: u64,
+        /// Largest frame in bytes
+        max_bytes: usize,
+    },
+    /// Change and persist the UART frame delimiter (None disables it)
+    SetFrameDelimiter(Option<Vec<u8>>),
+    /// Change the size and latency limits of UART receive batching (None disables it)
+    SetBatching(Option<(usize, u64)>),
+    /// Enable or disable gap markers for the requesting client
+    SetMarkGaps(bool),
+    /// Switch the requesting client into or out of raw transparent mode
+    SetRawMode(bool),
+    /// Enable or disable local echo for the requesting client
+    SetEcho(bool),
+    /// Switch the requesting client between verbose replies and the OK/ERROR format
+    SetVerbose(bool),
+    /// Switch the requesting client between hex dumps (true) and raw data
+    SetHexTap(bool),
+    /// Subscribe the requesting client to the device log or unsubscribe it
+    SetLogStream(bool),
+    /// Change the default log level (target None) or the level of one target
+    SetLogLevel {
+        /// New level
+        level: log::LevelFilter,
+        /// Rust module path or ESP-IDF tag, None for the default level
+        target: Option<String>,
+    },
+    /// Persist the log levels currently in effect
+    SaveLogLevels,
+    /// Reset the traffic statistics counters
+    ResetStats,
+    /// Reset the UART receive error counters
+    ResetUartErrors,
+    /// Clear the boot counter and the stored panic message
+    ResetBootInfo,
+    /// Forcibly disconnect a data port client
+    Kick(std::net::SocketAddr),
+    /// Pause (false) or resume (true) forwarding between UART and the network
+    SetBridge(bool),
+    /// Persist the size of the UART history replayed to new clients (0 disables it)
+    SetReplay(usize),
+    /// Change the line ending translation of command mode clients
+    SetLineEndings {
+        tcp_to_uart: TcpToUartEol,
+        uart_to_tcp: UartToTcpEol,
+    },
+    /// Drive or pulse a named GPIO
+    SetGpio {
+        name: String,
+        action: GpioAction,
+    },
+    /// Reset the target with the esptool sequence, into its bootloader if set
+    ResetTarget { bootloader: bool },
+    /// Write bytes to UART (AT+SEND)
+    SendBytes(Vec<u8>),
+    /// Write a line of text to UART followed by the configured line ending (AT+SENDLN)
+    SendLine(String),
+    /// Run a self test (AT+TEST)
+    RunSelfTest(SelfTest),
+    /// Give the named data client (None: the requesting client) exclusive UART TX rights
+    LockUart(Option<std::net::SocketAddr>),
+    /// Release exclusive UART TX rights (on the control port, whoever holds them)
+    UnlockUart,
+    /// Detect the baudrate of the attached device, switching to it if `apply` is set
+    DetectBaudrate { apply: bool },
+    /// Persist a new data port, used after the next restart
+    SetTcpPort(u16),
+    /// Restart the device
+    Restart,
+    /// Erase all stored settings and restart the device
+    FactoryReset,
+    /// Change and persist the device name, which is also the mDNS host name
+    SetDeviceName(String),
+    /// Change and persist the welcome banner template (None disables it)
+    SetBanner(Option<String>),
+    /// Append a rule to the access list and persist it
+    AddAclRule(AclRule),
+    /// Remove the access list rule at a 1-based position and persist the list
+    RemoveAclRule(usize),
+    /// Remove every access list rule, admitting all peers again
+    ClearAcl,
+    /// Change the WiFi station credentials and reconnect
+    SetStaCredentials {
+        /// New station SSID
+        ssid: String,
+        /// New station password
+        password: String,
+    },
+    /// Persist the access point security, used after the next restart
+    SetApSecurity {
+        auth: WiFiAuth,
+        /// New password, None keeps the current one
+        password: Option<String>,
+    },
+    /// Change and persist the WiFi regulatory country
+    SetCountry(String),
+    /// Change and persist the maximum WiFi transmit power in dBm
+    SetTxPower(u8),
+    /// Persist the access point settings and restart it once the reply is sent
+    SetAccessPoint {
+        ssid: String,
+        password: String,
+        /// Channel 1-13, 0 picks it by a scan
+        channel: u8,
+        hidden: bool,
+        /// New channel width, None keeps the current one
+        bandwidth: Option<ApBandwidth>,
+    },
+}
+
+impl fmt::Display for CommandPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CommandPlan::SetBaudrate(baudrate) => write!(f, "Baudrate would change to {}", baudrate),
+            CommandPlan::DetectBaudrate { apply: false } => write!(f, "Baudrate would be detected"),
+            CommandPlan::DetectBaudrate { apply: true } => {
+                write!(f, "Baudrate would be detected and changed to the best candidate")
+            }
+            CommandPlan::SetSerialParams { baudrate, format } => {
+                write!(f, "UART settings would change to {},{}", baudrate, format)
+            }
+            CommandPlan::SetFrameGap { gap_ms: 0, .. } => write!(f, "UART frame gap would be disabled"),
+            CommandPlan::SetFrameGap { gap_ms, max_bytes } => write!(
+                f,
+                "UART frame gap would change to {} ms, max {} bytes",
+                gap_ms, max_bytes
+            ),
+            CommandPlan::SetFrameDelimiter(Some(delimiter)) => write!(
+                f,
+                "UART frame delimiter would change to {}",
+                uart::format_hex(delimiter)
+            ),
+            CommandPlan::SetFrameDelimiter(None) => write!(f, "UART frame delimiter would be disabled"),
+            CommandPlan::SetBatching(Some((batch_bytes, batch_ms))) => write!(
+                f,
+                "UART batching would change to {} bytes within {} ms",
+                batch_bytes, batch_ms
+            ),
+            CommandPlan::SetBatching(None) => write!(f, "UART batching would be disabled"),
+            CommandPlan::SetMarkGaps(enabled) => write!(
+                f,
+                "Gap markers would be {}",
+                if *enabled { "enabled" } else { "disabled" }
+            ),
+            CommandPlan::SetRawMode(enabled) => write!(
+                f,
+                "Raw mode would be {}",
+                if *enabled { "entered" } else { "left" }
+            ),
+            CommandPlan::SetEcho(enabled) => write!(
+                f,
+                "Echo would be {}",
+                if *enabled { "enabled" } else { "disabled" }
+            ),
+            CommandPlan::SetVerbose(enabled) => write!(
+                f,
+                "Verbose replies would be {}",
+                if *enabled { "enabled" } else { "disabled" }
+            ),
+            CommandPlan::SetHexTap(enabled) => write!(
+                f,
+                "Tap mode would change to {}",
+                if *enabled { "HEX" } else { "RAW" }
+            ),
+            CommandPlan::SetLogStream(enabled) => write!(
+                f,
+                "Log streaming would be {}",
+                if *enabled { "enabled" } else { "disabled" }
+            ),
+            CommandPlan::SetLogLevel { level, target: Some(target) } => write!(
+                f,
+                "Log level of {} would change to {}",
+                target,
+                log_level::level_name(*level)
+            ),
+            CommandPlan::SetLogLevel { level, target: None } => {
+                write!(f, "Log level would change to {}", log_level::level_name(*level))
+            }
+            CommandPlan::SaveLogLevels => write!(f, "Log levels {} would be saved", log_level::current()),
+            CommandPlan::ResetStats => write!(f, "Traffic statistics would be reset"),
+            CommandPlan::ResetUartErrors => write!(f, "UART error counters would be reset"),
+            CommandPlan::ResetBootInfo => write!(f, "Boot counter would be cleared"),
+            CommandPlan::Kick(addr) => write!(f, "Client {} would be disconnected", addr),
+            CommandPlan::SetBridge(enabled) => write!(
+                f,
+                "Bridge would be {}",
+                if *enabled { "resumed" } else { "paused" }
+            ),
+            CommandPlan::SetLineEndings { tcp_to_uart, uart_to_tcp } => write!(
+                f,
+                "Line endings would change to TCP>UART {}, UART>TCP {}",
+                tcp_to_uart, uart_to_tcp
+            ),
+            CommandPlan::SetReplay(0) => write!(f, "UART history replay would be disabled"),
+            CommandPlan::SetReplay(bytes) => {
+                write!(f, "Last {} bytes from UART would be replayed to new clients", bytes)
+            }
+            CommandPlan::SetGpio { name, action } => match action {
+                GpioAction::Set(true) => write!(f, "GPIO {} would be asserted", name),
+                GpioAction::Set(false) => write!(f, "GPIO {} would be released", name),
+                GpioAction::Pulse(Some(ms)) => write!(f, "GPIO {} would be pulsed for {} ms", name, ms),
+                GpioAction::Pulse(None) => write!(f, "GPIO {} would be pulsed", name),
+            },
+            CommandPlan::ResetTarget { bootloader: true } => write!(f, "Target would be reset into its bootloader"),
+            CommandPlan::ResetTarget { bootloader: false } => write!(f, "Target would be reset"),
+            CommandPlan::SendBytes(data) => write!(f, "{} bytes would be sent to UART", data.len()),
+            CommandPlan::SendLine(text) => {
+                write!(f, "{} bytes and a line ending would be sent to UART", text.len())
+            }
+            CommandPlan::RunSelfTest(test) => write!(f, "{} self test would run", test),
+            CommandPlan::LockUart(None) => write!(f, "UART would be locked to this client"),
+            CommandPlan::LockUart(Some(addr)) => write!(f, "UART would be locked to client {}", addr),
+            CommandPlan::UnlockUart => write!(f, "UART would be unlocked"),
+            CommandPlan::SetTcpPort(port) => {
+                write!(f, "TCP port would be set to {} after restart", port)
+            }
+            CommandPlan::Restart => write!(f, "Device would restart"),
+            CommandPlan::FactoryReset => {
+                write!(f, "All stored settings would be erased and the device would restart")
+            }
+            CommandPlan::SetDeviceName(name) => {
+                write!(f, "Device name would change to {} ({}.local)", name, name)
+            }
+            CommandPlan::SetBanner(Some(banner)) => {
+                write!(f, "Welcome banner would change to: {}", banner)
+            }
+            CommandPlan::SetBanner(None) => write!(f, "Welcome banner would be disabled"),
+            CommandPlan::AddAclRule(rule) => write!(f, "Access list rule would be added: {}", rule),
+            CommandPlan::RemoveAclRule(position) => {
+                write!(f, "Access list rule {} would be removed", position)
+            }
+            CommandPlan::ClearAcl => write!(f, "Access list would be cleared, admitting all peers"),
+            CommandPlan::SetStaCredentials { ssid, .. } => {
+                write!(f, "WiFi station would connect to {}", ssid)
+            }
+            CommandPlan::SetApSecurity { auth, .. } => {
+                write!(f, "Access point security would be set to {} after restart", auth)
+            }
+            CommandPlan::SetCountry(code) => write!(f, "WiFi country would change to {}", code),
+            CommandPlan::SetTxPower(dbm) => write!(f, "WiFi max TX power would change to {} dBm", dbm),
+            CommandPlan::SetAccessPoint { ssid, channel, .. } => {
+                write!(f, "Access point would restart as {} on channel ", ssid)?;
+                match channel {
+                    0 => write!(f, "auto")?,
+                    channel => write!(f, "{}", channel)?,
+                }
+                write!(f, ", dropping connected stations")
+            }
+        }
+    }
+}
+
+/// Where a `DetachedStream` puts what a command writes
+pub(super) enum ReplySink {
+    /// Written back to the UART
+    Uart(Arc<UartManager>),
+    /// Collected for a binary control response
+    Buffer(Vec<u8>),
+}
+
+/// Stands in for a client stream when a command does not come as a text line
+/// from a TCP connection
+///
+/// Reading yields nothing, so commands that upload data fail instead of consuming
+/// other data.
+pub(super) struct DetachedStream {
+    pub(super) peer_addr: SocketAddr,
+    pub(super) sink: ReplySink,
+}
+
+impl Read for DetachedStream {
+    fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for DetachedStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match &mut self.sink {
+            ReplySink::Uart(uart_manager) => uart_manager
+                .send_data(buf)
+                .map_err(|e| std::io::Error::other(e.to_string()))?,
+            ReplySink::Buffer(reply) => reply.extend_from_slice(buf),
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl ClientStream for DetachedStream {
+    fn peer_addr(&self) -> std::io::Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+
+    fn set_nonblocking(&self, _nonblocking: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_read_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_write_timeout(&self, _timeout: Option<Duration>) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn shutdown(&self, _how: Shutdown) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn set_nodelay(&self, _nodelay: bool) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    fn nodelay(&self) -> std::io::Result<bool> {
+        Ok(true)
+    }
+
+    fn read_view(&self) -> std::io::Result<Box<dyn Read + Send>> {
+        Ok(Box::new(std::io::empty()))
+    }
+}
+
+impl TcpServer {
+    /// Process a command from a client
+    ///
+    /// The command is looked up in the server's `CommandRegistry`, which also
+    /// generates the AT+HELP text (see `commands`).
+    pub(super) fn process_command(
+        data: &[u8],
+        context: &CommandContext,
+        client_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Result<()> {
+        // 将命令转换为规范形式的字符串
+        let cmd_str = match std::str::from_utf8(data) {
+            Ok(s) => commands::normalize(s),
+            Err(_) => {
+                // 发送错误响应
+                let verbose = client_manager.is_verbose(peer_addr);
+                let response =
+                    commands::format_error(ErrorCode::InvalidFormat, "Invalid command format (not UTF-8)", verbose);
+                Self::send_response(stream_arc, &response, peer_addr)?;
+                return Err(Error::tcp("Invalid command format (not UTF-8)"));
+            }
+        };
+
+        let cmd_str = cmd_str.as_str();
+
+        info!("Received command from client {}: {}", peer_addr, cmd_str);
+
+        // 记录命令历史（不记录历史命令本身和带有秘密的命令，包括被AT+VERIFY包装的）
+        if context.commands.is_recorded(cmd_str) {
+            if let Err(e) = client_manager.record_command(peer_addr, cmd_str) {
+                debug!("Failed to record command for client {}: {}", peer_addr, e);
+            }
+        }
+
+        Self::execute_command(cmd_str, context, client_manager, stream_arc, peer_addr)
+    }
+
+    /// Execute a normalized command line and send the reply, without recording it
+    ///
+    /// Lines replayed from the history with AT+! run here, so replaying does not
+    /// add to the history and shift the entry numbers.
+    fn execute_command(
+        cmd_str: &str,
+        context: &CommandContext,
+        client_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Result<()> {
+        let request = CommandRequest {
+            command: cmd_str,
+            context,
+            client_manager,
+            stream_arc,
+            peer_addr,
+        };
+        let (name, response) = match context.commands.find(cmd_str) {
+            Some((handler, args)) => {
+                info!("Processing {} command from client {}", handler.name(), peer_addr);
+                (handler.name(), handler.execute(args, &request)?)
+            }
+            None => {
+                info!("Processing unknown command '{}' from client {}", cmd_str, peer_addr);
+                ("", commands::unknown_command(cmd_str))
+            }
+        };
+
+        // 按客户端选择的格式（AT+VERBOSE）回复，设置本身已经生效
+        let verbose = client_manager.is_verbose(peer_addr);
+        let (reply, restart, restart_ap) = match response {
+            Response::Reply(text) => (commands::format_reply(name, &text, verbose), false, false),
+            Response::Restart(text) => (commands::format_reply(name, &text, verbose), true, false),
+            Response::RestartAp(text) => (commands::format_reply(name, &text, verbose), false, true),
+            Response::Error(code, message) => (commands::format_error(code, &message, verbose), false, false),
+            // 重新执行历史中的命令，不再记录
+            Response::Rerun(line) => {
+                info!("Replaying command from history for client {}: {}", peer_addr, line);
+                return Self::execute_command(&line, context, client_manager, stream_arc, peer_addr);
+            }
+            Response::Sent { restart } => (String::new(), restart, false),
+        };
+        if !reply.is_empty() {
+            Self::send_command_response(stream_arc, &reply, peer_addr)?;
+        }
+        if restart {
+            Self::restart_device(stream_arc, peer_addr);
+        }
+        if restart_ap {
+            Self::restart_access_point(context.wifi_manager.as_ref(), peer_addr);
+        }
+
+        Ok(())
+    }
+
+    /// Run a command line for a peer outside the TCP ports, such as the HTTP API
+    ///
+    /// The command goes through the registry like one of a control client. Returns
+    /// whether it succeeded and its reply text. An access point change restarts the
+    /// access point a moment later, so the peer still gets the reply through it.
+    pub fn run_command(&self, line: &str, peer_addr: &std::net::SocketAddr) -> (bool, String) {
+        let (status, text, response) =
+            Self::execute_binary_command(line, &self.context, &self.control_manager, peer_addr);
+        if let Some(Response::RestartAp(_)) = response {
+            let wifi_manager = self.context.wifi_manager.clone();
+            let peer_addr = *peer_addr;
+            thread::spawn(move || Self::restart_access_point(wifi_manager.as_ref(), &peer_addr));
+        }
+        (status == STATUS_OK, String::from_utf8_lossy(&text).into_owned())
+    }
+
+    /// Run a command line received from the UART (see `UartConfig::command_prefix`)
+    ///
+    /// The command goes through the same registry as client commands, as if sent
+    /// by a data port client at `UART_COMMAND_ADDR` that is not in the client
+    /// manager, and its reply is written back to the UART.
+    pub(super) fn run_uart_command(context: &CommandContext, line: &str) {
+        let stream_arc: SharedStream = Arc::new(Mutex::new(DetachedStream {
+            peer_addr: UART_COMMAND_ADDR,
+            sink: ReplySink::Uart(Arc::clone(&context.uart_manager)),
+        }));
+        if let Err(e) =
+            Self::process_command(line.as_bytes(), context, &context.data_clients, &stream_arc, &UART_COMMAND_ADDR)
+        {
+            warn!("UART command failed: {}", e);
+        }
+    }
+
+    /// Send the reply to a command, logging a failure
+    fn send_command_response(
+        stream_arc: &SharedStream,
+        response: &str,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Result<()> {
+        Self::send_response(stream_arc, response, peer_addr).map_err(|e| {
+            error!("Failed to send command response to client {}: {}", peer_addr, e);
+            e
+        })
+    }
+
+    /// Send `prompt`, then read exactly `len` bytes from the client (AT+CFGIMPORT=)
+    ///
+    /// The connection is switched to blocking mode for the upload, like for AT+OTA=.
+    pub(crate) fn receive_upload(
+        len: usize,
+        prompt: &str,
+        client_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Result<Vec<u8>> {
+        let mut data = vec![0u8; len];
+        let result = {
+            let mut stream = stream_arc
+                .lock()
+                .map_err(|_| Error::tcp("Failed to lock client stream"))?;
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_write_timeout(Some(Duration::from_millis(RESPONSE_WRITE_TIMEOUT_MS)));
+            let result = stream
+                .set_read_timeout(Some(Duration::from_millis(UPLOAD_STALL_TIMEOUT_MS)))
+                .and_then(|_| stream.write_all(prompt.as_bytes()))
+                .and_then(|_| stream.read_exact(&mut data))
+                .map_err(|e| Error::tcp_caused(format!("Failed to receive {} bytes", len), e));
+            let _ = stream.set_read_timeout(None);
+            let _ = stream.set_write_timeout(None);
+            let _ = stream.set_nonblocking(true);
+            result
+        };
+        client_manager.touch(peer_addr);
+        debug!("Received {} byte upload from client {}", len, peer_addr);
+        result.map(|_| data)
+    }
+
+    /// Run an AT+OTA upload on the issuing connection
+    ///
+    /// Returns true when the new image was activated and the device should restart.
+    /// On failure the running firmware stays bootable and the connection goes back to
+    /// command mode.
+    pub(crate) fn receive_firmware(
+        args: &str,
+        context: &CommandContext,
+        client_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) -> Result<bool> {
+        // 数据端口会收到UART广播，会与镜像数据和进度回复混在一起
+        let ready = if Arc::ptr_eq(client_manager, &context.data_clients) {
+            Err("Firmware updates are only accepted on the control port".to_string())
+        } else {
+            OtaRequest::parse(args.trim()).and_then(|request| match ota::update_partition_size() {
+                None => Err("No OTA partition available".to_string()),
+                Some(capacity) if request.size > capacity => Err(format!(
+                    "Image too large: {} bytes, partition holds {}",
+                    request.size, capacity
+                )),
+                Some(_) => Ok(request),
+            })
+        };
+        let request = match ready {
+            Ok(request) => request,
+            Err(msg) => {
+                Self::send_response(stream_arc, &format!("ERROR: {}\r\n", msg), peer_addr)?;
+                return Ok(false);
+            }
+        };
+
+        // 上传期间一直持有连接，以阻塞方式读取
+        let result = {
+            let mut stream = stream_arc
+                .lock()
+                .map_err(|_| Error::tcp("Failed to lock client stream"))?;
+            let _ = stream.set_nonblocking(false);
+            let _ = stream.set_write_timeout(Some(Duration::from_millis(RESPONSE_WRITE_TIMEOUT_MS)));
+            let result = stream
+                .set_read_timeout(Some(ota::STALL_TIMEOUT))
+                .and_then(|_| stream.write_all(format!("OK: Ready for {} bytes\r\n", request.size).as_bytes()))
+                .map_err(|e| Error::tcp_caused("Failed to enter upload mode", e))
+                .and_then(|_| ota::receive(&mut *stream, &request));
+            let _ = stream.set_read_timeout(None);
+            let _ = stream.set_write_timeout(None);
+            let _ = stream.set_nonblocking(true);
+            result
+        };
+        client_manager.touch(peer_addr);
+
+        match result {
+            Ok(()) => {
+                info!("Firmware update from client {} complete", peer_addr);
+                let response = format!("OK: Firmware updated ({} bytes), restarting\r\n", request.size);
+                Self::send_response(stream_arc, &response, peer_addr)?;
+                Ok(true)
+            }
+            Err(e) => {
+                error!("Firmware update from client {} failed: {}", peer_addr, e);
+                Self::send_response(stream_arc, &format!("ERROR: {}\r\n", e), peer_addr)?;
+                Ok(false)
+            }
+        }
+    }
+
+    /// Give the client time to receive the reply, then restart the device
+    pub(super) fn restart_device(stream_arc: &SharedStream, peer_addr: &SocketAddr) -> ! {
+        thread::sleep(Duration::from_millis(RESTART_GRACE_MS));
+        if let Ok(stream) = stream_arc.lock() {
+            let _ = stream.shutdown(Shutdown::Both);
+        }
+        warn!("Restarting device as requested by client {}", peer_addr);
+        boot_info::restart()
+    }
+
+    /// Give the client time to receive the reply, then restart the access point
+    ///
+    /// A failure is only logged, the reply has already been sent.
+    pub(super) fn restart_access_point(wifi_manager: Option<&Arc<Mutex<WiFiManager>>>, peer_addr: &SocketAddr) {
+        let Some(wifi_manager) = wifi_manager else {
+            return;
+        };
+        thread::sleep(Duration::from_millis(RESTART_GRACE_MS));
+        info!("Restarting access point as requested by client {}", peer_addr);
+        let result = match wifi_manager.lock() {
+            Ok(mut wifi) => wifi.restart_ap(),
+            Err(_) => Err(Error::wifi("Failed to lock WiFi manager")),
+        };
+        if let Err(e) = result {
+            error!("Failed to restart access point: {}", e);
+        }
+    }
+
+    /// Read the data port saved with AT+PORT
+    pub(crate) fn saved_port(context: &CommandContext) -> Option<u16> {
+        let storage = context.storage.as_ref()?.lock().ok()?;
+        storage.read_tcp_port()
+    }
+
+    /// Build the AT+STATUS report
+    ///
+    /// One "Key: value" pair per line so the output is readable in a terminal
+    /// and easy to parse from scripts.
+    pub(crate) fn status_report(context: &CommandContext) -> String {
+        let uptime = time::uptime();
+        let free_heap = diagnostics::free_heap();
+        let mut report = format!(
+            "Device name: {}\r\nUptime: {} s ({})\r\nFree heap: {} bytes\r\n",
+            device_name::get(),
+            uptime.as_secs(),
+            time::format_duration(uptime),
+            free_heap
+        );
+
+        // 修改STA配置时WiFi管理器会被长时间锁定，此时不等待
+        match context.wifi_manager.as_ref().map(|wifi| wifi.try_lock()) {
+            Some(Ok(wifi)) => {
+                let ap_ip = wifi
+                    .ap_ip_info()
+                    .map(|info| info.ip.to_string())
+                    .unwrap_or_else(|| "none".to_string());
+                report += &format!("AP SSID: {}\r\nAP IP: {}\r\n", wifi.ap_ssid(), ap_ip);
+                report += &format!("Host name: {}.local\r\n", device_name::get());
+                report += &format!(
+                    "WiFi country: {}\r\nWiFi TX power: {} dBm\r\n",
+                    wifi.country_code(),
+                    wifi.tx_power_dbm()
+                );
+                let sta = wifi.sta_status();
+                let sta_state = match (sta.enabled, sta.connected) {
+                    (false, _) => "disabled",
+                    (true, true) => "connected",
+                    (true, false) => "disconnected",
+                };
+                report += &format!("STA SSID: {}\r\nSTA state: {}\r\n", wifi.sta_ssid(), sta_state);
+                if let Some(info) = wifi.sta_ip_info() {
+                    report += &format!("STA IP: {}\r\n", info.ip);
+                }
+                if sta.attempts > 0 {
+                    report += &format!("STA reconnect attempts: {}\r\n", sta.attempts);
+                }
+                if let Some(retry) = sta.next_retry {
+                    report += &format!("STA next retry: {}\r\n", time::format_duration(retry));
+                }
+                if let Some(error) = &sta.last_error {
+                    report += &format!("STA last error: {}\r\n", error);
+                }
+            }
+            Some(Err(_)) => report += "WiFi: busy\r\n",
+            None => report += "WiFi: not available\r\n",
+        }
+
+        report += &Self::for_each_bridge(context, |uart_manager, clients, indent| {
+            let mut section = format!(
+                "{}UART: {},{}\r\n",
+                indent,
+                uart_manager.get_baudrate(),
+                uart_manager.get_format()
+            );
+            section += &format!("{}UART framing: {}\r\n", indent, uart_manager.framing());
+
+            let clients = clients.list_clients().unwrap_or_default();
+            section += &format!("{}TCP clients: {}\r\n", indent, clients.len());
+            for client in clients {
+                section += &format!("{}Client: {}\r\n", indent, client.addr);
+            }
+
+            let uart_stats = uart_manager.stats();
+            section += &format!(
+                "{}Bytes TCP->UART: {}\r\n{}Bytes UART->TCP: {}\r\n",
+                indent,
+                uart_stats.bytes_sent_to_uart,
+                indent,
+                uart_stats.bytes_received_from_uart
+            );
+            section
+        });
+        if let Some(link) = &context.client_link {
+            report += &format!("Remote: {} ({})\r\n", link.remote(), link.state());
+        }
+        if let Some(mqtt) = &context.mqtt {
+            report += &format!("MQTT: {} ({})\r\n", mqtt.broker(), mqtt.state());
+        }
+        report
+    }
+
+    /// Build the AT+STATS report in the same "Key: value" format as AT+STATUS
+    pub(crate) fn stats_report(context: &CommandContext) -> String {
+        Self::for_each_bridge(context, |uart_manager, clients, indent| {
+            let uart = uart_manager.stats();
+            let clients = clients.stats();
+            format!(
+                "{indent}Bytes sent to UART: {}\r\n\
+                {indent}Bytes received from UART: {}\r\n\
+                {indent}UART flow pauses: {}\r\n\
+                {indent}UART flow resumes: {}\r\n\
+                {indent}UART packet errors: {}\r\n\
+                {indent}Bytes broadcast: {}\r\n\
+                {indent}Broadcast errors: {}\r\n\
+                {indent}Clients total: {}\r\n\
+                {indent}Clients evicted: {}\r\n\
+                {indent}UART TX queue: {}\r\n",
+                uart.bytes_sent_to_uart,
+                uart.bytes_received_from_uart,
+                uart.flow_pauses,
+                uart.flow_resumes,
+                uart.packet_errors,
+                clients.bytes_broadcast,
+                clients.broadcast_errors,
+                clients.clients_total,
+                clients.clients_evicted,
+                uart_manager.get_tx_queue_len()
+            )
+        })
+    }
+
+    /// Build the AT+UARTERR report of the receive error counters
+    pub(crate) fn uart_error_report(context: &CommandContext) -> String {
+        Self::for_each_bridge(context, |uart_manager, _, indent| {
+            let errors = uart_manager.get_error_counters();
+            format!(
+                "{indent}Framing errors: {}\r\n\
+                {indent}Parity errors: {}\r\n\
+                {indent}Overflows: {}\r\n\
+                {indent}Breaks: {}\r\n",
+                errors.framing,
+                errors.parity,
+                errors.overflow,
+                errors.breaks
+            )
+        })
+    }
+
+    /// Build one report section per bridge, each under a "Bridge:" line with
+    /// indented "Key: value" lines; a single bridge gets one section without header
+    fn for_each_bridge(
+        context: &CommandContext,
+        section: impl Fn(&UartManager, &TcpClientManager, &str) -> String,
+    ) -> String {
+        if context.bridges.is_empty() {
+            return section(&context.uart_manager, &context.data_clients, "");
+        }
+        context
+            .bridges
+            .iter()
+            .map(|bridge| {
+                let this_port = Arc::ptr_eq(&bridge.uart_manager, &context.uart_manager);
+                Self::bridge_header(bridge.name, bridge.port, this_port)
+                    + &section(&bridge.uart_manager, &bridge.client_manager, "  ")
+            })
+            .collect()
+    }
+
+    /// "Bridge:" line starting the report section of a bridge
+    pub(super) fn bridge_header(name: &str, port: u16, this_port: bool) -> String {
+        format!("Bridge: {} (port {}{})\r\n", name, port, if this_port { ", this port" } else { "" })
+    }
+}
@@ -0,0 +1,268 @@
+//! Control port sessions
+//!
+//! Serves control port clients in the text or the binary framed protocol (see
+//! `control_frame`).
+
+use log::{debug, error, info, warn};
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::commands::{self, CommandRequest, ErrorCode, Response};
+use crate::config::{ControlProtocol, TcpServerConfig};
+use crate::control_frame::{self, Frame, FrameError, OPCODE_FRAME_ERROR, STATUS_OK};
+use crate::diagnostics;
+use crate::error::{Error, Result};
+use crate::tcp_client_manager::{SharedStream, StreamReader, TcpClientManager};
+
+use super::command_handler::{DetachedStream, ReplySink};
+use super::{CommandContext, CommandFramer, Framed, TcpServer};
+
+impl TcpServer {
+    /// Handle a control port connection
+    ///
+    /// Control clients may only issue AT commands. Nothing they send is forwarded
+    /// to UART, and they are tracked by a separate client manager so UART data is
+    /// never broadcast to them.
+    pub(super) fn handle_control_client(
+        stream: TcpStream,
+        control_manager: Arc<TcpClientManager>,
+        context: CommandContext,
+        config: TcpServerConfig,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let _tracker = diagnostics::track_thread("control_client");
+        let peer_addr = stream
+            .peer_addr()
+            .map_err(|e| Error::tcp_caused("Failed to get peer address", e))?;
+
+        info!("New control client connected: {}", peer_addr);
+
+        let stream_arc = Arc::new(Mutex::new(stream));
+        let mut reader = StreamReader::new(&stream_arc)?;
+        let conn_id = control_manager.add_client(peer_addr, Arc::clone(&stream_arc))?;
+        let stream_arc: SharedStream = stream_arc;
+
+        if let Ok(stream) = stream_arc.lock() {
+            if let Err(e) = stream.set_nonblocking(true) {
+                error!(
+                    "Failed to set non-blocking mode for control client {}: {}",
+                    peer_addr, e
+                );
+            }
+        }
+
+        // 二进制协议的客户端不会解析欢迎信息
+        let binary = config.control_protocol == ControlProtocol::Binary;
+        if !binary {
+            let welcome_msg = format!(
+                "ESP32 UART-TCP Bridge control port. Your client ID: {}\r\n\
+                Type AT+HELP for available commands\r\n",
+                peer_addr
+            );
+            let _ = Self::send_response(&stream_arc, &welcome_msg, &peer_addr);
+        }
+
+        let mut buffer = vec![0; config.buffer_size];
+        let mut framer = CommandFramer::new(Duration::from_millis(config.command_timeout_ms));
+        let mut decoder = control_frame::FrameDecoder::new();
+        let mut frames = Vec::new();
+        loop {
+            if shutdown.load(Ordering::SeqCst) {
+                Self::close_on_shutdown(&control_manager, &stream_arc, &peer_addr, conn_id)?;
+                break;
+            }
+
+            if let Some(framed) = framer.poll() {
+                Self::dispatch_control(framed, &context, &control_manager, &stream_arc, &peer_addr);
+            }
+
+            // 读取时不持有流锁，写线程可以同时写出日志流
+            match reader.read(&mut buffer) {
+                Ok(0) => {
+                    info!("Control client {} disconnected", peer_addr);
+                    control_manager.remove_client(&peer_addr, conn_id)?;
+                    break;
+                }
+                Ok(n) if binary => {
+                    control_manager.touch(&peer_addr);
+
+                    decoder.decode(&buffer[0..n], &mut frames);
+                    for frame in frames.drain(..) {
+                        Self::dispatch_binary(frame, &context, &control_manager, &stream_arc, &peer_addr);
+                    }
+                }
+                Ok(n) => {
+                    control_manager.touch(&peer_addr);
+
+                    for framed in framer.push(&buffer[0..n]) {
+                        Self::dispatch_control(
+                            framed,
+                            &context,
+                            &control_manager,
+                            &stream_arc,
+                            &peer_addr,
+                        );
+                    }
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+                        thread::sleep(Duration::from_millis(10));
+                    }
+                    ErrorKind::Interrupted => {}
+                    ErrorKind::BrokenPipe | ErrorKind::ConnectionReset | ErrorKind::ConnectionAborted => {
+                        info!("Control client {} connection lost: {}", peer_addr, e);
+                        control_manager.remove_client(&peer_addr, conn_id)?;
+                        break;
+                    }
+                    _ => {
+                        error!("Error reading from control client {}: {}", peer_addr, e);
+                        control_manager.remove_client(&peer_addr, conn_id)?;
+                        break;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Answer a binary control request (see `control_frame`)
+    pub(super) fn dispatch_binary(
+        frame: std::result::Result<Frame, FrameError>,
+        context: &CommandContext,
+        control_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) {
+        let frame = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("Invalid control frame from client {}: {}", peer_addr, e);
+                let reply = control_frame::response(
+                    OPCODE_FRAME_ERROR,
+                    ErrorCode::InvalidFormat as u8,
+                    e.to_string().as_bytes(),
+                );
+                let _ = Self::write_reply(stream_arc, &reply, peer_addr);
+                return;
+            }
+        };
+
+        let (status, text, response) = match control_frame::command_line(&frame) {
+            Some(Ok(line)) => {
+                Self::execute_binary_command(&line, context, control_manager, peer_addr)
+            }
+            Some(Err(message)) => (ErrorCode::InvalidArgument as u8, message.into_bytes(), None),
+            None => (
+                ErrorCode::UnknownCommand as u8,
+                format!("Unknown opcode 0x{:02X}", frame.opcode).into_bytes(),
+                None,
+            ),
+        };
+        let reply = control_frame::response(frame.opcode, status, &text);
+        if let Err(e) = Self::write_reply(stream_arc, &reply, peer_addr) {
+            error!("Failed to send control response to client {}: {}", peer_addr, e);
+            return;
+        }
+        debug!(
+            "Sent control response 0x{:02X} with status {} to client {}",
+            frame.opcode,
+            status,
+            peer_addr
+        );
+        match response {
+            Some(Response::Restart(_)) | Some(Response::Sent { restart: true }) => {
+                Self::restart_device(stream_arc, peer_addr)
+            }
+            Some(Response::RestartAp(_)) => Self::restart_access_point(context.wifi_manager.as_ref(), peer_addr),
+            _ => {}
+        }
+    }
+
+    /// Run the command line of a binary control request
+    ///
+    /// Returns the status byte and the reply text of the response, and the
+    /// handler's response, which tells whether a restart follows the reply.
+    pub(super) fn execute_binary_command(
+        line: &str,
+        context: &CommandContext,
+        control_manager: &Arc<TcpClientManager>,
+        peer_addr: &std::net::SocketAddr,
+    ) -> (u8, Vec<u8>, Option<Response>) {
+        let cmd_str = commands::normalize(line);
+        info!("Received binary command from client {}: {}", peer_addr, cmd_str);
+
+        // 处理程序直接写出的内容收集到响应中
+        let stream = Arc::new(Mutex::new(DetachedStream {
+            peer_addr: *peer_addr,
+            sink: ReplySink::Buffer(Vec::new()),
+        }));
+        let stream_arc: SharedStream = stream.clone();
+        let request = CommandRequest {
+            command: &cmd_str,
+            context,
+            client_manager: control_manager,
+            stream_arc: &stream_arc,
+            peer_addr,
+        };
+        let response = match context.commands.find(&cmd_str) {
+            // 二进制请求没有可供上传的字节流
+            Some((handler, args)) if handler.reads_upload(args) => Response::Error(
+                ErrorCode::InvalidArgument,
+                format!("{}= uploads are only accepted in text mode", handler.name()),
+            ),
+            Some((handler, args)) => handler
+                .execute(args, &request)
+                .unwrap_or_else(|e| Response::Error(ErrorCode::Failed, e.to_string())),
+            None => commands::unknown_command(&cmd_str),
+        };
+
+        let (status, text) = match &response {
+            Response::Reply(text) | Response::Restart(text) | Response::RestartAp(text) => {
+                let failed = text.lines().any(|line| line.trim_start().starts_with("ERROR: "));
+                let status = if failed { ErrorCode::Failed as u8 } else { STATUS_OK };
+                (status, text.clone().into_bytes())
+            }
+            Response::Error(code, message) => (*code as u8, message.clone().into_bytes()),
+            Response::Rerun(line) => {
+                return Self::execute_binary_command(line, context, control_manager, peer_addr);
+            }
+            Response::Sent { .. } => {
+                let written = match stream.lock().as_deref_mut() {
+                    Ok(DetachedStream { sink: ReplySink::Buffer(written), .. }) => std::mem::take(written),
+                    _ => Vec::new(),
+                };
+                (STATUS_OK, written)
+            }
+        };
+        (status, text, Some(response))
+    }
+
+    /// Process a framed command line from a control client, rejecting other data
+    fn dispatch_control(
+        framed: Framed,
+        context: &CommandContext,
+        control_manager: &Arc<TcpClientManager>,
+        stream_arc: &SharedStream,
+        peer_addr: &std::net::SocketAddr,
+    ) {
+        match framed {
+            Framed::Command(line) => {
+                if let Err(e) =
+                    Self::process_command(&line, context, control_manager, stream_arc, peer_addr)
+                {
+                    error!("Error processing command from control client {}: {}", peer_addr, e);
+                }
+            }
+            Framed::Data(_) => {
+                // 控制端口上的数据不会转发到UART
+                let response = "ERROR: Control port only accepts AT commands\r\n";
+                let _ = Self::send_response(stream_arc, response, peer_addr);
+            }
+        }
+    }
+}
@@ -0,0 +1,114 @@
+//! RFC 2217 sessions
+//!
+//! Serves remote serial port clients on the RFC 2217 port (see `rfc2217`).
+
+use log::{debug, error, info, warn};
+use std::io::{ErrorKind, Read};
+use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TcpServerConfig;
+use crate::diagnostics;
+use crate::error::{Error, Result};
+use crate::rfc2217::TelnetSession;
+use crate::tcp_client_manager::{ClientProtocol, SharedStream, StreamReader, TcpClientManager};
+
+use super::{CommandContext, Registration, TcpServer};
+
+impl TcpServer {
+    /// Handle an RFC 2217 connection
+    ///
+    /// Telnet commands are answered through the client's outbound queue, so they
+    /// stay in order with the escaped UART data queued by `broadcast`.
+    pub(super) fn handle_rfc2217_client(
+        stream: TcpStream,
+        client_manager: Arc<TcpClientManager>,
+        context: CommandContext,
+        config: TcpServerConfig,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let _tracker = diagnostics::track_thread("rfc2217_client");
+        let peer_addr = stream
+            .peer_addr()
+            .map_err(|e| Error::tcp_caused("Failed to get peer address", e))?;
+        info!("New RFC 2217 client connected: {}", peer_addr);
+
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("Failed to set non-blocking mode for client {}: {}", peer_addr, e);
+        }
+        if let Err(e) = stream.set_nodelay(true) {
+            error!("Failed to set TCP_NODELAY for client {}: {}", peer_addr, e);
+        }
+        Self::enable_keepalive(&stream, &config, &peer_addr);
+
+        let stream_arc = Arc::new(Mutex::new(stream));
+        client_manager.register_client(peer_addr);
+        let mut reader = StreamReader::new(&stream_arc)?;
+        let conn_id = client_manager.add_client(peer_addr, Arc::clone(&stream_arc))?;
+        let _registration = Registration { client_manager: &client_manager, peer_addr, conn_id };
+        let stream_arc: SharedStream = stream_arc;
+        let traffic = client_manager.traffic_counter(&peer_addr)?;
+        client_manager.set_protocol(&peer_addr, ClientProtocol::Telnet)?;
+
+        let mut telnet = TelnetSession::new();
+        let mut buffer = vec![0; config.buffer_size];
+        let mut data = Vec::with_capacity(config.buffer_size);
+        let mut replies = Vec::new();
+
+        loop {
+            // 服务器停止时关闭连接
+            if shutdown.load(Ordering::SeqCst) {
+                Self::close_on_shutdown(&client_manager, &stream_arc, &peer_addr, conn_id)?;
+                break;
+            }
+
+            // 读取时不持有流锁，写线程可以同时写出排队的数据
+            match reader.read(&mut buffer) {
+                Ok(0) => {
+                    info!("RFC 2217 client {} disconnected", peer_addr);
+                    break;
+                }
+                Ok(n) => {
+                    traffic.record_received(n);
+                    data.clear();
+                    replies.clear();
+                    telnet.receive(&buffer[..n], &context.uart_manager, &mut data, &mut replies);
+
+                    if !replies.is_empty() && !client_manager.queue_to(&peer_addr, &replies)? {
+                        warn!("Failed to queue Telnet reply for client {}", peer_addr);
+                    }
+                    if data.is_empty() {
+                        continue;
+                    }
+                    // 其他客户端独占UART时丢弃数据，Telnet流中无法插入错误信息
+                    if let Some(holder) = client_manager.locked_by_other(&peer_addr) {
+                        debug!(
+                            "Dropping {} bytes from RFC 2217 client {}: UART locked by {}",
+                            data.len(),
+                            peer_addr,
+                            holder
+                        );
+                    } else if !context.uart_manager.is_bridge_enabled() {
+                        debug!("Dropping {} bytes from RFC 2217 client {}: bridge paused", data.len(), peer_addr);
+                    } else if let Err(e) = context.send_to_uart(&peer_addr, &data) {
+                        error!("Error sending data to UART: {}", e);
+                    }
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    _ => {
+                        info!("RFC 2217 client {} connection lost: {}", peer_addr, e);
+                        break;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+}
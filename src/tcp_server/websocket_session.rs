@@ -0,0 +1,170 @@
+//! WebSocket sessions
+//!
+//! Serves browser-based terminals on the WebSocket port (see `websocket`).
+
+use log::{debug, error, info, warn};
+use std::io::{ErrorKind, Read};
+use std::net::{Shutdown, SocketAddr, TcpStream};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::TcpServerConfig;
+use crate::diagnostics;
+use crate::error::{Error, Result};
+use crate::tcp_client_manager::{ClientProtocol, StreamReader, TcpClientManager};
+use crate::time::Stopwatch;
+use crate::websocket::{self, FrameDecoder, Message};
+
+use super::{CommandContext, Registration, TcpServer};
+
+/// Time in milliseconds a closing WebSocket client gets to receive its close frame
+const WEBSOCKET_CLOSE_GRACE_MS: u64 = 1000;
+
+impl TcpServer {
+    /// Handle a WebSocket connection
+    ///
+    /// Pongs and close frames go through the client's outbound queue, behind the
+    /// UART data already framed for the client by `broadcast`.
+    pub(super) fn handle_websocket_client(
+        mut stream: TcpStream,
+        client_manager: Arc<TcpClientManager>,
+        context: CommandContext,
+        config: TcpServerConfig,
+        shutdown: Arc<AtomicBool>,
+    ) -> Result<()> {
+        let _tracker = diagnostics::track_thread("websocket_client");
+        let peer_addr = stream
+            .peer_addr()
+            .map_err(|e| Error::tcp_caused("Failed to get peer address", e))?;
+        let leftover = match websocket::accept(&mut stream) {
+            Ok(leftover) => leftover,
+            Err(e) => {
+                warn!("WebSocket handshake with {} failed: {}", peer_addr, e);
+                let _ = stream.shutdown(Shutdown::Both);
+                return Ok(());
+            }
+        };
+        info!("New WebSocket client connected: {}", peer_addr);
+
+        if let Err(e) = stream.set_nonblocking(true) {
+            error!("Failed to set non-blocking mode for client {}: {}", peer_addr, e);
+        }
+        if let Err(e) = stream.set_nodelay(true) {
+            error!("Failed to set TCP_NODELAY for client {}: {}", peer_addr, e);
+        }
+        Self::enable_keepalive(&stream, &config, &peer_addr);
+
+        let stream_arc = Arc::new(Mutex::new(stream));
+        client_manager.register_client(peer_addr);
+        let mut reader = StreamReader::new(&stream_arc)?;
+        let conn_id = client_manager.add_client(peer_addr, Arc::clone(&stream_arc))?;
+        let _registration = Registration { client_manager: &client_manager, peer_addr, conn_id };
+        let traffic = client_manager.traffic_counter(&peer_addr)?;
+        client_manager.set_protocol(&peer_addr, ClientProtocol::WebSocket)?;
+
+        let mut decoder = FrameDecoder::new(websocket::MAX_MESSAGE_BYTES);
+        let mut buffer = vec![0; config.buffer_size];
+        let mut open = Self::websocket_receive(&leftover, &mut decoder, &context, &client_manager, &peer_addr)?;
+
+        while open {
+            // 服务器停止时发送关闭帧
+            if shutdown.load(Ordering::SeqCst) {
+                info!("Closing client {}: server is stopping", peer_addr);
+                Self::close_websocket(&client_manager, &peer_addr, websocket::CLOSE_GOING_AWAY, "Server is shutting down");
+                break;
+            }
+
+            // 读取时不持有流锁，写线程可以同时写出排队的数据
+            match reader.read(&mut buffer) {
+                Ok(0) => {
+                    info!("WebSocket client {} disconnected", peer_addr);
+                    break;
+                }
+                Ok(n) => {
+                    traffic.record_received(n);
+                    open = Self::websocket_receive(&buffer[..n], &mut decoder, &context, &client_manager, &peer_addr)?;
+                }
+                Err(e) => match e.kind() {
+                    ErrorKind::WouldBlock | ErrorKind::TimedOut | ErrorKind::Interrupted => {
+                        thread::sleep(Duration::from_millis(1));
+                    }
+                    _ => {
+                        info!("WebSocket client {} connection lost: {}", peer_addr, e);
+                        break;
+                    }
+                },
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Handle bytes received from a WebSocket client
+    ///
+    /// Returns false once the connection is closing.
+    fn websocket_receive(
+        input: &[u8],
+        decoder: &mut FrameDecoder,
+        context: &CommandContext,
+        client_manager: &TcpClientManager,
+        peer_addr: &SocketAddr,
+    ) -> Result<bool> {
+        let mut messages = Vec::new();
+        let decoded = decoder.decode(input, &mut messages);
+
+        for message in messages {
+            match message {
+                Message::Data(data) => {
+                    // 其他客户端独占UART时丢弃数据
+                    if let Some(holder) = client_manager.locked_by_other(peer_addr) {
+                        debug!(
+                            "Dropping {} bytes from WebSocket client {}: UART locked by {}",
+                            data.len(),
+                            peer_addr,
+                            holder
+                        );
+                    } else if !context.uart_manager.is_bridge_enabled() {
+                        debug!("Dropping {} bytes from WebSocket client {}: bridge paused", data.len(), peer_addr);
+                    } else if let Err(e) = context.send_to_uart(peer_addr, &data) {
+                        error!("Error sending data to UART: {}", e);
+                    }
+                }
+                Message::Ping(payload) => {
+                    client_manager.queue_to(peer_addr, &websocket::frame(websocket::OPCODE_PONG, &payload))?;
+                }
+                Message::Pong => {}
+                Message::Close(code) => {
+                    info!("WebSocket client {} closed the connection (code {:?})", peer_addr, code);
+                    Self::close_websocket(client_manager, peer_addr, websocket::CLOSE_NORMAL, "");
+                    return Ok(false);
+                }
+            }
+        }
+
+        if let Err(code) = decoded {
+            let reason = match code {
+                websocket::CLOSE_TOO_BIG => "Message too big",
+                _ => "Protocol error",
+            };
+            warn!("Closing WebSocket client {}: {}", peer_addr, reason);
+            Self::close_websocket(client_manager, peer_addr, code, reason);
+            return Ok(false);
+        }
+        Ok(true)
+    }
+
+    /// Queue a close frame for a WebSocket client and give it time to be written
+    fn close_websocket(client_manager: &TcpClientManager, peer_addr: &SocketAddr, code: u16, reason: &str) {
+        if !matches!(client_manager.queue_to(peer_addr, &websocket::close_frame(code, reason)), Ok(true)) {
+            return;
+        }
+        let waited = Stopwatch::start();
+        while !waited.has_elapsed(Duration::from_millis(WEBSOCKET_CLOSE_GRACE_MS))
+            && client_manager.queue_len(peer_addr).is_ok_and(|len| len > 0)
+        {
+            thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Time utilities module
+//!
+//! This module provides the monotonic time helpers shared by the bridge components:
+//! the boot instant, the system uptime and a small stopwatch for measuring idle
+//! periods and rates.
+//!
+//! All helpers read the clock through [`now`], which tests can fast-forward with
+//! `advance` so timeouts can be exercised without really sleeping.
+
+#[cfg(test)]
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+/// Instant captured the first time the clock is read (call early in `main`)
+static BOOT_INSTANT: OnceLock<Instant> = OnceLock::new();
+
+/// Offset added to the real clock by `advance`, in microseconds
+#[cfg(test)]
+static OFFSET_US: AtomicU64 = AtomicU64::new(0);
+
+/// Get the instant the application started
+pub fn boot_instant() -> Instant {
+    *BOOT_INSTANT.get_or_init(Instant::now)
+}
+
+/// Get the current instant, including any offset applied with `advance` in tests
+pub fn now() -> Instant {
+    // 确保启动时间早于任何返回的时间点
+    boot_instant();
+    let now = Instant::now();
+    #[cfg(test)]
+    let now = now + Duration::from_micros(OFFSET_US.load(Ordering::Relaxed));
+    now
+}
+
+/// Get the time elapsed since the application started
+pub fn uptime() -> Duration {
+    now().saturating_duration_since(boot_instant())
+}
+
+/// Move the clock forward without sleeping, to trigger timeouts in tests
+#[cfg(test)]
+pub(crate) fn advance(by: Duration) {
+    OFFSET_US.fetch_add(by.as_micros() as u64, Ordering::Relaxed);
+}
+
+/// Serialize tests that advance the clock or depend on it standing still
+///
+/// The offset is shared by all tests of the process, so a test advancing it could
+/// otherwise expire the timers of a test running alongside.
+#[cfg(test)]
+pub(crate) fn lock_clock() -> std::sync::MutexGuard<'static, ()> {
+    static CLOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    CLOCK.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Format a duration as a human-readable breakdown like "1d 02h 03m 04s"
+pub fn format_duration(duration: Duration) -> String {
+    let total = duration.as_secs();
+    let days = total / 86_400;
+    let hours = (total % 86_400) / 3_600;
+    let minutes = (total % 3_600) / 60;
+    let seconds = total % 60;
+    format!("{}d {:02}h {:02}m {:02}s", days, hours, minutes, seconds)
+}
+
+/// Stopwatch measuring the time since it was started or last restarted
+#[derive(Debug, Clone, Copy)]
+pub struct Stopwatch {
+    /// Instant the stopwatch was (re)started
+    start: Instant,
+}
+
+impl Stopwatch {
+    /// Create a stopwatch started now
+    pub fn start() -> Self {
+        Self { start: now() }
+    }
+
+    /// Restart the stopwatch from now
+    pub fn restart(&mut self) {
+        self.start = now();
+    }
+
+    /// Get the time elapsed since the stopwatch was started
+    pub fn elapsed(&self) -> Duration {
+        now().saturating_duration_since(self.start)
+    }
+
+    /// Check whether at least `duration` has elapsed
+    pub fn has_elapsed(&self, duration: Duration) -> bool {
+        self.elapsed() >= duration
+    }
+}
+
+impl Default for Stopwatch {
+    fn default() -> Self {
+        Self::start()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn durations_are_broken_down() {
+        assert_eq!(format_duration(Duration::ZERO), "0d 00h 00m 00s");
+        assert_eq!(format_duration(Duration::from_millis(59_999)), "0d 00h 00m 59s");
+        assert_eq!(format_duration(Duration::from_secs(93_784)), "1d 02h 03m 04s");
+        assert_eq!(format_duration(Duration::from_secs(400 * 86_400 + 3_599)), "400d 00h 59m 59s");
+    }
+
+    #[test]
+    fn stopwatch_follows_the_clock() {
+        let _clock = lock_clock();
+        let mut stopwatch = Stopwatch::start();
+        assert!(!stopwatch.has_elapsed(Duration::from_secs(5)));
+
+        advance(Duration::from_secs(5));
+        assert!(stopwatch.has_elapsed(Duration::from_secs(5)));
+        assert!(stopwatch.elapsed() >= Duration::from_secs(5));
+
+        stopwatch.restart();
+        assert!(stopwatch.elapsed() < Duration::from_secs(5));
+        assert!(Stopwatch::default().elapsed() < Duration::from_secs(5));
+    }
+
+    #[test]
+    fn uptime_counts_from_boot() {
+        let _clock = lock_clock();
+        let before = uptime();
+        assert!(now() >= boot_instant());
+
+        advance(Duration::from_secs(60));
+        let after = uptime();
+        assert!(after >= before + Duration::from_secs(60), "{:?} -> {:?}", before, after);
+    }
+}
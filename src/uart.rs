@@ -2,340 +2,3478 @@
 //!
 //! This module provides functionality for UART communication and forwarding data between
 //! UART and TCP clients.
+//!
+//! The serial port is kept as a `SerialPort` trait object. On the device it is the
+//! ESP-IDF UART driver, but the queueing, framing and forwarding logic only relies
+//! on the trait, so it also works with e.g. an in-memory pipe off the device.
+//! The host build only has `UartManager::with_port`, without RS485 direction control
+//! or event-driven receive.
+//!
+//! To check that a configuration keeps up with a baudrate, e.g. 921600, stream a
+//! known amount of data into the UART for a minute while a TCP client counts what
+//! it receives. The byte counts of AT+STATS and the client must match, and the
+//! overflow counter of AT+UARTERR must stay 0; otherwise raise
+//! `UartConfig::driver_rx_buffer_size`.
 
-use esp_idf_hal::gpio;
-use esp_idf_hal::uart::{UartDriver, config};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::gpio::{self, PinDriver};
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::uart::{Uart, UartDriver, config};
+#[cfg(target_os = "espidf")]
 use esp_idf_hal::prelude::*;
-use esp_idf_hal::delay::BLOCK;
+#[cfg(target_os = "espidf")]
+use esp_idf_hal::delay::{Ets, TickType, BLOCK};
+#[cfg(target_os = "espidf")]
 use esp_idf_hal::peripheral::Peripheral;
-use log::{info, error, trace, warn};
-use std::sync::{Arc, Mutex};
+use log::{debug, info, error, trace, warn};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex, MutexGuard, OnceLock};
 use std::thread;
 use std::time::Duration;
 
-use crate::config::UartConfig;
+use crate::config::{FlowControl, PacketCodec, SerialFormat, TcpToUartEol, UartConfig, UartToTcpEol};
+#[cfg(target_os = "espidf")]
+use crate::config::{Parity, StopBits};
+use crate::diagnostics;
+use crate::eol::EolState;
 use crate::error::{Error, Result};
+use crate::packet_codec::{self, PacketDecoder};
+use crate::self_test::TestReport;
 use crate::storage::StorageManager;
 use crate::tcp_client_manager::TcpClientManager;
+use crate::udp_bridge::UdpPeerManager;
+use crate::time::Stopwatch;
+use crate::watchdog;
 
-/// UART Manager
+/// Hard limit for a UART reconfiguration window in milliseconds
 ///
-/// Manages UART communication and provides methods for sending and receiving data.
-pub struct UartManager {
-    /// UART driver
-    uart: Mutex<UartDriver<'static>>,
-    /// UART configuration
-    config: UartConfig,
-    /// Storage manager for persistent configuration
-    storage: Option<Mutex<StorageManager>>,
-}
+/// Covers acquiring the UART and draining the TX FIFO, so a failed reconfiguration
+/// cannot stall the bridge.
+const RECONFIG_TIMEOUT_MS: u64 = 200;
 
-impl UartManager {
-    /// Create a new UART manager with the given configuration
-    pub fn new(
-        uart: impl Peripheral<P = esp_idf_hal::uart::UART1> + 'static,
-        tx_pin: impl Peripheral<P = impl gpio::OutputPin> + 'static,
-        rx_pin: impl Peripheral<P = impl gpio::InputPin> + 'static,
-        mut config: UartConfig,
-    ) -> Result<Self> {
-        // Try to initialize storage manager
-        let storage = match StorageManager::new() {
-            Ok(storage) => {
-                // Try to read baudrate from flash
-                if let Some(baudrate) = storage.read_baudrate() {
-                    // Check if the baudrate is valid
-                    if Self::is_valid_baudrate(baudrate) {
-                        // Update config with the baudrate from flash
-                        info!("Using baudrate {} from flash", baudrate);
-                        config.baudrate = baudrate;
-                    } else {
-                        warn!("Invalid baudrate {} read from flash, using default", baudrate);
-                    }
-                } else {
-                    info!("No baudrate found in flash, using default: {}", config.baudrate);
-                }
-                Some(Mutex::new(storage))
-            },
-            Err(e) => {
-                warn!("Failed to initialize storage manager: {}, baudrate will not be persisted", e);
-                None
-            }
-        };
+/// Highest GPIO number on the ESP32-C3
+pub(crate) const MAX_GPIO: i32 = 21;
 
-        // Configure UART
-        let uart_config = config::Config::new().baudrate(Hertz(config.baudrate));
+/// GPIOs wired to the SPI flash on most ESP32-C3 modules
+pub(crate) const FLASH_GPIOS: std::ops::RangeInclusive<i32> = 12..=17;
 
-        // Create UART driver
-        let uart = UartDriver::new(
-            uart,
-            tx_pin,
-            rx_pin,
-            Option::<gpio::Gpio0>::None, // RTS pin (not used)
-            Option::<gpio::Gpio1>::None, // CTS pin (not used)
-            &uart_config,
-        ).map_err(|e| Error::UartError(format!("Failed to create UART driver: {}", e)))?;
+/// Snapshot of the UART traffic counters
+///
+/// Counters wrap around on overflow instead of panicking.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UartStats {
+    /// Bytes written to the UART (TCP -> UART)
+    pub bytes_sent_to_uart: u64,
+    /// Bytes read from the UART (UART -> TCP)
+    pub bytes_received_from_uart: u64,
+    /// Times reading the UART was paused because the TCP clients fell behind
+    pub flow_pauses: u64,
+    /// Times reading the UART resumed after such a pause
+    pub flow_resumes: u64,
+    /// UART packets dropped by the packet codec as malformed or too long
+    pub packet_errors: u64,
+}
 
-        info!("UART initialized with baudrate: {}", config.baudrate);
+/// Snapshot of the receive errors reported by the UART driver
+///
+/// Only counted with event-driven receive (`UartConfig::event_driven_rx`), as the
+/// polling loop never sees the driver events.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct UartErrorCounters {
+    /// Bytes received without a valid stop bit, usually a baudrate mismatch
+    pub framing: u32,
+    /// Bytes received with a wrong parity bit
+    pub parity: u32,
+    /// Times the hardware FIFO or the driver's receive buffer overflowed
+    pub overflow: u32,
+    /// Break conditions, the RX line held low for longer than a character
+    pub breaks: u32,
+}
 
-        Ok(Self {
-            uart: Mutex::new(uart),
-            config,
-            storage,
-        })
+impl UartErrorCounters {
+    /// Check whether no error has been counted
+    pub fn is_zero(&self) -> bool {
+        *self == Self::default()
     }
+}
 
-    /// Send data to UART
-    /// Optimized for low latency
-    pub fn send_data(&self, data: &[u8]) -> Result<()> {
-        // 如果没有数据，直接返回
-        if data.is_empty() {
-            return Ok(());
-        }
+impl fmt::Display for UartErrorCounters {
+    /// Formats as "framing 0, parity 0, overflow 0, break 0"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "framing {}, parity {}, overflow {}, break {}",
+            self.framing, self.parity, self.overflow, self.breaks
+        )
+    }
+}
 
-        // 尽量减少锁的持有时间
-        {
-            let uart = self.uart.lock().map_err(|_| Error::UartError("Failed to lock UART".to_string()))?;
-            uart.write(data).map_err(|e| Error::UartError(format!("Failed to write to UART: {}", e)))?;
-        }
+/// Size of the ESP32-C3 UART hardware FIFO in bytes
+#[cfg(target_os = "espidf")]
+const UART_HW_FIFO_LEN: usize = 128;
 
-        // 只在trace级别记录详细日志
-        if log::log_enabled!(log::Level::Trace) {
-            trace!("UART sent {} bytes", data.len());
-        }
+/// Bytes in the hardware FIFO at which RTS is raised or XOFF is sent
+#[cfg(target_os = "espidf")]
+const FLOW_CONTROL_STOP_LEVEL: u8 = 100;
+
+/// Bytes in the hardware FIFO at which XON is sent again
+#[cfg(target_os = "espidf")]
+const FLOW_CONTROL_RESUME_LEVEL: u8 = 32;
+
+/// Milliseconds between checks of the client backlog while reading is paused
+const BACKPRESSURE_CHECK_MS: u64 = 10;
+
+/// Longest time in milliseconds the forwarding thread blocks waiting for a receive event
+///
+/// After each wait the receive buffer is drained even without an event, so data
+/// whose event was swallowed during a reconfiguration is not held back for long.
+const RX_EVENT_WAIT_MS: u64 = 100;
 
+/// Raw handle of the UART driver's event queue
+///
+/// The queue is owned by the driver in `UartManager::uart` and lives as long as
+/// the manager, so waiting on it does not require the UART lock.
+#[cfg(target_os = "espidf")]
+struct RxEventQueue(esp_idf_sys::QueueHandle_t);
+
+// FreeRTOS queues can be used from any task
+#[cfg(target_os = "espidf")]
+unsafe impl Send for RxEventQueue {}
+#[cfg(target_os = "espidf")]
+unsafe impl Sync for RxEventQueue {}
+
+/// The host build has no driver event queue
+#[cfg(not(target_os = "espidf"))]
+enum RxEventQueue {}
+
+/// How the RS485 driver-enable (DE/RE) pin is controlled (never in the host build)
+enum Rs485Mode {
+    /// The ESP-IDF half-duplex mode drives the pin as RTS
+    #[cfg(target_os = "espidf")]
+    Native,
+    /// The pin is raised around each write by `write_frame`
+    #[cfg(target_os = "espidf")]
+    Manual(Mutex<PinDriver<'static, gpio::AnyOutputPin, gpio::Output>>),
+}
+
+/// Data queued while the UART is being reconfigured
+struct PendingTx {
+    /// Queued chunks, written in order once the reconfiguration is done
+    chunks: VecDeque<Vec<u8>>,
+    /// Total number of queued bytes
+    bytes: usize,
+}
+
+impl PendingTx {
+    /// Queue a chunk, refusing it whole if the queue would exceed `limit` bytes
+    fn push(&mut self, data: &[u8], limit: usize) -> Result<()> {
+        if self.bytes + data.len() > limit {
+            return Err(Error::uart("tx queue full, dropping data"));
+        }
+        self.bytes += data.len();
+        self.chunks.push_back(data.to_vec());
         Ok(())
     }
+}
 
-    /// Receive data from UART (non-blocking)
-    /// Optimized for low latency
-    pub fn receive_data(&self, buffer: &mut [u8]) -> Result<usize> {
-        // 尽量减少锁的持有时间
-        let result = {
-            let uart = self.uart.lock().map_err(|_| Error::UartError("Failed to lock UART".to_string()))?;
-            match uart.read(buffer, 0) {
-                Ok(len) => Ok(len),
-                Err(e) => {
-                    // 检查错误类型，如果是超时错误，不记录
-                    // 超时错误通常意味着没有数据可读
-                    let error_string = format!("{:?}", e);
-                    if error_string.contains("TIMEOUT") {
-                        // 返回0表示没有数据
-                        Ok(0)
-                    } else {
-                        // 只记录非超时错误
-                        Err(Error::UartError(format!("Failed to read from UART: {}", e)))
-                    }
-                }
-            }
-        };
+/// Marks a UART reconfiguration in progress
+///
+/// While the window is open, `send_data` queues data and `receive_data` pauses.
+/// The window is closed when dropped, even if the reconfiguration fails.
+struct ReconfigWindow<'a> {
+    manager: &'a UartManager,
+}
 
-        // 只在出错时记录日志，减少日志开销
-        if let Err(ref e) = result {
-            error!("UART receive error: {}", e);
-        }
+impl<'a> ReconfigWindow<'a> {
+    /// Open a reconfiguration window
+    fn open(manager: &'a UartManager) -> Result<Self> {
+        let _pending = manager.pending_tx.lock().map_err(|_| Error::uart("Failed to lock TX queue"))?;
+        manager.reconfiguring.store(true, Ordering::Release);
+        Ok(Self { manager })
+    }
 
+    /// Write the queued data at the new settings and close the window
+    fn finish(self, uart: &dyn SerialPort) -> Result<()> {
+        let mut pending = self.manager.pending_tx.lock().map_err(|_| Error::uart("Failed to lock TX queue"))?;
+        let result = self.manager.write_pending(uart, &mut pending);
+        self.manager.reconfiguring.store(false, Ordering::Release);
         result
     }
+}
 
-    /// Receive data from UART (blocking)
-    /// Optimized for low latency
-    pub fn receive_data_blocking(&self, buffer: &mut [u8]) -> Result<usize> {
-        // 尽量减少锁的持有时间
-        let result = {
-            let uart = self.uart.lock().map_err(|_| Error::UartError("Failed to lock UART".to_string()))?;
-            match uart.read(buffer, BLOCK) {
-                Ok(len) => Ok(len),
-                Err(e) => {
-                    // 即使在阻塞模式下，也可能出现超时
-                    let error_string = format!("{:?}", e);
-                    if error_string.contains("TIMEOUT") {
-                        Ok(0)
-                    } else {
-                        Err(Error::UartError(format!("Failed to read from UART: {}", e)))
-                    }
-                }
-            }
-        };
+impl Drop for ReconfigWindow<'_> {
+    fn drop(&mut self) {
+        // 出错时也要关闭窗口；残留的数据由下一次send_data写出
+        let _pending = self.manager.pending_tx.lock();
+        self.manager.reconfiguring.store(false, Ordering::Release);
+    }
+}
 
-        // 只在出错时记录日志，减少日志开销
-        if let Err(ref e) = result {
-            error!("UART receive error: {}", e);
-        }
+/// Outcome of a baudrate detection
+#[derive(Debug, Clone)]
+pub struct AutoBaudReport {
+    /// Each tried baudrate with its score, None if nothing readable was received at that rate
+    pub scores: Vec<(u32, Option<u32>)>,
+    /// Best scoring baudrate, None if nothing readable was received at any rate
+    pub best: Option<u32>,
+}
 
-        result
+/// Score a sample received at a candidate baudrate, from 0 to 1000
+///
+/// Data read at the wrong rate comes out as random bytes and framing or parity
+/// errors, so the score is the share of printable ASCII (including CR, LF and TAB)
+/// among the bytes and `errors`. Returns None for a sample without a single
+/// printable byte, such as an empty one or one made only of errors, so such a rate
+/// is never picked.
+pub fn score_baud_sample(data: &[u8], errors: u32) -> Option<u32> {
+    let total = data.len() as u64 + u64::from(errors);
+    let printable = data
+        .iter()
+        .filter(|&&b| matches!(b, 0x20..=0x7e | b'\r' | b'\n' | b'\t'))
+        .count() as u64;
+    if printable == 0 {
+        return None;
     }
+    Some((printable * 1000 / total) as u32)
+}
 
-    /// 修改UART波特率
-    ///
-    /// 这个方法允许动态修改UART的波特率
-    pub fn set_baudrate(&self, baudrate: u32) -> Result<()> {
-        // 验证波特率是否有效
-        if !Self::is_valid_baudrate(baudrate) {
-            return Err(Error::UartError(format!("Invalid baudrate: {}", baudrate)));
+/// Outcome of a UART read that did not fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UartRead {
+    /// This many bytes were read
+    Data(usize),
+    /// No data arrived before the timeout
+    Timeout,
+}
+
+impl UartRead {
+    /// Number of bytes read, 0 on timeout
+    pub fn bytes_read(&self) -> usize {
+        match self {
+            UartRead::Data(len) => *len,
+            UartRead::Timeout => 0,
         }
+    }
+}
 
-        // 锁定UART进行重新配置
-        let uart_guard = self.uart.lock().map_err(|_| Error::UartError("Failed to lock UART".to_string()))?;
+/// Serial port driven by `UartManager`
+pub trait SerialPort: Send {
+    /// Write data, returning the number of bytes accepted
+    fn write(&self, data: &[u8]) -> Result<usize>;
 
-        // 创建新的UART配置
-        // 注意：当前不使用这个配置，但保留代码以便将来实现
-        let _uart_config = config::Config::new().baudrate(Hertz(baudrate));
+    /// Read into `buffer`, waiting up to `timeout` for data (None waits until data arrives)
+    fn read(&self, buffer: &mut [u8], timeout: Option<Duration>) -> Result<UartRead>;
 
-        // 应用新的波特率设置
-        // 尝试直接重新配置UART
-        // 在ESP32上，我们可以尝试使用低级API来设置波特率
-        // 这是不安全的操作，需要使用unsafe块
-        // 使用UART1而不是UART0，因为我们在初始化时使用的是UART1
-        let result = unsafe {
-            esp_idf_sys::uart_set_baudrate(1, baudrate)
+    /// Wait up to `timeout` until all written data has been sent
+    fn wait_tx_done(&self, timeout: Duration) -> Result<()>;
+
+    /// Discard received data that was not read yet
+    fn flush_input(&self) -> Result<()>;
+
+    /// Change the baudrate, returning the baudrate actually achieved
+    fn set_baudrate(&self, baudrate: u32) -> Result<u32>;
+
+    /// Change data bits, parity and stop bits
+    fn set_format(&self, format: &SerialFormat) -> Result<()>;
+}
+
+#[cfg(target_os = "espidf")]
+impl SerialPort for UartDriver<'static> {
+    fn write(&self, data: &[u8]) -> Result<usize> {
+        UartDriver::write(self, data).map_err(|e| Error::uart_caused("Failed to write to UART", e))
+    }
+
+    fn read(&self, buffer: &mut [u8], timeout: Option<Duration>) -> Result<UartRead> {
+        let ticks = match timeout {
+            None => BLOCK,
+            Some(timeout) if timeout.is_zero() => 0,
+            Some(timeout) => TickType::new_millis(timeout.as_millis() as u64).ticks().max(1),
         };
+        match UartDriver::read(self, buffer, ticks) {
+            Ok(len) => Ok(UartRead::Data(len)),
+            // 超时意味着没有数据可读，不是错误
+            Err(e) if e.code() == esp_idf_sys::ESP_ERR_TIMEOUT => Ok(UartRead::Timeout),
+            Err(e) => Err(Error::uart_caused("Failed to read from UART", e)),
+        }
+    }
 
-        match result {
-            0 => {
-                info!("Successfully changed UART baudrate to {} at runtime", baudrate);
-            },
-            err => {
-                // 如果失败，我们仍然更新内部配置
-                warn!("Failed to change UART baudrate at runtime (error code: {}). \
-                      Baudrate change will take full effect after device restart", err);
-            }
+    fn wait_tx_done(&self, timeout: Duration) -> Result<()> {
+        UartDriver::wait_tx_done(self, TickType::new_millis(timeout.as_millis() as u64).ticks())
+            .map_err(|e| Error::uart_caused("Failed to drain UART TX", e))
+    }
+
+    fn flush_input(&self) -> Result<()> {
+        let err = unsafe { esp_idf_sys::uart_flush_input(self.port()) };
+        if err != esp_idf_sys::ESP_OK {
+            return Err(Error::uart(format!("Failed to flush UART input (error code: {})", err)));
         }
+        Ok(())
+    }
 
-        // 更新内部配置
-        let mut config = self.config.clone();
-        config.baudrate = baudrate;
-        // 修改结构体内部字段
+    fn set_baudrate(&self, baudrate: u32) -> Result<u32> {
+        // 通过底层API修改波特率，并读回时钟分频实际得到的波特率
+        let err = unsafe { esp_idf_sys::uart_set_baudrate(self.port(), baudrate) };
+        if err != esp_idf_sys::ESP_OK {
+            return Err(Error::uart(format!("Failed to set UART baudrate (error code: {})", err)));
+        }
+        let mut achieved = baudrate;
         unsafe {
-            let config_ptr = &self.config as *const UartConfig as *mut UartConfig;
-            (*config_ptr).baudrate = baudrate;
+            esp_idf_sys::uart_get_baudrate(self.port(), &mut achieved);
         }
+        Ok(achieved)
+    }
 
-        // 保存波特率到flash
-        if let Some(storage_mutex) = &self.storage {
-            match storage_mutex.lock() {
-                Ok(mut storage) => {
-                    if let Err(e) = storage.save_baudrate(baudrate) {
-                        warn!("Failed to save baudrate to flash: {}", e);
-                    } else {
-                        info!("Baudrate {} saved to flash", baudrate);
-                    }
-                },
-                Err(e) => {
-                    warn!("Failed to lock storage manager: {}, baudrate will not be persisted", e);
-                }
-            }
+    fn set_format(&self, format: &SerialFormat) -> Result<()> {
+        self.change_data_bits(UartManager::hal_data_bits(format.data_bits))
+            .map_err(|e| Error::uart_caused("Failed to set data bits", e))?;
+        self.change_parity(UartManager::hal_parity(format.parity))
+            .map_err(|e| Error::uart_caused("Failed to set parity", e))?;
+        self.change_stop_bits(UartManager::hal_stop_bits(format.stop_bits))
+            .map_err(|e| Error::uart_caused("Failed to set stop bits", e))?;
+        Ok(())
+    }
+}
+
+/// Largest frame size accepted for UART receive framing
+pub const MAX_FRAME_BYTES: usize = 4096;
+
+/// Longest frame delimiter in bytes
+pub const MAX_DELIMITER_LEN: usize = 8;
+
+/// Standard baudrates, tried in this order by `detect_baudrate`
+///
+/// With `UartConfig::strict_baudrates` set, these are the only accepted rates.
+pub const SUPPORTED_BAUDRATES: [u32; 9] = [
+    9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1500000
+];
+
+/// Lowest baudrate accepted
+pub const MIN_BAUDRATE: u32 = 300;
+
+/// Highest baudrate accepted
+pub const MAX_BAUDRATE: u32 = 2_000_000;
+
+/// Largest deviation of the achieved from the requested baudrate, in per mille
+const BAUDRATE_TOLERANCE_PERMILLE: u64 = 20;
+
+/// Time the loopback test waits beyond the transfer time of its pattern
+const LOOPBACK_MARGIN_MS: u64 = 100;
+
+/// Bytes sampled at most at each baudrate during detection
+const AUTOBAUD_SAMPLE_BYTES: usize = 256;
+
+/// Largest UART history kept for replay to new clients
+pub const MAX_REPLAY_BYTES: usize = 16384;
+
+/// Milliseconds after which a frame without delimiter is sent when no gap is set
+const DELIMITER_FLUSH_MS: u64 = 100;
+
+/// Longest time received bytes are held back for batching, in milliseconds
+pub const MAX_BATCH_LATENCY_MS: u64 = 1000;
+
+/// Longest UART command line after the prefix; longer lines are forwarded as data
+pub const MAX_UART_COMMAND_LEN: usize = 128;
+
+/// Milliseconds a UART command line may take from its prefix to its line end
+const UART_COMMAND_TIMEOUT_MS: u64 = 500;
+
+/// Runs an AT command line received from the UART, writing the reply with `send_data`
+pub type UartCommandHandler = Box<dyn Fn(&str) + Send + Sync>;
+
+/// UART frame delimiter
+pub type Delimiter = heapless::Vec<u8, MAX_DELIMITER_LEN>;
+
+/// Line ending translation modes and the state of the UART to TCP direction
+struct LineEndings {
+    tcp_to_uart: TcpToUartEol,
+    uart_to_tcp: UartToTcpEol,
+    /// Translation state of the UART data stream
+    rx: EolState,
+}
+
+/// Most recent UART bytes broadcast, replayed to new clients
+struct ReplayBuffer {
+    /// Retained bytes, oldest first
+    data: VecDeque<u8>,
+    /// Bytes retained at most (0 disables the replay)
+    capacity: usize,
+}
+
+impl ReplayBuffer {
+    /// Append broadcast data, dropping the oldest bytes beyond the capacity
+    fn push(&mut self, data: &[u8]) {
+        if self.capacity == 0 {
+            return;
+        }
+        let data = &data[data.len().saturating_sub(self.capacity)..];
+        let excess = (self.data.len() + data.len()).saturating_sub(self.capacity);
+        self.data.drain(..excess);
+        self.data.extend(data);
+    }
+
+    /// Change the capacity, keeping the most recent bytes
+    fn resize(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        let excess = self.data.len().saturating_sub(capacity);
+        self.data.drain(..excess);
+        if capacity == 0 {
+            // 关闭后释放内存
+            self.data = VecDeque::new();
         }
+    }
+}
 
-        // 释放锁
-        drop(uart_guard);
+/// UART receive framing settings
+///
+/// With a gap set, received bytes are collected and broadcast as one frame once no
+/// new bytes arrived for `gap_ms`, or as soon as `max_bytes` have been collected.
+/// With a delimiter set, a frame also ends right after the delimiter, so each
+/// broadcast carries one line or frame; an unterminated frame is sent after the
+/// gap, or after 100 ms if no gap is set.
+///
+/// Without gap or delimiter, batching can coalesce received bytes into fewer,
+/// larger broadcasts: a batch is sent once it holds `batch_bytes`, or
+/// `batch_ms` after its first byte arrived, whichever comes first.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Framing {
+    /// Quiet time in milliseconds that ends a frame (0 forwards data as it is read)
+    pub gap_ms: u64,
+    /// Largest frame in bytes
+    pub max_bytes: usize,
+    /// Byte sequence that ends a frame
+    pub delimiter: Option<Delimiter>,
+    /// Largest batch in bytes
+    pub batch_bytes: usize,
+    /// Longest time the first byte of a batch is held back (0 disables batching)
+    pub batch_ms: u64,
+}
 
-        info!("UART baudrate changed to: {}", baudrate);
-        Ok(())
+impl Framing {
+    /// Whether received data is collected into frames or batches
+    pub fn is_enabled(&self) -> bool {
+        self.gap_ms > 0 || self.delimiter.is_some() || self.batch_ms > 0
     }
 
-    /// 检查波特率是否有效
-    fn is_valid_baudrate(baudrate: u32) -> bool {
-        // 支持的波特率列表
-        const VALID_BAUDRATES: [u32; 9] = [
-            9600, 19200, 38400, 57600, 115200, 230400, 460800, 921600, 1500000
-        ];
+    /// Whether received data is batched (gap and delimiter framing take precedence)
+    pub fn is_batching(&self) -> bool {
+        self.batch_ms > 0 && self.gap_ms == 0 && self.delimiter.is_none()
+    }
 
-        VALID_BAUDRATES.contains(&baudrate)
+    /// Size at which a collected frame or batch is sent
+    fn limit(&self) -> usize {
+        if self.is_batching() {
+            self.batch_bytes.max(1)
+        } else {
+            self.max_bytes.max(1)
+        }
     }
 
-    /// 获取当前波特率
-    pub fn get_baudrate(&self) -> u32 {
-        self.config.baudrate
+    /// Quiet time after which a collected frame is sent
+    fn flush_after(&self) -> Duration {
+        match (self.gap_ms, &self.delimiter) {
+            (0, Some(_)) => Duration::from_millis(DELIMITER_FLUSH_MS),
+            (gap_ms, _) => Duration::from_millis(gap_ms),
+        }
+    }
+}
+
+impl fmt::Display for Framing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if !self.is_enabled() {
+            return write!(f, "off");
+        }
+        if self.is_batching() {
+            return write!(f, "batch {} bytes within {} ms", self.batch_bytes, self.batch_ms);
+        }
+        if let Some(delimiter) = &self.delimiter {
+            write!(f, "delimiter {}, ", format_hex(delimiter))?;
+        }
+        write!(
+            f,
+            "gap {} ms, max {} bytes",
+            self.flush_after().as_millis(),
+            self.max_bytes
+        )
     }
+}
 
-    /// Start UART forwarding service
-    ///
-    /// This method starts a thread that reads data from UART and forwards it to TCP clients.
-    /// Highly optimized for low latency.
-    pub fn start_forwarding(self_arc: Arc<Self>, client_manager: Arc<TcpClientManager>) -> Result<()> {
-        let uart_manager = Arc::clone(&self_arc);
-        let config = uart_manager.config.clone();
+/// Format bytes as upper case hex without separators, e.g. "0D0A"
+pub fn format_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02X}", b)).collect()
+}
 
-        let builder = thread::Builder::new()
-            .name("uart_forwarding".into())
-            .stack_size(4096); // 指定足够的栈大小
+/// Picks command lines out of received UART data for the forwarding thread
+///
+/// Only a line that starts with the command prefix followed by "AT" is held back.
+/// Anything else, including such a line that grows too long or does not end in
+/// time, is forwarded unchanged.
+struct CommandScanner {
+    /// Held bytes of a possible command line, starting with the prefix
+    line: Vec<u8>,
+    /// Whether the next byte starts a line
+    at_line_start: bool,
+    /// Set after a command ended with CR, so the LF of a CR LF is dropped too
+    skip_lf: bool,
+    /// Time since the first byte of `line`
+    started: Stopwatch,
+}
 
-        builder.spawn(move || {
-            unsafe {
-                esp_idf_sys::vTaskPrioritySet(
-                    esp_idf_sys::xTaskGetCurrentTaskHandle(),
-                    24       // 使用高优先级线程处理UART数据 // 优先级范围通常是 0-24，数字越大优先级越高
-                );
-            }
-            // 预分配缓冲区以避免运行时分配
-            let mut buffer = vec![0u8; config.buffer_size];
-            let poll_interval = Duration::from_millis(config.poll_interval_ms);
+impl CommandScanner {
+    fn new() -> Self {
+        Self {
+            line: Vec::new(),
+            at_line_start: true,
+            skip_lf: false,
+            started: Stopwatch::start(),
+        }
+    }
 
-            // 记录上次有数据的时间，用于自适应轮询
-            let mut last_data_time = std::time::Instant::now();
-            let mut adaptive_interval = poll_interval;
+    /// Add received data, emitting what is not part of a command line and running
+    /// each complete command line, without prefix and line end
+    fn push(&mut self, data: &[u8], prefix: &[u8], mut emit: impl FnMut(&[u8]), mut run: impl FnMut(&str)) {
+        // 连续的普通数据一次发出，start是尚未发出部分的起点
+        let mut start = 0;
+        for (index, &byte) in data.iter().enumerate() {
+            let is_line_end = byte == b'\r' || byte == b'\n';
+            if std::mem::take(&mut self.skip_lf) && byte == b'\n' {
+                start = index + 1;
+                continue;
+            }
 
-            // 检查是否有客户端的频率较低，减少不必要的检查
-            let mut check_counter = 0;
-            let check_interval = 10; // 每10次读取才检查一次客户端数量
+            if self.line.is_empty() {
+                if self.at_line_start && Self::matches_marker(prefix, 0, byte) {
+                    emit(&data[start..index]);
+                    self.line.push(byte);
+                    self.started.restart();
+                    start = index + 1;
+                } else {
+                    self.at_line_start = is_line_end;
+                }
+                continue;
+            }
 
-            loop {
-                // 定期检查是否有客户端连接
-                check_counter += 1;
-                if check_counter >= check_interval {
-                    check_counter = 0;
-                    // 如果没有客户端，可以使用更长的轮询间隔
-                    let client_count = match client_manager.client_count() {
-                        Ok(count) => count,
-                        Err(_) => 0, // 如果出错，假设没有客户端
-                    };
-                    if client_count == 0 {
-                        thread::sleep(Duration::from_millis(50)); // 更长的睡眠时间
-                        continue;
-                    }
+            // 前缀和"AT"必须完全匹配，否则按普通数据转发
+            if self.line.len() < prefix.len() + 2 {
+                if Self::matches_marker(prefix, self.line.len(), byte) {
+                    self.line.push(byte);
+                    start = index + 1;
+                } else {
+                    self.release(&mut emit);
+                    self.at_line_start = is_line_end;
+                    start = index;
                 }
+                continue;
+            }
 
-                // 使用非阻塞模式读取数据
-                match uart_manager.receive_data(&mut buffer) {
-                    Ok(len) => {
-                        if len > 0 {
-                            // 有数据时立即广播到所有TCP客户端，不做中间处理
-                            let _ = client_manager.broadcast(&buffer[0..len]); // 忽略错误，减少延迟
-
-                            // 更新最后收到数据的时间
-                            last_data_time = std::time::Instant::now();
-
-                            // 当有数据时使用最短轮询间隔，减少延迟
-                            adaptive_interval = poll_interval;
-
-                            // 只在trace级别记录详细数据
-                            if log::log_enabled!(log::Level::Trace) {
-                                trace!("UART -> TCP: {} bytes", len);
-                            }
-                        } else {
-                            // 如果长时间没有数据，可以增加轮询间隔以减少CPU使用
-                            let elapsed = last_data_time.elapsed();
-                            if elapsed > Duration::from_millis(100) {
-                                // 最多增加到5ms，保证响应性
-                                adaptive_interval = Duration::from_millis(
-                                    (config.poll_interval_ms).min(5)
-                                );
-                            }
-                        }
+            if is_line_end {
+                match std::str::from_utf8(&self.line[prefix.len()..]) {
+                    Ok(command) => {
+                        run(command);
+                        self.line.clear();
+                        self.skip_lf = byte == b'\r';
+                        start = index + 1;
                     }
                     Err(_) => {
-                        // 完全忽略错误，减少延迟
+                        self.release(&mut emit);
+                        start = index;
                     }
                 }
-
-                // 使用自适应的轮询间隔
-                thread::sleep(adaptive_interval);
+                self.at_line_start = true;
+            } else if self.line.len() >= prefix.len() + MAX_UART_COMMAND_LEN {
+                self.release(&mut emit);
+                self.at_line_start = false;
+                start = index;
+            } else {
+                self.line.push(byte);
+                start = index + 1;
             }
-        }).map_err(|e| Error::UartError(format!("Failed to spawn UART forwarding thread: {}", e)))?;
+        }
+        emit(&data[start..]);
+    }
 
-        info!("UART to TCP forwarding service started with optimized latency");
-        Ok(())
+    /// Forward a held line that did not end in time
+    fn poll(&mut self, emit: impl FnMut(&[u8])) {
+        if !self.line.is_empty() && self.started.has_elapsed(Duration::from_millis(UART_COMMAND_TIMEOUT_MS)) {
+            self.release(emit);
+            self.at_line_start = false;
+        }
+    }
+
+    /// Forward the held bytes as data
+    fn release(&mut self, mut emit: impl FnMut(&[u8])) {
+        emit(&self.line);
+        self.line.clear();
+    }
+
+    /// Check `byte` against position `position` of the prefix followed by "AT"
+    fn matches_marker(prefix: &[u8], position: usize, byte: u8) -> bool {
+        match position.checked_sub(prefix.len()) {
+            None => prefix[position] == byte,
+            Some(at) => b"AT".get(at) == Some(&byte.to_ascii_uppercase()),
+        }
     }
 }
 
-// 旧的兼容性函数已删除
+/// Pauses reading the UART for the forwarding thread while the TCP clients fall behind
+///
+/// Data left in the driver fills its receive buffer and then the hardware FIFO,
+/// where flow control holds the device back. Without flow control the UART is
+/// always read, as pausing would only move the data loss into the driver.
+///
+/// The backlog is that of the client furthest behind, as that is the one whose
+/// queue limit comes into reach; the other clients may well be keeping up.
+struct Backpressure {
+    /// Queued bytes above which reading pauses, 0 if flow control is off
+    high: usize,
+    /// Queued bytes below which reading resumes
+    low: usize,
+    /// How long a pause may last before the most backlogged client is evicted
+    stall: Duration,
+    /// Time since the pause started or the last client was evicted, None while reading
+    paused: Option<Stopwatch>,
+}
+
+impl Backpressure {
+    fn new(config: &UartConfig) -> Self {
+        let high = match config.flow_control {
+            FlowControl::None => 0,
+            _ => config.backpressure_high_watermark.max(1),
+        };
+        let mut low = config.backpressure_low_watermark;
+        if low >= high && high > 0 {
+            warn!(
+                "Backpressure low watermark {} is not below the high watermark {}, using {}",
+                low,
+                high,
+                high / 2
+            );
+            low = high / 2;
+        }
+        Self {
+            high,
+            low,
+            stall: Duration::from_millis(config.backpressure_stall_ms),
+            paused: None,
+        }
+    }
+
+    /// Check whether reading must pause, counting pauses and resumes in `uart`
+    fn should_pause(&mut self, uart: &UartManager, client_manager: &TcpClientManager) -> bool {
+        if self.high == 0 {
+            return false;
+        }
+        let queued = client_manager.max_queued_bytes().unwrap_or(0);
+        let Some(paused) = &mut self.paused else {
+            if queued <= self.high {
+                return false;
+            }
+            uart.flow_pauses.fetch_add(1, Ordering::Relaxed);
+            debug!("UART reading paused, {} bytes queued for a TCP client", queued);
+            self.paused = Some(Stopwatch::start());
+            return true;
+        };
+        if queued < self.low {
+            uart.flow_resumes.fetch_add(1, Ordering::Relaxed);
+            debug!("UART reading resumed after {} ms", paused.elapsed().as_millis());
+            self.paused = None;
+            return false;
+        }
+        if paused.has_elapsed(self.stall) {
+            // 客户端长时间不读取数据时断开它，避免串口被永久暂停
+            paused.restart();
+            if let Ok(Some((addr, queued))) = client_manager.evict_stalled() {
+                warn!("Client {} stalled UART flow control with {} bytes queued, disconnected", addr, queued);
+            }
+        }
+        true
+    }
+}
+
+/// Collects received UART bytes into frames for the forwarding thread
+struct FrameAccumulator {
+    /// Bytes of the frame being collected
+    frame: Vec<u8>,
+    /// Time since the last byte was added
+    quiet: Stopwatch,
+    /// Time since the first byte of the frame was added
+    started: Stopwatch,
+}
+
+impl FrameAccumulator {
+    fn new() -> Self {
+        Self {
+            frame: Vec::new(),
+            quiet: Stopwatch::start(),
+            started: Stopwatch::start(),
+        }
+    }
+
+    /// Add received data, emitting each frame that ends with the delimiter or
+    /// reaches the frame or batch size limit
+    fn push(&mut self, data: &[u8], framing: &Framing, mut emit: impl FnMut(&[u8])) {
+        let max_bytes = framing.limit();
+        self.quiet.restart();
+
+        let Some(delimiter) = framing.delimiter.as_deref() else {
+            let mut data = data;
+            while !data.is_empty() {
+                if self.frame.is_empty() {
+                    self.started.restart();
+                }
+                // 帧满时立即发送，剩余数据开始新的一帧
+                let take = max_bytes.saturating_sub(self.frame.len()).min(data.len());
+                self.frame.extend_from_slice(&data[..take]);
+                data = &data[take..];
+                if self.frame.len() >= max_bytes {
+                    self.flush(&mut emit);
+                }
+            }
+            return;
+        };
+
+        // 逐字节检查帧尾，分隔符跨两次读取时也能识别
+        for &byte in data {
+            if self.frame.is_empty() {
+                self.started.restart();
+            }
+            self.frame.push(byte);
+            if self.frame.ends_with(delimiter) || self.frame.len() >= max_bytes {
+                self.flush(&mut emit);
+            }
+        }
+    }
+
+    /// Emit the collected frame if the line has been quiet long enough, or the
+    /// batch has been held back long enough
+    fn poll(&mut self, framing: &Framing, emit: impl FnMut(&[u8])) {
+        if self.due_in(framing) == Some(Duration::ZERO) {
+            self.flush(emit);
+        }
+    }
+
+    /// Emit the collected frame, if any
+    fn flush(&mut self, mut emit: impl FnMut(&[u8])) {
+        if !self.frame.is_empty() {
+            emit(&self.frame);
+            self.frame.clear();
+        }
+    }
+
+    /// Time until the collected frame is due, None if nothing is collected
+    fn due_in(&self, framing: &Framing) -> Option<Duration> {
+        if self.frame.is_empty() {
+            return None;
+        }
+        // 批量发送从第一个字节开始计时，分帧则从最后一个字节开始
+        Some(if framing.is_batching() {
+            Duration::from_millis(framing.batch_ms).saturating_sub(self.started.elapsed())
+        } else {
+            framing.flush_after().saturating_sub(self.quiet.elapsed())
+        })
+    }
+}
+
+/// UART Manager
+///
+/// Manages UART communication and provides methods for sending and receiving data.
+///
+/// # Lock ordering
+///
+/// The UART manager is shared between the forwarding thread, every client thread
+/// and command processing. To stay deadlock free, locks are always taken in this order:
+///
+/// 1. a client's stream lock (`TcpClientManager`, held while writing to the client)
+/// 2. `uart` (only taken by the `uart_tx` writer thread, the forwarding thread and
+///    reconfiguration; `send_data` just enqueues and never waits for it)
+/// 3. `pending_tx`
+/// 4. `storage`
+///
+/// `replay` is held while UART data is broadcast and while a new client is added
+/// (see `attach_with_replay`), so it may be held while taking client locks; it is
+/// never taken while holding a client stream lock or `uart`.
+///
+/// `uart` is never held while locking a client stream, and `pending_tx`, `format`,
+/// `framing`, `line_endings`, `packet_rx` and `storage` are released before any
+/// other lock is taken. The RS485 DE pin lock is only taken while holding `uart`
+/// and is a leaf lock.
+///
+/// `write_data` and `ReconfigWindow::finish` hold `uart` while taking `pending_tx`;
+/// opening and dropping a `ReconfigWindow` take `pending_tx` alone. Nothing takes
+/// `uart` while holding `pending_tx`.
+///
+/// # Runtime settings
+///
+/// The serial settings live only in `baudrate` and `format`. The `UartConfig` kept
+/// in `config` is never written after `new`, and its `baudrate` and `format` fields
+/// must not be read at runtime. The baudrate is an atomic because it is read on
+/// every client connection and command, and the format is a small `Copy` value
+/// behind a leaf mutex.
+pub struct UartManager {
+    /// Serial port, the UART driver on the device
+    uart: Mutex<Box<dyn SerialPort>>,
+    /// UART configuration as of boot (see "Runtime settings" above)
+    config: UartConfig,
+    /// Current baudrate, updated at runtime by `set_baudrate`
+    baudrate: AtomicU32,
+    /// Current character format, updated at runtime by `set_serial_params`
+    format: Mutex<SerialFormat>,
+    /// Current receive framing, updated at runtime by `set_frame_gap` and `set_frame_delimiter`
+    framing: Mutex<Framing>,
+    /// Line ending translation, updated at runtime by `set_line_endings`
+    line_endings: Mutex<LineEndings>,
+    /// RS485 direction control (None for full-duplex UART)
+    rs485: Option<Rs485Mode>,
+    /// Driver event queue for event-driven receive (None when polling)
+    rx_events: Option<RxEventQueue>,
+    /// Whether a reconfiguration window is open
+    reconfiguring: AtomicBool,
+    /// Data queued from TCP while the UART is being reconfigured
+    pending_tx: Mutex<PendingTx>,
+    /// Sending side of the queue drained by the `uart_tx` writer thread
+    tx_sender: SyncSender<Vec<u8>>,
+    /// Receiving side of the queue, taken when the writer thread starts
+    tx_receiver: Mutex<Option<Receiver<Vec<u8>>>>,
+    /// Number of chunks currently in the writer queue
+    tx_queue_len: AtomicUsize,
+    /// Total bytes written to the UART (TCP -> UART)
+    bytes_sent_to_uart: AtomicU64,
+    /// Total bytes read from the UART (UART -> TCP)
+    bytes_received_from_uart: AtomicU64,
+    /// Whether data is forwarded between UART and the network (AT+BRIDGE)
+    bridge_enabled: AtomicBool,
+    /// UART bytes discarded while the bridge was paused
+    bridge_discarded: AtomicU64,
+    /// Times reading the UART was paused for backpressure
+    flow_pauses: AtomicU64,
+    /// Times reading the UART resumed after a backpressure pause
+    flow_resumes: AtomicU64,
+    /// UART packets dropped by the packet codec
+    packet_errors: AtomicU64,
+    /// Decoder of the packets received from the UART (see `UartConfig::packet_codec`)
+    packet_rx: Mutex<PacketDecoder>,
+    /// Framing and parity errors reported by the driver (event-driven receive only)
+    ///
+    /// Never reset, unlike the counters below, so autobaud can compare readings.
+    rx_errors: AtomicU32,
+    /// Framing errors since boot or the last AT+UARTERR=RESET
+    framing_errors: AtomicU32,
+    /// Parity errors since boot or the last AT+UARTERR=RESET
+    parity_errors: AtomicU32,
+    /// FIFO and receive buffer overflows since boot or the last AT+UARTERR=RESET
+    rx_overflows: AtomicU32,
+    /// Break conditions since boot or the last AT+UARTERR=RESET
+    rx_breaks: AtomicU32,
+    /// Set while `detect_baudrate` or `loopback_test` reads the UART directly;
+    /// reconfigurations and other such reads are refused meanwhile
+    sampling: AtomicBool,
+    /// Recent UART data replayed to new clients (AT+REPLAY)
+    replay: Mutex<ReplayBuffer>,
+    /// Storage manager for persistent configuration (shared with other managers)
+    storage: Option<Arc<Mutex<StorageManager>>>,
+    /// Runs command lines received from the UART (see `UartConfig::command_prefix`)
+    command_handler: OnceLock<UartCommandHandler>,
+}
+
+impl UartManager {
+    /// Create a new UART manager with the given configuration
+    ///
+    /// Settings saved in `storage` take precedence over `config`. The TX and RX
+    /// pins are taken from `config`, and `uart` must be the peripheral selected by
+    /// `config.uart_num`.
+    #[cfg(target_os = "espidf")]
+    pub fn new<U: Uart>(
+        uart: impl Peripheral<P = U> + 'static,
+        mut config: UartConfig,
+        storage: Option<Arc<Mutex<StorageManager>>>,
+    ) -> Result<Self> {
+        // 检查外设和引脚配置
+        let port = U::port();
+        if port != config.uart_num as esp_idf_sys::uart_port_t {
+            return Err(Error::uart(format!(
+                "UART{} peripheral does not match configured uart_num {}",
+                port, config.uart_num
+            )));
+        }
+        if config.tx_pin == config.rx_pin {
+            return Err(Error::uart(format!(
+                "TX and RX cannot use the same GPIO {}",
+                config.tx_pin
+            )));
+        }
+        let tx_pin = Self::gpio_pin(config.tx_pin, "TX")?;
+        let rx_pin = Self::gpio_pin(config.rx_pin, "RX")?;
+        let de_pin_num = Self::rs485_de_pin(&config)?;
+        let (rts_pin_num, cts_pin_num) = Self::flow_control_pins(&config, de_pin_num)?;
+        // RS485 DE引脚作为RTS交给驱动，以便使用原生半双工模式
+        let de_pin = de_pin_num.map(|pin| Self::gpio_pin(pin, "RS485 DE")).transpose()?;
+        let rts_pin = match rts_pin_num {
+            Some(pin) => Some(Self::gpio_pin(pin, "RTS")?),
+            None => de_pin,
+        };
+        let cts_pin = cts_pin_num.map(|pin| Self::gpio_pin(pin, "CTS")).transpose()?;
+
+        Self::load_saved_settings(&mut config, storage.as_ref());
+
+        // Configure UART
+        let mut uart_config = Self::driver_config(config.baudrate, &config.format)
+            .rx_fifo_size(Self::driver_buffer_size(config.driver_rx_buffer_size, "RX").max(UART_HW_FIFO_LEN + 1))
+            .tx_fifo_size(Self::driver_buffer_size(config.driver_tx_buffer_size, "TX"));
+        let hardware_flow = match (rts_pin_num, cts_pin_num) {
+            (Some(_), Some(_)) => config::FlowControl::CTSRTS,
+            (Some(_), None) => config::FlowControl::RTS,
+            (None, Some(_)) => config::FlowControl::CTS,
+            (None, None) => config::FlowControl::None,
+        };
+        uart_config = uart_config
+            .flow_control(hardware_flow)
+            .flow_control_rts_threshold(usize::from(FLOW_CONTROL_STOP_LEVEL));
+        if config.event_driven_rx {
+            // 安装驱动事件队列，接收数据时由中断唤醒转发线程
+            uart_config = uart_config.queue_size(config.event_queue_size);
+        }
+
+        // Create UART driver
+        let uart = UartDriver::new(
+            uart,
+            tx_pin,
+            rx_pin,
+            cts_pin,                     // CTS pin (hardware flow control, if configured)
+            rts_pin,                     // RTS pin (flow control or RS485 DE, if configured)
+            &uart_config,
+        ).map_err(|e| Error::uart_caused("Failed to create UART driver", e))?;
+
+        if config.flow_control == FlowControl::Software {
+            // 由驱动根据硬件FIFO的填充程度发送XON/XOFF
+            let err = unsafe {
+                esp_idf_sys::uart_set_sw_flow_ctrl(
+                    port,
+                    true,
+                    FLOW_CONTROL_RESUME_LEVEL,
+                    FLOW_CONTROL_STOP_LEVEL,
+                )
+            };
+            if err != esp_idf_sys::ESP_OK {
+                return Err(Error::esp(err, "Enabling XON/XOFF flow control"));
+            }
+        }
+        if config.flow_control != FlowControl::None {
+            info!("UART flow control: {}", config.flow_control);
+        }
+
+        let rs485 = de_pin_num.map(|pin| Self::init_rs485(port, pin)).transpose()?;
+
+        info!(
+            "UART{} initialized on TX GPIO{} / RX GPIO{} with baudrate: {}, format: {}",
+            port, config.tx_pin, config.rx_pin, config.baudrate, config.format
+        );
+
+        let rx_events = match uart.event_queue() {
+            Some(queue) if config.event_driven_rx => Some(RxEventQueue(queue.as_raw())),
+            None if config.event_driven_rx => {
+                warn!("UART event queue not available, falling back to polling");
+                None
+            }
+            _ => None,
+        };
+
+        Ok(Self::from_parts(Box::new(uart), config, storage, rs485, rx_events))
+    }
+
+    /// Create a UART manager on any serial port, e.g. an in-memory pipe off the device
+    ///
+    /// Settings saved in `storage` take precedence over `config` and are applied to
+    /// `port`. RS485 direction control and event-driven receive are not available.
+    pub fn with_port(
+        port: Box<dyn SerialPort>,
+        mut config: UartConfig,
+        storage: Option<Arc<Mutex<StorageManager>>>,
+    ) -> Result<Self> {
+        Self::load_saved_settings(&mut config, storage.as_ref());
+        port.set_baudrate(config.baudrate)?;
+        port.set_format(&config.format)?;
+        Ok(Self::from_parts(port, config, storage, None, None))
+    }
+
+    /// Read the serial settings saved in `storage` into `config`
+    fn load_saved_settings(config: &mut UartConfig, storage: Option<&Arc<Mutex<StorageManager>>>) {
+        // Try to read saved settings from flash
+        match storage.map(|storage| storage.lock()) {
+            Some(Ok(storage)) => {
+                // Try to read baudrate from flash
+                if let Some(baudrate) = storage.read_baudrate() {
+                    // Check if the baudrate is valid
+                    if Self::is_valid_baudrate(baudrate)
+                        && (!config.strict_baudrates || SUPPORTED_BAUDRATES.contains(&baudrate))
+                    {
+                        // Update config with the baudrate from flash
+                        info!("Using baudrate {} from flash", baudrate);
+                        config.baudrate = baudrate;
+                    } else {
+                        warn!("Invalid baudrate {} read from flash, using default", baudrate);
+                    }
+                } else {
+                    info!("No baudrate found in flash, using default: {}", config.baudrate);
+                }
+                // Try to read the character format from flash
+                if let Some(format) = storage.read_format() {
+                    info!("Using serial format {} from flash", format);
+                    config.format = format;
+                }
+                if let Some(delimiter) = storage.read_frame_delimiter() {
+                    info!("Using frame delimiter {} from flash", delimiter.as_deref().map_or("off".to_string(), format_hex));
+                    config.frame_delimiter = delimiter;
+                }
+                if let Some(bytes) = storage.read_replay_bytes() {
+                    info!("Using replay size {} bytes from flash", bytes);
+                    config.replay_bytes = bytes as usize;
+                }
+            },
+            Some(Err(e)) => {
+                warn!("Failed to lock storage manager: {}, using default serial settings", e);
+            }
+            None => {
+                warn!("No storage available, serial settings will not be persisted");
+            }
+        }
+    }
+
+    /// Assemble the manager around an initialized serial port
+    fn from_parts(
+        uart: Box<dyn SerialPort>,
+        config: UartConfig,
+        storage: Option<Arc<Mutex<StorageManager>>>,
+        rs485: Option<Rs485Mode>,
+        rx_events: Option<RxEventQueue>,
+    ) -> Self {
+        let (tx_sender, tx_receiver) = mpsc::sync_channel(config.tx_queue_capacity);
+
+        Self {
+            uart: Mutex::new(uart),
+            rs485,
+            baudrate: AtomicU32::new(config.baudrate),
+            format: Mutex::new(config.format),
+            framing: Mutex::new(Framing {
+                gap_ms: config.frame_gap_ms,
+                max_bytes: config.frame_max_bytes.clamp(1, MAX_FRAME_BYTES),
+                delimiter: config
+                    .frame_delimiter
+                    .as_deref()
+                    .filter(|delimiter| !delimiter.is_empty())
+                    .and_then(|delimiter| Delimiter::from_slice(delimiter).ok()),
+                batch_bytes: config.batch_bytes.clamp(1, MAX_FRAME_BYTES),
+                batch_ms: config.batch_max_latency_ms.min(MAX_BATCH_LATENCY_MS),
+            }),
+            rx_events,
+            reconfiguring: AtomicBool::new(false),
+            pending_tx: Mutex::new(PendingTx {
+                chunks: VecDeque::new(),
+                bytes: 0,
+            }),
+            tx_sender,
+            tx_receiver: Mutex::new(Some(tx_receiver)),
+            tx_queue_len: AtomicUsize::new(0),
+            bytes_sent_to_uart: AtomicU64::new(0),
+            bytes_received_from_uart: AtomicU64::new(0),
+            bridge_enabled: AtomicBool::new(true),
+            bridge_discarded: AtomicU64::new(0),
+            flow_pauses: AtomicU64::new(0),
+            flow_resumes: AtomicU64::new(0),
+            packet_errors: AtomicU64::new(0),
+            packet_rx: Mutex::new(PacketDecoder::new(config.packet_codec)),
+            rx_errors: AtomicU32::new(0),
+            framing_errors: AtomicU32::new(0),
+            parity_errors: AtomicU32::new(0),
+            rx_overflows: AtomicU32::new(0),
+            rx_breaks: AtomicU32::new(0),
+            sampling: AtomicBool::new(false),
+            line_endings: Mutex::new(LineEndings {
+                tcp_to_uart: config.tcp_to_uart_eol,
+                uart_to_tcp: config.uart_to_tcp_eol,
+                rx: EolState::default(),
+            }),
+            replay: Mutex::new(ReplayBuffer {
+                data: VecDeque::new(),
+                capacity: config.replay_bytes.min(MAX_REPLAY_BYTES),
+            }),
+            config,
+            storage,
+            command_handler: OnceLock::new(),
+        }
+    }
+
+    /// Send data to UART
+    ///
+    /// The data is queued for the `uart_tx` writer thread, so this never waits for
+    /// the UART. Fails with "tx queue full" if the writer cannot keep up.
+    pub fn send_data(&self, data: &[u8]) -> Result<()> {
+        // 如果没有数据，直接返回
+        if data.is_empty() {
+            return Ok(());
+        }
+
+        Self::enqueue_tx(&self.tx_sender, &self.tx_queue_len, data)
+    }
+
+    /// Put a chunk on the writer queue, counting it in `queue_len`
+    fn enqueue_tx(sender: &SyncSender<Vec<u8>>, queue_len: &AtomicUsize, data: &[u8]) -> Result<()> {
+        // 先计数再入队，避免写线程取出后计数变为负数
+        queue_len.fetch_add(1, Ordering::Relaxed);
+        match sender.try_send(data.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(e) => {
+                queue_len.fetch_sub(1, Ordering::Relaxed);
+                match e {
+                    TrySendError::Full(_) => Err(Error::uart("tx queue full")),
+                    TrySendError::Disconnected(_) => {
+                        Err(Error::uart("UART writer thread stopped"))
+                    }
+                }
+            }
+        }
+    }
+
+    /// Send one packet from a network client to the UART
+    ///
+    /// Encoded with `UartConfig::packet_codec` first, so each read from a client
+    /// becomes one packet; without a codec this is `send_data`.
+    pub fn send_packet(&self, packet: &[u8]) -> Result<()> {
+        if packet.is_empty() || self.config.packet_codec == PacketCodec::None {
+            return self.send_data(packet);
+        }
+        self.send_data(&packet_codec::encode(self.config.packet_codec, packet))
+    }
+
+    /// Get the number of data chunks waiting for the `uart_tx` writer thread
+    pub fn get_tx_queue_len(&self) -> usize {
+        self.tx_queue_len.load(Ordering::Relaxed)
+    }
+
+    /// Get a snapshot of the traffic counters
+    pub fn stats(&self) -> UartStats {
+        UartStats {
+            bytes_sent_to_uart: self.bytes_sent_to_uart.load(Ordering::Relaxed),
+            bytes_received_from_uart: self.bytes_received_from_uart.load(Ordering::Relaxed),
+            flow_pauses: self.flow_pauses.load(Ordering::Relaxed),
+            flow_resumes: self.flow_resumes.load(Ordering::Relaxed),
+            packet_errors: self.packet_errors.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the traffic counters to zero
+    pub fn reset_stats(&self) {
+        self.bytes_sent_to_uart.store(0, Ordering::Relaxed);
+        self.bytes_received_from_uart.store(0, Ordering::Relaxed);
+        self.flow_pauses.store(0, Ordering::Relaxed);
+        self.flow_resumes.store(0, Ordering::Relaxed);
+        self.packet_errors.store(0, Ordering::Relaxed);
+    }
+
+    /// Get a snapshot of the receive error counters (AT+UARTERR)
+    pub fn get_error_counters(&self) -> UartErrorCounters {
+        UartErrorCounters {
+            framing: self.framing_errors.load(Ordering::Relaxed),
+            parity: self.parity_errors.load(Ordering::Relaxed),
+            overflow: self.rx_overflows.load(Ordering::Relaxed),
+            breaks: self.rx_breaks.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Reset the receive error counters to zero
+    pub fn reset_error_counters(&self) {
+        self.framing_errors.store(0, Ordering::Relaxed);
+        self.parity_errors.store(0, Ordering::Relaxed);
+        self.rx_overflows.store(0, Ordering::Relaxed);
+        self.rx_breaks.store(0, Ordering::Relaxed);
+    }
+
+    /// Pause (false) or resume (true) forwarding between UART and the network
+    ///
+    /// While paused, UART data is discarded instead of broadcast, and the bridges
+    /// drop network data instead of calling `send_data`. Always enabled at boot.
+    pub fn set_bridge_enabled(&self, enabled: bool) {
+        if self.bridge_enabled.swap(enabled, Ordering::SeqCst) == enabled {
+            return;
+        }
+        if enabled {
+            let discarded = self.bridge_discarded.swap(0, Ordering::Relaxed);
+            info!("UART bridge resumed, {} bytes from UART were discarded", discarded);
+        } else {
+            info!("UART bridge paused");
+        }
+    }
+
+    /// Check whether data is forwarded between UART and the network
+    pub fn is_bridge_enabled(&self) -> bool {
+        self.bridge_enabled.load(Ordering::Relaxed)
+    }
+
+    /// Get the UART bytes discarded since the bridge was paused
+    pub fn bridge_discarded(&self) -> u64 {
+        self.bridge_discarded.load(Ordering::Relaxed)
+    }
+
+    /// Change and persist the size of the UART history replayed to new clients
+    ///
+    /// 0 disables the replay and frees the history.
+    pub fn set_replay_bytes(&self, bytes: usize) -> Result<()> {
+        if bytes > MAX_REPLAY_BYTES {
+            return Err(Error::uart(format!("Replay size must be at most {} bytes", MAX_REPLAY_BYTES)));
+        }
+        self.lock_replay().resize(bytes);
+        info!("UART replay size: {} bytes", bytes);
+
+        if let Some(storage_mutex) = &self.storage {
+            match storage_mutex.lock() {
+                Ok(mut storage) => {
+                    if let Err(e) = storage.save_replay_bytes(bytes as u16) {
+                        warn!("Failed to save replay size to flash: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to lock storage manager: {}, replay size will not be persisted", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the size of the UART history replayed to new clients (0 if disabled)
+    pub fn replay_bytes(&self) -> usize {
+        self.lock_replay().capacity
+    }
+
+    /// Whether replayed history is framed by marker lines
+    pub fn replay_markers(&self) -> bool {
+        self.config.replay_markers
+    }
+
+    /// Change the line ending translation of command mode clients (not saved)
+    pub fn set_line_endings(&self, tcp_to_uart: TcpToUartEol, uart_to_tcp: UartToTcpEol) {
+        let mut line_endings = self.lock_line_endings();
+        line_endings.tcp_to_uart = tcp_to_uart;
+        line_endings.uart_to_tcp = uart_to_tcp;
+        info!("Line endings: TCP>UART {}, UART>TCP {}", tcp_to_uart, uart_to_tcp);
+    }
+
+    /// Get the line ending translation of both directions
+    pub fn line_endings(&self) -> (TcpToUartEol, UartToTcpEol) {
+        let line_endings = self.lock_line_endings();
+        (line_endings.tcp_to_uart, line_endings.uart_to_tcp)
+    }
+
+    fn lock_line_endings(&self) -> MutexGuard<'_, LineEndings> {
+        self.line_endings.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Get the number of bytes currently retained for replay
+    pub fn replay_len(&self) -> usize {
+        self.lock_replay().data.len()
+    }
+
+    /// Add a client with `attach`, which gets the retained UART history to queue
+    ///
+    /// No UART data is broadcast while `attach` runs, so the client receives the
+    /// history followed by the live data without gaps or repeats. `attach` must only
+    /// add and queue, never wait on the network.
+    pub fn attach_with_replay<T>(&self, attach: impl FnOnce(&[u8]) -> T) -> T {
+        let mut replay = self.lock_replay();
+        let history = replay.data.make_contiguous();
+        attach(history)
+    }
+
+    fn lock_replay(&self) -> MutexGuard<'_, ReplayBuffer> {
+        // 中毒后的缓冲区内容仍然有效
+        self.replay.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Start the `uart_tx` thread that drains the writer queue into the UART
+    fn spawn_tx_writer(self_arc: &Arc<Self>) -> Result<()> {
+        let receiver = self_arc
+            .tx_receiver
+            .lock()
+            .map_err(|_| Error::uart("Failed to lock TX receiver"))?
+            .take()
+            .ok_or_else(|| Error::uart("UART writer thread already started"))?;
+        let uart_manager = Arc::clone(self_arc);
+
+        thread::Builder::new()
+            .name("uart_tx".into())
+            .stack_size(4096)
+            .spawn(move || {
+                let _tracker = diagnostics::track_thread("uart_tx");
+                for data in receiver {
+                    uart_manager.tx_queue_len.fetch_sub(1, Ordering::Relaxed);
+                    match uart_manager.write_data(&data) {
+                        Ok(()) => {
+                            uart_manager.bytes_sent_to_uart.fetch_add(data.len() as u64, Ordering::Relaxed);
+                        }
+                        Err(e) => error!("Error sending data to UART: {}", e),
+                    }
+                }
+            })
+            .map_err(|e| Error::uart_caused("Failed to spawn UART writer thread", e))?;
+
+        Ok(())
+    }
+
+    /// Write data to the UART, called only from the `uart_tx` writer thread
+    fn write_data(&self, data: &[u8]) -> Result<()> {
+        // 尽量减少锁的持有时间；按锁顺序先取uart再取pending_tx
+        {
+            let uart = self.uart.lock().map_err(|_| Error::uart("Failed to lock UART"))?;
+            let mut pending = self.pending_tx.lock().map_err(|_| Error::uart("Failed to lock TX queue"))?;
+
+            // 重新配置期间将数据排队，避免一帧数据跨越两种串口设置
+            if self.reconfiguring.load(Ordering::Acquire) {
+                return self.enqueue_pending(&mut pending, data);
+            }
+
+            // 先写出上次重新配置中止或写入失败时残留的数据，保持顺序
+            let result = self
+                .write_pending(&**uart, &mut pending)
+                .and_then(|_| self.write_frame(&**uart, data));
+            if let Err(e) = result {
+                // 写入失败的数据留在队列中，下次写入时重试
+                self.enqueue_pending(&mut pending, data)?;
+                return Err(e);
+            }
+        }
+
+        // 只在trace级别记录详细日志
+        if log::log_enabled!(log::Level::Trace) {
+            trace!("UART sent {} bytes", data.len());
+        }
+
+        Ok(())
+    }
+
+    /// Queue data to be written by the next `write_pending`
+    fn enqueue_pending(&self, pending: &mut PendingTx, data: &[u8]) -> Result<()> {
+        pending.push(data, self.config.reconfig_queue_size)
+    }
+
+    /// Write data queued during a reconfiguration window or after a failed write
+    ///
+    /// A chunk is only taken off the queue once it was written, so a failed write
+    /// keeps it and everything after it queued in order.
+    fn write_pending(&self, uart: &dyn SerialPort, pending: &mut PendingTx) -> Result<()> {
+        while let Some(chunk) = pending.chunks.front() {
+            self.write_frame(uart, chunk)?;
+            pending.bytes -= chunk.len();
+            pending.chunks.pop_front();
+        }
+        Ok(())
+    }
+
+    /// Write one chunk to the UART, driving the RS485 DE pin if it is controlled manually
+    ///
+    /// In manual RS485 mode the pin is held high until the last bit has left the
+    /// shift register, so the transceiver does not cut off the frame or talk over
+    /// the reply.
+    fn write_frame(&self, uart: &dyn SerialPort, data: &[u8]) -> Result<()> {
+        match &self.rs485 {
+            #[cfg(target_os = "espidf")]
+            Some(Rs485Mode::Manual(de_pin)) => self.write_rs485_frame(uart, de_pin, data),
+            _ => {
+                uart.write(data)?;
+                Ok(())
+            }
+        }
+    }
+
+    /// Write one chunk with the RS485 DE pin raised
+    #[cfg(target_os = "espidf")]
+    fn write_rs485_frame(
+        &self,
+        uart: &dyn SerialPort,
+        de_pin: &Mutex<PinDriver<'static, gpio::AnyOutputPin, gpio::Output>>,
+        data: &[u8],
+    ) -> Result<()> {
+        let turnaround_us = self.config.rs485_turnaround_us;
+        let mut de_pin = de_pin.lock().map_err(|_| Error::uart("Failed to lock RS485 DE pin"))?;
+
+        de_pin.set_high().map_err(|e| Error::uart_caused("Failed to raise RS485 DE pin", e))?;
+        if turnaround_us > 0 {
+            Ets::delay_us(turnaround_us);
+        }
+
+        let result = uart
+            .write(data)
+            .and_then(|_| uart.wait_tx_done(Duration::from_millis(RECONFIG_TIMEOUT_MS)));
+
+        // 无论写入是否成功都要释放总线
+        if turnaround_us > 0 {
+            Ets::delay_us(turnaround_us);
+        }
+        de_pin.set_low().map_err(|e| Error::uart_caused("Failed to lower RS485 DE pin", e))?;
+        result
+    }
+
+    /// Lock the UART, giving up after `timeout`
+    fn lock_uart_within(&self, timeout: Duration) -> Result<MutexGuard<'_, Box<dyn SerialPort>>> {
+        let stopwatch = Stopwatch::start();
+        loop {
+            if let Ok(guard) = self.uart.try_lock() {
+                return Ok(guard);
+            }
+            if stopwatch.has_elapsed(timeout) {
+                return Err(Error::uart("Timed out waiting for UART"));
+            }
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Read from UART, waiting up to `timeout` for data (None waits until data arrives)
+    ///
+    /// A driver timeout is reported as `UartRead::Timeout`, not as an error.
+    pub fn read(&self, buffer: &mut [u8], timeout: Option<Duration>) -> Result<UartRead> {
+        // 尽量减少锁的持有时间
+        let result = {
+            let uart = self.uart.lock().map_err(|_| Error::uart("Failed to lock UART"))?;
+            uart.read(buffer, timeout)
+        };
+
+        // 只在出错时记录日志，减少日志开销
+        match result {
+            Ok(UartRead::Data(len)) => {
+                self.bytes_received_from_uart.fetch_add(len as u64, Ordering::Relaxed);
+            }
+            Ok(UartRead::Timeout) => {}
+            Err(ref e) => error!("UART receive error: {}", e),
+        }
+
+        result
+    }
+
+    /// Receive data from UART (non-blocking)
+    ///
+    /// Returns 0 if no data is available.
+    /// Optimized for low latency
+    pub fn receive_data(&self, buffer: &mut [u8]) -> Result<usize> {
+        // 重新配置期间暂停读取
+        if self.reconfiguring.load(Ordering::Acquire) {
+            return Ok(0);
+        }
+        Ok(self.read(buffer, Some(Duration::ZERO))?.bytes_read())
+    }
+
+    /// Receive data from UART (blocking)
+    ///
+    /// Returns 0 if the driver times out.
+    /// Optimized for low latency
+    pub fn receive_data_blocking(&self, buffer: &mut [u8]) -> Result<usize> {
+        Ok(self.read(buffer, None)?.bytes_read())
+    }
+
+    /// 修改UART波特率
+    ///
+    /// 这个方法允许动态修改UART的波特率，返回时钟分频实际得到的波特率
+    pub fn set_baudrate(&self, baudrate: u32) -> Result<u32> {
+        let format = self.get_format();
+        self.set_serial_params(baudrate, format)
+    }
+
+    /// 修改UART的全部串口参数（波特率、数据位、校验位、停止位）
+    ///
+    /// 新的参数会保存到flash，重启后仍然有效。
+    /// 即使底层驱动无法在运行时修改波特率，`get_baudrate` 也会报告新的波特率，
+    /// 因为保存的设置会在下次启动时生效。返回实际得到的波特率
+    pub fn set_serial_params(&self, baudrate: u32, format: SerialFormat) -> Result<u32> {
+        self.reconfigure(baudrate, format, true)
+    }
+
+    /// Change all serial parameters without saving them to flash
+    ///
+    /// For RFC 2217 clients, which set the parameters every time they open the
+    /// port; the saved settings apply again after a restart. Returns the achieved
+    /// baudrate.
+    pub fn apply_serial_params(&self, baudrate: u32, format: SerialFormat) -> Result<u32> {
+        self.reconfigure(baudrate, format, false)
+    }
+
+    /// Apply new serial parameters, saving them to flash if `persist` is set
+    ///
+    /// Returns the baudrate achieved by the clock divider. A rate the divider cannot
+    /// reach within `BAUDRATE_TOLERANCE_PERMILLE` is refused and the old rate kept.
+    fn reconfigure(&self, baudrate: u32, format: SerialFormat, persist: bool) -> Result<u32> {
+        // 验证波特率是否有效
+        if !Self::is_valid_baudrate(baudrate) {
+            return Err(Error::uart(format!(
+                "Invalid baudrate: {} (use {}-{})",
+                baudrate, MIN_BAUDRATE, MAX_BAUDRATE
+            )));
+        }
+        if self.config.strict_baudrates && !SUPPORTED_BAUDRATES.contains(&baudrate) {
+            return Err(Error::uart(format!("Unsupported baudrate: {} (standard rates only)", baudrate)));
+        }
+        if self.sampling.load(Ordering::Acquire) {
+            return Err(Error::uart("Baudrate detection or loopback test in progress"));
+        }
+
+        // 打开重新配置窗口：TCP数据排队，UART读取暂停
+        let window = ReconfigWindow::open(self)?;
+
+        // 锁定UART进行重新配置，超时则放弃，避免卡住整个桥接
+        let timeout = Duration::from_millis(RECONFIG_TIMEOUT_MS);
+        let uart_guard = self.lock_uart_within(timeout)?;
+
+        // 等待TX FIFO中的数据以旧参数发送完毕
+        if let Err(e) = uart_guard.wait_tx_done(timeout) {
+            warn!("Timed out draining UART TX before reconfiguration: {}", e);
+        }
+
+        // 应用新的波特率设置
+        let achieved = match uart_guard.set_baudrate(baudrate) {
+            Ok(achieved) => {
+                if !Self::within_tolerance(baudrate, achieved) {
+                    let _ = uart_guard.set_baudrate(self.get_baudrate());
+                    if let Err(e) = window.finish(&**uart_guard) {
+                        warn!("Failed to flush data queued during reconfiguration: {}", e);
+                    }
+                    return Err(Error::uart(format!(
+                        "Baudrate {} cannot be achieved (nearest {})",
+                        baudrate, achieved
+                    )));
+                }
+                info!("Successfully changed UART baudrate to {} (achieved {}) at runtime", baudrate, achieved);
+                achieved
+            },
+            Err(e) => {
+                // 如果失败，我们仍然更新内部配置
+                warn!("{}. Baudrate change will take full effect after device restart", e);
+                baudrate
+            }
+        };
+
+        // 应用数据位、校验位和停止位
+        if format != self.get_format() {
+            if let Err(e) = uart_guard.set_format(&format) {
+                // 保持波特率与格式一致，窗口在返回时关闭
+                let _ = uart_guard.set_baudrate(self.get_baudrate());
+                return Err(e);
+            }
+            info!("Successfully changed UART format to {} at runtime", format);
+        }
+
+        // 更新内部配置
+        self.baudrate.store(baudrate, Ordering::Release);
+        if let Ok(mut current) = self.format.lock() {
+            *current = format;
+        }
+
+        // 以新参数写出排队的数据并关闭窗口
+        if let Err(e) = window.finish(&**uart_guard) {
+            warn!("Failed to flush data queued during reconfiguration: {}", e);
+        }
+
+        // 释放锁，避免写flash期间阻塞UART收发
+        drop(uart_guard);
+
+        // 保存串口参数到flash
+        if let Some(storage_mutex) = self.storage.as_ref().filter(|_| persist) {
+            match storage_mutex.lock() {
+                Ok(mut storage) => {
+                    if let Err(e) = storage.save_baudrate(baudrate) {
+                        warn!("Failed to save baudrate to flash: {}", e);
+                    } else {
+                        info!("Baudrate {} saved to flash", baudrate);
+                    }
+                    if let Err(e) = storage.save_format(&format) {
+                        warn!("Failed to save serial format to flash: {}", e);
+                    }
+                },
+                Err(e) => {
+                    warn!("Failed to lock storage manager: {}, serial settings will not be persisted", e);
+                }
+            }
+        }
+
+        info!("UART settings changed to: {},{}", baudrate, format);
+        Ok(achieved)
+    }
+
+    /// Check whether an achieved baudrate is close enough to the requested one
+    fn within_tolerance(requested: u32, achieved: u32) -> bool {
+        u64::from(requested.abs_diff(achieved)) * 1000 <= u64::from(requested) * BAUDRATE_TOLERANCE_PERMILLE
+    }
+
+    /// Find the baudrate of the attached device by listening at each supported rate
+    ///
+    /// Forwarding is paused while listening `autobaud_window_ms` at each rate, and
+    /// data from the network is queued as during a reconfiguration. The original
+    /// baudrate is restored afterwards; applying the detected rate is up to the caller.
+    pub fn detect_baudrate(&self) -> Result<AutoBaudReport> {
+        if self.sampling.swap(true, Ordering::AcqRel) {
+            return Err(Error::uart("Baudrate detection or loopback test already in progress"));
+        }
+        let result = self.sample_baudrates(Duration::from_millis(self.config.autobaud_window_ms));
+        self.sampling.store(false, Ordering::Release);
+        result
+    }
+
+    /// Score every supported baudrate, then restore the current one
+    fn sample_baudrates(&self, window: Duration) -> Result<AutoBaudReport> {
+        let original = self.get_baudrate();
+        let window_guard = ReconfigWindow::open(self)?;
+        info!("Detecting UART baudrate, {} ms per rate", window.as_millis());
+
+        let mut sample = [0u8; AUTOBAUD_SAMPLE_BYTES];
+        let scores: Result<Vec<(u32, Option<u32>)>> = SUPPORTED_BAUDRATES
+            .iter()
+            .map(|&baudrate| Ok((baudrate, self.sample_baudrate(baudrate, window, &mut sample)?)))
+            .collect();
+
+        // 出错时也恢复原来的波特率，并以原参数写出检测期间排队的数据
+        let uart_guard = self.lock_uart_within(Duration::from_millis(RECONFIG_TIMEOUT_MS))?;
+        let _ = uart_guard.set_baudrate(original);
+        let _ = uart_guard.flush_input();
+        if let Err(e) = window_guard.finish(&**uart_guard) {
+            warn!("Failed to flush data queued during baudrate detection: {}", e);
+        }
+        drop(uart_guard);
+
+        let scores = scores?;
+        // 分数相同时保留较低的波特率
+        let best = scores
+            .iter()
+            .filter_map(|&(baudrate, score)| score.map(|score| (baudrate, score)))
+            .fold(None, |best: Option<(u32, u32)>, (baudrate, score)| match best {
+                Some((_, best_score)) if best_score >= score => best,
+                _ => Some((baudrate, score)),
+            })
+            .map(|(baudrate, _)| baudrate);
+        info!("Baudrate detection finished, best candidate: {:?}", best);
+        Ok(AutoBaudReport { scores, best })
+    }
+
+    /// Listen at one baudrate for `window` and score what was received
+    fn sample_baudrate(&self, baudrate: u32, window: Duration, sample: &mut [u8]) -> Result<Option<u32>> {
+        // 检测可能持续数秒，每个波特率喂一次看门狗
+        watchdog::feed();
+        {
+            let uart = self.lock_uart_within(Duration::from_millis(RECONFIG_TIMEOUT_MS))?;
+            let _ = uart.set_baudrate(baudrate);
+            // 丢弃以上一个波特率收到的数据
+            let _ = uart.flush_input();
+        }
+        let errors_before = self.rx_errors.load(Ordering::Relaxed);
+        let started = Stopwatch::start();
+        let mut len = 0;
+        while len < sample.len() && !started.has_elapsed(window) {
+            let remaining = window.saturating_sub(started.elapsed());
+            len += self.read(&mut sample[len..], Some(remaining))?.bytes_read();
+        }
+        let errors = self.rx_errors.load(Ordering::Relaxed).wrapping_sub(errors_before);
+        let score = score_baud_sample(&sample[..len], errors);
+        debug!("Baudrate {}: {} bytes, {} errors, score {:?}", baudrate, len, errors, score);
+        Ok(score)
+    }
+
+    /// Write `pattern` and read it back, for a self test with TX jumpered to RX
+    ///
+    /// Forwarding is paused and data from the network is queued as during a
+    /// reconfiguration, so connected clients neither receive the pattern nor get
+    /// their data mixed into it. Waits for the transfer time of the pattern at the
+    /// current baudrate plus `LOOPBACK_MARGIN_MS`.
+    pub fn loopback_test(&self, pattern: &[u8]) -> Result<TestReport> {
+        if self.sampling.swap(true, Ordering::AcqRel) {
+            return Err(Error::uart("Baudrate detection or loopback test already in progress"));
+        }
+        let result = self.run_loopback(pattern);
+        self.sampling.store(false, Ordering::Release);
+        result
+    }
+
+    fn run_loopback(&self, pattern: &[u8]) -> Result<TestReport> {
+        let window = ReconfigWindow::open(self)?;
+        let timeout = Duration::from_millis(RECONFIG_TIMEOUT_MS);
+
+        let written = {
+            let uart = self.lock_uart_within(timeout)?;
+            // 等待之前的数据发送完毕，并丢弃尚未转发的接收数据
+            if let Err(e) = uart.wait_tx_done(timeout) {
+                warn!("Timed out draining UART TX before loopback test: {}", e);
+            }
+            let _ = uart.flush_input();
+            self.write_frame(&**uart, pattern)
+        };
+
+        let mut received = vec![0u8; pattern.len()];
+        let mut len = 0;
+        let read = written.and_then(|_| {
+            // 每个字符按最多12位估算传输时间
+            let transfer_ms = pattern.len() as u64 * 12 * 1000 / u64::from(self.get_baudrate().max(1));
+            let deadline = Duration::from_millis(transfer_ms + LOOPBACK_MARGIN_MS);
+            let started = Stopwatch::start();
+            while len < received.len() && !started.has_elapsed(deadline) {
+                watchdog::feed();
+                let remaining = deadline.saturating_sub(started.elapsed());
+                len += self.read(&mut received[len..], Some(remaining))?.bytes_read();
+            }
+            Ok(())
+        });
+
+        // 丢弃多余的回环数据，再写出测试期间排队的数据
+        let uart_guard = self.lock_uart_within(timeout)?;
+        let _ = uart_guard.flush_input();
+        if let Err(e) = window.finish(&**uart_guard) {
+            warn!("Failed to flush data queued during loopback test: {}", e);
+        }
+        drop(uart_guard);
+
+        read?;
+        let report = TestReport::compare(pattern, &received[..len]);
+        info!("UART loopback test: {}", report);
+        Ok(report)
+    }
+
+    /// Wait for a receive event from the UART driver and count it if it reports an error
+    ///
+    /// Returns on timeout, or at once when event-driven receive is not in use.
+    #[cfg(target_os = "espidf")]
+    fn wait_rx_event(&self, timeout: Duration, client_manager: &TcpClientManager) {
+        let Some(queue) = self.rx_events.as_ref() else {
+            return;
+        };
+        let ticks = TickType::new_millis(timeout.as_millis() as u64).ticks();
+        let event = unsafe {
+            let mut event: esp_idf_sys::uart_event_t = core::mem::zeroed();
+            let received = esp_idf_sys::xQueueReceive(
+                queue.0,
+                &mut event as *mut esp_idf_sys::uart_event_t as *mut core::ffi::c_void,
+                ticks,
+            );
+            (received != 0).then_some(event)
+        };
+        if let Some(event) = event {
+            self.count_rx_event(event.type_, client_manager);
+        }
+    }
+
+    #[cfg(not(target_os = "espidf"))]
+    fn wait_rx_event(&self, _timeout: Duration, _client_manager: &TcpClientManager) {
+        if let Some(queue) = &self.rx_events {
+            match *queue {}
+        }
+    }
+
+    /// Check whether UART data has to be read: a TCP client or UDP peer would receive
+    /// it, or it is kept for replay
+    fn has_receivers(&self, client_manager: &TcpClientManager, udp_peers: Option<&UdpPeerManager>) -> bool {
+        client_manager.client_count().unwrap_or(0) > 0
+            || udp_peers.is_some_and(|peers| peers.peer_count().unwrap_or(0) > 0)
+            || self.replay_bytes() > 0
+            || self.command_channel().is_some()
+    }
+
+    /// Check whether `UartConfig::command_prefix` enables commands from the UART
+    pub fn accepts_commands(&self) -> bool {
+        self.config.command_prefix.is_some_and(|prefix| !prefix.is_empty())
+    }
+
+    /// Set the handler running command lines received from the UART
+    ///
+    /// Only the first handler set is used, and only if `accepts_commands`.
+    pub fn set_command_handler(&self, handler: UartCommandHandler) {
+        if self.command_handler.set(handler).is_err() {
+            debug!("UART command handler already set");
+        }
+    }
+
+    /// Get the command prefix and the handler if UART commands are enabled
+    fn command_channel(&self) -> Option<(&[u8], &UartCommandHandler)> {
+        let prefix = self.config.command_prefix.filter(|prefix| !prefix.is_empty())?;
+        Some((prefix.as_bytes(), self.command_handler.get()?))
+    }
+
+    /// Run a command line received from the UART
+    ///
+    /// Runs on the forwarding thread, so received data waits in the driver buffer
+    /// until the command is done.
+    fn run_uart_command(&self, handler: &UartCommandHandler, line: &str) {
+        info!("Received command from UART: {}", line);
+        handler(line);
+    }
+
+    /// Send UART data to all TCP clients and UDP peers, or discard it while paused
+    fn distribute(&self, client_manager: &TcpClientManager, udp_peers: Option<&UdpPeerManager>, data: &[u8]) {
+        if !self.is_bridge_enabled() {
+            self.bridge_discarded.fetch_add(data.len() as u64, Ordering::Relaxed);
+            return;
+        }
+        let text = {
+            let mut line_endings = self.lock_line_endings();
+            let mode = line_endings.uart_to_tcp;
+            line_endings.rx.uart_to_tcp(mode, data)
+        };
+        {
+            // 记录与广播在同一把锁内进行，新客户端的回放与实时数据之间不会重复或遗漏
+            let mut replay = self.lock_replay();
+            replay.push(data);
+            let _ = client_manager.broadcast(data, &text); // 忽略错误，减少延迟
+        }
+        if let Some(peers) = udp_peers {
+            let _ = peers.broadcast(data);
+        }
+    }
+
+    /// Forward UART data to TCP clients and UDP peers as the driver reports it
+    ///
+    /// Blocks on the driver event queue between bursts and drains the receive
+    /// buffer in one go when woken up. Never returns.
+    fn forward_events(
+        &self,
+        client_manager: &TcpClientManager,
+        udp_peers: Option<&UdpPeerManager>,
+        buffer: &mut [u8],
+    ) -> ! {
+        let mut frames = FrameAccumulator::new();
+        let mut commands = CommandScanner::new();
+        let mut backpressure = Backpressure::new(&self.config);
+        info!("UART receive is event driven");
+
+        loop {
+            watchdog::feed();
+
+            // 没有接收方时不唤醒读取，数据留在驱动缓冲区中；波特率检测期间仍需统计错误事件
+            if !self.has_receivers(client_manager, udp_peers) && !self.sampling.load(Ordering::Acquire) {
+                thread::sleep(Duration::from_millis(50));
+                continue;
+            }
+
+            // 有未发送的帧时只等到帧间隔结束
+            let wait = frames
+                .due_in(&self.framing())
+                .unwrap_or(Duration::from_millis(RX_EVENT_WAIT_MS))
+                .min(Duration::from_millis(RX_EVENT_WAIT_MS));
+            // 暂停期间不等待接收事件，驱动缓冲区满后由流控让对端停止发送
+            if backpressure.should_pause(self, client_manager) {
+                thread::sleep(Duration::from_millis(BACKPRESSURE_CHECK_MS));
+                self.forward_due_frame(&mut frames, &mut commands, client_manager, udp_peers);
+                continue;
+            }
+
+            self.wait_rx_event(wait, client_manager);
+
+            self.drain_rx(&mut frames, &mut commands, &mut backpressure, client_manager, udp_peers, buffer);
+            self.forward_due_frame(&mut frames, &mut commands, client_manager, udp_peers);
+        }
+    }
+
+    /// Read and forward everything in the driver's receive buffer
+    ///
+    /// Reads until a read returns less than `buffer` holds, so a burst is taken in
+    /// as few wakeups as possible, or until the clients fall too far behind.
+    /// Returns the number of bytes forwarded.
+    fn drain_rx(
+        &self,
+        frames: &mut FrameAccumulator,
+        commands: &mut CommandScanner,
+        backpressure: &mut Backpressure,
+        client_manager: &TcpClientManager,
+        udp_peers: Option<&UdpPeerManager>,
+        buffer: &mut [u8],
+    ) -> usize {
+        let mut total = 0;
+        loop {
+            let len = match self.receive_data(buffer) {
+                Ok(len) if len > 0 => len,
+                // 完全忽略错误，减少延迟
+                _ => break,
+            };
+            self.forward_data(frames, commands, client_manager, udp_peers, &buffer[0..len]);
+            if log::log_enabled!(log::Level::Trace) {
+                trace!("UART -> TCP: {} bytes", len);
+            }
+            total += len;
+            // 读不满缓冲区说明驱动中已没有更多数据
+            if len < buffer.len() || backpressure.should_pause(self, client_manager) {
+                break;
+            }
+        }
+        total
+    }
+
+    /// Count an error event of the driver, announcing breaks to the clients
+    #[cfg(target_os = "espidf")]
+    fn count_rx_event(&self, event_type: esp_idf_sys::uart_event_type_t, client_manager: &TcpClientManager) {
+        // 启用流控时缓冲区满不会丢数据，数据留在硬件FIFO中直到被读取
+        let buffer_full_loses_data = self.config.flow_control == FlowControl::None;
+        if event_type == esp_idf_sys::uart_event_type_t_UART_FIFO_OVF
+            || (event_type == esp_idf_sys::uart_event_type_t_UART_BUFFER_FULL && buffer_full_loses_data)
+        {
+            self.rx_overflows.fetch_add(1, Ordering::Relaxed);
+            warn!("UART receive overflow, some data may have been lost");
+        } else if event_type == esp_idf_sys::uart_event_type_t_UART_FRAME_ERR {
+            self.framing_errors.fetch_add(1, Ordering::Relaxed);
+            // 供波特率检测评分使用
+            self.rx_errors.fetch_add(1, Ordering::Relaxed);
+        } else if event_type == esp_idf_sys::uart_event_type_t_UART_PARITY_ERR {
+            self.parity_errors.fetch_add(1, Ordering::Relaxed);
+            self.rx_errors.fetch_add(1, Ordering::Relaxed);
+        } else if event_type == esp_idf_sys::uart_event_type_t_UART_BREAK {
+            self.rx_breaks.fetch_add(1, Ordering::Relaxed);
+            debug!("UART break detected");
+            // 暂停转发时不通知，与数据一样丢弃
+            if let Some(marker) = self.config.break_marker {
+                if self.bridge_enabled.load(Ordering::Relaxed) {
+                    let _ = client_manager.broadcast(marker.as_bytes(), marker.as_bytes());
+                }
+            }
+        }
+    }
+
+    /// Enable RS485 half-duplex direction control on `de_pin`
+    ///
+    /// Prefers the ESP-IDF half-duplex mode, which toggles the pin (routed as RTS)
+    /// from the driver. If that mode is not available the pin is detached from the
+    /// UART and driven manually around each write.
+    #[cfg(target_os = "espidf")]
+    fn init_rs485(port: esp_idf_sys::uart_port_t, de_pin: i32) -> Result<Rs485Mode> {
+        let err = unsafe {
+            esp_idf_sys::uart_set_mode(port, esp_idf_sys::uart_mode_t_UART_MODE_RS485_HALF_DUPLEX)
+        };
+        if err == esp_idf_sys::ESP_OK {
+            info!("RS485 half-duplex mode enabled, DE on GPIO{}", de_pin);
+            return Ok(Rs485Mode::Native);
+        }
+
+        warn!(
+            "Native RS485 mode not available (error code: {}), driving DE on GPIO{} manually",
+            err, de_pin
+        );
+        // 断开RTS信号，改为普通GPIO输出
+        unsafe {
+            esp_idf_sys::gpio_reset_pin(de_pin);
+        }
+        let mut pin = PinDriver::output(unsafe { gpio::AnyOutputPin::new(de_pin) })
+            .map_err(|e| Error::uart_caused("Failed to configure RS485 DE pin", e))?;
+        pin.set_low()
+            .map_err(|e| Error::uart_caused("Failed to lower RS485 DE pin", e))?;
+        Ok(Rs485Mode::Manual(Mutex::new(pin)))
+    }
+
+    /// Get the GPIO number of the RS485 DE pin, if half-duplex mode is configured
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    fn rs485_de_pin(config: &UartConfig) -> Result<Option<i32>> {
+        let de_pin_num = config.rs485_de_pin.map(i32::from);
+        if de_pin_num.is_some_and(|pin| pin == config.tx_pin || pin == config.rx_pin) {
+            return Err(Error::uart("RS485 DE pin cannot be the TX or RX pin"));
+        }
+        Ok(de_pin_num)
+    }
+
+    /// Get the GPIO numbers of the RTS and CTS pins, if hardware flow control is configured
+    ///
+    /// `de_pin` is the RS485 DE pin, which uses the RTS signal as well.
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    fn flow_control_pins(config: &UartConfig, de_pin: Option<i32>) -> Result<(Option<i32>, Option<i32>)> {
+        let (rts_pin_num, cts_pin_num) = match config.flow_control {
+            FlowControl::Hardware => (config.rts_pin.map(i32::from), config.cts_pin.map(i32::from)),
+            _ => (None, None),
+        };
+        if config.flow_control == FlowControl::Hardware && rts_pin_num.is_none() && cts_pin_num.is_none() {
+            return Err(Error::uart("Hardware flow control needs an RTS or CTS pin"));
+        }
+        if rts_pin_num.is_some() && de_pin.is_some() {
+            return Err(Error::uart("RS485 DE pin and RTS flow control both use the RTS signal"));
+        }
+        for pin in [rts_pin_num, cts_pin_num].into_iter().flatten() {
+            if pin == config.tx_pin || pin == config.rx_pin {
+                return Err(Error::uart(format!("Flow control pin GPIO{} cannot be the TX or RX pin", pin)));
+            }
+        }
+        Ok((rts_pin_num, cts_pin_num))
+    }
+
+    /// Get a GPIO for a UART signal, rejecting numbers the ESP32-C3 cannot use
+    #[cfg(target_os = "espidf")]
+    fn gpio_pin(pin: i32, name: &str) -> Result<gpio::AnyIOPin> {
+        Self::check_gpio(pin, name)?;
+        // 引脚编号已经验证，且引脚只被UART驱动使用
+        Ok(unsafe { gpio::AnyIOPin::new(pin) })
+    }
+
+    /// Check that a GPIO number exists on the ESP32-C3 and is not wired to the flash
+    #[cfg_attr(not(target_os = "espidf"), allow(dead_code))]
+    fn check_gpio(pin: i32, name: &str) -> Result<()> {
+        if !(0..=MAX_GPIO).contains(&pin) {
+            return Err(Error::uart(format!(
+                "Invalid {} pin GPIO{} (valid: 0-{})",
+                name, pin, MAX_GPIO
+            )));
+        }
+        if FLASH_GPIOS.contains(&pin) {
+            return Err(Error::uart(format!(
+                "Invalid {} pin GPIO{}: reserved for SPI flash",
+                name, pin
+            )));
+        }
+        Ok(())
+    }
+
+    /// Check a driver ring buffer size, which ESP-IDF only accepts as 0 or above the FIFO size
+    #[cfg(target_os = "espidf")]
+    fn driver_buffer_size(size: usize, name: &str) -> usize {
+        if size == 0 || size > UART_HW_FIFO_LEN {
+            return size;
+        }
+        warn!(
+            "UART driver {} buffer of {} bytes is too small, using {} bytes",
+            name,
+            size,
+            UART_HW_FIFO_LEN + 1
+        );
+        UART_HW_FIFO_LEN + 1
+    }
+
+    /// Build the driver configuration for a baudrate and character format
+    #[cfg(target_os = "espidf")]
+    fn driver_config(baudrate: u32, format: &SerialFormat) -> config::Config {
+        let uart_config = config::Config::new()
+            .baudrate(Hertz(baudrate))
+            .data_bits(Self::hal_data_bits(format.data_bits))
+            .stop_bits(Self::hal_stop_bits(format.stop_bits));
+        match format.parity {
+            Parity::None => uart_config.parity_none(),
+            Parity::Even => uart_config.parity_even(),
+            Parity::Odd => uart_config.parity_odd(),
+        }
+    }
+
+    #[cfg(target_os = "espidf")]
+    fn hal_data_bits(data_bits: u8) -> config::DataBits {
+        match data_bits {
+            5 => config::DataBits::DataBits5,
+            6 => config::DataBits::DataBits6,
+            7 => config::DataBits::DataBits7,
+            _ => config::DataBits::DataBits8,
+        }
+    }
+
+    #[cfg(target_os = "espidf")]
+    fn hal_parity(parity: Parity) -> config::Parity {
+        match parity {
+            Parity::None => config::Parity::ParityNone,
+            Parity::Even => config::Parity::ParityEven,
+            Parity::Odd => config::Parity::ParityOdd,
+        }
+    }
+
+    #[cfg(target_os = "espidf")]
+    fn hal_stop_bits(stop_bits: StopBits) -> config::StopBits {
+        match stop_bits {
+            StopBits::One => config::StopBits::STOP1,
+            StopBits::OnePointFive => config::StopBits::STOP1P5,
+            StopBits::Two => config::StopBits::STOP2,
+        }
+    }
+
+    /// 检查波特率是否在支持的范围内
+    ///
+    /// Whether the clock divider can reach the rate, and the whitelist of strict
+    /// deployments, are checked when it is applied.
+    pub fn is_valid_baudrate(baudrate: u32) -> bool {
+        (MIN_BAUDRATE..=MAX_BAUDRATE).contains(&baudrate)
+    }
+
+    /// 获取当前波特率
+    pub fn get_baudrate(&self) -> u32 {
+        self.baudrate.load(Ordering::Acquire)
+    }
+
+    /// 获取当前数据位、校验位和停止位
+    pub fn get_format(&self) -> SerialFormat {
+        Self::read_setting(&self.format)
+    }
+
+    /// Read a runtime setting kept behind a leaf mutex
+    ///
+    /// A setting is a plain value that is always complete, so it stays valid even
+    /// if a thread panicked while holding the lock.
+    fn read_setting<T: Copy>(setting: &Mutex<T>) -> T {
+        setting
+            .lock()
+            .map(|value| *value)
+            .unwrap_or_else(|poisoned| *poisoned.into_inner())
+    }
+
+    /// 获取当前接收分帧设置
+    pub fn framing(&self) -> Framing {
+        self.framing
+            .lock()
+            .map(|framing| framing.clone())
+            .unwrap_or_else(|poisoned| poisoned.into_inner().clone())
+    }
+
+    /// Change the gap and size limit of receive framing
+    ///
+    /// Takes effect immediately and keeps the delimiter; a frame collected under
+    /// the old settings is sent by the forwarding thread on its next round.
+    pub fn set_frame_gap(&self, gap_ms: u64, max_bytes: usize) -> Result<()> {
+        if !(1..=MAX_FRAME_BYTES).contains(&max_bytes) {
+            return Err(Error::uart(format!(
+                "Frame size must be 1-{} bytes",
+                MAX_FRAME_BYTES
+            )));
+        }
+        let mut framing = self.framing.lock().map_err(|_| Error::uart("Failed to lock framing settings"))?;
+        framing.gap_ms = gap_ms;
+        framing.max_bytes = max_bytes;
+        info!("UART receive framing: {}", framing);
+        Ok(())
+    }
+
+    /// Change the size and latency limits of receive batching (0 ms disables batching)
+    ///
+    /// Takes effect immediately; a batch collected under the old settings is sent
+    /// by the forwarding thread on its next round. Not saved.
+    pub fn set_batching(&self, batch_bytes: usize, batch_ms: u64) -> Result<()> {
+        if !(1..=MAX_FRAME_BYTES).contains(&batch_bytes) {
+            return Err(Error::uart(format!(
+                "Batch size must be 1-{} bytes",
+                MAX_FRAME_BYTES
+            )));
+        }
+        if batch_ms > MAX_BATCH_LATENCY_MS {
+            return Err(Error::uart(format!(
+                "Batch latency must be at most {} ms",
+                MAX_BATCH_LATENCY_MS
+            )));
+        }
+        let mut framing = self.framing.lock().map_err(|_| Error::uart("Failed to lock framing settings"))?;
+        framing.batch_bytes = batch_bytes;
+        framing.batch_ms = batch_ms;
+        info!("UART receive framing: {}", framing);
+        Ok(())
+    }
+
+    /// Change the frame delimiter (None disables delimiter framing)
+    ///
+    /// The new delimiter is saved to flash and used after a restart.
+    pub fn set_frame_delimiter(&self, delimiter: Option<&[u8]>) -> Result<()> {
+        let delimiter = match delimiter {
+            Some([]) => return Err(Error::uart("Empty frame delimiter")),
+            Some(bytes) => Some(Delimiter::from_slice(bytes).map_err(|_| {
+                Error::uart(format!("Frame delimiter must be at most {} bytes", MAX_DELIMITER_LEN))
+            })?),
+            None => None,
+        };
+        {
+            let mut framing = self.framing.lock().map_err(|_| Error::uart("Failed to lock framing settings"))?;
+            framing.delimiter = delimiter.clone();
+            info!("UART receive framing: {}", framing);
+        }
+
+        if let Some(storage_mutex) = &self.storage {
+            match storage_mutex.lock() {
+                Ok(mut storage) => {
+                    if let Err(e) = storage.save_frame_delimiter(delimiter.as_deref()) {
+                        warn!("Failed to save frame delimiter to flash: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to lock storage manager: {}, frame delimiter will not be persisted", e),
+            }
+        }
+        Ok(())
+    }
+
+    /// Forward received UART data, taking out command lines if UART commands are enabled
+    fn forward_data(
+        &self,
+        frames: &mut FrameAccumulator,
+        commands: &mut CommandScanner,
+        client_manager: &TcpClientManager,
+        udp_peers: Option<&UdpPeerManager>,
+        data: &[u8],
+    ) {
+        let Some((prefix, handler)) = self.command_channel() else {
+            self.forward_frames(frames, client_manager, udp_peers, data);
+            return;
+        };
+        commands.push(
+            data,
+            prefix,
+            |data| {
+                if !data.is_empty() {
+                    self.forward_frames(frames, client_manager, udp_peers, data);
+                }
+            },
+            |line| self.run_uart_command(handler, line),
+        );
+    }
+
+    /// Forward UART data, collecting it into frames if framing is enabled
+    fn forward_frames(
+        &self,
+        frames: &mut FrameAccumulator,
+        client_manager: &TcpClientManager,
+        udp_peers: Option<&UdpPeerManager>,
+        data: &[u8],
+    ) {
+        let framing = self.framing();
+        if self.config.packet_codec != PacketCodec::None {
+            self.forward_packets(client_manager, udp_peers, data, framing.max_bytes);
+            return;
+        }
+        let emit = |frame: &[u8]| self.distribute(client_manager, udp_peers, frame);
+        if framing.is_enabled() {
+            frames.push(data, &framing, emit);
+        } else {
+            // 分帧刚被关闭时先发出已收集的数据，保持顺序
+            frames.flush(emit);
+            self.distribute(client_manager, udp_peers, data);
+        }
+    }
+
+    /// Decode UART data with the packet codec and forward each complete packet
+    fn forward_packets(
+        &self,
+        client_manager: &TcpClientManager,
+        udp_peers: Option<&UdpPeerManager>,
+        data: &[u8],
+        max_len: usize,
+    ) {
+        // 解码器锁在广播之前释放，先收集本次读取中完整的数据包
+        let mut packets = Vec::new();
+        {
+            let mut decoder = self.packet_rx.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            decoder.decode(data, max_len, |packet| match packet {
+                Ok(packet) => packets.push(packet.to_vec()),
+                Err(e) => {
+                    self.packet_errors.fetch_add(1, Ordering::Relaxed);
+                    debug!("Dropped {} packet from UART: {}", self.config.packet_codec, e);
+                }
+            });
+        }
+        for packet in &packets {
+            self.distribute(client_manager, udp_peers, packet);
+        }
+    }
+
+    /// Send the collected frame once its gap has passed (or framing was disabled)
+    ///
+    /// A held UART command line that did not end in time is forwarded first.
+    fn forward_due_frame(
+        &self,
+        frames: &mut FrameAccumulator,
+        commands: &mut CommandScanner,
+        client_manager: &TcpClientManager,
+        udp_peers: Option<&UdpPeerManager>,
+    ) {
+        commands.poll(|data| self.forward_frames(frames, client_manager, udp_peers, data));
+        let framing = self.framing();
+        let emit = |frame: &[u8]| self.distribute(client_manager, udp_peers, frame);
+        if framing.is_enabled() {
+            frames.poll(&framing, emit);
+        } else {
+            frames.flush(emit);
+        }
+    }
+
+    /// Start UART forwarding service
+    ///
+    /// This method starts a thread that reads data from UART and forwards it to TCP clients
+    /// and, if given, UDP peers, and the `uart_tx` thread that writes data queued by
+    /// `send_data`. Highly optimized for low latency.
+    pub fn start_forwarding(
+        self_arc: Arc<Self>,
+        client_manager: Arc<TcpClientManager>,
+        udp_peers: Option<Arc<UdpPeerManager>>,
+    ) -> Result<()> {
+        Self::spawn_tx_writer(&self_arc)?;
+
+        let uart_manager = Arc::clone(&self_arc);
+        let config = uart_manager.config.clone();
+
+        let builder = thread::Builder::new()
+            .name("uart_forwarding".into())
+            .stack_size(4096); // 指定足够的栈大小
+
+        builder.spawn(move || {
+            // 使用高优先级线程处理UART数据
+            diagnostics::set_task_priority(24);
+            // 转发线程卡住时由看门狗复位
+            let _watchdog = watchdog::supervise("uart_forwarding");
+            let _tracker = diagnostics::track_thread("uart_forwarding");
+            // 预分配缓冲区以避免运行时分配
+            let mut buffer = vec![0u8; config.buffer_size];
+
+            if uart_manager.rx_events.is_some() {
+                uart_manager.forward_events(&client_manager, udp_peers.as_deref(), &mut buffer);
+            }
+
+            // 轮询模式（事件队列不可用或被禁用时的后备方案）
+            let poll_interval = Duration::from_millis(config.poll_interval_ms);
+
+            // 记录上次有数据的时间，用于自适应轮询
+            let mut last_data_time = Stopwatch::start();
+            let mut adaptive_interval = poll_interval;
+
+            // 检查是否有客户端的频率较低，减少不必要的检查
+            let mut check_counter = 0;
+            let check_interval = 10; // 每10次读取才检查一次客户端数量
+            let mut frames = FrameAccumulator::new();
+            let mut commands = CommandScanner::new();
+            let mut backpressure = Backpressure::new(&config);
+
+            loop {
+                watchdog::feed();
+
+                // 定期检查是否有客户端连接
+                check_counter += 1;
+                if check_counter >= check_interval {
+                    check_counter = 0;
+                    // 如果没有客户端，可以使用更长的轮询间隔
+                    if !uart_manager.has_receivers(&client_manager, udp_peers.as_deref()) {
+                        thread::sleep(Duration::from_millis(50)); // 更长的睡眠时间
+                        continue;
+                    }
+                }
+
+                if backpressure.should_pause(&uart_manager, &client_manager) {
+                    uart_manager.forward_due_frame(&mut frames, &mut commands, &client_manager, udp_peers.as_deref());
+                    thread::sleep(Duration::from_millis(BACKPRESSURE_CHECK_MS));
+                    continue;
+                }
+
+                // 使用非阻塞模式读空驱动缓冲区，立即广播到所有TCP客户端和UDP接收方（启用分帧时先收集成帧）
+                let forwarded = uart_manager.drain_rx(
+                    &mut frames,
+                    &mut commands,
+                    &mut backpressure,
+                    &client_manager,
+                    udp_peers.as_deref(),
+                    &mut buffer,
+                );
+                if forwarded > 0 {
+                    // 更新最后收到数据的时间
+                    last_data_time.restart();
+
+                    // 当有数据时使用最短轮询间隔，减少延迟
+                    adaptive_interval = poll_interval;
+                } else if last_data_time.has_elapsed(Duration::from_millis(100)) {
+                    // 如果长时间没有数据，可以增加轮询间隔以减少CPU使用，最多增加到5ms，保证响应性
+                    adaptive_interval = Duration::from_millis(
+                        (config.poll_interval_ms).min(5)
+                    );
+                }
+                uart_manager.forward_due_frame(&mut frames, &mut commands, &client_manager, udp_peers.as_deref());
+
+                // 使用自适应的轮询间隔
+                thread::sleep(adaptive_interval);
+            }
+        }).map_err(|e| Error::uart_caused("Failed to spawn UART forwarding thread", e))?;
+
+        info!("UART to TCP forwarding service started with optimized latency");
+        Ok(())
+    }
+}
+
+// 旧的兼容性函数已删除
+
+#[cfg(test)]
+pub(crate) mod mock {
+    use super::*;
+    use crate::time;
+
+    /// Both ends of a `MockSerial` as seen by the test
+    #[derive(Debug)]
+    pub(crate) struct MockLine {
+        /// Bytes the attached device sent, not read yet
+        pub rx: VecDeque<u8>,
+        /// Bytes written to the attached device
+        pub tx: Vec<u8>,
+        /// Baudrate of each write
+        pub write_baudrates: Vec<u32>,
+        /// Whether written bytes are received again, as with TX jumpered to RX
+        pub loopback: bool,
+        /// Device sending this text over and over at this baudrate; read at another
+        /// rate the text arrives garbled
+        pub talker: Option<(u32, &'static [u8])>,
+        /// Baudrate the port runs at
+        pub baudrate: u32,
+        /// Character format the port uses
+        pub format: SerialFormat,
+        /// Fastest baudrate the clock divider reaches (None reaches every rate)
+        pub max_baudrate: Option<u32>,
+        /// Whether the TX FIFO never drains, so `wait_tx_done` times out
+        pub tx_stuck: bool,
+        /// Timeout passed to the last `wait_tx_done`
+        pub drain_timeout: Option<Duration>,
+        /// Whether `set_format` fails
+        pub reject_format: bool,
+        /// Whether `set_baudrate` fails, as with a driver that cannot change it at runtime
+        pub reject_baudrate: bool,
+        /// Whether writes fail, as with a driver error
+        pub reject_writes: bool,
+    }
+
+    /// Driver error reported by a `MockSerial`, named like the ESP-IDF error
+    #[derive(Debug)]
+    pub(crate) struct MockError(pub &'static str);
+
+    impl fmt::Display for MockError {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for MockError {}
+
+    /// Shared handle on the line of a mock serial port
+    pub(crate) type Line = Arc<Mutex<MockLine>>;
+
+    /// Serial port writing to and reading from a `MockLine`
+    ///
+    /// Reads return `UartRead::Timeout` at once when nothing was received, after
+    /// moving the clock forward by the timeout (see `time::advance`).
+    pub(crate) struct MockSerial {
+        line: Line,
+    }
+
+    impl MockSerial {
+        /// Create a port at the default settings, returning it and its line
+        pub(crate) fn open() -> (Box<dyn SerialPort>, Line) {
+            let line = Arc::new(Mutex::new(MockLine {
+                rx: VecDeque::new(),
+                tx: Vec::new(),
+                write_baudrates: Vec::new(),
+                loopback: false,
+                talker: None,
+                baudrate: 0,
+                format: SerialFormat::default(),
+                max_baudrate: None,
+                tx_stuck: false,
+                drain_timeout: None,
+                reject_format: false,
+                reject_baudrate: false,
+                reject_writes: false,
+            }));
+            (Box::new(Self { line: Arc::clone(&line) }), line)
+        }
+    }
+
+    impl SerialPort for MockSerial {
+        fn write(&self, data: &[u8]) -> Result<usize> {
+            let mut line = self.line.lock().unwrap();
+            if line.reject_writes {
+                return Err(Error::uart_caused("Failed to write to UART", MockError("ESP_FAIL")));
+            }
+            line.tx.extend_from_slice(data);
+            let baudrate = line.baudrate;
+            line.write_baudrates.push(baudrate);
+            if line.loopback {
+                line.rx.extend(data);
+            }
+            Ok(data.len())
+        }
+
+        fn read(&self, buffer: &mut [u8], timeout: Option<Duration>) -> Result<UartRead> {
+            let mut line = self.line.lock().unwrap();
+            if let Some((baudrate, text)) = line.talker.filter(|_| line.rx.is_empty()) {
+                let garbled = baudrate != line.baudrate;
+                line.rx.extend(text.iter().map(|&b| if garbled { b | 0x80 } else { b }));
+            }
+            if line.rx.is_empty() {
+                time::advance(timeout.unwrap_or_default());
+                return Ok(UartRead::Timeout);
+            }
+            let len = buffer.len().min(line.rx.len());
+            for (slot, byte) in buffer.iter_mut().zip(line.rx.drain(..len)) {
+                *slot = byte;
+            }
+            Ok(UartRead::Data(len))
+        }
+
+        fn wait_tx_done(&self, timeout: Duration) -> Result<()> {
+            let mut line = self.line.lock().unwrap();
+            line.drain_timeout = Some(timeout);
+            if line.tx_stuck {
+                time::advance(timeout);
+                return Err(Error::uart_caused("Failed to drain UART TX", MockError("ESP_ERR_TIMEOUT")));
+            }
+            Ok(())
+        }
+
+        fn flush_input(&self) -> Result<()> {
+            self.line.lock().unwrap().rx.clear();
+            Ok(())
+        }
+
+        fn set_baudrate(&self, baudrate: u32) -> Result<u32> {
+            let mut line = self.line.lock().unwrap();
+            if line.reject_baudrate {
+                return Err(Error::uart_caused("Failed to set UART baudrate", MockError("ESP_FAIL")));
+            }
+            line.baudrate = line.max_baudrate.map_or(baudrate, |max| baudrate.min(max));
+            Ok(line.baudrate)
+        }
+
+        fn set_format(&self, format: &SerialFormat) -> Result<()> {
+            let mut line = self.line.lock().unwrap();
+            if line.reject_format {
+                return Err(Error::uart_caused("Failed to set parity", MockError("ESP_ERR_INVALID_ARG")));
+            }
+            line.format = *format;
+            Ok(())
+        }
+    }
+
+    /// Create a UART manager on a mock port, returning it and the port's line
+    pub(crate) fn manager(config: UartConfig) -> (UartManager, Line) {
+        let (port, line) = MockSerial::open();
+        (UartManager::with_port(port, config, None).unwrap(), line)
+    }
+
+    /// Run one round of the `uart_tx` writer and the forwarding thread, which tests do not start
+    ///
+    /// Writes the queued data to the port, then broadcasts what the port received
+    /// to the clients of `client_manager`.
+    pub(crate) fn pump(uart: &UartManager, client_manager: &TcpClientManager) -> Result<()> {
+        for data in queued_chunks(uart) {
+            uart.tx_queue_len.fetch_sub(1, Ordering::Relaxed);
+            uart.write_data(&data)?;
+        }
+        let mut buffer = [0u8; 256];
+        let len = uart.receive_data(&mut buffer)?;
+        if len > 0 {
+            uart.distribute(client_manager, None, &buffer[..len]);
+        }
+        Ok(())
+    }
+
+    /// Take the chunks queued for the `uart_tx` writer thread
+    fn queued_chunks(uart: &UartManager) -> Vec<Vec<u8>> {
+        let receiver = uart.tx_receiver.lock().unwrap();
+        let receiver = receiver.as_ref().expect("UART writer thread is running");
+        receiver.try_iter().collect()
+    }
+
+    /// Take the data queued for the `uart_tx` writer thread, which tests do not start
+    pub(crate) fn queued_tx(uart: &UartManager) -> Vec<u8> {
+        queued_chunks(uart).concat()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::mock::{manager, Line};
+    use super::*;
+    use crate::config::{Parity, StopBits};
+    use crate::tcp_client_manager::mock::{addr, MockStream, Wire};
+    use crate::time;
+
+    /// Client manager with one raw mode mock client
+    fn client_manager() -> (TcpClientManager, Wire) {
+        let client_manager = TcpClientManager::new();
+        let (stream, wire) = MockStream::new(addr(1));
+        client_manager.add_client(addr(1), stream).unwrap();
+        client_manager.set_raw_mode(&addr(1), true).unwrap();
+        (client_manager, wire)
+    }
+
+    /// Read what the attached device sent and forward it, returning what the client got
+    fn forward(uart: &UartManager, line: &Line, client_manager: &TcpClientManager, wire: &Wire, rx: &[u8]) -> Vec<u8> {
+        let mut frames = FrameAccumulator::new();
+        let mut commands = CommandScanner::new();
+        let mut backpressure = Backpressure::new(&uart.config);
+        let mut buffer = [0u8; 16];
+        line.lock().unwrap().rx.extend(rx);
+        uart.drain_rx(&mut frames, &mut commands, &mut backpressure, client_manager, None, &mut buffer);
+        uart.forward_due_frame(&mut frames, &mut commands, client_manager, None);
+        client_manager.write_queued().unwrap();
+        std::mem::take(&mut wire.lock().unwrap().output)
+    }
+
+    fn format_7e2() -> SerialFormat {
+        SerialFormat { data_bits: 7, parity: Parity::Even, stop_bits: StopBits::Two }
+    }
+
+    #[test]
+    fn with_port_applies_the_configured_settings() {
+        let (uart, line) = manager(UartConfig { baudrate: 57600, ..UartConfig::default() });
+        assert_eq!(line.lock().unwrap().baudrate, 57600);
+        assert_eq!(uart.get_baudrate(), 57600);
+    }
+
+    #[test]
+    fn reconfigure_applies_baudrate_and_format() {
+        let (uart, line) = manager(UartConfig::default());
+        assert_eq!(uart.set_serial_params(460800, format_7e2()).unwrap(), 460800);
+
+        let line = line.lock().unwrap();
+        assert_eq!((line.baudrate, line.format), (460800, format_7e2()));
+        assert_eq!((uart.get_baudrate(), uart.get_format()), (460800, format_7e2()));
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn baudrate_is_reported_when_the_driver_cannot_change_it() {
+        let (uart, line) = manager(UartConfig::default());
+        line.lock().unwrap().reject_baudrate = true;
+
+        // 保存的波特率在重启后生效，所以报告新的波特率
+        assert_eq!(uart.set_baudrate(9600).unwrap(), 9600);
+        assert_eq!(uart.get_baudrate(), 9600);
+        assert_eq!(line.lock().unwrap().baudrate, 115_200);
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn reconfigure_refuses_invalid_and_unreachable_baudrates() {
+        let (uart, line) = manager(UartConfig::default());
+        assert!(uart.set_baudrate(MAX_BAUDRATE + 1).is_err());
+
+        line.lock().unwrap().max_baudrate = Some(1_000_000);
+        let e = uart.set_baudrate(1_500_000).unwrap_err();
+        assert!(e.to_string().contains("cannot be achieved"), "{}", e);
+        assert_eq!(line.lock().unwrap().baudrate, 115_200);
+        assert_eq!(uart.get_baudrate(), 115_200);
+    }
+
+    #[test]
+    fn strict_baudrates_refuse_nonstandard_rates() {
+        let (uart, _line) = manager(UartConfig { strict_baudrates: true, ..UartConfig::default() });
+        assert!(uart.set_baudrate(100_000).is_err());
+        assert!(uart.set_baudrate(230_400).is_ok());
+    }
+
+    #[test]
+    fn data_queued_during_reconfiguration_is_written_afterwards() {
+        let (uart, line) = manager(UartConfig::default());
+        let window = ReconfigWindow::open(&uart).unwrap();
+        uart.write_data(b"abc").unwrap();
+        assert!(line.lock().unwrap().tx.is_empty());
+
+        let port = uart.uart.lock().unwrap();
+        window.finish(&**port).unwrap();
+        assert_eq!(line.lock().unwrap().tx, b"abc");
+    }
+
+    #[test]
+    fn no_chunk_is_split_across_a_baudrate_change() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig { tx_queue_capacity: 256, ..UartConfig::default() });
+        let uart = Arc::new(uart);
+        UartManager::spawn_tx_writer(&uart).unwrap();
+
+        let sender = {
+            let uart = Arc::clone(&uart);
+            thread::spawn(move || {
+                for chunk in 0..200u32 {
+                    uart.send_data(format!("chunk {:03}\n", chunk).as_bytes()).unwrap();
+                }
+            })
+        };
+        for &baudrate in SUPPORTED_BAUDRATES.iter().cycle().take(20) {
+            uart.set_baudrate(baudrate).unwrap();
+        }
+        sender.join().unwrap();
+        while uart.get_tx_queue_len() > 0 || uart.stats().bytes_sent_to_uart < 200 * 10 {
+            thread::sleep(Duration::from_millis(1));
+        }
+
+        // 每块数据只以一种波特率一次写出，并保持顺序
+        let line = line.lock().unwrap();
+        let expected: String = (0..200).map(|chunk| format!("chunk {:03}\n", chunk)).collect();
+        assert_eq!(line.tx, expected.as_bytes());
+        assert_eq!(line.write_baudrates.len(), 200);
+    }
+
+    #[test]
+    fn reconfigure_goes_ahead_when_tx_does_not_drain() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+        line.lock().unwrap().tx_stuck = true;
+
+        assert_eq!(uart.set_baudrate(9600).unwrap(), 9600);
+        let line = line.lock().unwrap();
+        assert_eq!(line.drain_timeout, Some(Duration::from_millis(RECONFIG_TIMEOUT_MS)));
+        assert_eq!(line.baudrate, 9600);
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn failed_reconfiguration_closes_the_window() {
+        let (uart, line) = manager(UartConfig::default());
+        line.lock().unwrap().reject_format = true;
+
+        assert!(uart.set_serial_params(9600, format_7e2()).is_err());
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+        // 端口保持原来的设置
+        assert_eq!(line.lock().unwrap().baudrate, 115_200);
+        assert_eq!((uart.get_baudrate(), uart.get_format()), (115_200, SerialFormat::default()));
+
+        uart.write_data(b"after").unwrap();
+        assert_eq!(line.lock().unwrap().tx, b"after");
+    }
+
+    #[test]
+    fn reconfigure_gives_up_when_the_uart_stays_locked() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+
+        let port = uart.uart.lock().unwrap();
+        let e = uart.set_baudrate(9600).unwrap_err();
+        drop(port);
+        assert!(e.to_string().contains("Timed out waiting for UART"), "{}", e);
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+        assert_eq!(line.lock().unwrap().baudrate, 115_200);
+    }
+
+    #[test]
+    fn data_left_by_an_aborted_window_is_written_first() {
+        let (uart, line) = manager(UartConfig::default());
+        let window = ReconfigWindow::open(&uart).unwrap();
+        uart.write_data(b"abc").unwrap();
+        drop(window);
+
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+        uart.write_data(b"def").unwrap();
+        assert_eq!(line.lock().unwrap().tx, b"abcdef");
+    }
+
+    #[test]
+    fn data_of_a_failed_write_stays_queued() {
+        let (uart, line) = manager(UartConfig::default());
+        let window = ReconfigWindow::open(&uart).unwrap();
+        uart.write_data(b"abc").unwrap();
+        uart.write_data(b"def").unwrap();
+        drop(window);
+
+        // 写入失败时队列中的数据和本次数据都保留
+        line.lock().unwrap().reject_writes = true;
+        assert!(uart.write_data(b"ghi").is_err());
+        assert!(line.lock().unwrap().tx.is_empty());
+        {
+            let pending = uart.pending_tx.lock().unwrap();
+            assert_eq!(pending.chunks, [b"abc".to_vec(), b"def".to_vec(), b"ghi".to_vec()]);
+            assert_eq!(pending.bytes, 9);
+        }
+
+        line.lock().unwrap().reject_writes = false;
+        uart.write_data(b"jkl").unwrap();
+        assert_eq!(line.lock().unwrap().tx, b"abcdefghijkl");
+        assert_eq!(uart.pending_tx.lock().unwrap().bytes, 0);
+    }
+
+    #[test]
+    fn baud_sample_scores() {
+        let cases: [(&[u8], u32, Option<u32>); 7] = [
+            (b"", 0, None),
+            (b"", 12, None),
+            (&[0x80, 0xfe, 0x00, 0x13], 0, None),
+            (&[0x80, 0xfe], 30, None),
+            (b"OK\r\n\tready", 0, Some(1000)),
+            (b"ab\xff\x00", 0, Some(500)),
+            (b"abc", 1, Some(750)),
+        ];
+        for (data, errors, expected) in cases {
+            assert_eq!(score_baud_sample(data, errors), expected, "{:?} with {} errors", data, errors);
+        }
+    }
+
+    #[test]
+    fn queued_chunks_keep_their_order() {
+        let mut pending = PendingTx { chunks: VecDeque::new(), bytes: 0 };
+        pending.push(b"first", 16).unwrap();
+        pending.push(b"second", 16).unwrap();
+        assert_eq!(pending.bytes, 11);
+        assert_eq!(pending.chunks, [b"first".to_vec(), b"second".to_vec()]);
+    }
+
+    #[test]
+    fn chunk_that_does_not_fit_is_refused_whole() {
+        let mut pending = PendingTx { chunks: VecDeque::new(), bytes: 0 };
+        pending.push(b"0123456789", 16).unwrap();
+        assert!(pending.push(b"0123456", 16).is_err());
+        assert_eq!(pending.bytes, 10);
+        assert_eq!(pending.chunks.len(), 1);
+
+        // 剩余空间正好够用时仍然接受
+        pending.push(b"012345", 16).unwrap();
+        assert_eq!(pending.bytes, 16);
+    }
+
+    #[test]
+    fn runtime_setting_survives_a_poisoned_lock() {
+        let format = Arc::new(Mutex::new(SerialFormat::default()));
+        let seven_e_one = SerialFormat::parse_compact("7E1").unwrap();
+        {
+            let format = Arc::clone(&format);
+            let _ = thread::spawn(move || {
+                let mut guard = format.lock().unwrap();
+                *guard = seven_e_one;
+                panic!("reconfiguration failed");
+            })
+            .join();
+        }
+        assert!(format.is_poisoned());
+        assert_eq!(UartManager::read_setting(&format), seven_e_one);
+    }
+
+    #[test]
+    fn full_writer_queue_refuses_data() {
+        let (sender, receiver) = mpsc::sync_channel(2);
+        let queue_len = AtomicUsize::new(0);
+        UartManager::enqueue_tx(&sender, &queue_len, b"one").unwrap();
+        UartManager::enqueue_tx(&sender, &queue_len, b"two").unwrap();
+        let err = UartManager::enqueue_tx(&sender, &queue_len, b"three").unwrap_err();
+        assert!(err.to_string().contains("tx queue full"));
+        assert_eq!(queue_len.load(Ordering::Relaxed), 2);
+
+        // 写线程按顺序取出数据
+        assert_eq!(receiver.recv().unwrap(), b"one");
+        drop(receiver);
+        let err = UartManager::enqueue_tx(&sender, &queue_len, b"four").unwrap_err();
+        assert!(err.to_string().contains("writer thread stopped"));
+        assert_eq!(queue_len.load(Ordering::Relaxed), 2);
+    }
+
+    #[test]
+    fn uart_pins_outside_the_chip_or_on_flash_are_refused() {
+        assert!(UartManager::check_gpio(21, "TX").is_ok());
+        assert!(UartManager::check_gpio(0, "RX").is_ok());
+        assert!(UartManager::check_gpio(-1, "TX").is_err());
+        assert!(UartManager::check_gpio(22, "TX").is_err());
+        for pin in 12..=17 {
+            let err = UartManager::check_gpio(pin, "RX").unwrap_err();
+            assert!(err.to_string().contains("SPI flash"));
+        }
+    }
+
+    #[test]
+    fn rs485_de_pin_must_not_be_a_data_pin() {
+        let mut config = UartConfig::default();
+        assert_eq!(UartManager::rs485_de_pin(&config).unwrap(), None);
+
+        config.rs485_de_pin = Some(4);
+        assert_eq!(UartManager::rs485_de_pin(&config).unwrap(), Some(4));
+
+        for pin in [config.tx_pin, config.rx_pin] {
+            config.rs485_de_pin = Some(pin as u8);
+            assert!(UartManager::rs485_de_pin(&config).is_err());
+        }
+    }
+
+    #[test]
+    fn hardware_flow_control_needs_a_free_rts_or_cts_pin() {
+        let mut config = UartConfig { rts_pin: Some(6), cts_pin: Some(7), ..UartConfig::default() };
+        // 不使用硬件流控时忽略RTS和CTS引脚
+        assert_eq!(UartManager::flow_control_pins(&config, None).unwrap(), (None, None));
+
+        config.flow_control = FlowControl::Hardware;
+        assert_eq!(UartManager::flow_control_pins(&config, None).unwrap(), (Some(6), Some(7)));
+        let err = UartManager::flow_control_pins(&config, Some(4)).unwrap_err();
+        assert!(err.to_string().contains("both use the RTS signal"), "{}", err);
+
+        config.rts_pin = None;
+        assert_eq!(UartManager::flow_control_pins(&config, Some(4)).unwrap(), (None, Some(7)));
+        config.cts_pin = Some(config.rx_pin as u8);
+        assert!(UartManager::flow_control_pins(&config, None).is_err());
+        config.cts_pin = None;
+        let err = UartManager::flow_control_pins(&config, None).unwrap_err();
+        assert!(err.to_string().contains("needs an RTS or CTS pin"), "{}", err);
+    }
+
+    #[test]
+    fn read_timeout_counts_as_no_data() {
+        assert_eq!(UartRead::Data(12).bytes_read(), 12);
+        assert_eq!(UartRead::Timeout.bytes_read(), 0);
+    }
+
+    #[test]
+    fn frame_is_sent_after_a_quiet_gap() {
+        let _clock = time::lock_clock();
+        let framing = Framing { gap_ms: 20, max_bytes: 64, delimiter: None, batch_bytes: 0, batch_ms: 0 };
+        let mut frames = FrameAccumulator::new();
+        let mut sent: Vec<Vec<u8>> = Vec::new();
+
+        frames.push(b"$GPGGA,", &framing, |frame| sent.push(frame.to_vec()));
+        time::advance(Duration::from_millis(15));
+        frames.push(b"123519*47", &framing, |frame| sent.push(frame.to_vec()));
+        time::advance(Duration::from_millis(15));
+        frames.poll(&framing, |frame| sent.push(frame.to_vec()));
+        assert!(sent.is_empty());
+        let due = frames.due_in(&framing).unwrap();
+        assert!(due > Duration::ZERO && due <= Duration::from_millis(5), "{:?}", due);
+
+        time::advance(Duration::from_millis(5));
+        frames.poll(&framing, |frame| sent.push(frame.to_vec()));
+        assert_eq!(sent, [b"$GPGGA,123519*47".to_vec()]);
+        assert_eq!(frames.due_in(&framing), None);
+    }
+
+    #[test]
+    fn full_frames_are_sent_without_waiting() {
+        let framing = Framing { gap_ms: 1000, max_bytes: 4, delimiter: None, batch_bytes: 0, batch_ms: 0 };
+        let mut frames = FrameAccumulator::new();
+        let mut sent: Vec<Vec<u8>> = Vec::new();
+
+        frames.push(b"0123456789", &framing, |frame| sent.push(frame.to_vec()));
+        assert_eq!(sent, [b"0123".to_vec(), b"4567".to_vec()]);
+        frames.flush(|frame| sent.push(frame.to_vec()));
+        assert_eq!(sent.last().unwrap(), b"89");
+    }
+
+    #[test]
+    fn framing_is_shown_readably() {
+        let gap = |gap_ms| Framing { gap_ms, max_bytes: 256, delimiter: None, batch_bytes: 0, batch_ms: 0 };
+        assert_eq!(gap(5).to_string(), "gap 5 ms, max 256 bytes");
+        assert_eq!(gap(0).to_string(), "off");
+    }
+
+    #[test]
+    fn detect_baudrate_finds_the_rate_the_device_talks_at() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+        line.lock().unwrap().talker = Some((38400, b"temperature=21.5\r\n"));
+
+        let report = uart.detect_baudrate().unwrap();
+        assert_eq!(report.best, Some(38400));
+        assert_eq!(report.scores.len(), SUPPORTED_BAUDRATES.len());
+        assert!(report.scores.iter().all(|&(baudrate, score)| (baudrate == 38400) == score.is_some()));
+        // 检测后恢复原来的波特率
+        assert_eq!(line.lock().unwrap().baudrate, 115_200);
+    }
+
+    #[test]
+    fn detect_baudrate_without_data_has_no_candidate() {
+        let _clock = time::lock_clock();
+        let (uart, _line) = manager(UartConfig::default());
+
+        let report = uart.detect_baudrate().unwrap();
+        assert_eq!(report.best, None);
+        assert!(report.scores.iter().all(|(_, score)| score.is_none()));
+    }
+
+    #[test]
+    fn loopback_test_reads_the_pattern_back() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+        {
+            let mut line = line.lock().unwrap();
+            line.loopback = true;
+            line.rx.extend(b"stale");
+        }
+
+        let report = uart.loopback_test(b"0123456789").unwrap();
+        assert!(report.passed(), "{}", report);
+        assert_eq!(report.received, 10);
+        assert!(line.lock().unwrap().rx.is_empty());
+        assert!(!uart.reconfiguring.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn loopback_test_without_jumper_receives_nothing() {
+        let _clock = time::lock_clock();
+        let (uart, _line) = manager(UartConfig::default());
+
+        let report = uart.loopback_test(b"0123456789").unwrap();
+        assert!(!report.passed());
+        assert_eq!((report.sent, report.received), (10, 0));
+        assert!(!uart.sampling.load(Ordering::Acquire));
+    }
+
+    #[test]
+    fn received_data_reaches_the_clients() {
+        let (uart, line) = manager(UartConfig::default());
+        let (client_manager, wire) = client_manager();
+
+        // 比读取缓冲区长的数据分多次读出
+        let data: Vec<u8> = (0..40).collect();
+        assert_eq!(forward(&uart, &line, &client_manager, &wire, &data), data);
+        assert_eq!(uart.stats().bytes_received_from_uart, 40);
+    }
+
+    #[test]
+    fn delimiter_framing_holds_back_the_unterminated_frame() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+        let (client_manager, wire) = client_manager();
+        uart.set_frame_delimiter(Some(b"\n")).unwrap();
+
+        let mut frames = FrameAccumulator::new();
+        let mut commands = CommandScanner::new();
+        let mut backpressure = Backpressure::new(&uart.config);
+        let mut buffer = [0u8; 16];
+        line.lock().unwrap().rx.extend(b"one\ntw");
+        uart.drain_rx(&mut frames, &mut commands, &mut backpressure, &client_manager, None, &mut buffer);
+        client_manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"one\n");
+
+        line.lock().unwrap().rx.extend(b"o\n");
+        uart.drain_rx(&mut frames, &mut commands, &mut backpressure, &client_manager, None, &mut buffer);
+        client_manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, b"one\ntwo\n");
+    }
+
+    #[test]
+    fn line_endings_are_translated_for_command_mode_clients_only() {
+        let (uart, _line) = manager(UartConfig::default());
+        let (client_manager, raw_wire) = client_manager();
+        let (stream, text_wire) = MockStream::new(addr(2));
+        client_manager.add_client(addr(2), stream).unwrap();
+        uart.set_line_endings(TcpToUartEol::None, UartToTcpEol::LfToCrLf);
+
+        // CR和LF分在两次读取中
+        uart.distribute(&client_manager, None, b"one\r");
+        uart.distribute(&client_manager, None, b"\ntwo\n");
+        client_manager.write_queued().unwrap();
+        assert_eq!(raw_wire.lock().unwrap().output, b"one\r\ntwo\n");
+        assert_eq!(text_wire.lock().unwrap().output, b"one\r\ntwo\r\n");
+    }
+
+    #[test]
+    fn uart_command_lines_are_run_instead_of_forwarded() {
+        let (uart, line) = manager(UartConfig { command_prefix: Some("+"), ..UartConfig::default() });
+        let (client_manager, wire) = client_manager();
+        let commands = Arc::new(Mutex::new(Vec::new()));
+        let sink = Arc::clone(&commands);
+        uart.set_command_handler(Box::new(move |line| sink.lock().unwrap().push(line.to_string())));
+
+        let received = forward(&uart, &line, &client_manager, &wire, b"data\r\n+AT+STATS\r\n+ATX\nmore");
+        assert_eq!(received, b"data\r\nmore");
+        assert_eq!(*commands.lock().unwrap(), ["AT+STATS", "ATX"]);
+    }
+
+    #[test]
+    fn paused_bridge_discards_received_data() {
+        let (uart, line) = manager(UartConfig::default());
+        let (client_manager, wire) = client_manager();
+        uart.set_bridge_enabled(false);
+
+        assert!(forward(&uart, &line, &client_manager, &wire, b"lost").is_empty());
+        assert_eq!(uart.bridge_discarded(), 4);
+    }
+
+    /// Config pausing reads above `high` bytes queued for one client, resuming below `low`
+    fn flow_controlled(high: usize, low: usize) -> UartConfig {
+        UartConfig {
+            flow_control: FlowControl::Hardware,
+            backpressure_high_watermark: high,
+            backpressure_low_watermark: low,
+            ..UartConfig::default()
+        }
+    }
+
+    /// Add a raw mode client that takes no data, returning its wire
+    fn add_stalled_client(client_manager: &TcpClientManager, n: u16) -> Wire {
+        let (stream, wire) = MockStream::new(addr(n));
+        client_manager.add_client(addr(n), stream).unwrap();
+        client_manager.set_raw_mode(&addr(n), true).unwrap();
+        wire.lock().unwrap().write_capacity = Some(0);
+        wire
+    }
+
+    #[test]
+    fn backpressure_follows_the_client_furthest_behind() {
+        let _clock = time::lock_clock();
+        let (uart, _line) = manager(flow_controlled(100, 50));
+        let client_manager = TcpClientManager::new();
+        add_stalled_client(&client_manager, 1);
+        let second = add_stalled_client(&client_manager, 2);
+        let mut backpressure = Backpressure::new(&uart.config);
+
+        // 两个客户端合计超过高水位，但单个客户端都没有超过
+        client_manager.broadcast(&[0; 60], &[0; 60]).unwrap();
+        assert!(!backpressure.should_pause(&uart, &client_manager));
+
+        client_manager.broadcast(&[0; 60], &[0; 60]).unwrap();
+        assert!(backpressure.should_pause(&uart, &client_manager));
+        assert!(backpressure.should_pause(&uart, &client_manager));
+
+        // 只有一个客户端追上时仍然暂停
+        second.lock().unwrap().write_capacity = None;
+        client_manager.write_queued().unwrap();
+        assert!(backpressure.should_pause(&uart, &client_manager));
+
+        client_manager.disconnect(&addr(1)).unwrap();
+        assert!(!backpressure.should_pause(&uart, &client_manager));
+        let stats = uart.stats();
+        assert_eq!((stats.flow_pauses, stats.flow_resumes), (1, 1));
+    }
+
+    #[test]
+    fn backpressure_is_off_without_flow_control() {
+        let (uart, _line) = manager(UartConfig { flow_control: FlowControl::None, ..flow_controlled(100, 50) });
+        let client_manager = TcpClientManager::new();
+        add_stalled_client(&client_manager, 1);
+        client_manager.broadcast(&[0; 200], &[0; 200]).unwrap();
+        assert!(!Backpressure::new(&uart.config).should_pause(&uart, &client_manager));
+    }
+
+    #[test]
+    fn stalled_client_is_evicted_after_the_stall_time() {
+        let _clock = time::lock_clock();
+        let (uart, _line) = manager(flow_controlled(100, 50));
+        let client_manager = TcpClientManager::new();
+        add_stalled_client(&client_manager, 1);
+        let mut backpressure = Backpressure::new(&uart.config);
+        client_manager.broadcast(&[0; 150], &[0; 150]).unwrap();
+        assert!(backpressure.should_pause(&uart, &client_manager));
+
+        time::advance(Duration::from_millis(uart.config.backpressure_stall_ms));
+        assert!(backpressure.should_pause(&uart, &client_manager));
+        assert_eq!(client_manager.client_count().unwrap(), 0);
+        assert!(!backpressure.should_pause(&uart, &client_manager));
+    }
+
+    #[test]
+    fn drain_rx_stops_reading_when_a_client_falls_behind() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(flow_controlled(20, 10));
+        let client_manager = TcpClientManager::new();
+        add_stalled_client(&client_manager, 1);
+        let mut frames = FrameAccumulator::new();
+        let mut commands = CommandScanner::new();
+        let mut backpressure = Backpressure::new(&uart.config);
+        let mut buffer = [0u8; 16];
+        line.lock().unwrap().rx.extend([b'x'; 64]);
+
+        // 第二次读取后队列超过高水位，其余数据留在驱动中
+        let forwarded = uart.drain_rx(&mut frames, &mut commands, &mut backpressure, &client_manager, None, &mut buffer);
+        assert_eq!(forwarded, 32);
+        assert_eq!(line.lock().unwrap().rx.len(), 32);
+        assert_eq!(uart.stats().flow_pauses, 1);
+        assert!(backpressure.should_pause(&uart, &client_manager));
+    }
+
+    #[test]
+    fn drain_rx_takes_a_burst_in_one_wakeup() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+        let (client_manager, wire) = client_manager();
+        let mut frames = FrameAccumulator::new();
+        let mut commands = CommandScanner::new();
+        let mut backpressure = Backpressure::new(&uart.config);
+        let mut buffer = vec![0u8; uart.config.buffer_size];
+        // 超过读取缓冲区几倍但不超过客户端队列上限的突发数据，最后一次读取不满
+        let burst: Vec<u8> = (0..uart.config.buffer_size * 3 + 100).map(|i| (i % 251) as u8).collect();
+        assert!(burst.len() < client_manager.queue_limit());
+        line.lock().unwrap().rx.extend(&burst);
+
+        let forwarded = uart.drain_rx(&mut frames, &mut commands, &mut backpressure, &client_manager, None, &mut buffer);
+        assert_eq!(forwarded, burst.len());
+        assert!(line.lock().unwrap().rx.is_empty());
+        assert_eq!(uart.stats().bytes_received_from_uart, burst.len() as u64);
+        client_manager.write_queued().unwrap();
+        assert_eq!(wire.lock().unwrap().output, burst);
+    }
+
+    #[test]
+    fn drain_rx_stops_at_a_short_read() {
+        let _clock = time::lock_clock();
+        let (uart, line) = manager(UartConfig::default());
+        let (client_manager, _wire) = client_manager();
+        let mut frames = FrameAccumulator::new();
+        let mut commands = CommandScanner::new();
+        let mut backpressure = Backpressure::new(&uart.config);
+        let mut buffer = [0u8; 16];
+        // 对端不停发送，每次读取前只收到10个字节
+        line.lock().unwrap().talker = Some((115_200, b"0123456789"));
+
+        let forwarded = uart.drain_rx(&mut frames, &mut commands, &mut backpressure, &client_manager, None, &mut buffer);
+        assert_eq!(forwarded, 10);
+    }
+
+    /// Run `CommandScanner::push` with the prefix "+", returning forwarded data and commands
+    fn scan(scanner: &mut CommandScanner, data: &[u8]) -> (Vec<u8>, Vec<String>) {
+        let mut forwarded = Vec::new();
+        let mut commands = Vec::new();
+        scanner.push(data, b"+", |data| forwarded.extend_from_slice(data), |line| commands.push(line.to_string()));
+        (forwarded, commands)
+    }
+
+    #[test]
+    fn command_scanner_only_takes_prefixed_at_lines() {
+        let _clock = time::lock_clock();
+        let mut scanner = CommandScanner::new();
+        let (forwarded, commands) = scan(&mut scanner, b"+AB\r\nx+AT\r\n+at+baud?\r\n");
+        assert_eq!(forwarded, b"+AB\r\nx+AT\r\n");
+        assert_eq!(commands, ["at+baud?"]);
+    }
+
+    #[test]
+    fn command_scanner_joins_a_line_split_across_reads() {
+        let _clock = time::lock_clock();
+        let mut scanner = CommandScanner::new();
+        assert_eq!(scan(&mut scanner, b"+A"), (Vec::new(), Vec::new()));
+        assert_eq!(scan(&mut scanner, b"T+RST\r"), (Vec::new(), vec!["AT+RST".to_string()]));
+        // CR LF的LF属于命令行
+        assert_eq!(scan(&mut scanner, b"\nok"), (b"ok".to_vec(), Vec::new()));
+    }
+
+    #[test]
+    fn command_scanner_forwards_overlong_lines() {
+        let _clock = time::lock_clock();
+        let mut scanner = CommandScanner::new();
+        let mut line = b"+AT".to_vec();
+        line.resize(1 + MAX_UART_COMMAND_LEN + 10, b'X');
+        line.extend_from_slice(b"\r\n");
+        let (forwarded, commands) = scan(&mut scanner, &line);
+        assert_eq!(forwarded, line);
+        assert!(commands.is_empty());
+    }
+
+    #[test]
+    fn command_scanner_releases_a_line_that_does_not_end() {
+        let _clock = time::lock_clock();
+        let mut scanner = CommandScanner::new();
+        scan(&mut scanner, b"+AT+ST");
+
+        let mut forwarded = Vec::new();
+        scanner.poll(|data| forwarded.extend_from_slice(data));
+        assert!(forwarded.is_empty());
+
+        time::advance(Duration::from_millis(UART_COMMAND_TIMEOUT_MS));
+        scanner.poll(|data| forwarded.extend_from_slice(data));
+        assert_eq!(forwarded, b"+AT+ST");
+        // 后续数据不再位于行首
+        assert_eq!(scan(&mut scanner, b"+AT\r\n").0, b"+AT\r\n");
+    }
+
+    /// Framing with the given gap, delimiter and batch latency, 8 byte frames and 4 byte batches
+    fn framing(gap_ms: u64, delimiter: Option<&[u8]>, batch_ms: u64) -> Framing {
+        Framing {
+            gap_ms,
+            max_bytes: 8,
+            delimiter: delimiter.map(|delimiter| Delimiter::from_slice(delimiter).unwrap()),
+            batch_bytes: 4,
+            batch_ms,
+        }
+    }
+
+    /// Push `data` into `frames`, returning the frames emitted
+    fn push(frames: &mut FrameAccumulator, framing: &Framing, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut emitted = Vec::new();
+        frames.push(data, framing, |frame| emitted.push(frame.to_vec()));
+        emitted
+    }
+
+    /// Poll `frames`, returning the frame emitted if one was due
+    fn poll(frames: &mut FrameAccumulator, framing: &Framing) -> Vec<Vec<u8>> {
+        let mut emitted = Vec::new();
+        frames.poll(framing, |frame| emitted.push(frame.to_vec()));
+        emitted
+    }
+
+    #[test]
+    fn framing_modes_pick_their_limits() {
+        let off = framing(0, None, 0);
+        assert!(!off.is_enabled() && !off.is_batching());
+
+        let batching = framing(0, None, 50);
+        assert!(batching.is_enabled() && batching.is_batching());
+        assert_eq!(batching.limit(), 4);
+
+        // 分帧优先于批量发送
+        for framed in [framing(20, None, 50), framing(0, Some(b"\n"), 50)] {
+            assert!(framed.is_enabled() && !framed.is_batching());
+            assert_eq!(framed.limit(), 8);
+        }
+
+        assert_eq!(framing(20, None, 0).flush_after(), Duration::from_millis(20));
+        assert_eq!(framing(20, Some(b"\n"), 0).flush_after(), Duration::from_millis(20));
+        assert_eq!(framing(0, Some(b"\n"), 0).flush_after(), Duration::from_millis(DELIMITER_FLUSH_MS));
+
+        let zero_sizes = Framing { max_bytes: 0, batch_bytes: 0, ..batching.clone() };
+        assert_eq!(zero_sizes.limit(), 1);
+    }
+
+    #[test]
+    fn batch_is_sent_when_full() {
+        let _clock = time::lock_clock();
+        let framing = framing(0, None, 50);
+        let mut frames = FrameAccumulator::new();
+        assert!(push(&mut frames, &framing, b"ab").is_empty());
+        assert_eq!(push(&mut frames, &framing, b"cdefghij"), [b"abcd".to_vec(), b"efgh".to_vec()]);
+        assert_eq!(frames.frame, b"ij");
+    }
+
+    #[test]
+    fn batch_is_sent_once_its_first_byte_is_old_enough() {
+        let _clock = time::lock_clock();
+        let framing = framing(0, None, 50);
+        let mut frames = FrameAccumulator::new();
+        push(&mut frames, &framing, b"a");
+        time::advance(Duration::from_millis(30));
+        // 新数据不会推迟批量发送
+        push(&mut frames, &framing, b"b");
+        assert!(poll(&mut frames, &framing).is_empty());
+        assert!(frames.due_in(&framing).unwrap() <= Duration::from_millis(20));
+
+        time::advance(Duration::from_millis(20));
+        assert_eq!(frames.due_in(&framing), Some(Duration::ZERO));
+        assert_eq!(poll(&mut frames, &framing), [b"ab".to_vec()]);
+        assert_eq!(frames.due_in(&framing), None);
+    }
+
+    #[test]
+    fn gap_frame_is_sent_after_a_quiet_period() {
+        let _clock = time::lock_clock();
+        let framing = framing(20, None, 50);
+        let mut frames = FrameAccumulator::new();
+        push(&mut frames, &framing, b"a");
+        time::advance(Duration::from_millis(15));
+        // 分帧从最后一个字节开始计时，批量设置不起作用
+        push(&mut frames, &framing, b"b");
+        time::advance(Duration::from_millis(15));
+        assert!(poll(&mut frames, &framing).is_empty());
+
+        time::advance(Duration::from_millis(5));
+        assert_eq!(poll(&mut frames, &framing), [b"ab".to_vec()]);
+    }
+
+    #[test]
+    fn gap_frame_is_sent_when_full() {
+        let _clock = time::lock_clock();
+        let framing = framing(20, None, 0);
+        let mut frames = FrameAccumulator::new();
+        assert_eq!(push(&mut frames, &framing, b"0123456789"), [b"01234567".to_vec()]);
+        assert_eq!(frames.frame, b"89");
+    }
+
+    #[test]
+    fn delimiter_ends_frames_before_the_size_limit() {
+        let _clock = time::lock_clock();
+        let framing = framing(0, Some(b"\n"), 50);
+        let mut frames = FrameAccumulator::new();
+        assert_eq!(
+            push(&mut frames, &framing, b"ab\n0123456789"),
+            [b"ab\n".to_vec(), b"01234567".to_vec()]
+        );
+
+        // 没有分隔符的剩余数据在默认间隔后发出
+        time::advance(Duration::from_millis(DELIMITER_FLUSH_MS / 2));
+        assert!(poll(&mut frames, &framing).is_empty());
+        time::advance(Duration::from_millis(DELIMITER_FLUSH_MS / 2));
+        assert_eq!(poll(&mut frames, &framing), [b"89".to_vec()]);
+    }
+
+    #[test]
+    fn frame_accumulator_finds_a_delimiter_split_across_reads() {
+        let _clock = time::lock_clock();
+        let framing = Framing {
+            gap_ms: 0,
+            max_bytes: 64,
+            delimiter: Delimiter::from_slice(b"\r\n").ok(),
+            batch_bytes: 64,
+            batch_ms: 0,
+        };
+        let mut frames = FrameAccumulator::new();
+        let mut emitted = Vec::new();
+        frames.push(b"ab\r", &framing, |frame| emitted.push(frame.to_vec()));
+        assert!(emitted.is_empty());
+        frames.push(b"\ncd\r\n", &framing, |frame| emitted.push(frame.to_vec()));
+        assert_eq!(emitted, [b"ab\r\n".to_vec(), b"cd\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn delimiter_is_found_wherever_the_reads_split_the_data() {
+        let _clock = time::lock_clock();
+        let framing = framing(0, Some(b"\r\n"), 0);
+        let data = b"one\r\ntwo\r\n";
+        for split in 0..=data.len() {
+            let mut frames = FrameAccumulator::new();
+            let mut emitted = push(&mut frames, &framing, &data[..split]);
+            emitted.extend(push(&mut frames, &framing, &data[split..]));
+            assert_eq!(emitted, [b"one\r\n".to_vec(), b"two\r\n".to_vec()], "split at {}", split);
+            assert!(frames.frame.is_empty());
+        }
+    }
+
+    #[test]
+    fn delimiter_ending_at_the_size_limit_ends_a_single_frame() {
+        let _clock = time::lock_clock();
+        let framing = framing(0, Some(b"\r\n"), 0);
+        let mut frames = FrameAccumulator::new();
+        assert_eq!(push(&mut frames, &framing, b"012345\r\n"), [b"012345\r\n".to_vec()]);
+        assert_eq!(frames.due_in(&framing), None);
+
+        // 空行各成一帧
+        assert_eq!(push(&mut frames, &framing, b"\r\n\r\n"), [b"\r\n".to_vec(), b"\r\n".to_vec()]);
+    }
+
+    #[test]
+    fn oversize_lines_keep_every_byte() {
+        let _clock = time::lock_clock();
+        let framing = framing(0, Some(&[0x7e]), 0);
+        let mut frames = FrameAccumulator::new();
+        let data: Vec<u8> = (0..20u8).chain([0x7e]).collect();
+        let mut emitted = push(&mut frames, &framing, &data);
+        assert!(emitted.iter().all(|frame| frame.len() <= 8));
+        assert_eq!(emitted.len(), 3);
+        assert_eq!(emitted.last().unwrap().last(), Some(&0x7e));
+        emitted.extend(poll(&mut frames, &framing));
+        assert_eq!(emitted.concat(), data);
+    }
+
+    #[test]
+    fn gap_flushes_an_unterminated_line_before_the_default_timeout() {
+        let _clock = time::lock_clock();
+        let framing = framing(20, Some(b"\n"), 0);
+        let mut frames = FrameAccumulator::new();
+        assert!(push(&mut frames, &framing, b"login: ").is_empty());
+        time::advance(Duration::from_millis(20));
+        assert_eq!(poll(&mut frames, &framing), [b"login: ".to_vec()]);
+    }
+
+    #[test]
+    fn replay_keeps_only_the_most_recent_bytes() {
+        let mut replay = ReplayBuffer { data: VecDeque::new(), capacity: 8 };
+        replay.push(b"boot");
+        replay.push(b" banner");
+        assert_eq!(replay.data, b"t banner");
+        replay.push(b"0123456789");
+        assert_eq!(replay.data, b"23456789");
+
+        replay.resize(4);
+        assert_eq!(replay.data, b"6789");
+        replay.resize(0);
+        replay.push(b"ignored");
+        assert!(replay.data.is_empty());
+    }
+
+    #[test]
+    fn baudrates_are_accepted_by_range_and_checked_against_the_divider() {
+        assert!(UartManager::is_valid_baudrate(MIN_BAUDRATE));
+        assert!(UartManager::is_valid_baudrate(250_000));
+        assert!(!UartManager::is_valid_baudrate(MIN_BAUDRATE - 1));
+        assert!(!UartManager::is_valid_baudrate(MAX_BAUDRATE + 1));
+
+        // 允许2%的偏差
+        assert!(UartManager::within_tolerance(250_000, 255_000));
+        assert!(UartManager::within_tolerance(250_000, 245_000));
+        assert!(!UartManager::within_tolerance(250_000, 255_001));
+        assert!(!UartManager::within_tolerance(2_000_000, 1_600_000));
+    }
+
+    #[test]
+    fn frame_delimiter_is_checked_and_saved() {
+        let (port, _line) = mock::MockSerial::open();
+        let (storage, flash) = crate::storage::mock::storage();
+        let storage = Arc::new(Mutex::new(storage));
+        let uart = UartManager::with_port(port, UartConfig::default(), Some(Arc::clone(&storage))).unwrap();
+
+        assert!(uart.set_frame_delimiter(Some(b"")).is_err());
+        assert!(uart.set_frame_delimiter(Some(&[0; MAX_DELIMITER_LEN + 1])).is_err());
+        assert_eq!(uart.framing().delimiter, None);
+
+        uart.set_frame_delimiter(Some(&[0x7e])).unwrap();
+        assert_eq!(uart.framing().delimiter.as_deref(), Some(&[0x7e][..]));
+        let (port, _line) = mock::MockSerial::open();
+        let storage = Arc::new(Mutex::new(crate::storage::mock::reopen(&flash)));
+        let restarted = UartManager::with_port(port, UartConfig::default(), Some(storage)).unwrap();
+        assert_eq!(restarted.framing().delimiter.as_deref(), Some(&[0x7e][..]));
+
+        // 关闭分隔符同样保存
+        restarted.set_frame_delimiter(None).unwrap();
+        let (port, _line) = mock::MockSerial::open();
+        let storage = Arc::new(Mutex::new(crate::storage::mock::reopen(&flash)));
+        let restarted = UartManager::with_port(port, UartConfig::default(), Some(storage)).unwrap();
+        assert_eq!(restarted.framing().delimiter, None);
+    }
+}
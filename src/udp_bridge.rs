@@ -0,0 +1,307 @@
+//! UDP bridge module
+//!
+//! This module provides a UDP alternative to the TCP server for low-latency telemetry.
+//! Every datagram received is forwarded to UART, and UART data is sent back to the
+//! peers that recently sent a datagram. There is no connection state, so a lost
+//! datagram is simply lost. Datagrams from peers refused by the access list of the
+//! TCP server are dropped.
+
+use log::{debug, error, info, trace, warn};
+use std::net::{SocketAddr, UdpSocket};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use crate::acl::AccessList;
+use crate::config::UdpBridgeConfig;
+use crate::error::{Error, Result};
+use crate::time::Stopwatch;
+use crate::uart::UartManager;
+
+/// A peer that has sent us a datagram
+struct UdpPeer {
+    /// Address replies are sent to
+    addr: SocketAddr,
+    /// Time since the peer last sent a datagram
+    last_seen: Stopwatch,
+}
+
+/// UDP peer manager
+///
+/// Tracks the peers that sent a datagram within the timeout window and sends UART
+/// data to them, like `TcpClientManager` does for TCP clients.
+pub struct UdpPeerManager {
+    /// Socket shared with the receiving `UdpBridge`
+    socket: Arc<UdpSocket>,
+    /// Known peers, most recently seen last
+    peers: Mutex<Vec<UdpPeer>>,
+    /// Maximum number of peers; the least recently seen one is replaced when full
+    max_peers: usize,
+    /// Peers that have been silent for longer are forgotten
+    peer_timeout: Duration,
+}
+
+impl UdpPeerManager {
+    /// Create a new peer manager sending from `socket`
+    pub fn new(socket: Arc<UdpSocket>, max_peers: usize, peer_timeout: Duration) -> Self {
+        Self {
+            socket,
+            peers: Mutex::new(Vec::with_capacity(max_peers)),
+            max_peers: max_peers.max(1),
+            peer_timeout,
+        }
+    }
+
+    /// Record a datagram from `addr`, adding it as a peer if needed
+    pub fn touch(&self, addr: SocketAddr) -> Result<()> {
+        let mut peers = self.lock_peers()?;
+        if let Some(index) = peers.iter().position(|peer| peer.addr == addr) {
+            // 移到末尾，保持按最近活动排序
+            let mut peer = peers.remove(index);
+            peer.last_seen.restart();
+            peers.push(peer);
+            return Ok(());
+        }
+
+        self.expire(&mut peers);
+        if peers.len() >= self.max_peers {
+            let replaced = peers.remove(0);
+            debug!("UDP peer limit reached, replacing {}", replaced.addr);
+        }
+        info!("New UDP peer {}", addr);
+        peers.push(UdpPeer {
+            addr,
+            last_seen: Stopwatch::start(),
+        });
+        Ok(())
+    }
+
+    /// Send data to every active peer
+    ///
+    /// Returns the number of peers the data was sent to.
+    pub fn broadcast(&self, data: &[u8]) -> Result<usize> {
+        if data.is_empty() {
+            return Ok(0);
+        }
+
+        let mut peers = self.lock_peers()?;
+        self.expire(&mut peers);
+        let mut sent = 0;
+        for peer in peers.iter() {
+            match self.socket.send_to(data, peer.addr) {
+                Ok(_) => sent += 1,
+                Err(e) => warn!("Failed to send {} bytes to UDP peer {}: {}", data.len(), peer.addr, e),
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Get the number of active peers
+    pub fn peer_count(&self) -> Result<usize> {
+        let mut peers = self.lock_peers()?;
+        self.expire(&mut peers);
+        Ok(peers.len())
+    }
+
+    /// Get the addresses of all active peers, most recently seen last
+    pub fn list_peers(&self) -> Result<Vec<SocketAddr>> {
+        let mut peers = self.lock_peers()?;
+        self.expire(&mut peers);
+        Ok(peers.iter().map(|peer| peer.addr).collect())
+    }
+
+    /// Forget peers that have been silent for longer than the timeout
+    fn expire(&self, peers: &mut Vec<UdpPeer>) {
+        peers.retain(|peer| {
+            let active = !peer.last_seen.has_elapsed(self.peer_timeout);
+            if !active {
+                info!("UDP peer {} timed out", peer.addr);
+            }
+            active
+        });
+    }
+
+    fn lock_peers(&self) -> Result<std::sync::MutexGuard<'_, Vec<UdpPeer>>> {
+        self.peers
+            .lock()
+            .map_err(|_| Error::ClientError("Failed to lock UDP peers".to_string()))
+    }
+}
+
+/// UDP bridge
+///
+/// Receives datagrams on the configured port and forwards their payload to UART.
+/// UART data reaches the peers through the `UdpPeerManager` passed to
+/// `UartManager::start_forwarding`.
+pub struct UdpBridge {
+    /// UDP bridge configuration
+    config: UdpBridgeConfig,
+    /// Bound socket, shared with the peer manager
+    socket: Arc<UdpSocket>,
+    /// Peers that receive UART data
+    peers: Arc<UdpPeerManager>,
+    /// UART manager receiving the datagram payloads
+    uart_manager: Arc<UartManager>,
+    /// Rules deciding which peers may send datagrams, shared with the TCP server
+    access_list: Arc<Mutex<AccessList>>,
+}
+
+impl UdpBridge {
+    /// Bind the UDP socket on the configured port
+    pub fn bind(
+        config: UdpBridgeConfig,
+        uart_manager: Arc<UartManager>,
+        access_list: Arc<Mutex<AccessList>>,
+    ) -> Result<Self> {
+        let socket = UdpSocket::bind((config.bind_address, config.port)).map_err(|e| {
+            Error::tcp_caused(
+                format!("Failed to bind UDP socket to {}:{}", config.bind_address, config.port),
+                e,
+            )
+        })?;
+        let socket = Arc::new(socket);
+        let peers = Arc::new(UdpPeerManager::new(
+            Arc::clone(&socket),
+            config.max_peers,
+            Duration::from_secs(config.peer_timeout_secs),
+        ));
+        info!("UDP bridge bound to {}:{}", config.bind_address, config.port);
+
+        Ok(Self {
+            config,
+            socket,
+            peers,
+            uart_manager,
+            access_list,
+        })
+    }
+
+    /// Get the peer manager that UART data is sent through
+    pub fn peers(&self) -> Arc<UdpPeerManager> {
+        Arc::clone(&self.peers)
+    }
+
+    /// Receive datagrams and forward them to UART
+    ///
+    /// Only returns if the socket fails.
+    pub fn run(&self) -> Result<()> {
+        let mut buffer = vec![0u8; self.config.buffer_size];
+        loop {
+            let (len, addr) = match self.socket.recv_from(&mut buffer) {
+                Ok(received) => received,
+                Err(e) => {
+                    error!("UDP receive failed: {}", e);
+                    return Err(Error::Io(e));
+                }
+            };
+
+            self.receive(&buffer[..len], addr)?;
+        }
+    }
+
+    /// Handle one datagram from `addr`
+    fn receive(&self, data: &[u8], addr: SocketAddr) -> Result<()> {
+        // 锁异常时放行，与TCP端口一致
+        let permitted = self.access_list.lock().map_or(true, |list| list.is_allowed(addr.ip()));
+        if !permitted {
+            debug!("Dropping datagram from {}: not permitted by the access list", addr);
+            return Ok(());
+        }
+
+        self.peers.touch(addr)?;
+        if data.is_empty() {
+            // 空数据报只用于注册为接收方
+            return Ok(());
+        }
+        trace!("UDP -> UART: {} bytes from {}", data.len(), addr);
+        if !self.uart_manager.is_bridge_enabled() {
+            debug!("Dropping datagram from {}, bridge paused", addr);
+        } else if let Err(e) = self.uart_manager.send_packet(data) {
+            error!("Error sending data to UART: {}", e);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::UartConfig;
+    use crate::time;
+    use crate::uart::mock;
+
+    fn peer_socket() -> UdpSocket {
+        let socket = UdpSocket::bind("127.0.0.1:0").unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        socket
+    }
+
+    fn manager(max_peers: usize) -> UdpPeerManager {
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").unwrap());
+        UdpPeerManager::new(socket, max_peers, Duration::from_secs(30))
+    }
+
+    #[test]
+    fn uart_data_reaches_every_peer() {
+        let peers = manager(4);
+        let first = peer_socket();
+        let second = peer_socket();
+        peers.touch(first.local_addr().unwrap()).unwrap();
+        peers.touch(second.local_addr().unwrap()).unwrap();
+
+        assert_eq!(peers.broadcast(b"telemetry").unwrap(), 2);
+        assert_eq!(peers.broadcast(b"").unwrap(), 0);
+        for socket in [first, second] {
+            let mut buffer = [0u8; 16];
+            let (len, _) = socket.recv_from(&mut buffer).unwrap();
+            assert_eq!(&buffer[..len], b"telemetry");
+        }
+    }
+
+    #[test]
+    fn least_recently_seen_peer_makes_room() {
+        let peers = manager(2);
+        let a: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        let c: SocketAddr = "10.0.0.3:5000".parse().unwrap();
+        peers.touch(a).unwrap();
+        peers.touch(b).unwrap();
+        // a再次发送数据报后，b成为最久未活动的接收方
+        peers.touch(a).unwrap();
+        peers.touch(c).unwrap();
+        assert_eq!(peers.list_peers().unwrap(), [a, c]);
+    }
+
+    #[test]
+    fn silent_peers_are_forgotten() {
+        let _clock = time::lock_clock();
+        let peers = manager(4);
+        let a: SocketAddr = "10.0.0.1:5000".parse().unwrap();
+        let b: SocketAddr = "10.0.0.2:5000".parse().unwrap();
+        peers.touch(a).unwrap();
+        time::advance(Duration::from_secs(20));
+        peers.touch(b).unwrap();
+        time::advance(Duration::from_secs(15));
+        assert_eq!(peers.list_peers().unwrap(), [b]);
+        assert_eq!(peers.peer_count().unwrap(), 1);
+    }
+
+    fn bridge(access_list: &str) -> (UdpBridge, Arc<UartManager>) {
+        let (uart, _line) = mock::manager(UartConfig::default());
+        let uart = Arc::new(uart);
+        let config = UdpBridgeConfig { bind_address: "127.0.0.1", port: 0, ..UdpBridgeConfig::default() };
+        let access_list = Arc::new(Mutex::new(AccessList::parse(access_list).unwrap()));
+        (UdpBridge::bind(config, Arc::clone(&uart), access_list).unwrap(), uart)
+    }
+
+    #[test]
+    fn datagrams_of_refused_peers_are_dropped() {
+        let (bridge, uart) = bridge("deny 192.168.4.0/24");
+        bridge.receive(b"denied", "192.168.4.2:5000".parse().unwrap()).unwrap();
+        assert_eq!(bridge.peers.peer_count().unwrap(), 0);
+        assert!(mock::queued_tx(&uart).is_empty());
+
+        bridge.receive(b"allowed", "10.0.0.7:5000".parse().unwrap()).unwrap();
+        assert_eq!(bridge.peers.list_peers().unwrap(), ["10.0.0.7:5000".parse().unwrap()]);
+        assert_eq!(mock::queued_tx(&uart), b"allowed");
+    }
+}
@@ -0,0 +1,136 @@
+//! Version module
+//!
+//! This module collects the firmware version, the commit and build time injected by
+//! build.rs, the ESP-IDF version and the chip model, for AT+VERSION and the boot log.
+
+#[cfg(target_os = "espidf")]
+use std::ffi::CStr;
+use std::fmt;
+
+/// Crate version
+pub const FIRMWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// Short commit hash of the build ("unknown" outside a git checkout)
+pub const GIT_HASH: &str = match option_env!("ESPC3_GIT_HASH") {
+    Some(hash) => hash,
+    None => "unknown",
+};
+
+/// Build time in UTC
+pub const BUILD_TIMESTAMP: &str = match option_env!("ESPC3_BUILD_TIMESTAMP") {
+    Some(timestamp) => timestamp,
+    None => "unknown",
+};
+
+/// Firmware and hardware version information
+#[derive(Debug, Clone)]
+pub struct VersionInfo {
+    /// Crate version
+    pub firmware: &'static str,
+    /// Short commit hash
+    pub commit: &'static str,
+    /// Build time in UTC
+    pub built: &'static str,
+    /// ESP-IDF version, e.g. "v5.2.2"
+    pub idf: String,
+    /// Chip model, e.g. "ESP32-C3"
+    pub chip: &'static str,
+    /// Chip revision as major * 100 + minor
+    pub revision: u16,
+    /// Number of CPU cores
+    pub cores: u8,
+}
+
+impl VersionInfo {
+    /// Collect the version information of the running firmware
+    #[cfg(target_os = "espidf")]
+    pub fn current() -> Self {
+        let idf = unsafe { CStr::from_ptr(esp_idf_sys::esp_get_idf_version()) }
+            .to_string_lossy()
+            .into_owned();
+
+        let mut chip_info = esp_idf_sys::esp_chip_info_t::default();
+        unsafe { esp_idf_sys::esp_chip_info(&mut chip_info) };
+        let chip = match chip_info.model {
+            esp_idf_sys::esp_chip_model_t_CHIP_ESP32 => "ESP32",
+            esp_idf_sys::esp_chip_model_t_CHIP_ESP32S2 => "ESP32-S2",
+            esp_idf_sys::esp_chip_model_t_CHIP_ESP32S3 => "ESP32-S3",
+            esp_idf_sys::esp_chip_model_t_CHIP_ESP32C3 => "ESP32-C3",
+            esp_idf_sys::esp_chip_model_t_CHIP_ESP32C6 => "ESP32-C6",
+            esp_idf_sys::esp_chip_model_t_CHIP_ESP32H2 => "ESP32-H2",
+            _ => "unknown",
+        };
+
+        Self {
+            firmware: FIRMWARE_VERSION,
+            commit: GIT_HASH,
+            built: BUILD_TIMESTAMP,
+            idf,
+            chip,
+            revision: chip_info.revision,
+            cores: chip_info.cores,
+        }
+    }
+
+    /// Collect the version information of the host build, which has no ESP-IDF or chip
+    #[cfg(not(target_os = "espidf"))]
+    pub fn current() -> Self {
+        Self {
+            firmware: FIRMWARE_VERSION,
+            commit: GIT_HASH,
+            built: BUILD_TIMESTAMP,
+            idf: "none".to_string(),
+            chip: "host",
+            revision: 0,
+            cores: 1,
+        }
+    }
+}
+
+impl fmt::Display for VersionInfo {
+    /// One "key: value" line per item, separated by CRLF
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "Firmware: {}\r\nCommit: {}\r\nBuilt: {}\r\nESP-IDF: {}\r\nChip: {} rev v{}.{}, {} core(s)",
+            self.firmware,
+            self.commit,
+            self.built,
+            self.idf,
+            self.chip,
+            self.revision / 100,
+            self.revision % 100,
+            self.cores
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn version_is_five_key_value_lines() {
+        let info = VersionInfo {
+            firmware: "0.1.0",
+            commit: "1a2b3c4-dirty",
+            built: "2024-05-01T12:00:00Z",
+            idf: "v5.2.2".to_string(),
+            chip: "ESP32-C3",
+            revision: 4,
+            cores: 1,
+        };
+        let text = info.to_string();
+        let lines: Vec<&str> = text.split("\r\n").collect();
+        assert_eq!(
+            lines,
+            [
+                "Firmware: 0.1.0",
+                "Commit: 1a2b3c4-dirty",
+                "Built: 2024-05-01T12:00:00Z",
+                "ESP-IDF: v5.2.2",
+                "Chip: ESP32-C3 rev v0.4, 1 core(s)",
+            ]
+        );
+    }
+}
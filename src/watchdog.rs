@@ -0,0 +1,252 @@
+//! Watchdog module
+//!
+//! This module puts the threads that keep data moving (UART forwarding and the TCP
+//! server) under the ESP-IDF task watchdog. A supervised thread calls `feed` on every
+//! loop iteration; if it panics or blocks, e.g. on a deadlocked mutex, the watchdog
+//! panics and resets the device, leaving the thread name in the reset log.
+//!
+//! Every feed also advances a heartbeat counter. The main loop calls `check`, which
+//! logs an error for a thread whose heartbeat stopped before the watchdog fires, so
+//! the cause is visible even without the panic output.
+//!
+//! The host build has no task watchdog; there only the heartbeats are checked.
+
+use log::{error, info, warn};
+use std::cell::RefCell;
+use std::sync::atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+
+use crate::config::WatchdogConfig;
+use crate::error::Result;
+use crate::time;
+
+/// Liveness counter of a supervised thread
+struct Heartbeat {
+    /// Thread name used in the log
+    name: &'static str,
+    /// Number of feeds
+    beats: AtomicU32,
+    /// Beats seen by the last `check`
+    last_beats: AtomicU32,
+    /// Uptime in milliseconds at which `check` last saw the beats advance
+    last_progress_ms: AtomicU64,
+    /// Whether a stall was reported and not yet cleared
+    stalled: AtomicBool,
+}
+
+impl Heartbeat {
+    fn new(name: &'static str, now_ms: u64) -> Self {
+        Self {
+            name,
+            beats: AtomicU32::new(0),
+            last_beats: AtomicU32::new(0),
+            last_progress_ms: AtomicU64::new(now_ms),
+            stalled: AtomicBool::new(false),
+        }
+    }
+
+    /// Compare the beats with the last check, logging a stall once
+    ///
+    /// Returns true while the thread went half the timeout without a feed.
+    fn check(&self, now_ms: u64, timeout_ms: u32) -> bool {
+        let beats = self.beats.load(Ordering::Relaxed);
+        if self.last_beats.swap(beats, Ordering::Relaxed) != beats {
+            self.last_progress_ms.store(now_ms, Ordering::Relaxed);
+            if self.stalled.swap(false, Ordering::Relaxed) {
+                warn!("Thread {} is making progress again", self.name);
+            }
+            return false;
+        }
+
+        let silent_ms = now_ms.saturating_sub(self.last_progress_ms.load(Ordering::Relaxed));
+        if silent_ms < u64::from(timeout_ms / 2) {
+            return false;
+        }
+        if !self.stalled.swap(true, Ordering::Relaxed) {
+            error!(
+                "Thread {} made no progress for {} ms, the watchdog resets the device after {} ms",
+                self.name, silent_ms, timeout_ms
+            );
+        }
+        true
+    }
+}
+
+/// Timeout set by `init`; unset while supervision is disabled
+static TIMEOUT_MS: OnceLock<u32> = OnceLock::new();
+
+/// Heartbeats of the supervised threads
+static HEARTBEATS: Mutex<Vec<Arc<Heartbeat>>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// Heartbeat of the current thread, if it is supervised
+    static CURRENT: RefCell<Option<Arc<Heartbeat>>> = const { RefCell::new(None) };
+}
+
+/// Keeps the current thread under supervision until dropped
+pub struct WatchdogGuard {
+    heartbeat: Arc<Heartbeat>,
+}
+
+impl Drop for WatchdogGuard {
+    fn drop(&mut self) {
+        CURRENT.with(|current| current.borrow_mut().take());
+        if let Ok(mut heartbeats) = HEARTBEATS.lock() {
+            heartbeats.retain(|heartbeat| !Arc::ptr_eq(heartbeat, &self.heartbeat));
+        }
+        task_wdt::remove_current();
+        info!("Thread {} left watchdog supervision", self.heartbeat.name);
+    }
+}
+
+/// Configure the task watchdog
+///
+/// The reason of the last reset, e.g. a watchdog reset, is logged by
+/// `boot_info::record`. With supervision disabled, `supervise` and `feed` do nothing.
+pub fn init(config: &WatchdogConfig) -> Result<()> {
+    if !config.enabled {
+        info!("Thread watchdog disabled");
+        return Ok(());
+    }
+
+    task_wdt::configure(config.timeout_ms)?;
+    let _ = TIMEOUT_MS.set(config.timeout_ms);
+    info!("Thread watchdog enabled, timeout {} ms", config.timeout_ms);
+    Ok(())
+}
+
+/// Put the current thread under watchdog supervision
+///
+/// Returns None if supervision is disabled. The thread must call `feed` at least
+/// once per timeout for as long as the guard lives.
+pub fn supervise(name: &'static str) -> Option<WatchdogGuard> {
+    TIMEOUT_MS.get()?;
+
+    if let Err(err) = task_wdt::add_current() {
+        warn!("Failed to add thread {} to the task watchdog (error code: {})", name, err);
+        return None;
+    }
+
+    let heartbeat = Arc::new(Heartbeat::new(name, time::uptime().as_millis() as u64));
+    CURRENT.with(|current| *current.borrow_mut() = Some(Arc::clone(&heartbeat)));
+    if let Ok(mut heartbeats) = HEARTBEATS.lock() {
+        heartbeats.push(Arc::clone(&heartbeat));
+    }
+    info!("Thread {} is supervised by the task watchdog", name);
+    Some(WatchdogGuard { heartbeat })
+}
+
+/// Report progress of the current thread
+///
+/// Does nothing on threads that are not supervised, so shared loops can call it.
+pub fn feed() {
+    CURRENT.with(|current| {
+        if let Some(heartbeat) = current.borrow().as_ref() {
+            heartbeat.beats.fetch_add(1, Ordering::Relaxed);
+            task_wdt::reset_current();
+        }
+    });
+}
+
+/// Log supervised threads that stopped making progress
+///
+/// Called periodically from the main loop; a thread is reported once it went half
+/// the timeout without a feed, before the watchdog resets the device.
+pub fn check() {
+    let Some(&timeout_ms) = TIMEOUT_MS.get() else {
+        return;
+    };
+    let Ok(heartbeats) = HEARTBEATS.lock() else {
+        return;
+    };
+    let now_ms = time::uptime().as_millis() as u64;
+    for heartbeat in heartbeats.iter() {
+        heartbeat.check(now_ms, timeout_ms);
+    }
+}
+
+/// The ESP-IDF task watchdog
+#[cfg(target_os = "espidf")]
+mod task_wdt {
+    use crate::error::{Error, Result};
+    use crate::version::VersionInfo;
+
+    /// Set the timeout and make the watchdog panic when it fires
+    pub fn configure(timeout_ms: u32) -> Result<()> {
+        let wdt_config = esp_idf_sys::esp_task_wdt_config_t {
+            timeout_ms,
+            // 保留对空闲任务的监视
+            idle_core_mask: (1u32 << VersionInfo::current().cores) - 1,
+            trigger_panic: true,
+        };
+        let mut err = unsafe { esp_idf_sys::esp_task_wdt_reconfigure(&wdt_config) };
+        if err == esp_idf_sys::ESP_ERR_INVALID_STATE {
+            // 启动时没有初始化看门狗
+            err = unsafe { esp_idf_sys::esp_task_wdt_init(&wdt_config) };
+        }
+        if err != esp_idf_sys::ESP_OK {
+            return Err(Error::esp(err, "Configuring the task watchdog"));
+        }
+        Ok(())
+    }
+
+    /// Watch the current task; the error is the `esp_err_t` of the call
+    pub fn add_current() -> std::result::Result<(), i32> {
+        match unsafe { esp_idf_sys::esp_task_wdt_add(std::ptr::null_mut()) } {
+            esp_idf_sys::ESP_OK => Ok(()),
+            err => Err(err),
+        }
+    }
+
+    /// Stop watching the current task
+    pub fn remove_current() {
+        unsafe {
+            esp_idf_sys::esp_task_wdt_delete(std::ptr::null_mut());
+        }
+    }
+
+    /// Tell the watchdog that the current task is alive
+    pub fn reset_current() {
+        unsafe {
+            esp_idf_sys::esp_task_wdt_reset();
+        }
+    }
+}
+
+/// Stand-in for the task watchdog in the host build, which has none
+#[cfg(not(target_os = "espidf"))]
+mod task_wdt {
+    use crate::error::Result;
+
+    pub fn configure(_timeout_ms: u32) -> Result<()> {
+        Ok(())
+    }
+
+    pub fn add_current() -> std::result::Result<(), i32> {
+        Ok(())
+    }
+
+    pub fn remove_current() {}
+
+    pub fn reset_current() {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thread_without_feeds_is_reported_after_half_the_timeout() {
+        let heartbeat = Heartbeat::new("uart_rx", 1000);
+        assert!(!heartbeat.check(3000, 5000));
+        assert!(heartbeat.check(3500, 5000));
+        assert!(heartbeat.check(4000, 5000));
+
+        // 恢复喂狗后清除停滞状态
+        heartbeat.beats.fetch_add(1, Ordering::Relaxed);
+        assert!(!heartbeat.check(4100, 5000));
+        assert!(!heartbeat.stalled.load(Ordering::Relaxed));
+        assert!(!heartbeat.check(6500, 5000));
+        assert!(heartbeat.check(6600, 5000));
+    }
+}
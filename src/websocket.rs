@@ -0,0 +1,458 @@
+//! WebSocket module
+//!
+//! This module implements the small part of RFC 6455 the bridge needs to serve
+//! browser-based serial terminals: the HTTP Upgrade handshake, server frames and a
+//! decoder for masked client frames. It has no async runtime; the TCP server runs
+//! each connection on its own thread like the other ports.
+//!
+//! UART data is sent to WebSocket clients as binary messages. Binary and text
+//! messages from clients are forwarded to UART, pings are answered, and fragmented
+//! messages are reassembled up to `MAX_MESSAGE_BYTES`.
+
+#[cfg(target_os = "espidf")]
+use esp_idf_sys as sys;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::time::Duration;
+
+use crate::error::{Error, Result};
+
+/// Largest message accepted from a client; larger ones close the connection
+pub const MAX_MESSAGE_BYTES: usize = 4096;
+
+/// Largest HTTP upgrade request accepted
+const MAX_REQUEST_BYTES: usize = 2048;
+
+/// Time a client gets to send its upgrade request
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// GUID appended to the client key to compute Sec-WebSocket-Accept
+#[cfg(target_os = "espidf")]
+const ACCEPT_GUID: &str = "258EAFA5-E914-47DA-95CA-C5AB0DC85B11";
+
+/// Frame opcodes
+const OPCODE_CONTINUATION: u8 = 0x0;
+pub const OPCODE_TEXT: u8 = 0x1;
+pub const OPCODE_BINARY: u8 = 0x2;
+pub const OPCODE_CLOSE: u8 = 0x8;
+const OPCODE_PING: u8 = 0x9;
+pub const OPCODE_PONG: u8 = 0xA;
+
+/// Close status codes
+pub const CLOSE_NORMAL: u16 = 1000;
+pub const CLOSE_GOING_AWAY: u16 = 1001;
+pub const CLOSE_PROTOCOL_ERROR: u16 = 1002;
+pub const CLOSE_TOO_BIG: u16 = 1009;
+
+/// Longest payload of a control frame
+const MAX_CONTROL_PAYLOAD: usize = 125;
+
+/// A complete message received from a client
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Message {
+    /// Binary or text message, forwarded to UART
+    Data(Vec<u8>),
+    /// Ping with its payload, to be answered with a pong
+    Ping(Vec<u8>),
+    /// Pong, ignored
+    Pong,
+    /// Close request with its status code, if any
+    Close(Option<u16>),
+}
+
+/// Read the HTTP upgrade request from a new connection and accept it
+///
+/// Returns the bytes received after the request, which already belong to the
+/// first frames. A request that is not a valid WebSocket upgrade is answered with
+/// an HTTP error and returned as an error.
+pub fn accept(stream: &mut TcpStream) -> Result<Vec<u8>> {
+    stream
+        .set_read_timeout(Some(HANDSHAKE_TIMEOUT))
+        .map_err(|e| Error::tcp_caused("Failed to set handshake timeout", e))?;
+
+    // 读取到空行为止，请求之后的数据属于第一个帧
+    let mut request = Vec::with_capacity(512);
+    let mut buffer = [0u8; 256];
+    let header_end = loop {
+        if let Some(end) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        if request.len() > MAX_REQUEST_BYTES {
+            return Err(reject(stream, "431 Request Header Fields Too Large", "Upgrade request too large"));
+        }
+        match stream.read(&mut buffer) {
+            Ok(0) => return Err(Error::tcp("Connection closed during WebSocket handshake")),
+            Ok(n) => request.extend_from_slice(&buffer[..n]),
+            Err(e) => return Err(Error::tcp_caused("Failed to read WebSocket handshake", e)),
+        }
+    };
+
+    let head = String::from_utf8_lossy(&request[..header_end]).into_owned();
+    let mut lines = head.split("\r\n");
+    if !lines.next().is_some_and(|line| line.starts_with("GET ")) {
+        return Err(reject(stream, "405 Method Not Allowed", "Upgrade request is not a GET"));
+    }
+    let mut key = None;
+    let mut upgrade = false;
+    let mut version_ok = false;
+    for line in lines {
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        let value = value.trim();
+        match name.trim().to_ascii_lowercase().as_str() {
+            "upgrade" => upgrade = value.eq_ignore_ascii_case("websocket"),
+            "sec-websocket-key" => key = Some(value.to_string()),
+            "sec-websocket-version" => version_ok = value == "13",
+            _ => {}
+        }
+    }
+    let (true, true, Some(key)) = (upgrade, version_ok, key) else {
+        return Err(reject(stream, "426 Upgrade Required", "Not a WebSocket upgrade request"));
+    };
+
+    let response = format!(
+        "HTTP/1.1 101 Switching Protocols\r\n\
+         Upgrade: websocket\r\n\
+         Connection: Upgrade\r\n\
+         Sec-WebSocket-Accept: {}\r\n\r\n",
+        accept_key(&key)?
+    );
+    stream
+        .write_all(response.as_bytes())
+        .map_err(|e| Error::tcp_caused("Failed to send WebSocket handshake", e))?;
+    Ok(request.split_off(header_end))
+}
+
+/// Answer a failed upgrade request with an HTTP error
+fn reject(stream: &mut TcpStream, status: &str, reason: &str) -> Error {
+    let response = format!(
+        "HTTP/1.1 {}\r\nSec-WebSocket-Version: 13\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+        status
+    );
+    let _ = stream.write_all(response.as_bytes());
+    Error::tcp(reason)
+}
+
+/// Compute the Sec-WebSocket-Accept value for a client key
+#[cfg(target_os = "espidf")]
+fn accept_key(key: &str) -> Result<String> {
+    let input = format!("{}{}", key, ACCEPT_GUID);
+    let mut digest = [0u8; 20];
+    let err = unsafe { sys::mbedtls_sha1(input.as_ptr(), input.len(), digest.as_mut_ptr()) };
+    if err != 0 {
+        return Err(Error::General(format!("Failed to hash WebSocket key (error code: {})", err)));
+    }
+    Ok(base64(&digest))
+}
+
+/// Compute the Sec-WebSocket-Accept value for a client key
+///
+/// The host build has no mbedtls for the SHA-1 hash, so the handshake is refused.
+#[cfg(not(target_os = "espidf"))]
+fn accept_key(_key: &str) -> Result<String> {
+    Err(Error::General("WebSocket handshakes need mbedtls, which the host build lacks".to_string()))
+}
+
+/// Encode bytes as standard base64 with padding
+#[cfg(any(target_os = "espidf", test))]
+fn base64(data: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut encoded = String::with_capacity(data.len().div_ceil(3) * 4);
+    for chunk in data.chunks(3) {
+        let bits = chunk.iter().enumerate().fold(0u32, |bits, (i, &b)| bits | (u32::from(b) << (16 - 8 * i)));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                encoded.push(ALPHABET[((bits >> (18 - 6 * i)) & 0x3F) as usize] as char);
+            } else {
+                encoded.push('=');
+            }
+        }
+    }
+    encoded
+}
+
+/// Build an unmasked server frame
+pub fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + 10);
+    frame.push(0x80 | opcode);
+    match payload.len() {
+        len @ 0..=125 => frame.push(len as u8),
+        len @ 126..=0xFFFF => {
+            frame.push(126);
+            frame.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        len => {
+            frame.push(127);
+            frame.extend_from_slice(&(len as u64).to_be_bytes());
+        }
+    }
+    frame.extend_from_slice(payload);
+    frame
+}
+
+/// Build a close frame with a status code and a reason
+///
+/// The reason is cut to fit the control frame limit.
+pub fn close_frame(code: u16, reason: &str) -> Vec<u8> {
+    let mut payload = code.to_be_bytes().to_vec();
+    let mut end = reason.len().min(MAX_CONTROL_PAYLOAD - 2);
+    while !reason.is_char_boundary(end) {
+        end -= 1;
+    }
+    payload.extend_from_slice(&reason.as_bytes()[..end]);
+    frame(OPCODE_CLOSE, &payload)
+}
+
+/// Decodes the frames sent by one client
+pub struct FrameDecoder {
+    /// Received bytes not yet decoded, at most one incomplete frame
+    buffer: Vec<u8>,
+    /// Payload of a fragmented message being reassembled
+    message: Vec<u8>,
+    /// Whether a fragmented message is in progress
+    fragmented: bool,
+    /// Largest message accepted
+    max_message: usize,
+}
+
+impl FrameDecoder {
+    /// Create a decoder that accepts messages of up to `max_message` bytes
+    pub fn new(max_message: usize) -> Self {
+        Self {
+            buffer: Vec::new(),
+            message: Vec::new(),
+            fragmented: false,
+            max_message,
+        }
+    }
+
+    /// Decode received bytes, appending every complete message to `messages`
+    ///
+    /// Returns the close status code to send if the client broke the protocol or
+    /// sent an oversized message; messages decoded before that are still returned.
+    pub fn decode(&mut self, input: &[u8], messages: &mut Vec<Message>) -> std::result::Result<(), u16> {
+        self.buffer.extend_from_slice(input);
+        loop {
+            match self.next_frame()? {
+                Decoded::Incomplete => return Ok(()),
+                Decoded::Fragment => {}
+                Decoded::Message(message) => messages.push(message),
+            }
+        }
+    }
+
+    /// Decode one frame from the buffer
+    fn next_frame(&mut self) -> std::result::Result<Decoded, u16> {
+        let [b0, b1, ..] = self.buffer[..] else {
+            return Ok(Decoded::Incomplete);
+        };
+        let fin = b0 & 0x80 != 0;
+        let opcode = b0 & 0x0F;
+        // 客户端帧必须加掩码，且不使用扩展位
+        if b0 & 0x70 != 0 || b1 & 0x80 == 0 {
+            return Err(CLOSE_PROTOCOL_ERROR);
+        }
+
+        let (len, mask_at) = match b1 & 0x7F {
+            126 => match self.buffer.get(2..4) {
+                Some(len) => (u64::from(u16::from_be_bytes([len[0], len[1]])), 4),
+                None => return Ok(Decoded::Incomplete),
+            },
+            127 => match self.buffer.get(2..10) {
+                Some(len) => (u64::from_be_bytes(len.try_into().unwrap_or_default()), 10),
+                None => return Ok(Decoded::Incomplete),
+            },
+            len => (u64::from(len), 2),
+        };
+
+        let control = opcode & 0x08 != 0;
+        if control && (!fin || len > MAX_CONTROL_PAYLOAD as u64) {
+            return Err(CLOSE_PROTOCOL_ERROR);
+        }
+        // 在分配内存之前检查长度，超长消息直接关闭连接；64位长度可能接近u64::MAX
+        if !control && (self.message.len() as u64).saturating_add(len) > self.max_message as u64 {
+            return Err(CLOSE_TOO_BIG);
+        }
+        let len = len as usize;
+        let payload_at = mask_at + 4;
+        if self.buffer.len() < payload_at + len {
+            return Ok(Decoded::Incomplete);
+        }
+
+        let mask = [
+            self.buffer[mask_at],
+            self.buffer[mask_at + 1],
+            self.buffer[mask_at + 2],
+            self.buffer[mask_at + 3],
+        ];
+        let mut payload: Vec<u8> = self.buffer[payload_at..payload_at + len]
+            .iter()
+            .enumerate()
+            .map(|(i, b)| b ^ mask[i % 4])
+            .collect();
+        self.buffer.drain(..payload_at + len);
+
+        let message = match opcode {
+            OPCODE_CONTINUATION if !self.fragmented => return Err(CLOSE_PROTOCOL_ERROR),
+            OPCODE_TEXT | OPCODE_BINARY if self.fragmented => return Err(CLOSE_PROTOCOL_ERROR),
+            OPCODE_CONTINUATION | OPCODE_TEXT | OPCODE_BINARY => {
+                self.message.append(&mut payload);
+                self.fragmented = !fin;
+                if !fin {
+                    return Ok(Decoded::Fragment);
+                }
+                Message::Data(std::mem::take(&mut self.message))
+            }
+            OPCODE_CLOSE => Message::Close(payload.get(..2).map(|code| u16::from_be_bytes([code[0], code[1]]))),
+            OPCODE_PING => Message::Ping(payload),
+            OPCODE_PONG => Message::Pong,
+            _ => return Err(CLOSE_PROTOCOL_ERROR),
+        };
+        Ok(Decoded::Message(message))
+    }
+}
+
+/// Result of decoding one frame
+enum Decoded {
+    /// The buffer does not hold a complete frame yet
+    Incomplete,
+    /// A fragment that did not complete its message
+    Fragment,
+    /// A complete message
+    Message(Message),
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MASK: [u8; 4] = [0x37, 0xFA, 0x21, 0x3D];
+
+    /// Build a masked client frame
+    fn client_frame(fin: bool, opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = frame(opcode, payload);
+        if !fin {
+            frame[0] &= 0x7F;
+        }
+        let header_len = frame.len() - payload.len();
+        frame[1] |= 0x80;
+        frame.splice(header_len..header_len, MASK);
+        for (i, byte) in frame[header_len + 4..].iter_mut().enumerate() {
+            *byte ^= MASK[i % 4];
+        }
+        frame
+    }
+
+    /// Header of a masked client frame announcing a 64 bit `len`, without payload
+    fn long_header(fin: bool, opcode: u8, len: u64) -> Vec<u8> {
+        let mut header = vec![u8::from(fin) << 7 | opcode, 0x80 | 127];
+        header.extend_from_slice(&len.to_be_bytes());
+        header.extend_from_slice(&MASK);
+        header
+    }
+
+    fn decode(decoder: &mut FrameDecoder, input: &[u8]) -> (Vec<Message>, std::result::Result<(), u16>) {
+        let mut messages = Vec::new();
+        let result = decoder.decode(input, &mut messages);
+        (messages, result)
+    }
+
+    #[test]
+    fn base64_padding() {
+        assert_eq!(base64(b""), "");
+        assert_eq!(base64(b"f"), "Zg==");
+        assert_eq!(base64(b"fo"), "Zm8=");
+        assert_eq!(base64(b"foo"), "Zm9v");
+        assert_eq!(base64(b"foobar"), "Zm9vYmFy");
+    }
+
+    #[test]
+    fn server_frame_lengths() {
+        assert_eq!(frame(OPCODE_BINARY, &[1; 125])[..2], [0x82, 125]);
+        assert_eq!(frame(OPCODE_BINARY, &[1; 126])[..4], [0x82, 126, 0, 126]);
+        assert_eq!(frame(OPCODE_BINARY, &[1; 0x10000])[..10], [0x82, 127, 0, 0, 0, 0, 0, 1, 0, 0]);
+    }
+
+    #[test]
+    fn close_reason_is_cut_at_a_char_boundary() {
+        let close = close_frame(CLOSE_NORMAL, &"\u{e9}".repeat(100));
+        assert_eq!(close[1] as usize, close.len() - 2);
+        assert!(close.len() - 2 <= MAX_CONTROL_PAYLOAD);
+        assert!(std::str::from_utf8(&close[4..]).is_ok());
+    }
+
+    #[test]
+    fn masked_frames_split_across_reads() {
+        let mut decoder = FrameDecoder::new(MAX_MESSAGE_BYTES);
+        let input = [client_frame(true, OPCODE_TEXT, b"hello"), client_frame(true, OPCODE_PING, b"p")].concat();
+        let (first, rest) = input.split_at(4);
+        assert_eq!(decode(&mut decoder, first), (Vec::new(), Ok(())));
+        assert_eq!(
+            decode(&mut decoder, rest),
+            (vec![Message::Data(b"hello".to_vec()), Message::Ping(b"p".to_vec())], Ok(()))
+        );
+    }
+
+    #[test]
+    fn fragments_are_reassembled_around_control_frames() {
+        let mut decoder = FrameDecoder::new(MAX_MESSAGE_BYTES);
+        let input = [
+            client_frame(false, OPCODE_BINARY, b"ab"),
+            client_frame(true, OPCODE_PONG, b""),
+            client_frame(true, OPCODE_CONTINUATION, b"cd"),
+            client_frame(true, OPCODE_CLOSE, &CLOSE_GOING_AWAY.to_be_bytes()),
+        ]
+        .concat();
+        assert_eq!(
+            decode(&mut decoder, &input),
+            (
+                vec![Message::Pong, Message::Data(b"abcd".to_vec()), Message::Close(Some(CLOSE_GOING_AWAY))],
+                Ok(())
+            )
+        );
+    }
+
+    #[test]
+    fn message_size_limit() {
+        let mut decoder = FrameDecoder::new(8);
+        let (messages, result) = decode(&mut decoder, &client_frame(true, OPCODE_BINARY, &[7; 8]));
+        assert_eq!((messages, result), (vec![Message::Data(vec![7; 8])], Ok(())));
+
+        let input = [client_frame(false, OPCODE_BINARY, &[1; 5]), client_frame(true, OPCODE_CONTINUATION, &[2; 4])].concat();
+        assert_eq!(decode(&mut decoder, &input).1, Err(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn oversized_lengths_close_with_too_big() {
+        // 长度不完整时不分配内存，直接关闭
+        for len in [MAX_MESSAGE_BYTES as u64 + 1, 1 << 63, u64::MAX] {
+            let mut decoder = FrameDecoder::new(MAX_MESSAGE_BYTES);
+            assert_eq!(decode(&mut decoder, &long_header(true, OPCODE_BINARY, len)).1, Err(CLOSE_TOO_BIG), "{}", len);
+        }
+
+        // 分片消息进行中时长度相加不能溢出
+        let mut decoder = FrameDecoder::new(MAX_MESSAGE_BYTES);
+        let input = [client_frame(false, OPCODE_BINARY, b"abc"), long_header(true, OPCODE_CONTINUATION, u64::MAX)].concat();
+        assert_eq!(decode(&mut decoder, &input).1, Err(CLOSE_TOO_BIG));
+    }
+
+    #[test]
+    fn protocol_errors() {
+        let unmasked = frame(OPCODE_TEXT, b"x");
+        let long_ping = client_frame(true, OPCODE_PING, &[0; MAX_CONTROL_PAYLOAD + 1]);
+        let fragmented_ping = client_frame(false, OPCODE_PING, b"");
+        let stray_continuation = client_frame(true, OPCODE_CONTINUATION, b"x");
+        let mut reserved_bits = client_frame(true, OPCODE_TEXT, b"x");
+        reserved_bits[0] |= 0x40;
+        let unknown_opcode = client_frame(true, 0x3, b"");
+        for input in [unmasked, long_ping, fragmented_ping, stray_continuation, reserved_bits, unknown_opcode] {
+            let mut decoder = FrameDecoder::new(MAX_MESSAGE_BYTES);
+            assert_eq!(decode(&mut decoder, &input).1, Err(CLOSE_PROTOCOL_ERROR), "{:02X?}", input);
+        }
+
+        let mut decoder = FrameDecoder::new(MAX_MESSAGE_BYTES);
+        let interleaved = [client_frame(false, OPCODE_TEXT, b"a"), client_frame(true, OPCODE_TEXT, b"b")].concat();
+        assert_eq!(decode(&mut decoder, &interleaved).1, Err(CLOSE_PROTOCOL_ERROR));
+    }
+}
@@ -1,112 +1,422 @@
 //! WiFi module for ESP32
 //!
 //! This module provides functionality for configuring and managing WiFi on ESP32.
+//!
+//! The host build has no radio, so `WiFiManager::new` fails there.
 
+#[cfg(target_os = "espidf")]
 use esp_idf_svc::{
     eventloop::EspSystemEventLoop,
     nvs::EspDefaultNvsPartition,
     wifi::{AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, EspWifi},
 };
 use log::{info, warn, error};
+use std::fmt;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::thread;
 use std::time::Duration;
 
-use crate::config::WiFiConfig;
+use crate::config::{ApBandwidth, WiFiAuth, WiFiConfig, WiFiMode};
 use crate::error::{Error, Result};
+use crate::status_led::DeviceStatus;
+use crate::storage::StorageManager;
+use crate::time::Stopwatch;
+use radio::Radio;
+
+/// IP settings of a WiFi interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct IpInfo {
+    /// Address of the interface
+    pub ip: Ipv4Addr,
+}
+
+/// Result of connecting the station to a new network
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StaConnectResult {
+    /// The station connected to the network
+    Connected,
+    /// The connection attempt failed
+    Failed(String),
+    /// The station did not connect within the timeout
+    TimedOut,
+}
+
+/// A station associated with the access point
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StationInfo {
+    /// MAC address of the station
+    pub mac: [u8; 6],
+    /// Signal strength of the station in dBm
+    pub rssi: i8,
+    /// Address leased to the station by the AP's DHCP server, if known
+    pub ip: Option<Ipv4Addr>,
+}
+
+impl fmt::Display for StationInfo {
+    /// Formats as "aa:bb:cc:dd:ee:ff rssi=-52dBm ip=192.168.4.2"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.mac;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x} rssi={}dBm",
+            a, b, c, d, e, g, self.rssi
+        )?;
+        match self.ip {
+            Some(ip) => write!(f, " ip={}", ip),
+            None => write!(f, " ip=unknown"),
+        }
+    }
+}
+
+/// The network the station is associated with
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaLink {
+    /// Signal strength in dBm
+    pub rssi: i8,
+    /// Primary channel of the network
+    pub channel: u8,
+    /// MAC address of the access point the station is associated with
+    pub bssid: [u8; 6],
+}
+
+impl StaLink {
+    /// Rough rating of the signal strength, from "excellent" to "unusable"
+    pub fn quality(&self) -> &'static str {
+        match self.rssi {
+            -50..=i8::MAX => "excellent",
+            -60..=-51 => "good",
+            -70..=-61 => "fair",
+            -80..=-71 => "weak",
+            _ => "unusable",
+        }
+    }
+
+    /// BSSID as "aa:bb:cc:dd:ee:ff"
+    pub fn bssid_string(&self) -> String {
+        let [a, b, c, d, e, g] = self.bssid;
+        format!("{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}", a, b, c, d, e, g)
+    }
+}
+
+impl fmt::Display for StaLink {
+    /// Formats as "aa:bb:cc:dd:ee:ff ch=6 rssi=-52dBm (good)"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ch={} rssi={}dBm ({})",
+            self.bssid_string(),
+            self.channel,
+            self.rssi,
+            self.quality()
+        )
+    }
+}
+
+/// A network found by a WiFi scan
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ScanResult {
+    /// SSID of the network (empty for hidden networks)
+    pub ssid: String,
+    /// Signal strength in dBm
+    pub rssi: i8,
+    /// Primary channel of the network
+    pub channel: u8,
+    /// Authentication method as named by the driver, if it reported one
+    pub auth: Option<String>,
+}
+
+impl fmt::Display for ScanResult {
+    /// Formats as "HomeNet rssi=-48dBm ch=6 auth=WPA2Personal"
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} rssi={}dBm ch={} auth=", self.ssid, self.rssi, self.channel)?;
+        match &self.auth {
+            Some(auth) => write!(f, "{}", auth),
+            None => write!(f, "unknown"),
+        }
+    }
+}
+
+/// Sort scan results by signal strength, strongest first
+fn sort_by_signal(results: &mut [ScanResult]) {
+    results.sort_by_key(|result| std::cmp::Reverse(result.rssi));
+}
+
+/// Lowest transmit power in dBm the driver accepts
+pub const MIN_TX_POWER_DBM: u8 = 2;
+
+/// Highest transmit power in dBm the driver accepts
+pub const MAX_TX_POWER_DBM: u8 = 20;
+
+/// How long the station signal stays weak before a warning is logged
+const WEAK_SIGNAL_WARN_AFTER: Duration = Duration::from_secs(60);
+
+/// Interval in milliseconds at which the reconnect task checks the station
+const RECONNECT_POLL_MS: u64 = 500;
+
+/// Station reconnection state, reported by AT+STATUS
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StaStatus {
+    /// Whether the station should stay connected
+    pub enabled: bool,
+    /// Whether the station is connected to a network
+    pub connected: bool,
+    /// Reconnect attempts since the station was last connected
+    pub attempts: u32,
+    /// Error of the last failed reconnect attempt
+    pub last_error: Option<String>,
+    /// Time until the next reconnect attempt (None while connected or disabled)
+    pub next_retry: Option<Duration>,
+}
+
+/// Backoff state of the reconnect task
+struct Reconnect {
+    /// Attempts since the station was last connected
+    attempts: u32,
+    /// Error of the last failed attempt
+    last_error: Option<String>,
+    /// Delay before the next attempt
+    delay: Duration,
+    /// Time since the last attempt (or since the link was last seen up)
+    waited: Stopwatch,
+}
+
+impl Reconnect {
+    fn new(delay: Duration) -> Self {
+        Self {
+            attempts: 0,
+            last_error: None,
+            delay,
+            waited: Stopwatch::start(),
+        }
+    }
+
+    /// Start the next attempt, noting that the previous one did not connect
+    ///
+    /// Returns the number of the attempt. The delay before the one after it
+    /// doubles, up to `max_delay`.
+    fn start_attempt(&mut self, max_delay: Duration) -> u32 {
+        if self.attempts > 0 {
+            // 上一次尝试在等待期间没有连上
+            self.last_error = Some(format!(
+                "Not connected {} s after attempt {}",
+                self.delay.as_secs(),
+                self.attempts
+            ));
+        }
+        self.attempts += 1;
+        self.waited.restart();
+        self.delay = (self.delay * 2).min(max_delay);
+        self.attempts
+    }
+}
 
 /// WiFi Manager for ESP32
 ///
 /// Manages WiFi configuration and connection for ESP32 in mixed mode (AP + STA)
 pub struct WiFiManager {
-    /// The ESP WiFi driver
-    wifi: Box<EspWifi<'static>>,
+    /// The WiFi driver
+    radio: Radio,
     /// WiFi configuration
     config: WiFiConfig,
+    /// Storage manager for persisting WiFi settings
+    storage: Option<Arc<Mutex<StorageManager>>>,
+    /// Whether the reconnect task keeps the station connected
+    sta_enabled: bool,
+    /// Backoff state of the reconnect task
+    reconnect: Reconnect,
+    /// Device status updated when WiFi comes up or goes down
+    status: Option<Arc<DeviceStatus>>,
+    /// Channel picked by the last scan while the access point channel is automatic
+    auto_channel: Option<u8>,
+    /// Time since the station signal dropped below `sta_weak_rssi_dbm`
+    weak_signal: Option<Stopwatch>,
+    /// Whether the current weak signal period was already warned about
+    weak_signal_warned: bool,
 }
 
 impl WiFiManager {
     /// Create a new WiFi manager with the given configuration
-    pub fn new(config: WiFiConfig) -> Result<Self> {
-        let nvs = EspDefaultNvsPartition::take().map_err(|e| Error::WiFiError(format!("Failed to take NVS partition: {}", e)))?;
-        let sysloop = EspSystemEventLoop::take().map_err(|e| Error::WiFiError(format!("Failed to take system event loop: {}", e)))?;
-
-        // Create WiFi driver
-        let modem = unsafe { esp_idf_svc::hal::modem::Modem::new() };
-        let wifi = Box::new(EspWifi::new(
-            modem,
-            sysloop.clone(),
-            Some(nvs),
-        ).map_err(|e| Error::WiFiError(format!("Failed to create WiFi driver: {}", e)))?);
+    ///
+    /// WiFi settings saved in `storage` take precedence over `config`.
+    pub fn new(mut config: WiFiConfig, storage: Option<&Arc<Mutex<StorageManager>>>) -> Result<Self> {
+        // 优先使用flash中保存的WiFi配置
+        if let Some(storage) = storage {
+            match storage.lock() {
+                Ok(storage) => {
+                    if let Some(saved) = storage.read_wifi_config() {
+                        info!("Using WiFi config from flash");
+                        // 工作模式不保存在flash中，沿用传入的配置
+                        config = WiFiConfig { mode: config.mode, ..saved };
+                    }
+                }
+                Err(e) => warn!("Failed to lock storage manager: {}, using default WiFi config", e),
+            }
+        }
 
+        let radio = Radio::new()?;
+
+        let reconnect = Reconnect::new(Duration::from_millis(config.sta_reconnect_interval_ms));
         Ok(Self {
-            wifi,
+            radio,
             config,
+            storage: storage.cloned(),
+            sta_enabled: true,
+            reconnect,
+            status: None,
+            auto_channel: None,
+            weak_signal: None,
+            weak_signal_warned: false,
         })
     }
 
-    /// Configure WiFi in mixed mode (AP + STA)
-    pub fn configure_mixed_mode(&mut self) -> Result<()> {
-        info!("Setting up WiFi AP with SSID: {}", self.config.ap_ssid);
+    /// Configure WiFi for the interfaces selected by the configured mode
+    ///
+    /// An access point without password runs as an open network. If the driver
+    /// rejects WPA3, as older chips and ESP-IDF versions do, WPA2 is used instead.
+    pub fn configure(&mut self) -> Result<()> {
+        let mut ap_auth = self.config.ap_auth;
+        if self.config.mode.has_ap() && self.config.ap_password.is_empty() && ap_auth != WiFiAuth::Open {
+            warn!("WiFi AP has no password, running it as an open network instead of {}", ap_auth);
+            ap_auth = WiFiAuth::Open;
+        }
+        let sta_auth = self.config.sta_auth;
 
-        self.wifi.set_configuration(&Configuration::Mixed(
-            ClientConfiguration {
-                ssid: self.config.client_ssid.clone(),
-                password: self.config.client_password.clone(),
-                auth_method: AuthMethod::WPA2Personal,
-                ..Default::default()
-            },
-            AccessPointConfiguration {
-                ssid: self.config.ap_ssid.clone(),
-                password: self.config.ap_password.clone(),
-                auth_method: AuthMethod::WPA2Personal,
-                channel: self.config.ap_channel,
-                max_connections: self.config.ap_max_connections,
-                ..Default::default()
-            },
-        )).map_err(|e| Error::WiFiError(format!("Failed to set WiFi configuration: {}", e)))?;
+        if self.config.mode.has_ap() {
+            info!(
+                "Setting up WiFi AP with SSID: {} ({}, channel {}, {}{})",
+                self.config.ap_ssid,
+                ap_auth,
+                self.ap_channel(),
+                self.config.ap_bandwidth,
+                if self.config.ap_hidden { ", hidden" } else { "" }
+            );
+        } else {
+            info!("Setting up WiFi station for SSID: {}", self.config.client_ssid);
+        }
+        let channel = self.ap_channel();
+        match self.radio.set_configuration(&self.config, ap_auth, sta_auth, channel) {
+            Ok(_) => {}
+            Err(e) if ap_auth.uses_wpa3() || sta_auth.uses_wpa3() => {
+                warn!("WiFi driver rejected WPA3 ({}), falling back to WPA2", e);
+                self.radio
+                    .set_configuration(&self.config, ap_auth.without_wpa3(), sta_auth.without_wpa3(), channel)
+                    .map_err(|e| Error::wifi_caused("Failed to set WiFi configuration", e))?;
+            }
+            Err(e) => return Err(Error::wifi_caused("Failed to set WiFi configuration", e)),
+        }
+        if self.config.mode.has_ap() {
+            self.apply_ap_bandwidth();
+        }
 
         Ok(())
     }
 
+    /// Set the channel width of the access point
+    ///
+    /// A width the driver rejects is logged and the access point keeps running.
+    fn apply_ap_bandwidth(&self) {
+        if let Err(e) = self.radio.set_ap_bandwidth(self.config.ap_bandwidth) {
+            warn!("Failed to set WiFi AP bandwidth to {}: {}", self.config.ap_bandwidth, e);
+        }
+    }
+
+    /// Scan for the least congested channel for the access point
+    ///
+    /// Without the station no scan is possible and channel 1 is used.
+    fn select_ap_channel(&mut self) {
+        let channel = match self.scan() {
+            Ok(networks) => {
+                let channel = least_congested_channel(&networks);
+                info!("Auto channel: picked channel {} ({} network(s) nearby)", channel, networks.len());
+                channel
+            }
+            Err(e) => {
+                warn!("Auto channel scan failed: {}, using channel 1", e);
+                1
+            }
+        };
+        self.auto_channel = Some(channel);
+    }
+
     /// Start WiFi and connect to the configured network
+    ///
+    /// Waits until the access point has its IP address, or in STA-only mode up to
+    /// `sta_connect_timeout_secs` for the station's. In AP-only mode the station is
+    /// never connected. Use `log_connection_info` to print the resulting addresses.
     pub fn start(&mut self) -> Result<()> {
         // Start WiFi
-        self.wifi.start().map_err(|e| Error::WiFiError(format!("Failed to start WiFi: {}", e)))?;
+        self.radio.start().map_err(|e| Error::wifi_caused("Failed to start WiFi", e))?;
         info!("WiFi started");
 
+        // 发射功率只能在驱动启动后设置，国家代码在扫描前设置
+        if let Err(e) = apply_country_code(&self.radio, &self.config.country_code) {
+            warn!("{}, keeping the driver's country", e);
+        }
+        if let Err(e) = apply_tx_power(&self.radio, self.config.tx_power_dbm) {
+            warn!("{}, keeping the driver's TX power", e);
+        }
+        info!(
+            "WiFi country: {}, max TX power: {} dBm",
+            self.config.country_code, self.config.tx_power_dbm
+        );
+
         // Wait a bit for WiFi to initialize
         std::thread::sleep(Duration::from_secs(1));
 
-        // Connect to client network if in mixed mode
-        if let Configuration::Mixed(_, _) = self.wifi.get_configuration().map_err(|e| Error::WiFiError(format!("Failed to get WiFi configuration: {}", e)))? {
-            match self.wifi.connect() {
+        // 自动信道需要驱动运行后才能扫描，再以选出的信道重新配置AP
+        if self.config.mode.has_ap() && self.config.ap_channel == 0 {
+            self.select_ap_channel();
+            self.configure()?;
+        }
+
+        // AP-only模式不连接STA，避免扫描导致信道切换
+        if self.config.mode.has_sta() {
+            match self.radio.connect() {
                 Ok(_) => info!("WiFi client connected"),
-                Err(e) => warn!("WiFi client connection failed: {:?} (continuing in AP-only mode)", e),
+                Err(e) => warn!("WiFi client connection failed: {:?} (the station will keep retrying)", e),
             };
         }
 
-        info!("WiFi mixed mode configured");
+        info!("WiFi {:?} mode configured", self.config.mode);
+
+        if self.config.mode.has_ap() {
+            self.wait_for_ap_ip();
+        } else {
+            self.wait_for_sta_ip();
+        }
+        Ok(())
+    }
+
+    /// Publish WiFi state changes to `status`
+    ///
+    /// Call this after `start`; until then the status stays in its starting state.
+    pub fn set_status(&mut self, status: Arc<DeviceStatus>) {
+        self.status = Some(status);
+        self.publish_status();
+    }
+
+    /// Report whether WiFi is up: the access point is, once started; a lone
+    /// station only while connected
+    fn publish_status(&self) {
+        if let Some(status) = &self.status {
+            status.set_wifi_up(self.config.mode.has_ap() || self.is_sta_connected());
+        }
+    }
 
+    /// Wait for the access point's IP address, restarting WiFi if it never comes
+    fn wait_for_ap_ip(&mut self) {
         // 等待AP模式完全初始化，使用更强的重试机制
-        let mut retry_count = 0;
         let max_retries = 10;  // 增加重试次数
-        let mut ap_ip = None;
-
-        // 尝试获取AP IP地址
-        while retry_count < max_retries {
-            if let Some(ap_info) = self.wifi.ap_netif().get_ip_info().ok() {
-                if ap_info.ip.is_unspecified() || ap_info.ip.is_loopback() {
-                    // IP地址无效，继续重试
-                    retry_count += 1;
-                    warn!("Invalid AP IP address: {}, retrying... ({}/{})", ap_info.ip, retry_count, max_retries);
-                } else {
-                    // 有效IP地址
+        for retry_count in 1..=max_retries {
+            match self.ap_ip_info() {
+                Some(ap_info) if !ap_info.ip.is_unspecified() && !ap_info.ip.is_loopback() => {
                     info!("AP IP address: {}", ap_info.ip);
-                    ap_ip = Some(ap_info.ip);
-                    break;
+                    return;
                 }
-            } else {
-                retry_count += 1;
-                warn!("Waiting for AP IP address... (attempt {}/{})", retry_count, max_retries);
+                // IP地址无效，继续重试
+                Some(ap_info) => warn!("Invalid AP IP address: {}, retrying... ({}/{})", ap_info.ip, retry_count, max_retries),
+                None => warn!("Waiting for AP IP address... (attempt {}/{})", retry_count, max_retries),
             }
 
             // 使用指数退避策略增加等待时间
@@ -114,52 +424,585 @@ impl WiFiManager {
             std::thread::sleep(Duration::from_millis(wait_time));
         }
 
-        // 显示WiFi状态信息
+        error!("Could not obtain valid AP IP address after {} attempts", max_retries);
+        // 尝试重新启动WiFi
+        warn!("Attempting to restart WiFi...");
+        if let Err(e) = self.radio.stop() {
+            error!("Failed to stop WiFi: {}", e);
+        } else if let Err(e) = self.radio.start() {
+            error!("Failed to restart WiFi: {}", e);
+        } else {
+            info!("WiFi restarted successfully");
+        }
+    }
+
+    /// Wait up to `sta_connect_timeout_secs` for the station's IP address
+    ///
+    /// The TCP server listens on all interfaces, so it is reachable as soon as the
+    /// station gets an address, even if that happens after the timeout.
+    fn wait_for_sta_ip(&self) {
+        let timeout = Duration::from_secs(self.config.sta_connect_timeout_secs as u64);
+        let stopwatch = Stopwatch::start();
+        while !stopwatch.has_elapsed(timeout) {
+            if let Some(info) = self.sta_ip_info() {
+                info!("STA IP address: {}", info.ip);
+                return;
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+        warn!("No IP address from '{}' within {:?}", self.config.client_ssid, timeout);
+    }
+
+    /// Log how clients reach the TCP server on `port`
+    pub fn log_connection_info(&self, port: u16) {
         info!("==================================================");
         info!("WiFi Status");
         info!("==================================================");
 
-        if let Some(ip) = ap_ip {
-            info!("Access Point Mode: READY");
-            info!("SSID: {}", self.config.ap_ssid);
-            info!("Password: {}", self.config.ap_password);
-            info!("IP Address: {}", ip);
-            info!("TCP Server Port: 8080");
-            info!("Connection Instructions:");
-            info!("1. Connect to WiFi network '{}'", self.config.ap_ssid);
-            info!("2. Use password '{}'", self.config.ap_password);
-            info!("3. Connect to TCP server at {}:8080", ip);
-        } else {
-            error!("Access Point Mode: FAILED");
-            error!("Could not obtain valid IP address after {} attempts", max_retries);
-            error!("Fallback Connection Instructions:");
-            error!("1. Try connecting to SSID '{}' with password '{}'", self.config.ap_ssid, self.config.ap_password);
-            error!("2. Try connecting to TCP server at 192.168.4.1:8080");
-
-            // 尝试重新启动WiFi
-            warn!("Attempting to restart WiFi...");
-            if let Err(e) = self.wifi.stop() {
-                error!("Failed to stop WiFi: {}", e);
-            } else if let Err(e) = self.wifi.start() {
-                error!("Failed to restart WiFi: {}", e);
-            } else {
-                info!("WiFi restarted successfully");
+        if self.config.mode.has_ap() {
+            match self.ap_ip_info().filter(|info| !info.ip.is_unspecified()) {
+                Some(info) => {
+                    info!("Access Point Mode: READY");
+                    info!("SSID: {}", self.config.ap_ssid);
+                    info!("Password: {}", self.config.ap_password);
+                    info!("IP Address: {}", info.ip);
+                    info!("TCP Server Port: {}", port);
+                    info!("Connection Instructions:");
+                    info!("1. Connect to WiFi network '{}'", self.config.ap_ssid);
+                    info!("2. Use password '{}'", self.config.ap_password);
+                    info!("3. Connect to TCP server at {}:{}", info.ip, port);
+                }
+                None => {
+                    error!("Access Point Mode: FAILED");
+                    error!("Fallback Connection Instructions:");
+                    error!("1. Try connecting to SSID '{}' with password '{}'", self.config.ap_ssid, self.config.ap_password);
+                    error!("2. Try connecting to TCP server at 192.168.4.1:{}", port);
+                }
+            }
+        }
+
+        if self.config.mode.has_sta() {
+            match self.sta_ip_info() {
+                Some(info) => {
+                    info!("Station Mode: CONNECTED");
+                    info!("SSID: {}", self.config.client_ssid);
+                    info!("IP Address: {}", info.ip);
+                    info!("Connect to TCP server at {}:{}", info.ip, port);
+                }
+                None => {
+                    warn!("Station Mode: NOT CONNECTED");
+                    warn!("SSID: {} (retrying in the background)", self.config.client_ssid);
+                }
             }
         }
         info!("==================================================");
+    }
+
+    /// Get the configured WiFi mode
+    pub fn mode(&self) -> WiFiMode {
+        self.config.mode
+    }
+
+    /// Get the station SSID currently configured
+    pub fn sta_ssid(&self) -> &str {
+        &self.config.client_ssid
+    }
+
+    /// Get the SSID of the access point
+    pub fn ap_ssid(&self) -> &str {
+        &self.config.ap_ssid
+    }
+
+    /// Get the IP information of the access point interface
+    pub fn ap_ip_info(&self) -> Option<IpInfo> {
+        if !self.config.mode.has_ap() {
+            return None;
+        }
+        self.radio.ap_ip_info()
+    }
+
+    /// List the stations associated with the access point
+    ///
+    /// Returns an empty list when no station is connected. The IP address is looked
+    /// up in the AP's DHCP leases and left empty for stations without a lease.
+    pub fn connected_stations(&self) -> Result<Vec<StationInfo>> {
+        if !self.config.mode.has_ap() {
+            return Ok(Vec::new());
+        }
+        self.radio.stations()
+    }
+
+    /// Scan for networks on the station interface
+    ///
+    /// The access point keeps running while the radio visits the other channels,
+    /// so its clients stay connected. The scan is aborted after `scan_timeout_ms`.
+    /// Results are sorted by signal strength, strongest first.
+    pub fn scan(&mut self) -> Result<Vec<ScanResult>> {
+        if !self.config.mode.has_sta() {
+            return Err(Error::wifi("Scanning needs the station, which is not used in AP-only mode"));
+        }
+        self.radio
+            .start_scan()
+            .map_err(|e| Error::wifi_caused("Failed to start scan", e))?;
+
+        // 非阻塞扫描，超时后停止，避免长时间占用命令线程
+        let timeout = Duration::from_millis(self.config.scan_timeout_ms);
+        let stopwatch = Stopwatch::start();
+        while !self.radio.is_scan_done().unwrap_or(false) {
+            if stopwatch.has_elapsed(timeout) {
+                if let Err(e) = self.radio.stop_scan() {
+                    warn!("Failed to stop scan: {}", e);
+                }
+                return Err(Error::wifi(format!("Scan did not finish within {:?}", timeout)));
+            }
+            std::thread::sleep(Duration::from_millis(100));
+        }
+
+        let mut results = self
+            .radio
+            .scan_results()
+            .map_err(|e| Error::wifi_caused("Failed to get scan results", e))?;
+        sort_by_signal(&mut results);
+        info!("WiFi scan found {} network(s) in {:?}", results.len(), stopwatch.elapsed());
+        Ok(results)
+    }
+
+    /// Check whether the station is connected to a network
+    pub fn is_sta_connected(&self) -> bool {
+        self.radio.is_connected().unwrap_or(false)
+    }
+
+    /// Get the IP information of the station interface (None if not connected)
+    pub fn sta_ip_info(&self) -> Option<IpInfo> {
+        if !self.is_sta_connected() {
+            return None;
+        }
+        self.radio
+            .sta_ip_info()
+            .filter(|info| !info.ip.is_unspecified())
+    }
+
+    /// Get the signal strength, channel and BSSID of the station's network
+    ///
+    /// Returns None if the station is not connected.
+    pub fn sta_link(&self) -> Result<Option<StaLink>> {
+        if !self.is_sta_connected() {
+            return Ok(None);
+        }
+        self.radio.sta_link().map(Some)
+    }
+
+    /// Get the signal strength of the network the station is connected to, in dBm
+    ///
+    /// Returns None if the station is not connected.
+    pub fn sta_rssi(&self) -> Result<Option<i8>> {
+        Ok(self.sta_link()?.map(|link| link.rssi))
+    }
+
+    /// Change the station credentials, persist them and reconnect
+    ///
+    /// Only the station interface is reconfigured, so clients connected to the access
+    /// point stay connected. Waits up to `sta_connect_timeout_secs` for the connection.
+    pub fn set_sta_credentials(&mut self, ssid: &str, password: &str) -> Result<StaConnectResult> {
+        if !self.config.mode.has_sta() {
+            return Err(Error::wifi("The station is not used in AP-only mode"));
+        }
+        let ssid_str: heapless::String<32> = heapless::String::try_from(ssid)
+            .map_err(|_| Error::wifi("SSID is longer than 32 bytes"))?;
+        let password_str: heapless::String<64> = heapless::String::try_from(password)
+            .map_err(|_| Error::wifi("Password is longer than 64 bytes"))?;
+
+        self.config.client_ssid = ssid_str;
+        self.config.client_password = password_str;
+
+        // 保存到flash
+        self.save_config();
+
+        info!("Reconnecting WiFi station to SSID: {}", ssid);
+        if let Err(e) = self.radio.disconnect() {
+            warn!("Failed to disconnect WiFi station: {}", e);
+        }
+
+        // 只修改STA接口的配置，不影响AP
+        self.radio.set_sta_credentials(ssid, password)?;
+
+        // 新网络重新开始重连退避
+        self.sta_enabled = true;
+        self.reset_reconnect();
+        if let Err(e) = self.radio.connect() {
+            warn!("WiFi station connection failed: {}", e);
+            return Ok(StaConnectResult::Failed(e.to_string()));
+        }
+
+        // 等待连接完成
+        let timeout = Duration::from_secs(self.config.sta_connect_timeout_secs as u64);
+        let stopwatch = Stopwatch::start();
+        while !stopwatch.has_elapsed(timeout) {
+            if self.radio.is_connected().unwrap_or(false) {
+                info!("WiFi station connected to {}", ssid);
+                return Ok(StaConnectResult::Connected);
+            }
+            std::thread::sleep(Duration::from_millis(200));
+        }
+
+        warn!("WiFi station did not connect to {} within {:?}", ssid, timeout);
+        Ok(StaConnectResult::TimedOut)
+    }
+
+    /// Change the access point SSID and password and persist them
+    ///
+    /// The running access point is not reconfigured, since that would disconnect
+    /// every station including the one making the change; the new settings take
+    /// effect after a restart.
+    pub fn set_ap_credentials(&mut self, ssid: &str, password: &str) -> Result<()> {
+        if ssid.is_empty() {
+            return Err(Error::wifi("SSID must not be empty"));
+        }
+        self.config.ap_auth.check_password(password).map_err(Error::wifi)?;
+        self.config.ap_ssid = heapless::String::try_from(ssid)
+            .map_err(|_| Error::wifi("SSID is longer than 32 bytes"))?;
+        self.config.ap_password = heapless::String::try_from(password)
+            .map_err(|_| Error::wifi("Password is longer than 64 bytes"))?;
+        self.save_config();
+        info!("WiFi access point set to SSID: {} (takes effect after restart)", ssid);
+        Ok(())
+    }
+
+    /// Get the channel the access point runs on
+    ///
+    /// With an automatic channel this is the one picked by the last scan. While the
+    /// station is connected, the access point shares the station's channel instead.
+    pub fn ap_channel(&self) -> u8 {
+        match self.config.ap_channel {
+            0 => self.auto_channel.unwrap_or(1),
+            channel => channel,
+        }
+    }
+
+    /// Check whether the access point channel is picked by a scan
+    pub fn is_ap_auto_channel(&self) -> bool {
+        self.config.ap_channel == 0
+    }
+
+    /// Check whether the access point hides its SSID
+    pub fn is_ap_hidden(&self) -> bool {
+        self.config.ap_hidden
+    }
+
+    /// Get the channel width of the access point
+    pub fn ap_bandwidth(&self) -> ApBandwidth {
+        self.config.ap_bandwidth
+    }
+
+    /// Change the access point settings and persist them (AT+AP=)
+    ///
+    /// `channel` 0 picks the channel by a scan and `bandwidth` None keeps the current
+    /// width. Call `restart_ap` to apply the settings.
+    pub fn set_access_point(
+        &mut self,
+        ssid: &str,
+        password: &str,
+        channel: u8,
+        hidden: bool,
+        bandwidth: Option<ApBandwidth>,
+    ) -> Result<()> {
+        if ssid.is_empty() {
+            return Err(Error::wifi("SSID must not be empty"));
+        }
+        if channel > 13 {
+            return Err(Error::wifi("Channel must be 1-13 or auto"));
+        }
+        self.config.ap_auth.check_password(password).map_err(Error::wifi)?;
+        self.config.ap_ssid = heapless::String::try_from(ssid)
+            .map_err(|_| Error::wifi("SSID is longer than 32 bytes"))?;
+        self.config.ap_password = heapless::String::try_from(password)
+            .map_err(|_| Error::wifi("Password is longer than 64 bytes"))?;
+        self.config.ap_channel = channel;
+        self.config.ap_hidden = hidden;
+        if let Some(bandwidth) = bandwidth {
+            self.config.ap_bandwidth = bandwidth;
+        }
+        self.auto_channel = None;
+        self.save_config();
+        info!("WiFi access point set to SSID: {}", ssid);
+        Ok(())
+    }
+
+    /// Apply the access point settings to the running access point
+    ///
+    /// Every station connected to the access point is dropped and has to join
+    /// again. An automatic channel is picked by a new scan first.
+    pub fn restart_ap(&mut self) -> Result<()> {
+        if !self.config.mode.has_ap() {
+            return Err(Error::wifi("The access point is not used in STA-only mode"));
+        }
+        warn!("Restarting WiFi access point, connected stations will drop");
+        if self.config.ap_channel == 0 {
+            self.select_ap_channel();
+        }
+        self.configure()?;
+        info!("WiFi access point restarted on channel {}", self.ap_channel());
+        Ok(())
+    }
+
+    /// Get the regulatory country code
+    pub fn country_code(&self) -> &str {
+        &self.config.country_code
+    }
+
+    /// Change the regulatory country, apply it and persist it (AT+COUNTRY=)
+    pub fn set_country_code(&mut self, code: &str) -> Result<()> {
+        let code = code.to_ascii_uppercase();
+        apply_country_code(&self.radio, &code)?;
+        // 格式已由apply_country_code检查
+        self.config.country_code = heapless::String::try_from(code.as_str()).unwrap_or_default();
+        self.save_config();
+        info!("WiFi country set to {}", code);
+        Ok(())
+    }
+
+    /// Get the maximum transmit power in dBm
+    pub fn tx_power_dbm(&self) -> u8 {
+        self.config.tx_power_dbm
+    }
+
+    /// Change the maximum transmit power, apply it and persist it (AT+TXPOWER=)
+    pub fn set_tx_power(&mut self, dbm: u8) -> Result<()> {
+        apply_tx_power(&self.radio, dbm)?;
+        self.config.tx_power_dbm = dbm;
+        self.save_config();
+        info!("WiFi max TX power set to {} dBm", dbm);
+        Ok(())
+    }
+
+    /// Get the configured access point security
+    pub fn ap_auth(&self) -> WiFiAuth {
+        self.config.ap_auth
+    }
+
+    /// Change the access point security and persist it
+    ///
+    /// Without `password` the current one is kept, except that an open access point
+    /// drops it. Like `set_ap_credentials`, this takes effect after a restart.
+    pub fn set_ap_security(&mut self, auth: WiFiAuth, password: Option<&str>) -> Result<()> {
+        let password = match (auth, password) {
+            (_, Some(password)) => password.to_string(),
+            (WiFiAuth::Open, None) => String::new(),
+            (_, None) => self.config.ap_password.to_string(),
+        };
+        auth.check_password(&password).map_err(Error::wifi)?;
+        self.config.ap_password = heapless::String::try_from(password.as_str())
+            .map_err(|_| Error::wifi("Password is longer than 64 bytes"))?;
+        self.config.ap_auth = auth;
+        self.save_config();
+        info!("WiFi access point security set to {} (takes effect after restart)", auth);
+        Ok(())
+    }
+
+    /// Save the WiFi settings to flash, if storage is available
+    fn save_config(&self) {
+        if let Some(storage) = &self.storage {
+            match storage.lock() {
+                Ok(mut storage) => {
+                    if let Err(e) = storage.save_wifi_config(&self.config) {
+                        warn!("Failed to save WiFi config to flash: {}", e);
+                    }
+                }
+                Err(e) => warn!("Failed to lock storage manager: {}, WiFi config will not be persisted", e),
+            }
+        }
+    }
+
+    /// Get the reconnection state of the station
+    pub fn sta_status(&self) -> StaStatus {
+        let connected = self.is_sta_connected();
+        let next_retry = (self.sta_enabled && self.config.mode.has_sta() && !connected)
+            .then(|| self.reconnect.delay.saturating_sub(self.reconnect.waited.elapsed()));
+        StaStatus {
+            enabled: self.sta_enabled && self.config.mode.has_sta(),
+            connected,
+            attempts: self.reconnect.attempts,
+            last_error: self.reconnect.last_error.clone(),
+            next_retry,
+        }
+    }
+
+    /// Enable or disable the station
+    ///
+    /// A disabled station is disconnected and no longer reconnected; the access
+    /// point is not affected.
+    pub fn set_sta_enabled(&mut self, enabled: bool) {
+        if enabled == self.sta_enabled {
+            return;
+        }
+        self.sta_enabled = enabled;
+        self.reset_reconnect();
+        if enabled {
+            info!("WiFi station enabled");
+        } else {
+            info!("WiFi station disabled");
+            if let Err(e) = self.radio.disconnect() {
+                warn!("Failed to disconnect WiFi station: {}", e);
+            }
+        }
+    }
 
+    /// Start the task that reconnects the station when it drops
+    ///
+    /// The delay between attempts starts at `sta_reconnect_interval_ms` and doubles
+    /// up to `sta_max_backoff_ms`. Connecting does not wait for the result and the
+    /// task skips a round while the manager is busy, so it never blocks the access
+    /// point or the TCP server.
+    pub fn start_reconnect(wifi_arc: Arc<Mutex<Self>>) -> Result<()> {
+        thread::Builder::new()
+            .name("wifi_reconnect".into())
+            .stack_size(4096)
+            .spawn(move || loop {
+                thread::sleep(Duration::from_millis(RECONNECT_POLL_MS));
+                // 其他线程正在使用WiFi管理器（扫描、修改配置）时跳过本轮
+                if let Ok(mut wifi) = wifi_arc.try_lock() {
+                    wifi.reconnect_step();
+                }
+            })
+            .map_err(|e| Error::wifi_caused("Failed to spawn WiFi reconnect thread", e))?;
         Ok(())
     }
 
+    /// Reconnect the station if it is down and the backoff delay has passed
+    fn reconnect_step(&mut self) {
+        self.publish_status();
+        if !self.sta_enabled || !self.config.mode.has_sta() || self.config.client_ssid.is_empty() {
+            return;
+        }
+        self.check_signal();
+        if self.is_sta_connected() {
+            if self.reconnect.attempts > 0 {
+                info!(
+                    "WiFi station reconnected after {} attempt(s)",
+                    self.reconnect.attempts
+                );
+            }
+            self.reset_reconnect();
+            return;
+        }
+        if !self.reconnect.waited.has_elapsed(self.reconnect.delay) {
+            return;
+        }
+
+        let initial_delay = Duration::from_millis(self.config.sta_reconnect_interval_ms);
+        let max_delay = Duration::from_millis(self.config.sta_max_backoff_ms).max(initial_delay);
+        let attempt = self.reconnect.start_attempt(max_delay);
+        info!(
+            "Reconnecting WiFi station to {} (attempt {})",
+            self.config.client_ssid, attempt
+        );
+        // connect只发起连接，不等待结果
+        if let Err(e) = self.radio.connect() {
+            warn!("WiFi station reconnect failed: {}", e);
+            self.reconnect.last_error = Some(e.to_string());
+        }
+    }
+
+    /// Warn once when the station signal stayed below `sta_weak_rssi_dbm` for a minute
+    ///
+    /// A weak signal usually explains dropped TCP sessions on the station side.
+    fn check_signal(&mut self) {
+        let threshold = self.config.sta_weak_rssi_dbm;
+        let rssi = match self.sta_rssi() {
+            Ok(Some(rssi)) if threshold != 0 => rssi,
+            // 未连接或已禁用时重新计时
+            _ => {
+                self.weak_signal = None;
+                self.weak_signal_warned = false;
+                return;
+            }
+        };
+        if rssi >= threshold {
+            if self.weak_signal.take().is_some() && self.weak_signal_warned {
+                info!("WiFi station signal recovered: {} dBm", rssi);
+            }
+            self.weak_signal_warned = false;
+            return;
+        }
+        let weak_for = self.weak_signal.get_or_insert_with(Stopwatch::start).elapsed();
+        if !self.weak_signal_warned && weak_for >= WEAK_SIGNAL_WARN_AFTER {
+            warn!(
+                "WiFi station signal has been below {} dBm for {} s (now {} dBm), TCP sessions may drop",
+                threshold,
+                weak_for.as_secs(),
+                rssi
+            );
+            self.weak_signal_warned = true;
+        }
+    }
+
+    /// Restart the backoff from the initial reconnect interval
+    fn reset_reconnect(&mut self) {
+        self.reconnect = Reconnect::new(Duration::from_millis(self.config.sta_reconnect_interval_ms));
+    }
+
     /// Get the underlying WiFi driver
+    #[cfg(target_os = "espidf")]
     pub fn wifi(&self) -> &EspWifi<'static> {
-        &self.wifi
+        self.radio.driver()
+    }
+}
+
+/// Pick the 2.4 GHz channel (1-13) least used by `networks`
+///
+/// A network counts against its own channel and, less, against the channels its
+/// 20 MHz signal overlaps, weighted by its signal strength. Ties go to the lower
+/// channel.
+fn least_congested_channel(networks: &[ScanResult]) -> u8 {
+    (1..=13u8)
+        .min_by_key(|&channel| {
+            networks
+                .iter()
+                .map(|network| {
+                    let distance = i32::from(channel.abs_diff(network.channel));
+                    // 相隔5个及以上信道不重叠，-100dBm以下的信号几乎不造成干扰
+                    let strength = (i32::from(network.rssi) + 100).max(1);
+                    (5 - distance).max(0) * strength
+                })
+                .sum::<i32>()
+        })
+        .unwrap_or(1)
+}
+
+/// Check that `code` is a country code: two upper-case letters, or "01" for worldwide
+pub fn is_valid_country_code(code: &str) -> bool {
+    code == "01" || (code.len() == 2 && code.bytes().all(|b| b.is_ascii_uppercase()))
+}
+
+/// Set the regulatory country of the driver
+///
+/// 802.11d is disabled, so the station does not adopt the country announced by
+/// the network it joins.
+fn apply_country_code(radio: &Radio, code: &str) -> Result<()> {
+    if !is_valid_country_code(code) {
+        return Err(Error::wifi(format!(
+            "Invalid country code: {} (use two letters like DE, or 01 for worldwide)",
+            code
+        )));
+    }
+    radio.set_country_code(code)
+}
+
+/// Set the maximum transmit power of the driver
+fn apply_tx_power(radio: &Radio, dbm: u8) -> Result<()> {
+    if !(MIN_TX_POWER_DBM..=MAX_TX_POWER_DBM).contains(&dbm) {
+        return Err(Error::wifi(format!(
+            "TX power {} dBm is out of range (use {}-{})",
+            dbm, MIN_TX_POWER_DBM, MAX_TX_POWER_DBM
+        )));
     }
+    radio.set_max_tx_power(dbm)
 }
 
 /// Configure WiFi in mixed mode (AP + STA) with default configuration
 ///
 /// This is a convenience function for backward compatibility
+#[cfg(target_os = "espidf")]
 pub fn configure_wifi_mixed_mode() -> anyhow::Result<Box<EspWifi<'static>>> {
     let nvs = EspDefaultNvsPartition::take()?;
     let sysloop = EspSystemEventLoop::take()?;
@@ -225,3 +1068,500 @@ pub fn configure_wifi_mixed_mode() -> anyhow::Result<Box<EspWifi<'static>>> {
 
     Ok(wifi)
 }
+
+/// The ESP-IDF WiFi driver
+#[cfg(target_os = "espidf")]
+mod radio {
+    use esp_idf_svc::{
+        eventloop::EspSystemEventLoop,
+        nvs::EspDefaultNvsPartition,
+        sys::EspError,
+        wifi::{
+            config::{ScanConfig, ScanType},
+            AccessPointConfiguration, AuthMethod, ClientConfiguration, Configuration, EspWifi,
+        },
+    };
+    use log::warn;
+    use std::ffi::CString;
+    use std::net::Ipv4Addr;
+    use std::time::Duration;
+
+    use super::{IpInfo, ScanResult, StaLink, StationInfo};
+    use crate::config::{ApBandwidth, WiFiAuth, WiFiConfig, WiFiMode};
+    use crate::error::{Error, Result};
+
+    /// Error returned by the driver
+    pub type DriverError = EspError;
+
+    /// The WiFi driver of the modem
+    pub struct Radio(Box<EspWifi<'static>>);
+
+    impl Radio {
+        /// Take the modem and create the driver
+        pub fn new() -> Result<Self> {
+            let nvs = EspDefaultNvsPartition::take()
+                .map_err(|e| Error::wifi_caused("Failed to take NVS partition", e))?;
+            let sysloop = EspSystemEventLoop::take()
+                .map_err(|e| Error::wifi_caused("Failed to take system event loop", e))?;
+
+            let modem = unsafe { esp_idf_svc::hal::modem::Modem::new() };
+            let wifi = EspWifi::new(modem, sysloop, Some(nvs))
+                .map_err(|e| Error::wifi_caused("Failed to create WiFi driver", e))?;
+            Ok(Radio(Box::new(wifi)))
+        }
+
+        /// Get the underlying driver
+        pub fn driver(&self) -> &EspWifi<'static> {
+            &self.0
+        }
+
+        /// Configure the interfaces of `config.mode` with the given security and AP channel
+        pub fn set_configuration(
+            &mut self,
+            config: &WiFiConfig,
+            ap_auth: WiFiAuth,
+            sta_auth: WiFiAuth,
+            channel: u8,
+        ) -> std::result::Result<(), DriverError> {
+            let client = ClientConfiguration {
+                ssid: config.client_ssid.clone(),
+                password: config.client_password.clone(),
+                auth_method: auth_method(sta_auth),
+                ..Default::default()
+            };
+            let access_point = AccessPointConfiguration {
+                ssid: config.ap_ssid.clone(),
+                ssid_hidden: config.ap_hidden,
+                // 开放网络不使用密码
+                password: if ap_auth == WiFiAuth::Open {
+                    heapless::String::new()
+                } else {
+                    config.ap_password.clone()
+                },
+                auth_method: auth_method(ap_auth),
+                channel,
+                secondary_channel: config.ap_bandwidth.secondary_channel(channel),
+                max_connections: config.ap_max_connections,
+                ..Default::default()
+            };
+
+            let configuration = match config.mode {
+                WiFiMode::ApOnly => Configuration::AccessPoint(access_point),
+                WiFiMode::StaOnly => Configuration::Client(client),
+                WiFiMode::Mixed => Configuration::Mixed(client, access_point),
+            };
+            self.0.set_configuration(&configuration)
+        }
+
+        /// Set the channel width of the access point
+        pub fn set_ap_bandwidth(&self, bandwidth: ApBandwidth) -> Result<()> {
+            let bandwidth = match bandwidth {
+                ApBandwidth::Ht20 => esp_idf_sys::wifi_bandwidth_t_WIFI_BW_HT20,
+                ApBandwidth::Ht40 => esp_idf_sys::wifi_bandwidth_t_WIFI_BW_HT40,
+            };
+            let err = unsafe {
+                esp_idf_sys::esp_wifi_set_bandwidth(esp_idf_sys::wifi_interface_t_WIFI_IF_AP, bandwidth)
+            };
+            if err != esp_idf_sys::ESP_OK {
+                return Err(Error::esp(err, "Setting the AP bandwidth"));
+            }
+            Ok(())
+        }
+
+        pub fn start(&mut self) -> std::result::Result<(), DriverError> {
+            self.0.start()
+        }
+
+        pub fn stop(&mut self) -> std::result::Result<(), DriverError> {
+            self.0.stop()
+        }
+
+        /// Start connecting the station without waiting for the result
+        pub fn connect(&mut self) -> std::result::Result<(), DriverError> {
+            self.0.connect()
+        }
+
+        pub fn disconnect(&mut self) -> std::result::Result<(), DriverError> {
+            self.0.disconnect()
+        }
+
+        pub fn is_connected(&self) -> std::result::Result<bool, DriverError> {
+            self.0.is_connected()
+        }
+
+        pub fn ap_ip_info(&self) -> Option<IpInfo> {
+            self.0.ap_netif().get_ip_info().ok().map(|info| IpInfo { ip: info.ip })
+        }
+
+        pub fn sta_ip_info(&self) -> Option<IpInfo> {
+            self.0.sta_netif().get_ip_info().ok().map(|info| IpInfo { ip: info.ip })
+        }
+
+        /// List the stations associated with the access point, with their DHCP leases
+        pub fn stations(&self) -> Result<Vec<StationInfo>> {
+            let mut list: esp_idf_sys::wifi_sta_list_t = unsafe { core::mem::zeroed() };
+            let err = unsafe { esp_idf_sys::esp_wifi_ap_get_sta_list(&mut list) };
+            if err != esp_idf_sys::ESP_OK {
+                return Err(Error::esp(err, "Getting the AP station list"));
+            }
+
+            let count = (list.num.max(0) as usize).min(list.sta.len());
+            let mut stations: Vec<StationInfo> = list.sta[..count]
+                .iter()
+                .map(|sta| StationInfo {
+                    mac: sta.mac,
+                    rssi: sta.rssi,
+                    ip: None,
+                })
+                .collect();
+            if stations.is_empty() {
+                return Ok(stations);
+            }
+
+            // 从AP的DHCP服务器查询分配给各station的IP
+            let mut pairs: Vec<esp_idf_sys::esp_netif_pair_mac_ip_t> = stations
+                .iter()
+                .map(|station| {
+                    let mut pair: esp_idf_sys::esp_netif_pair_mac_ip_t = unsafe { core::mem::zeroed() };
+                    pair.mac = station.mac;
+                    pair
+                })
+                .collect();
+            let err = unsafe {
+                esp_idf_sys::esp_netif_dhcps_get_clients_by_mac(
+                    self.0.ap_netif().handle(),
+                    pairs.len() as core::ffi::c_int,
+                    pairs.as_mut_ptr(),
+                )
+            };
+            if err == esp_idf_sys::ESP_OK {
+                for (station, pair) in stations.iter_mut().zip(&pairs) {
+                    // 地址按网络字节序存放
+                    let ip = Ipv4Addr::from(pair.ip.addr.to_le_bytes());
+                    station.ip = (!ip.is_unspecified()).then_some(ip);
+                }
+            } else {
+                warn!("{}, station IPs unknown", Error::esp(err, "Looking up the AP's DHCP leases"));
+            }
+
+            Ok(stations)
+        }
+
+        /// Start an active scan without waiting for it to finish
+        pub fn start_scan(&mut self) -> std::result::Result<(), DriverError> {
+            let scan_config = ScanConfig {
+                scan_type: ScanType::Active {
+                    min: Duration::from_millis(0),
+                    max: Duration::from_millis(120),
+                },
+                show_hidden: false,
+                ..Default::default()
+            };
+            self.0.start_scan(&scan_config, false)
+        }
+
+        pub fn is_scan_done(&self) -> std::result::Result<bool, DriverError> {
+            self.0.is_scan_done()
+        }
+
+        pub fn stop_scan(&mut self) -> std::result::Result<(), DriverError> {
+            self.0.stop_scan()
+        }
+
+        /// Networks found by the finished scan, in the driver's order
+        pub fn scan_results(&mut self) -> std::result::Result<Vec<ScanResult>, DriverError> {
+            let found = self.0.get_scan_result()?;
+            Ok(found
+                .into_iter()
+                .map(|ap| ScanResult {
+                    ssid: ap.ssid.as_str().to_string(),
+                    rssi: ap.signal_strength,
+                    channel: ap.channel,
+                    auth: ap.auth_method.map(|auth| format!("{:?}", auth)),
+                })
+                .collect())
+        }
+
+        /// Get the network the station is associated with
+        pub fn sta_link(&self) -> Result<StaLink> {
+            let mut record: esp_idf_sys::wifi_ap_record_t = unsafe { core::mem::zeroed() };
+            let err = unsafe { esp_idf_sys::esp_wifi_sta_get_ap_info(&mut record) };
+            if err != esp_idf_sys::ESP_OK {
+                return Err(Error::esp(err, "Getting the station's access point info"));
+            }
+            Ok(StaLink {
+                rssi: record.rssi,
+                channel: record.primary,
+                bssid: record.bssid,
+            })
+        }
+
+        /// Change the SSID and password of the station configuration only
+        ///
+        /// Both must already be checked to fit the driver's 32 and 64 byte fields.
+        pub fn set_sta_credentials(&mut self, ssid: &str, password: &str) -> Result<()> {
+            unsafe {
+                let mut sta_config: esp_idf_sys::wifi_config_t = core::mem::zeroed();
+                let err = esp_idf_sys::esp_wifi_get_config(esp_idf_sys::wifi_interface_t_WIFI_IF_STA, &mut sta_config);
+                if err != esp_idf_sys::ESP_OK {
+                    return Err(Error::esp(err, "Getting the station configuration"));
+                }
+                sta_config.sta.ssid = [0; 32];
+                sta_config.sta.ssid[..ssid.len()].copy_from_slice(ssid.as_bytes());
+                sta_config.sta.password = [0; 64];
+                sta_config.sta.password[..password.len()].copy_from_slice(password.as_bytes());
+                let err = esp_idf_sys::esp_wifi_set_config(esp_idf_sys::wifi_interface_t_WIFI_IF_STA, &mut sta_config);
+                if err != esp_idf_sys::ESP_OK {
+                    return Err(Error::esp(err, "Setting the station configuration"));
+                }
+            }
+            Ok(())
+        }
+
+        /// Set the regulatory country; 802.11d stays disabled
+        pub fn set_country_code(&self, code: &str) -> Result<()> {
+            let c_code = CString::new(code).map_err(|_| Error::wifi("Country code contains a NUL byte"))?;
+            let err = unsafe { esp_idf_sys::esp_wifi_set_country_code(c_code.as_ptr(), false) };
+            if err == esp_idf_sys::ESP_ERR_INVALID_ARG {
+                Err(Error::wifi(format!("Country code {} is not supported by the WiFi driver", code)))
+            } else if err != esp_idf_sys::ESP_OK {
+                Err(Error::esp(err, "Setting the WiFi country"))
+            } else {
+                Ok(())
+            }
+        }
+
+        /// Set the maximum transmit power
+        pub fn set_max_tx_power(&self, dbm: u8) -> Result<()> {
+            // 驱动以0.25dBm为单位
+            let err = unsafe { esp_idf_sys::esp_wifi_set_max_tx_power((dbm * 4) as i8) };
+            if err == esp_idf_sys::ESP_ERR_INVALID_ARG {
+                Err(Error::wifi(format!("TX power {} dBm is not supported by the WiFi driver", dbm)))
+            } else if err != esp_idf_sys::ESP_OK {
+                Err(Error::esp(err, "Setting the WiFi TX power"))
+            } else {
+                Ok(())
+            }
+        }
+    }
+
+    /// Driver auth method for a security mode
+    fn auth_method(auth: WiFiAuth) -> AuthMethod {
+        match auth {
+            WiFiAuth::Open => AuthMethod::None,
+            WiFiAuth::Wpa2Personal => AuthMethod::WPA2Personal,
+            WiFiAuth::Wpa2Wpa3Personal => AuthMethod::WPA2WPA3Personal,
+            WiFiAuth::Wpa3Personal => AuthMethod::WPA3Personal,
+        }
+    }
+}
+
+/// Stand-in for the driver in the host build, which has no radio
+#[cfg(not(target_os = "espidf"))]
+mod radio {
+    use std::convert::Infallible;
+
+    use super::{IpInfo, ScanResult, StaLink, StationInfo};
+    use crate::config::{ApBandwidth, WiFiAuth, WiFiConfig};
+    use crate::error::{Error, Result};
+
+    pub type DriverError = Infallible;
+
+    /// Never constructed, `new` always fails
+    pub enum Radio {}
+
+    impl Radio {
+        pub fn new() -> Result<Self> {
+            Err(Error::wifi("No WiFi radio in the host build"))
+        }
+
+        pub fn set_configuration(
+            &mut self,
+            _config: &WiFiConfig,
+            _ap_auth: WiFiAuth,
+            _sta_auth: WiFiAuth,
+            _channel: u8,
+        ) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn set_ap_bandwidth(&self, _bandwidth: ApBandwidth) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn start(&mut self) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn stop(&mut self) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn connect(&mut self) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn disconnect(&mut self) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn is_connected(&self) -> std::result::Result<bool, DriverError> {
+            match *self {}
+        }
+
+        pub fn ap_ip_info(&self) -> Option<IpInfo> {
+            match *self {}
+        }
+
+        pub fn sta_ip_info(&self) -> Option<IpInfo> {
+            match *self {}
+        }
+
+        pub fn stations(&self) -> Result<Vec<StationInfo>> {
+            match *self {}
+        }
+
+        pub fn start_scan(&mut self) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn is_scan_done(&self) -> std::result::Result<bool, DriverError> {
+            match *self {}
+        }
+
+        pub fn stop_scan(&mut self) -> std::result::Result<(), DriverError> {
+            match *self {}
+        }
+
+        pub fn scan_results(&mut self) -> std::result::Result<Vec<ScanResult>, DriverError> {
+            match *self {}
+        }
+
+        pub fn sta_link(&self) -> Result<StaLink> {
+            match *self {}
+        }
+
+        pub fn set_sta_credentials(&mut self, _ssid: &str, _password: &str) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_country_code(&self, _code: &str) -> Result<()> {
+            match *self {}
+        }
+
+        pub fn set_max_tx_power(&self, _dbm: u8) -> Result<()> {
+            match *self {}
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::time;
+
+    #[test]
+    fn station_is_shown_with_mac_signal_and_address() {
+        let mut station = StationInfo {
+            mac: [0x24, 0x0a, 0xc4, 0x01, 0xbe, 0xef],
+            rssi: -52,
+            ip: Some(Ipv4Addr::new(192, 168, 4, 2)),
+        };
+        assert_eq!(station.to_string(), "24:0a:c4:01:be:ef rssi=-52dBm ip=192.168.4.2");
+
+        station.ip = None;
+        assert_eq!(station.to_string(), "24:0a:c4:01:be:ef rssi=-52dBm ip=unknown");
+    }
+
+    #[test]
+    fn scan_result_is_shown_with_signal_channel_and_auth() {
+        let mut network = ScanResult {
+            ssid: "HomeNet".to_string(),
+            rssi: -48,
+            channel: 6,
+            auth: Some("WPA2Personal".to_string()),
+        };
+        assert_eq!(network.to_string(), "HomeNet rssi=-48dBm ch=6 auth=WPA2Personal");
+
+        network.auth = None;
+        assert_eq!(network.to_string(), "HomeNet rssi=-48dBm ch=6 auth=unknown");
+    }
+
+    #[test]
+    fn reconnect_delay_doubles_and_notes_failed_attempts() {
+        let _clock = time::lock_clock();
+        let max_delay = Duration::from_secs(4);
+        let mut reconnect = Reconnect::new(Duration::from_secs(1));
+        assert!(!reconnect.waited.has_elapsed(reconnect.delay));
+        time::advance(Duration::from_secs(1));
+        assert!(reconnect.waited.has_elapsed(reconnect.delay));
+
+        assert_eq!(reconnect.start_attempt(max_delay), 1);
+        assert_eq!(reconnect.last_error, None);
+        assert_eq!(reconnect.delay, Duration::from_secs(2));
+        assert!(!reconnect.waited.has_elapsed(reconnect.delay));
+
+        assert_eq!(reconnect.start_attempt(max_delay), 2);
+        assert_eq!(reconnect.last_error.as_deref(), Some("Not connected 2 s after attempt 1"));
+        assert_eq!(reconnect.delay, Duration::from_secs(4));
+        reconnect.start_attempt(max_delay);
+        assert_eq!(reconnect.delay, max_delay);
+    }
+
+    #[test]
+    fn scan_results_are_sorted_strongest_first() {
+        let network = |ssid: &str, rssi| ScanResult { ssid: ssid.to_string(), rssi, channel: 1, auth: None };
+        let mut results = vec![network("far", -80), network("near", -40), network("middle", -62)];
+        sort_by_signal(&mut results);
+        let order: Vec<&str> = results.iter().map(|result| result.ssid.as_str()).collect();
+        assert_eq!(order, ["near", "middle", "far"]);
+    }
+
+    fn network(channel: u8, rssi: i8) -> ScanResult {
+        ScanResult {
+            ssid: String::new(),
+            rssi,
+            channel,
+            auth: None,
+        }
+    }
+
+    #[test]
+    fn auto_channel_avoids_busy_channels() {
+        assert_eq!(least_congested_channel(&[]), 1);
+
+        // 信道1和6被占用，11及以上不受干扰，取最低的
+        assert_eq!(least_congested_channel(&[network(1, -40), network(6, -45)]), 11);
+
+        // 相邻的弱信号比重叠的强信号干扰小
+        let networks = [network(1, -30), network(13, -30), network(7, -90)];
+        assert_eq!(least_congested_channel(&networks), 6);
+    }
+
+    #[test]
+    fn country_codes_are_checked() {
+        assert!(is_valid_country_code("DE"));
+        assert!(is_valid_country_code("01"));
+        // 调用方先转换为大写
+        assert!(!is_valid_country_code("de"));
+        assert!(!is_valid_country_code("DEU"));
+        assert!(!is_valid_country_code("D1"));
+        assert!(!is_valid_country_code(""));
+    }
+
+    #[test]
+    fn sta_link_reports_the_signal_quality() {
+        let link = StaLink {
+            rssi: -52,
+            channel: 6,
+            bssid: [0xaa, 0xbb, 0xcc, 0x01, 0x02, 0x0f],
+        };
+        assert_eq!(link.to_string(), "aa:bb:cc:01:02:0f ch=6 rssi=-52dBm (good)");
+
+        let quality = |rssi| StaLink { rssi, ..link.clone() }.quality();
+        assert_eq!(quality(-50), "excellent");
+        assert_eq!(quality(-70), "fair");
+        assert_eq!(quality(-71), "weak");
+        assert_eq!(quality(-81), "unusable");
+    }
+}